@@ -5,7 +5,7 @@ use std::io::Write;
 use std::path::Path;
 use std::process::{Command, Stdio};
 
-static ASSET_FILES: &'static [&'static str] = &[
+static ASSET_FILES: &[&str] = &[
     "node_modules/mozilla-fira-pack/Fira/woff/FiraMono-Regular.woff",
     "node_modules/mozilla-fira-pack/Fira/woff/FiraSans-Regular.woff",
     "node_modules/mozilla-fira-pack/Fira/woff/FiraSans-Light.woff",
@@ -52,7 +52,7 @@ fn main() {
     fs::create_dir_all(&assets_out_dir).unwrap();
 
     let mut wahb = String::new();
-    wahb.push_str("fn _webassets_handler_body<'a>(path: &'a str) -> Option<(WebAssetContainer, &'static str)> {\n");
+    wahb.push_str("fn _webassets_handler_body(path: &str) -> Option<(WebAssetContainer, &'static str)> {\n");
     wahb.push_str("match path {\n");
 
     for asset_source in ASSET_FILES {
@@ -12,37 +12,242 @@ const {h, render, Component} = window.preact;
 
 const SENTINEL_ERROR = -2100000000;
 const SENTINEL_NODATA = -2000000000;
+const SENTINEL_PAUSED = -2000000001;
+const SENTINEL_MAINTENANCE = -2000000002;
+
+// descriptions of the classes of failure recorded instead of a value
+const FAILURE_NAMES = {
+    [SENTINEL_ERROR]: 'error',
+    [-2100000001]: 'NXDOMAIN',
+    [-2100000002]: 'SERVFAIL',
+    [-2100000003]: 'resolve failed',
+    [-2100000004]: 'connection refused',
+    [-2100000005]: 'timed out',
+    [-2100000006]: 'unreachable',
+    [-2100000007]: 'TLS failed',
+    [-2100000008]: 'command failed'
+};
+
+/*
+ * Describes the class of failure represented by the given sentinel value.
+ */
+function failureName(sentinel) {
+    return FAILURE_NAMES[sentinel] || FAILURE_NAMES[SENTINEL_ERROR];
+}
+
+/*
+ * Converts a stored value to a graphed one: addresses are probed on their own
+ * schedules, so a missing value (no data at this time) is skipped over, while
+ * a failure (or a round skipped while paused or in maintenance) is drawn as a
+ * gap.
+ */
+function graphValue(n) {
+    if (n >= 0) {
+        return n;
+    }
+    return n == SENTINEL_NODATA ? null : NaN;
+}
+
+// whether the server's (native) byte order, which we share, is little-endian
+const LITTLE_ENDIAN = new Uint8Array(new Uint32Array([1]).buffer)[0] == 1;
 const TARGET_KINDS = [
     {
         name: 'tcpping',
         prettyName: 'TCP Ping',
         addrsPrompt: 'Addresses (host:port) to ping',
+        columns: ['', 'dns'],
         valFormatter: function(val) {
             return (val / 1000).toFixed() + ' ms';
         }
-    }
-    /*
+    },
+    {
+        name: 'icmpping',
+        prettyName: 'ICMP Ping',
+        addrsPrompt: 'Hosts to ping',
+        columns: [''],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' ms';
+        }
+    },
     {
-        name: 'httpdownload',
-        pretty_name: 'HTTP Download',
+        name: 'httpping',
+        prettyName: 'HTTP Ping',
+        addrsPrompt: 'URLs (optionally preceded by method, e.g. HEAD) to request',
+        columns: ['', 'dns', 'connect', 'tls'],
         valFormatter: function(val) {
-            return 'NOT YET IMPLEMENTED';
+            return (val / 1000).toFixed() + ' ms';
+        }
+    },
+    {
+        name: 'dns',
+        prettyName: 'DNS Lookup',
+        addrsPrompt: 'Queries (name [type] [@resolver]) to time',
+        columns: [''],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' ms';
+        }
+    },
+    {
+        name: 'udpping',
+        prettyName: 'UDP Ping',
+        addrsPrompt: 'Addresses ([echo|dns] host:port) to ping',
+        columns: [''],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' ms';
+        }
+    },
+    {
+        name: 'traceroute',
+        prettyName: 'Traceroute',
+        addrsPrompt: 'Hosts to trace the path to',
+        columns: [''],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' ms';
+        }
+    },
+    {
+        name: 'tls',
+        prettyName: 'TLS Handshake',
+        addrsPrompt: 'Addresses (host:port) to handshake with',
+        columns: ['', 'dns', 'connect', 'expiry'],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed() + ' ms';
+        }
+    },
+    {
+        name: 'exec',
+        prettyName: 'Exec',
+        addrsPrompt: 'Commands printing the value to record',
+        columns: ['', 'runtime'],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1);
+        }
+    },
+    {
+        name: 'ntp',
+        prettyName: 'NTP Offset',
+        addrsPrompt: 'NTP servers (host[:port]) to compare the clock with',
+        columns: ['', 'delay', 'ahead', 'behind'],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' ms';
+        }
+    },
+    {
+        name: 'throughput',
+        prettyName: 'Throughput',
+        addrsPrompt: 'URLs ([download|upload [size]] URL) to transfer with',
+        columns: ['', 'ttfb', 'transfer'],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' Mbit/s';
+        }
+    },
+    {
+        name: 'speedtest',
+        prettyName: 'Speedtest',
+        addrsPrompt: 'Servers ([librespeed|ookla] [size] URL) to run speed tests against',
+        columns: ['', 'upload', 'idle', 'loaded'],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' Mbit/s';
+        }
+    },
+    {
+        name: 'quic',
+        prettyName: 'QUIC Handshake',
+        addrsPrompt: 'Addresses (host:port, or https:// URL to GET over HTTP/3) to handshake with',
+        columns: ['', 'dns', 'ttfb'],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed() + ' ms';
+        }
+    },
+    {
+        name: 'grpc',
+        prettyName: 'gRPC Health',
+        addrsPrompt: 'Servers ([grpc://]host:port[/service]) to health check',
+        columns: ['', 'dns', 'connect', 'status'],
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' ms';
         }
     }
-    */
 ];
 
+/*
+ * Columns the server derives from each round of attempts for every address,
+ * following each kind's own columns.
+ */
+const ROUND_COLUMNS = ['loss', 'jitter'];
+
+/*
+ * Gets all the columns collected for each address for the given target kind
+ * under the given options (including further aggregates, and each raw sample
+ * if they're being kept).
+ */
+function allColumns(kind, options) {
+    var columns = kind.columns.concat(ROUND_COLUMNS, options.aggregates || []);
+    if (options.raw_samples) {
+        for (let i = 1; i <= options.avg_across; i++) {
+            columns.push('sample' + i);
+        }
+    }
+    return columns;
+}
+
+/*
+ * Gets the display name of the given address (the address itself if it has
+ * none) in the given options.
+ */
+function displayName(options, addr) {
+    return (options.names && options.names[addr]) || addr;
+}
+
+/*
+ * Builds the graph series labels for the given target kind and addrs, one for
+ * each of the kind's columns for each address (matching the order of values
+ * sent by the server), by their display names.
+ */
+function seriesLabels(kind, options) {
+    var labels = [];
+    for (let addr of options.addrs) {
+        let name = displayName(options, addr);
+        for (let column of allColumns(kind, options)) {
+            labels.push(column ? name + ' (' + column + ')' : name);
+        }
+    }
+    return labels;
+}
+
+/*
+ * Determines whether the given series label is that of a loss column.
+ */
+function isLossSeries(seriesName) {
+    return seriesName.endsWith(' (loss)');
+}
+
+/*
+ * Formats a loss column value (a percentage).
+ */
+function lossFormatter(val) {
+    return val.toFixed() + '% loss';
+}
+
+/*
+ * Determines whether the given series label is that of a certificate expiry
+ * column (in days rather than microseconds).
+ */
+function isExpirySeries(seriesName) {
+    return seriesName.endsWith(' (expiry)');
+}
+
 /*
  * A self-reconnecting WebSocket that tries to re-establish a connection if it
  * becomes disconnected for whatever reason.
  */
 class SPSocket {
-    constructor(port, cb, interval) {
+    constructor(port, token, cb, interval) {
         if (!interval) {
             interval = 20000;
         }
 
-        this.addr = 'ws://' + window.location.hostname + ':' + port;
+        this.addr = 'ws://' + window.location.hostname + ':' + port + '/?token=' + encodeURIComponent(token);
         this.socket = this.newSocket(cb);
 
         setInterval(function() {
@@ -67,7 +272,7 @@ class SPSocket {
 /*
  * Performs an AJAX (XMLHttpRequest) request where
  *     - method is the HTTP verb to use (e.g. 'POST')
- *     - dest is the destination endpoint path (e.g. '/api/endpoint')
+ *     - dest is the destination endpoint path, relative to the page (e.g. 'api/endpoint')
  *     - type concerns how the response should be handled (e.g. 'json')
  *     - success is callback function that takes a response body
  *     - error is a callback funtion that takes a full error response object
@@ -155,6 +360,10 @@ class Graph extends Component {
         var gvFormatter = function(val, opts, seriesName) {
             if (seriesName == 'Time') {
                 return dateFormatter(val);
+            } else if (isLossSeries(seriesName)) {
+                return lossFormatter(val);
+            } else if (isExpirySeries(seriesName)) {
+                return val.toFixed() + ' days to expiry';
             } else {
                 return this.props.valFormatter(val);
             }
@@ -165,6 +374,7 @@ class Graph extends Component {
             [[0]],
             {
                 animatedZooms: true,
+                connectSeparatedPoints: true,
                 valueFormatter: gvFormatter,
                 valueRange: autoValueRange,
                 axes: {
@@ -173,6 +383,10 @@ class Graph extends Component {
                     },
                     y: {
                         axisLabelFormatter: this.props.valFormatter
+                    },
+                    y2: {
+                        valueRange: [0, 100],
+                        axisLabelFormatter: lossFormatter
                     }
                 },
                 isZoomedIgnoreProgrammaticZoom: true,
@@ -201,7 +415,15 @@ class Graph extends Component {
          */
         if (!this.graph.isZoomed()) {
             g.isZoomedIgnoreProgrammaticZoom = true;
-            g.labels = ['Time'].concat(this.props.options.addrs);
+            g.labels = ['Time'].concat(seriesLabels(this.props.kind, this.props.options));
+
+            // plot loss columns against their own (percentage) axis
+            g.series = {};
+            for (let label of g.labels) {
+                if (isLossSeries(label)) {
+                    g.series[label] = {axis: 'y2'};
+                }
+            }
 
             var h = hoursBack(this.props.preset);
             g.dateWindow = h == 0 ? null : [h, this.props.data.slice(-1)[0][0]];
@@ -272,7 +494,7 @@ class Options extends Component {
 
             // UI element for adjusting avg_across
             h('div', null, [
-                'Avg across',
+                'Aggregate across',
                 h('input', {
                     type: 'number',
                     value: this.state.avg_across,
@@ -281,19 +503,84 @@ class Options extends Component {
                 'values'
             ]),
 
+            // UI element for choosing the aggregate
+            h('div', null, [
+                'Aggregated as the',
+                h('select', {
+                    value: this.state.aggregate || 'mean',
+                    onChange: (evt) => this.setState({aggregate: evt.target.value})
+                }, [
+                    h('option', {value: 'mean'}, 'mean'),
+                    h('option', {value: 'median'}, 'median'),
+                    h('option', {value: 'min'}, 'minimum'),
+                    h('option', {value: 'max'}, 'maximum'),
+                    h('option', {value: 'p95'}, '95th percentile'),
+                    h('option', {value: 'p99'}, '99th percentile')
+                ])
+            ]),
+
+            // UI element for adjusting the timeout (left blank for the default)
+            h('div', null, [
+                'Give up on a value after',
+                h('input', {
+                    type: 'number',
+                    value: this.state.timeout == null ? '' : this.state.timeout / 1000,
+                    placeholder: 'default',
+                    onInput: (evt) => this.setState({
+                        timeout: evt.target.value === '' ? null : Math.round(evt.target.value * 1000)
+                    }),
+                    title: 'seconds'
+                }),
+                's'
+            ]),
+
+            // UI element for adjusting resolve_ttl
+            h('div', null, [
+                'Reuse resolved host names for',
+                h('input', {
+                    type: 'number',
+                    value: this.state.resolve_ttl / 1000,
+                    onInput: (evt) => this.setState({resolve_ttl: Math.round(evt.target.value * 1000)}),
+                    title: 'seconds'
+                }),
+                's'
+            ]),
+
+            // UI element for toggling raw_samples
+            h('div', null, [
+                h('input', {
+                    type: 'checkbox',
+                    checked: this.state.raw_samples,
+                    onChange: (evt) => this.setState({raw_samples: evt.target.checked})
+                }),
+                'Also keep every value averaged across'
+            ]),
+
+            // UI element for toggling paused
+            h('div', null, [
+                h('input', {
+                    type: 'checkbox',
+                    checked: this.state.paused,
+                    onChange: (evt) => this.setState({paused: evt.target.checked})
+                }),
+                'Paused (skip collecting data)'
+            ]),
+
             // UI elements for editing addrs
             h('div', null, [
                 this.props.kind.addrsPrompt,
                 h('ul', null, [
                     this.state.addrs.map(function(val, i, arr) {
-                        return h('li', {className: 'addr-item'}, [
+                        // with its display name, and its notes on hover
+                        var name = displayName(this.state, val);
+                        return h('li', {className: 'addr-item', title: (this.state.notes || {})[val] || ''}, [
                             h('button', {
                                 onClick: () => {
                                     arr.splice(i, 1);
                                     this.setState({addrs: arr});
                                 }
                             }, '-'),
-                            val
+                            name != val ? name + ' (' + val + ')' : val
                         ]);
                     }.bind(this))
                 ]),
@@ -334,6 +621,12 @@ class Target extends Component {
             // the lower bound on how much persisted data we have in-browser
             leftLimit: currentTime(),
 
+            /*
+             * the most recent live failure (time and sentinel) of each
+             * series that has failed since load, keyed by series label
+             */
+            failures: {},
+
             /*
              * the user-selected base time interval in terms of "hours back" of
              * how much (persisted) data to retrieve/display
@@ -353,7 +646,7 @@ class Target extends Component {
 
     componentDidMount() {
         // fetch information about this target from the server on load
-        ajax('GET', '/api/target/' + this.props.kind.name, 'json', function(res) {
+        ajax('GET', 'api/target/' + this.props.kind.name, 'json', function(res) {
             console.log('Fetched option for: ' + this.props.kind.name);
             this.setState({
                 options: res
@@ -382,18 +675,20 @@ class Target extends Component {
         var leftTarget = hoursBack(hoursPreset);
         var leftLimit = this.state.leftLimit;
 
-        var elementLength = this.state.options.addrs.length + 1;
+        var numVals = this.state.options.addrs.length * allColumns(this.props.kind, this.state.options).length;
+        // each segment is a 64-bit time followed by 32-bit values
+        var segmentBytes = 8 + 4 * numVals;
         var nonce = this.state.options.nonce;
 
         // only hit the server for the data if we don't already have it in-browser
         if (leftTarget < leftLimit) {
-            ajax('POST', '/api/target/' + this.props.kind.name, 'arraybuffer', function(res) {
+            ajax('POST', 'api/target/' + this.props.kind.name, 'arraybuffer', function(res) {
                 if (nonce == this.state.options.nonce) {
-                    // read the response from the server as a Int32 Typed Array
-                    var raw = new Int32Array(res);
+                    // read the response from the server through a DataView
+                    var raw = new DataView(res);
 
                     // pre-allocate a large buffer array that will be assimilated into this.data
-                    var newData = new Array(Math.ceil(raw.length / elementLength));
+                    var newData = new Array(Math.ceil(raw.byteLength / segmentBytes));
                     let k = 0;
 
                     /*
@@ -401,11 +696,12 @@ class Target extends Component {
                      * segments, creating a new [time, datapoint1, datapoint2, ...]
                      * array for each segment and appending it to newData.
                      */
-                    for (let j = 0; j < raw.length; j += elementLength) {
-                        let arr = new Array(elementLength);
-                        for (let i = 0; i < arr.length; i++) {
-                            let n = raw[j + i];
-                            arr[i] = n >= 0 ? n : null;
+                    for (let j = 0; j + segmentBytes <= raw.byteLength; j += segmentBytes) {
+                        let arr = new Array(numVals + 1);
+                        arr[0] = Number(raw.getBigInt64(j, LITTLE_ENDIAN));
+                        for (let i = 0; i < numVals; i++) {
+                            let n = raw.getInt32(j + 8 + 4 * i, LITTLE_ENDIAN);
+                            arr[i + 1] = graphValue(n);
                         }
                         newData[k++] = arr;
                     }
@@ -461,7 +757,16 @@ class Target extends Component {
             // diff the independent fields
             var optsChanged = newOpts.interval != curOpts.interval ||
                               newOpts.avg_across != curOpts.avg_across ||
-                              newOpts.pause != curOpts.pause;
+                              newOpts.pause != curOpts.pause ||
+                              newOpts.raw_samples != curOpts.raw_samples ||
+                              newOpts.timeout != curOpts.timeout ||
+                              newOpts.resolve_ttl != curOpts.resolve_ttl ||
+                              newOpts.paused != curOpts.paused ||
+                              newOpts.aggregate != curOpts.aggregate;
+
+            // whether the columns collected for each address changed
+            var columnsChanged = newOpts.raw_samples != curOpts.raw_samples ||
+                                 (newOpts.raw_samples && newOpts.avg_across != curOpts.avg_across);
 
             // diff the addrs
             var addrsChanged = newOpts.addrs.length != curOpts.addrs.length;
@@ -477,7 +782,7 @@ class Target extends Component {
              */
             if (optsChanged || addrsChanged) {
                 console.log('Saving options to server...');
-                ajax('PUT', '/api/target/' + this.props.kind.name, 'text', function(res) {
+                ajax('PUT', 'api/target/' + this.props.kind.name, 'text', function(res) {
                     console.log('Server accepted options update.');
                     var newNonce = parseInt(res, 10);
                     newOpts.nonce = newNonce;
@@ -485,13 +790,14 @@ class Target extends Component {
                         options: newOpts,
                         optionsMode: false
                     };
-                    if (addrsChanged) {
+                    if (addrsChanged || columnsChanged) {
                         /*
                          * invalidate the graph and all in-browser data if
-                         * addrs changed
+                         * addrs (or their columns) changed
                          */
                         this.data = null;
                         newState.leftLimit = currentTime();
+                        newState.failures = {};
                     }
 
                     // set the new options and retrieve data that may now be needed
@@ -578,7 +884,8 @@ class Target extends Component {
         }, [
             // stick the buttons with the graph title for aesthetics
             h('div', {className: 'target-head'}, [
-                h('h2', null, this.props.kind.prettyName),
+                h('h2', null, this.props.kind.prettyName +
+                    (this.state.options && this.state.options.paused ? ' (paused)' : '')),
                 h('div', {className: 'button-container'}, buttons)
             ]),
 
@@ -599,7 +906,14 @@ class Target extends Component {
              * the "controls", either graph and data controls, or the options-
              * editing UI
              */
-            h('div', {className: 'graph-controls'}, controls)
+            h('div', {className: 'graph-controls'}, controls),
+
+            // the most recent failure of each series that has failed
+            h('ul', {className: 'failures'}, Object.keys(this.state.failures).map((label) => {
+                let f = this.state.failures[label];
+                return h('li', null,
+                    label + ': ' + failureName(f.sentinel) + ' at ' + dateFormatter(f.time));
+            }))
         ]);
     }
 
@@ -624,9 +938,16 @@ class Target extends Component {
          * and append it to this.data
          */
         var arr = new Array(inArr.length);
+        var labels = seriesLabels(this.props.kind, this.state.options);
         for (let i = 0; i < arr.length; i++) {
             let n = inArr[i];
-            arr[i] = n >= 0 ? n : null;
+            arr[i] = i > 0 ? graphValue(n) : n;
+
+            // remember the class of each failure (the first element is time)
+            if (i > 0 && n < 0 && n != SENTINEL_NODATA && n != SENTINEL_PAUSED &&
+                    n != SENTINEL_MAINTENANCE) {
+                this.state.failures[labels[i - 1]] = {time: inArr[0], sentinel: n};
+            }
         }
         this.data.push(arr);
 
@@ -650,23 +971,45 @@ class App extends Component {
     }
 
     handleSocketMessage(message) {
-        // on receiving a websockets message, read it as an Int32 Typed Array
-        var buf = message.data;
-        var raw = new Int32Array(buf);
+        // on receiving a websockets message, read it through a DataView
+        var raw = new DataView(message.data);
 
         // separate the target kind and nonce from the actual data
-        var kind_id = raw[0];
-        var nonce = raw[1];
-        var arr = raw.slice(2);
+        var kind_id = raw.getInt32(0, LITTLE_ENDIAN);
+        var nonce = raw.getInt32(4, LITTLE_ENDIAN);
+
+        // the (64-bit) time, followed by the (32-bit) values
+        var arr = [Number(raw.getBigInt64(8, LITTLE_ENDIAN))];
+        for (let i = 16; i + 4 <= raw.byteLength; i += 4) {
+            arr.push(raw.getInt32(i, LITTLE_ENDIAN));
+        }
 
         // live-update the appropriate target
         this.targets[kind_id].liveDataUpdate(nonce, arr);
     }
 
+    handleRawEvent(event) {
+        // the same as a websockets message, as a JSON array [kind, nonce, time, values...]
+        var raw = JSON.parse(event.data);
+        this.targets[raw[0]].liveDataUpdate(raw[1], raw.slice(2));
+    }
+
     componentDidMount() {
-        // connect websockets on load
-        ajax('GET', '/api/config/ws_port', 'text', function(port_str) {
-            new SPSocket(port_str, this.handleSocketMessage.bind(this));
+        ajax('GET', 'api/config/ws_port', 'text', function(port_str) {
+            /*
+             * over HTTPS (there are no secure websockets), or without a
+             * websockets server, receive live data as server-sent events
+             */
+            if (window.location.protocol == 'https:' || port_str == '0') {
+                var events = new EventSource('api/live/raw');
+                events.addEventListener('raw', this.handleRawEvent.bind(this));
+                return;
+            }
+
+            // otherwise connect websockets, with the token authorizing us to connect, if auth is configured
+            ajax('GET', 'api/config/ws_token', 'text', function(token) {
+                new SPSocket(port_str, token, this.handleSocketMessage.bind(this));
+            }.bind(this));
         }.bind(this));
     }
 
@@ -11,7 +11,8 @@ from pathlib import Path
 
 SENTINEL_ERROR = -2_100_000_000
 SENTINEL_NODATA = -2_000_000_000
-RECORD_SIZE = 12  # 3 x i32 = 12 bytes
+RECORD_SIZE = 16  # i64 + 2 x i32 = 16 bytes
+LEGACY_RECORD_SIZE = 12  # 3 x i32 = 12 bytes (32-bit timestamps)
 
 
 def parse_datetime(s: str) -> datetime:
@@ -58,16 +59,21 @@ def read_index(data_dir: Path) -> list[str]:
 
 def read_data(data_dir: Path) -> list[tuple[int, int, int]]:
     """Read all (timestamp, addr_index, value) triplets from the data file."""
-    data_path = data_dir / "tcpping.data.dat"
+    data_path = data_dir / "tcpping.data64.dat"
+    record_size, record_fmt = RECORD_SIZE, "qii"
+    if not data_path.exists():
+        # fall back on a data file not yet migrated to 64-bit timestamps
+        data_path = data_dir / "tcpping.data.dat"
+        record_size, record_fmt = LEGACY_RECORD_SIZE, "iii"
     if not data_path.exists():
         sys.exit(f"Data file not found: {data_path}")
 
     raw = data_path.read_bytes()
-    if len(raw) % RECORD_SIZE != 0:
-        print(f"Warning: data file size ({len(raw)}) is not a multiple of {RECORD_SIZE}", file=sys.stderr)
+    if len(raw) % record_size != 0:
+        print(f"Warning: data file size ({len(raw)}) is not a multiple of {record_size}", file=sys.stderr)
 
-    count = len(raw) // RECORD_SIZE
-    records = struct.unpack(f"<{count * 3}i", raw[: count * RECORD_SIZE])
+    count = len(raw) // record_size
+    records = struct.unpack(f"<{record_fmt * count}", raw[: count * record_size])
     return [(records[i], records[i + 1], records[i + 2]) for i in range(0, len(records), 3)]
 
 
@@ -81,7 +87,7 @@ def dump_csv(
     records = read_data(data_dir)
 
     start_ts = int(start.timestamp()) if start else 0
-    end_ts = int(end.timestamp()) if end else 2**31 - 1
+    end_ts = int(end.timestamp()) if end else 2**63 - 1
 
     # Group records by timestamp, building {timestamp: {addr_index: value}}
     grouped: dict[int, dict[int, int]] = defaultdict(dict)
@@ -106,7 +112,7 @@ def dump_csv(
             row_vals = []
             for idx in all_indices:
                 val = grouped[ts].get(idx)
-                if val is None or val == SENTINEL_NODATA or val == SENTINEL_ERROR:
+                if val is None or val < 0:  # SENTINEL_NODATA or any error sentinel
                     row_vals.append("")
                 else:
                     row_vals.append(f"{val / 1000:.3f}")  # microseconds -> milliseconds
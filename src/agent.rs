@@ -0,0 +1,502 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Distributed agent mode. Agents (stabping instances with `agent` configured,
+ * e.g. at remote sites) push every round of results to a central stabping
+ * server as they are collected, which persists and serves them along with its
+ * own.
+ *
+ * The central server records the results of each address of a site under an
+ * address of its own, `<addr> from <site>` (e.g. `google.com:80 from nyc`),
+ * added to the same target (tagged with the site) the first time it is pushed,
+ * and never probed by its own worker. So the latency to the same address from
+ * every site shows up side by side, and can be filtered by `tag=site:<site>`.
+ *
+ * With an `agents` section, the central server only takes results from known
+ * agents: those presenting the token of their site, or a client certificate
+ * (over HTTPS, see `https.rs`) with their site among its DNS names, or an
+ * admin's credentials. Results of unknown agents are refused, or quarantined:
+ * accepted, but only noted (see `SiteStatus`) rather than recorded.
+ */
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Local;
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{same, Access, Auth};
+use crate::http;
+use crate::http::Url;
+use crate::https;
+use crate::options::{AgentConfiguration, AgentsConfiguration, TargetOptions, TargetResults, UnknownAgents,
+                     SENTINEL_NODATA, next_nonce};
+use crate::persist::{TargetManager, ManagerError};
+use crate::sink::{ResultsSink, SinkError};
+use crate::spool::{http_outcome, PushError, Spool};
+use crate::telemetry;
+use crate::webserver::round_json;
+
+/**
+ * The tag carrying the site of the addresses pushed by agents.
+ */
+pub const SITE_TAG: &str = "site";
+
+
+
+#[derive(Debug)]
+pub enum AgentError {
+    UnknownTarget(String),
+    InvalidSite,
+    Store(ManagerError),
+    Closed,  // shutting down
+}
+
+impl AgentError {
+    pub fn description(&self) -> String {
+        match *self {
+            AgentError::UnknownTarget(ref t) => format!("unknown target '{}'", t),
+            AgentError::InvalidSite => "site names may only contain letters, digits, '_', '-' and '.'".to_owned(),
+            AgentError::Store(ref e) => format!("failed to update options: {}", e),
+            AgentError::Closed => "shutting down".to_owned(),
+        }
+    }
+}
+
+impl Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+/**
+ * Returns whether the given name may be that of a site.
+ */
+pub fn valid_site(site: &str) -> bool {
+    !site.is_empty() && site.chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+}
+
+/**
+ * Gets the address the central server records the given address of the given
+ * site under.
+ */
+pub fn remote_addr(addr: &str, site: &str) -> String {
+    format!("{} from {}", addr, site)
+}
+
+/**
+ * Sink pushing all results of an agent to the central server, as JSON rounds
+ * (see `round_json`). Failed pushes are reported and dropped.
+ */
+pub struct AgentSink {
+    url: Url,
+    replay_url: Url,  // for rounds replayed after failing to push (see `spool.rs`)
+    auth: Option<String>,
+    tls: Arc<ClientConfig>,
+    managers: Vec<Arc<TargetManager>>,
+    spool: Spool,
+}
+
+impl AgentSink {
+    /**
+     * Creates a sink for the given configuration (its paths relative to the
+     * given directory), making a self-signed client certificate for the site
+     * first if asked to present one and there is none, or describes what's
+     * wrong with it.
+     */
+    pub fn new(config: AgentConfiguration, config_dir: &Path, managers: &[Arc<TargetManager>],
+               spool: Spool) -> Result<Self, String> {
+        if !valid_site(&config.site) {
+            return Err(format!("invalid site '{}'", config.site));
+        }
+        let url_str = format!("{}/api/agents/{}", config.server.trim_end_matches('/'), config.site);
+        let url = Url::parse(&url_str).ok_or_else(|| format!("invalid server '{}'", config.server))?;
+        let replay_url = Url { path: format!("{}?replayed=true", url.path), ..url.clone() };
+
+        let identity = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => {
+                let (cert, key) = (config_dir.join(cert), config_dir.join(key));
+                https::ensure_self_signed(&cert, &key, std::slice::from_ref(&config.site))?;
+                Some((cert, key))
+            },
+            (None, None) => None,
+            _ => return Err("agent needs both a cert and a key to present one".to_owned()),
+        };
+        let ca = config.ca.as_ref().map(|ca| config_dir.join(ca));
+        let tls = http::client_config(ca.as_deref(), identity.as_ref().map(|(c, k)| (c.as_path(), k.as_path())))?;
+
+        Ok(AgentSink {
+            url,
+            replay_url,
+            auth: config.token.map(|t| format!("Bearer {}", t)),
+            tls,
+            managers: managers.to_vec(),
+            spool,
+        })
+    }
+}
+
+impl ResultsSink for AgentSink {
+    fn name(&self) -> &'static str {
+        "Agent"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        let body = {
+            let manager = &self.managers[results.kind as usize];
+            let options = manager.options_read();
+            if results.nonce != options.nonce {
+                return Ok(());
+            }
+            round_json(manager.kind.compact_name(), results, &options.addrs, &manager.kind.columns(&options))
+        };
+        if body["addrs"].as_array().is_none_or(|a| a.is_empty()) {
+            return Ok(());
+        }
+
+        let mut headers = vec![("Content-Type", "application/json")];
+        headers.extend(self.auth.iter().map(|a| ("Authorization", a.as_str())));
+        let (tls, url, replay_url) = (&self.tls, &self.url, &self.replay_url);
+        self.spool.send(body.to_string().into_bytes(), |b, replayed| {
+            let url = if replayed { replay_url } else { url };
+            match http::send_with(tls.clone(), "POST", url, &headers, b, Duration::from_secs(10)) {
+                Ok(202) => Err(PushError::Reject("quarantined by the central server as an unknown agent".to_owned())),
+                outcome => http_outcome(outcome),
+            }
+        })
+    }
+}
+
+/**
+ * A round of results pushed by an agent (as sent by `AgentSink`).
+ */
+#[derive(Deserialize, Debug)]
+pub struct AgentRound {
+    pub target: String,
+    #[serde(default)]
+    pub timestamp: Option<i64>,  // when the agent collected it
+    pub addrs: Vec<AgentAddr>,
+}
+
+/**
+ * The values of an address in a round pushed by an agent, by column (the
+ * primary one named `value`).
+ */
+#[derive(Deserialize, Debug)]
+pub struct AgentAddr {
+    pub addr: String,
+    pub values: BTreeMap<String, i32>,
+}
+
+/**
+ * Which agents the central server takes results from (see
+ * `AgentsConfiguration`), and what it does with those of any other.
+ */
+pub struct AgentAuth {
+    tokens: BTreeMap<String, String>,
+    unknown: UnknownAgents,
+    enabled: bool,
+}
+
+impl AgentAuth {
+    /**
+     * Makes the checks for the given configuration (taking results from
+     * anyone authorized as an admin if there is none), or describes what's
+     * wrong with it.
+     */
+    pub fn new(config: Option<&AgentsConfiguration>) -> Result<Self, String> {
+        let config = match config {
+            Some(c) => c,
+            None => return Ok(AgentAuth { tokens: BTreeMap::new(), unknown: UnknownAgents::Reject, enabled: false }),
+        };
+        if let Some(site) = config.tokens.keys().find(|s| !valid_site(s)) {
+            return Err(format!("invalid site '{}'", site));
+        }
+        if config.tokens.values().any(|t| t.is_empty()) {
+            return Err("agent tokens may not be empty".to_owned());
+        }
+        Ok(AgentAuth {
+            tokens: config.tokens.clone(),
+            unknown: config.unknown,
+            enabled: true,
+        })
+    }
+
+    /**
+     * Whether an agent of the given site, pushing with the given
+     * `Authorization` header (if any) and a client certificate with the
+     * given DNS names, is known.
+     */
+    pub fn knows(&self, auth: &Auth, site: &str, authorization: Option<&str>, cert_names: &[String]) -> bool {
+        let admin = auth.access(authorization) == Some(Access::Admin);
+        if !self.enabled {
+            return admin;
+        }
+        let token = authorization
+            .and_then(|a| a.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, t)| t.trim());
+        (auth.enabled() && admin)
+            || cert_names.iter().any(|n| n == site)
+            || self.tokens.get(site).zip(token).is_some_and(|(t, given)| same(t.as_bytes(), given.as_bytes()))
+    }
+
+    /**
+     * Whether results of unknown agents are quarantined (rather than
+     * refused).
+     */
+    pub fn quarantines(&self) -> bool {
+        self.unknown == UnknownAgents::Quarantine
+    }
+}
+
+/**
+ * What the central server has seen of a site of agents.
+ */
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SiteStatus {
+    pub last_seen: i64,  // when it last pushed a round, as a timestamp
+    pub rounds: u64,  // rounds pushed since the central server started
+    pub quarantined: bool,  // whether its rounds were last set aside as those of an unknown agent
+    pub addrs: BTreeSet<String>,  // the addresses pushed, as `<kind> <addr>`
+}
+
+/**
+ * Adds the given addresses of the given site (see `remote_addr`) that aren't
+ * yet in the given options to them, tagged with the site, returning whether
+ * any were.
+ */
+fn add_remote_addrs(options: &mut TargetOptions, addrs: &[String], site: &str) -> bool {
+    let mut added = false;
+    for addr in addrs {
+        if options.is_remote(addr) && options.addrs.contains(addr) {
+            continue;
+        }
+        if !options.addrs.contains(addr) {
+            options.addrs.push(addr.clone());
+        }
+        options.sites.insert(addr.clone(), site.to_owned());
+        options.tags.entry(addr.clone()).or_default().entry(SITE_TAG.to_owned()).or_insert_with(|| site.to_owned());
+        added = true;
+    }
+    added
+}
+
+/**
+ * Lays out the values of the given addresses (by column, see `AgentAddr`) in
+ * results under the given options, missing (`SENTINEL_NODATA`) for every other
+ * address and column.
+ */
+fn layout(options: &TargetOptions, columns: &[String], pushed: &[(String, &BTreeMap<String, i32>)]) -> Vec<i32> {
+    let mut vals = vec![SENTINEL_NODATA; options.addrs.len() * columns.len()];
+    for (addr, values) in pushed {
+        let slot = match options.addrs.iter().position(|a| a == addr) {
+            Some(s) => s,
+            None => continue,
+        };
+        for (v, column) in vals[slot * columns.len()..].iter_mut().zip(columns) {
+            let name = if column.is_empty() { "value" } else { column.as_str() };
+            if let Some(&val) = values.get(name) {
+                *v = val;
+            }
+        }
+    }
+    vals
+}
+
+/**
+ * The intake of the central server, taking in the rounds pushed by agents and
+ * sending them off to the main thread like those of the workers.
+ */
+pub struct Aggregator {
+    managers: Vec<Arc<TargetManager>>,
+    results_out: Mutex<Option<Sender<TargetResults>>>,
+    adding: Mutex<()>,  // held while adding addresses, so that sites pushing at once don't race
+    agents: AgentAuth,
+    sites: Mutex<BTreeMap<String, SiteStatus>>,
+}
+
+impl Aggregator {
+    pub fn new(managers: &[Arc<TargetManager>], results_out: Sender<TargetResults>, agents: AgentAuth) -> Self {
+        Aggregator {
+            managers: managers.to_vec(),
+            results_out: Mutex::new(Some(results_out)),
+            adding: Mutex::new(()),
+            agents,
+            sites: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /**
+     * Which agents it takes results from.
+     */
+    pub fn agents(&self) -> &AgentAuth {
+        &self.agents
+    }
+
+    /**
+     * Gets what it has seen of each site.
+     */
+    pub fn sites(&self) -> BTreeMap<String, SiteStatus> {
+        self.sites.lock().unwrap().clone()
+    }
+
+    /**
+     * Notes the given round of the given site as seen.
+     */
+    fn note(&self, site: &str, round: &AgentRound, quarantined: bool) {
+        let mut sites = self.sites.lock().unwrap();
+        let status = sites.entry(site.to_owned()).or_default();
+        status.last_seen = Local::now().timestamp();
+        status.rounds += 1;
+        status.quarantined = quarantined;
+        status.addrs.extend(round.addrs.iter().map(|a| format!("{} {}", round.target, a.addr)));
+    }
+
+    /**
+     * Sets aside the given round of the given (unknown) site, only noting it.
+     */
+    pub fn quarantine(&self, site: &str, round: AgentRound) -> Result<(), AgentError> {
+        if !valid_site(site) {
+            return Err(AgentError::InvalidSite);
+        }
+        if !self.sites.lock().unwrap().get(site).is_some_and(|s| s.quarantined) {
+            warn!("Quarantining results of unknown agent of site {}.", site);
+        }
+        self.note(site, &round, true);
+        Ok(())
+    }
+
+    /**
+     * Stops taking in rounds (on shutdown), so that the main thread stops once
+     * the workers do.
+     */
+    pub fn close(&self) {
+        self.results_out.lock().unwrap().take();
+    }
+
+    /**
+     * Takes in the given round of the given site, adding its addresses to the
+     * target if they are new. Live rounds are timestamped as they are received
+     * (so that the target's results stay in order of time whatever the agents'
+     * clocks say) and sent off like those of the workers, while those replayed
+     * (after the agent failed to push them) are merged straight into the
+     * target's data at the time they were collected. Returns the addresses it
+     * is recorded under.
+     */
+    pub fn receive(&self, site: &str, round: AgentRound, replayed: bool) -> Result<Vec<String>, AgentError> {
+        if !valid_site(site) {
+            return Err(AgentError::InvalidSite);
+        }
+        let tm = self.managers.iter()
+            .find(|tm| tm.kind.compact_name() == round.target)
+            .ok_or_else(|| AgentError::UnknownTarget(round.target.clone()))?;
+        let pushed: Vec<(String, &BTreeMap<String, i32>)> = round.addrs.iter()
+            .map(|a| (remote_addr(&a.addr, site), &a.values))
+            .collect();
+        let addrs: Vec<String> = pushed.iter().map(|(a, _)| a.clone()).collect();
+        self.note(site, &round, false);
+
+        {
+            let _adding = self.adding.lock().unwrap();
+            let mut new_options = tm.options_read().clone();
+            if add_remote_addrs(&mut new_options, &addrs, site) {
+                info!("Adding {} of site {} to {}.", addrs.join(", "), site, tm.kind.compact_name());
+                new_options.nonce = next_nonce(new_options.nonce);
+                tm.options_update(new_options).map_err(AgentError::Store)?;
+            }
+        }
+
+        let late = round.timestamp.filter(|_| replayed);
+        let results = {
+            let options = tm.options_read();
+            TargetResults {
+                kind: tm.kind.kind_id(),
+                nonce: options.nonce,
+                timestamp: late.unwrap_or_else(|| Local::now().timestamp()),
+                vals: layout(&options, &tm.kind.columns(&options), &pushed),
+            }
+        };
+        if late.is_some() {
+            tm.merge_results(&results).map_err(AgentError::Store)?;
+            return Ok(addrs);
+        }
+        let out = self.results_out.lock().unwrap();
+        let out = out.as_ref().ok_or(AgentError::Closed)?;
+        telemetry::result_sending();
+        if out.send(results).is_err() {
+            telemetry::result_received();
+            return Err(AgentError::Closed);
+        }
+        Ok(addrs)
+    }
+}
+
+#[test]
+fn rounds_of_sites_are_laid_out_under_their_own_addrs() {
+    let mut opt = crate::tcpping::KIND.default_options();
+    opt.addrs = vec!["google.com:80".to_owned()];
+    let addrs = vec![remote_addr("google.com:80", "nyc"), remote_addr("8.8.8.8:53", "nyc")];
+    assert!(add_remote_addrs(&mut opt, &addrs, "nyc"));
+    assert!(!add_remote_addrs(&mut opt, &addrs, "nyc"));
+    assert_eq!(opt.addrs, vec!["google.com:80", "google.com:80 from nyc", "8.8.8.8:53 from nyc"]);
+    assert!(opt.is_remote("8.8.8.8:53 from nyc") && !opt.is_remote("google.com:80"));
+    assert_eq!(opt.tags["google.com:80 from nyc"][SITE_TAG], "nyc");
+
+    let columns: Vec<String> = vec!["".to_owned(), "dns".to_owned(), "loss".to_owned()];
+    let values: BTreeMap<String, i32> = [("value", 1500), ("loss", 0), ("jitter", 20)].iter()
+        .map(|&(k, v)| (k.to_owned(), v))
+        .collect();
+    assert_eq!(layout(&opt, &columns, &[(addrs[1].clone(), &values)]),
+               vec![SENTINEL_NODATA, SENTINEL_NODATA, SENTINEL_NODATA,
+                    SENTINEL_NODATA, SENTINEL_NODATA, SENTINEL_NODATA,
+                    1500, SENTINEL_NODATA, 0]);
+
+    // declared addresses leave those pushed by agents be
+    let declared: crate::options::TargetDeclaration = toml::from_str(r#"addrs = ["example.com:80"]"#).unwrap();
+    let opt = declared.apply_to(&opt).unwrap();
+    assert_eq!(opt.addrs, vec!["example.com:80", "google.com:80 from nyc", "8.8.8.8:53 from nyc"]);
+
+    assert!(valid_site("nyc-1") && !valid_site("new york") && !valid_site(""));
+}
+
+#[test]
+fn agents_are_known_by_their_token_certificate_or_an_admin() {
+    use crate::options::AuthConfiguration;
+
+    let open = Auth::new(None).unwrap();
+    let auth = Auth::new(Some(&AuthConfiguration { tokens: vec!["admin".to_owned()], ..Default::default() })).unwrap();
+
+    // without an agents section, anyone authorized as an admin is
+    let agents = AgentAuth::new(None).unwrap();
+    assert!(agents.knows(&open, "nyc", None, &[]));
+    assert!(agents.knows(&auth, "nyc", Some("Bearer admin"), &[]));
+    assert!(!agents.knows(&auth, "nyc", None, &["nyc".to_owned()]));
+
+    let config: AgentsConfiguration = toml::from_str(r#"
+        tokens = { nyc = "nyc-token" }
+        unknown = "quarantine"
+    "#).unwrap();
+    let agents = AgentAuth::new(Some(&config)).unwrap();
+    assert!(agents.quarantines());
+    assert!(agents.knows(&open, "nyc", Some("Bearer nyc-token"), &[]));
+    assert!(!agents.knows(&open, "lon", Some("Bearer nyc-token"), &[]));
+    assert!(!agents.knows(&open, "nyc", None, &[]));
+    assert!(agents.knows(&open, "lon", None, &["lon".to_owned()]));
+    assert!(!agents.knows(&open, "lon", None, &["nyc".to_owned()]));
+    assert!(agents.knows(&auth, "lon", Some("Bearer admin"), &[]));
+
+    let config: AgentsConfiguration = toml::from_str(r#"tokens = { "new york" = "x" }"#).unwrap();
+    assert!(AgentAuth::new(Some(&config)).is_err());
+}
@@ -0,0 +1,677 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Evaluation of the alert rules of every target against each round of
+ * results, keeping track of (and persisting) which alerts are firing.
+ * `anomalous` rules compare rounds with learned baselines (see `anomaly.rs`).
+ *
+ * Alerts of addresses whose parents (see `depends_on`) are down still change
+ * state, but aren't notified of while they are, so that e.g. a router
+ * rebooting doesn't raise an alert storm for every host behind it. Likewise
+ * for alerts silenced (see `silences.rs`), and acknowledged alerts aren't
+ * notified of firing again until they have resolved.
+ */
+use std::collections::{HashMap, HashSet};
+use std::fs::{OpenOptions, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+
+use crate::anomaly::Baselines;
+use crate::helpers::{SPIOError, SPFile, overwrite_json};
+use crate::history::{AlertEvent, AlertHistory, Transition};
+use crate::incidents::Incidents;
+use crate::options::{TargetResults, AlertRule, AlertCondition, CompositeRule};
+use crate::persist::TargetManager;
+use crate::notify::Notifications;
+use crate::silences::{Silence, SilenceRequest, Silences};
+use crate::sink::{ResultsSink, SinkError};
+
+/**
+ * The target of the alerts of composite rules, whose addr lists the members
+ * down as of the alert's state, and whose `down` column counts them.
+ */
+pub const COMPOSITE_TARGET: &str = "composite";
+pub const DOWN_COLUMN: &str = "down";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+/**
+ * An acknowledgement (over the API) of a firing alert.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Acknowledgement {
+    pub at: i64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+}
+
+/**
+ * An acknowledgement as requested over the API, of the alert of the given
+ * rule of the given target for the given address (any, if `None`, as for
+ * composite rules).
+ */
+#[derive(Deserialize, Debug, Clone)]
+pub struct AckRequest {
+    pub target: String,
+    #[serde(default)]
+    pub addr: Option<String>,
+    pub rule: String,
+    #[serde(default)]
+    pub comment: String,
+}
+
+/**
+ * The current state of a single rule of a target for a single address.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alert {
+    pub target: String,
+    pub addr: String,
+    pub rule: String,
+    #[serde(default)]
+    pub column: String,  // column watched by the rule
+    pub state: AlertState,
+    pub since: i64,  // time of the round that put the alert in its current state
+    pub value: i32,  // value of the watched column in that round
+    #[serde(default)]
+    pub fired: i64,  // time of the round that (last) fired the alert
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<i32>,  // what the baseline of an `anomalous` rule expected of that round
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub season: Option<String>,  // the hour of the week a seasonal baseline expected it for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notified: Option<AlertState>,  // the state last notified of (see `renotify_interval`)
+    #[serde(default)]
+    pub notified_at: i64,  // time of the round it was notified in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suppressed_by: Option<String>,  // the parent found down as it entered its state, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acknowledged: Option<Acknowledgement>,  // until its resolution is notified of
+}
+
+/**
+ * Alerts ever raised (firing or since resolved), along with the number of
+ * consecutive breaching (and then of consecutive clear) rounds of every
+ * (target, addr, rule), and the baselines of `anomalous` rules.
+ */
+#[derive(Default)]
+struct AlertBook {
+    alerts: Vec<Alert>,
+    streaks: HashMap<(String, String, String), (u32, u32)>,
+    baselines: Baselines,
+}
+
+impl AlertBook {
+    /**
+     * Evaluates the given rule against the given value of the watched column
+     * in a round of the given address (suppressed by the given parent being
+     * down, if any), returning its alert if this changed its state. Rounds
+     * without a value leave everything as is.
+     */
+    fn evaluate(&mut self, target: &str, addr: &str, rule: &AlertRule,
+                val: i32, time: i64, suppressed_by: Option<&str>) -> Option<Alert> {
+        if val < 0 {
+            return None;
+        }
+
+        let mut deviation = None;
+        let breached = match rule.when {
+            AlertCondition::Above => val > rule.threshold,
+            AlertCondition::Below => val < rule.threshold,
+            AlertCondition::Anomalous => {
+                deviation = self.baselines.observe(target, addr, &rule.name, rule.baseline, val, time);
+                deviation.as_ref().is_some_and(|d| d.sigmas > rule.sigmas && val - d.expected > rule.threshold)
+            },
+        };
+
+        let key = (target.to_owned(), addr.to_owned(), rule.name.clone());
+        let (breaching, clear) = {
+            let s = self.streaks.entry(key).or_insert((0, 0));
+            *s = if breached { (s.0 + 1, 0) } else { (0, s.1 + 1) };
+            *s
+        };
+
+        let existing = self.alerts.iter_mut()
+            .find(|a| a.target == target && a.addr == addr && a.rule == rule.name);
+        let firing = existing.as_ref().is_some_and(|a| a.state == AlertState::Firing);
+
+        let new_state = if !firing && breaching >= rule.rounds.max(1) {
+            AlertState::Firing
+        } else if firing && clear >= rule.resolve_rounds.max(1) {
+            AlertState::Resolved
+        } else {
+            return None;
+        };
+
+        info!("Alert '{}' of {} for {} is now {:?} (value {}).",
+              rule.name, target, addr, new_state, val);
+        let alert = match existing {
+            Some(a) => a,
+            None => {
+                self.alerts.push(Alert {
+                    target: target.to_owned(),
+                    addr: addr.to_owned(),
+                    rule: rule.name.clone(),
+                    column: rule.column.clone(),
+                    state: new_state,
+                    since: time,
+                    value: val,
+                    fired: time,
+                    expected: None,
+                    season: None,
+                    notified: None,
+                    notified_at: 0,
+                    suppressed_by: None,
+                    acknowledged: None,
+                });
+                self.alerts.last_mut().unwrap()
+            },
+        };
+        alert.column = rule.column.clone();
+        alert.suppressed_by = suppressed_by.map(|p| p.to_owned());
+        alert.state = new_state;
+        alert.since = time;
+        alert.value = val;
+        alert.expected = deviation.as_ref().map(|d| d.expected);
+        alert.season = deviation.and_then(|d| d.season);
+        if new_state == AlertState::Firing {
+            alert.fired = time;
+        }
+        Some(alert.clone())
+    }
+
+    /**
+     * Evaluates the given composite rule given its members down as of a
+     * round at the given time, returning its alert if this changed its state.
+     */
+    fn evaluate_composite(&mut self, rule: &CompositeRule, down: Vec<String>, time: i64) -> Option<Alert> {
+        let existing = self.alerts.iter_mut().find(|a| a.target == COMPOSITE_TARGET && a.rule == rule.name);
+        let firing = existing.as_ref().is_some_and(|a| a.state == AlertState::Firing);
+        let new_state = match (firing, down.len() as u32 >= rule.at_least.max(1)) {
+            (false, true) => AlertState::Firing,
+            (true, false) => AlertState::Resolved,
+            _ => return None,
+        };
+
+        info!("Composite alert '{}' is now {:?} ({} down).", rule.name, new_state, down.len());
+        let alert = match existing {
+            Some(a) => a,
+            None => {
+                self.alerts.push(Alert {
+                    target: COMPOSITE_TARGET.to_owned(),
+                    addr: String::new(),
+                    rule: rule.name.clone(),
+                    column: DOWN_COLUMN.to_owned(),
+                    state: new_state,
+                    since: time,
+                    value: 0,
+                    fired: time,
+                    expected: None,
+                    season: None,
+                    notified: None,
+                    notified_at: 0,
+                    suppressed_by: None,
+                    acknowledged: None,
+                });
+                self.alerts.last_mut().unwrap()
+            },
+        };
+        alert.state = new_state;
+        alert.since = time;
+        alert.value = down.len() as i32;
+        if new_state == AlertState::Firing {
+            alert.addr = down.join(", ");
+            alert.fired = time;
+        }
+        Some(alert.clone())
+    }
+
+    /**
+     * Returns the alert of the given rule (of the given target) for the given
+     * address (any, if `None`) if it is due to be notified of as of a round at
+     * the given time: if its state isn't the one last notified of (resolved,
+     * if none yet), and the last notification was at least the given
+     * `renotify_interval` ago. Changes within the interval are thereby
+     * notified of (if they still stand) once it has passed. Acknowledged
+     * alerts firing never are.
+     */
+    fn due(&mut self, target: &str, addr: Option<&str>, rule: &str, renotify_interval: u32,
+           time: i64) -> Option<Alert> {
+        let alert = self.alerts.iter_mut()
+            .find(|a| a.target == target && addr.is_none_or(|addr| a.addr == addr) && a.rule == rule)?;
+        if alert.notified.unwrap_or(AlertState::Resolved) == alert.state ||
+            (alert.notified.is_some() && time - alert.notified_at < renotify_interval as i64) ||
+            (alert.acknowledged.is_some() && alert.state == AlertState::Firing) {
+            return None;
+        }
+        if alert.state == AlertState::Resolved {
+            alert.acknowledged = None;
+        }
+        alert.notified = Some(alert.state);
+        alert.notified_at = time;
+        Some(alert.clone())
+    }
+}
+
+/**
+ * Alerts of all targets (and composite rules), persisted to `alerts.json` in
+ * the data directory, with notifications sent whenever one changes state, and
+ * every change recorded in the given history.
+ */
+pub struct Alerts {
+    managers: Vec<Arc<TargetManager>>,
+    composites: Vec<CompositeRule>,
+    path: PathBuf,
+    book: Mutex<AlertBook>,
+    silences: Mutex<Silences>,
+    notifications: Notifications,
+    history: Arc<AlertHistory>,
+}
+
+impl Alerts {
+    /**
+     * Creates the alerts of the given targets and composite rules, reading
+     * back any persisted from the given data directory, sending the given
+     * notifications and recording to the given history.
+     */
+    pub fn new(managers: &[Arc<TargetManager>], composites: &[CompositeRule], data_path: &Path,
+               notifications: Notifications, history: Arc<AlertHistory>) -> Result<Self, SPIOError> {
+        let path = data_path.join("alerts.json");
+        let mut alerts: Vec<Alert> = if path.exists() {
+            File::open_from(OpenOptions::new().read(true), &path)?.read_json_p(&path)?
+        } else {
+            Vec::new()
+        };
+        // alerts persisted by earlier versions were notified of as they changed
+        for a in alerts.iter_mut().filter(|a| a.notified.is_none()) {
+            a.notified = Some(a.state);
+            a.notified_at = a.since;
+        }
+        // forget alerts of composite rules no longer configured
+        alerts.retain(|a| a.target != COMPOSITE_TARGET || composites.iter().any(|r| r.name == a.rule));
+
+        Ok(Alerts {
+            managers: managers.to_vec(),
+            composites: composites.to_vec(),
+            path,
+            book: Mutex::new(AlertBook {
+                alerts,
+                streaks: HashMap::new(),
+                baselines: Baselines::load(data_path)?,
+            }),
+            silences: Mutex::new(Silences::load(data_path)?),
+            notifications,
+            history,
+        })
+    }
+
+    /**
+     * Evaluates the rules of a target against the given live-collected data
+     * (`TargetResults`) of it, given the addresses (as `<target>/<addr>`)
+     * down, persisting any resulting changes.
+     */
+    pub fn record(&self, data_res: &TargetResults, down: &HashSet<String>) -> Result<(), SPIOError> {
+        let manager = &self.managers[data_res.kind as usize];
+        let options = manager.options_read();
+        if data_res.nonce != options.nonce {
+            return Ok(());
+        }
+
+        let target = manager.kind.compact_name();
+        let columns = manager.kind.columns(&options);
+        let watchable = &columns[..manager.kind.probe_columns().len() + 1];
+
+        let mut book = self.book.lock().unwrap();
+        let silences = self.silences.lock().unwrap();
+
+        // forget alerts of addresses and rules no longer in the options
+        let before = book.alerts.len();
+        book.alerts.retain(|a| {
+            a.target != target ||
+                (options.addrs.contains(&a.addr) && options.alerts.iter().any(|r| r.name == a.rule))
+        });
+        let mut changed = book.alerts.len() != before;
+        book.baselines.retain(target, |addr, rule| {
+            options.addrs.iter().any(|a| a == addr) && options.alerts.iter().any(|r| r.name == rule)
+        });
+
+        for (addr, vals) in options.addrs.iter().zip(data_res.vals.chunks(columns.len())) {
+            let suppressed_by = options.parents_of(target, addr).find(|p| down.contains(*p));
+            let silenced = silences.silences(target, options.tags_of(addr), data_res.timestamp);
+            for rule in options.alerts.iter() {
+                // rules watching columns this target doesn't have never fire
+                if let Some(i) = watchable.iter().position(|c| *c == rule.column) {
+                    let suppressed_by = suppressed_by.map(|p| p.as_str());
+                    if let Some(alert) = book.evaluate(target, addr, rule, vals[i], data_res.timestamp,
+                                                       suppressed_by) {
+                        self.history.record(&Transition::changed(&alert));
+                        changed = true;
+                    }
+                    if suppressed_by.is_some() || silenced {
+                        continue;
+                    }
+                    if let Some(alert) = book.due(target, Some(addr), &rule.name, rule.renotify_interval,
+                                                  data_res.timestamp) {
+                        self.notifications.send(&alert);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        if changed {
+            overwrite_json(&book.alerts, &self.path)?;
+        }
+        book.baselines.save(false)
+    }
+
+    /**
+     * Evaluates the composite rules given which addresses (as target and
+     * addr) are down as of a round at the given time, persisting any
+     * resulting changes.
+     */
+    pub fn record_composites(&self, down: &[(String, String)], time: i64) -> Result<(), SPIOError> {
+        if self.composites.is_empty() {
+            return Ok(());
+        }
+        let mut members_down = vec![Vec::new(); self.composites.len()];
+        for manager in self.managers.iter() {
+            let target = manager.kind.compact_name();
+            let options = manager.options_read();
+            for (_, addr) in down.iter().filter(|(t, _)| t == target) {
+                for (rule, members) in self.composites.iter().zip(members_down.iter_mut()) {
+                    if rule.has_member(target, addr, options.tags_of(addr)) {
+                        members.push(format!("{}/{}", target, addr));
+                    }
+                }
+            }
+        }
+
+        let mut book = self.book.lock().unwrap();
+        let silenced = self.silences.lock().unwrap().silences(COMPOSITE_TARGET, None, time);
+        let mut changed = false;
+        for (rule, members) in self.composites.iter().zip(members_down) {
+            if let Some(alert) = book.evaluate_composite(rule, members, time) {
+                self.history.record(&Transition::changed(&alert));
+                changed = true;
+            }
+            if silenced {
+                continue;
+            }
+            if let Some(alert) = book.due(COMPOSITE_TARGET, None, &rule.name, rule.renotify_interval, time) {
+                self.notifications.send(&alert);
+                changed = true;
+            }
+        }
+        if changed {
+            overwrite_json(&book.alerts, &self.path)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Persists the learned baselines (e.g. on shutdown).
+     */
+    pub fn save_baselines(&self) -> Result<(), SPIOError> {
+        self.book.lock().unwrap().baselines.save(true)
+    }
+
+    /**
+     * Returns all alerts, optionally only those of the given target and/or in
+     * the given state.
+     */
+    pub fn list(&self, target: Option<&str>, state: Option<AlertState>) -> Vec<Alert> {
+        self.book.lock().unwrap().alerts.iter()
+            .filter(|a| target.is_none_or(|t| a.target == t))
+            .filter(|a| state.is_none_or(|s| a.state == s))
+            .cloned()
+            .collect()
+    }
+
+    /**
+     * Returns the history of alerts from `from` to `to` (inclusive),
+     * optionally only of the given target.
+     */
+    pub fn history(&self, from: i64, to: i64, target: Option<&str>) -> Result<Vec<Transition>, SPIOError> {
+        self.history.query(from, to, target)
+    }
+
+    /**
+     * Acknowledges the firing alert requested at the given time, returning
+     * it, or `None` if there is no such alert.
+     */
+    pub fn acknowledge(&self, request: AckRequest, time: i64) -> Result<Option<Alert>, SPIOError> {
+        let mut book = self.book.lock().unwrap();
+        let alert = book.alerts.iter_mut().find(|a| {
+            a.target == request.target && request.addr.as_ref().is_none_or(|addr| a.addr == *addr) &&
+                a.rule == request.rule && a.state == AlertState::Firing
+        });
+        let alert = match alert {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+        info!("Alert '{}' of {} for {} acknowledged.", alert.rule, alert.target, alert.addr);
+        self.history.record(&Transition {
+            comment: request.comment.clone(),
+            ..Transition::of(alert, AlertEvent::Acknowledged, time)
+        });
+        alert.acknowledged = Some(Acknowledgement {
+            at: time,
+            comment: request.comment,
+        });
+        let alert = alert.clone();
+        overwrite_json(&book.alerts, &self.path)?;
+        Ok(Some(alert))
+    }
+
+    /**
+     * Adds a silence as requested at the given time, returning it, or `None`
+     * if it would silence everything (see `Silences::add`).
+     */
+    pub fn silence(&self, request: SilenceRequest, time: i64) -> Result<Option<Silence>, SPIOError> {
+        self.silences.lock().unwrap().add(request, time)
+    }
+
+    /**
+     * Ends the silence of the given id, returning whether there was one.
+     */
+    pub fn unsilence(&self, id: u32) -> Result<bool, SPIOError> {
+        self.silences.lock().unwrap().remove(id)
+    }
+
+    /**
+     * Returns the silences not yet over at the given time.
+     */
+    pub fn silences(&self, time: i64) -> Vec<Silence> {
+        self.silences.lock().unwrap().list(time)
+    }
+}
+
+/**
+ * Sink evaluating all results against the shared `Alerts`, given the
+ * addresses the shared `Incidents` have down.
+ */
+pub struct AlertsSink {
+    alerts: Arc<Alerts>,
+    incidents: Arc<Incidents>,
+}
+
+impl AlertsSink {
+    pub fn new(alerts: Arc<Alerts>, incidents: Arc<Incidents>) -> Self {
+        AlertsSink {
+            alerts,
+            incidents,
+        }
+    }
+}
+
+impl ResultsSink for AlertsSink {
+    fn name(&self) -> &'static str {
+        "Alerts"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        let down = self.incidents.down().into_iter().map(|(t, a)| format!("{}/{}", t, a)).collect();
+        self.alerts.record(results, &down)
+            .map_err(|e| SinkError::Dropped(e.description()))
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.alerts.save_baselines()
+            .map_err(|e| SinkError::Dropped(e.description()))
+    }
+}
+
+#[test]
+fn alert_fires_after_consecutive_rounds_and_resolves() {
+    let rule = AlertRule {
+        name: "slow".to_owned(),
+        column: "".to_owned(),
+        when: AlertCondition::Above,
+        threshold: 200_000,
+        rounds: 2,
+        resolve_rounds: 1,
+        renotify_interval: 0,
+        baseline: Default::default(),
+        sigmas: 3.0,
+    };
+    let mut book = AlertBook::default();
+
+    assert!(book.evaluate("tcpping", "a:80", &rule, 300_000, 1, None).is_none());
+    assert!(book.evaluate("tcpping", "a:80", &rule, -2_100_000_005, 2, None).is_none());
+    let fired = book.evaluate("tcpping", "a:80", &rule, 250_000, 3, None).unwrap();
+    assert_eq!(fired.state, AlertState::Firing);
+    assert!(book.evaluate("tcpping", "a:80", &rule, 400_000, 4, None).is_none());
+    let resolved = book.evaluate("tcpping", "a:80", &rule, 1000, 5, None).unwrap();
+    assert_eq!(resolved.state, AlertState::Resolved);
+    assert_eq!((resolved.since, resolved.fired), (5, 3));
+}
+
+#[test]
+fn flapping_alerts_resolve_after_clear_rounds_and_notify_once_in_a_while() {
+    let rule = AlertRule {
+        name: "slow".to_owned(),
+        column: "".to_owned(),
+        when: AlertCondition::Above,
+        threshold: 200_000,
+        rounds: 2,
+        resolve_rounds: 3,
+        renotify_interval: 600,
+        baseline: Default::default(),
+        sigmas: 3.0,
+    };
+    let mut book = AlertBook::default();
+    let mut notified = Vec::new();
+    // a borderline link, breaching every other round but twice in a row now and then
+    let vals = [300_000, 300_000, 100, 300_000, 100, 100, 100, 300_000, 300_000, 100, 100, 100];
+    for (i, &val) in vals.iter().enumerate() {
+        let time = i as i64 * 60;
+        book.evaluate("tcpping", "a:80", &rule, val, time, None);
+        if let Some(alert) = book.due("tcpping", Some("a:80"), &rule.name, rule.renotify_interval, time) {
+            notified.push((alert.state, time));
+        }
+    }
+    // fired at 60, then resolved at 360, fired at 480 and resolved at 660, the
+    // last notified of once the interval passed
+    assert_eq!(notified, vec![(AlertState::Firing, 60), (AlertState::Resolved, 660)]);
+    assert!(book.due("tcpping", Some("a:80"), &rule.name, rule.renotify_interval, 2000).is_none());
+}
+
+#[test]
+fn composite_alerts_fire_once_enough_members_are_down() {
+    let rule: CompositeRule = toml::from_str(r#"
+        name = "wan"
+        members = ["tcpping/google.com:80", "icmpping/8.8.8.8"]
+        group = "references"
+        at_least = 2
+    "#).unwrap();
+    let mut tags = crate::tags::Tags::new();
+    tags.insert("group".to_owned(), "references".to_owned());
+    assert!(rule.has_member("tcpping", "google.com:80", None));
+    assert!(rule.has_member("tcpping", "1.1.1.1:53", Some(&tags)));
+    assert!(!rule.has_member("icmpping", "google.com:80", None));
+
+    let mut book = AlertBook::default();
+    assert!(book.evaluate_composite(&rule, vec!["icmpping/8.8.8.8".to_owned()], 10).is_none());
+    let down = vec!["icmpping/8.8.8.8".to_owned(), "tcpping/google.com:80".to_owned()];
+    let fired = book.evaluate_composite(&rule, down, 20).unwrap();
+    assert_eq!((fired.state, fired.value), (AlertState::Firing, 2));
+    assert_eq!(fired.addr, "icmpping/8.8.8.8, tcpping/google.com:80");
+    assert!(book.due(COMPOSITE_TARGET, None, "wan", 0, 20).is_some());
+    let resolved = book.evaluate_composite(&rule, vec!["tcpping/google.com:80".to_owned()], 30).unwrap();
+    assert_eq!((resolved.state, resolved.fired, resolved.addr.len()), (AlertState::Resolved, 20, fired.addr.len()));
+}
+
+#[test]
+fn alerts_of_children_of_parents_down_are_never_notified() {
+    let options: crate::options::TargetOptions = serde_json::from_value(serde_json::json!({
+        "nonce": 0,
+        "addrs": ["router:80", "a:80"],
+        "interval": 60000,
+        "avg_across": 1,
+        "pause": 0,
+        "depends_on": ["tcpping/router:80"],
+    })).unwrap();
+    assert_eq!(options.parents_of("tcpping", "a:80").collect::<Vec<_>>(), vec!["tcpping/router:80"]);
+    assert_eq!(options.parents_of("tcpping", "router:80").count(), 0);
+
+    let rule = AlertRule {
+        name: "slow".to_owned(),
+        column: "".to_owned(),
+        when: AlertCondition::Above,
+        threshold: 200_000,
+        rounds: 1,
+        resolve_rounds: 1,
+        renotify_interval: 0,
+        baseline: Default::default(),
+        sigmas: 3.0,
+    };
+    let mut book = AlertBook::default();
+    // fired and resolved while the router was down, as record skips due then
+    let fired = book.evaluate("tcpping", "a:80", &rule, 300_000, 1, Some("tcpping/router:80")).unwrap();
+    assert_eq!(fired.suppressed_by.as_deref(), Some("tcpping/router:80"));
+    assert!(book.evaluate("tcpping", "a:80", &rule, 100, 2, None).is_some());
+    assert!(book.due("tcpping", Some("a:80"), &rule.name, 0, 3).is_none());
+}
+
+#[test]
+fn acknowledged_alerts_are_not_notified_of_firing_until_resolved() {
+    let rule = AlertRule {
+        name: "slow".to_owned(),
+        column: "".to_owned(),
+        when: AlertCondition::Above,
+        threshold: 200_000,
+        rounds: 1,
+        resolve_rounds: 1,
+        renotify_interval: 600,
+        baseline: Default::default(),
+        sigmas: 3.0,
+    };
+    let mut book = AlertBook::default();
+    book.evaluate("tcpping", "a:80", &rule, 300_000, 0, None);
+    assert!(book.due("tcpping", Some("a:80"), &rule.name, rule.renotify_interval, 0).is_some());
+    book.alerts[0].acknowledged = Some(Acknowledgement { at: 10, comment: String::new() });
+
+    // flapping back and forth within the interval, then firing on
+    book.evaluate("tcpping", "a:80", &rule, 100, 60, None);
+    book.evaluate("tcpping", "a:80", &rule, 300_000, 120, None);
+    assert!(book.due("tcpping", Some("a:80"), &rule.name, rule.renotify_interval, 900).is_none());
+
+    // its resolution is still notified of, ending the acknowledgement
+    book.evaluate("tcpping", "a:80", &rule, 100, 960, None);
+    let resolved = book.due("tcpping", Some("a:80"), &rule.name, rule.renotify_interval, 960).unwrap();
+    assert!(resolved.state == AlertState::Resolved && resolved.acknowledged.is_none());
+}
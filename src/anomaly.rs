@@ -0,0 +1,292 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Learned baselines of the values watched by `anomalous` alert rules (see
+ * `alerts.rs`), to tell how far each round deviates from what is usual for
+ * it, even when no absolute threshold is crossed.
+ *
+ * An `ewma` baseline is the exponentially weighted moving average and
+ * variance of the value over recent rounds. A `seasonal` one is a profile of
+ * the value by hour of the week: each hour (e.g. Tuesdays from 9:00 to 10:00)
+ * has the mean and variance of its rounds of past weeks, folded in once the
+ * hour is over, so that a round is compared with the same hour of earlier
+ * weeks rather than with the (possibly just as abnormal) rounds before it.
+ *
+ * Rounds are compared before being learned, and baselines still learning
+ * (for too few rounds, or weeks) compare nothing. Baselines are persisted to
+ * `baselines.json` in the data directory, from time to time and on shutdown.
+ */
+use std::collections::HashMap;
+use std::fs::{OpenOptions, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use serde::{Serialize, Deserialize};
+
+use crate::helpers::{SPIOError, SPFile, overwrite_json};
+use crate::options::Baseline;
+
+/**
+ * Weight of each round in an `ewma` baseline.
+ */
+const ALPHA: f64 = 0.05;
+
+/**
+ * Rounds an `ewma` baseline learns before comparing any.
+ */
+const WARM_UP: u32 = 30;
+
+/**
+ * Weight of each week in the hours of a `seasonal` baseline.
+ */
+const WEEKLY_ALPHA: f64 = 0.3;
+
+const HOURS_PER_WEEK: usize = 7 * 24;
+
+/**
+ * How often baselines are persisted (besides on shutdown).
+ */
+const SAVE_INTERVAL: Duration = Duration::from_secs(600);
+
+/**
+ * A mean and variance, either moving (weighting recent values most) or of
+ * all values.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct Stats {
+    n: u32,
+    mean: f64,
+    var: f64,
+}
+
+impl Stats {
+    fn add_weighted(&mut self, x: f64, alpha: f64) {
+        if self.n == 0 {
+            self.mean = x;
+        } else {
+            let diff = x - self.mean;
+            let incr = alpha * diff;
+            self.mean += incr;
+            self.var = (1.0 - alpha) * (self.var + diff * incr);
+        }
+        self.n = self.n.saturating_add(1);
+    }
+
+    fn add(&mut self, x: f64) {
+        let alpha = 1.0 / (self.n as f64 + 1.0);
+        let diff = x - self.mean;
+        self.mean += alpha * diff;
+        self.var = (1.0 - alpha) * (self.var + alpha * diff * diff);
+        self.n = self.n.saturating_add(1);
+    }
+
+    /**
+     * Deviation of the given value above the mean, in standard deviations.
+     */
+    fn sigmas(&self, x: f64) -> f64 {
+        // not to make much of tiny deviations of values that hardly vary
+        (x - self.mean) / self.var.sqrt().max(1.0)
+    }
+}
+
+/**
+ * How a round compared with the baseline.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deviation {
+    pub expected: i32,  // the mean of the baseline
+    pub sigmas: f64,  // how far above it the round was
+    pub season: Option<String>,  // the hour of the week it was expected for, e.g. "Tuesday 09:00"
+}
+
+/**
+ * What has been learned of the values of an address watched by a rule.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Profile {
+    #[serde(default)]
+    recent: Stats,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    hours: Vec<Stats>,  // for each hour of the week (from Monday 0:00), of its past weeks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current: Option<(usize, Stats)>,  // the hour under way, and its rounds so far
+}
+
+impl Profile {
+    fn observe(&mut self, baseline: Baseline, x: f64, time: i64) -> Option<Deviation> {
+        match baseline {
+            Baseline::Ewma => {
+                let deviation = if self.recent.n >= WARM_UP {
+                    Some(Deviation {
+                        expected: self.recent.mean.round() as i32,
+                        sigmas: self.recent.sigmas(x),
+                        season: None,
+                    })
+                } else {
+                    None
+                };
+                self.recent.add_weighted(x, ALPHA);
+                deviation
+            },
+            Baseline::Seasonal => {
+                let local = Local.timestamp(time, 0);
+                let hour = local.weekday().num_days_from_monday() as usize * 24 + local.hour() as usize;
+                self.hours.resize(HOURS_PER_WEEK, Stats::default());
+
+                // the hour before is over, fold it into its past weeks
+                match self.current {
+                    Some((h, _)) if h == hour => {},
+                    _ => {
+                        if let Some((h, week)) = self.current.take() {
+                            let past = &mut self.hours[h];
+                            if past.n == 0 {
+                                *past = Stats { n: 1, ..week };
+                            } else {
+                                past.mean += WEEKLY_ALPHA * (week.mean - past.mean);
+                                past.var += WEEKLY_ALPHA * (week.var - past.var);
+                                past.n = past.n.saturating_add(1);
+                            }
+                        }
+                        self.current = Some((hour, Stats::default()));
+                    },
+                }
+
+                let past = &self.hours[hour];
+                let deviation = if past.n > 0 {
+                    Some(Deviation {
+                        expected: past.mean.round() as i32,
+                        sigmas: past.sigmas(x),
+                        season: Some(local.format("%A %H:00").to_string()),
+                    })
+                } else {
+                    None
+                };
+                self.current.as_mut().unwrap().1.add(x);
+                deviation
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedProfile {
+    target: String,
+    addr: String,
+    rule: String,
+    #[serde(flatten)]
+    profile: Profile,
+}
+
+/**
+ * The baselines of every (target, addr, rule), persisted to the given path.
+ */
+pub struct Baselines {
+    path: Option<PathBuf>,
+    profiles: HashMap<(String, String, String), Profile>,
+    saved: Instant,
+}
+
+impl Baselines {
+    /**
+     * Reads back any baselines persisted to the given data directory.
+     */
+    pub fn load(data_path: &Path) -> Result<Self, SPIOError> {
+        let path = data_path.join("baselines.json");
+        let saved: Vec<SavedProfile> = if path.exists() {
+            File::open_from(OpenOptions::new().read(true), &path)?.read_json_p(&path)?
+        } else {
+            Vec::new()
+        };
+        Ok(Baselines {
+            path: Some(path),
+            profiles: saved.into_iter().map(|p| ((p.target, p.addr, p.rule), p.profile)).collect(),
+            saved: Instant::now(),
+        })
+    }
+
+    /**
+     * Compares the given value (of a round at the given time) of the given
+     * address watched by the given rule with what its baseline expects, then
+     * learns it. Returns `None` while the baseline is still learning.
+     */
+    pub fn observe(&mut self, target: &str, addr: &str, rule: &str, baseline: Baseline,
+                   val: i32, time: i64) -> Option<Deviation> {
+        let key = (target.to_owned(), addr.to_owned(), rule.to_owned());
+        self.profiles.entry(key).or_default().observe(baseline, val as f64, time)
+    }
+
+    /**
+     * Forgets the baselines of the given target not kept by the given
+     * predicate (of their address and rule).
+     */
+    pub fn retain<F: Fn(&str, &str) -> bool>(&mut self, target: &str, keep: F) {
+        self.profiles.retain(|(t, a, r), _| t != target || keep(a, r));
+    }
+
+    /**
+     * Persists the baselines, only if they haven't been for a while unless
+     * asked to.
+     */
+    pub fn save(&mut self, now: bool) -> Result<(), SPIOError> {
+        let path = match self.path {
+            Some(ref p) if now || self.saved.elapsed() >= SAVE_INTERVAL => p,
+            _ => return Ok(()),
+        };
+        self.saved = Instant::now();
+        let saved: Vec<SavedProfile> = self.profiles.iter().map(|((target, addr, rule), profile)| SavedProfile {
+            target: target.clone(),
+            addr: addr.clone(),
+            rule: rule.clone(),
+            profile: profile.clone(),
+        }).collect();
+        overwrite_json(&saved, path)
+    }
+}
+
+impl Default for Baselines {
+    /**
+     * Baselines persisted nowhere.
+     */
+    fn default() -> Self {
+        Baselines {
+            path: None,
+            profiles: HashMap::new(),
+            saved: Instant::now(),
+        }
+    }
+}
+
+#[test]
+fn baselines_flag_values_far_above_what_they_learned() {
+    let mut baselines = Baselines::default();
+    let observe = |b: &mut Baselines, baseline, val, time| b.observe("tcpping", "a:80", "odd", baseline, val, time);
+
+    // still learning, then values within the usual jitter deviate little
+    for i in 0..WARM_UP {
+        assert!(observe(&mut baselines, Baseline::Ewma, 10_000 + (i as i32 % 3) * 1000, i as i64).is_none());
+    }
+    let usual = observe(&mut baselines, Baseline::Ewma, 11_000, 100).unwrap();
+    assert!((10_500..11_500).contains(&usual.expected) && usual.sigmas.abs() < 2.0);
+    assert!(observe(&mut baselines, Baseline::Ewma, 30_000, 101).unwrap().sigmas > 10.0);
+
+    // a seasonal baseline compares with the same hour of the week before
+    let week = 7 * 24 * 3600;
+    let monday = Local.ymd(2017, 7, 3).and_hms(9, 0, 0).timestamp();
+    for (start, val) in [(monday, 50_000), (monday + 3600, 5_000)] {
+        for i in 0..10 {
+            assert!(observe(&mut baselines, Baseline::Seasonal, val + i % 2 * 1000, start + i as i64 * 60).is_none());
+        }
+    }
+    let busy = observe(&mut baselines, Baseline::Seasonal, 52_000, monday + week).unwrap();
+    assert_eq!((busy.expected, busy.season.as_deref()), (50_500, Some("Monday 09:00")));
+    assert!(busy.sigmas < 4.0);
+    let quiet = observe(&mut baselines, Baseline::Seasonal, 52_000, monday + week + 3600).unwrap();
+    assert!(quiet.expected == 5_500 && quiet.sigmas > 50.0);
+}
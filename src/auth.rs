@@ -0,0 +1,167 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Checking who may use the web interface, API and websockets server, by the
+ * `Authorization` of their requests (see `AuthConfiguration`), and whether
+ * they may change anything or only view it.
+ *
+ * Browsers don't send credentials with a websocket connection (to another
+ * port), so the web client instead connects with a token made when
+ * **Stabping** starts, which it gets from the web server once authorized.
+ */
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::options::AuthConfiguration;
+use crate::smtp::base64;
+
+/**
+ * What a request is allowed to do: only view targets and their data, or also
+ * change them. An admin may do anything one with read access may.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Access {
+    Read,
+    Admin,
+}
+
+/**
+ * Who may use the web and websockets servers: anyone, or those with one of
+ * the tokens or the basic auth credentials configured.
+ */
+pub struct Auth {
+    tokens: Vec<String>,
+    read_tokens: Vec<String>,
+    basic: Option<String>,  // base64 of `username:password`
+    ws_token: String,
+    enabled: bool,
+}
+
+impl Auth {
+    /**
+     * Makes the checks for the given configuration (letting anyone in if
+     * there is none), or describes what's wrong with it.
+     */
+    pub fn new(config: Option<&AuthConfiguration>) -> Result<Self, String> {
+        let mut bytes = [0; 16];
+        SystemRandom::new().fill(&mut bytes).map_err(|_| "Unable to make a websockets token".to_owned())?;
+        let ws_token = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let config = match config {
+            Some(c) => c,
+            None => return Ok(Auth { tokens: Vec::new(), read_tokens: Vec::new(), basic: None, ws_token,
+                                     enabled: false }),
+        };
+        let basic = match (&config.username, &config.password) {
+            (Some(u), Some(p)) => Some(base64(format!("{}:{}", u, p).as_bytes())),
+            (None, None) => None,
+            _ => return Err("auth needs both a username and a password for basic auth".to_owned()),
+        };
+        if config.tokens.iter().chain(config.read_tokens.iter()).any(|t| t.is_empty()) {
+            return Err("auth tokens may not be empty".to_owned());
+        }
+        if config.tokens.is_empty() && basic.is_none() {
+            return Err("auth needs admin tokens, or a username and password".to_owned());
+        }
+
+        Ok(Auth {
+            tokens: config.tokens.clone(),
+            read_tokens: config.read_tokens.clone(),
+            basic,
+            ws_token,
+            enabled: true,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /**
+     * Whether basic auth is configured (so browsers should be asked for it).
+     */
+    pub fn has_basic(&self) -> bool {
+        self.basic.is_some()
+    }
+
+    /**
+     * The token the web client connects to the websockets server with.
+     */
+    pub fn ws_token(&self) -> &str {
+        &self.ws_token
+    }
+
+    /**
+     * Gets what a request with the given `Authorization` header (if any) may
+     * do, if anything.
+     */
+    pub fn access(&self, authorization: Option<&str>) -> Option<Access> {
+        if !self.enabled {
+            return Some(Access::Admin);
+        }
+        let (scheme, credentials) = authorization.and_then(|a| a.trim().split_once(' '))?;
+        let credentials = credentials.trim().as_bytes();
+        let any = |tokens: &[String]| tokens.iter().any(|t| same(t.as_bytes(), credentials));
+        match scheme.to_ascii_lowercase().as_str() {
+            "bearer" if any(&self.tokens) => Some(Access::Admin),
+            "bearer" if any(&self.read_tokens) => Some(Access::Read),
+            "basic" if self.basic.as_ref().is_some_and(|b| same(b.as_bytes(), credentials)) => Some(Access::Admin),
+            _ => None,
+        }
+    }
+
+    /**
+     * Whether a connection to the websockets server (which only ever sends
+     * results), with the given `Authorization` header and `token` query
+     * parameter (if any), is allowed.
+     */
+    pub fn allows_ws(&self, authorization: Option<&str>, token: Option<&str>) -> bool {
+        self.access(authorization).is_some() || token.is_some_and(|t| same(t.as_bytes(), self.ws_token.as_bytes()))
+    }
+}
+
+/**
+ * Compares the given credentials in time independent of where they differ.
+ */
+pub fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+#[test]
+fn requests_need_a_token_or_credentials() {
+    let open = Auth::new(None).unwrap();
+    assert_eq!(open.access(None), Some(Access::Admin));
+
+    let config = AuthConfiguration {
+        tokens: vec!["s3cret".to_owned()],
+        read_tokens: vec!["kiosk".to_owned()],
+        username: Some("admin".to_owned()),
+        password: Some("pw".to_owned()),
+    };
+    let auth = Auth::new(Some(&config)).unwrap();
+    assert_eq!(auth.access(Some("Bearer s3cret")), Some(Access::Admin));
+    assert_eq!(auth.access(Some("bearer  s3cret ")), Some(Access::Admin));
+    assert_eq!(auth.access(Some("Bearer kiosk")), Some(Access::Read));
+    assert_eq!(auth.access(Some("Basic YWRtaW46cHc=")), Some(Access::Admin));
+    assert_eq!(auth.access(None), None);
+    assert_eq!(auth.access(Some("Bearer s3cre")), None);
+    assert_eq!(auth.access(Some("Basic YWRtaW46cHc")), None);
+    assert_eq!(auth.access(Some("Basic kiosk")), None);
+    assert_eq!(auth.access(Some("s3cret")), None);
+    assert!(Access::Admin >= Access::Read);
+
+    assert!(auth.allows_ws(None, Some(auth.ws_token())));
+    assert!(auth.allows_ws(Some("Bearer kiosk"), None));
+    assert!(!auth.allows_ws(None, Some("")));
+    assert_ne!(auth.ws_token(), Auth::new(None).unwrap().ws_token());
+
+    assert!(Auth::new(Some(&AuthConfiguration { password: None, ..config.clone() })).is_err());
+    assert!(Auth::new(Some(&AuthConfiguration { tokens: Vec::new(), username: None, password: None, ..config }))
+        .is_err());
+    assert!(Auth::new(Some(&AuthConfiguration::default())).is_err());
+}
@@ -0,0 +1,421 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Backups of the data directory (the options, data, archive, index, schema,
+ * hops and rollup files of every target, and the alerts and incidents) that
+ * are consistent even when taken while stabping is running, and their
+ * restoration.
+ *
+ * The data files are copied only up to the end of the last round their commit
+ * files record (see `storage.rs`), and the other files appended to (archive,
+ * index, schema, hops and rollup files) only up to the end of their last
+ * complete element, segment, record or line, so that one being written at the
+ * time isn't copied torn. The data files are copied before their archives, so
+ * any elements compressed in between are dropped from the copies of the data
+ * files rather than lost (or backed up twice). The others are only ever replaced whole (by renaming, see
+ * `overwrite_json`), and SQLite databases are copied by SQLite itself. A
+ * backup is a directory of the copies along with a manifest (`backup.json`)
+ * of them, written last.
+ */
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+use serde::{Serialize, Deserialize};
+
+use crate::helpers::{SPIOError, SPFile};
+use crate::compress;
+use crate::hops;
+use crate::persist::DataElement;
+use crate::rollup::RollupElement;
+use crate::storage;
+
+const MANIFEST_NAME: &str = "backup.json";
+
+/**
+ * How many times a data file replaced while getting its committed length is
+ * opened again before settling for its whole elements.
+ */
+const COMMIT_RETRIES: usize = 5;
+
+/**
+ * How a file of the data directory is copied consistently.
+ */
+#[derive(Debug, PartialEq, PartialOrd)]
+enum CopyMode {
+    Sqlite,
+    Data,  // up to the end of the last round committed
+    Elements(usize),  // up to the last whole element of the given size
+    Segments,  // up to the last whole segment (after the data files, see `FileStorage`)
+    Hops,  // up to the last whole record
+    Lines,  // up to the last whole line
+    Whole,
+}
+
+impl CopyMode {
+    /**
+     * Gets how the file of the given name is copied, if it is backed up at
+     * all. Files appended to are listed before those they refer to (e.g. the
+     * data files before the index files naming their indices).
+     */
+    fn of(name: &str) -> Option<CopyMode> {
+        // commit and checksums files are only of use with the very data files they were written with
+        if name.ends_with(".tmp") || name.ends_with(".commit") || name.ends_with(".sums") || name.ends_with("-wal")
+            || name.ends_with("-shm") || name == MANIFEST_NAME {
+            None
+        } else if name.ends_with(".sqlite") {
+            Some(CopyMode::Sqlite)
+        } else if name.ends_with(".data64.dat") {
+            Some(CopyMode::Data)
+        } else if name.contains(".rollup-") && name.ends_with(".dat") {
+            Some(CopyMode::Elements(mem::size_of::<RollupElement>()))
+        } else if name.ends_with(".data64.archive") {
+            Some(CopyMode::Segments)
+        } else if name.ends_with(".hops.dat") || name.ends_with(".ips.dat") {
+            Some(CopyMode::Hops)
+        } else if name.ends_with(".index.json") || name.ends_with(".schema.json") {
+            Some(CopyMode::Lines)
+        } else {
+            Some(CopyMode::Whole)
+        }
+    }
+
+    /**
+     * Returns how much of the given contents is copied.
+     */
+    fn complete_len(&self, bytes: &[u8]) -> usize {
+        match *self {
+            CopyMode::Data => bytes.len() / mem::size_of::<DataElement>() * mem::size_of::<DataElement>(),
+            CopyMode::Elements(size) => bytes.len() / size * size,
+            CopyMode::Segments => compress::complete_len(bytes),
+            CopyMode::Hops => hops::complete_len(bytes),
+            CopyMode::Lines => bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1),
+            CopyMode::Sqlite | CopyMode::Whole => bytes.len(),
+        }
+    }
+}
+
+/**
+ * Whether the file of the given name is one of a target (and so is removed on
+ * restoring a backup without it).
+ */
+fn is_target_file(name: &str) -> bool {
+    [".data64.dat", ".data64.commit", ".data64.sums", ".data64.archive", ".data.dat", ".index.json",
+     ".schema.json", ".options.json", ".hops.dat", ".ips.dat",
+     ".sqlite", ".sqlite-wal", ".sqlite-shm"].iter().any(|s| name.ends_with(s))
+        || (name.contains(".rollup-") && name.ends_with(".dat"))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackedUpFile {
+    pub name: String,
+    pub len: u64,
+}
+
+/**
+ * The manifest of a backup: when it was taken (by which version of stabping)
+ * and the files in it.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub version: String,
+    pub time: i64,
+    pub files: Vec<BackedUpFile>,
+}
+
+/**
+ * Copies the file at the given path consistently (see `CopyMode`) to the given
+ * destination, returning its length.
+ */
+fn copy_file(path: &Path, dest: &Path, copy: &CopyMode) -> Result<u64, SPIOError> {
+    let read_error = || SPIOError::Read(Some(path.to_owned()));
+    let write_error = || SPIOError::Write(Some(dest.to_owned()));
+    if *copy == CopyMode::Sqlite {
+        snapshot_sqlite(path, dest)?;
+        return fs::metadata(dest).map(|m| m.len()).map_err(|_| SPIOError::Metadata(Some(dest.to_owned())));
+    }
+
+    let mut file = File::open(path).map_err(|_| SPIOError::Open(Some(path.to_owned())))?;
+    let mut out = File::create(dest).map_err(|_| SPIOError::Open(Some(dest.to_owned())))?;
+    let len = match *copy {
+        // data and rollup files may be large, so are copied without reading them whole
+        CopyMode::Data => {
+            let len = committed_len(path, &mut file)?;
+            file.seek(SeekFrom::Start(0)).map_err(|_| read_error())?;
+            io::copy(&mut (&mut file).take(len), &mut out).map_err(|_| write_error())?;
+            len
+        },
+        CopyMode::Elements(size) => {
+            let len = file.length_p(path)?;
+            let len = len - len % size as u64;
+            io::copy(&mut (&mut file).take(len), &mut out).map_err(|_| write_error())?;
+            len
+        },
+        _ => {
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).map_err(|_| read_error())?;
+            let len = copy.complete_len(&bytes);
+            out.write_all(&bytes[..len]).map_err(|_| write_error())?;
+            len as u64
+        },
+    };
+    out.sync_all().map_err(|_| write_error())?;
+    Ok(len)
+}
+
+/**
+ * Gets the length of the data file at the given path (opened as the given
+ * file) up to the end of the last round its commit file records, opening it
+ * again should it be replaced (by compressing it) in the meantime, or else
+ * (without a commit file matching it) up to the end of its last whole element.
+ */
+fn committed_len(path: &Path, file: &mut File) -> Result<u64, SPIOError> {
+    let read_error = || SPIOError::Read(Some(path.to_owned()));
+    for _ in 0..COMMIT_RETRIES {
+        let committed = storage::read_commit(path);
+        match committed {
+            None => break,
+            // the data file was emptied (e.g. by compressing it) since the last round
+            Some((0, _)) => return Ok(0),
+            _ => (),
+        }
+        if let Some(len) = storage::committed_len(file, committed).map_err(|_| read_error())? {
+            return Ok(len);
+        }
+        thread::sleep(Duration::from_millis(10));
+        *file = File::open(path).map_err(|_| SPIOError::Open(Some(path.to_owned())))?;
+    }
+    let size = mem::size_of::<DataElement>() as u64;
+    let len = file.length_p(path)?;
+    Ok(len - len % size)
+}
+
+/**
+ * Drops the elements at the start of the copy of a data file at the given
+ * path up to the given (last archived) time, those compressed into its
+ * archive after it was copied, returning the length left.
+ */
+fn drop_archived(path: &Path, last: i64) -> Result<u64, SPIOError> {
+    let read_error = || SPIOError::Read(Some(path.to_owned()));
+    let write_error = || SPIOError::Write(Some(path.to_owned()));
+    let mut file = File::open(path).map_err(|_| SPIOError::Open(Some(path.to_owned())))?;
+    let len = file.length_p(path)?;
+
+    // the time is the first field of an element, and they are in order of it
+    let size = mem::size_of::<DataElement>() as u64;
+    let mut archived = 0;
+    let mut time = [0; 8];
+    while archived < len {
+        file.seek(SeekFrom::Start(archived)).and_then(|_| file.read_exact(&mut time))
+            .map_err(|_| read_error())?;
+        if i64::from_ne_bytes(time) > last {
+            break;
+        }
+        archived += size;
+    }
+    if archived == 0 {
+        return Ok(len);
+    }
+
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut out = File::create(&tmp_path).map_err(|_| SPIOError::Open(Some(tmp_path.clone())))?;
+    file.seek(SeekFrom::Start(archived)).map_err(|_| read_error())?;
+    io::copy(&mut file, &mut out).and_then(|_| out.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|_| write_error())?;
+    Ok(len - archived)
+}
+
+#[cfg(feature = "sqlite")]
+fn snapshot_sqlite(path: &Path, dest: &Path) -> Result<(), SPIOError> {
+    crate::sqlite::snapshot(path, dest)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn snapshot_sqlite(path: &Path, _dest: &Path) -> Result<(), SPIOError> {
+    error!("Can't back up '{}', stabping was built without the sqlite feature.", path.display());
+    Err(SPIOError::Read(Some(path.to_owned())))
+}
+
+/**
+ * Takes a backup of the given data directory into the given directory (which
+ * must be empty, if it exists), returning its manifest.
+ */
+pub fn backup(data_path: &Path, dest: &Path) -> Result<Manifest, SPIOError> {
+    fs::create_dir_all(dest).map_err(|_| SPIOError::Open(Some(dest.to_owned())))?;
+    let not_empty = fs::read_dir(dest).map_err(|_| SPIOError::Read(Some(dest.to_owned())))?
+        .next().is_some();
+    if not_empty {
+        error!("Not backing up into '{}', which isn't empty.", dest.display());
+        return Err(SPIOError::Write(Some(dest.to_owned())));
+    }
+
+    let mut files: Vec<(String, CopyMode)> = fs::read_dir(data_path)
+        .map_err(|_| SPIOError::Read(Some(data_path.to_owned())))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| CopyMode::of(&name).map(|copy| (name, copy)))
+        .collect();
+    files.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)));
+
+    let mut manifest = Manifest {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        time: Local::now().timestamp(),
+        files: Vec::with_capacity(files.len()),
+    };
+    for (name, copy) in files.iter() {
+        let len = copy_file(&data_path.join(name), &dest.join(name), copy)?;
+        manifest.files.push(BackedUpFile { name: name.clone(), len });
+
+        // the archive is copied after its data file, which may have been compressed in between
+        let data_name = match name.strip_suffix(".archive") {
+            Some(stem) if *copy == CopyMode::Segments => format!("{}.dat", stem),
+            _ => continue,
+        };
+        let mut bytes = Vec::new();
+        File::open(dest.join(name)).and_then(|mut f| f.read_to_end(&mut bytes))
+            .map_err(|_| SPIOError::Read(Some(dest.join(name))))?;
+        let last = compress::segments(&bytes).last().map(|s| s.last);
+        let data_file = manifest.files.iter_mut().find(|f| f.name == data_name);
+        if let (Some(last), Some(data_file)) = (last, data_file) {
+            data_file.len = drop_archived(&dest.join(&data_name), last)?;
+        }
+    }
+
+    let manifest_path = dest.join(MANIFEST_NAME);
+    File::create(&manifest_path)
+        .map_err(|_| SPIOError::Open(Some(manifest_path.clone())))?
+        .write_json_p(&manifest, &manifest_path)?;
+    Ok(manifest)
+}
+
+/**
+ * Restores the backup in the given directory into the given data directory,
+ * replacing the files of the targets there (removing any not in the backup),
+ * returning its manifest. Stabping must not be running on the data directory.
+ */
+pub fn restore(src: &Path, data_path: &Path) -> Result<Manifest, SPIOError> {
+    let manifest_path = src.join(MANIFEST_NAME);
+    let manifest: Manifest = File::open(&manifest_path)
+        .map_err(|_| SPIOError::Open(Some(manifest_path.clone())))?
+        .read_json_p(&manifest_path)?;
+
+    // make sure the whole backup is there before touching anything
+    for f in manifest.files.iter() {
+        let path = src.join(&f.name);
+        let bad_name = f.name.contains(['/', '\\']) || f.name.starts_with('.');
+        if bad_name || fs::metadata(&path).map(|m| m.len()).ok() != Some(f.len) {
+            error!("Backup file '{}' is missing or incomplete.", path.display());
+            return Err(SPIOError::Parse(Some(path)));
+        }
+    }
+
+    fs::create_dir_all(data_path).map_err(|_| SPIOError::Open(Some(data_path.to_owned())))?;
+    for f in manifest.files.iter() {
+        let dest = data_path.join(&f.name);
+        let tmp_path = PathBuf::from(format!("{}.tmp", dest.display()));
+        fs::copy(src.join(&f.name), &tmp_path)
+            .and_then(|_| fs::rename(&tmp_path, &dest))
+            .map_err(|_| SPIOError::Write(Some(dest.clone())))?;
+    }
+
+    // then remove those of targets not in the backup (e.g. stale SQLite journals)
+    let stale: Vec<PathBuf> = fs::read_dir(data_path)
+        .map_err(|_| SPIOError::Read(Some(data_path.to_owned())))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_target_file(name) && !manifest.files.iter().any(|f| f.name == *name))
+        .map(|name| data_path.join(name))
+        .collect();
+    for path in stale.iter() {
+        fs::remove_file(path).map_err(|_| SPIOError::Write(Some(path.clone())))?;
+    }
+    Ok(manifest)
+}
+
+#[test]
+fn backups_leave_out_torn_writes_and_restore() {
+    let dir = std::env::temp_dir().join(format!("stabping-test-backup-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (data, dest) = (dir.join("data"), dir.join("backup"));
+    fs::create_dir_all(&data).unwrap();
+
+    // a round and a half, and a line and a half
+    let mut elements = Vec::new();
+    DataElement { time: 1, index: 0, val: 10 }.push_to(&mut elements);
+    elements.extend_from_slice(&[0; 5]);
+    fs::write(data.join("tcpping.data64.dat"), &elements).unwrap();
+    fs::write(data.join("tcpping.index.json"), "a:80\nb:8").unwrap();
+    fs::write(data.join("tcpping.options.json"), "{}").unwrap();
+    fs::write(data.join("tcpping.options.json.tmp"), "{").unwrap();
+
+    let manifest = backup(&data, &dest).unwrap();
+    let names: Vec<(&str, u64)> = manifest.files.iter().map(|f| (f.name.as_str(), f.len)).collect();
+    assert_eq!(names, vec![("tcpping.data64.dat", 16), ("tcpping.index.json", 5),
+                           ("tcpping.options.json", 2)]);
+    assert_eq!(fs::read_to_string(dest.join("tcpping.index.json")).unwrap(), "a:80\n");
+    assert!(backup(&data, &dest).is_err());
+
+    fs::write(data.join("tcpping.hops.dat"), "").unwrap();
+    fs::write(data.join("alerts.json"), "[]").unwrap();
+    restore(&dest, &data).unwrap();
+    assert_eq!(fs::read(data.join("tcpping.data64.dat")).unwrap(), &elements[..16]);
+    assert!(!data.join("tcpping.hops.dat").exists());
+    assert!(data.join("alerts.json").exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn backups_copy_data_files_up_to_their_commits() {
+    use crate::compress::Archive;
+    use crate::storage::{FileStorage, Storage};
+
+    let dir = std::env::temp_dir().join(format!("stabping-test-backup-commit-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (data, dest, restored) = (dir.join("data"), dir.join("backup"), dir.join("restored"));
+    fs::create_dir_all(&data).unwrap();
+    let path = data.join("tcpping.data64.dat");
+
+    // two rounds committed, the first of which is then archived (as if compressed mid-backup)
+    let storage = FileStorage::open(&path).unwrap();
+    storage.append(&[DataElement { time: 1, index: 0, val: 10 }, DataElement { time: 1, index: 1, val: 11 }])
+        .unwrap();
+    storage.append(&[DataElement { time: 2, index: 0, val: 20 }]).unwrap();
+    drop(storage);
+    Archive::open(&data.join("tcpping.data64.archive")).unwrap()
+        .append(&[DataElement { time: 1, index: 0, val: 10 }, DataElement { time: 1, index: 1, val: 11 }])
+        .unwrap();
+
+    // and a whole element of a third round being appended, but not yet committed
+    let mut tail = Vec::new();
+    DataElement { time: 3, index: 0, val: 30 }.push_to(&mut tail);
+    fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&tail).unwrap();
+
+    let manifest = backup(&data, &dest).unwrap();
+    let data_file = manifest.files.iter().find(|f| f.name == "tcpping.data64.dat").unwrap();
+    assert_eq!(data_file.len, mem::size_of::<DataElement>() as u64);
+    assert!(!manifest.files.iter().any(|f| f.name.ends_with(".commit")));
+
+    restore(&dest, &restored).unwrap();
+    let storage = FileStorage::open(&restored.join("tcpping.data64.dat")).unwrap();
+    let mut elements = Vec::new();
+    storage.read_range(i64::MIN, i64::MAX, &mut |e| {
+        elements.extend(e.iter().map(|e| (e.time, e.index, e.val)));
+        Ok(())
+    }).unwrap();
+    assert_eq!(elements, vec![(1, 0, 10), (1, 1, 11), (2, 0, 20)]);
+    let _ = fs::remove_dir_all(&dir);
+}
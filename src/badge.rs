@@ -0,0 +1,133 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Badges in the style of shields.io (a label on grey, then a message on a
+ * color), of the current state or recent uptime of (some addresses of) a
+ * target, to embed in wikis and READMEs.
+ */
+use crate::report::AddrReport;
+
+pub const GREEN: &str = "#4c1";
+pub const YELLOWGREEN: &str = "#a4a61d";
+pub const YELLOW: &str = "#dfb317";
+pub const ORANGE: &str = "#fe7d37";
+pub const RED: &str = "#e05d44";
+pub const GREY: &str = "#9f9f9f";
+
+/**
+ * Approximate width of the given text in 11px Verdana, as the badge is laid
+ * out without measuring it.
+ */
+fn text_width(text: &str) -> usize {
+    text.chars().map(|c| match c {
+        'i' | 'j' | 'l' | '.' | ',' | ':' | ';' | '!' | '|' | '\'' | ' ' | 'I' | 'f' | 't' | 'r' => 4,
+        'm' | 'w' | 'M' | 'W' | '%' => 10,
+        c if c.is_ascii_uppercase() => 8,
+        _ => 7,
+    }).sum()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/**
+ * Renders a badge of the given label and message, the message on the given
+ * color.
+ */
+pub fn render(label: &str, message: &str, color: &str) -> String {
+    let (lw, mw) = (text_width(label) + 10, text_width(message) + 10);
+    let (label, message) = (escape(label), escape(message));
+    format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"20\" role=\"img\" \
+             aria-label=\"{l}: {m}\"><title>{l}: {m}</title>\
+             <linearGradient id=\"s\" x2=\"0\" y2=\"100%\"><stop offset=\"0\" stop-color=\"#bbb\" stop-opacity=\".1\"/>\
+             <stop offset=\"1\" stop-opacity=\".1\"/></linearGradient>\
+             <clipPath id=\"r\"><rect width=\"{w}\" height=\"20\" rx=\"3\" fill=\"#fff\"/></clipPath>\
+             <g clip-path=\"url(#r)\"><rect width=\"{lw}\" height=\"20\" fill=\"#555\"/>\
+             <rect x=\"{lw}\" width=\"{mw}\" height=\"20\" fill=\"{c}\"/>\
+             <rect width=\"{w}\" height=\"20\" fill=\"url(#s)\"/></g>\
+             <g fill=\"#fff\" text-anchor=\"middle\" font-family=\"Verdana,Geneva,DejaVu Sans,sans-serif\" font-size=\"11\">\
+             <text x=\"{lx}\" y=\"15\" fill=\"#010101\" fill-opacity=\".3\">{l}</text><text x=\"{lx}\" y=\"14\">{l}</text>\
+             <text x=\"{mx}\" y=\"15\" fill=\"#010101\" fill-opacity=\".3\">{m}</text><text x=\"{mx}\" y=\"14\">{m}</text>\
+             </g></svg>",
+            w = lw + mw, lw = lw, mw = mw, c = color, l = label, m = message,
+            lx = lw as f64 / 2.0, mx = lw as f64 + mw as f64 / 2.0)
+}
+
+/**
+ * The message and color of a badge of the current state of addresses, given
+ * whether each is up (by its latest round).
+ */
+pub fn state(up: &[bool]) -> (String, &'static str) {
+    let n = up.iter().filter(|&&u| u).count();
+    match (n, up.len()) {
+        (_, 0) => ("unknown".to_owned(), GREY),
+        (n, total) if n == total => ("up".to_owned(), GREEN),
+        (0, _) => ("down".to_owned(), RED),
+        (n, total) => (format!("{}/{} up", n, total), ORANGE),
+    }
+}
+
+/**
+ * The message and color of a badge of the uptime of the addresses of the
+ * given reports, over all of their rounds.
+ */
+pub fn uptime(reports: &[AddrReport]) -> (String, &'static str) {
+    let rounds: u64 = reports.iter().map(|r| r.rounds).sum();
+    if rounds == 0 {
+        return ("no data".to_owned(), GREY);
+    }
+    let failed: u64 = reports.iter().map(|r| r.failed_rounds).sum();
+    let uptime = (rounds - failed) as f64 * 100.0 / rounds as f64;
+    let color = match uptime {
+        u if u >= 99.9 => GREEN,
+        u if u >= 99.0 => YELLOWGREEN,
+        u if u >= 95.0 => YELLOW,
+        u if u >= 90.0 => ORANGE,
+        _ => RED,
+    };
+    // as many decimals as it takes to tell e.g. 99.95% from 100%
+    let message = if failed == 0 {
+        "100%".to_owned()
+    } else if uptime >= 99.0 {
+        format!("{:.2}%", uptime.min(99.99))
+    } else {
+        format!("{:.1}%", uptime)
+    };
+    (message, color)
+}
+
+#[test]
+fn badges_show_state_and_uptime() {
+    assert_eq!(state(&[true, true]), ("up".to_owned(), GREEN));
+    assert_eq!(state(&[true, false, true]), ("2/3 up".to_owned(), ORANGE));
+    assert_eq!(state(&[false]), ("down".to_owned(), RED));
+    assert_eq!(state(&[]).1, GREY);
+
+    let report = |rounds, failed_rounds| AddrReport {
+        addr: "a:80".to_owned(),
+        name: None,
+        rounds,
+        failed_rounds,
+        uptime: None,
+        mean: None,
+        p95: None,
+        p99: None,
+        downtime: 0,
+        worst_hour: None,
+    };
+    assert_eq!(uptime(&[report(10_000, 0)]), ("100%".to_owned(), GREEN));
+    assert_eq!(uptime(&[report(10_000, 1), report(10_000, 0)]), ("99.99%".to_owned(), GREEN));
+    assert_eq!(uptime(&[report(1000, 20)]), ("98.0%".to_owned(), YELLOW));
+    assert_eq!(uptime(&[report(0, 0)]).1, GREY);
+
+    let svg = render("tcpping", "up", GREEN);
+    assert!(svg.starts_with("<svg") && svg.contains("<title>tcpping: up</title>") && svg.contains("fill=\"#4c1\""));
+    assert!(render("a&b", "<x>", RED).contains("a&amp;b: &lt;x&gt;"));
+}
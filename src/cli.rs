@@ -0,0 +1,159 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Command-line arguments, overriding parts of the configuration at runtime.
+ */
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use crate::export::ExportFormat;
+use crate::logging::{LogFilter, LogFormat};
+use crate::import::Unit;
+
+#[derive(Parser, Debug)]
+#[command(name = "stabping", version, about = "Monitors the stability of network connections")]
+pub struct Cli {
+    #[arg(long, value_name = "FILE",
+          help = "Configuration file to use, instead of searching the usual places")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, value_name = "DIR",
+          help = "Directory to keep persistent data in, overriding the configured one")]
+    pub data_dir: Option<PathBuf>,
+
+    #[arg(long, value_name = "ADDRESS",
+          help = "Address to listen on, overriding the configured one")]
+    pub listen: Option<String>,
+
+    #[arg(long, value_name = "FILTER", default_value = "info", env = "STABPING_LOG_LEVEL",
+          help = "Most verbose messages to print (off, error, warn, info, debug or trace), \
+                  optionally per module (e.g. info,worker=debug)")]
+    pub log_level: LogFilter,
+
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "text", env = "STABPING_LOG_FORMAT",
+          help = "How to print messages")]
+    pub log_format: LogFormat,
+
+    #[arg(long, help = "Only collect data, without starting the web and websocket servers")]
+    pub no_web: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(about = "Prints an uptime report of a target instead of running")]
+    Report {
+        #[arg(help = "Target to report on (e.g. tcpping)")]
+        kind: String,
+
+        #[arg(help = "Start of the range, in seconds since the epoch or as YYYY-MM-DD (default: 30 days before its end)")]
+        from: Option<String>,
+
+        #[arg(help = "End of the range, in seconds since the epoch or as YYYY-MM-DD (default: now)")]
+        to: Option<String>,
+    },
+
+    #[command(about = "Exports the data of a target as CSV or NDJSON instead of running")]
+    Export {
+        #[arg(help = "Target to export (e.g. tcpping)")]
+        kind: String,
+
+        #[arg(help = "Start of the range, in seconds since the epoch or as YYYY-MM-DD (default: 30 days before its end)")]
+        from: Option<String>,
+
+        #[arg(help = "End of the range, in seconds since the epoch or as YYYY-MM-DD (default: now)")]
+        to: Option<String>,
+
+        #[arg(long, short, value_name = "FILE", help = "File to write to, instead of standard output")]
+        output: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value = "csv", help = "Format to export in")]
+        format: ExportFormat,
+    },
+
+    #[command(about = "Writes the status page (see status_page in the configuration) as static HTML instead of running")]
+    StatusPage {
+        #[arg(help = "File to write to, instead of standard output")]
+        output: Option<PathBuf>,
+
+        #[arg(long, help = "Write it as JSON, rather than HTML")]
+        json: bool,
+    },
+
+    #[command(about = "Imports data (CSV or NDJSON, e.g. from smokeping or PingPlotter) into a target instead of running")]
+    Import {
+        #[arg(help = "Target to import into (e.g. tcpping)")]
+        kind: String,
+
+        #[arg(help = "File to import, instead of standard input")]
+        input: Option<PathBuf>,
+
+        #[arg(long, value_name = "ADDRESS", help = "Address of every round, for input without an address column")]
+        address: Option<String>,
+
+        #[arg(long, value_name = "NAME",
+              help = "Column holding the value (default: the first of value, latency, rtt, ping, median, avg, ...)")]
+        column: Option<String>,
+
+        #[arg(long, value_enum, help = "Unit of the value (default: from its column's name, e.g. rtt_ms, or microseconds)")]
+        unit: Option<Unit>,
+
+        #[arg(long, help = "Take times without an offset as local time, rather than UTC")]
+        local_time: bool,
+    },
+
+    #[command(about = "Adds (or updates) the addresses listed in a hosts file to a target instead of running")]
+    ImportHosts {
+        #[arg(help = "Target to add to (e.g. tcpping)")]
+        kind: String,
+
+        #[arg(help = "Hosts file to import (an address per line, optionally with name=, notes= and tags), \
+                      instead of standard input")]
+        input: Option<PathBuf>,
+
+        #[arg(long, help = "Also remove the addresses of the target not in the file")]
+        replace: bool,
+    },
+
+    #[command(about = "Backs up the data directory (consistently, even while stabping is running) instead of running")]
+    Backup {
+        #[arg(help = "Directory to write the backup to (which must be empty, if it exists)")]
+        path: PathBuf,
+    },
+
+    #[command(about = "Restores a backup into the data directory (with stabping stopped) instead of running")]
+    Restore {
+        #[arg(help = "Directory of the backup")]
+        path: PathBuf,
+    },
+
+    #[command(about = "Checks the data directory for corruption (and with --repair, repairs what it can) instead of running")]
+    Fsck {
+        #[arg(long, help = "Repair what can be (with stabping stopped), dropping what is corrupt")]
+        repair: bool,
+    },
+}
+
+#[test]
+fn cli_parses_flags_and_report() {
+    let cli = Cli::try_parse_from(["stabping", "--data-dir", "/tmp/d", "--no-web",
+                                   "--log-level", "warn", "report", "tcpping", "2016-01-01"]).unwrap();
+    assert_eq!(cli.data_dir, Some(PathBuf::from("/tmp/d")));
+    assert!(cli.no_web);
+    assert_eq!(cli.log_level, "warn".parse().unwrap());
+    assert_eq!(cli.log_format, LogFormat::Text);
+    match cli.command {
+        Some(Command::Report { kind, from, to }) => {
+            assert_eq!((kind.as_str(), from.as_deref(), to), ("tcpping", Some("2016-01-01"), None));
+        },
+        _ => panic!("report not parsed"),
+    }
+}
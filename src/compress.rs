@@ -0,0 +1,399 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Compression of the older data of a target's data file into its archive file
+ * (`<kind>.data64.archive`), a sequence of segments of (up to `BLOCK_LEN`
+ * bytes' worth of) elements in order of time.
+ *
+ * Each segment is a header of a magic number, the number of elements, the
+ * length and CRC-32 of the encoded elements, and the *time* of the first and
+ * last element, followed by the encoded elements. Each element is encoded as
+ * the difference of its *time* from that of the one before, its *index*, and
+ * the difference of its *value* from the one before of the same *index*, each
+ * as a (zigzag-encoded, where it may be negative) LEB128 varint. As rounds
+ * share a *time* and latencies change little from round to round, most
+ * elements take 3-4 bytes instead of 16.
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::helpers::{SPIOError, SPFile, PushRawBytes, discard_incomplete};
+use crate::persist::{DataElement, ManagerError};
+use crate::reader::map_data_file;
+use crate::storage::{BLOCK_LEN, block_checksum, merge_elements};
+
+const SEGMENT_MAGIC: u32 = 0x315a_5053;  // "SPZ1"
+pub const HEADER_LEN: usize = 32;
+
+/**
+ * The most elements in a segment.
+ */
+const SEGMENT_ELEMENTS: usize = BLOCK_LEN / mem::size_of::<DataElement>();
+
+/**
+ * The header of a segment of an archive file.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub offset: u64,  // of the header in the archive file
+    pub count: u32,
+    pub len: u32,  // of the encoded elements
+    pub checksum: u32,
+    pub first: i64,
+    pub last: i64,
+}
+
+impl Segment {
+    /**
+     * Reads the header of the segment at the given offset of the given
+     * contents of an archive file, if there is a (whole) one.
+     */
+    pub fn read(bytes: &[u8], offset: usize) -> Option<Segment> {
+        let header = bytes.get(offset..offset + HEADER_LEN)?;
+        let u32_at = |at: usize| u32::from_ne_bytes(header[at..at + 4].try_into().unwrap());
+        let i64_at = |at: usize| i64::from_ne_bytes(header[at..at + 8].try_into().unwrap());
+        if u32_at(0) != SEGMENT_MAGIC {
+            return None;
+        }
+        Some(Segment {
+            offset: offset as u64,
+            count: u32_at(4),
+            len: u32_at(8),
+            checksum: u32_at(12),
+            first: i64_at(16),
+            last: i64_at(24),
+        })
+    }
+
+    /**
+     * The offset just after the end of this segment.
+     */
+    pub fn end(&self) -> u64 {
+        self.offset + HEADER_LEN as u64 + self.len as u64
+    }
+
+    /**
+     * Decodes the elements of this segment from the given contents of its
+     * archive file, or None if they are corrupt.
+     */
+    pub fn decode(&self, bytes: &[u8]) -> Option<Vec<DataElement>> {
+        let start = self.offset as usize + HEADER_LEN;
+        let payload = bytes.get(start..start + self.len as usize)?;
+        if block_checksum(payload) != self.checksum {
+            return None;
+        }
+        let elements = decode(payload, self.count as usize)?;
+        let in_order = elements.first().map(|e| e.time) == Some(self.first)
+            && elements.last().map(|e| e.time) == Some(self.last);
+        if in_order { Some(elements) } else { None }
+    }
+}
+
+fn push_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn read_varint(bytes: &[u8], at: &mut usize) -> Option<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *bytes.get(*at)?;
+        *at += 1;
+        n |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    (n >> 1) as i64 ^ -((n & 1) as i64)
+}
+
+/**
+ * Encodes the given elements (in order of time).
+ */
+fn encode(elements: &[DataElement]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(elements.len() * 4);
+    // the first element's time is kept whole, as its difference from zero
+    let mut time = 0i64;
+    let mut vals: HashMap<i32, i32> = HashMap::new();
+    for e in elements.iter() {
+        push_varint(&mut buf, zigzag(e.time.wrapping_sub(time)));
+        push_varint(&mut buf, zigzag(e.index as i64));
+        let prev = vals.insert(e.index, e.val).unwrap_or(0);
+        push_varint(&mut buf, zigzag(e.val as i64 - prev as i64));
+        time = e.time;
+    }
+    buf
+}
+
+/**
+ * Decodes the given number of elements from the given encoding, or None if it
+ * isn't one of them.
+ */
+fn decode(bytes: &[u8], count: usize) -> Option<Vec<DataElement>> {
+    let mut elements = Vec::with_capacity(count);
+    let mut at = 0;
+    let mut time = 0i64;
+    let mut vals: HashMap<i32, i32> = HashMap::new();
+    while elements.len() < count {
+        let t = time.wrapping_add(unzigzag(read_varint(bytes, &mut at)?));
+        let index = i32::try_from(unzigzag(read_varint(bytes, &mut at)?)).ok()?;
+        let prev = vals.get(&index).cloned().unwrap_or(0);
+        let val = i32::try_from(prev as i64 + unzigzag(read_varint(bytes, &mut at)?)).ok()?;
+        vals.insert(index, val);
+        elements.push(DataElement { time: t, index, val });
+        time = t;
+    }
+    if at == bytes.len() { Some(elements) } else { None }
+}
+
+/**
+ * Encodes the given elements (in order of time) as segments of an archive
+ * file.
+ */
+pub fn encode_segments(elements: &[DataElement]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for chunk in elements.chunks(SEGMENT_ELEMENTS) {
+        let payload = encode(chunk);
+        buf.extend_from_slice(&SEGMENT_MAGIC.to_ne_bytes());
+        buf.extend_from_slice(&(chunk.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(&block_checksum(&payload).to_ne_bytes());
+        buf.push_i64(chunk[0].time);
+        buf.push_i64(chunk[chunk.len() - 1].time);
+        buf.extend_from_slice(&payload);
+    }
+    buf
+}
+
+/**
+ * Reads the headers of every whole segment of the given contents of an
+ * archive file, stopping at any incomplete one at the end.
+ */
+pub fn segments(bytes: &[u8]) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut at = 0;
+    while let Some(segment) = Segment::read(bytes, at) {
+        if segment.end() > bytes.len() as u64 {
+            break;
+        }
+        at = segment.end() as usize;
+        segments.push(segment);
+    }
+    segments
+}
+
+/**
+ * Returns the length of the given contents of an archive file up to the end
+ * of its last whole segment.
+ */
+pub fn complete_len(bytes: &[u8]) -> usize {
+    segments(bytes).last().map_or(0, |s| s.end() as usize)
+}
+
+/**
+ * The archive file of a data file, of its older elements compressed.
+ */
+pub struct Archive {
+    path: PathBuf,
+    file: File,
+    segments: Vec<Segment>,
+}
+
+impl Archive {
+    /**
+     * Opens (creating it if necessary) the archive file at the given path,
+     * discarding any incomplete segment at its end.
+     */
+    pub fn open(path: &Path) -> Result<Self, ManagerError> {
+        let mut file = File::open_from(OpenOptions::new().read(true).append(true).create(true), path)
+            .map_err(ManagerError::DataFileIO)?;
+
+        // only the headers are needed, so each segment is skipped over
+        let len = file.length_p(path).map_err(ManagerError::DataFileIO)?;
+        let mut segments = Vec::new();
+        let mut header = [0; HEADER_LEN];
+        let mut at = 0;
+        while file.seek(SeekFrom::Start(at)).and_then(|_| file.read_exact(&mut header)).is_ok() {
+            match Segment::read(&header, 0) {
+                Some(mut segment) if at + (HEADER_LEN as u64) + segment.len as u64 <= len => {
+                    segment.offset = at;
+                    at = segment.end();
+                    segments.push(segment);
+                },
+                _ => break,
+            }
+        }
+        discard_incomplete(&file, path, len, at).map_err(ManagerError::DataFileIO)?;
+        Ok(Archive { path: path.to_owned(), file, segments })
+    }
+
+    fn write_error(&self) -> ManagerError {
+        ManagerError::DataFileIO(SPIOError::Write(Some(self.path.clone())))
+    }
+
+    pub fn first_time(&self) -> Option<i64> {
+        self.segments.first().map(|s| s.first)
+    }
+
+    pub fn last_time(&self) -> Option<i64> {
+        self.segments.last().map(|s| s.last)
+    }
+
+    /**
+     * Appends the given elements (in order of time, and no earlier than any
+     * already archived) as new segments, flushing them to disk.
+     */
+    pub fn append(&mut self, elements: &[DataElement]) -> Result<(), ManagerError> {
+        if elements.is_empty() {
+            return Ok(());
+        }
+        let bytes = encode_segments(elements);
+        let offset = self.segments.last().map_or(0, |s| s.end());
+        self.file.write_all(&bytes).and_then(|_| self.file.sync_all())
+            .map_err(|_| self.write_error())?;
+        let mut at = 0;
+        while let Some(mut segment) = Segment::read(&bytes, at) {
+            at = segment.end() as usize;
+            segment.offset += offset;
+            self.segments.push(segment);
+        }
+        Ok(())
+    }
+
+    /**
+     * Calls `f` with the elements archived with times from `lower` to `upper`
+     * (inclusive), ordered by time, a segment at a time. Corrupt segments are
+     * skipped (and logged).
+     */
+    pub fn read_range(&self, lower: i64, upper: i64,
+                      f: &mut dyn FnMut(&[DataElement]) -> io::Result<()>) -> io::Result<()> {
+        let mut overlapping = self.segments.iter().filter(|s| s.first <= upper && s.last >= lower).peekable();
+        if overlapping.peek().is_none() {
+            return Ok(());
+        }
+        let map = map_data_file(&self.file)?;
+        let bytes = unsafe { map.as_slice() };
+        for segment in overlapping {
+            let elements = match segment.decode(bytes) {
+                Some(elements) => elements,
+                None => {
+                    error!("Skipping corrupt segment at {} of '{}' (see stabping fsck).",
+                           segment.offset, self.path.display());
+                    continue;
+                },
+            };
+            let begin = elements.partition_point(|e| e.time < lower);
+            let end = elements.partition_point(|e| e.time <= upper);
+            f(&elements[begin..end])?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Replaces the archive file with the given elements (in order of time),
+     * through a temporary file.
+     */
+    fn rewrite(&mut self, elements: &[DataElement]) -> Result<(), ManagerError> {
+        let tmp_path = self.path.with_extension("archive.tmp");
+        File::create(&tmp_path)
+            .and_then(|mut f| f.write_all(&encode_segments(elements)).and_then(|_| f.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|_| self.write_error())?;
+        *self = Archive::open(&self.path)?;
+        Ok(())
+    }
+
+    /**
+     * Reads back every element archived.
+     */
+    fn read_all(&self) -> Result<Vec<DataElement>, ManagerError> {
+        let mut all = Vec::new();
+        self.read_range(i64::MIN, i64::MAX, &mut |elements| {
+            all.extend_from_slice(elements);
+            Ok(())
+        }).map_err(|_| ManagerError::DataFileIO(SPIOError::Read(Some(self.path.clone()))))?;
+        Ok(all)
+    }
+
+    /**
+     * Removes the elements archived with times before `before`, returning how
+     * many were removed.
+     */
+    pub fn prune(&mut self, before: i64) -> Result<u64, ManagerError> {
+        if self.first_time().is_none_or(|first| first >= before) {
+            return Ok(0);
+        }
+        let all = self.read_all()?;
+        let kept = &all[all.partition_point(|e| e.time < before)..];
+        let pruned = (all.len() - kept.len()) as u64;
+        self.rewrite(kept)?;
+        Ok(pruned)
+    }
+
+    /**
+     * Merges the given elements (ordered by time, and none after those
+     * archived) into those archived like `Storage::import`, returning the
+     * elements that were merged in.
+     */
+    pub fn import(&mut self, elements: &[DataElement]) -> Result<Vec<DataElement>, ManagerError> {
+        let all = self.read_all()?;
+        let mut out = Vec::with_capacity(all.len() + elements.len());
+        let merged = merge_elements(&all, elements, &mut |e| {
+            out.push(*e);
+            Ok(())
+        }).map_err(|_| self.write_error())?;
+        self.rewrite(&out)?;
+        Ok(merged)
+    }
+
+    pub fn sync(&self) -> Result<(), ManagerError> {
+        self.file.sync_all().map_err(|_| self.write_error())
+    }
+}
+
+#[test]
+fn segments_round_trip_and_compress() {
+    // two addresses of four columns, every ten seconds, with a timeout
+    let mut elements = Vec::new();
+    for round in 0..2000i64 {
+        for index in 0..8 {
+            let val = if round == 1000 && index == 0 { -1 } else { 12_000 + (round * 37 % 300) as i32 };
+            elements.push(DataElement { time: 1_500_000_000 + round * 10, index, val });
+        }
+    }
+
+    let bytes = encode_segments(&elements);
+    assert!(bytes.len() * 4 < mem::size_of_val(&elements[..]));
+    let segments = segments(&bytes);
+    assert_eq!(segments.len(), elements.len().div_ceil(SEGMENT_ELEMENTS));
+    let decoded: Vec<DataElement> = segments.iter().flat_map(|s| s.decode(&bytes).unwrap()).collect();
+    assert_eq!(decoded, elements);
+
+    // a torn segment is left out, and a corrupt one fails to decode
+    assert_eq!(complete_len(&bytes[..bytes.len() - 1]), segments[segments.len() - 2].end() as usize);
+    let mut corrupt = bytes.clone();
+    corrupt[HEADER_LEN + 5] ^= 1;
+    assert!(segments[0].decode(&corrupt).is_none());
+}
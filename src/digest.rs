@@ -0,0 +1,202 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Digests summarizing every target over the last day or week (the report of
+ * each address, see `report.rs`, and the incidents in that time), compiled on
+ * schedule and delivered through the notifiers, to catch slow degradation
+ * that never trips an alert.
+ */
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Datelike, Local, TimeZone};
+use serde::Serialize;
+
+use crate::incidents::{Incident, Incidents};
+use crate::notify::{Notifications, format_duration};
+use crate::options::{DigestConfiguration, DigestPeriod};
+use crate::persist::TargetManager;
+use crate::report::{self, AddrReport};
+
+/**
+ * Summary of a single target over the period of a digest.
+ */
+#[derive(Serialize, Debug)]
+pub struct TargetDigest {
+    pub target: &'static str,
+    pub addrs: Vec<AddrReport>,
+    pub incidents: Vec<Incident>,  // those under way at any time of the period
+}
+
+/**
+ * Summary of every target over a day or week, from `from` to `to`.
+ */
+#[derive(Serialize, Debug)]
+pub struct Digest {
+    pub period: DigestPeriod,
+    pub from: i64,
+    pub to: i64,
+    pub targets: Vec<TargetDigest>,
+}
+
+impl Digest {
+    /**
+     * Compiles the digest of the given targets (and their incidents) over the
+     * given period up to the given time.
+     */
+    pub fn compile(managers: &[Arc<TargetManager>], incidents: &Incidents, period: DigestPeriod,
+                   to: i64) -> Self {
+        let from = to - period.secs();
+        let targets = managers.iter()
+            .filter(|tm| !tm.options_read().addrs.is_empty())
+            .map(|tm| {
+                let target = tm.kind.compact_name();
+                let addrs = match report::report(tm, from, to) {
+                    Ok(r) => r.addrs,
+                    Err(e) => {
+                        error!("Failed to read {} data for its digest: {}", target, e);
+                        Vec::new()
+                    },
+                };
+                let mut incidents = incidents.list(Some(target), None);
+                incidents.retain(|i| i.start <= to && i.end.is_none_or(|end| end >= from));
+                TargetDigest {
+                    target,
+                    addrs,
+                    incidents,
+                }
+            })
+            .collect();
+        Digest {
+            period,
+            from,
+            to,
+            targets,
+        }
+    }
+
+    /**
+     * Renders the subject and (plain text) body of a message about the
+     * digest.
+     */
+    pub fn render(&self) -> (String, String) {
+        let time = |t: i64| Local.timestamp(t, 0).format("%Y-%m-%d %H:%M").to_string();
+        let ms = |v: f64| format!("{:.1} ms", v / 1000.0);
+        let subject = format!("stabping {} digest, {} to {}", adjective(self.period), time(self.from), time(self.to));
+
+        let mut body = String::new();
+        for t in self.targets.iter() {
+            body.push_str(t.target);
+            body.push('\n');
+            for a in t.addrs.iter() {
+                let name = a.name.as_ref().map_or_else(String::new, |n| format!(" ({})", n));
+                let uptime = a.uptime.map_or_else(|| "no data".to_owned(), |u| format!("{:.2}% up", u));
+                body.push_str(&format!("  {}{}: {}", a.addr, name, uptime));
+                if let (Some(mean), Some(p95)) = (a.mean, a.p95) {
+                    body.push_str(&format!(", mean {}, p95 {}", ms(mean), ms(p95 as f64)));
+                }
+                if let Some(ref w) = a.worst_hour {
+                    body.push_str(&format!(", worst hour {} ({})", time(w.start), ms(w.mean)));
+                }
+                body.push('\n');
+            }
+            for i in t.incidents.iter() {
+                let duration = match i.duration {
+                    Some(d) => format!("for {}", format_duration(d)),
+                    None => "and still is".to_owned(),
+                };
+                body.push_str(&format!("  {} down {} {} ({})\n", i.addr, time(i.start), duration, i.cause));
+            }
+            if t.incidents.is_empty() {
+                body.push_str("  no incidents\n");
+            }
+        }
+        (subject, body)
+    }
+}
+
+fn adjective(period: DigestPeriod) -> &'static str {
+    match period {
+        DigestPeriod::Day => "daily",
+        DigestPeriod::Week => "weekly",
+    }
+}
+
+/**
+ * Gets the first time after the given one a digest of the given
+ * configuration is due at.
+ */
+pub fn next_due(config: &DigestConfiguration, after: i64) -> i64 {
+    let mut date = Local.timestamp(after, 0).date().naive_local();
+    loop {
+        let on = config.every == DigestPeriod::Day || date.weekday().num_days_from_monday() == config.on as u32;
+        let due = Local.from_local_datetime(&date.and_hms(config.at.0 / 60, config.at.0 % 60, 0)).earliest();
+        match due {
+            Some(t) if on && t.timestamp() > after => return t.timestamp(),
+            _ => date = date.succ(),
+        }
+    }
+}
+
+/**
+ * Starts a thread for each of the given configurations, compiling its digest
+ * of the given targets (and incidents) whenever due, and delivering it
+ * through the given notifications.
+ */
+pub fn run_digests(configs: Vec<DigestConfiguration>, managers: Vec<Arc<TargetManager>>,
+                   incidents: Arc<Incidents>, notifications: Notifications) {
+    for config in configs {
+        let (managers, incidents, notifications) = (managers.clone(), incidents.clone(), notifications.clone());
+        thread::spawn(move || {
+            let mut due = next_due(&config, Local::now().timestamp());
+            loop {
+                // in short naps, to stay on time even if the clock is changed
+                let now = Local::now().timestamp();
+                if now < due {
+                    thread::sleep(Duration::from_secs((due - now).clamp(1, 60) as u64));
+                    continue;
+                }
+                info!("Delivering the {} digest.", adjective(config.every));
+                notifications.send_digest(Digest::compile(&managers, &incidents, config.every, due));
+                due = next_due(&config, now);
+            }
+        });
+    }
+}
+
+#[test]
+fn digests_are_due_at_their_time_of_day() {
+    let config: DigestConfiguration = toml::from_str(r#"at = "08:30""#).unwrap();
+    let monday = Local.ymd(2017, 7, 3).and_hms(9, 0, 0).timestamp();
+    assert_eq!(next_due(&config, monday), Local.ymd(2017, 7, 4).and_hms(8, 30, 0).timestamp());
+    assert_eq!(next_due(&config, monday - 3600), Local.ymd(2017, 7, 3).and_hms(8, 30, 0).timestamp());
+
+    let config: DigestConfiguration = toml::from_str(r#"
+        every = "week"
+        on = "fri"
+    "#).unwrap();
+    assert_eq!(next_due(&config, monday), Local.ymd(2017, 7, 7).and_hms(8, 0, 0).timestamp());
+    let due = next_due(&config, next_due(&config, monday));
+    assert_eq!(due, Local.ymd(2017, 7, 14).and_hms(8, 0, 0).timestamp());
+
+    let digest = Digest {
+        period: DigestPeriod::Week,
+        from: due - DigestPeriod::Week.secs(),
+        to: due,
+        targets: vec![TargetDigest {
+            target: "tcpping",
+            addrs: vec![],
+            incidents: vec![],
+        }],
+    };
+    let (subject, body) = digest.render();
+    assert_eq!(subject, "stabping weekly digest, 2017-07-07 08:00 to 2017-07-14 08:00");
+    assert_eq!(body, "tcpping\n  no incidents\n");
+}
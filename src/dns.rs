@@ -0,0 +1,214 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * DNS query timing. Queries are sent directly to a resolver over UDP so that
+ * we time the resolver itself rather than the operating system's caching.
+ */
+use std::fs;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use time::precise_time_ns;
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_NXDOMAIN, SENTINEL_SERVFAIL,
+                     io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::sockopt;
+use crate::worker::ProbeSettings;
+
+// resolver to fall back on when none is given and none is configured
+static FALLBACK_RESOLVER: &str = "8.8.8.8";
+
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+// query ids shared across all attempts so replies can't be confused
+static QUERY_ID: AtomicU16 = AtomicU16::new(0);
+
+pub static KIND: TargetKind = TargetKind::of(&Dns);
+
+/**
+ * The DNS target, timing a single DNS query described by each addr (see
+ * `DnsQuery`).
+ */
+pub struct Dns;
+
+impl Probe for Dns {
+    type Target = DnsQuery;
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 3,
+        name: "dns",
+        columns: &[""],
+        units: &[],
+        default_timeout: 5_000,
+        keeps_hops: false,
+        keeps_ips: false,
+        default_addrs: &["google.com", "google.com AAAA @8.8.8.8"],
+        default_interval: 10_000,
+        default_avg_across: 3,
+    };
+
+    fn resolve(&self, addr: &str, _: &ProbeSettings) -> Result<DnsQuery, i32> {
+        DnsQuery::parse(addr).ok_or(SENTINEL_ERROR)
+    }
+
+    fn probe_once(&self, _: &str, query: DnsQuery, attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        dns_once(&query, attempt.settings)
+    }
+}
+
+/**
+ * A parsed DNS target addr, written like a `dig` command line, e.g.
+ * `example.com AAAA @1.1.1.1`. The record type defaults to `A` and the
+ * resolver to the system's first configured nameserver.
+ */
+pub struct DnsQuery {
+    name: String,
+    qtype: u16,
+    resolver: SocketAddr,
+}
+
+impl DnsQuery {
+    fn parse(addr: &str) -> Option<Self> {
+        let mut name = None;
+        let mut qtype = 1;
+        let mut resolver = None;
+
+        for token in addr.split_whitespace() {
+            if let Some(r) = token.strip_prefix('@') {
+                resolver = Some(parse_resolver(r)?);
+            } else if let Some(t) = record_type(token) {
+                qtype = t;
+            } else {
+                name = Some(token.trim_end_matches('.').to_owned());
+            }
+        }
+
+        let resolver = match resolver {
+            Some(r) => r,
+            None => parse_resolver(&system_resolver())?,
+        };
+
+        Some(DnsQuery {
+            name: name?,
+            qtype,
+            resolver,
+        })
+    }
+}
+
+/**
+ * Times a single run of the given DNS query.
+ */
+fn dns_once(query: &DnsQuery, settings: &ProbeSettings) -> Result<Vec<u64>, i32> {
+    let socket = sockopt::udp_socket(&query.resolver, settings).map_err(|_| SENTINEL_ERROR)?;
+    socket.set_read_timeout(Some(settings.timeout)).map_err(|_| SENTINEL_ERROR)?;
+    socket.connect(query.resolver).map_err(|e| io_error_sentinel(&e))?;
+
+    let id = QUERY_ID.fetch_add(1, Ordering::Relaxed);
+    let packet = query_packet(id, &query.name, query.qtype);
+
+    let start = precise_time_ns();
+    socket.send(&packet).map_err(|e| io_error_sentinel(&e))?;
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = socket.recv(&mut buf).map_err(|e| io_error_sentinel(&e))?;
+        let elapsed = precise_time_ns() - start;
+
+        // ignore anything that isn't a response to our query
+        if len < 12 || u16::from_be_bytes([buf[0], buf[1]]) != id || buf[2] & 0x80 == 0 {
+            continue;
+        }
+
+        return match response_rcode(&buf[..len]) {
+            0 => Ok(vec![elapsed]),
+            RCODE_NXDOMAIN => Err(SENTINEL_NXDOMAIN),
+            RCODE_SERVFAIL => Err(SENTINEL_SERVFAIL),
+            _ => Err(SENTINEL_ERROR),
+        };
+    }
+}
+
+/**
+ * Builds a recursive DNS query for the given name and record type.
+ */
+pub fn query_packet(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(18 + name.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x01, 0x00]);  // flags: recursion desired
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);  // one question
+
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&[0, 1]);  // class IN
+    packet
+}
+
+/**
+ * Extracts the response code from a DNS response.
+ */
+pub fn response_rcode(response: &[u8]) -> u8 {
+    response[3] & 0x0f
+}
+
+/**
+ * Maps a record type mnemonic (e.g. `AAAA`) to its numeric type.
+ */
+fn record_type(s: &str) -> Option<u16> {
+    Some(match s.to_ascii_uppercase().as_str() {
+        "A" => 1,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => 6,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => 28,
+        "SRV" => 33,
+        "CAA" => 257,
+        "ANY" => 255,
+        _ => return None,
+    })
+}
+
+/**
+ * Parses a resolver given as an IP, IP and port, or resolvable host name
+ * (using port 53 unless otherwise specified).
+ */
+fn parse_resolver(s: &str) -> Option<SocketAddr> {
+    if let Ok(sa) = s.parse::<SocketAddr>() {
+        return Some(sa);
+    }
+    if let Ok(ip) = s.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, 53));
+    }
+    (s, 53).to_socket_addrs().ok()?.next()
+}
+
+/**
+ * Finds the first nameserver configured for the system.
+ */
+fn system_resolver() -> String {
+    fs::read_to_string("/etc/resolv.conf").ok()
+        .and_then(|conf| {
+            conf.lines()
+                .filter_map(|l| l.trim().strip_prefix("nameserver"))
+                .map(|ns| ns.trim().to_owned())
+                .next()
+        })
+        .unwrap_or_else(|| FALLBACK_RESOLVER.to_owned())
+}
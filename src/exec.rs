@@ -0,0 +1,211 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Data collection by external commands, extending stabping to anything a
+ * shell script can measure. Each addr is a command line, run with `sh -c` on
+ * every attempt, printing the measured value (a non-negative number) as the
+ * first word of its output and exiting with status 0, any other status
+ * failing the attempt.
+ *
+ * Since whoever may change the options of the target may then run any
+ * command, commands only run when `allow_exec` is set in the configuration.
+ */
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use time::precise_time_ns;
+
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_EXIT, SENTINEL_TIMEOUT};
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::worker::ProbeSettings;
+
+/**
+ * How often a running command is checked on.
+ */
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+static ALLOWED: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Sets whether commands may be run (see `allow_exec` in the configuration).
+ */
+pub fn allow(allowed: bool) {
+    ALLOWED.store(allowed, Ordering::SeqCst);
+}
+
+pub static KIND: TargetKind = TargetKind::of(&Exec);
+
+/**
+ * The Exec target, running the command of each addr.
+ *
+ * Returns the value printed (in thousandths, so that e.g. a script printing
+ * milliseconds is recorded in microseconds like the durations of the other
+ * kinds), followed by the time the command took to run.
+ */
+pub struct Exec;
+
+impl Probe for Exec {
+    type Target = ();
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 7,
+        name: "exec",
+        columns: &["", "runtime"],
+        units: &[],
+        default_timeout: 10_000,
+        keeps_hops: false,
+        keeps_ips: false,
+        default_addrs: &[],
+        default_interval: 60_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, command: &str, _: &ProbeSettings) -> Result<(), i32> {
+        if !ALLOWED.load(Ordering::SeqCst) {
+            warn!("Commands may only be run with allow_exec set, skipping '{}'.", command);
+            return Err(SENTINEL_ERROR);
+        }
+        Ok(())
+    }
+
+    fn probe_once(&self, command: &str, _: (), attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        exec_once(command, attempt.settings)
+    }
+}
+
+/**
+ * Runs the given command once, killing it (and whatever it started) if it
+ * runs past the timeout.
+ */
+fn exec_once(command: &str, settings: &ProbeSettings) -> Result<Vec<u64>, i32> {
+    let start = precise_time_ns();
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    in_own_group(&mut cmd);
+    let mut child = cmd.spawn().map_err(|_| SENTINEL_ERROR)?;
+
+    // read the output as it comes, so that a chatty command can't fill up the pipe
+    let mut stdout = child.stdout.take().ok_or(SENTINEL_ERROR)?;
+    let reader = thread::spawn(move || {
+        let mut out = String::new();
+        let _ = stdout.read_to_string(&mut out);
+        out
+    });
+
+    let deadline = Instant::now() + settings.timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            Ok(None) => break Err(SENTINEL_TIMEOUT),
+            Err(_) => break Err(SENTINEL_ERROR),
+        }
+    };
+    let ran = precise_time_ns() - start;
+
+    // the output may be held open past the command by what it left running
+    while !reader.is_finished() && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+    let finished = reader.is_finished();
+    if status.is_err() || !finished {
+        kill_group(&mut child);
+    }
+    let _ = child.wait();
+    let out = reader.join().map_err(|_| SENTINEL_ERROR)?;
+
+    let status = status?;
+    if !status.success() {
+        debug!(command, status = status.code().unwrap_or(-1), "Command failed.");
+        return Err(SENTINEL_EXIT);
+    }
+    if !finished {
+        return Err(SENTINEL_TIMEOUT);
+    }
+    let value = parse_value(&out).ok_or(SENTINEL_ERROR)?;
+    Ok(vec![(value * 1e6).round() as u64, ran])
+}
+
+/**
+ * Makes the given command run in a process group of its own, so that it can
+ * be killed along with whatever it starts (e.g. the commands of a pipeline).
+ */
+#[cfg(unix)]
+fn in_own_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn in_own_group(_cmd: &mut Command) {}
+
+/**
+ * Kills the given child, along with the rest of its process group.
+ */
+#[cfg(unix)]
+fn kill_group(child: &mut Child) {
+    // the group is still there while any of it is left, even once the child itself has exited
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        let _ = child.kill();
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/**
+ * Parses the value printed by a command: the first word of its output, being
+ * a non-negative number.
+ */
+fn parse_value(out: &str) -> Option<f64> {
+    out.split_whitespace().next()?.parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0)
+}
+
+#[test]
+fn commands_print_their_value() {
+    assert_eq!(parse_value("12.5 ms\n"), Some(12.5));
+    assert_eq!(parse_value("\n 3\n4\n"), Some(3.0));
+    assert_eq!(parse_value(""), None);
+    assert_eq!(parse_value("-1"), None);
+    assert_eq!(parse_value("NaN"), None);
+    assert_eq!(parse_value("ok"), None);
+
+    let settings = ProbeSettings {
+        timeout: Duration::from_secs(5),
+        resolve_ttl: Duration::from_secs(0),
+        family: crate::options::AddressFamily::Any,
+        pin: None,
+        source: None,
+        proxy: None,
+        dscp: None,
+        tcp: Default::default(),
+    };
+    assert_eq!(exec_once("echo 12.5", &settings).unwrap()[0], 12_500_000);
+    assert_eq!(exec_once("echo 1; exit 3", &settings), Err(SENTINEL_EXIT));
+    assert_eq!(exec_once("echo fine", &settings), Err(SENTINEL_ERROR));
+
+    let settings = ProbeSettings { timeout: Duration::from_millis(50), ..settings };
+    assert_eq!(exec_once("sleep 5", &settings), Err(SENTINEL_TIMEOUT));
+
+    // with all of a pipeline killed, rather than the output being held open by what's left of it
+    for command in ["sleep 5 | cat", "(sleep 5 &); exit 3"] {
+        let start = Instant::now();
+        assert!(exec_once(command, &settings).is_err());
+        assert!(start.elapsed() < Duration::from_secs(2), "{}", command);
+    }
+}
@@ -0,0 +1,396 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Export of the persisted data of a target, round by round, for use outside
+ * of stabping: as CSV (e.g. as evidence for an ISP, in a spreadsheet), as
+ * JSON Lines (NDJSON), with every column of each round, or as Apache Parquet
+ * (for analytics tools such as pandas or DuckDB).
+ */
+use std::io;
+use std::io::{Write, BufWriter};
+use std::mem;
+use std::sync::Arc;
+
+use chrono::{TimeZone, UTC};
+use clap::ValueEnum;
+use iron::response::WriteBody;
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use crate::persist::TargetManager;
+use crate::tags::TagFilter;
+use crate::options::{was_probed, sentinel_name, column_key, SENTINEL_NODATA};
+
+/**
+ * How much of the data is read at a time, in seconds.
+ */
+const EXPORT_WINDOW: i64 = 86400;
+
+/**
+ * The formats data can be exported in.
+ */
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+/**
+ * A function called with a round of an address: its time, the address and its
+ * values.
+ */
+pub type RoundFn<'a> = dyn FnMut(i64, &str, &[i32]) -> io::Result<()> + 'a;
+
+/**
+ * Calls `f` with every round of every address of the given target (current,
+ * or since removed, see `TargetManager::known_addrs`) whose tags match the
+ * given filter (removed ones having none) from `from` to `to`
+ * (inclusive), in order of time: the time of the round, the address and its
+ * values of each of the target's current columns (in order of
+ * `TargetKind::columns`, `SENTINEL_NODATA` where missing). Addresses that
+ * weren't probed in a round (e.g. while paused, or since removed) are left
+ * out of it.
+ */
+pub fn for_each_round(tm: &TargetManager, from: i64, to: i64, tags: &TagFilter,
+                      f: &mut RoundFn) -> io::Result<()> {
+    let mut addrs = tm.known_addrs();
+    let columns = {
+        let options = tm.options_read();
+        addrs.retain(|a| tags.matches(options.tags_of(a)));
+        tm.kind.columns(&options)
+    };
+    let num_columns = columns.len();
+    let keys: Vec<String> = addrs.iter()
+        .flat_map(|addr| columns.iter().map(move |column| column_key(addr, column)))
+        .collect();
+    let indices = tm.find_indices(&keys);
+
+    // where each index goes in a round, in order of addrs
+    let mut positions = vec![None; indices.iter().flatten().max().map_or(0, |&i| i as usize + 1)];
+    for (p, i) in indices.iter().enumerate() {
+        if let Some(i) = *i {
+            positions[i as usize] = Some(p);
+        }
+    }
+
+    let mut round = vec![SENTINEL_NODATA; keys.len()];
+    let mut cur = None;
+    let mut flush = |time: i64, round: &mut [i32]| -> io::Result<()> {
+        for (addr, values) in addrs.iter().zip(round.chunks(num_columns.max(1))) {
+            if values.iter().any(|&v| was_probed(v)) {
+                f(time, addr, values)?;
+            }
+        }
+        round.iter_mut().for_each(|v| *v = SENTINEL_NODATA);
+        Ok(())
+    };
+
+    /*
+     * read a day at a time, so that the storage isn't held for the whole
+     * export (which may be streamed out slowly)
+     */
+    let mut window = from;
+    while window <= to {
+        let window_end = window.saturating_add(EXPORT_WINDOW - 1).min(to);
+        tm.read_data_range(window, window_end, &mut |elements| {
+            for d in elements.iter() {
+                let p = match positions.get(d.index as usize) {
+                    Some(&Some(p)) => p,
+                    _ => continue,
+                };
+                match cur {
+                    Some(t) if t != d.time => {
+                        flush(t, &mut round)?;
+                        cur = Some(d.time);
+                    },
+                    Some(_) => {},
+                    None => cur = Some(d.time),
+                }
+                round[p] = d.val;
+            }
+            Ok(())
+        })?;
+        if window_end == to {
+            break;
+        }
+        window = window_end + 1;
+    }
+
+    match cur {
+        Some(t) => flush(t, &mut round),
+        None => Ok(()),
+    }
+}
+
+/**
+ * Quotes the given field of a CSV row, if necessary.
+ */
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+pub const CSV_HEADER: &str = "timestamp,address,value,loss,error\n";
+
+/**
+ * Writes a round of an address (see `for_each_round`) as a CSV row: its time
+ * (UTC, in ISO 8601), its primary value (in microseconds, or empty if it
+ * failed), its loss (a percentage, if it has a loss column at the given
+ * position) and what failed (if anything).
+ */
+fn write_csv_row(out: &mut dyn Write, time: i64, addr: &str, values: &[i32],
+                 loss_at: Option<usize>) -> io::Result<()> {
+    let primary = values[0];
+    let value = if primary >= 0 { primary.to_string() } else { String::new() };
+    let loss = loss_at.map(|l| values[l]).filter(|&l| l >= 0).map_or(String::new(), |l| l.to_string());
+    let error = if primary < 0 && was_probed(primary) { sentinel_name(primary) } else { "" };
+    writeln!(out, "{},{},{},{},{}",
+             UTC.timestamp(time, 0).format("%Y-%m-%dT%H:%M:%SZ"), csv_field(addr), value, loss, error)
+}
+
+/**
+ * Writes a round of an address (see `for_each_round`) as a JSON object on a
+ * line of its own: its time (in seconds since the epoch), the address, its
+ * value of each of the given columns (the primary one as `value`, null where
+ * failed or missing) and what failed (if anything).
+ */
+fn write_ndjson_row(out: &mut dyn Write, time: i64, addr: &str, columns: &[String],
+                    values: &[i32]) -> io::Result<()> {
+    // written out by hand to keep the fields in order
+    let json = |s: &str| serde_json::to_string(s).unwrap();
+    write!(out, "{{\"time\":{},\"addr\":{}", time, json(addr))?;
+    for (column, &v) in columns.iter().zip(values.iter()) {
+        let name = if column.is_empty() { "value" } else { column };
+        if v >= 0 {
+            write!(out, ",{}:{}", json(name), v)?;
+        } else {
+            write!(out, ",{}:null", json(name))?;
+        }
+    }
+    let primary = values[0];
+    if primary < 0 && was_probed(primary) {
+        write!(out, ",\"error\":{}", json(sentinel_name(primary)))?;
+    }
+    out.write_all(b"}\n")
+}
+
+const PARQUET_SCHEMA: &str = "
+    message stabping {
+        REQUIRED INT64 timestamp (TIMESTAMP(MILLIS, true));
+        REQUIRED BYTE_ARRAY target (UTF8);
+        REQUIRED BYTE_ARRAY address (UTF8);
+        OPTIONAL INT32 latency_us;
+        OPTIONAL BYTE_ARRAY error_code (UTF8);
+    }
+";
+
+/**
+ * How many rounds are written to each row group of a Parquet export.
+ */
+const PARQUET_ROW_GROUP: usize = 64 * 1024;
+
+/**
+ * The rounds of a row group of a Parquet export, column by column. Optional
+ * columns hold only the values present, along with whether each row has one.
+ */
+#[derive(Default)]
+struct ParquetRows {
+    timestamps: Vec<i64>,
+    addresses: Vec<ByteArray>,
+    latencies: Vec<i32>,
+    has_latency: Vec<i16>,
+    errors: Vec<ByteArray>,
+    has_error: Vec<i16>,
+}
+
+impl ParquetRows {
+    fn push(&mut self, time: i64, addr: &str, primary: i32) {
+        self.timestamps.push(time * 1000);
+        self.addresses.push(ByteArray::from(addr));
+        self.has_latency.push((primary >= 0) as i16);
+        if primary >= 0 {
+            self.latencies.push(primary);
+        }
+        let failed = primary < 0 && was_probed(primary);
+        self.has_error.push(failed as i16);
+        if failed {
+            self.errors.push(ByteArray::from(sentinel_name(primary)));
+        }
+    }
+
+    /**
+     * Writes these rows as a row group of the given file.
+     */
+    fn write_to<W: Write + Send>(&self, file: &mut SerializedFileWriter<W>,
+                                 target: &str) -> Result<(), ParquetError> {
+        let targets = vec![ByteArray::from(target); self.timestamps.len()];
+        let mut row_group = file.next_row_group()?;
+        let mut n = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match n {
+                0 => column.typed::<Int64Type>().write_batch(&self.timestamps, None, None)?,
+                1 => column.typed::<ByteArrayType>().write_batch(&targets, None, None)?,
+                2 => column.typed::<ByteArrayType>().write_batch(&self.addresses, None, None)?,
+                3 => column.typed::<Int32Type>()
+                    .write_batch(&self.latencies, Some(&self.has_latency), None)?,
+                _ => column.typed::<ByteArrayType>()
+                    .write_batch(&self.errors, Some(&self.has_error), None)?,
+            };
+            column.close()?;
+            n += 1;
+        }
+        row_group.close()?;
+        Ok(())
+    }
+}
+
+/**
+ * Writes every round of every current address of the given target (matching
+ * the given filter) from `from` to `to` (inclusive) as a Parquet file of
+ * (timestamp, target, address, latency_us, error_code) rows, a row group at a
+ * time.
+ */
+fn write_parquet(tm: &TargetManager, from: i64, to: i64, tags: &TagFilter,
+                 out: &mut dyn Write) -> io::Result<()> {
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).unwrap());
+    let props = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+    // each row group is written out as soon as it is complete
+    let mut file = SerializedFileWriter::new(Vec::new(), schema, props).map_err(io::Error::other)?;
+    let target = tm.kind.compact_name();
+
+    let mut rows = ParquetRows::default();
+    let mut write_rows = |rows: &mut ParquetRows,
+                          file: &mut SerializedFileWriter<Vec<u8>>| -> io::Result<()> {
+        rows.write_to(file, target).map_err(io::Error::other)?;
+        file.flush()?;
+        out.write_all(&mem::take(file.inner_mut()))?;
+        *rows = ParquetRows::default();
+        Ok(())
+    };
+    for_each_round(tm, from, to, tags, &mut |time, addr, values| {
+        rows.push(time, addr, values[0]);
+        if rows.timestamps.len() >= PARQUET_ROW_GROUP {
+            write_rows(&mut rows, &mut file)?;
+        }
+        Ok(())
+    })?;
+    if !rows.timestamps.is_empty() {
+        write_rows(&mut rows, &mut file)?;
+    }
+
+    let footer = file.into_inner().map_err(io::Error::other)?;
+    out.write_all(&footer)?;
+    out.flush()
+}
+
+/**
+ * Writes every round of every current address of the given target (matching
+ * the given filter) from `from` to `to` (inclusive) in the given format, as
+ * it goes.
+ */
+pub fn write_export(tm: &TargetManager, format: ExportFormat, from: i64, to: i64, tags: &TagFilter,
+                    out: &mut dyn Write) -> io::Result<()> {
+    let columns = tm.kind.columns(&tm.options_read());
+    let mut writer = BufWriter::new(out);
+    match format {
+        ExportFormat::Csv => {
+            let loss_at = columns.iter().position(|c| c == "loss");
+            writer.write_all(CSV_HEADER.as_bytes())?;
+            for_each_round(tm, from, to, tags, &mut |time, addr, values| {
+                write_csv_row(&mut writer, time, addr, values, loss_at)
+            })?;
+        },
+        ExportFormat::Ndjson => {
+            for_each_round(tm, from, to, tags, &mut |time, addr, values| {
+                write_ndjson_row(&mut writer, time, addr, &columns, values)
+            })?;
+        },
+        ExportFormat::Parquet => write_parquet(tm, from, to, tags, &mut writer)?,
+    }
+    writer.flush()
+}
+
+/**
+ * An Iron body writer streaming an export of a target's data.
+ */
+pub struct Export {
+    pub tm: Arc<TargetManager>,
+    pub format: ExportFormat,
+    pub from: i64,
+    pub to: i64,
+    pub tags: TagFilter,  // of the addresses exported
+}
+
+impl WriteBody for Export {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        write_export(&self.tm, self.format, self.from, self.to, &self.tags, res)
+    }
+}
+
+#[test]
+fn rows_hold_value_loss_and_error() {
+    let mut out = Vec::new();
+    write_csv_row(&mut out, 1_483_228_800, "google.com:80", &[1234, 30, 0, 12], Some(2)).unwrap();
+    write_csv_row(&mut out, 1_483_228_810, "http://a/?b,c", &[crate::options::SENTINEL_TIMEOUT, 30, 100, 0],
+                  Some(2)).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "2017-01-01T00:00:00Z,google.com:80,1234,0,\n\
+                2017-01-01T00:00:10Z,\"http://a/?b,c\",,100,timeout\n");
+
+    let mut out = Vec::new();
+    let columns: Vec<String> = ["", "dns", "loss"].iter().map(|c| c.to_string()).collect();
+    write_ndjson_row(&mut out, 1_483_228_800, "a:80", &columns, &[1234, 30, 0]).unwrap();
+    write_ndjson_row(&mut out, 1_483_228_810, "a:80", &columns,
+                     &[crate::options::SENTINEL_TIMEOUT, 30, 100]).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "{\"time\":1483228800,\"addr\":\"a:80\",\"value\":1234,\"dns\":30,\"loss\":0}\n\
+                {\"time\":1483228810,\"addr\":\"a:80\",\"value\":null,\"dns\":30,\"loss\":100,\"error\":\"timeout\"}\n");
+}
+
+#[test]
+fn parquet_rows_read_back() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let mut rows = ParquetRows::default();
+    rows.push(1_483_228_800, "a:80", 1234);
+    rows.push(1_483_228_810, "a:80", crate::options::SENTINEL_TIMEOUT);
+
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).unwrap());
+    let mut file = SerializedFileWriter::new(Vec::new(), schema, Default::default()).unwrap();
+    rows.write_to(&mut file, "tcpping").unwrap();
+    let path = std::env::temp_dir().join(format!("stabping-test-{}.parquet", std::process::id()));
+    std::fs::write(&path, file.into_inner().unwrap()).unwrap();
+
+    let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+    let read: Vec<String> = reader.get_row_iter(None).unwrap()
+        .map(|r| r.unwrap().to_string())
+        .collect();
+    assert_eq!(read.len(), 2);
+    assert!(read[0].contains("target: \"tcpping\"") && read[0].contains("latency_us: 1234"));
+    assert!(read[1].contains("latency_us: null") && read[1].contains("error_code: \"timeout\""));
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Fanning out of the host names of targets with `fan_out` set to every IP
+ * they resolve to, so that the variance between the backends behind a CDN or
+ * round-robin DNS shows, rather than hiding behind whichever IP the resolver
+ * returned first.
+ *
+ * Each IP of an address is an address of its own, `<addr> at <ip>`, probed as
+ * the address it is of but pinned to the IP (see `ProbeSettings::pin`), and
+ * so stored as a series of its own. The address itself is still probed as
+ * before. Every `FAN_OUT_INTERVAL`, host names are resolved afresh, adding the
+ * addresses of new IPs and removing those of IPs no longer returned (keeping
+ * their data, as for any address removed).
+ */
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::options::{TargetOptions, next_nonce};
+use crate::persist::TargetManager;
+
+/**
+ * How often the host names of targets fanning out are resolved afresh.
+ */
+const FAN_OUT_INTERVAL: Duration = Duration::from_secs(60);
+
+/**
+ * Gets the address of the given IP of the given address.
+ */
+pub fn fanned_addr(addr: &str, ip: &IpAddr) -> String {
+    format!("{} at {}", addr, ip)
+}
+
+/**
+ * Returns the given options (with the next nonce) with their fanned out
+ * addresses matching the IPs the others resolve to by `ips_of` (see
+ * `AnyProbe::ips_of`), or None if they already do. Addresses that can't be
+ * resolved keep those they had, and without `fan_out`, none are kept.
+ */
+pub fn fan_out(options: &TargetOptions, ips_of: &dyn Fn(&str) -> Option<Vec<IpAddr>>) -> Option<TargetOptions> {
+    // the fanned out addresses there should be, and the address each is of
+    let mut wanted: Vec<(String, String)> = Vec::new();
+    if options.fan_out {
+        for addr in options.addrs.iter().filter(|a| !options.is_remote(a) && !options.fanned.contains_key(*a)) {
+            match ips_of(addr) {
+                Some(ips) => wanted.extend(ips.iter().map(|ip| (fanned_addr(addr, ip), addr.clone()))),
+                None => wanted.extend(options.fanned.iter()
+                    .filter(|&(_, parent)| parent == addr)
+                    .map(|(a, parent)| (a.clone(), parent.clone()))),
+            }
+        }
+    }
+
+    let mut new = options.clone();
+    for addr in options.fanned.keys().filter(|a| !wanted.iter().any(|(w, _)| w == *a)) {
+        new.addrs.retain(|a| a != addr);
+        new.forget_addr(addr);
+    }
+    for (addr, parent) in wanted {
+        if new.fanned.contains_key(&addr) {
+            continue;
+        }
+        // taking after the address it is of
+        if let Some(tags) = options.tags_of(&parent) {
+            new.tags.insert(addr.clone(), tags.clone());
+        }
+        new.addrs.push(addr.clone());
+        new.fanned.insert(addr, parent);
+    }
+
+    if new == *options {
+        None
+    } else {
+        new.nonce = next_nonce(options.nonce);
+        Some(new)
+    }
+}
+
+/**
+ * Fans out the addresses of the given targets (see `fan_out`) on startup, and
+ * then every `FAN_OUT_INTERVAL`.
+ */
+pub fn run_fan_out(targets: Vec<Arc<TargetManager>>) {
+    thread::spawn(move || {
+        loop {
+            for tm in targets.iter() {
+                let options = tm.options_read().clone();
+                if !options.fan_out && options.fanned.is_empty() {
+                    continue;
+                }
+                let probe = tm.kind.probe();
+                let new = match fan_out(&options, &|addr| probe.ips_of(addr, options.family)) {
+                    Some(n) => n,
+                    None => continue,
+                };
+                // unless the options changed while resolving
+                if tm.options_read().nonce != options.nonce {
+                    continue;
+                }
+                info!("Fanning out {} to {} addresses.", tm.kind.compact_name(), new.fanned.len());
+                if let Err(e) = tm.options_update(new) {
+                    error!("Failed to fan out {}: {}", tm.kind.compact_name(), e);
+                }
+            }
+            thread::sleep(FAN_OUT_INTERVAL);
+        }
+    });
+}
+
+#[test]
+fn addresses_fan_out_to_their_ips() {
+    let mut options = crate::tcpping::KIND.default_options();
+    options.addrs = vec!["cdn.example:443".to_owned(), "192.0.2.9:80".to_owned()];
+    options.tags.insert("cdn.example:443".to_owned(), [("env".to_owned(), "prod".to_owned())].into());
+    options.fan_out = true;
+    let ips = |addr: &str| -> Option<Vec<IpAddr>> {
+        match addr {
+            "cdn.example:443" => Some(vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()]),
+            _ => Some(Vec::new()),
+        }
+    };
+
+    let fanned = fan_out(&options, &ips).unwrap();
+    assert_eq!(fanned.nonce, 1);
+    assert_eq!(fanned.addrs, vec!["cdn.example:443", "192.0.2.9:80",
+                                  "cdn.example:443 at 192.0.2.1", "cdn.example:443 at 2001:db8::1"]);
+    assert_eq!(fanned.fanned_out("cdn.example:443 at 2001:db8::1"),
+               Some(("cdn.example:443", "2001:db8::1".parse().unwrap())));
+    assert_eq!(fanned.tags_of("cdn.example:443 at 192.0.2.1").unwrap()["env"], "prod");
+    assert_eq!(fan_out(&fanned, &ips), None);
+
+    // an IP no longer returned is removed, while failing to resolve keeps those there are
+    let one = |addr: &str| Some(if addr == "cdn.example:443" { vec!["192.0.2.1".parse().unwrap()] } else { Vec::new() });
+    let moved = fan_out(&fanned, &one).unwrap();
+    assert_eq!(moved.addrs.len(), 3);
+    assert_eq!(fan_out(&moved, &|_| None), None);
+
+    let mut off = moved.clone();
+    off.fan_out = false;
+    assert_eq!(fan_out(&off, &ips).unwrap().addrs, vec!["cdn.example:443", "192.0.2.9:80"]);
+}
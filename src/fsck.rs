@@ -0,0 +1,425 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Checks of the files of the data directory for corruption (e.g. by bit rot
+ * on an SD card), and their repair where possible: dropping the blocks of data
+ * files that fail their checksums (see `Checksums`), segments of archive files
+ * that do (see `compress.rs`) and elements that are out of order or of unknown
+ * indices, discarding anything incomplete (or, in schema files, unreadable),
+ * rolling up broken rollup files again, setting aside unreadable JSON files
+ * and rebuilding the indices of SQLite databases.
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write, BufWriter};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::helpers::SPIOError;
+use crate::compress;
+use crate::hops;
+use crate::persist::DataElement;
+use crate::rollup::RollupElement;
+use crate::schema::Layout;
+use crate::storage::{BLOCK_LEN, block_checksum};
+
+/**
+ * A problem found with a file of the data directory.
+ */
+pub struct Finding {
+    pub file: String,
+    pub problem: String,
+    pub repaired: bool,
+}
+
+struct Fsck {
+    data_path: PathBuf,
+    repair: bool,
+    findings: Vec<Finding>,
+}
+
+/**
+ * Reads into the given buffer until it is full, or the end of the given file.
+ */
+fn read_block(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read(&mut buf[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n)
+}
+
+impl Fsck {
+    fn found(&mut self, file: &str, problem: String) {
+        let repaired = self.repair;
+        self.findings.push(Finding { file: file.to_owned(), problem, repaired });
+    }
+
+    /**
+     * Replaces the given file with the one written to the given temporary
+     * path.
+     */
+    fn replace(&self, tmp_path: &Path, path: &Path) -> Result<(), SPIOError> {
+        fs::rename(tmp_path, path).map_err(|_| SPIOError::Write(Some(path.to_owned())))
+    }
+
+    /**
+     * Checks an index file, returning its number of (complete) lines.
+     */
+    fn check_index(&mut self, name: &str) -> Result<usize, SPIOError> {
+        let path = self.data_path.join(name);
+        let bytes = fs::read(&path).map_err(|_| SPIOError::Read(Some(path.clone())))?;
+        let complete = bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let lines: Vec<&[u8]> = bytes[..complete].split(|&b| b == b'\n').collect();
+        let lines = &lines[..lines.len() - 1];
+
+        let invalid = lines.iter().filter(|l| std::str::from_utf8(l).is_err()).count();
+        if complete < bytes.len() {
+            self.found(name, format!("the last {} bytes are an incomplete line", bytes.len() - complete));
+        }
+        if invalid > 0 {
+            self.found(name, format!("{} addresses are of invalid UTF-8 (replaced, keeping their indices)", invalid));
+        }
+        if self.repair && (invalid > 0 || complete < bytes.len()) {
+            let tmp_path = path.with_extension("json.tmp");
+            let mut fixed = String::new();
+            for line in lines.iter() {
+                fixed.push_str(&String::from_utf8_lossy(line));
+                fixed.push('\n');
+            }
+            fs::write(&tmp_path, fixed).map_err(|_| SPIOError::Write(Some(tmp_path.clone())))?;
+            self.replace(&tmp_path, &path)?;
+        }
+        Ok(lines.len())
+    }
+
+    /**
+     * Checks a data file against its checksums, and that its elements are in
+     * order of time and of indices in its index file (of the given length).
+     */
+    fn check_data(&mut self, name: &str, index_len: Option<usize>) -> Result<(), SPIOError> {
+        let path = self.data_path.join(name);
+        let read_error = || SPIOError::Read(Some(path.clone()));
+        let sums: Vec<u32> = fs::read(path.with_extension("sums")).unwrap_or_default()
+            .chunks_exact(4)
+            .map(|c| u32::from_ne_bytes(c.try_into().unwrap()))
+            .collect();
+        let mut file = File::open(&path).map_err(|_| SPIOError::Open(Some(path.clone())))?;
+
+        // what is kept is written out to a temporary file, to replace it if repairing
+        let tmp_path = path.with_extension("dat.tmp");
+        let mut out = match self.repair {
+            true => Some(BufWriter::new(File::create(&tmp_path)
+                .map_err(|_| SPIOError::Open(Some(tmp_path.clone())))?)),
+            false => None,
+        };
+
+        let size = mem::size_of::<DataElement>();
+        let mut buf = vec![0; BLOCK_LEN];
+        let mut bad_blocks = Vec::new();
+        let (mut unknown, mut out_of_order, mut incomplete) = (0, 0, 0);
+        let mut last_time = i64::MIN;
+        for block in 0.. {
+            let n = read_block(&mut file, &mut buf).map_err(|_| read_error())?;
+            if n == 0 {
+                break;
+            }
+            if n == BLOCK_LEN && sums.get(block).is_some_and(|&sum| sum != block_checksum(&buf)) {
+                bad_blocks.push(block.to_string());
+                continue;
+            }
+            incomplete = n % size;
+            for e in buf[..n - incomplete].chunks_exact(size) {
+                let time = i64::from_ne_bytes(e[..8].try_into().unwrap());
+                let index = i32::from_ne_bytes(e[8..12].try_into().unwrap());
+                if index < 0 || index_len.is_some_and(|l| index as usize >= l) {
+                    unknown += 1;
+                } else if time < last_time {
+                    out_of_order += 1;
+                } else {
+                    last_time = time;
+                    if let Some(ref mut out) = out {
+                        out.write_all(e).map_err(|_| SPIOError::Write(Some(tmp_path.clone())))?;
+                    }
+                }
+            }
+        }
+
+        let problems = [
+            (bad_blocks.len(), format!("blocks of {} elements fail their checksums (blocks {})",
+                                       BLOCK_LEN / size, bad_blocks.join(", "))),
+            (unknown, "elements are of indices not in the index file".to_owned()),
+            (out_of_order, "elements are out of order of time".to_owned()),
+            (incomplete, "bytes at the end are of an incomplete element".to_owned()),
+        ];
+        let found = problems.iter().any(|&(n, _)| n > 0);
+        for (n, problem) in problems.iter() {
+            if *n > 0 {
+                self.found(name, format!("{} {}", n, problem));
+            }
+        }
+
+        if let Some(out) = out {
+            out.into_inner().map_err(|_| SPIOError::Write(Some(tmp_path.clone())))?
+                .sync_all().map_err(|_| SPIOError::Write(Some(tmp_path.clone())))?;
+            if found {
+                self.replace(&tmp_path, &path)?;
+                // which are written again for what is left when next opened
+                let _ = fs::remove_file(path.with_extension("commit"));
+                let _ = fs::remove_file(path.with_extension("sums"));
+            } else {
+                let _ = fs::remove_file(&tmp_path);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks that every segment of an archive file decodes (matching its
+     * checksum), in order of time and of indices in its index file (of the
+     * given length). Those that don't (and any incomplete at the end) are
+     * dropped.
+     */
+    fn check_archive(&mut self, name: &str, index_len: Option<usize>) -> Result<(), SPIOError> {
+        let path = self.data_path.join(name);
+        let bytes = fs::read(&path).map_err(|_| SPIOError::Read(Some(path.clone())))?;
+        let segments = compress::segments(&bytes);
+        let complete = segments.last().map_or(0, |s| s.end() as usize);
+
+        let mut kept = Vec::new();
+        let mut bad = Vec::new();
+        let mut last_time = i64::MIN;
+        for segment in segments.iter() {
+            let known = |e: &DataElement| e.index >= 0 && index_len.is_none_or(|l| (e.index as usize) < l);
+            match segment.decode(&bytes) {
+                Some(elements) if segment.first >= last_time && elements.iter().all(known) => {
+                    last_time = segment.last;
+                    kept.extend(elements);
+                },
+                _ => bad.push(segment.offset.to_string()),
+            }
+        }
+
+        if complete < bytes.len() {
+            self.found(name, format!("the last {} bytes are an incomplete segment", bytes.len() - complete));
+        }
+        if !bad.is_empty() {
+            self.found(name, format!("{} segments are corrupt or out of order (at {})", bad.len(), bad.join(", ")));
+        }
+        if self.repair && (!bad.is_empty() || complete < bytes.len()) {
+            let tmp_path = path.with_extension("archive.tmp");
+            fs::write(&tmp_path, compress::encode_segments(&kept))
+                .map_err(|_| SPIOError::Write(Some(tmp_path.clone())))?;
+            self.replace(&tmp_path, &path)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks that a rollup file is of whole elements in order of time and
+     * index. Broken ones are removed, to be rolled up again from the data.
+     */
+    fn check_rollup(&mut self, name: &str) -> Result<(), SPIOError> {
+        let path = self.data_path.join(name);
+        let bytes = fs::read(&path).map_err(|_| SPIOError::Read(Some(path.clone())))?;
+        let size = mem::size_of::<RollupElement>();
+        let keys: Vec<(i64, i32)> = bytes.chunks_exact(size)
+            .map(|e| (i64::from_ne_bytes(e[..8].try_into().unwrap()),
+                      i32::from_ne_bytes(e[8..12].try_into().unwrap())))
+            .collect();
+
+        let problem = if bytes.len() % size != 0 {
+            Some(format!("the last {} bytes are of an incomplete element", bytes.len() % size))
+        } else {
+            keys.windows(2).position(|w| w[0] >= w[1])
+                .map(|i| format!("element {} is out of order", i + 1))
+        };
+        if let Some(problem) = problem {
+            self.found(name, format!("{} (to be rolled up again from the data)", problem));
+            if self.repair {
+                fs::remove_file(&path).map_err(|_| SPIOError::Write(Some(path.clone())))?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks that a hops (or IPs) file is of complete records.
+     */
+    fn check_hops(&mut self, name: &str) -> Result<(), SPIOError> {
+        let path = self.data_path.join(name);
+        let bytes = fs::read(&path).map_err(|_| SPIOError::Read(Some(path.clone())))?;
+        let complete = hops::complete_len(&bytes);
+        if complete < bytes.len() {
+            self.found(name, format!("the last {} bytes are of an incomplete record", bytes.len() - complete));
+            if self.repair {
+                fs::write(&path, &bytes[..complete]).map_err(|_| SPIOError::Write(Some(path.clone())))?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks that every line of a schema file is a layout. Those that aren't
+     * (and any incomplete line at the end) are dropped.
+     */
+    fn check_schema(&mut self, name: &str) -> Result<(), SPIOError> {
+        let path = self.data_path.join(name);
+        let text = fs::read(&path).map_err(|_| SPIOError::Read(Some(path.clone())))?;
+        let text = String::from_utf8_lossy(&text);
+        let complete = text.rfind('\n').map_or(0, |i| i + 1);
+        let (kept, bad): (Vec<&str>, Vec<&str>) = text[..complete].lines()
+            .partition(|l| serde_json::from_str::<Layout>(l).is_ok());
+
+        if complete < text.len() {
+            self.found(name, format!("the last {} bytes are an incomplete line", text.len() - complete));
+        }
+        if !bad.is_empty() {
+            self.found(name, format!("{} lines aren't layouts", bad.len()));
+        }
+        if self.repair && (!bad.is_empty() || complete < text.len()) {
+            let tmp_path = path.with_extension("json.tmp");
+            let fixed: String = kept.iter().map(|l| format!("{}\n", l)).collect();
+            fs::write(&tmp_path, fixed).map_err(|_| SPIOError::Write(Some(tmp_path.clone())))?;
+            self.replace(&tmp_path, &path)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks that a JSON file (e.g. of options) can be read. Those that can't
+     * are set aside, to be replaced by the defaults.
+     */
+    fn check_json(&mut self, name: &str) -> Result<(), SPIOError> {
+        let path = self.data_path.join(name);
+        let bytes = fs::read(&path).map_err(|_| SPIOError::Read(Some(path.clone())))?;
+        if let Err(e) = serde_json::from_slice::<serde_json::Value>(&bytes) {
+            let aside = format!("{}.corrupt", name);
+            self.found(name, format!("can't be read ({}), to be set aside as {}", e, aside));
+            if self.repair {
+                self.replace(&path, &self.data_path.join(aside))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn check_sqlite(&mut self, name: &str) -> Result<(), SPIOError> {
+        let path = self.data_path.join(name);
+        let problems = crate::sqlite::check_integrity(&path, false)?;
+        if !problems.is_empty() {
+            // rebuilding the indices is all that can be done
+            let left = match self.repair {
+                true => crate::sqlite::check_integrity(&path, true)?,
+                false => problems.clone(),
+            };
+            self.findings.push(Finding {
+                file: name.to_owned(),
+                problem: format!("fails its integrity check ({})", problems.join("; ")),
+                repaired: self.repair && left.is_empty(),
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn check_sqlite(&mut self, name: &str) -> Result<(), SPIOError> {
+        self.findings.push(Finding {
+            file: name.to_owned(),
+            problem: "can't be checked, stabping was built without the sqlite feature".to_owned(),
+            repaired: false,
+        });
+        Ok(())
+    }
+}
+
+/**
+ * Checks every file of the given data directory, repairing what can be if
+ * asked to (which must only be with stabping stopped), and returns what was
+ * found.
+ */
+pub fn fsck(data_path: &Path, repair: bool) -> Result<Vec<Finding>, SPIOError> {
+    let mut names: Vec<String> = fs::read_dir(data_path)
+        .map_err(|_| SPIOError::Read(Some(data_path.to_owned())))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    let mut fsck = Fsck { data_path: data_path.to_owned(), repair, findings: Vec::new() };
+
+    // the index files first, so data files can be checked against them
+    let mut index_lens = HashMap::new();
+    for name in names.iter().filter(|n| n.ends_with(".index.json")) {
+        let kind = name.trim_end_matches(".index.json").to_owned();
+        index_lens.insert(kind, fsck.check_index(name)?);
+    }
+    for name in names.iter() {
+        let kind = name.split('.').next().unwrap_or("");
+        if name.ends_with(".data64.dat") {
+            fsck.check_data(name, index_lens.get(kind).copied())?;
+        } else if name.ends_with(".data64.archive") {
+            fsck.check_archive(name, index_lens.get(kind).copied())?;
+        } else if name.contains(".rollup-") && name.ends_with(".dat") {
+            fsck.check_rollup(name)?;
+        } else if name.ends_with(".hops.dat") || name.ends_with(".ips.dat") {
+            fsck.check_hops(name)?;
+        } else if name.ends_with(".sqlite") {
+            fsck.check_sqlite(name)?;
+        } else if name.ends_with(".schema.json") {
+            fsck.check_schema(name)?;
+        } else if name.ends_with(".json") && !name.ends_with(".index.json") && name != "backup.json" {
+            fsck.check_json(name)?;
+        }
+    }
+    Ok(fsck.findings)
+}
+
+#[test]
+fn fsck_drops_corrupt_blocks() {
+    use crate::storage::{FileStorage, Storage};
+
+    let dir = std::env::temp_dir().join(format!("stabping-test-fsck-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("tcpping.data64.dat");
+    fs::write(dir.join("tcpping.index.json"), "a:80\nb:80\n").unwrap();
+
+    // two whole blocks and a bit, then bit rot in the first
+    let per_block = (BLOCK_LEN / mem::size_of::<DataElement>()) as i64;
+    let storage = FileStorage::open(&path).unwrap();
+    for time in 0..per_block * 2 + 10 {
+        storage.append(&[DataElement { time, index: (time % 2) as i32, val: 0 }]).unwrap();
+    }
+    drop(storage);
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[100] ^= 0x10;
+    fs::write(&path, &bytes).unwrap();
+    assert!(!fsck(&dir, false).unwrap()[0].repaired);
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+
+    let findings = fsck(&dir, true).unwrap();
+    assert_eq!(findings.len(), 1);
+    assert!(findings[0].problem.starts_with("1 blocks") && findings[0].repaired);
+    assert!(fsck(&dir, false).unwrap().is_empty());
+
+    let storage = FileStorage::open(&path).unwrap();
+    let mut times = Vec::new();
+    storage.read_range(i64::MIN, i64::MAX, &mut |elements| {
+        times.extend(elements.iter().map(|e| e.time));
+        Ok(())
+    }).unwrap();
+    assert_eq!(times, (per_block..per_block * 2 + 10).collect::<Vec<_>>());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,260 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The search, query and annotations requests of Grafana's JSON datasources
+ * (SimpleJSON, and the Infinity plugin in its JSON mode), graphing persisted
+ * data natively. Each column of each address of each target is a metric
+ * named `<target>/<addr>` (for the primary column) or
+ * `<target>/<addr>/<column>`, and incidents and alert changes are
+ * annotations.
+ */
+use std::io;
+use std::sync::Arc;
+
+use chrono::DateTime;
+use serde::{Serialize, Deserialize};
+
+use crate::alerts::Alerts;
+use crate::history::AlertEvent;
+use crate::incidents::Incidents;
+use crate::options::was_probed;
+use crate::persist::TargetManager;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Range {
+    pub from: String,  // RFC 3339 times, e.g. 2017-07-03T09:00:00.000Z
+    pub to: String,
+}
+
+impl Range {
+    /**
+     * Parses the bounds of the range, in seconds since the epoch.
+     */
+    pub fn parse(&self) -> Option<(i64, i64)> {
+        let parse = |t: &str| DateTime::parse_from_rfc3339(t).ok().map(|t| t.timestamp());
+        Some((parse(&self.from)?, parse(&self.to)?))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SearchRequest {
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct QueryTarget {
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    pub range: Range,
+    pub targets: Vec<QueryTarget>,
+    #[serde(default)]
+    pub max_data_points: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AnnotationQuery {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub query: String,  // `incidents` and/or `alerts`, optionally with the targets to annotate
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnnotationsRequest {
+    pub range: Range,
+    #[serde(default)]
+    pub annotation: AnnotationQuery,
+}
+
+/**
+ * A metric's values over time, as `[value, milliseconds since the epoch]`
+ * (the value being null for failed rounds).
+ */
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TimeSeries {
+    pub target: String,
+    pub datapoints: Vec<(Option<f64>, i64)>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Annotation {
+    pub annotation: AnnotationQuery,
+    pub time: i64,  // in milliseconds since the epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_end: Option<i64>,
+    pub is_region: bool,
+    pub title: String,
+    pub text: String,
+    pub tags: Vec<String>,
+}
+
+/**
+ * Lists the names of all metrics of the given targets containing the given
+ * search string.
+ */
+pub fn search(managers: &[Arc<TargetManager>], request: &SearchRequest) -> Vec<String> {
+    let mut metrics = Vec::new();
+    for tm in managers.iter() {
+        let options = tm.options_read();
+        let columns = tm.kind.columns(&options);
+        for addr in options.addrs.iter() {
+            for column in columns.iter() {
+                let metric = metric_name(tm.kind.compact_name(), addr, column);
+                if metric.contains(&request.target) {
+                    metrics.push(metric);
+                }
+            }
+        }
+    }
+    metrics
+}
+
+fn metric_name(target: &str, addr: &str, column: &str) -> String {
+    if column.is_empty() {
+        format!("{}/{}", target, addr)
+    } else {
+        format!("{}/{}/{}", target, addr, column)
+    }
+}
+
+/**
+ * Finds the target of the given metric (among the given ones), and the index
+ * of its column in rounds of it.
+ */
+fn find_metric<'a>(managers: &'a [Arc<TargetManager>], metric: &str) -> Option<(&'a TargetManager, usize)> {
+    let (target, rest) = metric.split_once('/')?;
+    let tm = managers.iter().find(|tm| tm.kind.compact_name() == target)?;
+    let options = tm.options_read();
+    let columns = tm.kind.columns(&options);
+    // addresses may contain slashes themselves, so match them whole
+    options.addrs.iter().enumerate().find_map(|(a, addr)| {
+        let column = rest.strip_prefix(addr.as_str())?;
+        let c = match column.strip_prefix('/') {
+            Some(column) => columns.iter().position(|c| !c.is_empty() && c == column)?,
+            None if column.is_empty() => 0,
+            None => return None,
+        };
+        Some((&**tm, a * columns.len() + c))
+    })
+}
+
+/**
+ * Answers the given query with the values of each metric asked for (and
+ * known) in its range, averaged into at most its `max_data_points` buckets.
+ */
+pub fn query(managers: &[Arc<TargetManager>], request: &QueryRequest) -> io::Result<Vec<TimeSeries>> {
+    let (from, to) = request.range.parse()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid range"))?;
+    let mut series = Vec::new();
+    for t in request.targets.iter() {
+        let (tm, position) = match find_metric(managers, &t.target) {
+            Some(m) => m,
+            None => continue,
+        };
+        let index = tm.get_current_indices().1[position];
+        let mut rounds = Vec::new();
+        tm.read_data_range(from, to, &mut |elements| {
+            for d in elements.iter().filter(|d| d.index == index && was_probed(d.val)) {
+                rounds.push((d.time, d.val));
+            }
+            Ok(())
+        })?;
+        series.push(TimeSeries {
+            target: t.target.clone(),
+            datapoints: downsample(&rounds, request.max_data_points.unwrap_or(usize::MAX)),
+        });
+    }
+    Ok(series)
+}
+
+/**
+ * Turns the given (time, value) rounds into at most the given number of data
+ * points, each the mean of the values (ignoring failures) of a run of
+ * consecutive rounds.
+ */
+fn downsample(rounds: &[(i64, i32)], max: usize) -> Vec<(Option<f64>, i64)> {
+    let per_point = rounds.len().div_ceil(max.max(1)).max(1);
+    rounds.chunks(per_point).map(|chunk| {
+        let values: Vec<f64> = chunk.iter().filter(|&&(_, v)| v >= 0).map(|&(_, v)| v as f64).collect();
+        let mean = if values.is_empty() { None } else { Some(values.iter().sum::<f64>() / values.len() as f64) };
+        (mean, chunk[0].0 * 1000)
+    }).collect()
+}
+
+/**
+ * Answers the given annotations request with the incidents and/or alert
+ * changes in its range, of the targets named in its query (any, if none).
+ */
+pub fn annotations(incidents: &Incidents, alerts: &Alerts, request: &AnnotationsRequest) -> io::Result<Vec<Annotation>> {
+    let (from, to) = request.range.parse()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid range"))?;
+    let words: Vec<&str> = request.annotation.query.split_whitespace().collect();
+    let wants = |kind: &str| (!words.contains(&"incidents") && !words.contains(&"alerts")) || words.contains(&kind);
+    let targets: Vec<&str> = words.iter().cloned().filter(|w| *w != "incidents" && *w != "alerts").collect();
+    let of_target = |target: &str| targets.is_empty() || targets.contains(&target);
+
+    let mut annotations = Vec::new();
+    if wants("incidents") {
+        for i in incidents.list(None, None) {
+            if !of_target(&i.target) || i.start > to || i.end.is_some_and(|end| end < from) {
+                continue;
+            }
+            annotations.push(Annotation {
+                annotation: request.annotation.clone(),
+                time: i.start * 1000,
+                time_end: i.end.map(|end| end * 1000),
+                is_region: i.end.is_some(),
+                title: format!("{} {} down", i.target, i.addr),
+                text: i.cause.clone(),
+                tags: vec![i.target.clone(), "incident".to_owned()],
+            });
+        }
+    }
+    if wants("alerts") {
+        let history = alerts.history(from, to, None)
+            .map_err(|e| io::Error::other(e.description()))?;
+        for t in history.into_iter().filter(|t| of_target(&t.target)) {
+            let event = match t.event {
+                AlertEvent::Fired => "fired",
+                AlertEvent::Resolved => "resolved",
+                AlertEvent::Acknowledged => "acknowledged",
+                _ => continue,
+            };
+            annotations.push(Annotation {
+                annotation: request.annotation.clone(),
+                time: t.time * 1000,
+                time_end: None,
+                is_region: false,
+                title: format!("{} {}", t.rule, event),
+                text: format!("{} {}", t.target, t.addr),
+                tags: vec![t.target, "alert".to_owned()],
+            });
+        }
+    }
+    Ok(annotations)
+}
+
+#[test]
+fn downsampling_averages_runs_of_rounds() {
+    let timeout = -2_100_000_005;
+    let rounds = [(10, 1000), (20, 3000), (30, timeout), (40, timeout), (50, 5000)];
+    assert_eq!(downsample(&rounds, 100).len(), 5);
+    assert_eq!(downsample(&rounds, 2), vec![(Some(2000.0), 10_000), (Some(5000.0), 40_000)]);
+    assert_eq!(downsample(&rounds[2..4], 1), vec![(None, 30_000)]);
+
+    let range = Range { from: "2017-07-03T09:00:00.000Z".to_owned(), to: "2017-07-03T10:00:00Z".to_owned() };
+    assert_eq!(range.parse(), Some((1_499_072_400, 1_499_076_000)));
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Forwarding of every round of results to a Graphite (Carbon) server in its
+ * plaintext protocol, in addition to storing them locally. Rounds that fail to
+ * send are buffered and replayed (see `spool.rs`).
+ */
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::options::{GraphiteConfiguration, TargetResults};
+use crate::persist::TargetManager;
+use crate::sink::{ResultsSink, SinkError};
+use crate::spool::{PushError, Spool};
+
+/**
+ * Sink forwarding all results to the configured Carbon server over a lazily
+ * (re-)established connection. Results that fail to send are buffered in the
+ * given spool.
+ */
+pub struct GraphiteSink {
+    config: GraphiteConfiguration,
+    managers: Vec<Arc<TargetManager>>,
+    conn: Option<TcpStream>,
+    spool: Spool,
+}
+
+impl GraphiteSink {
+    pub fn new(config: GraphiteConfiguration, managers: &[Arc<TargetManager>], spool: Spool) -> Self {
+        GraphiteSink {
+            config,
+            managers: managers.to_vec(),
+            conn: None,
+            spool,
+        }
+    }
+}
+
+impl ResultsSink for GraphiteSink {
+    fn name(&self) -> &'static str {
+        "Graphite"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        let body = {
+            let manager = &self.managers[results.kind as usize];
+            let options = manager.options_read();
+            if results.nonce != options.nonce {
+                return Ok(());
+            }
+            let columns = manager.kind.columns(&options);
+            let num_probe_columns = manager.kind.probe_columns().len();
+            lines(&self.config.prefix, manager.kind.compact_name(), &options.addrs,
+                  &columns[..num_probe_columns + 1], columns.len(), results)
+        };
+
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        let (address, conn) = (&self.config.address, &mut self.conn);
+        self.spool.send(body.into_bytes(), |b, _| {
+            if conn.is_none() {
+                *conn = Some(connect(address).map_err(|e| PushError::Retry(format!("failed to connect: {}", e)))?);
+            }
+            if let Some(ref mut stream) = *conn {
+                if let Err(e) = stream.write_all(b) {
+                    *conn = None;
+                    return Err(PushError::Retry(format!("{}", e)));
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/**
+ * Connects to the Carbon server at the given `host:port`.
+ */
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let timeout = Duration::from_secs(10);
+    let sock_addr = address.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))?;
+    let stream = TcpStream::connect_timeout(&sock_addr, timeout)?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/**
+ * Formats the successfully measured values of the given (probe and loss)
+ * columns (out of the given total number of columns for each address) as
+ * `<prefix>.<target>.<addr>.<column>` metrics, the primary column being named
+ * `value`.
+ */
+fn lines(prefix: &str, target: &str, addrs: &[String], columns: &[String],
+         num_columns: usize, r: &TargetResults) -> String {
+    let mut out = String::new();
+    for (addr, vals) in addrs.iter().zip(r.vals.chunks(num_columns)) {
+        for (column, &val) in columns.iter().zip(vals).filter(|&(_, &v)| v >= 0) {
+            let name = if column.is_empty() { "value" } else { column.as_str() };
+            out.push_str(&format!("{}.{}.{}.{} {} {}\n", prefix, target, path_component(addr),
+                                  name, val, r.timestamp));
+        }
+    }
+    out
+}
+
+/**
+ * Turns the given string into a single component of a metric path, replacing
+ * anything but alphanumerics and `-` (such as the `.` of host names) by `_`.
+ */
+pub fn path_component(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+#[test]
+fn lines_skip_failures_and_sanitize_addrs() {
+    let r = TargetResults {
+        kind: 0,
+        nonce: 0,
+        timestamp: 1_500_000_000,
+        vals: vec![1200, 0, -2_100_000_005, 100],
+    };
+    let columns = vec!["".to_owned(), "loss".to_owned()];
+    let addrs = vec!["google.com:80".to_owned(), "[::1]:80".to_owned()];
+
+    assert_eq!(lines("stabping", "tcpping", &addrs, &columns, 2, &r),
+               "stabping.tcpping.google_com_80.value 1200 1500000000\n\
+                stabping.tcpping.google_com_80.loss 0 1500000000\n\
+                stabping.tcpping.___1__80.loss 100 1500000000\n");
+}
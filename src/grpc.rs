@@ -0,0 +1,430 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * gRPC health check data collection, timing the standard
+ * `grpc.health.v1.Health/Check` RPC (over HTTP/2, with TLS or in plaintext)
+ * and recording the serving status it answers with, since a service can
+ * accept connections long after it has stopped serving.
+ *
+ * Only as much of HTTP/2 (RFC 9113) is done as a single unary RPC needs: the
+ * request's headers are sent as HPACK literals (RFC 7541) that need no state,
+ * and the response's headers aren't decoded at all, the RPC having succeeded
+ * if its response message arrives (servers failing an RPC answer with
+ * trailers alone).
+ */
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{Arc, OnceLock};
+
+use rustls::ClientConfig;
+use time::precise_time_ns;
+
+use crate::http;
+use crate::http::HttpStream;
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, SENTINEL_TLS, io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema, Unit};
+use crate::resolve;
+use crate::proxy;
+use crate::worker::ProbeSettings;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+
+/**
+ * The stream of the RPC, the first a client opens.
+ */
+const STREAM_ID: u32 = 1;
+
+pub static KIND: TargetKind = TargetKind::of(&Grpc);
+
+/**
+ * The gRPC Health target, calling `grpc.health.v1.Health/Check` once on the
+ * server of each addr, a `host:port` (or `host` if on port 443) optionally
+ * followed by `/` and the name of the service to check (the server as a
+ * whole if not given), and preceded by `grpc://` if the server takes
+ * plaintext HTTP/2 rather than TLS (`grpcs://`, the default), e.g.
+ * `grpc://10.0.0.5:50051/payments.Ledger`.
+ *
+ * Returns the time from sending the request to receiving the response,
+ * followed by the time spent resolving the host and connecting (TCP and TLS)
+ * beforehand, and the serving status answered with (scaled like the
+ * durations, so that it is recorded as is: 1 serving, 2 not serving, 0 and 3
+ * unknown).
+ */
+pub struct Grpc;
+
+impl Probe for Grpc {
+    type Target = (bool, String, String, SocketAddr);  // whether over TLS, the host, the service and its address
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 12,
+        name: "grpc",
+        columns: &["", "dns", "connect", "status"],
+        units: &[("status", Unit::Gauge)],
+        default_timeout: 30_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &[],
+        default_interval: 30_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32> {
+        let (tls, host, port, service) = parse_addr(addr).ok_or(SENTINEL_ERROR)?;
+        let sock_addr = resolve::resolve(&host, port, settings).ok_or(SENTINEL_RESOLVE)?;
+        Ok((tls, host, service.to_owned(), sock_addr))
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        parse_addr(addr).map(|(_, host, port, _)| (host, port))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.3.ip())
+    }
+
+    fn probe_once(&self, _: &str, (tls, host, service, sock_addr): Self::Target,
+                  attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        let (rpc, connect, status) = check_once(tls, &host, &service, sock_addr, attempt.settings)?;
+        Ok(vec![rpc, attempt.resolve_nanos, connect, status * 1000])
+    }
+}
+
+/**
+ * Splits the given addr into whether it's over TLS, its host, port, and the
+ * service to check.
+ */
+fn parse_addr(addr: &str) -> Option<(bool, String, u16, &str)> {
+    let (tls, rest) = match addr.split_once("://") {
+        Some(("grpcs", rest)) => (true, rest),
+        Some(("grpc", rest)) => (false, rest),
+        Some(_) => return None,
+        None => (true, addr),
+    };
+    let (host_port, service) = rest.split_once('/').unwrap_or((rest, ""));
+    match resolve::split_host_port(host_port) {
+        Some((host, port)) => Some((tls, host.to_owned(), port, service)),
+        None if !host_port.is_empty() && !addr.contains(' ') => Some((tls, host_port.to_owned(), 443, service)),
+        None => None,
+    }
+}
+
+/**
+ * Returns the TLS client configuration of gRPC connections: trusting the
+ * Mozilla root certificates, and offering HTTP/2 (which gRPC servers insist
+ * on).
+ */
+fn client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let mut config = (*http::client_config(None, None).expect("the Mozilla roots make a configuration")).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Arc::new(config)
+    }).clone()
+}
+
+fn frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.push(frame_type);
+    frame.push(flags);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/**
+ * Appends the given integer with an N-bit prefix (RFC 7541, section 5.1) to
+ * the given header block, the bits above it set as given.
+ */
+fn put_prefixed(buf: &mut Vec<u8>, flags: u8, bits: u32, v: usize) {
+    let max = (1usize << bits) - 1;
+    if v < max {
+        buf.push(flags | v as u8);
+        return;
+    }
+    buf.push(flags | max as u8);
+    let mut rest = v - max;
+    while rest >= 0x80 {
+        buf.push((rest % 0x80) as u8 | 0x80);
+        rest /= 0x80;
+    }
+    buf.push(rest as u8);
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    put_prefixed(buf, 0x00, 7, s.len());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/**
+ * Builds the header block of a Check request with the given authority, every
+ * field a literal left out of the dynamic table (named from the static one
+ * where it can be).
+ */
+pub fn request_headers(tls: bool, authority: &str, timeout_millis: u128) -> Vec<u8> {
+    let mut block = vec![
+        0x83,  // :method POST
+        if tls { 0x87 } else { 0x86 },  // :scheme https (or http)
+    ];
+    for (index, value) in [(4, "/grpc.health.v1.Health/Check"), (1, authority), (31, "application/grpc")] {
+        put_prefixed(&mut block, 0x00, 4, index);
+        put_string(&mut block, value);
+    }
+    // gRPC timeouts only have up to 8 digits
+    let timeout = timeout_millis.min(99_999_999).to_string() + "m";
+    for (name, value) in [("te", "trailers"), ("grpc-timeout", &timeout)] {
+        block.push(0x00);
+        put_string(&mut block, name);
+        put_string(&mut block, value);
+    }
+    block
+}
+
+/**
+ * Builds the length-prefixed message of a `HealthCheckRequest` for the given
+ * service.
+ */
+pub fn request_message(service: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    if !service.is_empty() {
+        message.push(0x0a);  // field 1 (service), length-delimited
+        put_varint(&mut message, service.len() as u64);
+        message.extend_from_slice(service.as_bytes());
+    }
+    let mut prefixed = vec![0x00];  // not compressed
+    prefixed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    prefixed.extend(message);
+    prefixed
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v & 0x7f) as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *buf.get(*pos)?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+/**
+ * Gets the serving status from the given `HealthCheckResponse` message, or
+ * None if it's malformed.
+ */
+pub fn serving_status(message: &[u8]) -> Option<u64> {
+    let mut status = 0;  // UNKNOWN, as fields left out are their default
+    let mut pos = 0;
+    while pos < message.len() {
+        let tag = get_varint(message, &mut pos)?;
+        match tag & 0x07 {
+            0 => {
+                let v = get_varint(message, &mut pos)?;
+                if tag >> 3 == 1 {
+                    status = v;
+                }
+            },
+            1 => pos += 8,
+            2 => pos += get_varint(message, &mut pos)? as usize,
+            5 => pos += 4,
+            _ => return None,
+        }
+    }
+    if pos == message.len() { Some(status) } else { None }
+}
+
+/**
+ * Reads the next frame from the given stream, returning its type, flags,
+ * stream and payload.
+ */
+fn read_frame<R: Read>(stream: &mut R) -> std::io::Result<(u8, u8, u32, Vec<u8>)> {
+    let mut head = [0u8; 9];
+    stream.read_exact(&mut head)?;
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    let stream_id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok((head[3], head[4], stream_id, payload))
+}
+
+/**
+ * Makes the Check RPC with the given service over the given stream (its
+ * connection preface not sent yet), returning the serving status answered
+ * with.
+ */
+fn check<S: Read + Write>(stream: &mut S, tls: bool, authority: &str, service: &str,
+                          timeout_millis: u128) -> Result<u64, i32> {
+    let io_err = |e: std::io::Error| io_error_sentinel(&e);
+    let mut request = PREFACE.to_vec();
+    request.extend(frame(SETTINGS, 0, 0, &[]));
+    request.extend(frame(HEADERS, END_HEADERS, STREAM_ID, &request_headers(tls, authority, timeout_millis)));
+    request.extend(frame(DATA, END_STREAM, STREAM_ID, &request_message(service)));
+    stream.write_all(&request).map_err(io_err)?;
+    stream.flush().map_err(io_err)?;
+
+    let mut data = Vec::new();
+    loop {
+        let (frame_type, flags, stream_id, mut payload) = read_frame(stream).map_err(io_err)?;
+        match (frame_type, stream_id) {
+            (SETTINGS, 0) if flags & ACK == 0 => {
+                stream.write_all(&frame(SETTINGS, ACK, 0, &[])).map_err(io_err)?;
+            },
+            (PING, 0) if flags & ACK == 0 => {
+                stream.write_all(&frame(PING, ACK, 0, &payload)).map_err(io_err)?;
+            },
+            (DATA, STREAM_ID) => {
+                if flags & PADDED != 0 {
+                    let pad = *payload.first().ok_or(SENTINEL_ERROR)? as usize;
+                    let end = payload.len().checked_sub(pad).filter(|&e| e >= 1).ok_or(SENTINEL_ERROR)?;
+                    payload.truncate(end);
+                    payload.remove(0);
+                }
+                data.extend(payload);
+                if data.len() >= 5 {
+                    if data[0] != 0 {
+                        return Err(SENTINEL_ERROR);  // compressed, which wasn't asked for
+                    }
+                    let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+                    if data.len() >= 5 + len {
+                        return serving_status(&data[5..5 + len]).ok_or(SENTINEL_ERROR);
+                    }
+                }
+                if flags & END_STREAM != 0 {
+                    return Err(SENTINEL_ERROR);
+                }
+            },
+            // trailers with no response message before them: the RPC failed
+            (HEADERS, STREAM_ID) if flags & END_STREAM != 0 => return Err(SENTINEL_ERROR),
+            (RST_STREAM, STREAM_ID) | (GOAWAY, 0) => return Err(SENTINEL_ERROR),
+            _ => (),
+        }
+    }
+}
+
+/**
+ * Makes a single Check RPC with the given service of the given host at the
+ * given address, returning the time it took, the time spent connecting
+ * beforehand, and the serving status answered with.
+ */
+fn check_once(tls: bool, host: &str, service: &str, sock_addr: SocketAddr,
+              settings: &ProbeSettings) -> Result<(u64, u64, u64), i32> {
+    let start = precise_time_ns();
+    // the timeout applies to each read and write separately
+    let tcp: TcpStream = proxy::connect(&sock_addr, settings).map_err(|e| io_error_sentinel(&e))?;
+    tcp.set_read_timeout(Some(settings.timeout)).map_err(|_| SENTINEL_ERROR)?;
+    tcp.set_write_timeout(Some(settings.timeout)).map_err(|_| SENTINEL_ERROR)?;
+
+    let mut stream = if tls {
+        http::tls_handshake_with(client_config(), host, tcp).map_err(|e| match io_error_sentinel(&e) {
+            s if s == SENTINEL_ERROR => SENTINEL_TLS,
+            s => s,
+        })?
+    } else {
+        HttpStream::Plain(tcp)
+    };
+    let connected = precise_time_ns();
+
+    let authority = match sock_addr.port() {
+        443 if tls => host.to_owned(),
+        port if host.contains(':') => format!("[{}]:{}", host, port),
+        port => format!("{}:{}", host, port),
+    };
+    let status = check(&mut stream, tls, &authority, service, settings.timeout.as_millis())?;
+    Ok((precise_time_ns() - connected, connected - start, status))
+}
+
+#[test]
+fn health_checks_answer_with_the_serving_status() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    assert_eq!(parse_addr("grpc://10.0.0.5:50051/payments.Ledger"),
+               Some((false, "10.0.0.5".to_owned(), 50051, "payments.Ledger")));
+    assert_eq!(parse_addr("api.example.com"), Some((true, "api.example.com".to_owned(), 443, "")));
+    assert_eq!(parse_addr("grpcs://[::1]:8443"), Some((true, "::1".to_owned(), 8443, "")));
+    assert_eq!(parse_addr("https://api.example.com/"), None);
+
+    assert_eq!(request_message("a.B"), [0, 0, 0, 0, 5, 0x0a, 3, b'a', b'.', b'B']);
+    assert_eq!(serving_status(&[0x08, 0x01]), Some(1));
+    assert_eq!(serving_status(&[]), Some(0));
+    assert_eq!(serving_status(&[0x12, 0x01, 0xff, 0x08, 0x02]), Some(2));
+    assert_eq!(serving_status(&[0x08]), None);
+    let mut block = Vec::new();
+    put_prefixed(&mut block, 0x00, 4, 31);
+    assert_eq!(block, [0x0f, 0x10]);
+
+    // a server answering a single RPC with the given status, or with trailers alone if None
+    let serve = |status: Option<u8>| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut preface = [0u8; 24];
+            conn.read_exact(&mut preface).unwrap();
+            assert_eq!(&preface[..], PREFACE);
+            let mut message = Vec::new();
+            while message.is_empty() {
+                let (frame_type, _, stream_id, payload) = read_frame(&mut conn).unwrap();
+                if frame_type == DATA && stream_id == STREAM_ID {
+                    message = payload;
+                }
+            }
+            let mut reply = frame(SETTINGS, 0, 0, &[0x00, 0x03, 0x00, 0x00, 0x00, 0x64]);
+            reply.extend(frame(PING, 0, 0, &[0; 8]));
+            reply.extend(frame(HEADERS, END_HEADERS, STREAM_ID, &[0x88]));  // :status 200
+            if let Some(status) = status {
+                reply.extend(frame(DATA, 0, STREAM_ID, &[0, 0, 0, 0, 2, 0x08, status]));
+            }
+            reply.extend(frame(HEADERS, END_HEADERS | END_STREAM, STREAM_ID, &[0x00, 0x01, b'x', 0x01, b'0']));
+            conn.write_all(&reply).unwrap();
+            let _ = conn.read(&mut [0u8; 64]);
+            message
+        });
+        (addr, server)
+    };
+
+    let settings = ProbeSettings {
+        timeout: std::time::Duration::from_secs(2),
+        resolve_ttl: std::time::Duration::from_secs(0),
+        family: crate::options::AddressFamily::Any,
+        pin: None,
+        source: None,
+        proxy: None,
+        dscp: None,
+        tcp: Default::default(),
+    };
+    let (addr, server) = serve(Some(2));
+    let (rpc, _, status) = check_once(false, "127.0.0.1", "payments.Ledger", addr, &settings).unwrap();
+    assert!(rpc > 0 && status == 2);
+    assert_eq!(server.join().unwrap(), request_message("payments.Ledger"));
+
+    let (addr, _server) = serve(None);
+    assert_eq!(check_once(false, "127.0.0.1", "nope", addr, &settings), Err(SENTINEL_ERROR));
+}
@@ -0,0 +1,185 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The health of stabping itself, for supervisors (container orchestrators,
+ * uptime monitors) to check on: whether the worker of every target is still
+ * running, and how long ago each last wrote results to its data file.
+ */
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use chrono::Local;
+use serde::Serialize;
+
+use crate::options::TargetOptions;
+use crate::persist::TargetManager;
+use crate::shutdown;
+
+/**
+ * Rounds (of a target's longest possible duration) without any results
+ * written after which it is stale.
+ */
+const STALE_ROUNDS: i64 = 3;
+
+/**
+ * Shortest time (in seconds) without any results written after which a
+ * target is stale, so that targets with short intervals aren't stale over a
+ * hiccup.
+ */
+const MIN_STALE_SECS: i64 = 60;
+
+/**
+ * The state of the worker of a target.
+ */
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerState {
+    Starting,  // not started yet
+    Running,
+    Stopped,
+}
+
+/**
+ * The health of a single target.
+ */
+#[derive(Serialize, Debug)]
+pub struct TargetHealth {
+    pub worker: WorkerState,
+    pub last_write: Option<i64>,  // time of the latest results written since startup
+    pub last_write_age: Option<i64>,  // seconds since then
+    pub stale: bool,  // whether it has gone too long without writing (see `is_stale`)
+}
+
+impl TargetHealth {
+    fn is_healthy(&self) -> bool {
+        self.worker == WorkerState::Running && !self.stale
+    }
+}
+
+/**
+ * The health of stabping as a whole.
+ */
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub uptime: i64,  // seconds since startup
+    pub targets: BTreeMap<&'static str, TargetHealth>,  // by compact name
+}
+
+/**
+ * Returns whether a target with the given options (and default timeout, in
+ * millis) has gone too long without writing any results since the given
+ * time: for `STALE_ROUNDS` of its longest possible rounds (all attempts
+ * timing out), and at least `MIN_STALE_SECS`. Targets without addresses
+ * never are.
+ */
+pub fn is_stale(opt: &TargetOptions, default_timeout: u32, since: i64, now: i64) -> bool {
+    if opt.addrs.is_empty() {
+        return false;
+    }
+    let timeout = opt.timeout.unwrap_or(default_timeout) as i64;
+    let interval = opt.interval.max(opt.down_interval.unwrap_or(0)) as i64;
+    let round = interval + opt.start_jitter as i64 + opt.avg_across as i64 * opt.family.probes_per_attempt() as i64 * (timeout + opt.pause as i64);
+    now - since > (STALE_ROUNDS * round / 1000).max(MIN_STALE_SECS)
+}
+
+/**
+ * Keeps track of the workers of all targets (once started), indexed by
+ * kind_id, to report on their health.
+ */
+pub struct Health {
+    managers: Vec<Arc<TargetManager>>,
+    started: i64,
+    workers: Mutex<Option<Vec<JoinHandle<()>>>>,
+}
+
+impl Health {
+    pub fn new(managers: &[Arc<TargetManager>]) -> Self {
+        Health {
+            managers: managers.to_vec(),
+            started: Local::now().timestamp(),
+            workers: Mutex::new(None),
+        }
+    }
+
+    /**
+     * Records the (just started) workers of all targets, indexed by kind_id,
+     * after which stabping is ready.
+     */
+    pub fn workers_started(&self, workers: Vec<JoinHandle<()>>) {
+        *self.workers.lock().unwrap() = Some(workers);
+    }
+
+    /**
+     * Returns whether stabping is ready: its workers started, and it isn't
+     * shutting down.
+     */
+    pub fn is_ready(&self) -> bool {
+        self.workers.lock().unwrap().is_some() && !shutdown::requested()
+    }
+
+    /**
+     * Reports on the health of stabping: healthy if ready, and every target's
+     * worker is running and none of them are stale.
+     */
+    pub fn report(&self) -> HealthReport {
+        let now = Local::now().timestamp();
+        let workers = self.workers.lock().unwrap();
+        let targets: BTreeMap<&'static str, TargetHealth> = self.managers.iter().enumerate()
+            .map(|(i, tm)| {
+                let worker = match workers.as_ref().and_then(|w| w.get(i)) {
+                    Some(w) if w.is_finished() => WorkerState::Stopped,
+                    Some(_) => WorkerState::Running,
+                    None => WorkerState::Starting,
+                };
+                let last_write = tm.last_write();
+                let since = last_write.unwrap_or(self.started).max(self.started);
+                let stale = is_stale(&tm.options_read(), tm.kind.default_timeout(), since, now);
+                (tm.kind.compact_name(), TargetHealth {
+                    worker,
+                    last_write,
+                    last_write_age: last_write.map(|t| now - t),
+                    stale,
+                })
+            })
+            .collect();
+        drop(workers);
+
+        HealthReport {
+            healthy: self.is_ready() && targets.values().all(TargetHealth::is_healthy),
+            uptime: now - self.started,
+            targets,
+        }
+    }
+}
+
+#[test]
+fn targets_are_stale_after_long_without_writing() {
+    let mut opt = crate::tcpping::KIND.default_options();
+    opt.addrs = vec!["a:80".to_owned()];
+    opt.interval = 10_000;
+    opt.avg_across = 1;
+    opt.pause = 0;
+    opt.start_jitter = 0;
+    opt.down_interval = None;
+    opt.timeout = None;
+
+    // three rounds of the interval and timeout, but no less than a minute
+    assert!(!is_stale(&opt, 5_000, 0, 45));
+    assert!(!is_stale(&opt, 5_000, 0, 60));
+    assert!(is_stale(&opt, 5_000, 0, 61));
+    opt.interval = 60_000;
+    assert!(!is_stale(&opt, 5_000, 0, 195));
+    assert!(is_stale(&opt, 5_000, 0, 196));
+
+    // nothing is expected of targets without addresses
+    opt.addrs.clear();
+    assert!(!is_stale(&opt, 5_000, 0, 1_000_000));
+}
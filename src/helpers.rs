@@ -10,9 +10,9 @@
  * Helper traits and functions for reducing verbosity, wraping errors, and
  * containing unsafe code for many commonly used I/O and parsing operations.
  */
-use std::mem;
 use std::fmt::{self, Display};
 use std::path::{Path, PathBuf};
+use std::fs;
 use std::fs::{OpenOptions, File};
 use std::io::{Read, Write};
 
@@ -43,11 +43,8 @@ impl SPIOError {
         };
 
         let path_str = match maybe_path {
-            &Some(ref path) => match path.to_str() {
-                Some(s) => s,
-                None => "",
-            },
-            &None => "",
+            Some(path) => path.to_str().unwrap_or(""),
+            None => "",
         };
 
         format!("Unable to {} '{}'", verb, path_str)
@@ -62,24 +59,21 @@ impl Display for SPIOError {
 
 
 /**
- * Trait for turning arbitrary data into a series of bytes that can be put directly
- * into a file or onto the network.
+ * Trait for appending integers to a buffer as raw (native byte order) bytes
+ * that can be put directly into a file or onto the network.
  */
-pub trait VecIntoRawBytes {
-    fn into_raw_bytes(self) -> Vec<u8>;
+pub trait PushRawBytes {
+    fn push_i32(&mut self, n: i32);
+    fn push_i64(&mut self, n: i64);
 }
 
-impl VecIntoRawBytes for Vec<i32> {
-    fn into_raw_bytes(mut self) -> Vec<u8> {
-        let raw_ptr = self.as_mut_ptr();
-        let new_len = self.len() * mem::size_of::<i32>();
-        let new_cap = self.capacity() * mem::size_of::<i32>();
+impl PushRawBytes for Vec<u8> {
+    fn push_i32(&mut self, n: i32) {
+        self.extend_from_slice(&n.to_ne_bytes());
+    }
 
-        unsafe {
-            // take full control over memory originally controlled by orig_data
-            mem::forget(self);
-            Vec::from_raw_parts(raw_ptr as *mut u8, new_len, new_cap)
-        }
+    fn push_i64(&mut self, n: i64) {
+        self.extend_from_slice(&n.to_ne_bytes());
     }
 }
 
@@ -97,54 +91,68 @@ pub trait SPFile {
     /**
      * Opens a file from the given path with the given `OpenOptions`.
      */
-    fn open_from<'a, 'b>(oo: &'b mut OpenOptions, path: &'a Path) -> Result<File, SPIOError>;
+    fn open_from(oo: &mut OpenOptions, path: &Path) -> Result<File, SPIOError>;
 
     /**
      * Attempts to read from this file and decode all its contents as a JSON
      * object (serde::Deserialize).
      */
+    #[allow(dead_code)]
     fn read_json<T: DeserializeOwned>(&mut self) -> Result<T, SPIOError> {
         self._read_json(None)
     }
-    fn read_json_p<'a, T: DeserializeOwned>(&mut self, path: &'a Path) -> Result<T, SPIOError> {
+    fn read_json_p<T: DeserializeOwned>(&mut self, path: &Path) -> Result<T, SPIOError> {
         self._read_json(Some(path))
     }
-    fn _read_json<'a, T: DeserializeOwned>(&mut self, path: Option<&'a Path>) -> Result<T, SPIOError>;
+    fn _read_json<T: DeserializeOwned>(&mut self, path: Option<&Path>) -> Result<T, SPIOError>;
+
+    /**
+     * Attempts to read from this file and decode all its contents as a TOML
+     * document (serde::Deserialize).
+     */
+    #[allow(dead_code)]
+    fn read_toml<T: DeserializeOwned>(&mut self) -> Result<T, SPIOError> {
+        self._read_toml(None)
+    }
+    fn read_toml_p<T: DeserializeOwned>(&mut self, path: &Path) -> Result<T, SPIOError> {
+        self._read_toml(Some(path))
+    }
+    fn _read_toml<T: DeserializeOwned>(&mut self, path: Option<&Path>) -> Result<T, SPIOError>;
 
 
     /**
      * Attempts to write a JSON object (serde::Serialize) to this file.
      */
-    fn write_json<'b, T: Serialize>(&mut self, obj: &'b T) -> Result<(), SPIOError> {
+    #[allow(dead_code)]
+    fn write_json<T: Serialize>(&mut self, obj: &T) -> Result<(), SPIOError> {
         self._write_json(obj, None)
     }
-    fn write_json_p<'a, 'b, T: Serialize>(&mut self, obj: &'b T, path: &'a Path) -> Result<(), SPIOError> {
+    fn write_json_p<T: Serialize>(&mut self, obj: &T, path: &Path) -> Result<(), SPIOError> {
         self._write_json(obj, Some(path))
     }
-    fn _write_json<'a, 'b, T: Serialize>(&mut self, obj: &'b T, path: Option<&'a Path>) -> Result<(), SPIOError>;
+    fn _write_json<T: Serialize>(&mut self, obj: &T, path: Option<&Path>) -> Result<(), SPIOError>;
 
 
     /**
      * Attempts to obtain the length of this file from filesystem metadata.
      */
+    #[allow(dead_code)]
     fn length(&mut self) -> Result<u64, SPIOError> {
         self._length(None)
     }
-    fn length_p<'a>(&mut self, path: &'a Path) -> Result<u64, SPIOError> {
+    fn length_p(&mut self, path: &Path) -> Result<u64, SPIOError> {
         self._length(Some(path))
     }
-    fn _length<'a>(&mut self, path: Option<&'a Path>) -> Result<u64, SPIOError>;
+    fn _length(&mut self, path: Option<&Path>) -> Result<u64, SPIOError>;
 }
 
 impl SPFile for File {
-    fn open_from<'a, 'b>(oo: &'b mut OpenOptions, path: &'a Path) -> Result<File, SPIOError> {
-        Ok(
-            oo.open(path)
-            .map_err(|_| SPIOError::Open(Some(path.to_owned())))?
-        )
+    fn open_from(oo: &mut OpenOptions, path: &Path) -> Result<File, SPIOError> {
+        oo.open(path)
+            .map_err(|_| SPIOError::Open(Some(path.to_owned())))
     }
 
-    fn _read_json<'a, T: DeserializeOwned>(&mut self, path: Option<&'a Path>) -> Result<T, SPIOError> {
+    fn _read_json<T: DeserializeOwned>(&mut self, path: Option<&Path>) -> Result<T, SPIOError> {
         let mut buffer = String::new();
         self.read_to_string(&mut buffer)
             .map_err(|_| SPIOError::Read(path.map(|p| p.to_owned())))?;
@@ -152,7 +160,15 @@ impl SPFile for File {
             .map_err(|_| SPIOError::Parse(path.map(|p| p.to_owned())))
     }
 
-    fn _write_json<'a, 'b, T: Serialize>(&mut self, obj: &'b T, path: Option<&'a Path>) -> Result<(), SPIOError> {
+    fn _read_toml<T: DeserializeOwned>(&mut self, path: Option<&Path>) -> Result<T, SPIOError> {
+        let mut buffer = String::new();
+        self.read_to_string(&mut buffer)
+            .map_err(|_| SPIOError::Read(path.map(|p| p.to_owned())))?;
+        toml::from_str::<T>(&buffer)
+            .map_err(|_| SPIOError::Parse(path.map(|p| p.to_owned())))
+    }
+
+    fn _write_json<T: Serialize>(&mut self, obj: &T, path: Option<&Path>) -> Result<(), SPIOError> {
         let buffer = serde_json::to_string(obj).unwrap();
         self.write_all(buffer.as_bytes())
             .map_err(|_| SPIOError::Write(path.map(|p| p.to_owned())))?;
@@ -161,7 +177,7 @@ impl SPFile for File {
         Ok(())
     }
 
-    fn _length<'a>(&mut self, path: Option<&'a Path>) -> Result<u64, SPIOError> {
+    fn _length(&mut self, path: Option<&Path>) -> Result<u64, SPIOError> {
         let meta =
             self.metadata()
             .map_err(|_| SPIOError::Metadata(path.map(|p| p.to_owned())))?;
@@ -170,14 +186,32 @@ impl SPFile for File {
 }
 
 /**
- * Overwrite (create if necessary, truncate if already exists) the file
+ * Overwrite (create if necessary, replace if already exists) the file
  * residing at the given path with the given JSON object (serde::Serialize).
+ * The object is written out to a temporary file first and then renamed over
+ * the file, so that it is never seen (e.g. backed up) half-written.
  */
-pub fn overwrite_json<'a, 'b, T: Serialize>(obj: &'a T, path: &'b Path) -> Result<(), SPIOError> {
+pub fn overwrite_json<T: Serialize>(obj: &T, path: &Path) -> Result<(), SPIOError> {
+    let tmp_path = path.with_extension("json.tmp");
     let mut file =
-        OpenOptions::new().write(true).truncate(true).create(true).open(path)
-        .map_err(|_| SPIOError::Open(Some(path.to_owned())))?;
+        OpenOptions::new().write(true).truncate(true).create(true).open(&tmp_path)
+        .map_err(|_| SPIOError::Open(Some(tmp_path.clone())))?;
+
+    file.write_json_p(obj, &tmp_path)?;
+    fs::rename(&tmp_path, path).map_err(|_| SPIOError::Write(Some(path.to_owned())))
+}
 
-    file.write_json_p(obj, path)?;
+/**
+ * Truncates the given file residing at the given path (of the given length)
+ * to the given length of its complete records, discarding any left incomplete
+ * at its end (e.g. by being killed in the middle of appending one), and logs
+ * what was discarded.
+ */
+pub fn discard_incomplete(file: &File, path: &Path, len: u64, complete_len: u64) -> Result<(), SPIOError> {
+    if complete_len < len {
+        warn!("Discarding the last {} bytes of '{}', which were left incomplete.",
+              len - complete_len, path.display());
+        file.set_len(complete_len).map_err(|_| SPIOError::Write(Some(path.to_owned())))?;
+    }
     Ok(())
 }
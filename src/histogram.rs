@@ -0,0 +1,218 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Latency histograms of the attempts against each address. Values (in
+ * microseconds) are bucketed with a fixed relative precision, as in HDR
+ * histograms, and kept over a rolling window of hourly slices, so that high
+ * percentiles stay accurate over long windows without keeping every sample.
+ */
+use std::collections::VecDeque;
+
+/**
+ * Each power of two is split into `2^SUB_BUCKET_BITS` buckets, bounding the
+ * error of any value read back to about 6%.
+ */
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const NUM_BUCKETS: usize = ((32 - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+/**
+ * Length of each slice of the rolling window, in seconds.
+ */
+pub const SLICE_SECS: i64 = 3600;
+
+/**
+ * Number of slices making up the rolling window (i.e. a day).
+ */
+pub const NUM_SLICES: i64 = 24;
+
+/**
+ * Quantiles (with their names) served from the rolling window of each
+ * histogram.
+ */
+pub const QUANTILES: [(f64, &str); 4] = [(0.5, "p50"), (0.9, "p90"), (0.99, "p99"), (0.999, "p999")];
+
+/**
+ * Gets the index of the bucket holding the given value.
+ */
+fn bucket_of(val: u64) -> usize {
+    if val < SUB_BUCKETS {
+        return val as usize;
+    }
+    let shift = 63 - val.leading_zeros() - SUB_BUCKET_BITS;
+    ((shift as u64 + 1) * SUB_BUCKETS + (val >> shift) - SUB_BUCKETS) as usize
+}
+
+/**
+ * Gets the lowest and highest value (both inclusive) held by the bucket of the
+ * given index.
+ */
+fn bucket_bounds(i: usize) -> (u64, u64) {
+    let i = i as u64;
+    if i < SUB_BUCKETS {
+        return (i, i);
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    let m = i % SUB_BUCKETS + SUB_BUCKETS;
+    (m << shift, ((m + 1) << shift) - 1)
+}
+
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            counts: vec![0; NUM_BUCKETS],
+            count: 0,
+            sum: 0,
+        }
+    }
+
+    /**
+     * Records the given (non-negative) value.
+     */
+    pub fn record(&mut self, val: u32) {
+        self.counts[bucket_of(val as u64).min(NUM_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += val as u64;
+    }
+
+    /**
+     * Adds all values recorded in the given histogram to this one.
+     */
+    pub fn merge(&mut self, other: &Histogram) {
+        for (c, o) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *c += o;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    /**
+     * Gets the value at the given quantile (0 to 1), being the highest value
+     * of the bucket holding it, or None if nothing was recorded.
+     */
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (i, c) in self.counts.iter().enumerate() {
+            seen += c;
+            if seen >= rank {
+                return Some(bucket_bounds(i).1);
+            }
+        }
+        None
+    }
+
+    /**
+     * Gets the (lowest value, highest value, count) of every bucket holding
+     * any values, in order.
+     */
+    pub fn buckets(&self) -> Vec<(u64, u64, u64)> {
+        self.counts.iter().enumerate()
+            .filter(|&(_, &c)| c > 0)
+            .map(|(i, &c)| {
+                let (low, high) = bucket_bounds(i);
+                (low, high, c)
+            })
+            .collect()
+    }
+}
+
+/**
+ * A histogram of the values of the last `NUM_SLICES` slices of time, along
+ * with one of all values recorded since startup.
+ */
+pub struct RollingHistogram {
+    slices: VecDeque<(i64, Histogram)>,
+    total: Histogram,
+}
+
+impl RollingHistogram {
+    pub fn new() -> Self {
+        RollingHistogram {
+            slices: VecDeque::new(),
+            total: Histogram::new(),
+        }
+    }
+
+    /**
+     * Forgets the slices that fell out of the window as of the given time.
+     */
+    fn expire(&mut self, time: i64) {
+        let oldest = time - time.rem_euclid(SLICE_SECS) - (NUM_SLICES - 1) * SLICE_SECS;
+        while self.slices.front().is_some_and(|&(start, _)| start < oldest) {
+            self.slices.pop_front();
+        }
+    }
+
+    /**
+     * Records the given value at the given time (in seconds since the epoch).
+     */
+    pub fn record(&mut self, val: u32, time: i64) {
+        let start = time - time.rem_euclid(SLICE_SECS);
+        if self.slices.back().is_none_or(|&(s, _)| s < start) {
+            self.slices.push_back((start, Histogram::new()));
+        }
+        self.slices.back_mut().unwrap().1.record(val);
+        self.total.record(val);
+        self.expire(time);
+    }
+
+    /**
+     * Gets a histogram of the values in the window as of the given time.
+     */
+    pub fn window(&mut self, time: i64) -> Histogram {
+        self.expire(time);
+        let mut h = Histogram::new();
+        for (_, slice) in self.slices.iter() {
+            h.merge(slice);
+        }
+        h
+    }
+
+    /**
+     * Gets the histogram of all values recorded since startup.
+     */
+    pub fn total(&self) -> &Histogram {
+        &self.total
+    }
+}
+
+#[test]
+fn histogram_quantiles_within_bucket_precision() {
+    for v in [0, 15, 16, 17, 1000, 123_456, i32::MAX as u64] {
+        let (low, high) = bucket_bounds(bucket_of(v));
+        assert!(low <= v && v <= high && (high - low) * 16 <= v.max(1), "{}", v);
+    }
+
+    let mut rolling = RollingHistogram::new();
+    for v in 1..=1000 {
+        rolling.record(v * 100, 0);
+    }
+    rolling.record(5, 2 * SLICE_SECS);
+
+    let h = rolling.window(2 * SLICE_SECS);
+    assert_eq!(h.count, 1001);
+    let p99 = h.quantile(0.99).unwrap();
+    assert!((98_000..=104_000).contains(&p99), "{}", p99);
+    assert_eq!(h.quantile(0.0), Some(5));
+
+    // the first slice falls out of the window a day later
+    assert_eq!(rolling.window(NUM_SLICES * SLICE_SECS).count, 1);
+    assert_eq!(rolling.total().count, 1001);
+}
@@ -0,0 +1,186 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The history of every alert (fired, acknowledged, resolved, and each attempt
+ * to notify of it), appended as JSON lines to `alert_history.jsonl` in the
+ * data directory, for timelines of what happened when.
+ */
+use std::fs::{OpenOptions, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Serialize, Deserialize};
+
+use crate::alerts::{Alert, AlertState};
+use crate::helpers::{SPIOError, SPFile, discard_incomplete};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertEvent {
+    Fired,
+    Resolved,
+    Acknowledged,
+    Notified,
+    NotifyFailed,
+}
+
+/**
+ * A single event in the history of the alert of a rule of a target for an
+ * address.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub time: i64,
+    pub target: String,
+    pub addr: String,
+    pub rule: String,
+    pub event: AlertEvent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<i32>,  // of the round that fired or resolved the alert
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<AlertState>,  // notified of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notifier: Option<String>,  // that was (or failed to be) notified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,  // of the failed notification
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,  // of the acknowledgement
+}
+
+impl Transition {
+    /**
+     * Creates an event of the given alert at the given time, with nothing but
+     * where it happened filled in.
+     */
+    pub fn of(alert: &Alert, event: AlertEvent, time: i64) -> Self {
+        Transition {
+            time,
+            target: alert.target.clone(),
+            addr: alert.addr.clone(),
+            rule: alert.rule.clone(),
+            event,
+            value: None,
+            state: None,
+            notifier: None,
+            error: None,
+            comment: String::new(),
+        }
+    }
+
+    /**
+     * Creates the event of the given alert having just changed state.
+     */
+    pub fn changed(alert: &Alert) -> Self {
+        let event = match alert.state {
+            AlertState::Firing => AlertEvent::Fired,
+            AlertState::Resolved => AlertEvent::Resolved,
+        };
+        Transition {
+            value: Some(alert.value),
+            ..Transition::of(alert, event, alert.since)
+        }
+    }
+}
+
+/**
+ * The history of all alerts, appended to the given file (nowhere, by
+ * default).
+ */
+#[derive(Default)]
+pub struct AlertHistory {
+    path: PathBuf,
+    file: Option<Mutex<File>>,
+}
+
+impl AlertHistory {
+    /**
+     * Opens the history kept in the given data directory, discarding any
+     * event left incomplete at its end.
+     */
+    pub fn open(data_path: &Path) -> Result<Self, SPIOError> {
+        let path = data_path.join("alert_history.jsonl");
+        let mut file = File::open_from(OpenOptions::new().read(true).append(true).create(true), &path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).map_err(|_| SPIOError::Read(Some(path.clone())))?;
+        let complete_len = bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        discard_incomplete(&file, &path, bytes.len() as u64, complete_len as u64)?;
+        Ok(AlertHistory {
+            path,
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /**
+     * Appends the given event, failures being reported and otherwise ignored
+     * (as the history is only informative).
+     */
+    pub fn record(&self, transition: &Transition) {
+        let file = match self.file {
+            Some(ref f) => f,
+            None => return,
+        };
+        let mut line = serde_json::to_string(transition).unwrap();
+        line.push('\n');
+        if file.lock().unwrap().write_all(line.as_bytes()).is_err() {
+            warn!("{}", SPIOError::Write(Some(self.path.clone())));
+        }
+    }
+
+    /**
+     * Reads back the events from `from` to `to` (inclusive) of the given
+     * target (any, if `None`), in the order they were recorded.
+     */
+    pub fn query(&self, from: i64, to: i64, target: Option<&str>) -> Result<Vec<Transition>, SPIOError> {
+        let file = match self.file {
+            Some(ref f) => f,
+            None => return Ok(Vec::new()),
+        };
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(0)).map_err(|_| SPIOError::Read(Some(self.path.clone())))?;
+        let mut transitions = Vec::new();
+        for line in BufReader::new(&*file).lines() {
+            let line = line.map_err(|_| SPIOError::Read(Some(self.path.clone())))?;
+            // skip lines of other versions
+            if let Ok(t) = serde_json::from_str::<Transition>(&line) {
+                if from <= t.time && t.time <= to && target.is_none_or(|target| t.target == target) {
+                    transitions.push(t);
+                }
+            }
+        }
+        Ok(transitions)
+    }
+}
+
+#[test]
+fn history_is_queried_by_time_and_target() {
+    let dir = std::env::temp_dir().join(format!("stabping-test-{}-history", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let history = AlertHistory::open(&dir).unwrap();
+
+    let alert: Alert = serde_json::from_value(serde_json::json!({
+        "target": "tcpping", "addr": "a:80", "rule": "slow", "state": "firing", "since": 100, "value": 250000,
+    })).unwrap();
+    history.record(&Transition::changed(&alert));
+    history.record(&Transition { state: Some(AlertState::Firing), ..Transition::of(&alert, AlertEvent::Notified, 101) });
+    history.record(&Transition { target: "icmpping".to_owned(), ..Transition::of(&alert, AlertEvent::Resolved, 150) });
+    drop(history);
+
+    // reopened, as after a restart, with a torn line at its end
+    OpenOptions::new().append(true).open(dir.join("alert_history.jsonl")).unwrap().write_all(b"{\"time\":").unwrap();
+    let history = AlertHistory::open(&dir).unwrap();
+    history.record(&Transition::of(&alert, AlertEvent::Acknowledged, 110));
+    let tcpping = history.query(0, 200, Some("tcpping")).unwrap();
+    assert_eq!(tcpping.iter().map(|t| t.event).collect::<Vec<_>>(), 
+               vec![AlertEvent::Fired, AlertEvent::Notified, AlertEvent::Acknowledged]);
+    assert_eq!(tcpping[0].value, Some(250000));
+    assert_eq!(history.query(120, 200, None).unwrap().len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
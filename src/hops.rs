@@ -0,0 +1,292 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Persistence of the paths (hop by hop) mapped by the Traceroute target.
+ *
+ * Paths have a variable number of hops, so unlike the data file (of
+ * fixed-size elements), the hops file is a sequence of variable-size records:
+ * a 64-bit time, the 32-bit index of the address (see `AddrIndex`) and the
+ * 32-bit number of hops, followed for each hop by its 32-bit round-trip time
+ * in microseconds (negative if it didn't answer) and its 16-byte IP address
+ * (IPv4 addresses mapped into IPv6, unspecified if it didn't answer).
+ *
+ * The IPs file of kinds recording the IPs their addresses resolved to (see
+ * `TargetManager::record_ips`) is of the same records, each "hop" being an IP
+ * probed in the round, with the primary value of its last attempt.
+ */
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::helpers::{SPIOError, SPFile, PushRawBytes, discard_incomplete};
+
+const RECORD_HEADER_LEN: usize = 16;
+const HOP_LEN: usize = 20;
+
+/**
+ * A single hop of a path: the address that answered at its distance (TTL),
+ * and how long it took to.
+ */
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Hop {
+    pub ip: Option<IpAddr>,
+    pub rtt: Option<i32>,  // in microseconds
+}
+
+/**
+ * A path mapped to an address at the given time, hop by hop.
+ */
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HopRound {
+    pub time: i64,
+    pub hops: Vec<Hop>,
+}
+
+/**
+ * Encodes the path mapped to the address of the given index at the given time
+ * as a record of the hops file.
+ */
+fn encode(time: i64, index: i32, hops: &[Hop]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + hops.len() * HOP_LEN);
+    buf.push_i64(time);
+    buf.push_i32(index);
+    buf.push_i32(hops.len() as i32);
+    for hop in hops.iter() {
+        buf.push_i32(hop.rtt.unwrap_or(-1));
+        let ip = match hop.ip {
+            Some(IpAddr::V4(ip)) => ip.to_ipv6_mapped(),
+            Some(IpAddr::V6(ip)) => ip,
+            None => Ipv6Addr::UNSPECIFIED,
+        };
+        buf.extend_from_slice(&ip.octets());
+    }
+    buf
+}
+
+/**
+ * Decodes the records of the given contents of a hops file as (time, index,
+ * hops), stopping at any incomplete record at the end.
+ */
+fn decode(bytes: &[u8]) -> Vec<(i64, i32, Vec<Hop>)> {
+    let i32_at = |at: usize| i32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap());
+
+    let mut records = Vec::new();
+    let mut at = 0;
+    while at + RECORD_HEADER_LEN <= bytes.len() {
+        let time = i64::from_ne_bytes(bytes[at..at + 8].try_into().unwrap());
+        let index = i32_at(at + 8);
+        let num_hops = i32_at(at + 12).max(0) as usize;
+        let end = at + RECORD_HEADER_LEN + num_hops * HOP_LEN;
+        if end > bytes.len() {
+            break;
+        }
+
+        let hops = (0..num_hops).map(|h| {
+            let hop_at = at + RECORD_HEADER_LEN + h * HOP_LEN;
+            let rtt = i32_at(hop_at);
+            let octets: [u8; 16] = bytes[hop_at + 4..hop_at + HOP_LEN].try_into().unwrap();
+            let ip = Ipv6Addr::from(octets);
+            Hop {
+                ip: if ip.is_unspecified() {
+                    None
+                } else {
+                    Some(ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4))
+                },
+                rtt: if rtt < 0 { None } else { Some(rtt) },
+            }
+        }).collect();
+        records.push((time, index, hops));
+        at = end;
+    }
+    records
+}
+
+/**
+ * Returns the length of the given contents of a hops file up to the end of
+ * its last complete record.
+ */
+pub fn complete_len(bytes: &[u8]) -> usize {
+    let mut at = 0;
+    while at + RECORD_HEADER_LEN <= bytes.len() {
+        let num_hops = i32::from_ne_bytes(bytes[at + 12..at + 16].try_into().unwrap()).max(0) as usize;
+        let end = at + RECORD_HEADER_LEN + num_hops * HOP_LEN;
+        if end > bytes.len() {
+            break;
+        }
+        at = end;
+    }
+    at
+}
+
+/**
+ * The hops file of a target, appended to with every path mapped.
+ */
+pub struct HopLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl HopLog {
+    /**
+     * Opens (creating it if necessary) the hops file at the given path,
+     * discarding any incomplete record at its end.
+     */
+    pub fn open(path: &Path) -> Result<Self, SPIOError> {
+        let log = HopLog {
+            path: path.to_owned(),
+            file: Mutex::new(Self::open_file(path)?),
+        };
+        {
+            let mut file = log.file.lock().unwrap();
+            let bytes = log.read_all(&mut file)?;
+            discard_incomplete(&file, path, bytes.len() as u64, complete_len(&bytes) as u64)?;
+        }
+        Ok(log)
+    }
+
+    fn open_file(path: &Path) -> Result<File, SPIOError> {
+        File::open_from(OpenOptions::new().read(true).append(true).create(true), path)
+    }
+
+    fn read_all(&self, file: &mut File) -> Result<Vec<u8>, SPIOError> {
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_to_end(&mut bytes))
+            .map_err(|_| SPIOError::Read(Some(self.path.clone())))?;
+        Ok(bytes)
+    }
+
+    /**
+     * Appends the path mapped to the address of the given index at the given
+     * time.
+     */
+    pub fn append(&self, time: i64, index: i32, hops: &[Hop]) -> Result<(), SPIOError> {
+        self.file.lock().unwrap().write_all(&encode(time, index, hops))
+            .map_err(|_| SPIOError::Write(Some(self.path.clone())))
+    }
+
+    /**
+     * Reads back the paths mapped to the address of the given index from
+     * `from` to `to` (inclusive), in order of time.
+     */
+    pub fn read_range(&self, index: i32, from: i64, to: i64) -> Result<Vec<HopRound>, SPIOError> {
+        let bytes = self.read_all(&mut self.file.lock().unwrap())?;
+        Ok(decode(&bytes).into_iter()
+            .filter(|&(time, i, _)| i == index && from <= time && time <= to)
+            .map(|(time, _, hops)| HopRound { time, hops })
+            .collect())
+    }
+
+    /**
+     * Removes the paths mapped before `before`, rewriting the hops file with
+     * the rest. Returns how many were removed.
+     */
+    pub fn prune(&self, before: i64) -> Result<u64, SPIOError> {
+        let mut file = self.file.lock().unwrap();
+        let records = decode(&self.read_all(&mut file)?);
+        let kept: Vec<_> = records.iter().filter(|&&(time, _, _)| time >= before).collect();
+        if kept.len() == records.len() {
+            return Ok(0);
+        }
+
+        let mut bytes = Vec::new();
+        for &&(time, index, ref hops) in kept.iter() {
+            bytes.extend(encode(time, index, hops));
+        }
+        // write out to a temporary file first, so a failed prune leaves it be
+        let tmp_path = self.path.with_extension("dat.tmp");
+        File::create(&tmp_path)
+            .and_then(|mut f| f.write_all(&bytes).and_then(|_| f.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|_| SPIOError::Write(Some(self.path.clone())))?;
+        *file = Self::open_file(&self.path)?;
+        Ok((records.len() - kept.len()) as u64)
+    }
+
+    /**
+     * Flushes the hops file to disk.
+     */
+    pub fn sync(&self) -> Result<(), SPIOError> {
+        self.file.lock().unwrap().sync_all()
+            .map_err(|_| SPIOError::Write(Some(self.path.clone())))
+    }
+}
+
+/**
+ * Gets the rounds (of an IPs file) in which the set of IPs probed differed
+ * from the round before, as their time and the IPs probed since, i.e. when an
+ * address moved to other IPs (the first round always counting as a move).
+ */
+pub fn ip_changes(rounds: &[HopRound]) -> Vec<(i64, Vec<IpAddr>)> {
+    let mut changes: Vec<(i64, Vec<IpAddr>)> = Vec::new();
+    for round in rounds.iter() {
+        let mut ips: Vec<IpAddr> = round.hops.iter().filter_map(|h| h.ip).collect();
+        ips.sort_unstable();
+        if changes.last().is_none_or(|(_, last)| *last != ips) {
+            changes.push((round.time, ips));
+        }
+    }
+    changes
+}
+
+#[test]
+fn hop_records_round_trip() {
+    let hops = vec![
+        Hop { ip: Some("192.168.1.1".parse().unwrap()), rtt: Some(1200) },
+        Hop { ip: None, rtt: None },
+        Hop { ip: Some("2001:db8::1".parse().unwrap()), rtt: Some(15_000) },
+    ];
+    let mut bytes = encode(100, 2, &hops);
+    bytes.extend(encode(160, 3, &[]));
+    // an incomplete record (e.g. cut short by a crash) is left out
+    bytes.extend(&encode(220, 2, &hops)[..30]);
+
+    assert_eq!(decode(&bytes), vec![(100, 2, hops), (160, 3, Vec::new())]);
+}
+
+#[test]
+fn hop_log_prunes_paths_before_time() {
+    let path = std::env::temp_dir().join(format!("stabping-test-{}.hops.dat", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let log = HopLog::open(&path).unwrap();
+    let hops = vec![Hop { ip: Some("10.0.0.1".parse().unwrap()), rtt: Some(500) }];
+    for time in 1..=4 {
+        log.append(time, 0, &hops).unwrap();
+    }
+
+    assert_eq!(log.prune(3).unwrap(), 2);
+    assert_eq!(log.prune(3).unwrap(), 0);
+    log.append(5, 0, &hops).unwrap();
+    let times: Vec<i64> = log.read_range(0, 0, i64::MAX).unwrap().iter().map(|r| r.time).collect();
+    assert_eq!(times, vec![3, 4, 5]);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn ip_changes_are_rounds_moving_to_other_ips() {
+    let round = |time, ips: &[&str]| HopRound {
+        time,
+        hops: ips.iter().map(|ip| Hop { ip: Some(ip.parse().unwrap()), rtt: Some(100) }).collect(),
+    };
+    let rounds = vec![
+        round(10, &["192.0.2.1"]),
+        round(20, &["192.0.2.1"]),
+        round(30, &["2001:db8::1", "192.0.2.2"]),
+        round(40, &["192.0.2.2", "2001:db8::1"]),
+        round(50, &["192.0.2.1"]),
+    ];
+    let times: Vec<i64> = ip_changes(&rounds).iter().map(|&(time, _)| time).collect();
+    assert_eq!(times, vec![10, 30, 50]);
+    assert_eq!(ip_changes(&rounds)[1].1.len(), 2);
+}
@@ -0,0 +1,240 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Bulk import of the addresses of a target from a plain hosts file, for
+ * onboarding many devices at once.
+ *
+ * Each line holds an address (as in the target's `addrs`, e.g. `host:port`),
+ * optionally followed by `key=value` pairs: its display `name`, its `notes`
+ * and any of its tags (see `tags.rs`), values with spaces being quoted, e.g.
+ *
+ *     192.168.1.1:443 name="Office VPN gateway" site=office group=vpn
+ *
+ * Blank lines and lines starting with `#` are skipped.
+ */
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+
+use crate::options::{next_nonce, TargetOptions};
+use crate::persist::{TargetManager, ManagerError};
+use crate::tags::Tags;
+
+/**
+ * An address read from a hosts file, with whatever was given of it.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostEntry {
+    pub addr: String,
+    pub name: Option<String>,
+    pub notes: Option<String>,
+    pub tags: Tags,  // replacing those of the address, if any are given
+}
+
+#[derive(Debug)]
+pub enum HostsError {
+    Parse(usize, &'static str),  // the line, and what's wrong with it
+    Store(ManagerError),
+}
+
+impl HostsError {
+    pub fn description(&self) -> String {
+        match *self {
+            HostsError::Parse(line, reason) => format!("line {}: {}", line, reason),
+            HostsError::Store(ref e) => format!("failed to update options: {}", e),
+        }
+    }
+}
+
+impl Display for HostsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+/**
+ * What importing a hosts file changed of a target.
+ */
+#[derive(Debug, Default, PartialEq)]
+pub struct HostsSummary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,  // already there, with a new name, notes or tags
+    pub removed: Vec<String>,  // not in the file (when replacing)
+}
+
+impl HostsSummary {
+    pub fn changed(&self) -> bool {
+        !(self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty())
+    }
+}
+
+/**
+ * Splits the given line into its whitespace-separated words, keeping the
+ * whitespace within double quotes (which are dropped).
+ */
+fn split_words(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut words = Vec::new();
+    let mut word = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            },
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quote");
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/**
+ * Splits the given word into a key and its value, if it is a `key=value` pair
+ * (keys being made of letters, digits, `_`, `-` and `.`, so that e.g. URLs
+ * with queries aren't taken for pairs).
+ */
+fn split_pair(word: &str) -> Option<(&str, &str)> {
+    word.split_once('=').filter(|(key, _)| {
+        !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+    })
+}
+
+/**
+ * Parses the given hosts file (see the module documentation).
+ */
+pub fn parse_hosts(input: &str) -> Result<Vec<HostEntry>, HostsError> {
+    let mut entries: Vec<HostEntry> = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |reason| HostsError::Parse(i + 1, reason);
+
+        // the address may itself be several words (e.g. `dns 8.8.8.8:53`)
+        let words = split_words(line).map_err(err)?;
+        let num_addr_words = words.iter().position(|w| split_pair(w).is_some()).unwrap_or(words.len());
+        if num_addr_words == 0 {
+            return Err(err("missing address"));
+        }
+        let mut entry = HostEntry {
+            addr: words[..num_addr_words].join(" "),
+            ..HostEntry::default()
+        };
+        for word in words[num_addr_words..].iter() {
+            match split_pair(word) {
+                Some(("name", v)) => entry.name = Some(v.to_owned()),
+                Some(("notes", v)) => entry.notes = Some(v.to_owned()),
+                Some((k, v)) => { entry.tags.insert(k.to_owned(), v.to_owned()); },
+                None => return Err(err("address must come before its name, notes and tags")),
+            }
+        }
+        if entries.iter().any(|e| e.addr == entry.addr) {
+            return Err(err("address listed twice"));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/**
+ * Applies the given entries to the given options: adding the addresses not
+ * yet in them and updating whatever was given of those already there, and
+ * (when replacing) removing the addresses not among the entries.
+ */
+pub fn apply_hosts(options: &mut TargetOptions, entries: Vec<HostEntry>, replace: bool) -> HostsSummary {
+    let mut summary = HostsSummary::default();
+    if replace {
+        let (kept, removed) = options.addrs.drain(..).partition(|a| entries.iter().any(|e| e.addr == *a));
+        options.addrs = kept;
+        for addr in removed {
+            options.forget_addr(&addr);
+            summary.removed.push(addr);
+        }
+    }
+
+    for e in entries {
+        let existing = options.addrs.contains(&e.addr);
+        let mut changed = false;
+        let mut set = |map: &mut BTreeMap<String, String>, value: Option<String>| {
+            if let Some(v) = value {
+                if map.get(&e.addr) != Some(&v) {
+                    map.insert(e.addr.clone(), v);
+                    changed = true;
+                }
+            }
+        };
+        set(&mut options.names, e.name);
+        set(&mut options.notes, e.notes);
+        if !e.tags.is_empty() && options.tags.get(&e.addr) != Some(&e.tags) {
+            options.tags.insert(e.addr.clone(), e.tags);
+            changed = true;
+        }
+
+        if !existing {
+            options.addrs.push(e.addr.clone());
+            summary.added.push(e.addr);
+        } else if changed {
+            summary.updated.push(e.addr);
+        }
+    }
+    summary
+}
+
+/**
+ * Imports the given entries into the given target (see `apply_hosts`),
+ * updating its options (with the next nonce) if anything changed.
+ */
+pub fn import_hosts(tm: &TargetManager, entries: Vec<HostEntry>, replace: bool) -> Result<HostsSummary, HostsError> {
+    let mut new_options = tm.options_read().clone();
+    let summary = apply_hosts(&mut new_options, entries, replace);
+    if summary.changed() {
+        new_options.nonce = next_nonce(new_options.nonce);
+        tm.options_update(new_options).map_err(HostsError::Store)?;
+    }
+    Ok(summary)
+}
+
+#[test]
+fn hosts_files_add_update_and_replace_addresses() {
+    let entries = parse_hosts(r#"
+        # the office
+        192.168.1.1:443 name="Office VPN gateway" site=office group=vpn
+        dns 8.8.8.8:53 notes="Google's resolver"
+        http://example.com/?a=b
+    "#).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].name.as_deref(), Some("Office VPN gateway"));
+    assert_eq!(entries[0].tags.get("group").map(String::as_str), Some("vpn"));
+    assert_eq!(entries[1].addr, "dns 8.8.8.8:53");
+    assert_eq!(entries[2].addr, "http://example.com/?a=b");
+
+    assert!(matches!(parse_hosts("a:1\nname=x"), Err(HostsError::Parse(2, _))));
+    assert!(matches!(parse_hosts("a:1 site=x b:2"), Err(HostsError::Parse(1, _))));
+    assert!(matches!(parse_hosts("a:1 name=\"x"), Err(HostsError::Parse(1, _))));
+    assert!(matches!(parse_hosts("a:1\na:1"), Err(HostsError::Parse(2, _))));
+
+    let mut opt = crate::tcpping::KIND.default_options();
+    opt.addrs = vec!["192.168.1.1:443".to_owned(), "old:80".to_owned()];
+    let summary = apply_hosts(&mut opt, entries.clone(), false);
+    assert_eq!(summary.added, vec!["dns 8.8.8.8:53", "http://example.com/?a=b"]);
+    assert_eq!(summary.updated, vec!["192.168.1.1:443"]);
+    assert_eq!(opt.addrs.len(), 4);
+
+    // applying the same again changes nothing, and replacing drops the rest
+    assert!(!apply_hosts(&mut opt, entries.clone(), false).changed());
+    let summary = apply_hosts(&mut opt, entries, true);
+    assert_eq!(summary.removed, vec!["old:80"]);
+    assert_eq!(opt.addrs, vec!["192.168.1.1:443", "dns 8.8.8.8:53", "http://example.com/?a=b"]);
+}
@@ -0,0 +1,302 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A minimal blocking HTTP/1.1 client, split into its individual phases
+ * (resolve, connect, TLS handshake, request) so that each can be timed
+ * separately.
+ */
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use iron::url::form_urlencoded;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+
+/**
+ * The parts of an `http://` or `https://` URL we need to make a request.
+ */
+#[derive(Debug, Clone)]
+pub struct Url {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    /**
+     * Parses the given string as an HTTP(S) URL, returning `None` if it is
+     * not one.
+     */
+    pub fn parse(s: &str) -> Option<Self> {
+        let (https, rest) = if let Some(r) = s.strip_prefix("https://") {
+            (true, r)
+        } else if let Some(r) = s.strip_prefix("http://") {
+            (false, r)
+        } else {
+            return None;
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        // split off an explicit port, minding bracketed IPv6 literals
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => {
+                (&authority[..i], authority[i + 1..].parse().ok()?)
+            },
+            _ => (authority, if https { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(Url {
+            https,
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+/**
+ * A connection to an HTTP server, either in the clear or over TLS.
+ */
+pub enum HttpStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            HttpStream::Plain(ref mut s) => s.read(buf),
+            HttpStream::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for HttpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            HttpStream::Plain(ref mut s) => s.write(buf),
+            HttpStream::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            HttpStream::Plain(ref mut s) => s.flush(),
+            HttpStream::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/**
+ * Returns the process-wide TLS client configuration, trusting the Mozilla
+ * root certificates.
+ */
+fn tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = ClientConfig::builder_with_provider(
+                Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Arc::new(config)
+    }).clone()
+}
+
+/**
+ * Makes a TLS client configuration trusting the certificates in the given PEM
+ * file besides the Mozilla root certificates, and presenting the certificate
+ * (chain) and private key in the given PEM files, if any, or describes what's
+ * wrong with them.
+ */
+pub fn client_config(ca: Option<&Path>, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    if let Some(ca) = ca {
+        let certs = CertificateDer::pem_file_iter(ca)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Unable to read certificates '{}': {}", ca.display(), e))?;
+        let (added, _) = roots.add_parsable_certificates(certs);
+        if added == 0 {
+            return Err(format!("No usable certificate in '{}'", ca.display()));
+        }
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("{}", e))?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert_path, key_path)) => {
+            let certs = CertificateDer::pem_file_iter(cert_path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("Unable to read certificate '{}': {}", cert_path.display(), e))?;
+            let key = PrivateKeyDer::from_pem_file(key_path)
+                .map_err(|e| format!("Unable to read private key '{}': {}", key_path.display(), e))?;
+            builder.with_client_auth_cert(certs, key).map_err(|e| format!("Invalid certificate or key: {}", e))?
+        },
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/**
+ * Performs a complete TLS handshake with the given host over an already
+ * connected TCP stream.
+ */
+pub fn tls_handshake(host: &str, tcp: TcpStream) -> io::Result<HttpStream> {
+    tls_handshake_with(tls_config(), host, tcp)
+}
+
+/**
+ * Performs a complete TLS handshake like `tls_handshake`, with the given
+ * client configuration (see `client_config`).
+ */
+pub fn tls_handshake_with(config: Arc<ClientConfig>, host: &str, mut tcp: TcpStream) -> io::Result<HttpStream> {
+    let name = ServerName::try_from(host.to_owned())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut conn = ClientConnection::new(config, name)
+        .map_err(io::Error::other)?;
+
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp)?;
+    }
+
+    Ok(HttpStream::Tls(Box::new(StreamOwned::new(conn, tcp))))
+}
+
+/**
+ * Writes a request with the given method, extra headers and body for the
+ * given URL to the stream. The connection is always asked to be closed after
+ * the response.
+ */
+pub fn write_request(stream: &mut HttpStream, method: &str, url: &Url,
+                     headers: &[(&str, &str)], body: &[u8]) -> io::Result<()> {
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: stabping/{}\r\nConnection: close\r\n",
+        method, url.path, url.host, env!("CARGO_PKG_VERSION")
+    );
+    for &(name, value) in headers {
+        req.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        req.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    req.push_str("\r\n");
+
+    stream.write_all(req.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/**
+ * Makes a complete request (see `write_request`) to the given URL, returning
+ * the status code of the response. Each phase of the request is given the
+ * given timeout.
+ */
+pub fn send(method: &str, url: &Url, headers: &[(&str, &str)], body: &[u8],
+            timeout: Duration) -> io::Result<u16> {
+    send_with(tls_config(), method, url, headers, body, timeout)
+}
+
+/**
+ * Makes a complete request like `send`, over HTTPS with the given client
+ * configuration (see `client_config`).
+ */
+pub fn send_with(config: Arc<ClientConfig>, method: &str, url: &Url, headers: &[(&str, &str)], body: &[u8],
+                 timeout: Duration) -> io::Result<u16> {
+    let sock_addr = (url.host.as_str(), url.port).to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))?;
+    let tcp = TcpStream::connect_timeout(&sock_addr, timeout)?;
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+
+    let mut stream = if url.https {
+        tls_handshake_with(config, &url.host, tcp)?
+    } else {
+        HttpStream::Plain(tcp)
+    };
+    write_request(&mut stream, method, url, headers, body)?;
+
+    // e.g. "HTTP/1.1 204 No Content"
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    status_line.split_whitespace().nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed status line"))
+}
+
+/**
+ * Percent-encodes the given string for use in a URL query.
+ */
+pub fn url_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/**
+ * Parses the given URL query into the names and values of its parameters, in
+ * order, each decoded (with `+` for spaces).
+ */
+pub fn query_pairs(query: &str) -> Vec<(String, String)> {
+    form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+}
+
+/**
+ * Parses the given URL query into its parameters by name (see `query_pairs`),
+ * the last of any given more than once winning.
+ */
+pub fn query_params(query: &str) -> HashMap<String, String> {
+    query_pairs(query).into_iter().collect()
+}
+
+#[test]
+fn url_parse_handles_ports_and_ipv6_literals() {
+    let u = Url::parse("https://[::1]:8443/status?x=1").unwrap();
+    assert!(u.https);
+    assert_eq!((u.host.as_str(), u.port, u.path.as_str()), ("::1", 8443, "/status?x=1"));
+
+    let u = Url::parse("http://example.com").unwrap();
+    assert_eq!((u.host.as_str(), u.port, u.path.as_str()), ("example.com", 80, "/"));
+
+    assert!(Url::parse("ftp://example.com/").is_none());
+
+    let params = query_params("label=home+link%3A%2080%&addr=a%26b&tag=x&tag=y");
+    assert_eq!(params["label"], "home link: 80%");
+    assert_eq!(params["addr"], "a&b");
+    assert_eq!(params["tag"], "y");
+    assert_eq!(query_pairs("tag=x&&tag=y"), vec![("tag".to_owned(), "x".to_owned()), ("tag".to_owned(), "y".to_owned())]);
+    assert!(query_params("").is_empty());
+}
@@ -0,0 +1,113 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+use std::io::Read;
+
+use time::precise_time_ns;
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::http;
+use crate::http::{HttpStream, Url};
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, SENTINEL_TLS,
+                     io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::resolve;
+use crate::proxy;
+use crate::worker::ProbeSettings;
+
+pub static KIND: TargetKind = TargetKind::of(&HttpPing);
+
+/**
+ * The HTTP Ping target, timing a single HTTP request against each addr, which
+ * is a URL optionally preceded by the method to use (`GET` if omitted), e.g.
+ * `HEAD https://example.com/`.
+ *
+ * Returns the time to first byte of the response followed by the time spent
+ * in DNS resolution, TCP connect, and TLS handshake (0 for plain HTTP).
+ */
+pub struct HttpPing;
+
+impl Probe for HttpPing {
+    type Target = (String, Url, SocketAddr);  // method, URL and the address of its host
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 2,
+        name: "httpping",
+        columns: &["", "dns", "connect", "tls"],
+        units: &[],
+        default_timeout: 30_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &["https://www.google.com/", "http://example.com/"],
+        default_interval: 30_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32> {
+        let (method, url_str) = match addr.split_once(' ') {
+            Some((m, u)) => (m, u.trim()),
+            None => ("GET", addr),
+        };
+        let url = Url::parse(url_str).ok_or(SENTINEL_ERROR)?;
+        let sock_addr = resolve::resolve(&url.host, url.port, settings).ok_or(SENTINEL_RESOLVE)?;
+        Ok((method.to_owned(), url, sock_addr))
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        let url = Url::parse(addr.split_once(' ').map_or(addr, |(_, u)| u.trim()))?;
+        Some((url.host, url.port))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.2.ip())
+    }
+
+    fn probe_once(&self, _: &str, (method, url, sock_addr): Self::Target,
+                  attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        httpping_once(&method, &url, sock_addr, attempt)
+    }
+}
+
+/**
+ * Times a single HTTP request with the given method against the given URL,
+ * its host having resolved to the given address.
+ */
+fn httpping_once(method: &str, url: &Url, sock_addr: SocketAddr, attempt: &Attempt) -> Result<Vec<u64>, i32> {
+    // the timeout applies to each phase of the request separately
+    let timeout = attempt.settings.timeout;
+
+    let resolved = precise_time_ns();
+    let tcp = proxy::connect(&sock_addr, attempt.settings).map_err(|e| io_error_sentinel(&e))?;
+    tcp.set_read_timeout(Some(timeout)).map_err(|_| SENTINEL_ERROR)?;
+    tcp.set_write_timeout(Some(timeout)).map_err(|_| SENTINEL_ERROR)?;
+    let connected = precise_time_ns();
+
+    let mut stream = if url.https {
+        // anything but an unresponsive server is a failed TLS negotiation
+        http::tls_handshake(&url.host, tcp).map_err(|e| match io_error_sentinel(&e) {
+            s if s == SENTINEL_ERROR => SENTINEL_TLS,
+            s => s,
+        })?
+    } else {
+        HttpStream::Plain(tcp)
+    };
+    let handshaken = precise_time_ns();
+
+    http::write_request(&mut stream, method, url, &[], &[]).map_err(|e| io_error_sentinel(&e))?;
+    let mut first = [0u8; 1];
+    stream.read_exact(&mut first).map_err(|e| io_error_sentinel(&e))?;
+    let first_byte = precise_time_ns();
+
+    Ok(vec![
+        attempt.resolve_nanos + first_byte - resolved,
+        attempt.resolve_nanos,
+        connected - resolved,
+        handshaken - connected,
+    ])
+}
@@ -0,0 +1,429 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Serving the web server over TLS (HTTPS) with rustls, from a certificate and
+ * key in PEM files, which may be made (self-signed) on first run.
+ *
+ * The web server (hyper, under Iron) wraps each connection it accepts through
+ * `SslServer`, and expects streams it can clone to read from and write to
+ * separately, so the connection is shared behind a lock. The handshake is
+ * done on first use of the stream, by the thread handling the connection
+ * (with its timeouts), rather than when it is accepted.
+ *
+ * With a `client_ca`, clients are asked for a certificate (but may go without
+ * one, e.g. browsers), and those presenting one issued by the `client_ca` are
+ * identified by its DNS names. Handlers only see the request (not the
+ * connection), so these are looked up by the address of the client (see
+ * `client_names`).
+ */
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{Datelike, Duration as ChronoDuration, UTC};
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+
+/**
+ * The DNS names of the verified client certificates of the connections open
+ * to the web server, by the address of their client.
+ */
+static CLIENT_NAMES: Mutex<BTreeMap<SocketAddr, Vec<String>>> = Mutex::new(BTreeMap::new());
+
+/**
+ * Gets the DNS names of the verified client certificate of the connection
+ * from the given address, if it presented one.
+ */
+pub fn client_names(peer: &SocketAddr) -> Vec<String> {
+    CLIENT_NAMES.lock().unwrap().get(peer).cloned().unwrap_or_default()
+}
+
+/**
+ * Gets the DNS names (subject alternative names) of the given certificate.
+ */
+fn cert_names(cert: &CertificateDer) -> Vec<String> {
+    webpki::EndEntityCert::try_from(cert)
+        .map(|c| c.valid_dns_names().map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/**
+ * The TLS configuration the web server wraps its connections with.
+ */
+#[derive(Clone)]
+pub struct HttpsServer {
+    config: Arc<ServerConfig>,
+}
+
+impl HttpsServer {
+    /**
+     * Loads the certificate (chain) and private key from the given PEM files,
+     * and the certificates client certificates must chain to (if any are
+     * accepted), or describes what's wrong with them.
+     */
+    pub fn load(cert_path: &Path, key_path: &Path, client_ca: Option<&Path>) -> Result<Self, String> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Unable to read certificate '{}': {}", cert_path.display(), e))?;
+        if certs.is_empty() {
+            return Err(format!("No certificate in '{}'", cert_path.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| format!("Unable to read private key '{}': {}", key_path.display(), e))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("{}", e))?;
+        let builder = match client_ca {
+            Some(ca_path) => {
+                let mut roots = RootCertStore::empty();
+                let ca = CertificateDer::pem_file_iter(ca_path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| format!("Unable to read client certificates '{}': {}", ca_path.display(), e))?;
+                if roots.add_parsable_certificates(ca).0 == 0 {
+                    return Err(format!("No usable client certificate in '{}'", ca_path.display()));
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(|e| format!("Invalid client certificates: {}", e))?;
+                builder.with_client_cert_verifier(verifier)
+            },
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(certs, key)
+            .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+        Ok(HttpsServer {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl SslServer for HttpsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        let conn = ServerConnection::new(self.config.clone())
+            .map_err(|e| hyper::Error::Ssl(Box::new(e)))?;
+        let tcp = stream.0.try_clone()?;
+        Ok(TlsStream {
+            client: Arc::new(Client {
+                peer: tcp.peer_addr().ok(),
+                identified: AtomicBool::new(false),
+            }),
+            tcp: Arc::new(tcp),
+            conn: Arc::new(Mutex::new(StreamOwned::new(conn, stream))),
+        })
+    }
+}
+
+/**
+ * The client of a connection, recorded in `CLIENT_NAMES` once the handshake
+ * is done (if it presented a certificate) until the connection is dropped.
+ */
+struct Client {
+    peer: Option<SocketAddr>,
+    identified: AtomicBool,
+}
+
+impl Client {
+    fn identify(&self, conn: &ServerConnection) {
+        if conn.is_handshaking() || self.identified.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let names = conn.peer_certificates().and_then(|certs| certs.first()).map(cert_names);
+        if let (Some(peer), Some(names)) = (self.peer, names) {
+            CLIENT_NAMES.lock().unwrap().insert(peer, names);
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(peer) = self.peer {
+            if self.identified.load(Ordering::SeqCst) {
+                CLIENT_NAMES.lock().unwrap().remove(&peer);
+            }
+        }
+    }
+}
+
+/**
+ * A connection to the web server over TLS.
+ */
+#[derive(Clone)]
+pub struct TlsStream {
+    client: Arc<Client>,
+    tcp: Arc<std::net::TcpStream>,
+    conn: Arc<Mutex<StreamOwned<ServerConnection, HttpStream>>>,
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TlsStream(_)")
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let read = conn.read(buf)?;
+        self.client.identify(&conn.conn);
+        Ok(read)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.lock().unwrap().flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.tcp.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        // let the client know we're done, if we still can
+        let mut conn = self.conn.lock().unwrap();
+        conn.conn.send_close_notify();
+        let _ = conn.flush();
+        match self.tcp.shutdown(how) {
+            // the client hung up already
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            r => r,
+        }
+    }
+}
+
+/*
+ * DER encoding of just what a self-signed certificate needs.
+ */
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().copied().skip_while(|&b| b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+fn sequence(parts: &[Vec<u8>]) -> Vec<u8> {
+    der(0x30, &parts.concat())
+}
+
+static OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+static OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+static OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+static OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+static OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+fn time(t: chrono::DateTime<UTC>) -> Vec<u8> {
+    // UTCTime until 2050, GeneralizedTime after
+    if t.year() < 2050 {
+        der(0x17, t.format("%y%m%d%H%M%SZ").to_string().as_bytes())
+    } else {
+        der(0x18, t.format("%Y%m%d%H%M%SZ").to_string().as_bytes())
+    }
+}
+
+/**
+ * Makes a self-signed certificate (valid for 10 years) for the given host
+ * names and addresses, and its (ECDSA P-256) private key, as PEM.
+ */
+pub fn self_signed(names: &[String]) -> Result<(String, String), String> {
+    let rng = SystemRandom::new();
+    let failed = |_| "Unable to generate a key".to_owned();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).map_err(failed)?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+        .map_err(|_| "Unable to generate a key".to_owned())?;
+
+    let mut serial = [0; 16];
+    rng.fill(&mut serial).map_err(failed)?;
+    serial[0] &= 0x7f;  // kept positive
+    serial[0] |= 0x40;  // and without leading zeros
+
+    let algorithm = sequence(&[der(0x06, OID_ECDSA_SHA256)]);
+    let name = sequence(&[der(0x31, &sequence(&[der(0x06, OID_COMMON_NAME), der(0x0c, b"stabping")]))]);
+    let now = UTC::now();
+    let alt_names: Vec<Vec<u8>> = names.iter()
+        .map(|n| match n.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => der(0x87, &ip.octets()),
+            Ok(IpAddr::V6(ip)) => der(0x87, &ip.octets()),
+            Err(_) => der(0x82, n.as_bytes()),
+        })
+        .collect();
+    let extensions = der(0xa3, &sequence(&[
+        sequence(&[der(0x06, OID_SUBJECT_ALT_NAME), der(0x04, &sequence(&alt_names))]),
+    ]));
+
+    let mut public_key = vec![0];
+    public_key.extend_from_slice(key.public_key().as_ref());
+    let tbs = sequence(&[
+        der(0xa0, &der(0x02, &[2])),  // v3
+        der(0x02, &serial),
+        algorithm.clone(),
+        name.clone(),
+        sequence(&[time(now - ChronoDuration::days(1)), time(now + ChronoDuration::days(3650))]),
+        name,
+        sequence(&[sequence(&[der(0x06, OID_EC_PUBLIC_KEY), der(0x06, OID_P256)]), der(0x03, &public_key)]),
+        extensions,
+    ]);
+    let signature = key.sign(&rng, &tbs).map_err(|_| "Unable to sign the certificate".to_owned())?;
+    let mut signature_bits = vec![0];
+    signature_bits.extend_from_slice(signature.as_ref());
+    let cert = sequence(&[tbs, algorithm, der(0x03, &signature_bits)]);
+
+    Ok((pem("CERTIFICATE", &cert), pem("PRIVATE KEY", pkcs8.as_ref())))
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = crate::smtp::base64(der);
+    let mut out = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+    out
+}
+
+/**
+ * Makes a self-signed certificate and key (see `self_signed`) at the given
+ * paths, unless both are already there.
+ */
+pub fn ensure_self_signed(cert_path: &Path, key_path: &Path, names: &[String]) -> Result<(), String> {
+    if cert_path.exists() && key_path.exists() {
+        return Ok(());
+    }
+    let (cert, key) = self_signed(names)?;
+    let write = |path: &Path, contents: &str, mode: u32| -> io::Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        #[cfg(not(unix))]
+        let _ = mode;
+        options.open(path)?.write_all(contents.as_bytes())
+    };
+    write(key_path, &key, 0o600)
+        .and_then(|()| write(cert_path, &cert, 0o644))
+        .map_err(|e| format!("Unable to write self-signed certificate: {}", e))?;
+    info!("Generated a self-signed certificate at '{}'.", cert_path.display());
+    Ok(())
+}
+
+#[test]
+fn self_signed_certificates_complete_a_handshake() {
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    let (cert, key) = self_signed(&["localhost".to_owned(), "127.0.0.1".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("stabping-test-{}-https", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("cert.pem"), &cert).unwrap();
+    fs::write(dir.join("key.pem"), &key).unwrap();
+    let server = HttpsServer::load(&dir.join("cert.pem"), &dir.join("key.pem"), None).unwrap();
+    assert!(HttpsServer::load(&dir.join("key.pem"), &dir.join("key.pem"), None).is_err());
+    let _ = fs::remove_dir_all(&dir);
+
+    // a client trusting just the certificate can connect by its name
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_slice(cert.as_bytes()).unwrap()).unwrap();
+    let client_config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions().unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let mut client = ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap();
+    let mut server = ServerConnection::new(server.config).unwrap();
+    for _ in 0..10 {
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+        server.read_tls(&mut &buf[..]).unwrap();
+        server.process_new_packets().unwrap();
+        buf.clear();
+        server.write_tls(&mut buf).unwrap();
+        client.read_tls(&mut &buf[..]).unwrap();
+        client.process_new_packets().unwrap();
+    }
+    assert!(!client.is_handshaking() && !server.is_handshaking());
+}
+
+#[test]
+fn client_certificates_of_the_client_ca_are_identified() {
+    use rustls::ClientConnection;
+
+    let (cert, key) = self_signed(&["localhost".to_owned()]).unwrap();
+    let (agent_cert, agent_key) = self_signed(&["nyc".to_owned()]).unwrap();
+    let (other_cert, other_key) = self_signed(&["lon".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("stabping-test-{}-mtls", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (name, contents) in [("cert.pem", &cert), ("key.pem", &key), ("agent.pem", &agent_cert),
+                             ("agent.key.pem", &agent_key), ("other.pem", &other_cert),
+                             ("other.key.pem", &other_key)] {
+        fs::write(dir.join(name), contents).unwrap();
+    }
+    let server = HttpsServer::load(&dir.join("cert.pem"), &dir.join("key.pem"), Some(&dir.join("agent.pem")))
+        .unwrap();
+
+    // completes the handshake as the given client (if any), returning the names it is identified by
+    let handshake = |identity: Option<(&str, &str)>| -> Result<Vec<String>, rustls::Error> {
+        let identity = identity.map(|(c, k)| (dir.join(c), dir.join(k)));
+        let config = crate::http::client_config(Some(&dir.join("cert.pem")),
+                                                identity.as_ref().map(|(c, k)| (c.as_path(), k.as_path())))
+            .unwrap();
+        let mut client = ClientConnection::new(config, "localhost".try_into().unwrap()).unwrap();
+        let mut server = ServerConnection::new(server.config.clone()).unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets()?;
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets()?;
+        }
+        Ok(server.peer_certificates().and_then(|c| c.first()).map(cert_names).unwrap_or_default())
+    };
+    assert_eq!(handshake(Some(("agent.pem", "agent.key.pem"))).unwrap(), vec!["nyc"]);
+    assert_eq!(handshake(None).unwrap(), Vec::<String>::new());
+    assert!(handshake(Some(("other.pem", "other.key.pem"))).is_err());
+    let _ = fs::remove_dir_all(&dir);
+}
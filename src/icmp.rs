@@ -0,0 +1,247 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * ICMP echo ("real" ping) data collection. Echo requests are sent over a raw
+ * socket when we have the privileges for one, and otherwise over an
+ * unprivileged ICMP datagram socket (on Linux, see `net.ipv4.ping_group_range`).
+ */
+use std::io;
+use std::io::Read;
+use std::process;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use std::time::Instant;
+use time::precise_time_ns;
+
+use std::net::{IpAddr, SocketAddr};
+
+use socket2::{Socket, Domain, Type, Protocol, SockAddr};
+
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, SENTINEL_TIMEOUT,
+                     SENTINEL_UNREACHABLE, io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::resolve;
+use crate::sockopt;
+use crate::worker::ProbeSettings;
+
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+pub const ICMP_DEST_UNREACHABLE: u8 = 3;
+pub const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+
+// bytes of padding carried in each echo request after the ICMP header
+const PAYLOAD_LEN: usize = 32;
+
+// sequence numbers shared across all attempts so replies can't be confused
+pub static SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+pub static KIND: TargetKind = TargetKind::of(&IcmpPing);
+
+/**
+ * The ICMP Ping target, timing the round-trip of a single ICMP echo request
+ * to each host.
+ */
+pub struct IcmpPing;
+
+impl Probe for IcmpPing {
+    type Target = SocketAddr;
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 1,
+        name: "icmpping",
+        columns: &[""],
+        units: &[],
+        default_timeout: 5_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &["google.com", "8.8.8.8"],
+        default_interval: 10_000,
+        default_avg_across: 3,
+    };
+
+    fn resolve(&self, host: &str, settings: &ProbeSettings) -> Result<SocketAddr, i32> {
+        // addrs for this kind are bare hosts, so resolve with a dummy port
+        resolve::resolve(host, 0, settings).ok_or(SENTINEL_RESOLVE)
+    }
+
+    fn host_of(&self, host: &str) -> Option<(String, u16)> {
+        Some((host.to_owned(), 0))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.ip())
+    }
+
+    fn probe_once(&self, _: &str, addr: SocketAddr, attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        icmp_once(addr, attempt.settings)
+    }
+}
+
+/**
+ * Times the round-trip of a single ICMP echo request to the given address.
+ */
+fn icmp_once(addr: SocketAddr, settings: &ProbeSettings) -> Result<Vec<u64>, i32> {
+    let (socket, raw) = open_icmp_socket(&addr, settings).map_err(|_| SENTINEL_ERROR)?;
+    socket.set_read_timeout(Some(settings.timeout)).map_err(|_| SENTINEL_ERROR)?;
+
+    let ident = process::id() as u16;
+    let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let request = echo_request(&addr, ident, seq);
+
+    let start = precise_time_ns();
+    socket.send_to(&request, &SockAddr::from(addr)).map_err(|e| io_error_sentinel(&e))?;
+
+    let deadline = Instant::now() + settings.timeout;
+    let mut buf = [0u8; 1500];
+    loop {
+        let len = (&socket).read(&mut buf).map_err(|e| io_error_sentinel(&e))?;
+        let elapsed = precise_time_ns() - start;
+
+        /*
+         * raw IPv4 sockets hand us the IP header as well, skip past it to get
+         * to the ICMP message
+         */
+        let msg = match addr {
+            SocketAddr::V4(_) if raw => {
+                let ihl = ((buf[0] & 0x0f) as usize) * 4;
+                if len < ihl {
+                    continue;
+                }
+                &buf[ihl..len]
+            },
+            _ => &buf[..len],
+        };
+
+        if is_echo_reply(&addr, msg, ident, seq, raw) {
+            return Ok(vec![elapsed]);
+        }
+        if raw && is_unreachable_for(&addr, msg, ident, seq) {
+            return Err(SENTINEL_UNREACHABLE);
+        }
+
+        // raw sockets see all ICMP traffic, keep waiting for our reply
+        if Instant::now() >= deadline {
+            return Err(SENTINEL_TIMEOUT);
+        }
+    }
+}
+
+/**
+ * Opens a socket suitable for sending echo requests to the given address (as
+ * the given settings say, see `sockopt::prepare`), returning it along with
+ * whether it is a raw socket.
+ */
+pub fn open_icmp_socket(addr: &SocketAddr, settings: &ProbeSettings) -> io::Result<(Socket, bool)> {
+    let (domain, protocol) = match *addr {
+        SocketAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        SocketAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+
+    let (socket, raw) = match Socket::new(domain, Type::RAW, Some(protocol)) {
+        Ok(s) => (s, true),
+        Err(_) => (Socket::new(domain, Type::DGRAM, Some(protocol))?, false),
+    };
+    sockopt::prepare(&socket, addr, settings)?;
+    Ok((socket, raw))
+}
+
+/**
+ * Builds an echo request message for the given address family.
+ */
+pub fn echo_request(addr: &SocketAddr, ident: u16, seq: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(8 + PAYLOAD_LEN);
+    msg.push(match *addr {
+        SocketAddr::V4(_) => ICMP_ECHO_REQUEST,
+        SocketAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+    });
+    msg.push(0);  // code
+    msg.extend_from_slice(&[0, 0]);  // checksum, filled in below
+    msg.extend_from_slice(&ident.to_be_bytes());
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend((0..PAYLOAD_LEN).map(|i| i as u8));
+
+    /*
+     * the kernel computes ICMPv6 checksums itself (as they cover a pseudo
+     * header we don't have), we only need to do it for ICMPv4
+     */
+    if let SocketAddr::V4(_) = *addr {
+        let sum = checksum(&msg);
+        msg[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    msg
+}
+
+/**
+ * Determines whether the given ICMP message is the reply to our request.
+ *
+ * Datagram sockets have their identifier rewritten by the kernel (and only
+ * receive replies for that identifier), so only raw sockets check it.
+ */
+fn is_echo_reply(addr: &SocketAddr, msg: &[u8], ident: u16, seq: u16, raw: bool) -> bool {
+    if msg.len() < 8 {
+        return false;
+    }
+    let reply_type = match *addr {
+        SocketAddr::V4(_) => ICMP_ECHO_REPLY,
+        SocketAddr::V6(_) => ICMPV6_ECHO_REPLY,
+    };
+    let msg_ident = u16::from_be_bytes([msg[4], msg[5]]);
+    let msg_seq = u16::from_be_bytes([msg[6], msg[7]]);
+
+    msg[0] == reply_type && msg_seq == seq && (!raw || msg_ident == ident)
+}
+
+/**
+ * Determines whether the given ICMP message reports the destination of our
+ * request unreachable (only raw sockets receive these). Such messages quote
+ * the IP header and start of the ICMP message they are about.
+ */
+fn is_unreachable_for(addr: &SocketAddr, msg: &[u8], ident: u16, seq: u16) -> bool {
+    let (unreachable_type, quoted) = match *addr {
+        SocketAddr::V4(_) => {
+            let ihl = (*msg.get(8).unwrap_or(&0) & 0x0f) as usize * 4;
+            (ICMP_DEST_UNREACHABLE, msg.get(8 + ihl..))
+        },
+        SocketAddr::V6(_) => (ICMPV6_DEST_UNREACHABLE, msg.get(8 + 40..)),
+    };
+    match quoted {
+        Some(q) if q.len() >= 8 && msg[0] == unreachable_type => {
+            u16::from_be_bytes([q[4], q[5]]) == ident && u16::from_be_bytes([q[6], q[7]]) == seq
+        },
+        _ => false,
+    }
+}
+
+/**
+ * Computes the Internet checksum (RFC 1071) of the given bytes.
+ */
+pub fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in bytes.chunks(2) {
+        let word = match *chunk {
+            [hi, lo] => u16::from_be_bytes([hi, lo]),
+            [hi] => u16::from_be_bytes([hi, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[test]
+fn checksum_of_message_with_checksum_is_zero() {
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let msg = echo_request(&addr, 0x1234, 7);
+    assert_eq!(checksum(&msg), 0);
+}
@@ -0,0 +1,438 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Import of data collected elsewhere (exports of stabping itself, or of tools
+ * such as smokeping or PingPlotter) into the persisted data of a target, so
+ * that history from before switching to stabping can be kept.
+ *
+ * The input is either CSV with a header row (separated by commas, semicolons
+ * or tabs) or JSON Lines (NDJSON) of an object per round, told apart by their
+ * lines. Columns (or fields) are picked out by name: the time of the round,
+ * its address, its primary value (in the unit its name is suffixed with, e.g.
+ * `rtt_ms`) and optionally what failed and its loss, along with any others
+ * named like columns of the target.
+ */
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+use std::io::BufRead;
+use std::mem;
+
+use chrono::{DateTime, Local, TimeZone, UTC};
+use clap::ValueEnum;
+use serde_json::Value;
+
+use crate::persist::{TargetManager, DataElement, ManagerError};
+use crate::options::{sentinel_from_name, SENTINEL_ERROR, SENTINEL_NODATA};
+
+const TIME_NAMES: &[&str] = &["timestamp", "time", "sample time", "date", "datetime", "epoch"];
+const ADDR_NAMES: &[&str] = &["address", "addr", "target", "host", "hostname", "destination"];
+const VALUE_NAMES: &[&str] = &["value", "latency", "rtt", "ping", "median", "avg", "average", "mean"];
+const ERROR_NAMES: &[&str] = &["error", "error_code"];
+const LOSS_NAMES: &[&str] = &["loss", "pl", "packet loss"];
+
+/**
+ * Values taken to mean the round failed.
+ */
+const FAILED_VALUES: &[&str] = &["", "nan", "u", "*", "-", "null", "timeout", "lost"];
+
+/**
+ * Formats of times (other than seconds since the epoch and RFC 3339) taken,
+ * in UTC unless asked otherwise.
+ */
+const TIME_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y/%m/%d %H:%M:%S",
+    "%m/%d/%Y %H:%M:%S",
+    "%m/%d/%Y %I:%M:%S %p",
+];
+
+/**
+ * The units a value can be given in.
+ */
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    S,
+    Ms,
+    Us,
+}
+
+impl Unit {
+    fn from_suffix(suffix: &str) -> Option<Unit> {
+        match suffix {
+            "s" | "sec" | "secs" | "seconds" => Some(Unit::S),
+            "ms" | "msec" | "msecs" => Some(Unit::Ms),
+            "us" | "µs" | "usec" | "usecs" => Some(Unit::Us),
+            _ => None,
+        }
+    }
+
+    fn micros(&self) -> f64 {
+        match *self {
+            Unit::S => 1_000_000.0,
+            Unit::Ms => 1_000.0,
+            Unit::Us => 1.0,
+        }
+    }
+}
+
+/**
+ * How to take the input of an import, where it doesn't say itself.
+ */
+#[derive(Debug, Default)]
+pub struct ImportOptions {
+    pub address: Option<String>,  // of every round, instead of any given
+    pub column: Option<String>,  // name of the column holding the value
+    pub unit: Option<Unit>,  // of the value, instead of its column's suffix
+    pub local_time: bool,  // whether times without an offset are local, rather than UTC
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Read(io::Error),
+    Parse(usize, String),  // the line, and what's wrong with it
+    Store(ManagerError),
+}
+
+impl ImportError {
+    pub fn description(&self) -> String {
+        match *self {
+            ImportError::Read(ref e) => format!("failed to read input: {}", e),
+            ImportError::Parse(line, ref reason) => format!("line {}: {}", line, reason),
+            ImportError::Store(ref e) => format!("failed to store data: {}", e),
+        }
+    }
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+/**
+ * What was imported into a target.
+ */
+pub struct ImportSummary {
+    pub rounds: u64,  // read from the input
+    pub imported: u64,  // data elements merged in (those not already stored)
+    pub addrs: Vec<String>,  // of the rounds read, in order of appearance
+}
+
+/**
+ * Splits the given (lowercase) name of a column into its name proper and the
+ * unit it is suffixed with, if any (e.g. `rtt_ms`, `rtt ms` or `rtt (ms)`).
+ */
+fn split_unit(name: &str) -> (&str, Option<Unit>) {
+    let name = name.trim().trim_end_matches('%').trim_end();
+    if let Some(open) = name.rfind(['(', '[']).filter(|_| name.ends_with([')', ']'])) {
+        if let Some(unit) = Unit::from_suffix(name[open + 1..name.len() - 1].trim()) {
+            return (name[..open].trim_end(), Some(unit));
+        }
+    }
+    if let Some(sep) = name.rfind(['_', ' ']) {
+        if let Some(unit) = Unit::from_suffix(&name[sep + 1..]) {
+            return (&name[..sep], Some(unit));
+        }
+    }
+    (name, None)
+}
+
+/**
+ * Which of the columns of the input hold what.
+ */
+#[derive(Debug)]
+struct Layout {
+    time: usize,
+    addr: Option<usize>,
+    value: usize,
+    unit: Unit,
+    error: Option<usize>,
+    loss: Option<usize>,
+    others: Vec<(usize, usize)>,  // (column of the input, position among the target's columns)
+}
+
+/**
+ * Works out the layout of rounds with the given names of columns, for a
+ * target with the given columns (see `TargetKind::columns`).
+ */
+fn layout(names: &[String], columns: &[String], opts: &ImportOptions) -> Result<Layout, String> {
+    let names: Vec<String> = names.iter().map(|n| n.trim().to_lowercase()).collect();
+    let find = |candidates: &[&str]| candidates.iter()
+        .find_map(|c| names.iter().position(|n| split_unit(n).0 == *c));
+
+    let time = find(TIME_NAMES)
+        .ok_or_else(|| format!("no column of the time (one of {})", TIME_NAMES.join(", ")))?;
+    let value = match opts.column {
+        Some(ref column) => {
+            let column = column.to_lowercase();
+            names.iter().position(|n| *n == column || split_unit(n).0 == column)
+                .ok_or_else(|| format!("no column named '{}'", column))?
+        },
+        None => find(VALUE_NAMES)
+            .ok_or_else(|| format!("no column of the value (one of {}, or give it with --column)",
+                                   VALUE_NAMES.join(", ")))?,
+    };
+    let unit = opts.unit.or(split_unit(&names[value]).1).unwrap_or(Unit::Us);
+
+    let mut layout = Layout {
+        time,
+        addr: find(ADDR_NAMES),
+        value,
+        unit,
+        error: find(ERROR_NAMES),
+        loss: find(LOSS_NAMES),
+        others: Vec::new(),
+    };
+    // any others named exactly like columns of the target (e.g. in stabping's own exports)
+    let used = [Some(layout.time), layout.addr, Some(layout.value), layout.error, layout.loss];
+    for (p, column) in columns.iter().enumerate().skip(1) {
+        if column == "loss" {
+            continue;
+        }
+        if let Some(c) = names.iter().position(|n| n == column) {
+            if !used.contains(&Some(c)) {
+                layout.others.push((c, p));
+            }
+        }
+    }
+    Ok(layout)
+}
+
+/**
+ * Parses the given time of a round: in seconds (or milliseconds) since the
+ * epoch, RFC 3339 or one of `TIME_FORMATS`.
+ */
+fn parse_time(s: &str, local_time: bool) -> Option<i64> {
+    if let Ok(n) = s.parse::<f64>() {
+        // in milliseconds if too large to be seconds
+        return Some(if n.abs() >= 1e11 { (n / 1000.0) as i64 } else { n as i64 });
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.timestamp());
+    }
+    TIME_FORMATS.iter().find_map(|fmt| if local_time {
+        Local.datetime_from_str(s, fmt).ok().map(|t| t.timestamp())
+    } else {
+        UTC.datetime_from_str(s, fmt).ok().map(|t| t.timestamp())
+    })
+}
+
+/**
+ * Parses a number of the given field, rounded to a whole one.
+ */
+fn parse_number(s: &str, scale: f64) -> Option<i32> {
+    s.trim_end_matches('%').trim().parse::<f64>().ok()
+        .filter(|n| n.is_finite() && *n >= 0.0)
+        .map(|n| (n * scale).round().min(i32::MAX as f64) as i32)
+}
+
+/**
+ * Parses a round of the given layout, from its columns, into its time, its
+ * address and its values of each of the given number of columns of the
+ * target (`SENTINEL_NODATA` where missing).
+ */
+fn parse_round(fields: &[String], layout: &Layout, num_columns: usize, loss_at: Option<usize>,
+               opts: &ImportOptions) -> Result<(i64, String, Vec<i32>), String> {
+    let field = |i: usize| fields.get(i).map_or("", |f| f.trim());
+
+    let time = parse_time(field(layout.time), opts.local_time)
+        .ok_or_else(|| format!("invalid time '{}'", field(layout.time)))?;
+    let addr = match (&opts.address, layout.addr.map(field)) {
+        (Some(addr), _) => addr.clone(),
+        (None, Some(addr)) if !addr.is_empty() => addr.to_owned(),
+        _ => return Err("no address (give one with --address)".to_owned()),
+    };
+
+    let mut values = vec![SENTINEL_NODATA; num_columns];
+    let value = field(layout.value);
+    let error = layout.error.map_or("", field);
+    let failed = !error.is_empty() || FAILED_VALUES.contains(&value.to_lowercase().as_str());
+    values[0] = if failed {
+        if error.is_empty() { SENTINEL_ERROR } else { sentinel_from_name(&error.to_lowercase()) }
+    } else {
+        parse_number(value, layout.unit.micros()).ok_or_else(|| format!("invalid value '{}'", value))?
+    };
+
+    if let Some(l) = loss_at {
+        values[l] = layout.loss.and_then(|c| parse_number(field(c), 1.0))
+            .unwrap_or(if failed { 100 } else { 0 });
+    }
+    for &(c, p) in layout.others.iter() {
+        values[p] = parse_number(field(c), 1.0).unwrap_or(SENTINEL_NODATA);
+    }
+    Ok((time, addr, values))
+}
+
+/**
+ * Splits the given line of CSV into its (unquoted) fields.
+ */
+fn split_csv(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/**
+ * Gets a field of an NDJSON object as it would be in CSV (empty if null).
+ */
+fn json_field(value: &Value) -> String {
+    match *value {
+        Value::Null => String::new(),
+        Value::String(ref s) => s.clone(),
+        ref v => v.to_string(),
+    }
+}
+
+/**
+ * A function called with a round read: its time, its address and its values
+ * (like `RoundFn`).
+ */
+type ReadFn<'a> = dyn FnMut(i64, &str, &[i32]) -> Result<(), ImportError> + 'a;
+
+/**
+ * Calls `f` with every round read from the given input, of a target with the
+ * given columns.
+ */
+fn read_rounds(input: &mut dyn BufRead, columns: &[String], opts: &ImportOptions,
+               f: &mut ReadFn) -> Result<(), ImportError> {
+    let loss_at = columns.iter().position(|c| c == "loss");
+    // the delimiter and layout of CSV, once its header is read
+    let mut csv: Option<(char, Layout)> = None;
+    // and the keys and layout of the last NDJSON object
+    let mut ndjson: Option<(Vec<String>, Layout)> = None;
+
+    for (n, line) in input.lines().enumerate() {
+        let line = line.map_err(ImportError::Read)?;
+        let line = line.trim();
+        let parse_error = |reason: String| ImportError::Parse(n + 1, reason);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (fields, layout) = if line.starts_with('{') {
+            let object: serde_json::Map<String, Value> = serde_json::from_str(line)
+                .map_err(|e| parse_error(e.to_string()))?;
+            let keys: Vec<String> = object.keys().cloned().collect();
+            if ndjson.as_ref().is_none_or(|(k, _)| *k != keys) {
+                let layout = layout(&keys, columns, opts).map_err(parse_error)?;
+                ndjson = Some((keys, layout));
+            }
+            (object.values().map(json_field).collect::<Vec<_>>(), &ndjson.as_ref().unwrap().1)
+        } else {
+            match csv {
+                Some((delimiter, ref layout)) => (split_csv(line, delimiter), layout),
+                None => {
+                    let delimiter = [',', ';', '\t'].into_iter()
+                        .max_by_key(|&d| line.matches(d).count())
+                        .unwrap();
+                    let layout = layout(&split_csv(line, delimiter), columns, opts).map_err(parse_error)?;
+                    csv = Some((delimiter, layout));
+                    continue;
+                },
+            }
+        };
+
+        let (time, addr, values) = parse_round(&fields, layout, columns.len(), loss_at, opts)
+            .map_err(parse_error)?;
+        f(time, &addr, &values)?;
+    }
+    Ok(())
+}
+
+/**
+ * Imports every round read from the given input into the given target,
+ * merging them in with its data (and skipping any already stored).
+ */
+pub fn import(tm: &TargetManager, input: &mut dyn BufRead,
+              opts: &ImportOptions) -> Result<ImportSummary, ImportError> {
+    let columns = tm.kind.columns(&tm.options_read());
+    let mut summary = ImportSummary { rounds: 0, imported: 0, addrs: Vec::new() };
+    let mut indices: HashMap<String, Vec<i32>> = HashMap::new();
+    let mut elements = Vec::new();
+
+    read_rounds(input, &columns, opts, &mut |time, addr, values| {
+        if !indices.contains_key(addr) {
+            indices.insert(addr.to_owned(), tm.indices_for(addr).map_err(ImportError::Store)?);
+            summary.addrs.push(addr.to_owned());
+        }
+        for (&index, &val) in indices[addr].iter().zip(values.iter()) {
+            if val != SENTINEL_NODATA {
+                elements.push(DataElement { time, index, val });
+            }
+        }
+        summary.rounds += 1;
+        Ok(())
+    })?;
+
+    summary.imported = tm.import_data(&mut elements).map_err(ImportError::Store)?;
+    Ok(summary)
+}
+
+#[test]
+fn rounds_are_read_from_csv_and_ndjson() {
+    let columns: Vec<String> = ["", "dns", "loss", "jitter"].iter().map(|c| c.to_string()).collect();
+    let read = |input: &str, opts: &ImportOptions| {
+        let mut rounds = Vec::new();
+        read_rounds(&mut input.as_bytes(), &columns, opts, &mut |time, addr, values| {
+            rounds.push((time, addr.to_owned(), values.to_vec()));
+            Ok(())
+        }).map(|_| rounds)
+    };
+    let nodata = SENTINEL_NODATA;
+    let timeout = crate::options::SENTINEL_TIMEOUT;
+
+    // stabping's own exports
+    let csv = "timestamp,address,value,loss,error\n\
+               2017-01-01T00:00:00Z,google.com:80,14237,0,\n\
+               2017-01-01T00:00:10Z,\"a,b:80\",,100,timeout\n";
+    assert_eq!(read(csv, &ImportOptions::default()).unwrap(), vec![
+        (1_483_228_800, "google.com:80".to_owned(), vec![14237, nodata, 0, nodata]),
+        (1_483_228_810, "a,b:80".to_owned(), vec![timeout, nodata, 100, nodata]),
+    ]);
+    let ndjson = "{\"time\":1483228800,\"addr\":\"a:80\",\"value\":1234,\"dns\":30,\"loss\":0}\n\
+                  {\"time\":1483228810,\"addr\":\"a:80\",\"value\":null,\"dns\":30,\"loss\":100,\"error\":\"timeout\"}\n";
+    assert_eq!(read(ndjson, &ImportOptions::default()).unwrap(), vec![
+        (1_483_228_800, "a:80".to_owned(), vec![1234, 30, 0, nodata]),
+        (1_483_228_810, "a:80".to_owned(), vec![timeout, 30, 100, nodata]),
+    ]);
+
+    // others', without an address and in other units
+    let opts = ImportOptions { address: Some("b:80".to_owned()), ..Default::default() };
+    let smokeping = "# exported\ntime;median (s);loss\n1483228800;0.0125;0\n1483228860;NaN;20\n";
+    assert_eq!(read(smokeping, &opts).unwrap(), vec![
+        (1_483_228_800, "b:80".to_owned(), vec![12500, nodata, 0, nodata]),
+        (1_483_228_860, "b:80".to_owned(), vec![SENTINEL_ERROR, nodata, 20, nodata]),
+    ]);
+    let pingplotter = "Sample Time\tRTT_ms\n2017-01-01 00:00:00\t12.3\n";
+    assert_eq!(read(pingplotter, &opts).unwrap(),
+               vec![(1_483_228_800, "b:80".to_owned(), vec![12300, nodata, 0, nodata])]);
+
+    match read("timestamp,value\n2017-01-01,12\n", &opts) {
+        Err(ImportError::Parse(2, _)) => {},
+        r => panic!("invalid time accepted: {:?}", r.map(|_| ())),
+    }
+    assert!(read("when,value\n", &opts).is_err());
+}
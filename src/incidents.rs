@@ -0,0 +1,260 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Detection of incidents (spans of time an address was down, i.e. failed
+ * several rounds in a row) from each round of results, and their
+ * persistence.
+ */
+use std::collections::HashMap;
+use std::fs::{OpenOptions, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+
+use crate::alerts::Alerts;
+use crate::helpers::{SPIOError, SPFile, overwrite_json};
+use crate::options::{TargetResults, sentinel_name, was_probed};
+use crate::persist::TargetManager;
+use crate::sink::{ResultsSink, SinkError};
+
+/**
+ * A span of time a single address of a target was down.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Incident {
+    pub target: String,
+    pub addr: String,
+    pub cause: String,  // class of failure of the first failed round
+    pub start: i64,  // time of the first failed round
+    pub end: Option<i64>,  // time of the first round to succeed again, if any yet
+    pub duration: Option<i64>,  // seconds from start to end, once ended
+}
+
+/**
+ * A run of consecutive failed rounds of an address.
+ */
+struct FailedRun {
+    rounds: u32,
+    start: i64,
+    cause: &'static str,
+}
+
+/**
+ * All incidents ever detected, along with the current run of failed rounds of
+ * every (target, addr).
+ */
+#[derive(Default)]
+struct IncidentLog {
+    incidents: Vec<Incident>,
+    runs: HashMap<(String, String), FailedRun>,
+}
+
+impl IncidentLog {
+    fn open_incident(&mut self, target: &str, addr: &str) -> Option<&mut Incident> {
+        self.incidents.iter_mut()
+            .find(|i| i.end.is_none() && i.target == target && i.addr == addr)
+    }
+
+    /**
+     * Ends the open incident of the given address (if any) at the given time,
+     * returning whether there was one.
+     */
+    fn end(&mut self, target: &str, addr: &str, time: i64) -> bool {
+        match self.open_incident(target, addr) {
+            Some(i) => {
+                info!("Incident of {} for {} ended after {}s.", target, addr, time - i.start);
+                i.end = Some(time);
+                i.duration = Some(time - i.start);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /**
+     * Accounts for the given primary value of a round of the given address,
+     * starting an incident once `down_after` rounds in a row have failed and
+     * ending it with the first that succeeds. Returns whether any incident
+     * started or ended.
+     */
+    fn record(&mut self, target: &str, addr: &str, val: i32, time: i64, down_after: u32) -> bool {
+        let key = (target.to_owned(), addr.to_owned());
+
+        if val >= 0 {
+            self.runs.remove(&key);
+            return self.end(target, addr, time);
+        } else if !was_probed(val) {
+            return false;
+        }
+
+        let run = self.runs.entry(key).or_insert(FailedRun {
+            rounds: 0,
+            start: time,
+            cause: sentinel_name(val),
+        });
+        run.rounds += 1;
+        if run.rounds < down_after.max(1) {
+            return false;
+        }
+        let (start, cause) = (run.start, run.cause);
+
+        if self.open_incident(target, addr).is_some() {
+            return false;
+        }
+        info!("Incident of {} for {} started ({}).", target, addr, cause);
+        self.incidents.push(Incident {
+            target: target.to_owned(),
+            addr: addr.to_owned(),
+            cause: cause.to_owned(),
+            start,
+            end: None,
+            duration: None,
+        });
+        true
+    }
+}
+
+/**
+ * Incidents of all targets, persisted to `incidents.json` in the data
+ * directory.
+ */
+pub struct Incidents {
+    managers: Vec<Arc<TargetManager>>,
+    path: PathBuf,
+    log: Mutex<IncidentLog>,
+}
+
+impl Incidents {
+    /**
+     * Creates the incidents of the given targets, reading back any persisted
+     * from the given data directory.
+     */
+    pub fn new(managers: &[Arc<TargetManager>], data_path: &Path) -> Result<Self, SPIOError> {
+        let path = data_path.join("incidents.json");
+        let incidents = if path.exists() {
+            File::open_from(OpenOptions::new().read(true), &path)?.read_json_p(&path)?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Incidents {
+            managers: managers.to_vec(),
+            path,
+            log: Mutex::new(IncidentLog {
+                incidents,
+                runs: HashMap::new(),
+            }),
+        })
+    }
+
+    /**
+     * Accounts for the given live-collected data (`TargetResults`) of a
+     * target, persisting any resulting changes.
+     */
+    pub fn record(&self, data_res: &TargetResults) -> Result<(), SPIOError> {
+        let manager = &self.managers[data_res.kind as usize];
+        let options = manager.options_read();
+        if data_res.nonce != options.nonce {
+            return Ok(());
+        }
+
+        let target = manager.kind.compact_name();
+        let num_columns = manager.kind.columns(&options).len();
+        let mut log = self.log.lock().unwrap();
+        let mut changed = false;
+
+        // end the open incidents of addresses no longer in the options
+        let removed: Vec<String> = log.incidents.iter()
+            .filter(|i| i.end.is_none() && i.target == target && !options.addrs.contains(&i.addr))
+            .map(|i| i.addr.clone())
+            .collect();
+        for addr in removed {
+            changed |= log.end(target, &addr, data_res.timestamp);
+        }
+
+        for (addr, vals) in options.addrs.iter().zip(data_res.vals.chunks(num_columns)) {
+            changed |= log.record(target, addr, vals[0], data_res.timestamp, options.down_after);
+        }
+
+        if changed {
+            overwrite_json(&log.incidents, &self.path)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Returns the addresses (as target and addr) with an incident under way.
+     */
+    pub fn down(&self) -> Vec<(String, String)> {
+        self.log.lock().unwrap().incidents.iter()
+            .filter(|i| i.end.is_none())
+            .map(|i| (i.target.clone(), i.addr.clone()))
+            .collect()
+    }
+
+    /**
+     * Returns all incidents, optionally only those of the given target and/or
+     * only those (not) yet ended.
+     */
+    pub fn list(&self, target: Option<&str>, ended: Option<bool>) -> Vec<Incident> {
+        self.log.lock().unwrap().incidents.iter()
+            .filter(|i| target.is_none_or(|t| i.target == t))
+            .filter(|i| ended.is_none_or(|e| i.end.is_some() == e))
+            .cloned()
+            .collect()
+    }
+}
+
+/**
+ * Sink detecting incidents in all results through the shared `Incidents`,
+ * then evaluating the composite rules of the shared `Alerts` with them.
+ */
+pub struct IncidentsSink {
+    incidents: Arc<Incidents>,
+    alerts: Arc<Alerts>,
+}
+
+impl IncidentsSink {
+    pub fn new(incidents: Arc<Incidents>, alerts: Arc<Alerts>) -> Self {
+        IncidentsSink {
+            incidents,
+            alerts,
+        }
+    }
+}
+
+impl ResultsSink for IncidentsSink {
+    fn name(&self) -> &'static str {
+        "Incidents"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        self.incidents.record(results)
+            .and_then(|_| self.alerts.record_composites(&self.incidents.down(), results.timestamp))
+            .map_err(|e| SinkError::Dropped(e.description()))
+    }
+}
+
+#[test]
+fn incident_starts_after_consecutive_failures_and_ends() {
+    let timeout = -2_100_000_005;
+    let mut log = IncidentLog::default();
+
+    assert!(!log.record("tcpping", "a:80", timeout, 10, 2));
+    assert!(!log.record("tcpping", "a:80", 1000, 20, 2));
+    assert!(!log.record("tcpping", "a:80", timeout, 30, 2));
+    assert!(log.record("tcpping", "a:80", timeout, 40, 2));
+    assert!(!log.record("tcpping", "a:80", timeout, 50, 2));
+    assert!(log.record("tcpping", "a:80", 1000, 60, 2));
+
+    assert_eq!(log.incidents.len(), 1);
+    let i = &log.incidents[0];
+    assert_eq!((i.cause.as_str(), i.start, i.end, i.duration), ("timeout", 30, Some(60), Some(30)));
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Pushing of every round of results to InfluxDB in its line protocol, in
+ * addition to (and never instead of) storing them locally. Rounds that fail to
+ * push are buffered and replayed (see `spool.rs`).
+ */
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::http;
+use crate::http::Url;
+use crate::options::{InfluxConfiguration, TargetResults, sentinel_name, was_probed};
+use crate::persist::TargetManager;
+use crate::sink::{ResultsSink, SinkError};
+use crate::spool::{http_outcome, Spool};
+
+/**
+ * Sink pushing all results to the configured InfluxDB. Failed pushes are
+ * buffered in the given spool.
+ */
+pub struct InfluxSink {
+    measurement: String,
+    url: Url,
+    auth: Option<String>,
+    managers: Vec<Arc<TargetManager>>,
+    spool: Spool,
+}
+
+impl InfluxSink {
+    /**
+     * Creates a sink for the given configuration, or returns `None` if its
+     * url is invalid.
+     */
+    pub fn new(config: InfluxConfiguration, managers: &[Arc<TargetManager>], spool: Spool) -> Option<Self> {
+        let (url_str, auth) = write_endpoint(&config);
+        Some(InfluxSink {
+            measurement: config.measurement,
+            url: Url::parse(&url_str)?,
+            auth,
+            managers: managers.to_vec(),
+            spool,
+        })
+    }
+}
+
+impl ResultsSink for InfluxSink {
+    fn name(&self) -> &'static str {
+        "InfluxDB"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        let body = {
+            let manager = &self.managers[results.kind as usize];
+            let options = manager.options_read();
+            if results.nonce != options.nonce {
+                return Ok(());
+            }
+            let columns = manager.kind.columns(&options);
+            let num_probe_columns = manager.kind.probe_columns().len();
+            lines(&self.measurement, manager.kind.compact_name(), &options.addrs,
+                  &columns[..num_probe_columns + 1], columns.len(), results)
+        };
+
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        let headers: Vec<(&str, &str)> =
+            self.auth.iter().map(|a| ("Authorization", a.as_str())).collect();
+        let url = &self.url;
+        self.spool.send(body.into_bytes(), |b, _| {
+            http_outcome(http::send("POST", url, &headers, b, Duration::from_secs(10)))
+        })
+    }
+}
+
+/**
+ * Builds the URL to write to (with precision in seconds) and the value of
+ * the Authorization header to send (if any) from the given configuration.
+ */
+fn write_endpoint(config: &InfluxConfiguration) -> (String, Option<String>) {
+    let base = config.url.trim_end_matches('/');
+    match config.bucket {
+        Some(ref bucket) => {
+            let url = format!("{}/api/v2/write?org={}&bucket={}&precision=s", base,
+                              http::url_encode(config.org.as_deref().unwrap_or("")),
+                              http::url_encode(bucket));
+            (url, config.token.as_ref().map(|t| format!("Token {}", t)))
+        },
+        None => {
+            let mut url = format!("{}/write?db={}&precision=s", base,
+                                  http::url_encode(config.database.as_deref().unwrap_or("stabping")));
+            if let (Some(u), Some(p)) = (&config.username, &config.password) {
+                url.push_str(&format!("&u={}&p={}", http::url_encode(u), http::url_encode(p)));
+            }
+            (url, None)
+        },
+    }
+}
+
+/**
+ * Formats the given results as one line per address, tagged with the target
+ * and address, with a field for each of the given (probe and loss) columns
+ * (the primary one named `value`), out of the given total number of columns
+ * for each address. Fields of failed values are replaced by a `failure`
+ * field naming the class of failure.
+ */
+fn lines(measurement: &str, target: &str, addrs: &[String], columns: &[String],
+         num_columns: usize, r: &TargetResults) -> String {
+    let mut out = String::new();
+    for (addr, vals) in addrs.iter().zip(r.vals.chunks(num_columns)) {
+        let mut fields = Vec::with_capacity(columns.len());
+        for (column, &val) in columns.iter().zip(vals) {
+            let name = if column.is_empty() { "value" } else { column.as_str() };
+            if val >= 0 {
+                fields.push(format!("{}={}i", escape_key(name), val));
+            } else if column.is_empty() && was_probed(val) {
+                fields.push(format!("failure=\"{}\"", sentinel_name(val)));
+            }
+        }
+        // this address wasn't probed in this round (or its round was skipped)
+        if fields.is_empty() {
+            continue;
+        }
+
+        out.push_str(&format!("{},target={},addr={} {} {}\n",
+                              escape_key(measurement), escape_key(target), escape_key(addr),
+                              fields.join(","), r.timestamp));
+    }
+    out
+}
+
+/**
+ * Escapes the given measurement name, tag key or value, or field key for the
+ * line protocol.
+ */
+fn escape_key(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+#[test]
+fn lines_tag_addrs_and_name_failures() {
+    let r = TargetResults {
+        kind: 0,
+        nonce: 0,
+        timestamp: 1_500_000_000,
+        vals: vec![1200, 30, 0, -2_100_000_005, -2_100_000_005, 100],
+    };
+    let columns = vec!["".to_owned(), "dns".to_owned(), "loss".to_owned()];
+    let addrs = vec!["a:80".to_owned(), "dns b:53".to_owned()];
+
+    assert_eq!(lines("stabping", "tcpping", &addrs, &columns, 3, &r),
+               "stabping,target=tcpping,addr=a:80 value=1200i,dns=30i,loss=0i 1500000000\n\
+                stabping,target=tcpping,addr=dns\\ b:53 failure=\"timeout\",loss=100i 1500000000\n");
+}
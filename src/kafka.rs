@@ -0,0 +1,346 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A minimal Kafka producer (see `publish.rs`), over the plain binary protocol
+ * of Kafka: any of the bootstrap brokers is asked (by a `Metadata` request)
+ * which broker leads the configured partition, and every round is produced
+ * to the leader as a record batch of its own (with `acks = 1`), keyed by its
+ * target and timestamped with its time.
+ *
+ * The versions of the requests used (`Metadata` v4 and `Produce` v3) are the
+ * lowest still supported by Kafka 4, and long supported by earlier brokers.
+ * Failures drop the connection, so that the leader is looked up again.
+ */
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::options::KafkaConfiguration;
+use crate::protobuf::{varint, zigzag};
+use crate::publish::Publisher;
+use crate::spool::PushError;
+
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+
+/**
+ * Publisher to the configured Kafka topic.
+ */
+pub struct KafkaPublisher {
+    config: KafkaConfiguration,
+    leader: Option<TcpStream>,
+    correlation: i32,
+}
+
+impl KafkaPublisher {
+    /**
+     * Creates a publisher for the given configuration, or returns `None` if
+     * it has no brokers or topic.
+     */
+    pub fn new(config: KafkaConfiguration) -> Option<Self> {
+        if config.brokers.is_empty() || config.topic.is_empty() || config.partition < 0 {
+            return None;
+        }
+        Some(KafkaPublisher {
+            config,
+            leader: None,
+            correlation: 0,
+        })
+    }
+
+    /**
+     * Sends the given request (its body, after the header) on the given
+     * connection, and returns the body of its response.
+     */
+    fn request(&mut self, conn: &mut TcpStream, api: i16, version: i16, body: &[u8]) -> io::Result<Vec<u8>> {
+        self.correlation = self.correlation.wrapping_add(1);
+        let mut header = Vec::new();
+        header.extend_from_slice(&api.to_be_bytes());
+        header.extend_from_slice(&version.to_be_bytes());
+        header.extend_from_slice(&self.correlation.to_be_bytes());
+        string(&mut header, "stabping");
+
+        let size = (header.len() + body.len()) as i32;
+        conn.write_all(&size.to_be_bytes())?;
+        conn.write_all(&header)?;
+        conn.write_all(body)?;
+        conn.flush()?;
+
+        let mut size = [0; 4];
+        conn.read_exact(&mut size)?;
+        let size = i32::from_be_bytes(size);
+        if !(4..=1 << 26).contains(&size) {
+            return Err(invalid("response of invalid size"));
+        }
+        let mut response = vec![0; size as usize];
+        conn.read_exact(&mut response)?;
+        if response[..4] != self.correlation.to_be_bytes() {
+            return Err(invalid("response to another request"));
+        }
+        response.drain(..4);
+        Ok(response)
+    }
+
+    /**
+     * Asks the bootstrap brokers (in turn) for the leader of the partition,
+     * and connects to it.
+     */
+    fn connect_leader(&mut self) -> Result<TcpStream, String> {
+        let mut failure = String::new();
+        for broker in self.config.brokers.clone() {
+            let leader = connect(&broker).and_then(|mut conn| {
+                let mut body = Vec::new();
+                body.extend_from_slice(&1i32.to_be_bytes());
+                string(&mut body, &self.config.topic);
+                body.push(0);  // not creating the topic
+                let response = self.request(&mut conn, API_METADATA, 4, &body)?;
+                leader_of(&response, &self.config.topic, self.config.partition)
+                    .map_err(|e| io::Error::other(format!("{} (asking {})", e, broker)))
+            });
+            match leader.and_then(|(host, port)| connect(&format!("{}:{}", host, port))) {
+                Ok(conn) => return Ok(conn),
+                Err(e) => failure = format!("{}", e),
+            }
+        }
+        Err(failure)
+    }
+}
+
+fn invalid(e: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_owned())
+}
+
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let timeout = Duration::from_secs(10);
+    let sock_addr = addr.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))?;
+    let conn = TcpStream::connect_timeout(&sock_addr, timeout)?;
+    conn.set_read_timeout(Some(timeout))?;
+    conn.set_write_timeout(Some(timeout))?;
+    Ok(conn)
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as i16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/**
+ * Reader of the fields of a response.
+ */
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("truncated response"));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /**
+     * Reads a (nullable) string, `None` if null.
+     */
+    fn string(&mut self) -> io::Result<Option<&'a str>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        std::str::from_utf8(self.take(len as usize)?).map(Some).map_err(|_| invalid("invalid string"))
+    }
+
+    fn count(&mut self) -> io::Result<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+}
+
+/**
+ * Finds the leader (its host and port) of the given partition of the given
+ * topic in the given `Metadata` v4 response.
+ */
+fn leader_of(response: &[u8], topic: &str, partition: i32) -> io::Result<(String, i32)> {
+    let mut f = Fields(response);
+    f.i32()?;  // throttle time
+    let mut brokers = Vec::new();
+    for _ in 0..f.count()? {
+        let node = f.i32()?;
+        let host = f.string()?.unwrap_or_default().to_owned();
+        let port = f.i32()?;
+        f.string()?;  // rack
+        brokers.push((node, host, port));
+    }
+    f.string()?;  // cluster id
+    f.i32()?;  // controller id
+    for _ in 0..f.count()? {
+        let error = f.i16()?;
+        let name = f.string()?;
+        f.take(1)?;  // internal
+        let ours = name == Some(topic);
+        if ours && error != 0 {
+            return Err(io::Error::other(format!("topic '{}' unavailable (error {})", topic, error)));
+        }
+        for _ in 0..f.count()? {
+            let error = f.i16()?;
+            let index = f.i32()?;
+            let leader = f.i32()?;
+            for _ in 0..2 {  // replicas and in-sync replicas
+                let n = f.count()?;
+                f.take(n * 4)?;
+            }
+            if ours && index == partition {
+                if error != 0 {
+                    return Err(io::Error::other(format!("partition {} unavailable (error {})", partition, error)));
+                }
+                return brokers.into_iter().find(|b| b.0 == leader).map(|(_, host, port)| (host, port))
+                    .ok_or_else(|| io::Error::other(format!("leader of partition {} unknown", partition)));
+            }
+        }
+    }
+    Err(io::Error::other(format!("no partition {} of topic '{}'", partition, topic)))
+}
+
+/**
+ * Computes the CRC-32C (Castagnoli) checksum of the given bytes, as record
+ * batches carry.
+ */
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/**
+ * Encodes a record batch (of the v2 format) of a single record of the given
+ * key and value, timestamped with the given time (in milliseconds).
+ */
+fn record_batch(key: &[u8], value: &[u8], timestamp_ms: i64) -> Vec<u8> {
+    let mut record = vec![0, 0, 0];  // attributes, and the timestamp and offset deltas
+    varint(&mut record, zigzag(key.len() as i64));
+    record.extend_from_slice(key);
+    varint(&mut record, zigzag(value.len() as i64));
+    record.extend_from_slice(value);
+    record.push(0);  // no headers
+
+    // everything covered by the checksum, from the attributes on
+    let mut checked = Vec::new();
+    checked.extend_from_slice(&0i16.to_be_bytes());  // attributes: no compression
+    checked.extend_from_slice(&0i32.to_be_bytes());  // last offset delta
+    checked.extend_from_slice(&timestamp_ms.to_be_bytes());
+    checked.extend_from_slice(&timestamp_ms.to_be_bytes());
+    checked.extend_from_slice(&(-1i64).to_be_bytes());  // producer id
+    checked.extend_from_slice(&(-1i16).to_be_bytes());  // producer epoch
+    checked.extend_from_slice(&(-1i32).to_be_bytes());  // base sequence
+    checked.extend_from_slice(&1i32.to_be_bytes());  // records
+    varint(&mut checked, zigzag(record.len() as i64));
+    checked.extend_from_slice(&record);
+
+    let mut batch = Vec::new();
+    batch.extend_from_slice(&0i64.to_be_bytes());  // base offset
+    batch.extend_from_slice(&((checked.len() + 9) as i32).to_be_bytes());
+    batch.extend_from_slice(&(-1i32).to_be_bytes());  // partition leader epoch
+    batch.push(2);  // magic
+    batch.extend_from_slice(&crc32c(&checked).to_be_bytes());
+    batch.extend_from_slice(&checked);
+    batch
+}
+
+/**
+ * Makes out whether producing worked from the error code of the partition in
+ * the given `Produce` v3 response: errors saying that the batch is bad (e.g.
+ * too large) won't go away if retried, others might.
+ */
+fn produce_outcome(response: &[u8]) -> Result<(), PushError> {
+    let mut f = Fields(response);
+    let error = (|| {
+        f.count()?;  // one topic
+        f.string()?;
+        f.count()?;  // one partition
+        f.i32()?;
+        f.i16()
+    })().map_err(|e| PushError::Retry(format!("{}", e)))?;
+    match error {
+        0 => Ok(()),
+        2 | 10 | 87 => Err(PushError::Reject(format!("rejected with error {}", error))),
+        _ => Err(PushError::Retry(format!("failed with error {}", error))),
+    }
+}
+
+impl Publisher for KafkaPublisher {
+    fn name(&self) -> &'static str {
+        "Kafka"
+    }
+
+    fn publish(&mut self, target: &str, timestamp: i64, payload: &[u8]) -> Result<(), PushError> {
+        let mut conn = match self.leader.take() {
+            Some(c) => c,
+            None => self.connect_leader().map_err(|e| PushError::Retry(format!("failed to connect: {}", e)))?,
+        };
+
+        let batch = record_batch(target.as_bytes(), payload, timestamp * 1000);
+        let mut body = Vec::new();
+        body.extend_from_slice(&(-1i16).to_be_bytes());  // no transaction
+        body.extend_from_slice(&1i16.to_be_bytes());  // acks from the leader
+        body.extend_from_slice(&10_000i32.to_be_bytes());
+        body.extend_from_slice(&1i32.to_be_bytes());
+        string(&mut body, &self.config.topic);
+        body.extend_from_slice(&1i32.to_be_bytes());
+        body.extend_from_slice(&self.config.partition.to_be_bytes());
+        body.extend_from_slice(&(batch.len() as i32).to_be_bytes());
+        body.extend_from_slice(&batch);
+
+        let outcome = self.request(&mut conn, API_PRODUCE, 3, &body)
+            .map_err(|e| PushError::Retry(format!("{}", e)))
+            .and_then(|r| produce_outcome(&r));
+        if !matches!(outcome, Err(PushError::Retry(_))) {
+            self.leader = Some(conn);
+        }
+        outcome
+    }
+}
+
+#[test]
+fn rounds_are_produced_as_checksummed_record_batches() {
+    assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+    let batch = record_batch(b"tcpping", b"{}", 1_500_000_000_000);
+    assert_eq!(&batch[..8], &[0; 8]);
+    assert_eq!(i32::from_be_bytes(batch[8..12].try_into().unwrap()) as usize, batch.len() - 12);
+    assert_eq!(batch[16], 2);
+    assert_eq!(u32::from_be_bytes(batch[17..21].try_into().unwrap()), crc32c(&batch[21..]));
+    // a single record: its length, attributes and deltas, then the key and value
+    let records = &batch[21 + 36..];
+    assert_eq!(records[..4], [0, 0, 0, 1]);
+    assert_eq!(records[4..], [
+        30, 0, 0, 0, 14, b't', b'c', b'p', b'p', b'i', b'n', b'g', 4, b'{', b'}', 0,
+    ]);
+
+    let mut response = vec![0, 0, 0, 1];
+    string(&mut response, "stabping");
+    response.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+    let with_error = |e: i16| [&response[..], &e.to_be_bytes()].concat();
+    assert!(produce_outcome(&with_error(0)).is_ok());
+    assert!(matches!(produce_outcome(&with_error(10)), Err(PushError::Reject(_))));
+    assert!(matches!(produce_outcome(&with_error(6)), Err(PushError::Retry(_))));
+}
@@ -0,0 +1,131 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Logging through `tracing`, printed to stderr (keeping stdout for output such
+ * as reports) by `tracing-subscriber`, filtered by level per module, as plain
+ * text or as JSON lines. Records of the `log` crate, which some of the crates
+ * used log with, are printed alike.
+ *
+ * Besides its message, each event carries the fields given with it (e.g.
+ * `warn!(addr, "...")`) and those of the spans it's in (e.g. the `round` of a
+ * target and address), so that the messages of the many threads running
+ * rounds at once can be told apart, along with the name of its thread.
+ */
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use tracing_subscriber::EnvFilter;
+
+/**
+ * How messages are printed: as text, or as JSON lines.
+ */
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/**
+ * Which messages are printed: those up to the level of the most specific
+ * directive matching their module, else up to the default level. Parsed from
+ * e.g. `info,worker=debug,hyper=warn` like `EnvFilter` directives, except
+ * that modules of stabping itself may leave out the leading `stabping::`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    directives: String,  // as `EnvFilter` takes them, with modules of stabping in full
+}
+
+impl LogFilter {
+    pub fn env_filter(&self) -> EnvFilter {
+        EnvFilter::new(&self.directives)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut directives = Vec::new();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                // a module named by itself may be one of stabping's as well as a crate
+                Some((module, level)) if !module.contains("::") && module.trim() != "stabping" => {
+                    directives.push(directive.to_owned());
+                    directives.push(format!("stabping::{}={}", module.trim(), level.trim()));
+                },
+                _ => directives.push(directive.to_owned()),
+            }
+        }
+        let directives = directives.join(",");
+        EnvFilter::builder().parse(&directives).map_err(|e| format!("invalid filter '{}': {}", s, e))?;
+        Ok(LogFilter { directives })
+    }
+}
+
+/**
+ * Installs the subscriber printing messages allowed by the given filter in
+ * the given format.
+ */
+pub fn init(filter: LogFilter, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter.env_filter())
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_thread_names(true);
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+}
+
+#[test]
+fn events_are_filtered_by_module_and_carry_their_fields() {
+    use std::sync::{Arc, Mutex};
+    use serde_json::Value;
+
+    let filter: LogFilter = "warn, worker=debug, stabping::pool=off, hyper=error".parse().unwrap();
+    assert_eq!(filter.directives,
+               "warn,worker=debug,stabping::worker=debug,stabping::pool=off,hyper=error,stabping::hyper=error");
+    assert!("info,worker=loud".parse::<LogFilter>().is_err());
+
+    // a subscriber printing JSON lines into a buffer
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter.env_filter())
+        .with_writer(move || writer.clone())
+        .json()
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = info_span!(target: "stabping::worker", "round", kind = "tcpping", addr = "a b").entered();
+        debug!(target: "stabping::worker", skipped = 2, "Round overran.");
+        debug!(target: "stabping::sink", "Not printed.");
+    });
+
+    let printed = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<Value> = printed.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["level"], "DEBUG");
+    assert_eq!(lines[0]["target"], "stabping::worker");
+    assert_eq!(lines[0]["fields"], serde_json::json!({"message": "Round overran.", "skipped": 2}));
+    assert_eq!(lines[0]["span"], serde_json::json!({"name": "round", "kind": "tcpping", "addr": "a b"}));
+}
@@ -13,170 +13,831 @@ extern crate serde_json;
 extern crate memmap;
 extern crate ws;
 extern crate iron;
+extern crate hyper;
 extern crate router;
 extern crate mount;
+extern crate socket2;
+extern crate rustls;
+extern crate webpki_roots;
+extern crate ring;
+extern crate toml;
+extern crate clap;
+extern crate parquet;
+extern crate crc32fast;
+#[macro_use]
+extern crate tracing;
+#[cfg(unix)]
+extern crate signal_hook;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 
+mod cli;
+mod logging;
 mod helpers;
 mod options;
+mod tags;
 mod persist;
+mod storage;
+mod compress;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 mod reader;
+mod auth;
+mod https;
+#[cfg(unix)]
+mod unixsocket;
 mod webserver;
 mod wsserver;
+mod worker;
+mod probe;
+mod pool;
+mod shutdown;
+mod resolve;
+mod source;
+mod proxy;
+mod sockopt;
+mod fanout;
 mod tcpping;
+mod syn;
+mod icmp;
+mod http;
+mod httpping;
+mod dns;
+mod udpping;
+mod tls;
+mod traceroute;
+mod exec;
+mod ntp;
+mod throughput;
+mod speedtest;
+mod quic;
+mod grpc;
+mod hops;
+mod mtr;
+mod metrics;
+mod health;
+mod telemetry;
+mod histogram;
+mod grafana;
+mod status;
+mod badge;
+mod influx;
+mod graphite;
+mod statsd;
+mod mqtt;
+mod agent;
+mod sink;
+mod spool;
+mod protobuf;
+mod otlp;
+#[cfg(any(feature = "nats", feature = "kafka"))]
+mod publish;
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod alerts;
+mod silences;
+mod history;
+mod anomaly;
+mod notify;
+mod digest;
+mod smtp;
+mod incidents;
+mod report;
+mod export;
+mod import;
+mod hosts;
+mod reload;
+mod retention;
+mod rollup;
+mod schema;
+mod backup;
+mod fsck;
 
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::fs::{OpenOptions, File};
+use std::io;
+use std::io::{BufReader, Read, Write};
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
-use crate::wsserver::Broadcaster;
+use clap::Parser;
 
-use crate::helpers::{SPIOError, SPFile, VecIntoRawBytes};
-use crate::options::{TargetKind, MainConfiguration};
-use crate::persist::ManagerError;
+use crate::cli::{Cli, Command};
+use crate::wsserver::{Broadcaster, BroadcastSink};
+use crate::auth::Auth;
+use crate::https::HttpsServer;
 
-static CONFIG_FILENAME: &'static str = "stabping_config.json";
+use crate::helpers::{SPIOError, SPFile};
+use crate::tags::TagFilter;
+use crate::options::{TargetKind, MainConfiguration, HttpsConfiguration, NatsConfiguration, KafkaConfiguration, ENV_PREFIX};
+use crate::persist::{ManagerError, PersistSink, TargetManager};
+use crate::metrics::{Metrics, MetricsSink};
+use crate::health::Health;
+use crate::influx::InfluxSink;
+use crate::graphite::GraphiteSink;
+use crate::statsd::StatsdSink;
+use crate::mqtt::MqttSink;
+use crate::agent::{AgentAuth, AgentSink, Aggregator};
+use crate::sink::ResultsBus;
+use crate::spool::Spool;
+use crate::pool::ThreadPool;
+use crate::alerts::{Alerts, AlertsSink};
+use crate::history::AlertHistory;
+use crate::incidents::{Incidents, IncidentsSink};
+use crate::notify::{Notifications, Webhook, ChatNotifier, EmailNotifier};
+use crate::export::ExportFormat;
+use crate::import::{ImportOptions, ImportError};
+
+static CONFIG_FILENAME: &str = "stabping_config.json";
+static TOML_CONFIG_FILENAME: &str = "stabping.toml";
 
 /**
- * Attempts to discover the configuration file and associated data directory.
- *
- * Returns a tuple of an `Arc` to the `MainConfiguration` and the path to the
- * data directory if found.
+ * Reads back the configuration file at the given path (in TOML if it is named
+ * like one, and JSON otherwise).
+ */
+fn read_configuration(p: &Path) -> Result<MainConfiguration, SPIOError> {
+    let mut file = File::open_from(OpenOptions::new().read(true), p)?;
+    if p.extension().is_some_and(|e| e == "toml") {
+        file.read_toml_p(p)
+    } else {
+        file.read_json_p(p)
+    }
+}
+
+/**
+ * Describes how the configuration file should be formatted.
+ */
+fn configuration_help() -> String {
+    let default = MainConfiguration::default();
+    format!("(in '{}'):\n{}\nor (in '{}'):\n{}\n",
+            TOML_CONFIG_FILENAME, toml::to_string_pretty(&default).unwrap(),
+            CONFIG_FILENAME, serde_json::to_string_pretty(&default).unwrap())
+}
+
+/**
+ * Reads back the configuration from the given file, or else the first one
+ * found in the usual places, returning it along with the path to that file (if
+ * there is one).
  */
-fn get_configuration() -> Option<(Arc<RwLock<MainConfiguration>>, PathBuf)> {
+fn get_configuration(explicit: Option<&Path>) -> Option<(MainConfiguration, Option<PathBuf>)> {
+    if let Some(p) = explicit {
+        return match read_configuration(p) {
+            Ok(mc) => {
+                info!("Using configuration file {}", p.display());
+                Some((mc, Some(p.to_path_buf())))
+            },
+            Err(err) => {
+                error!("{} configuration file. Please ensure that it exists and is formatted like {}",
+                       err.description(), configuration_help());
+                None
+            },
+        };
+    }
+
     /*
      * the list of (description, path) tuples of directories to try/places we
      * want to check for the existence of the configuration file
      */
     let dirs_to_try = &[
-        ("current working directory",
-         env::current_dir().ok().map(|mut cur| { cur.push(CONFIG_FILENAME); cur })),
+        ("current working directory", env::current_dir().ok()),
         ("user configuration directory",
-         env::home_dir().map(|mut home| { home.push(".config"); home.push(CONFIG_FILENAME); home })),
-        ("global configuration directory",
-         Some({ let mut p = PathBuf::from("/etc"); p.push(CONFIG_FILENAME); p })),
+         env::home_dir().map(|mut home| { home.push(".config"); home })),
+        ("global configuration directory", Some(PathBuf::from("/etc"))),
         ("directory where stabping is located",
-         env::current_exe().ok().map(|mut exe| { exe.pop(); exe.push(CONFIG_FILENAME); exe })),
+         env::current_exe().ok().map(|mut exe| { exe.pop(); exe })),
     ];
 
-    println!("Searching for configuration file '{}'.", CONFIG_FILENAME);
+    info!("Searching for configuration file '{}' or '{}'.",
+          TOML_CONFIG_FILENAME, CONFIG_FILENAME);
 
     // loop through all the directories we want to try
-    for &(desc, ref maybe_p) in dirs_to_try {
-        if let &Some(ref p) = maybe_p {
-            /*
-             * if we could obtain a path to this location, try and open the
-             * configuration file that might be there
-             */
-            println!("- checking {}:\n    {}", desc, p.to_str().unwrap());
-            if let Ok(mut file) = File::open_from(OpenOptions::new().read(true), &p) {
-                match file.read_json_p(&p) {
-                    Err(err @ SPIOError::Parse(_)) => {
-                        /*
-                         * if we found the file, could open it, but it was not
-                         * filled with JSON, then tell the user
-                         */
-                        println!(
-                            "\n{} configuration file. Invalid or missing JSON fields. Please ensure that this file is formatted like:\n{}\n",
-                            err.description(),
-                            serde_json::to_string_pretty(&MainConfiguration::default()).unwrap()
-                        );
-                        return None
-                    },
-                    Ok(mc) => {
-                        /*
-                         * we found a valid configuration file
-                         */
-                        println!("\nUsing configuration file in {}:\n  {}",
-                                 desc, p.to_str().unwrap());
-                        let mut data_path = p.clone();
-                        data_path.pop();
-                        data_path.push("stabping_data");
-                        if fs::create_dir_all(&data_path).is_err() {
-                            println!("Failed to create data directory '{}'. Please ensure this directory is writable by stabping.", data_path.to_str().unwrap());
-                            return None;
-                        }
-                        return Some((Arc::new(RwLock::new(mc)), data_path));
-                    },
-                    _ => {
-                        /*
-                         * we ran into some other issue with what looked like
-                         * the configuration file, continue to try other
-                         * locations
-                         */
-                    }
-                };
-            }
-        } else {
-            /*
-             * we couldn't obtain the path to this location, continue to try
-             * other locations
-             */
-            println!("- could not obtain {}", desc);
+    for &(desc, ref maybe_dir) in dirs_to_try {
+        let dir = match maybe_dir {
+            Some(dir) => dir,
+            None => {
+                /*
+                 * we couldn't obtain the path to this location, continue to
+                 * try other locations
+                 */
+                info!("- could not obtain {}", desc);
+                continue;
+            },
+        };
+
+        // in each, prefer a TOML configuration file over a JSON one
+        for name in [TOML_CONFIG_FILENAME, CONFIG_FILENAME] {
+            let p = dir.join(name);
+            info!("- checking {}:\n    {}", desc, p.display());
+            match read_configuration(&p) {
+                Err(err @ SPIOError::Parse(_)) => {
+                    /*
+                     * if we found the file, could open it, but it was not
+                     * filled with valid configuration, then tell the user
+                     */
+                    error!(
+                        "{} configuration file. Invalid or missing fields. Please ensure that this file is formatted like {}",
+                        err.description(), configuration_help()
+                    );
+                    return None
+                },
+                Ok(mc) => {
+                    // we found a valid configuration file
+                    info!("Using configuration file in {}:\n  {}", desc, p.display());
+                    return Some((mc, Some(p)));
+                },
+                _ => {
+                    /*
+                     * we ran into some other issue with what looked like the
+                     * configuration file, continue to try other locations
+                     */
+                }
+            };
         }
     }
 
     /*
-     * we looked everywhere and couldn't find the configuration file, tell the
-     * user
+     * we looked everywhere and couldn't find the configuration file, but the
+     * environment may configure everything needed (e.g. inside a container)
      */
-    println!(
-        "\nFailed to find configuration file. Please ensure that 'stabping_config.json' is accessible in one of the above checked locations, and is formatted like:\n{}\n",
-        serde_json::to_string_pretty(&MainConfiguration::default()).unwrap()
+    if env::vars().any(|(name, _)| name.starts_with(ENV_PREFIX)) {
+        info!("No configuration file found, using the default configuration with {}* overrides.",
+              ENV_PREFIX);
+        return Some((MainConfiguration::default(), None));
+    }
+
+    // otherwise, tell the user
+    error!(
+        "Failed to find configuration file. Please ensure that '{}' or '{}' is accessible in one of the above checked locations (or given with --config), and is formatted like {}",
+        TOML_CONFIG_FILENAME, CONFIG_FILENAME, configuration_help()
     );
     None
 }
 
+/**
+ * Reconciles the persisted options of all targets with those declared in the
+ * configuration, which take precedence.
+ */
+fn reconcile_targets(targets: &[Arc<TargetManager>], configuration: &MainConfiguration) {
+    for (name, declared) in configuration.targets.iter() {
+        let tm = match targets.iter().find(|tm| tm.kind.compact_name() == name) {
+            Some(tm) => tm,
+            None => {
+                warn!("Ignoring configuration of unknown target '{}'.", name);
+                continue;
+            },
+        };
+
+        let new_options = declared.apply_to(&tm.options_read());
+        if let Some(options) = new_options {
+            if let Err(e) = tm.options_update(options) {
+                handle_fatal_error(e);
+            }
+        }
+    }
+}
+
 fn main() {
-    // try and obtain our configuration and data directory path
-    let (configuration, data_path) = match get_configuration() {
+    let cli = Cli::parse();
+    logging::init(cli.log_level.clone(), cli.log_format);
+
+    // try and obtain our configuration
+    let (mut mc, config_path) = match get_configuration(cli.config.as_deref()) {
         Some(c) => c,
         None => {
             panic!("Failed to get configuration");
         }
     };
+    let config_dir = match config_path {
+        Some(ref p) => p.parent().map_or_else(PathBuf::new, Path::to_path_buf),
+        None => env::current_dir().unwrap_or_default(),
+    };
+    // the environment overrides the configuration file, and flags override both
+    if let Err(e) = mc.apply_env(env::vars()) {
+        panic!("{}", e);
+    }
+    if let Some(ref address) = cli.listen {
+        mc.listen_address = address.clone();
+    }
+    // and who may use the web and websockets servers
+    let auth = match Auth::new(mc.auth.as_ref()) {
+        Ok(a) => Arc::new(a),
+        Err(e) => panic!("Invalid auth configuration: {}", e),
+    };
+
+    /*
+     * and our data directory: the one given on the command line, else the
+     * configured one (relative to the configuration file), else the
+     * stabping_data directory next to the configuration file
+     */
+    let data_path = match (&cli.data_dir, &mc.data_dir) {
+        (Some(d), _) => d.clone(),
+        (None, Some(d)) => config_dir.join(d),
+        (None, None) => config_dir.join("stabping_data"),
+    };
+    if fs::create_dir_all(&data_path).is_err() {
+        error!("Failed to create data directory '{}'. Please ensure this directory is writable by stabping.",
+               data_path.display());
+        std::process::exit(1);
+    }
+    // the socket to (also) serve the web server on is likewise relative to the configuration file
+    mc.unix_socket = mc.unix_socket.take().map(|p| config_dir.join(p));
+
+    // `stabping backup ...` (or `restore ...`, `fsck`) works on the data directory as it is
+    match cli.command {
+        Some(Command::Backup { ref path }) => {
+            match backup::backup(&data_path, path) {
+                Ok(m) => println!("Backed up {} files of '{}' into '{}'.", m.files.len(),
+                                  data_path.display(), path.display()),
+                Err(e) => println!("Failed to back up: {}", e),
+            }
+            return;
+        },
+        Some(Command::Restore { ref path }) => {
+            match backup::restore(path, &data_path) {
+                Ok(m) => println!("Restored {} files (backed up by stabping {}) into '{}'.", m.files.len(),
+                                  m.version, data_path.display()),
+                Err(e) => println!("Failed to restore: {}", e),
+            }
+            return;
+        },
+        Some(Command::Fsck { repair }) => {
+            check_data_dir(&data_path, repair);
+            return;
+        },
+        _ => {},
+    }
+
+    let storage = mc.storage;
+    let configuration = Arc::new(RwLock::new(mc));
 
     // create managers for all the targets
-    let targets = match TargetKind::new_managers_for_all(&data_path) {
+    let targets = match TargetKind::new_managers_for_all(&data_path, storage) {
         Ok(targets) => targets,
         Err(e) => handle_fatal_error(e),
     };
+    reconcile_targets(&targets, &configuration.read().unwrap());
+
+    // `stabping report ...` (or `export ...`, `import ...`) just prints a report instead of running
+    match cli.command {
+        Some(Command::Report { kind, from, to }) => {
+            print_report(&targets, &kind, from.as_deref(), to.as_deref());
+            return;
+        },
+        Some(Command::Export { kind, from, to, output, format }) => {
+            export_data(&targets, &kind, from.as_deref(), to.as_deref(), output.as_deref(), format);
+            return;
+        },
+        Some(Command::StatusPage { output, json }) => {
+            write_status_page(&targets, &configuration.read().unwrap(), output.as_deref(), json);
+            return;
+        },
+        Some(Command::Import { kind, input, address, column, unit, local_time }) => {
+            import_data(&targets, &kind, input.as_deref(),
+                        &ImportOptions { address, column, unit, local_time });
+            return;
+        },
+        Some(Command::ImportHosts { kind, input, replace }) => {
+            import_hosts(&targets, &kind, input.as_deref(), replace);
+            return;
+        },
+        Some(Command::Backup { .. }) | Some(Command::Restore { .. }) | Some(Command::Fsck { .. }) | None => {},
+    }
 
     // create a broadcaster to be initialized with the websockets server
     let broadcaster = Arc::new(Broadcaster::new());
 
-    // start the web and websockets servers
-    webserver::web_server(configuration.clone(), targets.iter());
-    wsserver::ws_server(configuration.clone(), broadcaster.clone());
+    // keep track of the latest results of all targets for /metrics
+    let metrics = Arc::new(Metrics::new(&targets));
+
+    // and evaluate their alert rules, notifying any configured notifiers, with a history of both
+    let history = match AlertHistory::open(&data_path) {
+        Ok(h) => Arc::new(h),
+        Err(e) => panic!("Failed to open alert history: {}", e),
+    };
+    let mut notifications = Notifications::new(history.clone());
+    {
+        let config = configuration.read().unwrap();
+        for c in config.webhooks.iter() {
+            match Webhook::new(c.clone()) {
+                Some(w) => notifications.add(w),
+                None => warn!("Invalid webhook url '{}', not notifying it.", c.url),
+            }
+        }
+        for c in config.slack.iter() {
+            match ChatNotifier::slack(c.clone()) {
+                Some(n) => notifications.add(n),
+                None => warn!("Invalid Slack url '{}', not notifying it.", c.url),
+            }
+        }
+        for c in config.discord.iter() {
+            match ChatNotifier::discord(c.clone()) {
+                Some(n) => notifications.add(n),
+                None => warn!("Invalid Discord url '{}', not notifying it.", c.url),
+            }
+        }
+        for c in config.telegram.iter() {
+            match ChatNotifier::telegram(c.clone()) {
+                Some(n) => notifications.add(n),
+                None => warn!("Invalid Telegram token, not notifying chat {}.", c.chat_id),
+            }
+        }
+        if let Some(ref c) = config.smtp {
+            notifications.add(EmailNotifier::new(c.clone()));
+        }
+    }
+    let composites = configuration.read().unwrap().composite_alerts.clone();
+    let alerts = match Alerts::new(&targets, &composites, &data_path, notifications.clone(), history) {
+        Ok(a) => Arc::new(a),
+        Err(e) => panic!("Failed to read back alerts: {}", e),
+    };
+
+    // and keep track of when their addresses are down
+    let incidents = match Incidents::new(&targets, &data_path) {
+        Ok(i) => Arc::new(i),
+        Err(e) => panic!("Failed to read back incidents: {}", e),
+    };
 
     /*
-     * start the workers for all the targets, passing them one end of an MPSC
-     * communications channel so that we can receive all the data
+     * subscribe everything that should receive the collected results to the
+     * results bus: the data files, websocket clients, metrics, and any
+     * configured exporters
+     */
+    let mut bus = ResultsBus::new();
+    bus.subscribe(PersistSink::new(&targets));
+    bus.subscribe(BroadcastSink::new(broadcaster.clone(), &targets));
+    bus.subscribe(MetricsSink::new(metrics.clone()));
+    bus.subscribe(AlertsSink::new(alerts.clone(), incidents.clone()));
+    bus.subscribe(IncidentsSink::new(incidents.clone(), alerts.clone()));
+    {
+        let config = configuration.read().unwrap();
+        // what each sink pushing results elsewhere fails to, it buffers in the data directory
+        let spool = |name: &str| Spool::open(data_path.join(format!("{}.spool", name)), config.spool_limit << 20);
+        if let Some(ref c) = config.influxdb {
+            match InfluxSink::new(c.clone(), &targets, spool("influxdb")) {
+                Some(s) => bus.subscribe(s),
+                None => warn!("Invalid InfluxDB url '{}', not pushing results.", c.url),
+            }
+        }
+        if let Some(ref c) = config.graphite {
+            bus.subscribe(GraphiteSink::new(c.clone(), &targets, spool("graphite")));
+        }
+        if let Some(ref c) = config.statsd {
+            bus.subscribe(StatsdSink::new(c.clone(), &targets));
+        }
+        if let Some(ref c) = config.agent {
+            match AgentSink::new(c.clone(), &config_dir, &targets, spool("agent")) {
+                Ok(s) => bus.subscribe(s),
+                Err(e) => warn!("Invalid agent configuration ({}), not pushing results.", e),
+            }
+        }
+        if let Some(ref c) = config.mqtt {
+            match MqttSink::new(c.clone(), &targets) {
+                Some(s) => bus.subscribe(s),
+                None => warn!("Invalid MQTT broker '{}' or topic, not publishing results.", c.broker),
+            }
+        }
+        if let Some(ref c) = config.nats {
+            subscribe_nats(&mut bus, c, &targets, spool("nats"));
+        }
+        if let Some(ref c) = config.kafka {
+            subscribe_kafka(&mut bus, c, &targets, spool("kafka"));
+        }
+    }
+
+    /*
+     * the channel the workers (and the rounds pushed by agents) send their
+     * results to the main thread through
      */
     let (sender, results) = channel();
-    for tm in targets.iter() {
-        tm.kind.run_worker(tm.clone(), sender.clone());
+    let agents = match AgentAuth::new(configuration.read().unwrap().agents.as_ref()) {
+        Ok(a) => a,
+        Err(e) => panic!("Invalid agents configuration: {}", e),
+    };
+    let aggregator = Arc::new(Aggregator::new(&targets, sender.clone(), agents));
+
+    // keep track of the health of the workers of all targets for /healthz
+    let health = Arc::new(Health::new(&targets));
+
+    // start the web (over HTTPS, if configured) and websockets (unless on port 0) servers, unless asked not to
+    if !cli.no_web {
+        let https = configuration.read().unwrap().https.clone().map(|c| {
+            match https_server(&c, &config_dir, &data_path) {
+                Ok(s) => s,
+                Err(e) => panic!("Unable to serve HTTPS: {}", e),
+            }
+        });
+        webserver::web_server(configuration.clone(), targets.iter(), metrics.clone(), alerts.clone(),
+                              incidents.clone(), broadcaster.clone(), health.clone(), aggregator.clone(),
+                              auth.clone(), https);
+        if configuration.read().unwrap().ws_port != 0 {
+            wsserver::ws_server(configuration.clone(), broadcaster.clone(), auth.clone());
+        }
+    }
+
+    // export metrics to an OpenTelemetry collector every so often, if configured
+    if let Some(c) = configuration.read().unwrap().otlp.clone() {
+        otlp::run_exporter(c, metrics.clone());
+    }
+
+    // log telemetry of stabping itself every so often
+    telemetry::run_logger();
+
+    // prune (and compress) data older than the targets' retention (and compress_after), every so often
+    retention::run_pruner(targets.clone());
+
+    // and roll up the data into coarser tiers, as each becomes complete
+    rollup::run_rollups(targets.clone());
+
+    // fan out the host names of targets with `fan_out` to each of their IPs, every so often
+    fanout::run_fan_out(targets.clone());
+
+    // deliver digests of every target on schedule
+    digest::run_digests(configuration.read().unwrap().digests.clone(), targets.clone(), incidents.clone(),
+                        notifications);
+
+    // pick up changes to the declared targets while running
+    if let Some(p) = config_path {
+        reload::watch_configuration(p, configuration.clone(), targets.clone());
     }
 
     /*
-     * receive the live data coming from the workers and process it
+     * start the workers for all the targets, passing them one end of an MPSC
+     * communications channel so that we can receive all the data, and the
+     * pool of threads to run their rounds on
      */
-    for r in results {
-        // detect which target kind these data are for
-        let kind_id = r.0[0];
+    exec::allow(configuration.read().unwrap().allow_exec);
+    source::set_default(configuration.read().unwrap().source.clone());
+    let pool = Arc::new(ThreadPool::new(configuration.read().unwrap().probe_threads));
+    telemetry::watch_pool(pool.stats());
+    let workers = targets.iter()
+        .map(|tm| tm.kind.run_worker(tm.clone(), sender.clone(), pool.clone()))
+        .collect();
+    health.workers_started(workers);
+
+    drop(sender);
+
+    /*
+     * receive the live data coming from the workers and hand it off to all
+     * the sinks, until shutdown is requested and every worker is done (or the
+     * grace period is over)
+     */
+    shutdown::handle_signals();
+    loop {
+        // agents can't hold up shutdown either
+        if shutdown::requested() {
+            aggregator.close();
+        }
+        match results.recv_timeout(Duration::from_millis(250)) {
+            Ok(r) => {
+                telemetry::result_received();
+                bus.publish(r);
+            },
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                if shutdown::deadline().is_some_and(|d| Instant::now() >= d) {
+                    warn!("Rounds still running after {}s, not waiting for them.",
+                          shutdown::GRACE_PERIOD.as_secs());
+                    break;
+                }
+            },
+        }
+    }
+
+    // then make sure everything received has been delivered and flushed
+    info!("Flushing results.");
+    broadcaster.close();
+    bus.close();
+    info!("Shut down.");
+}
+
+/**
+ * Finds the given target for a command, printing what's wrong if there is
+ * none.
+ */
+fn find_target<'a>(targets: &'a [Arc<TargetManager>], kind: &str) -> Option<&'a TargetManager> {
+    let tm = targets.iter().find(|tm| tm.kind.compact_name() == kind);
+    if tm.is_none() {
+        println!("Unknown target '{}', expected one of {}.", kind,
+                 targets.iter().map(|tm| tm.kind.compact_name()).collect::<Vec<_>>().join(", "));
+    }
+    tm.map(|tm| &**tm)
+}
+
+/**
+ * Finds the given target and parses the given time range for a command,
+ * printing what's wrong if either is invalid.
+ */
+fn command_target<'a>(targets: &'a [Arc<TargetManager>], kind: &str, from: Option<&str>,
+                      to: Option<&str>) -> Option<(&'a TargetManager, i64, i64)> {
+    let tm = find_target(targets, kind)?;
+    match report::parse_range(from, to) {
+        Some((from, to)) => Some((tm, from, to)),
+        None => {
+            println!("Invalid time range, expected seconds since the epoch or YYYY-MM-DD dates.");
+            None
+        },
+    }
+}
+
+/**
+ * Prints a report (see `report.rs`) of the given target and time range.
+ */
+fn print_report(targets: &[Arc<TargetManager>], kind: &str, from: Option<&str>, to: Option<&str>) {
+    let (tm, from, to) = match command_target(targets, kind, from, to) {
+        Some(t) => t,
+        None => return,
+    };
+
+    match report::report(tm, from, to) {
+        Ok(r) => println!("{}", serde_json::to_string_pretty(&r).unwrap()),
+        Err(e) => println!("Failed to read {} data: {}", tm.kind.compact_name(), e),
+    }
+}
+
+/**
+ * Writes an export (see `export.rs`) of the given target and time range in
+ * the given format to the given file, or standard output.
+ */
+fn export_data(targets: &[Arc<TargetManager>], kind: &str, from: Option<&str>, to: Option<&str>,
+               output: Option<&Path>, format: ExportFormat) {
+    let (tm, from, to) = match command_target(targets, kind, from, to) {
+        Some(t) => t,
+        None => return,
+    };
+
+    let res = match output {
+        Some(path) => File::create(path)
+            .and_then(|mut f| export::write_export(tm, format, from, to, &TagFilter::default(), &mut f)),
+        None => export::write_export(tm, format, from, to, &TagFilter::default(), &mut io::stdout().lock()),
+    };
+    if let Err(e) = res {
+        println!("Failed to export {} data: {}", tm.kind.compact_name(), e);
+    }
+}
+
+/**
+ * Writes the status page (see `status.rs`) of the given targets as
+ * configured (or by default) to the given file, or standard output.
+ */
+fn write_status_page(targets: &[Arc<TargetManager>], config: &MainConfiguration, output: Option<&Path>, json: bool) {
+    let now = chrono::Local::now().timestamp();
+    let page = match status::compile(targets, &config.status_page.clone().unwrap_or_default(), now) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Failed to read data for the status page: {}", e);
+            return;
+        },
+    };
+    let rendered = if json { serde_json::to_string_pretty(&page).unwrap() } else { status::render_html(&page) };
+    let res = match output {
+        Some(path) => fs::write(path, rendered),
+        None => io::stdout().lock().write_all(rendered.as_bytes()),
+    };
+    if let Err(e) = res {
+        println!("Failed to write the status page: {}", e);
+    }
+}
+
+/**
+ * Imports (see `import.rs`) the given file, or standard input, into the given
+ * target.
+ */
+fn import_data(targets: &[Arc<TargetManager>], kind: &str, input: Option<&Path>, opts: &ImportOptions) {
+    let tm = match find_target(targets, kind) {
+        Some(tm) => tm,
+        None => return,
+    };
+
+    let res = match input {
+        Some(path) if path != Path::new("-") => File::open(path)
+            .map_err(ImportError::Read)
+            .and_then(|f| import::import(tm, &mut BufReader::new(f), opts)),
+        _ => import::import(tm, &mut io::stdin().lock(), opts),
+    };
+    match res {
+        Ok(summary) => {
+            println!("Imported {} rounds of {} into {} ({} new values).",
+                     summary.rounds, summary.addrs.join(", "), kind, summary.imported);
+            let options = tm.options_read();
+            let others: Vec<&str> = summary.addrs.iter()
+                .filter(|a| !options.addrs.contains(a))
+                .map(|a| a.as_str())
+                .collect();
+            if !others.is_empty() {
+                println!("Add {} to the addrs of {} to see their data.", others.join(", "), kind);
+            }
+        },
+        Err(e) => println!("Failed to import {} data: {}", kind, e),
+    }
+}
+
+/**
+ * Adds the addresses listed in the given hosts file (see `hosts.rs`), or
+ * standard input, to the given target, printing what changed.
+ */
+fn import_hosts(targets: &[Arc<TargetManager>], kind: &str, input: Option<&Path>, replace: bool) {
+    let tm = match find_target(targets, kind) {
+        Some(tm) => tm,
+        None => return,
+    };
+
+    let mut text = String::new();
+    let read = match input {
+        Some(path) if path != Path::new("-") => File::open(path).and_then(|mut f| f.read_to_string(&mut text)),
+        _ => io::stdin().lock().read_to_string(&mut text),
+    };
+    if let Err(e) = read {
+        println!("Failed to read hosts: {}", e);
+        return;
+    }
+    match hosts::parse_hosts(&text).and_then(|entries| hosts::import_hosts(tm, entries, replace)) {
+        Ok(summary) => println!("Added {}, updated {} and removed {} addresses of {}.",
+                                summary.added.len(), summary.updated.len(), summary.removed.len(), kind),
+        Err(e) => println!("Failed to import hosts into {}: {}", kind, e),
+    }
+}
 
-        // append the data to the data file via the appropriate manager
-        if let Err(e) = targets[kind_id as usize].append_data(&r) {
-            handle_fatal_error(e);
+/**
+ * Checks (see `fsck.rs`) the given data directory, exiting unsuccessfully if
+ * there are problems left unrepaired.
+ */
+fn check_data_dir(data_path: &Path, repair: bool) {
+    let findings = match fsck::fsck(data_path, repair) {
+        Ok(findings) => findings,
+        Err(e) => {
+            println!("Failed to check '{}': {}", data_path.display(), e);
+            std::process::exit(1);
+        },
+    };
+    for f in findings.iter() {
+        println!("{}: {}{}", f.file, f.problem, if f.repaired { " (repaired)" } else { "" });
+    }
+
+    let unrepaired = findings.iter().filter(|f| !f.repaired).count();
+    if findings.is_empty() {
+        println!("No problems found in '{}'.", data_path.display());
+    } else if unrepaired > 0 {
+        if !repair {
+            println!("Run with --repair (with stabping stopped) to repair what can be.");
         }
+        std::process::exit(1);
+    }
+}
 
-        // broadcast the live data over websockets
-        let raw_data_bytes = r.0.into_raw_bytes();
-        let _ = broadcaster.send(raw_data_bytes);
+/**
+ * Loads the certificate and key to serve HTTPS with, and the certificates of
+ * the clients it accepts (relative to the configuration file), making a self-signed one first (by default in the data
+ * directory) if asked to and there is none.
+ */
+fn https_server(c: &HttpsConfiguration, config_dir: &Path, data_path: &Path) -> Result<HttpsServer, String> {
+    let (cert, key) = match (&c.cert, &c.key) {
+        (Some(cert), Some(key)) => (config_dir.join(cert), config_dir.join(key)),
+        (None, None) if c.self_signed => (data_path.join("https.cert.pem"), data_path.join("https.key.pem")),
+        _ => return Err("https needs both a cert and a key, or self_signed".to_owned()),
+    };
+    if c.self_signed {
+        let mut names = vec!["localhost".to_owned()];
+        if let Ok(host) = fs::read_to_string("/proc/sys/kernel/hostname") {
+            names.push(host.trim().to_owned());
+        }
+        names.extend(c.names.iter().cloned());
+        names.retain(|n| !n.is_empty());
+        https::ensure_self_signed(&cert, &key, &names)?;
     }
+    let client_ca = c.client_ca.as_ref().map(|ca| config_dir.join(ca));
+    HttpsServer::load(&cert, &key, client_ca.as_deref())
+}
+
+#[cfg(feature = "nats")]
+fn subscribe_nats(bus: &mut ResultsBus, c: &NatsConfiguration, targets: &[Arc<TargetManager>], spool: Spool) {
+    match nats::NatsPublisher::new(c.clone()) {
+        Some(p) => bus.subscribe(publish::PublishSink::new(p, c.format, targets, spool)),
+        None => warn!("Invalid NATS server '{}' or subject, not publishing results.", c.server),
+    }
+}
+
+#[cfg(not(feature = "nats"))]
+fn subscribe_nats(_: &mut ResultsBus, _: &NatsConfiguration, _: &[Arc<TargetManager>], _: Spool) {
+    warn!("NATS was configured, but stabping was built without the nats feature.");
+}
+
+#[cfg(feature = "kafka")]
+fn subscribe_kafka(bus: &mut ResultsBus, c: &KafkaConfiguration, targets: &[Arc<TargetManager>], spool: Spool) {
+    match kafka::KafkaPublisher::new(c.clone()) {
+        Some(p) => bus.subscribe(publish::PublishSink::new(p, c.format, targets, spool)),
+        None => warn!("Invalid Kafka brokers or topic, not publishing results."),
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+fn subscribe_kafka(_: &mut ResultsBus, _: &KafkaConfiguration, _: &[Arc<TargetManager>], _: Spool) {
+    warn!("Kafka was configured, but stabping was built without the kafka feature.");
 }
 
 fn handle_fatal_error(e: ManagerError) -> ! {
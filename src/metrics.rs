@@ -0,0 +1,460 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * In-memory tracking of the latest results (and running counts) of every
+ * address of every target, exposed in the Prometheus text exposition format.
+ */
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use chrono::Local;
+
+use crate::histogram::QUANTILES;
+use crate::options::{TargetKind, TargetResults, EXPIRY_COLUMN, sentinel_name, signed_offset, was_probed};
+use crate::probe::Unit;
+use crate::persist::TargetManager;
+use crate::sink::{ResultsSink, SinkError};
+use crate::tags::{self, TagFilter, Tags};
+use crate::telemetry::{self, Snapshot};
+
+/**
+ * The latest values of, and running counts for, a single address.
+ */
+#[derive(Default)]
+struct AddrMetrics {
+    values: Vec<(String, i32)>,
+    loss: i32,
+    jitter: i32,
+    rounds: u64,
+    attempts: u64,
+    failed_attempts: u64,
+    failures: BTreeMap<&'static str, u64>,
+}
+
+/**
+ * The latest values of, and running counts for, a single address of a
+ * target, along with its tags and display name, for exporting elsewhere (see
+ * `otlp.rs`).
+ */
+pub struct AddrSnapshot {
+    pub kind: &'static TargetKind,
+    pub addr: String,
+    pub name: Option<String>,
+    pub tags: Option<Tags>,
+    pub values: Vec<(String, i32)>,  // by column, negative for failures
+    pub loss: i32,
+    pub jitter: i32,
+    pub rounds: u64,
+    pub attempts: u64,
+    pub failed_attempts: u64,
+}
+
+/**
+ * Metrics of a single target, keyed by address.
+ */
+#[derive(Default)]
+struct TargetMetrics {
+    last_round: i64,
+    addrs: BTreeMap<String, AddrMetrics>,
+}
+
+/**
+ * Metrics of all targets, indexed by kind_id.
+ */
+pub struct Metrics {
+    managers: Vec<Arc<TargetManager>>,
+    targets: Mutex<Vec<TargetMetrics>>,
+}
+
+impl Metrics {
+    pub fn new(managers: &[Arc<TargetManager>]) -> Self {
+        Metrics {
+            managers: managers.to_vec(),
+            targets: Mutex::new(managers.iter().map(|_| TargetMetrics::default()).collect()),
+        }
+    }
+
+    /**
+     * Records the given live-collected data (`TargetResults`) of a target.
+     */
+    pub fn record(&self, data_res: &TargetResults) {
+        let manager = &self.managers[data_res.kind as usize];
+        let options = manager.options_read();
+        if data_res.nonce != options.nonce {
+            return;
+        }
+
+        let columns = manager.kind.columns(&options);
+        let num_probe_columns = manager.kind.probe_columns().len();
+
+        let mut targets = self.targets.lock().unwrap();
+        let target = &mut targets[data_res.kind as usize];
+        target.last_round = data_res.timestamp;
+
+        // forget addresses no longer in the target's options
+        target.addrs.retain(|addr, _| options.addrs.contains(addr));
+
+        for (addr, vals) in options.addrs.iter().zip(data_res.vals.chunks(columns.len())) {
+            // this address wasn't probed in this round (or its round was skipped)
+            if !was_probed(vals[0]) {
+                continue;
+            }
+            let m = target.addrs.entry(addr.clone()).or_default();
+
+            m.values = columns.iter().cloned().zip(vals.iter().cloned())
+                .take(num_probe_columns)
+                .collect();
+            m.loss = vals[num_probe_columns];
+            m.jitter = vals[num_probe_columns + 1];
+
+            m.rounds += 1;
+            m.attempts += options.avg_across as u64;
+            // loss is rounded down, so round back up to the nearest attempt
+            m.failed_attempts += (m.loss as u64 * options.avg_across as u64).div_ceil(100);
+
+            if vals[0] < 0 {
+                *m.failures.entry(sentinel_name(vals[0])).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /**
+     * Takes a snapshot of the metrics of every address of every target.
+     */
+    pub fn snapshot(&self) -> Vec<AddrSnapshot> {
+        // (taken first, as the options are locked before the metrics when recording)
+        let meta: Vec<_> = self.managers.iter().map(|tm| {
+            let options = tm.options_read();
+            (options.tags.clone(), options.names.clone())
+        }).collect();
+
+        let targets = self.targets.lock().unwrap();
+        let mut snapshot = Vec::new();
+        for ((tm, t), (tags, names)) in self.managers.iter().zip(targets.iter()).zip(meta.iter()) {
+            for (addr, m) in t.addrs.iter() {
+                snapshot.push(AddrSnapshot {
+                    kind: tm.kind,
+                    addr: addr.clone(),
+                    name: names.get(addr).cloned(),
+                    tags: tags.get(addr).cloned(),
+                    values: m.values.clone(),
+                    loss: m.loss,
+                    jitter: m.jitter,
+                    rounds: m.rounds,
+                    attempts: m.attempts,
+                    failed_attempts: m.failed_attempts,
+                });
+            }
+        }
+        snapshot
+    }
+
+    /**
+     * Renders all metrics in the Prometheus text exposition format, those of
+     * addresses only of the addresses matching the given filter, labelled
+     * with their tags.
+     */
+    pub fn render(&self, filter: &TagFilter) -> String {
+        // (taken first, as the options are locked before the metrics when recording)
+        let tags: Vec<_> = self.managers.iter().map(|tm| tm.options_read().tags.clone()).collect();
+        let labels_of = |i: usize, target: &str, addr: &str| {
+            let t = tags[i].get(addr);
+            if filter.matches(t) { Some(addr_labels(target, addr, t)) } else { None }
+        };
+
+        let targets = self.targets.lock().unwrap();
+        let mut out = String::new();
+
+        // (labels, kind, metrics) of every address of every target
+        let all_addrs: Vec<(String, &TargetKind, &AddrMetrics)> = self.managers.iter().zip(targets.iter())
+            .enumerate()
+            .flat_map(|(i, (tm, t))| {
+                t.addrs.iter().filter_map(move |(addr, m)| {
+                    labels_of(i, tm.kind.compact_name(), addr).map(|l| (l, tm.kind, m))
+                })
+            })
+            .collect();
+        let measured = |kind: &TargetKind, m: &AddrMetrics, unit: Unit| -> Vec<(String, i32)> {
+            m.values.iter()
+                .filter(|&(c, v)| *v >= 0 && kind.unit_of(c) == unit)
+                .cloned()
+                .collect()
+        };
+
+        header(&mut out, "stabping_latency_seconds", "gauge",
+               "Latest measured value of each column of an address measuring a time.");
+        for (labels, kind, m) in all_addrs.iter() {
+            for (column, val) in measured(kind, m, Unit::Duration) {
+                let _ = writeln!(out, "stabping_latency_seconds{{{},column=\"{}\"}} {}",
+                                 labels, column, val as f64 / 1e6);
+            }
+        }
+
+        header(&mut out, "stabping_throughput_kbps", "gauge",
+               "Latest measured value of each column of an address measuring a rate of transfer, in kbit/s.");
+        for (labels, kind, m) in all_addrs.iter() {
+            for (column, val) in measured(kind, m, Unit::Rate) {
+                let _ = writeln!(out, "stabping_throughput_kbps{{{},column=\"{}\"}} {}", labels, column, val);
+            }
+        }
+
+        header(&mut out, "stabping_value", "gauge",
+               "Latest measured value of each other column of an address (e.g. a status), as is.");
+        for (labels, kind, m) in all_addrs.iter() {
+            for (column, val) in measured(kind, m, Unit::Gauge).into_iter().filter(|(c, _)| c != EXPIRY_COLUMN) {
+                let _ = writeln!(out, "stabping_value{{{},column=\"{}\"}} {}", labels, column, val);
+            }
+        }
+
+        header(&mut out, "stabping_certificate_expiry_days", "gauge",
+               "Whole days until the first certificate presented by an address expires.");
+        for (labels, _, m) in all_addrs.iter() {
+            for (_, val) in m.values.iter().filter(|&(c, v)| *v >= 0 && c == EXPIRY_COLUMN) {
+                let _ = writeln!(out, "stabping_certificate_expiry_days{{{}}} {}", labels, val);
+            }
+        }
+
+        header(&mut out, "stabping_clock_offset_seconds", "gauge",
+               "Latest offset of the local clock from an NTP server, positive if it is ahead.");
+        for (labels, _, m) in all_addrs.iter() {
+            if let Some(offset) = signed_offset(m.values.iter().map(|(c, v)| (c.as_str(), *v))) {
+                let _ = writeln!(out, "stabping_clock_offset_seconds{{{}}} {}", labels, offset as f64 / 1e6);
+            }
+        }
+
+        header(&mut out, "stabping_loss_ratio", "gauge",
+               "Fraction of the attempts of the latest round of an address that failed.");
+        for (labels, _, m) in all_addrs.iter() {
+            let _ = writeln!(out, "stabping_loss_ratio{{{}}} {}", labels, m.loss as f64 / 100.0);
+        }
+
+        header(&mut out, "stabping_jitter_seconds", "gauge",
+               "Mean difference between consecutive attempts of the latest round of an address.");
+        // (that of rates of transfer being no time)
+        let timed = |kind: &TargetKind| kind.unit_of("jitter") == Unit::Duration;
+        for (labels, _, m) in all_addrs.iter().filter(|(_, kind, m)| m.jitter >= 0 && timed(kind)) {
+            let _ = writeln!(out, "stabping_jitter_seconds{{{}}} {}", labels, m.jitter as f64 / 1e6);
+        }
+
+        header(&mut out, "stabping_attempt_latency_seconds", "summary",
+               "Primary values of the successful attempts against an address (quantiles over the last day).");
+        let now = Local::now().timestamp();
+        for (i, tm) in self.managers.iter().enumerate() {
+            for (addr, window, total) in tm.histograms(now) {
+                let labels = match labels_of(i, tm.kind.compact_name(), &addr) {
+                    Some(l) => l,
+                    None => continue,
+                };
+                for &(q, _) in QUANTILES.iter() {
+                    if let Some(v) = window.quantile(q) {
+                        let _ = writeln!(out, "stabping_attempt_latency_seconds{{{},quantile=\"{}\"}} {}",
+                                         labels, q, v as f64 / 1e6);
+                    }
+                }
+                let _ = writeln!(out, "stabping_attempt_latency_seconds_sum{{{}}} {}", labels, total.sum as f64 / 1e6);
+                let _ = writeln!(out, "stabping_attempt_latency_seconds_count{{{}}} {}", labels, total.count);
+            }
+        }
+
+        header(&mut out, "stabping_up", "gauge",
+               "Whether the latest round of an address produced a value.");
+        for (labels, _, m) in all_addrs.iter() {
+            let up = m.values.first().is_some_and(|&(_, v)| v >= 0);
+            let _ = writeln!(out, "stabping_up{{{}}} {}", labels, up as u8);
+        }
+
+        header(&mut out, "stabping_rounds_total", "counter",
+               "Rounds of collection completed for an address.");
+        for (labels, _, m) in all_addrs.iter() {
+            let _ = writeln!(out, "stabping_rounds_total{{{}}} {}", labels, m.rounds);
+        }
+
+        header(&mut out, "stabping_attempts_total", "counter",
+               "Attempts made against an address.");
+        for (labels, _, m) in all_addrs.iter() {
+            let _ = writeln!(out, "stabping_attempts_total{{{}}} {}", labels, m.attempts);
+        }
+
+        header(&mut out, "stabping_failed_attempts_total", "counter",
+               "Attempts against an address that failed.");
+        for (labels, _, m) in all_addrs.iter() {
+            let _ = writeln!(out, "stabping_failed_attempts_total{{{}}} {}", labels, m.failed_attempts);
+        }
+
+        header(&mut out, "stabping_failed_rounds_total", "counter",
+               "Rounds of an address that produced no value, by class of failure.");
+        for (labels, _, m) in all_addrs.iter() {
+            for (class, n) in m.failures.iter() {
+                let _ = writeln!(out, "stabping_failed_rounds_total{{{},class=\"{}\"}} {}", labels, class, n);
+            }
+        }
+
+        header(&mut out, "stabping_skipped_rounds_total", "counter",
+               "Rounds of an address skipped because its previous round overran.");
+        for (i, tm) in self.managers.iter().enumerate() {
+            let options = tm.options_read();
+            let mut skipped: Vec<_> = tm.skipped_rounds().into_iter()
+                .filter(|(addr, _)| options.addrs.contains(addr))
+                .collect();
+            skipped.sort();
+            for (addr, n) in skipped {
+                if let Some(labels) = labels_of(i, tm.kind.compact_name(), &addr) {
+                    let _ = writeln!(out, "stabping_skipped_rounds_total{{{}}} {}", labels, n);
+                }
+            }
+        }
+
+        header(&mut out, "stabping_last_round_timestamp_seconds", "gauge",
+               "Time of the latest round of collection of a target.");
+        for (tm, t) in self.managers.iter().zip(targets.iter()) {
+            if t.last_round > 0 {
+                let _ = writeln!(out, "stabping_last_round_timestamp_seconds{{target=\"{}\"}} {}",
+                                 tm.kind.compact_name(), t.last_round);
+            }
+        }
+
+        let names: Vec<&'static str> = self.managers.iter().map(|tm| tm.kind.compact_name()).collect();
+        render_telemetry(&mut out, &names, &telemetry::snapshot());
+        out
+    }
+}
+
+/**
+ * Sink recording all results into the shared `Metrics`.
+ */
+pub struct MetricsSink {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsSink {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        MetricsSink {
+            metrics,
+        }
+    }
+}
+
+impl ResultsSink for MetricsSink {
+    fn name(&self) -> &'static str {
+        "Metrics"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        self.metrics.record(results);
+        Ok(())
+    }
+}
+
+/**
+ * Renders the telemetry of stabping itself (see `telemetry.rs`), with the
+ * counts of the workers of the given targets.
+ */
+fn render_telemetry(out: &mut String, targets: &[&'static str], t: &Snapshot) {
+    let workers = || targets.iter().map(|target| (target, t.workers.get(target).copied().unwrap_or_default()));
+
+    header(out, "stabping_overrun_rounds_total", "counter",
+           "Rounds of a target that overran their address's next deadline.");
+    for (target, c) in workers() {
+        let _ = writeln!(out, "stabping_overrun_rounds_total{{target=\"{}\"}} {}", target, c.overruns);
+    }
+
+    header(out, "stabping_dropped_results_total", "counter",
+           "Results of a target its worker couldn't send on to the sinks.");
+    for (target, c) in workers() {
+        let _ = writeln!(out, "stabping_dropped_results_total{{target=\"{}\"}} {}", target, c.dropped);
+    }
+
+    header(out, "stabping_results_queue_depth", "gauge",
+           "Results sent by the workers not yet handed to the sinks.");
+    let _ = writeln!(out, "stabping_results_queue_depth {}", t.results_queued);
+
+    if let Some((size, busy, queued)) = t.pool {
+        header(out, "stabping_pool_threads", "gauge", "Threads of the pool running rounds.");
+        let _ = writeln!(out, "stabping_pool_threads {}", size);
+        header(out, "stabping_pool_busy_threads", "gauge", "Threads of the pool currently running a round.");
+        let _ = writeln!(out, "stabping_pool_busy_threads {}", busy);
+        header(out, "stabping_pool_queue_depth", "gauge", "Rounds waiting for a thread of the pool.");
+        let _ = writeln!(out, "stabping_pool_queue_depth {}", queued);
+    }
+
+    if let Some(threads) = t.threads {
+        header(out, "stabping_threads", "gauge", "Threads of the stabping process.");
+        let _ = writeln!(out, "stabping_threads {}", threads);
+    }
+
+    header(out, "stabping_sink_queue_depth", "gauge",
+           "Results queued for a sink, not yet delivered.");
+    for sink in t.sinks.iter() {
+        let _ = writeln!(out, "stabping_sink_queue_depth{{sink=\"{}\"}} {}", escape(sink.name), sink.queued);
+    }
+
+    header(out, "stabping_sink_dropped_total", "counter",
+           "Results a sink failed to deliver.");
+    for sink in t.sinks.iter() {
+        let _ = writeln!(out, "stabping_sink_dropped_total{{sink=\"{}\"}} {}", escape(sink.name), sink.dropped);
+    }
+
+    header(out, "stabping_sink_delivery_seconds", "summary",
+           "Time a sink took to deliver each round of results (for persistence, to write it).");
+    for sink in t.sinks.iter() {
+        let _ = writeln!(out, "stabping_sink_delivery_seconds_sum{{sink=\"{}\"}} {}",
+                         escape(sink.name), sink.delivery_seconds);
+        let _ = writeln!(out, "stabping_sink_delivery_seconds_count{{sink=\"{}\"}} {}",
+                         escape(sink.name), sink.delivered);
+    }
+
+    header(out, "stabping_sink_delivery_max_seconds", "gauge",
+           "Longest time a sink took to deliver a round of results.");
+    for sink in t.sinks.iter() {
+        let _ = writeln!(out, "stabping_sink_delivery_max_seconds{{sink=\"{}\"}} {}",
+                         escape(sink.name), sink.max_delivery_seconds);
+    }
+}
+
+/**
+ * Writes the HELP and TYPE lines introducing a metric family.
+ */
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/**
+ * Gets the labels of the metrics of the given address of the given target:
+ * its target and address, and its tags (under the label names they take, see
+ * `tags::label_name`).
+ */
+fn addr_labels(target: &str, addr: &str, tags: Option<&Tags>) -> String {
+    let mut labels = format!("target=\"{}\",addr=\"{}\"", target, escape(addr));
+    for (name, value) in tags.into_iter().flatten() {
+        if let Some(label) = tags::label_name(name) {
+            let _ = write!(labels, ",{}=\"{}\"", label, escape(value));
+        }
+    }
+    labels
+}
+
+/**
+ * Escapes the given string for use as a Prometheus label value.
+ */
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[test]
+fn escape_quotes_and_backslashes() {
+    assert_eq!(escape("dns \"a\\b\""), "dns \\\"a\\\\b\\\"");
+
+    let tags: Tags = [("site", "home"), ("data-center", "a\"b"), ("addr", "x")].iter()
+        .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+    assert_eq!(addr_labels("tcpping", "a:80", Some(&tags)),
+               "target=\"tcpping\",addr=\"a:80\",data_center=\"a\\\"b\",site=\"home\"");
+}
@@ -0,0 +1,383 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Publishing of the latest state of every address to an MQTT broker (in MQTT
+ * 3.1.1), for home automation (e.g. Home Assistant) to show and act on.
+ *
+ * After each round, every address probed in it has `up` or `down` published
+ * to `<topic>/<target>/<addr>/state` and, when up, its primary value (in
+ * milliseconds) to `<topic>/<target>/<addr>/latency`, retained by default so
+ * that subscribers get the last state right away. `<topic>/status` is
+ * `online` while connected, and set to `offline` by the broker (as the will
+ * of the connection) if stabping goes away.
+ *
+ * Since only the latest state matters, rounds that fail to publish are
+ * dropped rather than buffered, the next round superseding them.
+ */
+use std::collections::HashSet;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::http::{tls_handshake, HttpStream};
+use crate::options::{MqttConfiguration, TargetResults, was_probed};
+use crate::persist::TargetManager;
+use crate::sink::{ResultsSink, SinkError};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+/**
+ * Sink publishing the state of all addresses to the configured broker, over
+ * a lazily (re-)established connection.
+ */
+pub struct MqttSink {
+    config: MqttConfiguration,
+    host: String,
+    port: u16,
+    tls: bool,
+    managers: Vec<Arc<TargetManager>>,
+    conn: Option<HttpStream>,
+    announced: HashSet<(usize, String)>,  // addresses announced to Home Assistant over the connection
+}
+
+impl MqttSink {
+    /**
+     * Creates a sink for the given configuration, or returns `None` if its
+     * broker or topic is invalid.
+     */
+    pub fn new(config: MqttConfiguration, managers: &[Arc<TargetManager>]) -> Option<Self> {
+        let (tls, authority) = match config.broker.split_once("://") {
+            Some(("mqtt", a)) | Some(("tcp", a)) => (false, a),
+            Some(("mqtts", a)) | Some(("ssl", a)) => (true, a),
+            Some(_) => return None,
+            None => (false, config.broker.as_str()),
+        };
+        let authority = authority.trim_end_matches('/');
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) if !p.contains(']') => (h, p.parse().ok()?),
+            _ => (authority, if tls { 8883 } else { 1883 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_owned();
+        if host.is_empty() || config.topic.is_empty() || config.topic.contains(['+', '#']) {
+            return None;
+        }
+        Some(MqttSink {
+            config,
+            host,
+            port,
+            tls,
+            managers: managers.to_vec(),
+            conn: None,
+            announced: HashSet::new(),
+        })
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.config.topic)
+    }
+
+    fn connect(&self) -> io::Result<HttpStream> {
+        let timeout = Duration::from_secs(10);
+        let sock_addr = (self.host.as_str(), self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))?;
+        let tcp = TcpStream::connect_timeout(&sock_addr, timeout)?;
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+        let mut conn = if self.tls {
+            tls_handshake(&self.host, tcp)?
+        } else {
+            HttpStream::Plain(tcp)
+        };
+
+        // clean session, a retained will of `offline`, and no keep alive
+        let mut flags = 0x02 | 0x04 | 0x20;
+        let mut body = Vec::new();
+        string(&mut body, "MQTT");
+        body.push(4);
+        let flags_at = body.len();
+        body.extend_from_slice(&[0, 0, 0]);
+        string(&mut body, &self.config.client_id);
+        string(&mut body, &self.status_topic());
+        string(&mut body, "offline");
+        if let Some(ref username) = self.config.username {
+            flags |= 0x80;
+            string(&mut body, username);
+            if let Some(ref password) = self.config.password {
+                flags |= 0x40;
+                string(&mut body, password);
+            }
+        }
+        body[flags_at] = flags;
+        conn.write_all(&packet(CONNECT, &body))?;
+        conn.flush()?;
+
+        let (kind, ack) = read_packet(&mut conn)?;
+        if kind & 0xf0 != CONNACK || ack.len() != 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an MQTT broker"));
+        }
+        match ack[1] {
+            0 => Ok(conn),
+            4 => Err(io::Error::other("bad username or password")),
+            5 => Err(io::Error::other("not authorized")),
+            c => Err(io::Error::other(format!("connection refused ({})", c))),
+        }
+    }
+
+    /**
+     * Sends the given packets on the connection (connecting first if need
+     * be), and waits for the broker to answer a ping sent after them, so that
+     * it took them all.
+     */
+    fn send(&mut self, packets: &[u8]) -> io::Result<()> {
+        if self.conn.is_none() {
+            let mut conn = self.connect()?;
+            conn.write_all(&publish(&self.status_topic(), b"online", true))?;
+            self.conn = Some(conn);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        conn.write_all(packets)?;
+        conn.write_all(&[PINGREQ, 0])?;
+        conn.flush()?;
+        loop {
+            let (kind, _) = read_packet(conn)?;
+            if kind & 0xf0 == PINGRESP {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn remaining_length(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    remaining_length(&mut out, body.len());
+    out.extend_from_slice(body);
+    out
+}
+
+/**
+ * Makes a `PUBLISH` packet (at QoS 0) of the given payload to the given
+ * topic.
+ */
+fn publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+    string(&mut body, topic);
+    body.extend_from_slice(payload);
+    packet(PUBLISH | retain as u8, &body)
+}
+
+/**
+ * Reads a packet, returning its first byte (its type and flags) and what
+ * follows its length.
+ */
+fn read_packet<S: Read>(conn: &mut S) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0];
+    conn.read_exact(&mut byte)?;
+    let kind = byte[0];
+    let mut len = 0;
+    for shift in (0..28).step_by(7) {
+        conn.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            conn.read_exact(&mut body)?;
+            return Ok((kind, body));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "packet of invalid length"))
+}
+
+/**
+ * Makes the given address usable as a level of a topic, replacing the
+ * separators and wildcards of topics.
+ */
+fn topic_level(addr: &str) -> String {
+    addr.replace(['/', '+', '#'], "_")
+}
+
+/**
+ * Makes the given string usable as an id of Home Assistant's discovery.
+ */
+fn object_id(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+/**
+ * Makes the `PUBLISH` packets announcing the given address (with the given
+ * display name) of the given target to Home Assistant, under the given
+ * discovery prefix: a connectivity sensor of its state, and a sensor of its
+ * latency.
+ */
+fn discovery(config: &MqttConfiguration, prefix: &str, target: &str, addr: &str, name: &str) -> Vec<u8> {
+    let base = format!("{}/{}/{}", config.topic, target, topic_level(addr));
+    let id = format!("{}_{}_{}", object_id(&config.client_id), target, object_id(addr));
+    let availability = format!("{}/status", config.topic);
+    let state = serde_json::json!({
+        "name": format!("{} ({})", name, target),
+        "unique_id": format!("{}_state", id),
+        "state_topic": format!("{}/state", base),
+        "payload_on": "up",
+        "payload_off": "down",
+        "device_class": "connectivity",
+        "availability_topic": availability,
+    });
+    let latency = serde_json::json!({
+        "name": format!("{} ({}) latency", name, target),
+        "unique_id": format!("{}_latency", id),
+        "state_topic": format!("{}/latency", base),
+        "unit_of_measurement": "ms",
+        "state_class": "measurement",
+        "availability_topic": availability,
+    });
+    let mut out = publish(&format!("{}/binary_sensor/{}/state/config", prefix, id),
+                          state.to_string().as_bytes(), true);
+    out.extend(publish(&format!("{}/sensor/{}/latency/config", prefix, id),
+                       latency.to_string().as_bytes(), true));
+    out
+}
+
+impl ResultsSink for MqttSink {
+    fn name(&self) -> &'static str {
+        "MQTT"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        let mut packets = Vec::new();
+        let mut announcing = Vec::new();
+        {
+            let kind = results.kind as usize;
+            let manager = &self.managers[kind];
+            let options = manager.options_read();
+            if results.nonce != options.nonce {
+                return Ok(());
+            }
+            let target = manager.kind.compact_name();
+            let num_columns = manager.kind.columns(&options).len();
+            for (addr, vals) in options.addrs.iter().zip(results.vals.chunks(num_columns)) {
+                if !was_probed(vals[0]) {
+                    continue;
+                }
+                if let Some(ref prefix) = self.config.discovery {
+                    if !self.announced.contains(&(kind, addr.clone())) {
+                        let name = options.names.get(addr).unwrap_or(addr);
+                        packets.extend(discovery(&self.config, prefix, target, addr, name));
+                        announcing.push((kind, addr.clone()));
+                    }
+                }
+                let base = format!("{}/{}/{}", self.config.topic, target, topic_level(addr));
+                let up = vals[0] >= 0;
+                packets.extend(publish(&format!("{}/state", base), if up { b"up" } else { b"down" },
+                                       self.config.retain));
+                if up {
+                    let latency = format!("{:.3}", vals[0] as f64 / 1000.0);
+                    packets.extend(publish(&format!("{}/latency", base), latency.as_bytes(), self.config.retain));
+                }
+            }
+        }
+
+        if packets.is_empty() {
+            return Ok(());
+        }
+        match self.send(&packets) {
+            Ok(()) => {
+                self.announced.extend(announcing);
+                Ok(())
+            },
+            Err(e) => {
+                // announce everything again once reconnected, in case the broker lost it
+                self.conn = None;
+                self.announced.clear();
+                Err(SinkError::Dropped(format!("{}", e)))
+            },
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        // the will isn't published on a clean disconnect
+        if let Some(mut conn) = self.conn.take() {
+            let offline = publish(&self.status_topic(), b"offline", true);
+            let _ = conn.write_all(&offline)
+                .and_then(|_| conn.write_all(&[DISCONNECT, 0]))
+                .and_then(|_| conn.flush());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn states_are_published_once_the_broker_pongs() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let mut len = Vec::new();
+    remaining_length(&mut len, 321);
+    assert_eq!(len, vec![0xc1, 0x02]);
+    assert_eq!(topic_level("http://example.com/a+b#c"), "http:__example.com_a_b_c");
+    assert_eq!(object_id("google.com:80"), "google_com_80");
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let broker = thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let (kind, connect) = read_packet(&mut conn).unwrap();
+        assert_eq!(kind, CONNECT);
+        assert!(connect.windows(15).any(|w| w == b"stabping/status"));
+        conn.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+        let mut published = Vec::new();
+        loop {
+            match read_packet(&mut conn) {
+                Ok((PINGREQ, _)) => conn.write_all(&[PINGRESP, 0]).unwrap(),
+                Ok((kind, body)) if kind & 0xf0 == PUBLISH => {
+                    let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+                    published.push((kind & 1 == 1, topic, String::from_utf8(body[2 + len..].to_vec()).unwrap()));
+                },
+                Ok((DISCONNECT, _)) | Err(_) => return published,
+                Ok((kind, _)) => panic!("unexpected packet {}", kind),
+            }
+        }
+    });
+
+    let config: MqttConfiguration = toml::from_str(&format!(r#"broker = "mqtt://127.0.0.1:{}""#, port)).unwrap();
+    let mut sink = MqttSink::new(config, &[]).unwrap();
+    sink.send(&publish("stabping/tcpping/google.com:80/state", b"up", true)).unwrap();
+    sink.flush().unwrap();
+    assert_eq!(broker.join().unwrap(), vec![
+        (true, "stabping/status".to_owned(), "online".to_owned()),
+        (true, "stabping/tcpping/google.com:80/state".to_owned(), "up".to_owned()),
+        (true, "stabping/status".to_owned(), "offline".to_owned()),
+    ]);
+
+    let config: MqttConfiguration = toml::from_str(r#"broker = "mqtt://localhost"
+                                                      topic = "a/#""#).unwrap();
+    assert!(MqttSink::new(config, &[]).is_none());
+}
@@ -0,0 +1,167 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * MTR-style statistics of each hop of the paths mapped to an address (see
+ * `hops`), over consecutive steps of a range of time, so that loss or latency
+ * building up at a hop (e.g. a congested peer) shows over time.
+ */
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::hops::HopRound;
+use crate::persist::{TargetManager, ManagerError};
+
+/**
+ * Statistics of a single hop (by distance) of the paths mapped over a step,
+ * as in MTR's report. Round-trips are in microseconds.
+ */
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HopStats {
+    pub hop: usize,  // distance (TTL) of the hop, from 1
+    pub ips: Vec<IpAddr>,  // addresses that answered at this distance, most often first
+    pub sent: u64,  // paths reaching at least this far
+    pub lost: u64,  // of which this hop didn't answer
+    pub loss: f64,  // percentage of those sent that were lost
+    pub last: Option<i32>,
+    pub mean: Option<f64>,
+    pub best: Option<i32>,
+    pub worst: Option<i32>,
+    pub stdev: Option<f64>,
+}
+
+/**
+ * Statistics of each hop of the paths mapped from `from` to `to` (inclusive).
+ */
+#[derive(Serialize, Debug)]
+pub struct MtrStep {
+    pub from: i64,
+    pub to: i64,
+    pub paths: u64,
+    pub hops: Vec<HopStats>,
+}
+
+/**
+ * Statistics of the paths mapped to an address over a range of time, in
+ * steps.
+ */
+#[derive(Serialize, Debug)]
+pub struct MtrReport {
+    pub target: &'static str,
+    pub addr: String,
+    pub from: i64,
+    pub to: i64,
+    pub steps: Vec<MtrStep>,
+}
+
+/**
+ * Computes the statistics of each hop of the given paths, ordered by time.
+ * Each path counts as sent to every hop up to its length.
+ */
+fn hop_stats(rounds: &[HopRound]) -> Vec<HopStats> {
+    let num_hops = rounds.iter().map(|r| r.hops.len()).max().unwrap_or(0);
+    (0..num_hops).map(|h| {
+        let mut ips: Vec<(IpAddr, u64)> = Vec::new();
+        let mut rtts = Vec::new();
+        let mut sent = 0;
+        for hop in rounds.iter().filter_map(|r| r.hops.get(h)) {
+            sent += 1;
+            if let Some(ip) = hop.ip {
+                match ips.iter_mut().find(|&&mut (i, _)| i == ip) {
+                    Some(&mut (_, ref mut n)) => *n += 1,
+                    None => ips.push((ip, 1)),
+                }
+            }
+            if let Some(rtt) = hop.rtt {
+                rtts.push(rtt);
+            }
+        }
+        ips.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+
+        let (mean, stdev) = if rtts.is_empty() {
+            (None, None)
+        } else {
+            let n = rtts.len() as f64;
+            let mean = rtts.iter().map(|&r| r as f64).sum::<f64>() / n;
+            let var = rtts.iter().map(|&r| (r as f64 - mean).powi(2)).sum::<f64>() / n;
+            (Some(mean), Some(var.sqrt()))
+        };
+        let lost = sent - rtts.len() as u64;
+        HopStats {
+            hop: h + 1,
+            ips: ips.into_iter().map(|(ip, _)| ip).collect(),
+            sent,
+            lost,
+            loss: if sent > 0 { 100.0 * lost as f64 / sent as f64 } else { 0.0 },
+            last: rtts.last().cloned(),
+            mean,
+            best: rtts.iter().min().cloned(),
+            worst: rtts.iter().max().cloned(),
+            stdev,
+        }
+    }).collect()
+}
+
+/**
+ * Builds the statistics of the paths mapped to the given address from `from`
+ * to `to` (inclusive), in consecutive steps of `step` seconds (a single step
+ * if None). Steps without any paths are left out.
+ */
+pub fn mtr(tm: &TargetManager, addr: &str, from: i64, to: i64,
+           step: Option<i64>) -> Result<MtrReport, ManagerError> {
+    let rounds = tm.hops_in_range(addr, from, to)?;
+    let step = step.unwrap_or(to - from + 1).max(1);
+
+    let mut steps = Vec::new();
+    let mut rest = &rounds[..];
+    while let Some(first) = rest.first() {
+        let step_from = from + (first.time - from) / step * step;
+        let step_to = step_from + step - 1;
+        let n = rest.iter().position(|r| r.time > step_to).unwrap_or(rest.len());
+        steps.push(MtrStep {
+            from: step_from,
+            to: step_to.min(to),
+            paths: n as u64,
+            hops: hop_stats(&rest[..n]),
+        });
+        rest = &rest[n..];
+    }
+
+    Ok(MtrReport {
+        target: tm.kind.compact_name(),
+        addr: addr.to_owned(),
+        from,
+        to,
+        steps,
+    })
+}
+
+#[test]
+fn hop_stats_count_loss_and_latency_per_hop() {
+    use crate::hops::Hop;
+
+    let a: IpAddr = "10.0.0.1".parse().unwrap();
+    let b: IpAddr = "10.0.0.2".parse().unwrap();
+    let c: IpAddr = "10.0.0.3".parse().unwrap();
+    let hop = |ip, rtt| Hop { ip: Some(ip), rtt: Some(rtt) };
+    let rounds = vec![
+        HopRound { time: 0, hops: vec![hop(a, 100), hop(b, 1000)] },
+        HopRound { time: 1, hops: vec![hop(a, 300), Hop { ip: None, rtt: None }] },
+        HopRound { time: 2, hops: vec![hop(a, 200), hop(c, 2000)] },
+        HopRound { time: 3, hops: vec![hop(a, 200)] },
+    ];
+
+    let stats = hop_stats(&rounds);
+    assert_eq!(stats.len(), 2);
+    assert_eq!((stats[0].sent, stats[0].lost, stats[0].last), (4, 0, Some(200)));
+    assert_eq!((stats[0].best, stats[0].worst, stats[0].mean), (Some(100), Some(300), Some(200.0)));
+    assert!((stats[0].stdev.unwrap() - 70.71).abs() < 0.01);
+    assert_eq!((stats[1].sent, stats[1].lost, stats[1].mean), (3, 1, Some(1500.0)));
+    assert_eq!(stats[1].ips, vec![b, c]);
+}
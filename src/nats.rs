@@ -0,0 +1,203 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A minimal NATS client, only publishing (see `publish.rs`), in the text
+ * protocol of NATS over a lazily (re-)established connection.
+ *
+ * Each publish is followed by a `PING`, and only done once the server answers
+ * with `PONG` (having taken everything before it), so that failures show up
+ * with the round that failed rather than silently. The server's own `PING`s
+ * are answered along the way.
+ */
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::http::{tls_handshake, HttpStream};
+use crate::options::NatsConfiguration;
+use crate::publish::Publisher;
+use crate::spool::PushError;
+
+const DEFAULT_PORT: u16 = 4222;
+
+/**
+ * Publisher to the configured NATS server.
+ */
+pub struct NatsPublisher {
+    config: NatsConfiguration,
+    host: String,
+    port: u16,
+    tls: bool,
+    conn: Option<BufReader<HttpStream>>,
+}
+
+impl NatsPublisher {
+    /**
+     * Creates a publisher for the given configuration, or returns `None` if
+     * its server is invalid.
+     */
+    pub fn new(config: NatsConfiguration) -> Option<Self> {
+        let (tls, authority) = match config.server.split_once("://") {
+            Some(("nats", a)) => (false, a),
+            Some(("tls", a)) => (true, a),
+            Some(_) => return None,
+            None => (false, config.server.as_str()),
+        };
+        let authority = authority.trim_end_matches('/');
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) if !p.contains(']') => (h, p.parse().ok()?),
+            _ => (authority, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_owned();
+        if host.is_empty() || config.subject.is_empty() || config.subject.contains(char::is_whitespace) {
+            return None;
+        }
+        Some(NatsPublisher {
+            config,
+            host,
+            port,
+            tls,
+            conn: None,
+        })
+    }
+
+    fn connect(&self) -> io::Result<BufReader<HttpStream>> {
+        let timeout = Duration::from_secs(10);
+        let sock_addr = (self.host.as_str(), self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))?;
+        let tcp = TcpStream::connect_timeout(&sock_addr, timeout)?;
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+
+        // the server introduces itself first, before any TLS handshake
+        let mut conn = BufReader::new(HttpStream::Plain(tcp));
+        let mut info = String::new();
+        conn.read_line(&mut info)?;
+        if !info.starts_with("INFO ") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a NATS server"));
+        }
+        if self.tls {
+            let tcp = match conn.into_inner() {
+                HttpStream::Plain(tcp) => tcp,
+                HttpStream::Tls(_) => unreachable!(),
+            };
+            conn = BufReader::new(tls_handshake(&self.host, tcp)?);
+        }
+
+        let connect = serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "stabping",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "auth_token": self.config.token,
+            "user": self.config.username,
+            "pass": self.config.password,
+        });
+        write!(conn.get_mut(), "CONNECT {}\r\nPING\r\n", connect)?;
+        conn.get_mut().flush()?;
+        await_pong(&mut conn)?;
+        Ok(conn)
+    }
+}
+
+/**
+ * Reads what the server sends until it answers a `PING` with `PONG`,
+ * answering its own `PING`s, and failing on `-ERR`.
+ */
+fn await_pong<S: io::Read + Write>(conn: &mut BufReader<S>) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        match line.trim_end() {
+            "PONG" => return Ok(()),
+            "PING" => {
+                conn.get_mut().write_all(b"PONG\r\n")?;
+                conn.get_mut().flush()?;
+            },
+            l if l.starts_with("-ERR") => return Err(io::Error::other(l.trim_start_matches("-ERR ").to_owned())),
+            // +OK, and INFO updating the cluster
+            _ => {},
+        }
+    }
+}
+
+impl Publisher for NatsPublisher {
+    fn name(&self) -> &'static str {
+        "NATS"
+    }
+
+    fn publish(&mut self, target: &str, _: i64, payload: &[u8]) -> Result<(), PushError> {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect().map_err(|e| PushError::Retry(format!("failed to connect: {}", e)))?);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        let published = write!(conn.get_mut(), "PUB {}.{} {}\r\n", self.config.subject, target, payload.len())
+            .and_then(|_| conn.get_mut().write_all(payload))
+            .and_then(|_| conn.get_mut().write_all(b"\r\nPING\r\n"))
+            .and_then(|_| conn.get_mut().flush())
+            .and_then(|_| await_pong(conn));
+        published.map_err(|e| {
+            self.conn = None;
+            PushError::Retry(format!("{}", e))
+        })
+    }
+}
+
+#[test]
+fn rounds_are_published_once_the_server_pongs() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = BufReader::new(stream);
+        conn.get_mut().write_all(b"INFO {\"server_id\":\"test\"}\r\n").unwrap();
+        let mut received = Vec::new();
+        let mut line = String::new();
+        while conn.read_line(&mut line).unwrap() > 0 {
+            let l = line.trim_end().to_owned();
+            line.clear();
+            if l == "PING" {
+                conn.get_mut().write_all(b"PING\r\n+OK\r\nPONG\r\n").unwrap();
+            } else if let Some(rest) = l.strip_prefix("PUB ") {
+                let (subject, len) = rest.split_once(' ').unwrap();
+                let mut payload = vec![0; len.parse::<usize>().unwrap() + 2];
+                io::Read::read_exact(&mut conn, &mut payload).unwrap();
+                received.push((subject.to_owned(), String::from_utf8(payload).unwrap()));
+            } else if l.starts_with("CONNECT ") {
+                assert!(l.contains("\"auth_token\":\"s3cret\""));
+            } else if l != "PONG" {
+                panic!("unexpected {}", l);
+            }
+        }
+        received
+    });
+
+    let config: NatsConfiguration = toml::from_str(&format!(r#"
+        server = "nats://127.0.0.1:{}"
+        token = "s3cret"
+    "#, port)).unwrap();
+    let mut publisher = NatsPublisher::new(config).unwrap();
+    publisher.publish("tcpping", 0, b"{\"a\":1}").unwrap();
+    publisher.publish("icmpping", 0, b"{}").unwrap();
+    drop(publisher);
+    assert_eq!(server.join().unwrap(), vec![
+        ("stabping.tcpping".to_owned(), "{\"a\":1}\r\n".to_owned()),
+        ("stabping.icmpping".to_owned(), "{}\r\n".to_owned()),
+    ]);
+
+    let config: NatsConfiguration = toml::from_str(r#"server = "http://localhost""#).unwrap();
+    assert!(NatsPublisher::new(config).is_none());
+}
@@ -0,0 +1,433 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Notification of alerts firing and resolving (and delivery of digests, see
+ * `digest.rs`) through any number of `AlertNotifier`s, each fed on its own
+ * thread so that a slow or unreachable one can't hold up alert evaluation or
+ * the others.
+ */
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+
+use chrono::Local;
+
+use crate::alerts::{Alert, AlertState, DOWN_COLUMN};
+use crate::digest::Digest;
+use crate::history::{AlertEvent, AlertHistory, Transition};
+use crate::http;
+use crate::http::Url;
+use crate::smtp;
+use crate::options::{EXPIRY_COLUMN, WebhookConfiguration, ChatConfiguration, TelegramConfiguration, MessageTemplates,
+                     SmtpConfiguration};
+
+/**
+ * Error container for failures of a notifier to deliver a notification.
+ */
+#[derive(Debug)]
+pub enum NotifyError {
+    Send(io::Error),
+    Rejected(u16),
+}
+
+impl NotifyError {
+    pub fn description(&self) -> String {
+        match *self {
+            NotifyError::Send(ref e) => format!("Failed to send: {}", e),
+            NotifyError::Rejected(status) => format!("Rejected with status {}", status),
+        }
+    }
+}
+
+impl Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.description())
+    }
+}
+
+/**
+ * A destination for notifications of alerts changing state, and digests.
+ */
+pub trait AlertNotifier: Send {
+    /**
+     * A short description of this notifier, used when reporting its errors.
+     */
+    fn name(&self) -> String;
+
+    /**
+     * Notifies of the given alert having just changed to its current state.
+     */
+    fn notify(&mut self, alert: &Alert) -> Result<(), NotifyError>;
+
+    /**
+     * Delivers the given digest, with the given subject and body rendered
+     * from it.
+     */
+    fn digest(&mut self, digest: &Digest, subject: &str, body: &str) -> Result<(), NotifyError>;
+}
+
+enum Notification {
+    Alert(Box<Alert>),
+    Digest(Arc<Digest>),
+}
+
+/**
+ * All notifiers to notify of alerts changing state, recording each attempt in
+ * the given history.
+ */
+#[derive(Default, Clone)]
+pub struct Notifications {
+    notifiers: Vec<Sender<Notification>>,
+    history: Arc<AlertHistory>,
+}
+
+impl Notifications {
+    pub fn new(history: Arc<AlertHistory>) -> Self {
+        Notifications {
+            notifiers: Vec::new(),
+            history,
+        }
+    }
+
+    /**
+     * Adds the given notifier, starting a thread to deliver its
+     * notifications. Notifications that fail are reported and dropped.
+     */
+    pub fn add<N: AlertNotifier + 'static>(&mut self, mut notifier: N) {
+        let (tx, rx) = channel::<Notification>();
+        self.notifiers.push(tx);
+
+        let history = self.history.clone();
+        thread::spawn(move || {
+            for notification in rx {
+                let alert = match notification {
+                    Notification::Alert(a) => *a,
+                    Notification::Digest(digest) => {
+                        let (subject, body) = digest.render();
+                        if let Err(e) = notifier.digest(&digest, &subject, &body) {
+                            warn!("Failed to deliver digest to {}: {}", notifier.name(), e);
+                        }
+                        continue;
+                    },
+                };
+                let result = notifier.notify(&alert);
+                let mut transition = Transition {
+                    state: Some(alert.state),
+                    notifier: Some(notifier.name()),
+                    ..Transition::of(&alert, AlertEvent::Notified, Local::now().timestamp())
+                };
+                if let Err(ref e) = result {
+                    warn!("Failed to notify {} of alert '{}': {}",
+                          notifier.name(), alert.rule, e);
+                    transition.event = AlertEvent::NotifyFailed;
+                    transition.error = Some(e.description());
+                }
+                history.record(&transition);
+            }
+        });
+    }
+
+    /**
+     * Notifies all notifiers of the given alert having just changed state.
+     */
+    pub fn send(&self, alert: &Alert) {
+        for n in self.notifiers.iter() {
+            let _ = n.send(Notification::Alert(Box::new(alert.clone())));
+        }
+    }
+
+    /**
+     * Delivers the given digest to all notifiers.
+     */
+    pub fn send_digest(&self, digest: Digest) {
+        let digest = Arc::new(digest);
+        for n in self.notifiers.iter() {
+            let _ = n.send(Notification::Digest(digest.clone()));
+        }
+    }
+}
+
+/**
+ * Notifier POSTing every alert as JSON to a URL.
+ */
+pub struct Webhook {
+    config: WebhookConfiguration,
+    url: Url,
+}
+
+impl Webhook {
+    /**
+     * Creates a webhook for the given configuration, or returns `None` if its
+     * url is invalid.
+     */
+    pub fn new(config: WebhookConfiguration) -> Option<Self> {
+        Some(Webhook {
+            url: Url::parse(&config.url)?,
+            config,
+        })
+    }
+}
+
+impl AlertNotifier for Webhook {
+    fn name(&self) -> String {
+        format!("webhook {}", self.config.url)
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), NotifyError> {
+        let headers: Vec<(&str, &str)> =
+            self.config.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        post_json(&self.url, &headers, &serde_json::to_string(alert).unwrap())
+    }
+
+    fn digest(&mut self, digest: &Digest, _: &str, _: &str) -> Result<(), NotifyError> {
+        let headers: Vec<(&str, &str)> =
+            self.config.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        post_json(&self.url, &headers, &serde_json::json!({ "digest": digest }).to_string())
+    }
+}
+
+/**
+ * The chat services alerts can be posted to as messages.
+ */
+enum ChatService {
+    Slack,
+    Discord,
+    Telegram(String),  // with the chat to post to
+}
+
+/**
+ * Notifier posting every alert as a message (rendered from templates, see
+ * `render`) to a chat service.
+ */
+pub struct ChatNotifier {
+    service: ChatService,
+    url: Url,
+    templates: MessageTemplates,
+}
+
+impl ChatNotifier {
+    /**
+     * Creates a notifier posting to a Slack incoming webhook, or returns
+     * `None` if its url is invalid.
+     */
+    pub fn slack(config: ChatConfiguration) -> Option<Self> {
+        Some(ChatNotifier {
+            service: ChatService::Slack,
+            url: Url::parse(&config.url)?,
+            templates: config.templates,
+        })
+    }
+
+    /**
+     * Creates a notifier posting to a Discord webhook, or returns `None` if
+     * its url is invalid.
+     */
+    pub fn discord(config: ChatConfiguration) -> Option<Self> {
+        Some(ChatNotifier {
+            service: ChatService::Discord,
+            url: Url::parse(&config.url)?,
+            templates: config.templates,
+        })
+    }
+
+    /**
+     * Creates a notifier posting to a chat through a Telegram bot.
+     */
+    pub fn telegram(config: TelegramConfiguration) -> Option<Self> {
+        let url = format!("https://api.telegram.org/bot{}/sendMessage", config.token);
+        Some(ChatNotifier {
+            service: ChatService::Telegram(config.chat_id),
+            url: Url::parse(&url)?,
+            templates: config.templates,
+        })
+    }
+
+    /**
+     * Posts the given message to the chat.
+     */
+    fn post(&self, message: &str) -> Result<(), NotifyError> {
+        let body = match self.service {
+            ChatService::Slack => serde_json::json!({"text": message}),
+            ChatService::Discord => serde_json::json!({"content": message}),
+            ChatService::Telegram(ref chat_id) =>
+                serde_json::json!({"chat_id": chat_id, "text": message}),
+        };
+        post_json(&self.url, &[], &body.to_string())
+    }
+}
+
+impl AlertNotifier for ChatNotifier {
+    fn name(&self) -> String {
+        match self.service {
+            ChatService::Slack => "Slack".to_owned(),
+            ChatService::Discord => "Discord".to_owned(),
+            ChatService::Telegram(ref chat_id) => format!("Telegram chat {}", chat_id),
+        }
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), NotifyError> {
+        let template = match alert.state {
+            AlertState::Firing => &self.templates.firing_template,
+            AlertState::Resolved => &self.templates.resolved_template,
+        };
+        self.post(&render(template, alert))
+    }
+
+    fn digest(&mut self, _: &Digest, subject: &str, body: &str) -> Result<(), NotifyError> {
+        self.post(&format!("{}\n\n{}", subject, body))
+    }
+}
+
+/**
+ * Notifier emailing every alert, with a subject rendered from templates (see
+ * `render`).
+ */
+pub struct EmailNotifier {
+    config: SmtpConfiguration,
+}
+
+impl EmailNotifier {
+    pub fn new(config: SmtpConfiguration) -> Self {
+        EmailNotifier {
+            config,
+        }
+    }
+}
+
+impl AlertNotifier for EmailNotifier {
+    fn name(&self) -> String {
+        format!("SMTP server {}", self.config.server)
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), NotifyError> {
+        let template = match alert.state {
+            AlertState::Firing => &self.config.templates.firing_template,
+            AlertState::Resolved => &self.config.templates.resolved_template,
+        };
+        let subject = render(template, alert);
+        let body = render("Target: {target}\nAddress: {addr}\nRule: {rule} ({column})\n\
+                           State: {state}\nValue: {value}\nSince firing: {duration}\n", alert);
+
+        smtp::send_mail(&self.config, &subject, &body).map_err(NotifyError::Send)
+    }
+
+    fn digest(&mut self, _: &Digest, subject: &str, body: &str) -> Result<(), NotifyError> {
+        smtp::send_mail(&self.config, subject, body).map_err(NotifyError::Send)
+    }
+}
+
+/**
+ * POSTs the given JSON body (with the given extra headers) to the given URL.
+ */
+fn post_json(url: &Url, headers: &[(&str, &str)], body: &str) -> Result<(), NotifyError> {
+    let mut all_headers = vec![("Content-Type", "application/json")];
+    all_headers.extend_from_slice(headers);
+
+    match http::send("POST", url, &all_headers, body.as_bytes(), Duration::from_secs(10)) {
+        Ok(status) if (200..300).contains(&status) => Ok(()),
+        Ok(status) => Err(NotifyError::Rejected(status)),
+        Err(e) => Err(NotifyError::Send(e)),
+    }
+}
+
+/**
+ * Renders a message about the given alert from the given template, replacing
+ * the placeholders
+ *
+ * `{target}`, `{addr}`, `{rule}`, `{column}`, `{state}`: as in the alert
+ * `{value}`: the value that changed its state, e.g. `250.0 ms`, `40% loss`,
+ *           `13 days to expiry` or (for composite rules) `3 down`, along with what was expected of it for
+ *           `anomalous` rules, e.g. `250.0 ms, usually 40.0 ms for a Tuesday 09:00`
+ * `{duration}`: how long since it fired, e.g. `3m 20s` (the outage's length
+ * once resolved)
+ */
+fn render(template: &str, alert: &Alert) -> String {
+    let column = if alert.column.is_empty() { "value" } else { alert.column.as_str() };
+    let state = match alert.state {
+        AlertState::Firing => "firing",
+        AlertState::Resolved => "resolved",
+    };
+    let format_value = |v: i32| if alert.column == "loss" {
+        format!("{}% loss", v)
+    } else if alert.column == EXPIRY_COLUMN {
+        format!("{} days to expiry", v)
+    } else if alert.column == DOWN_COLUMN {
+        format!("{} down", v)
+    } else {
+        format!("{:.1} ms", v as f64 / 1000.0)
+    };
+    let mut value = format_value(alert.value);
+    if let Some(expected) = alert.expected {
+        value = match alert.season {
+            Some(ref season) => format!("{}, usually {} for a {}", value, format_value(expected), season),
+            None => format!("{}, usually {}", value, format_value(expected)),
+        };
+    }
+
+    template
+        .replace("{target}", &alert.target)
+        .replace("{addr}", &alert.addr)
+        .replace("{rule}", &alert.rule)
+        .replace("{column}", column)
+        .replace("{state}", state)
+        .replace("{value}", &value)
+        .replace("{duration}", &format_duration(alert.since - alert.fired))
+}
+
+/**
+ * Formats the given number of seconds as its two most significant units,
+ * e.g. `1h 5m`.
+ */
+pub fn format_duration(secs: i64) -> String {
+    let secs = secs.max(0);
+    let (d, h, m, s) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if d > 0 {
+        format!("{}d {}h", d, h)
+    } else if h > 0 {
+        format!("{}h {}m", h, m)
+    } else if m > 0 {
+        format!("{}m {}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+#[test]
+fn render_fills_in_placeholders() {
+    let alert = Alert {
+        target: "tcpping".to_owned(),
+        addr: "vpn:443".to_owned(),
+        rule: "slow".to_owned(),
+        column: "".to_owned(),
+        state: AlertState::Resolved,
+        since: 1_500_000_200,
+        value: 12_345,
+        fired: 1_500_000_000,
+        expected: None,
+        season: None,
+        notified: None,
+        notified_at: 0,
+        suppressed_by: None,
+        acknowledged: None,
+    };
+
+    assert_eq!(render("{addr} ({target}) {rule} {state} after {duration}, now {value}", &alert),
+               "vpn:443 (tcpping) slow resolved after 3m 20s, now 12.3 ms");
+
+    let anomaly = Alert {
+        expected: Some(4_000),
+        season: Some("Tuesday 09:00".to_owned()),
+        ..alert
+    };
+    assert_eq!(render("{value}", &anomaly), "12.3 ms, usually 4.0 ms for a Tuesday 09:00");
+}
@@ -0,0 +1,223 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * NTP clock offset data collection, asking NTP servers for the time (as an
+ * SNTP client, RFC 4330) to measure how far the clock of this host is off,
+ * since its drift skews the timestamps of every other series.
+ */
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::options::{TargetKind, AHEAD_COLUMN, BEHIND_COLUMN, SENTINEL_ERROR, SENTINEL_REFUSED, SENTINEL_RESOLVE,
+                     SENTINEL_TIMEOUT, io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::resolve;
+use crate::sockopt;
+use crate::worker::ProbeSettings;
+
+/**
+ * Seconds from the NTP epoch (1900) to the Unix one.
+ */
+const NTP_TO_UNIX: i64 = 2_208_988_800;
+
+const DEFAULT_PORT: u16 = 123;
+
+pub static KIND: TargetKind = TargetKind::of(&Ntp);
+
+/**
+ * The NTP target, asking the server of each addr (a `host`, or `host:port` if
+ * not on port 123) for the time.
+ *
+ * Returns the offset of the local clock from the server's (whichever way),
+ * followed by the round-trip delay to the server, and the offset again as
+ * `ahead` (if the local clock is ahead) or `behind` (if it is behind), the
+ * other being 0.
+ *
+ * The sign is kept apart since every value stored is non-negative, negative
+ * ones being the sentinels of failures (which the primary value is checked
+ * for everywhere), so an offset can't be recorded as one. The metrics exports
+ * join it back up (see `signed_offset`), and alert rules wanting to know
+ * which way the clock is off watch `ahead` or `behind` rather than the
+ * primary value.
+ */
+pub struct Ntp;
+
+impl Probe for Ntp {
+    type Target = SocketAddr;
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 8,
+        name: "ntp",
+        columns: &["", "delay", AHEAD_COLUMN, BEHIND_COLUMN],
+        units: &[],
+        default_timeout: 5_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &["pool.ntp.org", "time.google.com"],
+        default_interval: 64_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<SocketAddr, i32> {
+        let (host, port) = self.host_of(addr).ok_or(SENTINEL_ERROR)?;
+        resolve::resolve(&host, port, settings).ok_or(SENTINEL_RESOLVE)
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        match resolve::split_host_port(addr) {
+            Some((host, port)) => Some((host.to_owned(), port)),
+            None if !addr.is_empty() && !addr.contains(' ') => Some((addr.to_owned(), DEFAULT_PORT)),
+            None => None,
+        }
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.ip())
+    }
+
+    fn probe_once(&self, _: &str, sock_addr: SocketAddr, attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        ntp_once(sock_addr, attempt.settings)
+    }
+}
+
+/**
+ * Converts the given time since the Unix epoch (in nanoseconds) to an NTP
+ * timestamp (seconds since 1900, in 32.32 fixed point).
+ */
+fn to_ntp(nanos: i64) -> u64 {
+    let secs = nanos.div_euclid(1_000_000_000) + NTP_TO_UNIX;
+    let frac = ((nanos.rem_euclid(1_000_000_000) as u64) << 32) / 1_000_000_000;
+    (secs as u64) << 32 | frac
+}
+
+/**
+ * Converts the given NTP timestamp to the time since the Unix epoch (in
+ * nanoseconds), taking timestamps with the top bit clear to be of the era
+ * starting in 2036 (RFC 4330).
+ */
+fn from_ntp(ts: u64) -> i64 {
+    let mut secs = (ts >> 32) as i64;
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+    let frac = (((ts & 0xffff_ffff) * 1_000_000_000) >> 32) as i64;
+    (secs - NTP_TO_UNIX) * 1_000_000_000 + frac
+}
+
+fn unix_nanos(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+/**
+ * Builds a client request (version 4, mode 3) sent at the given NTP time.
+ */
+pub fn request(transmit: u64) -> [u8; 48] {
+    let mut packet = [0u8; 48];
+    packet[0] = 4 << 3 | 3;
+    packet[40..48].copy_from_slice(&transmit.to_be_bytes());
+    packet
+}
+
+/**
+ * Gets the offset of the local clock from the server's and the round-trip
+ * delay (in nanoseconds) from the given reply to the request sent at the
+ * given NTP time (`t1`) and received back at the given time (`t4`, since the
+ * Unix epoch in nanoseconds), or the sentinel to record if it isn't a valid
+ * reply to it.
+ */
+pub fn offset_and_delay(reply: &[u8], t1: u64, t4: i64) -> Result<(i64, i64), i32> {
+    let timestamp = |i: usize| u64::from_be_bytes([reply[i], reply[i + 1], reply[i + 2], reply[i + 3],
+                                                   reply[i + 4], reply[i + 5], reply[i + 6], reply[i + 7]]);
+    if reply.len() < 48 || reply[0] & 0x07 != 4 || timestamp(24) != t1 {
+        return Err(SENTINEL_ERROR);
+    }
+    let (leap, stratum) = (reply[0] >> 6, reply[1]);
+    if stratum == 0 {
+        // a "kiss-o'-death", turning us away
+        return Err(SENTINEL_REFUSED);
+    }
+    if leap == 3 || stratum >= 16 {
+        // the server's own clock isn't synchronized
+        return Err(SENTINEL_ERROR);
+    }
+
+    let (t1, t2, t3) = (from_ntp(t1), from_ntp(timestamp(32)), from_ntp(timestamp(40)));
+    // the server's view of the time, less ours
+    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+    let delay = (t4 - t1) - (t3 - t2);
+    Ok((-offset, delay.max(0)))
+}
+
+/**
+ * Asks the NTP server at the given address for the time once.
+ */
+fn ntp_once(sock_addr: SocketAddr, settings: &ProbeSettings) -> Result<Vec<u64>, i32> {
+    let socket = sockopt::udp_socket(&sock_addr, settings).map_err(|_| SENTINEL_ERROR)?;
+    socket.set_read_timeout(Some(settings.timeout)).map_err(|_| SENTINEL_ERROR)?;
+    socket.connect(sock_addr).map_err(|e| io_error_sentinel(&e))?;
+
+    let sent_at = unix_nanos(SystemTime::now());
+    let start = Instant::now();
+    let t1 = to_ntp(sent_at);
+    socket.send(&request(t1)).map_err(|e| io_error_sentinel(&e))?;
+
+    let deadline = start + settings.timeout;
+    let mut buf = [0u8; 512];
+    loop {
+        let len = socket.recv(&mut buf).map_err(|e| io_error_sentinel(&e))?;
+        // by the monotonic clock, so that the clock being stepped meanwhile can't skew the delay
+        let t4 = sent_at + start.elapsed().as_nanos() as i64;
+        match offset_and_delay(&buf[..len], t1, t4) {
+            Ok((offset, delay)) => {
+                let magnitude = offset.unsigned_abs();
+                let (ahead, behind) = if offset > 0 { (magnitude, 0) } else { (0, magnitude) };
+                return Ok(vec![magnitude, delay as u64, ahead, behind]);
+            },
+            // a stray datagram, keep waiting for the reply
+            Err(s) if s == SENTINEL_ERROR && (len < 48 || buf[24..32] != t1.to_be_bytes()) => (),
+            Err(s) => return Err(s),
+        }
+        if Instant::now() >= deadline {
+            return Err(SENTINEL_TIMEOUT);
+        }
+    }
+}
+
+#[test]
+fn replies_give_offset_and_delay() {
+    let now = 1_760_000_000_123_456_789;
+    assert!((from_ntp(to_ntp(now)) - now).abs() < 2);
+    // timestamps past the 2036 rollover
+    assert_eq!(from_ntp(5u64 << 32), ((1i64 << 32) + 5 - NTP_TO_UNIX) * 1_000_000_000);
+    assert_eq!(request(42)[0], 0x23);
+
+    // sent at t1, received by the server at t2 and sent back at t3: our clock is 30ms behind, 20ms round-trip
+    let t1 = now;
+    let reply = |stratum: u8, origin: u64| {
+        let mut r = [0u8; 48];
+        r[0] = 4 << 3 | 4;
+        r[1] = stratum;
+        r[24..32].copy_from_slice(&origin.to_be_bytes());
+        r[32..40].copy_from_slice(&to_ntp(t1 + 40_000_000).to_be_bytes());
+        r[40..48].copy_from_slice(&to_ntp(t1 + 40_000_000 + 1_000_000).to_be_bytes());
+        r
+    };
+    let (offset, delay) = offset_and_delay(&reply(2, to_ntp(t1)), to_ntp(t1), t1 + 21_000_000).unwrap();
+    assert!((offset + 30_000_000).abs() < 10 && (delay - 20_000_000).abs() < 10);
+
+    assert_eq!(offset_and_delay(&reply(0, to_ntp(t1)), to_ntp(t1), t1), Err(SENTINEL_REFUSED));
+    assert_eq!(offset_and_delay(&reply(16, to_ntp(t1)), to_ntp(t1), t1), Err(SENTINEL_ERROR));
+    assert_eq!(offset_and_delay(&reply(2, 7), to_ntp(t1), t1), Err(SENTINEL_ERROR));
+
+    assert_eq!(Ntp.host_of("time.google.com"), Some(("time.google.com".to_owned(), 123)));
+    assert_eq!(Ntp.host_of("[::1]:1123"), Some(("::1".to_owned(), 1123)));
+}
@@ -6,90 +6,979 @@
  * details.
  */
 
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::sync::Arc;
 use std::sync::mpsc::Sender;
 
+use crate::helpers::PushRawBytes;
 use crate::persist::{TargetManager, ManagerError};
-use crate::tcpping::run_tcpping_worker;
+use crate::pool::ThreadPool;
+use crate::proxy::Proxy;
+use crate::sockopt::{Dscp, TcpOptions};
+use crate::probe::{AnyProbe, Probe, ProbeSchema, Unit};
+use crate::worker;
+use crate::{tcpping, icmp, httpping, dns, udpping, traceroute, tls, exec, ntp, throughput, speedtest, quic, grpc};
+use crate::tags::{Tags, GROUP_TAG};
 
+use chrono::{DateTime, Datelike, Local, Timelike};
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TargetOptions {
     pub nonce: i32,
     pub addrs: Vec<String>,  // Vec of addresses (IPs to hit with TCP, files to download, etc.)
     pub interval: u32,  // interval between collection attempts, in millis
     pub avg_across: u32,  // number of sub-attempts average across for each interval
     pub pause: u32,  // pause between sub-attempts, in millis
+    #[serde(default)]
+    pub raw_samples: bool,  // whether to also store every sub-attempt (see `TargetKind::columns`)
+    #[serde(default)]
+    pub timeout: Option<u32>,  // time to give each sub-attempt, in millis (kind's default if None)
+    #[serde(default = "default_resolve_ttl")]
+    pub resolve_ttl: u32,  // time to reuse resolved host names for, in millis (0 to never reuse)
+    #[serde(default)]
+    pub family: AddressFamily,  // address family (or families) host names are probed over
+    #[serde(default)]
+    pub source: Option<String>,  // IP or interface probes are sent from (see `source.rs`), the configuration's if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<Proxy>,  // proxy the connections of TCP-based probes are tunneled through (see `proxy.rs`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<Dscp>,  // DSCP the packets of probes are marked with (see `sockopt.rs`)
+    #[serde(default)]
+    pub tcp: TcpOptions,  // options of the TCP connections of probes (see `sockopt.rs`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load: Option<String>,  // Throughput address loading the link for a second set of attempts (see `worker::loaded`)
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,  // rules evaluated against every address (see `alerts.rs`)
+    #[serde(default = "default_down_after")]
+    pub down_after: u32,  // failed rounds in a row after which an address is down (see `incidents.rs`)
+    #[serde(default)]
+    pub phase: u32,  // offset of the rounds into each interval, in millis
+    #[serde(default)]
+    pub start_jitter: u32,  // longest random delay of each round past its deadline, in millis
+    #[serde(default)]
+    pub paused: bool,  // whether rounds are skipped (recording `SENTINEL_PAUSED`) instead of run
+    #[serde(default)]
+    pub maintenance: Vec<MaintenanceWindow>,  // recurring windows in which rounds are skipped likewise
+    #[serde(default)]
+    pub down_interval: Option<u32>,  // interval after a failed round of an address, in millis (`interval` if None)
+    #[serde(default)]
+    pub aggregate: Aggregate,  // how the sub-attempts of a round are aggregated into its values
+    #[serde(default)]
+    pub aggregates: Vec<Aggregate>,  // further aggregates of the primary value, each as its own column
+    #[serde(default)]
+    pub retention: Option<u32>,  // days to keep collected data for, before it is pruned (forever if None)
+    #[serde(default)]
+    pub compress_after: Option<u32>,  // days after which collected data is compressed (never if None)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, Tags>,  // tags of the addresses (see `tags.rs`), by address
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<String, String>,  // display names of the addresses (e.g. "Office VPN gateway"), by address
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub notes: BTreeMap<String, String>,  // free-form notes on the addresses, by address
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sites: BTreeMap<String, String>,  // sites of the addresses pushed by agents (not probed here), by address
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,  // addresses (`<target>/<addr>`) whose incidents suppress alerts of the others
+    #[serde(default)]
+    pub fan_out: bool,  // whether host names are also probed at each IP they resolve to (see `fanout.rs`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fanned: BTreeMap<String, String>,  // addresses fanned out from the others, to the address each is of, by address
+}
+
+impl TargetOptions {
+    /**
+     * Whether the given (local) time falls into any of the target's
+     * maintenance windows.
+     */
+    pub fn in_maintenance(&self, time: &DateTime<Local>) -> bool {
+        let day = time.weekday().num_days_from_monday();
+        let minute = time.hour() * 60 + time.minute();
+        self.maintenance.iter().any(|w| w.contains(day, minute))
+    }
+
+    /**
+     * Gets the addresses (as `<target>/<addr>`) the given address of the given
+     * target (of these options) depends on, i.e. all of `depends_on` but
+     * itself.
+     */
+    pub fn parents_of<'a>(&'a self, target: &'a str, addr: &'a str) -> impl Iterator<Item = &'a String> {
+        self.depends_on.iter().filter(move |p| p.split_once('/') != Some((target, addr)))
+    }
+
+    /**
+     * Gets the tags of the given address, if it has any.
+     */
+    pub fn tags_of(&self, addr: &str) -> Option<&Tags> {
+        self.tags.get(addr)
+    }
+
+    /**
+     * Whether the given address is pushed by an agent (see `agent.rs`) rather
+     * than probed here.
+     */
+    pub fn is_remote(&self, addr: &str) -> bool {
+        self.sites.contains_key(addr)
+    }
+
+    /**
+     * Gets the address the given address was fanned out from (see
+     * `fanout.rs`) and the IP it is probed at, if it was.
+     */
+    pub fn fanned_out(&self, addr: &str) -> Option<(&str, IpAddr)> {
+        let parent = self.fanned.get(addr)?;
+        let ip = addr.rsplit_once(" at ")?.1.parse().ok()?;
+        Some((parent, ip))
+    }
+
+    /**
+     * Checks that these options make sense to probe with, returning what's
+     * wrong with them if not.
+     */
+    pub fn check(&self) -> Result<(), String> {
+        if self.interval < MIN_INTERVAL {
+            return Err(format!("interval must be at least {}ms", MIN_INTERVAL));
+        }
+        if self.down_interval.is_some_and(|d| d < MIN_INTERVAL) {
+            return Err(format!("down_interval must be at least {}ms", MIN_INTERVAL));
+        }
+        Ok(())
+    }
+
+    /**
+     * Forgets the tags, display name, notes, site and parent of the given
+     * address.
+     */
+    pub fn forget_addr(&mut self, addr: &str) {
+        self.tags.remove(addr);
+        self.names.remove(addr);
+        self.notes.remove(addr);
+        self.sites.remove(addr);
+        self.fanned.remove(addr);
+    }
+}
+
+/**
+ * Shortest interval (and `down_interval`) rounds are run at, in millis.
+ */
+pub const MIN_INTERVAL: u32 = 1_000;
+
+fn default_down_after() -> u32 {
+    3
+}
+
+fn default_resolve_ttl() -> u32 {
+    60_000
+}
+
+/**
+ * Increments (wrapping around if necessary) the given nonce.
+ */
+pub fn next_nonce(nonce: i32) -> i32 {
+    let (n, over) = nonce.overflowing_add(1);
+    if over {
+        0
+    } else {
+        n
+    }
+}
+
+/**
+ * Options of a target declared in the configuration file. Those given take
+ * precedence over the persisted ones, the rest are left as they are.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TargetDeclaration {
+    pub addrs: Option<Vec<String>>,
+    pub interval: Option<u32>,
+    pub avg_across: Option<u32>,
+    pub pause: Option<u32>,
+    pub raw_samples: Option<bool>,
+    pub timeout: Option<u32>,
+    pub resolve_ttl: Option<u32>,
+    pub family: Option<AddressFamily>,
+    pub source: Option<String>,
+    pub proxy: Option<Proxy>,
+    pub dscp: Option<Dscp>,
+    pub tcp: Option<TcpOptions>,
+    pub load: Option<String>,
+    pub alerts: Option<Vec<AlertRule>>,
+    pub down_after: Option<u32>,
+    pub phase: Option<u32>,
+    pub start_jitter: Option<u32>,
+    pub paused: Option<bool>,
+    pub maintenance: Option<Vec<MaintenanceWindow>>,
+    pub down_interval: Option<u32>,
+    pub aggregate: Option<Aggregate>,
+    pub aggregates: Option<Vec<Aggregate>>,
+    pub retention: Option<u32>,
+    pub compress_after: Option<u32>,
+    pub tags: Option<BTreeMap<String, Tags>>,
+    pub names: Option<BTreeMap<String, String>>,
+    pub notes: Option<BTreeMap<String, String>>,
+    pub depends_on: Option<Vec<String>>,
+    pub fan_out: Option<bool>,
+}
+
+impl TargetDeclaration {
+    /**
+     * Returns the given options (with the next nonce) updated with those
+     * declared, or None if they already match.
+     */
+    pub fn apply_to(&self, options: &TargetOptions) -> Option<TargetOptions> {
+        let mut new = options.clone();
+        if let Some(ref a) = self.addrs {
+            // addresses pushed by agents (or fanned out) aren't declared, so keep them
+            new.addrs = a.clone();
+            new.addrs.extend(options.addrs.iter()
+                .filter(|r| (options.is_remote(r) || options.fanned.contains_key(*r)) && !a.contains(r))
+                .cloned());
+        }
+        new.interval = self.interval.unwrap_or(new.interval);
+        new.avg_across = self.avg_across.unwrap_or(new.avg_across);
+        new.pause = self.pause.unwrap_or(new.pause);
+        new.raw_samples = self.raw_samples.unwrap_or(new.raw_samples);
+        new.timeout = self.timeout.or(new.timeout);
+        new.resolve_ttl = self.resolve_ttl.unwrap_or(new.resolve_ttl);
+        new.family = self.family.unwrap_or(new.family);
+        new.source = self.source.clone().or(new.source);
+        new.proxy = self.proxy.clone().or(new.proxy);
+        new.dscp = self.dscp.or(new.dscp);
+        new.tcp = self.tcp.unwrap_or(new.tcp);
+        new.load = self.load.clone().or(new.load);
+        if let Some(ref a) = self.alerts {
+            new.alerts = a.clone();
+        }
+        new.down_after = self.down_after.unwrap_or(new.down_after);
+        new.phase = self.phase.unwrap_or(new.phase);
+        new.start_jitter = self.start_jitter.unwrap_or(new.start_jitter);
+        new.paused = self.paused.unwrap_or(new.paused);
+        if let Some(ref m) = self.maintenance {
+            new.maintenance = m.clone();
+        }
+        new.down_interval = self.down_interval.or(new.down_interval);
+        new.aggregate = self.aggregate.unwrap_or(new.aggregate);
+        if let Some(ref a) = self.aggregates {
+            new.aggregates = a.clone();
+        }
+        new.retention = self.retention.or(new.retention);
+        new.compress_after = self.compress_after.or(new.compress_after);
+        if let Some(ref t) = self.tags {
+            new.tags = t.clone();
+        }
+        if let Some(ref n) = self.names {
+            new.names = n.clone();
+        }
+        if let Some(ref n) = self.notes {
+            new.notes = n.clone();
+        }
+        if let Some(ref d) = self.depends_on {
+            new.depends_on = d.clone();
+        }
+        new.fan_out = self.fan_out.unwrap_or(new.fan_out);
+
+        if new == *options {
+            None
+        } else {
+            new.nonce = next_nonce(options.nonce);
+            Some(new)
+        }
+    }
+}
+
+/**
+ * A rule raising an alert for an address once the value of one of its (probe
+ * or `loss`) columns has been `above`/`below` the threshold for `rounds`
+ * consecutive rounds, resolving it once it hasn't been for `resolve_rounds`.
+ * Thresholds are in the column's own unit (microseconds for times, percent
+ * for `loss`). Notifications of an alert are at least `renotify_interval`
+ * seconds apart, so that a flapping one is only notified of once in a while.
+ *
+ * Rules `anomalous` instead breach when the value is more than `sigmas`
+ * standard deviations above what the learned `baseline` (see `anomaly.rs`)
+ * expects, and by more than the threshold.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    #[serde(default)]
+    pub column: String,  // name of the column to watch (the primary one if empty)
+    pub when: AlertCondition,
+    #[serde(default)]
+    pub threshold: i32,
+    #[serde(default = "default_alert_rounds")]
+    pub rounds: u32,
+    #[serde(default = "default_alert_rounds")]
+    pub resolve_rounds: u32,
+    #[serde(default)]
+    pub renotify_interval: u32,
+    #[serde(default)]
+    pub baseline: Baseline,  // what `anomalous` rules expect
+    #[serde(default = "default_sigmas")]
+    pub sigmas: f64,  // how far above what's expected `anomalous` rules breach
+}
+
+/**
+ * A rule raising an alert once at least `at_least` of its members (of any
+ * targets) are down (see `incidents.rs`), e.g. most of a few references on
+ * the internet, pointing at the WAN rather than at any of them: the addresses
+ * listed as `<target>/<addr>`, and those in the given group.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompositeRule {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub at_least: u32,
+    #[serde(default)]
+    pub renotify_interval: u32,
+}
+
+impl CompositeRule {
+    /**
+     * Whether the given address of the given target (with the given tags) is
+     * a member.
+     */
+    pub fn has_member(&self, target: &str, addr: &str, tags: Option<&Tags>) -> bool {
+        self.members.iter().any(|m| m.split_once('/') == Some((target, addr))) ||
+            self.group.as_ref().is_some_and(|g| tags.and_then(|t| t.get(GROUP_TAG)) == Some(g))
+    }
+}
+
+/**
+ * A digest of every target (see `digest.rs`) over the last day (or week),
+ * delivered through the notifiers every day (or every week on the given day)
+ * at the given time of day.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DigestConfiguration {
+    #[serde(default)]
+    pub every: DigestPeriod,
+    #[serde(default = "default_digest_at")]
+    pub at: TimeOfDay,
+    #[serde(default = "default_digest_on")]
+    pub on: Weekday,  // for weekly digests
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestPeriod {
+    #[default]
+    Day,
+    Week,
+}
+
+impl DigestPeriod {
+    pub fn secs(&self) -> i64 {
+        match *self {
+            DigestPeriod::Day => 86400,
+            DigestPeriod::Week => 7 * 86400,
+        }
+    }
+}
+
+fn default_digest_at() -> TimeOfDay {
+    TimeOfDay(8 * 60)
+}
+
+fn default_digest_on() -> Weekday {
+    Weekday::Mon
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertCondition {
+    Above,
+    Below,
+    Anomalous,
+}
+
+/**
+ * What an `anomalous` rule learns to expect of a value: its exponentially
+ * weighted moving average (and variance) over recent rounds, or its profile
+ * by hour of the week (e.g. Tuesdays from 9:00 to 10:00), learned over past
+ * weeks.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Baseline {
+    #[default]
+    Ewma,
+    Seasonal,
+}
+
+fn default_sigmas() -> f64 {
+    3.0
+}
+
+/**
+ * A function aggregating the (successful) sub-attempts of a round into a
+ * single value.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    #[default]
+    Mean,
+    Median,
+    Min,
+    Max,
+    P95,
+    P99,
+}
+
+impl Aggregate {
+    /**
+     * Name of the aggregate, also naming its column (see `TargetKind::columns`).
+     */
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Aggregate::Mean => "mean",
+            Aggregate::Median => "median",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::P95 => "p95",
+            Aggregate::P99 => "p99",
+        }
+    }
+}
+
+/**
+ * The address family (or families) over which the host names of a target's
+ * addresses are probed: whichever the resolver returns first (`any`), only
+ * IPv4 or IPv6, or `both`, probing each address over each family in every
+ * attempt, or `happy_eyeballs`, racing each attempt over IPv6 against one
+ * over IPv4 like dual-stack clients do (see `TargetKind::columns`). Over a
+ * family a host (or IP literal) has no address of, attempts fail with
+ * `SENTINEL_RESOLVE`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+    Both,
+    #[serde(rename = "happy_eyeballs")]
+    HappyEyeballs,
+}
+
+impl AddressFamily {
+    /**
+     * Number of attempts made over (each of) the families in each of the
+     * round's attempts, each counting towards the round's values.
+     */
+    pub fn probes_per_attempt(&self) -> usize {
+        match *self {
+            AddressFamily::Both => 2,
+            _ => 1,
+        }
+    }
+
+    /**
+     * Whether the primary value of the attempts over each family is kept
+     * apart as well (see `FAMILY_COLUMNS`).
+     */
+    pub fn keeps_apart(&self) -> bool {
+        matches!(*self, AddressFamily::Both | AddressFamily::HappyEyeballs)
+    }
+
+    /**
+     * Whether the given resolved address is of this family.
+     */
+    pub fn admits(&self, addr: &std::net::SocketAddr) -> bool {
+        match *self {
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+            _ => true,
+        }
+    }
+}
+
+/**
+ * A recurring (weekly) window of local time in which a target's rounds are
+ * skipped (recording `SENTINEL_MAINTENANCE`), e.g. for scheduled reboots. The
+ * window starts at `start` on each of `days` (every day if empty), and ends at
+ * `end` on the same day, or on the next one if `end` isn't after `start`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceWindow {
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+impl MaintenanceWindow {
+    /**
+     * Whether the given minute of the given day (0 being Monday) falls into
+     * this window.
+     */
+    fn contains(&self, day: u32, minute: u32) -> bool {
+        let starts_on = |d: u32| self.days.is_empty() || self.days.iter().any(|w| *w as u32 == d);
+        let (start, end) = (self.start.0, self.end.0);
+        if start < end {
+            starts_on(day) && start <= minute && minute < end
+        } else {
+            // the window runs past midnight into the next day
+            (starts_on(day) && start <= minute) || (starts_on((day + 6) % 7) && minute < end)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/**
+ * A time of day, in minutes since midnight, written as `HH:MM`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(pub u32);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        let invalid = || format!("invalid time of day '{}' (expected HH:MM)", s);
+        let (h, m) = s.split_once(':').ok_or_else(invalid)?;
+        // an hour of 1 or 2 digits and a minute of 2 (which parse alone would let be signed)
+        let digits = |p: &str, lens: &[usize]| lens.contains(&p.len()) && p.bytes().all(|b| b.is_ascii_digit());
+        if !digits(h, &[1, 2]) || !digits(m, &[2]) {
+            return Err(invalid());
+        }
+        match (h.parse::<u32>(), m.parse::<u32>()) {
+            (Ok(hh), Ok(mm)) if hh < 24 && mm < 60 => Ok(TimeOfDay(hh * 60 + mm)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> String {
+        format!("{:02}:{:02}", t.0 / 60, t.0 % 60)
+    }
+}
+
+fn default_alert_rounds() -> u32 {
+    1
 }
 
 pub static SENTINEL_ERROR: i32 = -2_100_000_000;
+pub static SENTINEL_NXDOMAIN: i32 = -2_100_000_001;
+pub static SENTINEL_SERVFAIL: i32 = -2_100_000_002;
+pub static SENTINEL_RESOLVE: i32 = -2_100_000_003;
+pub static SENTINEL_REFUSED: i32 = -2_100_000_004;
+pub static SENTINEL_TIMEOUT: i32 = -2_100_000_005;
+pub static SENTINEL_UNREACHABLE: i32 = -2_100_000_006;
+pub static SENTINEL_TLS: i32 = -2_100_000_007;
+pub static SENTINEL_EXIT: i32 = -2_100_000_008;
 pub static SENTINEL_NODATA: i32 = -2_000_000_000;
+pub static SENTINEL_PAUSED: i32 = -2_000_000_001;
+pub static SENTINEL_MAINTENANCE: i32 = -2_000_000_002;
+
+/**
+ * Whether the given primary value is of a round that actually ran (whether it
+ * succeeded or failed), rather than missing (`SENTINEL_NODATA`) or skipped
+ * while paused (`SENTINEL_PAUSED`) or in maintenance (`SENTINEL_MAINTENANCE`).
+ */
+pub fn was_probed(val: i32) -> bool {
+    val != SENTINEL_NODATA && val != SENTINEL_PAUSED && val != SENTINEL_MAINTENANCE
+}
+
+/**
+ * Gets a short name for the class of failure the given sentinel represents.
+ */
+pub fn sentinel_name(sentinel: i32) -> &'static str {
+    match sentinel {
+        s if s == SENTINEL_NXDOMAIN => "nxdomain",
+        s if s == SENTINEL_SERVFAIL => "servfail",
+        s if s == SENTINEL_RESOLVE => "resolve",
+        s if s == SENTINEL_REFUSED => "refused",
+        s if s == SENTINEL_TIMEOUT => "timeout",
+        s if s == SENTINEL_UNREACHABLE => "unreachable",
+        s if s == SENTINEL_TLS => "tls",
+        s if s == SENTINEL_EXIT => "exit",
+        s if s == SENTINEL_PAUSED => "paused",
+        s if s == SENTINEL_MAINTENANCE => "maintenance",
+        _ => "error",
+    }
+}
+
+/**
+ * Gets the sentinel of the class of failure of the given short name (see
+ * `sentinel_name`), falling back on `SENTINEL_ERROR` for unknown names.
+ */
+pub fn sentinel_from_name(name: &str) -> i32 {
+    match name {
+        "nxdomain" => SENTINEL_NXDOMAIN,
+        "servfail" => SENTINEL_SERVFAIL,
+        "resolve" => SENTINEL_RESOLVE,
+        "refused" => SENTINEL_REFUSED,
+        "timeout" => SENTINEL_TIMEOUT,
+        "unreachable" => SENTINEL_UNREACHABLE,
+        "tls" => SENTINEL_TLS,
+        "exit" => SENTINEL_EXIT,
+        "paused" => SENTINEL_PAUSED,
+        "maintenance" => SENTINEL_MAINTENANCE,
+        _ => SENTINEL_ERROR,
+    }
+}
+
+/**
+ * Classifies a failed I/O operation of a probe as the sentinel to record for
+ * it, falling back on `SENTINEL_ERROR` for unclassified failures.
+ */
+pub fn io_error_sentinel(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => SENTINEL_REFUSED,
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => SENTINEL_TIMEOUT,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => SENTINEL_UNREACHABLE,
+        _ => SENTINEL_ERROR,
+    }
+}
 
 /*
- * Data for each address. Structured as:
- * [kind, nonce, timestamp, datapoint1, datapoint2, ...]
+ * Data for each address from one round of collection, where
  *
- * where kind is the kind_id for the TargetKind this result is coming from
- * where nonce determines the state of TargetOptions when these data were collected
- * where timestamp is in seconds from epoch,
+ * kind is the kind_id for the TargetKind this result is coming from
+ * nonce determines the state of TargetOptions when these data were collected
+ * timestamp is in seconds from epoch
  *
- * each datapoint is for each address in TargetOptions.addrs
+ * vals has a datapoint for each column (see TargetKind::columns) of each
+ * address in TargetOptions.addrs, grouped by address
  * (encoding of data inside the i32 is target-defined, or one of the
  * sentinel values for error or nodata),
  */
-pub struct TargetResults(pub Vec<i32>);
+#[derive(Clone)]
+pub struct TargetResults {
+    pub kind: i32,
+    pub nonce: i32,
+    pub timestamp: i64,
+    pub vals: Vec<i32>,
+}
+
+impl TargetResults {
+    /**
+     * Encodes these results for live broadcast as raw bytes: kind, nonce
+     * (32-bit), timestamp (64-bit), and then vals (32-bit each).
+     */
+    pub fn to_raw_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + self.vals.len() * 4);
+        bytes.push_i32(self.kind);
+        bytes.push_i32(self.nonce);
+        bytes.push_i64(self.timestamp);
+        for &v in self.vals.iter() {
+            bytes.push_i32(v);
+        }
+        bytes
+    }
+}
+
+/**
+ * A kind of target, as registered by its `Probe` (see `probe.rs`).
+ */
+pub struct TargetKind {
+    schema: ProbeSchema,
+    probe: &'static dyn AnyProbe,
+}
+
+/**
+ * Every kind of target, in order of kind_id.
+ */
+static ALL_KINDS: [&TargetKind; 13] = [
+    &tcpping::KIND,
+    &icmp::KIND,
+    &httpping::KIND,
+    &dns::KIND,
+    &udpping::KIND,
+    &traceroute::KIND,
+    &tls::KIND,
+    &exec::KIND,
+    &ntp::KIND,
+    &throughput::KIND,
+    &speedtest::KIND,
+    &quic::KIND,
+    &grpc::KIND,
+];
+
+/**
+ * Columns the worker derives from each round of attempts for every address,
+ * regardless of target kind:
+ *
+ * loss: percentage (0 to 100) of the round's attempts that failed
+ * jitter: mean absolute difference between the primary values of consecutive
+ *         successful attempts, in microseconds (missing with fewer than two)
+ */
+pub static ROUND_COLUMNS: [&str; 2] = ["loss", "jitter"];
+
+/**
+ * Columns of the primary value (aggregated like it) of the attempts over each
+ * family alone, for targets probing `both` or `happy_eyeballs` (see
+ * `AddressFamily`). Each holds the sentinel of the family's last attempt if
+ * all of them failed.
+ */
+pub static FAMILY_COLUMNS: [&str; 2] = ["ipv4", "ipv6"];
+
+/**
+ * Columns of targets with a `load`, following all others: the primary value
+ * (aggregated like it) of the attempts made again while the load ran (the
+ * sentinel of the last if all of them failed, missing if the load ended
+ * before any), and how far above the round's own primary value that is (the
+ * bufferbloat, missing unless both were measured).
+ */
+pub static LOAD_COLUMNS: [&str; 2] = ["loaded", "bloat"];
+
+/**
+ * Column of targets probing `happy_eyeballs` holding the percentage (0 to
+ * 100) of the round's successful attempts won by IPv6 (missing if none
+ * succeeded).
+ */
+pub const RACE_COLUMN: &str = "ipv6_wins";
+
+/**
+ * Column of the TLS Handshake target holding the whole days until the first
+ * of the presented certificates expires, rather than a duration.
+ */
+pub const EXPIRY_COLUMN: &str = "expiry";
+
+/**
+ * Columns of the NTP Offset target holding its offset by which way the local
+ * clock is off, its primary value being the offset whichever way (since
+ * negative values are failures, see `ntp.rs`).
+ */
+pub const AHEAD_COLUMN: &str = "ahead";
+pub const BEHIND_COLUMN: &str = "behind";
 
-pub enum TargetKind {
-    TcpPing,
-    HttpDownload,
+/**
+ * Gets the offset (in micros) of the local clock from an NTP server, positive
+ * if it is ahead, out of the given values by column of an address (of the NTP
+ * Offset target, none of the others having its columns), if it measured one.
+ */
+pub fn signed_offset<'a>(values: impl IntoIterator<Item = (&'a str, i32)>) -> Option<i64> {
+    let (mut ahead, mut behind) = (None, None);
+    for (column, val) in values {
+        match column {
+            AHEAD_COLUMN => ahead = Some(val),
+            BEHIND_COLUMN => behind = Some(val),
+            _ => (),
+        }
+    }
+    match (ahead?, behind?) {
+        (a, b) if a >= 0 && b >= 0 => Some(a as i64 - b as i64),
+        _ => None,
+    }
 }
 
-static ALL_KINDS: [TargetKind; 1] = [TargetKind::TcpPing];
+/**
+ * Builds the key under which the data of the given column of the given
+ * address is persisted. The first (unnamed) column of each address is keyed
+ * by the address alone, so single-column kinds are keyed purely by address.
+ */
+pub fn column_key(addr: &str, column: &str) -> String {
+    if column.is_empty() {
+        addr.to_owned()
+    } else {
+        format!("{}\t{}", addr, column)
+    }
+}
 
 impl TargetKind {
-    pub fn kind_id(&self) -> i32 {
-        match *self {
-            TargetKind::TcpPing => 0,
-            TargetKind::HttpDownload => 1
+    pub const fn of<P: Probe + 'static>(probe: &'static P) -> Self {
+        TargetKind {
+            schema: P::SCHEMA,
+            probe,
         }
     }
 
+    pub fn kind_id(&self) -> i32 {
+        self.schema.kind_id
+    }
+
     pub fn compact_name(&self) -> &'static str {
-        match *self {
-            TargetKind::TcpPing => "tcpping",
-            TargetKind::HttpDownload => "httpdownload",
+        self.schema.name
+    }
+
+    /**
+     * Names of the columns of data this kind's probe measures for each
+     * address, the first being its primary value.
+     */
+    pub fn probe_columns(&self) -> &'static [&'static str] {
+        self.schema.columns
+    }
+
+    /**
+     * What the values of the given column (see `columns`) measure: for the
+     * probe's own, what it says (durations unless it says otherwise), for
+     * `loss` and `RACE_COLUMN`, percentages, and for the rest, all derived
+     * from the primary value, the same as it.
+     */
+    pub fn unit_of(&self, column: &str) -> Unit {
+        let declared = |column: &str| self.schema.units.iter()
+            .find(|&&(c, _)| c == column)
+            .map_or(Unit::Duration, |&(_, unit)| unit);
+        if self.schema.columns.contains(&column) {
+            declared(column)
+        } else if column == "loss" || column == RACE_COLUMN {
+            Unit::Gauge
+        } else {
+            declared("")
+        }
+    }
+
+    /**
+     * Time given to each sub-attempt (in millis) when the target's options
+     * don't specify one.
+     */
+    pub fn default_timeout(&self) -> u32 {
+        self.schema.default_timeout
+    }
+
+    /**
+     * Whether this kind maps the path to each address, storing it hop by hop
+     * (see `hops`) alongside its data.
+     */
+    pub fn keeps_hops(&self) -> bool {
+        self.schema.keeps_hops
+    }
+
+    /**
+     * Whether this kind records the IPs its addresses resolved to, round by
+     * round (see `TargetManager::record_ips`).
+     */
+    pub fn keeps_ips(&self) -> bool {
+        self.schema.keeps_ips
+    }
+
+    pub fn probe(&self) -> &'static dyn AnyProbe {
+        self.probe
+    }
+
+    /**
+     * Names of all columns of data collected for each address under the given
+     * options: the probe's own, followed by the `ROUND_COLUMNS` derived by the
+     * worker, followed by a column for each of the further `aggregates` of the
+     * primary value (named after them), followed (if `family` is `both` or
+     * `happy_eyeballs`) by `ipv4` and `ipv6` holding the primary value over
+     * each family alone (and for `happy_eyeballs`, by `RACE_COLUMN`),
+     * followed (if `raw_samples` is set) by `sample1` to `sampleN` holding
+     * the primary value of each of the round's N sub-attempts (of each
+     * family in turn, probing `both`), followed (if there is a `load`) by
+     * the `LOAD_COLUMNS`.
+     */
+    pub fn columns(&self, options: &TargetOptions) -> Vec<String> {
+        let mut columns: Vec<String> = self.probe_columns().iter()
+            .chain(ROUND_COLUMNS.iter())
+            .map(|c| c.to_string())
+            .collect();
+        columns.extend(options.aggregates.iter().map(|a| a.name().to_owned()));
+        if options.family.keeps_apart() {
+            columns.extend(FAMILY_COLUMNS.iter().map(|c| c.to_string()));
+        }
+        if options.family == AddressFamily::HappyEyeballs {
+            columns.push(RACE_COLUMN.to_owned());
+        }
+        if options.raw_samples {
+            let samples = options.avg_across * options.family.probes_per_attempt() as u32;
+            columns.extend((1..=samples).map(|i| format!("sample{}", i)));
+        }
+        if options.load.is_some() {
+            columns.extend(LOAD_COLUMNS.iter().map(|c| c.to_string()));
         }
+        columns
+    }
+
+    /**
+     * Returns the keys (see `column_key`) of all columns for the addresses in
+     * the given options, in the order they appear in `TargetResults`.
+     */
+    pub fn column_keys(&self, options: &TargetOptions) -> Vec<String> {
+        let columns = self.columns(options);
+        let mut keys = Vec::with_capacity(options.addrs.len() * columns.len());
+        for addr in options.addrs.iter() {
+            for column in columns.iter() {
+                keys.push(column_key(addr, column));
+            }
+        }
+        keys
     }
 
     pub fn default_options(&self) -> TargetOptions {
-        match *self {
-            TargetKind::TcpPing => TargetOptions {
-                nonce: 0,
-                addrs: vec!["google.com:80".to_owned(), "8.8.8.8:53".to_owned()],
-                interval: 10_000,
-                avg_across: 3,
-                pause: 100,
-            },
-            _ => unimplemented!()
+        TargetOptions {
+            nonce: 0,
+            addrs: self.schema.default_addrs.iter().map(|a| a.to_string()).collect(),
+            interval: self.schema.default_interval,
+            avg_across: self.schema.default_avg_across,
+            pause: 100,
+            raw_samples: false,
+            timeout: None,
+            resolve_ttl: default_resolve_ttl(),
+            family: AddressFamily::Any,
+            source: None,
+            proxy: None,
+            dscp: None,
+            tcp: TcpOptions::default(),
+            load: None,
+            alerts: Vec::new(),
+            down_after: 3,
+            phase: 0,
+            start_jitter: 0,
+            paused: false,
+            maintenance: Vec::new(),
+            down_interval: None,
+            aggregate: Aggregate::Mean,
+            aggregates: Vec::new(),
+            retention: None,
+            compress_after: None,
+            tags: BTreeMap::new(),
+            names: BTreeMap::new(),
+            notes: BTreeMap::new(),
+            sites: BTreeMap::new(),
+            depends_on: Vec::new(),
+            fan_out: false,
+            fanned: BTreeMap::new(),
         }
     }
 
     pub fn run_worker(&self, manager: Arc<TargetManager>,
-                             results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
-        match *self {
-            TargetKind::TcpPing => run_tcpping_worker(manager, results_out),
-            _ => unimplemented!()
-        }
+                      results_out: Sender<TargetResults>,
+                      pool: Arc<ThreadPool>) -> thread::JoinHandle<()> {
+        worker::run_worker(manager, results_out, pool)
     }
 
-    pub fn new_managers_for_all<'a>(data_path: &'a Path) -> Result<Vec<Arc<TargetManager>>, ManagerError> {
+    /**
+     * Runs a single round of this target immediately (outside of its worker's
+     * schedule), returning its results.
+     */
+    pub fn run_round(&self, manager: &TargetManager) -> TargetResults {
+        worker::run_round(manager)
+    }
+
+    pub fn new_managers_for_all(data_path: &Path,
+                                backend: StorageBackend) -> Result<Vec<Arc<TargetManager>>, ManagerError> {
         let mut targets = Vec::with_capacity(ALL_KINDS.len());
         for k in ALL_KINDS.iter() {
             targets.push(
-                Arc::new(TargetManager::new(k, data_path)?)
+                Arc::new(TargetManager::new(k, data_path, backend)?)
             );
         }
         Ok(targets)
@@ -98,22 +987,740 @@ impl TargetKind {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MainConfiguration {
-    pub web_port: u16,
-    pub ws_port: u16,
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,  // address the web and websockets servers listen on
+    pub web_port: u16,  // 0 to not listen on TCP (e.g. with just unix_socket)
+    pub ws_port: u16,  // 0 to not run the websockets server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix_socket: Option<PathBuf>,  // relative to the configuration file, also served on if given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,  // path the web server is served under, e.g. /stabping/ (/ if None)
+    #[serde(default = "default_probe_threads")]
+    pub probe_threads: usize,  // number of rounds of all targets that may run at once
+    #[serde(default)]
+    pub allow_exec: bool,  // whether the Exec target may run its commands (see `exec.rs`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,  // IP or interface probes are sent from, for targets without their own (see `source.rs`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,  // relative to the configuration file (stabping_data if None)
+    #[serde(default)]
+    pub storage: StorageBackend,  // how the data of each target is stored in the data directory
+    #[serde(default = "default_spool_limit")]
+    pub spool_limit: u64,  // MiB of results each sink pushing them elsewhere may buffer while it can't (see `spool.rs`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub influxdb: Option<InfluxConfiguration>,  // where to push results to, if anywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub graphite: Option<GraphiteConfiguration>,  // where to forward results to, if anywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statsd: Option<StatsdConfiguration>,  // where to emit results to as StatsD metrics, if anywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub otlp: Option<OtlpConfiguration>,  // the OpenTelemetry collector to export metrics to, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nats: Option<NatsConfiguration>,  // the NATS server to publish results to, if any (with the `nats` feature)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kafka: Option<KafkaConfiguration>,  // the Kafka topic to publish results to, if any (with the `kafka` feature)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfiguration>,  // the MQTT broker to publish the state of every address to, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub composite_alerts: Vec<CompositeRule>,  // rules over the addresses of several targets (see `alerts.rs`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub digests: Vec<DigestConfiguration>,  // summaries of every target to deliver through the notifiers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<WebhookConfiguration>,  // where to POST alerts to when they change state
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slack: Vec<ChatConfiguration>,  // Slack incoming webhooks to post alerts to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discord: Vec<ChatConfiguration>,  // Discord webhooks to post alerts to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub telegram: Vec<TelegramConfiguration>,  // Telegram chats to post alerts to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp: Option<SmtpConfiguration>,  // how to email alerts, if at all
+    #[serde(default)]
+    pub public_badges: bool,  // whether /badge/<kind>.svg is served without authorization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_page: Option<StatusPageConfiguration>,  // the public status page to serve, if any (see `status.rs`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfiguration>,  // who may use the web and websockets servers (anyone if None)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https: Option<HttpsConfiguration>,  // how to serve the web server over HTTPS (plain HTTP if None)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<AgentConfiguration>,  // where to push results to as an agent, if anywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agents: Option<AgentsConfiguration>,  // which agents may push results here (any authorized if None)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub targets: BTreeMap<String, TargetDeclaration>,  // declared options, by target kind
+}
+
+/**
+ * How the data of each target is stored: in its data file (`files`, the
+ * default), or in an SQLite database (`sqlite`, with the `sqlite` feature).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Files,
+    Sqlite,
+}
+
+fn default_listen_address() -> String {
+    "0.0.0.0".to_owned()
+}
+
+fn default_probe_threads() -> usize {
+    64
+}
+
+fn default_spool_limit() -> u64 {
+    64
 }
 
 impl Default for MainConfiguration {
     fn default() -> Self {
         MainConfiguration {
+            listen_address: default_listen_address(),
             web_port: 5001,
             ws_port: 5002,
+            unix_socket: None,
+            path_prefix: None,
+            probe_threads: default_probe_threads(),
+            allow_exec: false,
+            source: None,
+            data_dir: None,
+            storage: StorageBackend::Files,
+            spool_limit: default_spool_limit(),
+            influxdb: None,
+            graphite: None,
+            statsd: None,
+            otlp: None,
+            nats: None,
+            kafka: None,
+            mqtt: None,
+            composite_alerts: Vec::new(),
+            digests: Vec::new(),
+            webhooks: Vec::new(),
+            slack: Vec::new(),
+            discord: Vec::new(),
+            telegram: Vec::new(),
+            smtp: None,
+            public_badges: false,
+            status_page: None,
+            auth: None,
+            https: None,
+            agent: None,
+            agents: None,
+            targets: BTreeMap::new(),
+        }
+    }
+}
+
+/**
+ * Prefix of the names of environment variables overriding the configuration.
+ */
+pub static ENV_PREFIX: &str = "STABPING_";
+
+impl MainConfiguration {
+    /**
+     * Gets the path the web server is served under, with leading and trailing
+     * slashes (just `/` by default).
+     */
+    pub fn path_prefix(&self) -> String {
+        let trimmed = self.path_prefix.as_deref().unwrap_or("").trim_matches('/');
+        if trimmed.is_empty() {
+            "/".to_owned()
+        } else {
+            format!("/{}/", trimmed)
+        }
+    }
+
+    /**
+     * Overrides values of the configuration with those of the given
+     * `STABPING_*` environment variables (other variables are ignored),
+     * returning a description of the first variable that couldn't be applied.
+     */
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), String>
+        where I: IntoIterator<Item = (String, String)>
+    {
+        for (name, val) in vars {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(k) => k,
+                None => continue,
+            };
+            let invalid = || format!("Invalid value '{}' of {}", val, name);
+            let unconfigured = |section| format!("{} given but {} isn't configured", name, section);
+            match key {
+                "LISTEN_ADDRESS" => self.listen_address = val.clone(),
+                "WEB_PORT" => self.web_port = val.parse().map_err(|_| invalid())?,
+                "WS_PORT" => self.ws_port = val.parse().map_err(|_| invalid())?,
+                "UNIX_SOCKET" => self.unix_socket = Some(PathBuf::from(&val)),
+                "PATH_PREFIX" => self.path_prefix = Some(val.clone()),
+                "DATA_DIR" => self.data_dir = Some(PathBuf::from(&val)),
+                "STORAGE" => self.storage = match val.as_str() {
+                    "files" => StorageBackend::Files,
+                    "sqlite" => StorageBackend::Sqlite,
+                    _ => return Err(invalid()),
+                },
+                "INFLUXDB_TOKEN" => match self.influxdb {
+                    Some(ref mut c) => c.token = Some(val.clone()),
+                    None => return Err(unconfigured("influxdb")),
+                },
+                "NATS_TOKEN" => match self.nats {
+                    Some(ref mut c) => c.token = Some(val.clone()),
+                    None => return Err(unconfigured("nats")),
+                },
+                "MQTT_PASSWORD" => match self.mqtt {
+                    Some(ref mut c) => c.password = Some(val.clone()),
+                    None => return Err(unconfigured("mqtt")),
+                },
+                "SMTP_PASSWORD" => match self.smtp {
+                    Some(ref mut c) => c.password = Some(val.clone()),
+                    None => return Err(unconfigured("smtp")),
+                },
+                "AUTH_TOKEN" => self.auth.get_or_insert_with(Default::default).tokens.push(val.clone()),
+                "AUTH_READ_TOKEN" => self.auth.get_or_insert_with(Default::default).read_tokens.push(val.clone()),
+                "AGENT_TOKEN" => match self.agent {
+                    Some(ref mut c) => c.token = Some(val.clone()),
+                    None => return Err(unconfigured("agent")),
+                },
+                "AUTH_PASSWORD" => match self.auth {
+                    Some(ref mut c) => c.password = Some(val.clone()),
+                    None => return Err(unconfigured("auth")),
+                },
+                // read by the command line (as defaults of --log-level and --log-format)
+                "LOG_LEVEL" | "LOG_FORMAT" => {},
+                _ => return Err(format!("Unknown environment variable {}", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Where and how to push results to InfluxDB. Results are written to `bucket`
+ * (authenticating with `token`) of `org` through the v2 API if a `bucket` is
+ * given, and otherwise to `database` (authenticating with `username` and
+ * `password`, if given) through the v1 API.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InfluxConfiguration {
+    pub url: String,  // base URL of the InfluxDB server, e.g. http://localhost:8086
+    #[serde(default = "default_influx_measurement")]
+    pub measurement: String,
+    #[serde(default)]
+    pub database: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub org: Option<String>,
+    #[serde(default)]
+    pub bucket: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+fn default_influx_measurement() -> String {
+    "stabping".to_owned()
+}
+
+/**
+ * What the public status page shows: under which title, the addresses of
+ * which groups (see `GROUP_TAG`; all, if none are given), and over how many
+ * hours of recent rounds.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusPageConfiguration {
+    #[serde(default = "default_status_title")]
+    pub title: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default = "default_status_hours")]
+    pub hours: u32,
+}
+
+fn default_status_title() -> String {
+    "Status".to_owned()
+}
+
+fn default_status_hours() -> u32 {
+    24
+}
+
+impl Default for StatusPageConfiguration {
+    fn default() -> Self {
+        StatusPageConfiguration {
+            title: default_status_title(),
+            groups: Vec::new(),
+            hours: default_status_hours(),
         }
     }
 }
 
+/**
+ * Where to emit results to as StatsD metrics (over UDP), under which metric
+ * name prefix, and whether with DogStatsD tags (including the given ones on
+ * every metric) rather than addresses in metric names.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsdConfiguration {
+    pub address: String,  // host:port of the StatsD server (or Datadog agent), e.g. localhost:8125
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub dogstatsd: bool,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+fn default_statsd_prefix() -> String {
+    "stabping".to_owned()
+}
+
+/**
+ * Where and how to export metrics to an OpenTelemetry collector over OTLP:
+ * POSTed to `<endpoint>/v1/metrics` every `interval` seconds, as protobuf
+ * (`http/protobuf`, the default) or JSON (`http/json`), with any extra
+ * headers to send (e.g. for authentication) and attributes to describe this
+ * stabping with (besides `service.name`).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OtlpConfiguration {
+    pub endpoint: String,  // base URL of the collector's OTLP/HTTP receiver, e.g. http://localhost:4318
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+    #[serde(default = "default_otlp_interval")]
+    pub interval: u32,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub resource_attributes: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum OtlpProtocol {
+    #[default]
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+    #[serde(rename = "http/json")]
+    HttpJson,
+    #[serde(rename = "grpc")]
+    Grpc,  // not supported (there being no HTTP/2 client), only recognized to say as much
+}
+
+fn default_otlp_interval() -> u32 {
+    60
+}
+
+/**
+ * Where to forward results to Graphite, and under which metric path prefix.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GraphiteConfiguration {
+    pub address: String,  // host:port of the Carbon plaintext listener, e.g. localhost:2003
+    #[serde(default = "default_graphite_prefix")]
+    pub prefix: String,
+}
+
+fn default_graphite_prefix() -> String {
+    "stabping".to_owned()
+}
+
+/**
+ * A URL to POST alerts to as JSON, with any extra headers to send (e.g. for
+ * authentication).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookConfiguration {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/**
+ * Templates of the messages posted to chat services about alerts firing and
+ * resolving (see `notify::render` for the placeholders available).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageTemplates {
+    #[serde(default = "default_firing_template")]
+    pub firing_template: String,
+    #[serde(default = "default_resolved_template")]
+    pub resolved_template: String,
+}
+
+fn default_firing_template() -> String {
+    "{target} {addr}: {rule} firing ({value})".to_owned()
+}
+
+fn default_resolved_template() -> String {
+    "{target} {addr}: {rule} resolved after {duration} ({value})".to_owned()
+}
+
+/**
+ * How rounds published to a message bus are encoded: as JSON (like the live
+ * results stream), or as protobuf (see `stabping.proto`).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PublishFormat {
+    #[default]
+    Json,
+    Protobuf,
+}
+
+fn default_publish_name() -> String {
+    "stabping".to_owned()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/**
+ * The NATS server (`nats://host:port`, or `tls://host:port` over TLS) to
+ * publish every round to, on `<subject>.<target>`, authenticating with the
+ * given token or username and password, if any.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NatsConfiguration {
+    pub server: String,
+    #[serde(default = "default_publish_name")]
+    pub subject: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub format: PublishFormat,
+}
+
+/**
+ * The Kafka brokers (`host:port`, any of which is asked for the leader of the
+ * partition) and the topic and partition to publish every round to, keyed by
+ * its target.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KafkaConfiguration {
+    pub brokers: Vec<String>,
+    #[serde(default = "default_publish_name")]
+    pub topic: String,
+    #[serde(default)]
+    pub partition: i32,
+    #[serde(default)]
+    pub format: PublishFormat,
+}
+
+/**
+ * The MQTT broker (`mqtt://host:port`, or `mqtts://host:port` over TLS) to
+ * publish the latest state of every address to, under `<topic>/<target>/<addr>`,
+ * authenticating with the given username and password, if any. With
+ * `discovery` (Home Assistant's discovery prefix, e.g. `homeassistant`), each
+ * address is also announced as sensors to Home Assistant.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttConfiguration {
+    pub broker: String,
+    #[serde(default = "default_publish_name")]
+    pub topic: String,
+    #[serde(default = "default_publish_name")]
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default = "default_true")]
+    pub retain: bool,  // whether the broker keeps the last state for new subscribers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discovery: Option<String>,
+}
+
+/**
+ * A Slack or Discord webhook URL to post alerts to.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatConfiguration {
+    pub url: String,
+    #[serde(flatten)]
+    pub templates: MessageTemplates,
+}
+
+/**
+ * A Telegram bot (by its token) and the chat it should post alerts to.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TelegramConfiguration {
+    pub token: String,
+    pub chat_id: String,
+    #[serde(flatten)]
+    pub templates: MessageTemplates,
+}
+
+/**
+ * Who may use the web interface, API and websockets server: anyone presenting
+ * one of the `tokens` (as `Authorization: Bearer <token>`), or the `username`
+ * and `password` by basic auth, if given. Those presenting one of the
+ * `read_tokens` may only view (not change) targets and their data.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuthConfiguration {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read_tokens: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/**
+ * Serving the web server over HTTPS, with the certificate (chain) and private
+ * key in the given PEM files (relative to the configuration file). If
+ * `self_signed`, a certificate is made for `localhost`, the host's name and the
+ * given `names` when there is none yet (by default in the data directory).
+ * Clients may present a certificate issued by (or being one of) those in
+ * `client_ca`, identifying them by its DNS names (see `agent.rs`).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HttpsConfiguration {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    #[serde(default)]
+    pub self_signed: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub names: Vec<String>,  // host names or addresses the web server is reached by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<PathBuf>,  // certificates client certificates must chain to, if any are accepted
+}
+
+/**
+ * The central stabping server an agent pushes all its results to (see
+ * `agent.rs`), as the given site, authorizing with the given token (its own,
+ * or one of the central server's `auth` tokens), if any, and over HTTPS with
+ * the client certificate and key in the given PEM files (relative to the
+ * configuration file, made self-signed for the site if not there yet), if
+ * given. The central server's certificate may be trusted through `ca`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentConfiguration {
+    pub server: String,  // base URL of the central server's web server, e.g. https://stabping.example.com/
+    pub site: String,  // name of the site of this agent, e.g. nyc
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<PathBuf>,  // certificates to trust besides the usual roots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+}
+
+/**
+ * Which agents a central server takes results from: those of a site
+ * presenting its token in `tokens` (by site), or a client certificate for it
+ * (see `HttpsConfiguration::client_ca`), or an admin's credentials. Results of
+ * any other are refused, or with `unknown = "quarantine"` accepted but set
+ * aside rather than recorded.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AgentsConfiguration {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tokens: BTreeMap<String, String>,
+    #[serde(default)]
+    pub unknown: UnknownAgents,
+}
+
+/**
+ * What a central server does with results pushed by unknown agents.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownAgents {
+    #[default]
+    Reject,
+    Quarantine,
+}
+
+/**
+ * The SMTP server (and credentials, if any) to email alerts through, from
+ * `from` to every address in `to`. Message templates render the subject.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SmtpConfiguration {
+    pub server: String,  // host:port of the server, e.g. smtp.example.com:587
+    #[serde(default)]
+    pub tls: SmtpTls,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(flatten)]
+    pub templates: MessageTemplates,
+}
+
+/**
+ * How to secure the connection to the SMTP server: TLS from the start (e.g.
+ * port 465), upgraded to TLS with STARTTLS (e.g. port 587), or not at all.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    Implicit,
+    #[default]
+    Starttls,
+    None,
+}
+
 #[test]
 fn ensure_kind_id_and_all_kinds_order_match() {
     for (i, k) in ALL_KINDS.iter().enumerate() {
         assert!(i as i32 == k.kind_id());
     }
 }
+
+#[test]
+fn declared_targets_override_persisted_options() {
+    let config: MainConfiguration = toml::from_str(r#"
+        web_port = 5001
+        ws_port = 5002
+        data_dir = "/var/lib/stabping"
+
+        [targets.tcpping]
+        addrs = ["vpn.example.com:443"]
+        interval = 5000
+    "#).unwrap();
+    assert_eq!(config.listen_address, "0.0.0.0");
+    assert_eq!(config.data_dir, Some(PathBuf::from("/var/lib/stabping")));
+
+    let persisted = tcpping::KIND.default_options();
+    let declared = config.targets["tcpping"].apply_to(&persisted).unwrap();
+    assert_eq!(declared.addrs, vec!["vpn.example.com:443"]);
+    assert_eq!((declared.interval, declared.avg_across), (5000, persisted.avg_across));
+    assert_eq!(declared.nonce, persisted.nonce + 1);
+    assert!(config.targets["tcpping"].apply_to(&declared).is_none());
+}
+
+#[test]
+fn intervals_shorter_than_a_second_are_refused() {
+    let mut opt = tcpping::KIND.default_options();
+    assert!(opt.check().is_ok());
+    opt.down_interval = Some(0);
+    assert!(opt.check().is_err());
+    opt.down_interval = Some(MIN_INTERVAL);
+    opt.interval = 0;
+    assert!(opt.check().is_err());
+    opt.interval = MIN_INTERVAL;
+    assert!(opt.check().is_ok());
+}
+
+#[test]
+fn options_files_from_before_a_field_get_its_default() {
+    let defaults = tcpping::KIND.default_options();
+    let mut json = serde_json::to_value(&defaults).unwrap();
+    let fields = json.as_object_mut().unwrap();
+    fields.remove("resolve_ttl");
+    fields.remove("down_after");
+    let read_back: TargetOptions = serde_json::from_value(json).unwrap();
+    assert_eq!((read_back.resolve_ttl, read_back.down_after), (defaults.resolve_ttl, defaults.down_after));
+}
+
+#[test]
+fn environment_overrides_configuration() {
+    let mut config = MainConfiguration::default();
+    config.apply_env(vec![
+        ("HOME".to_owned(), "/root".to_owned()),
+        ("STABPING_WEB_PORT".to_owned(), "8080".to_owned()),
+        ("STABPING_DATA_DIR".to_owned(), "/data".to_owned()),
+        ("STABPING_UNIX_SOCKET".to_owned(), "/run/stabping.sock".to_owned()),
+    ]).unwrap();
+    assert_eq!((config.web_port, config.ws_port), (8080, 5002));
+    assert_eq!(config.data_dir, Some(PathBuf::from("/data")));
+    assert_eq!(config.unix_socket, Some(PathBuf::from("/run/stabping.sock")));
+    assert_eq!(config.path_prefix(), "/");
+    config.apply_env(vec![("STABPING_PATH_PREFIX".to_owned(), "stabping".to_owned())]).unwrap();
+    assert_eq!(config.path_prefix(), "/stabping/");
+
+    assert!(config.apply_env(vec![("STABPING_LOG_FORMAT".to_owned(), "json".to_owned())]).is_ok());
+    assert!(config.apply_env(vec![("STABPING_WS_PORT".to_owned(), "x".to_owned())]).is_err());
+    assert!(config.apply_env(vec![("STABPING_SMTP_PASSWORD".to_owned(), "p".to_owned())]).is_err());
+    assert!(config.apply_env(vec![("STABPING_AUTH_PASSWORD".to_owned(), "p".to_owned())]).is_err());
+    config.apply_env(vec![("STABPING_AUTH_TOKEN".to_owned(), "t".to_owned())]).unwrap();
+    assert_eq!(config.auth.unwrap().tokens, vec!["t"]);
+}
+
+#[test]
+fn maintenance_windows_recur_and_run_past_midnight() {
+    let opt: TargetDeclaration = toml::from_str(r#"
+        [[maintenance]]
+        days = ["sun"]
+        start = "23:00"
+        end = "01:30"
+
+        [[maintenance]]
+        start = "12:00"
+        end = "12:15"
+    "#).unwrap();
+    let windows = opt.maintenance.unwrap();
+    let in_any = |day, minute| windows.iter().any(|w| w.contains(day, minute));
+
+    // Sunday 23:00 to Monday 01:30
+    assert!(in_any(6, 23 * 60) && in_any(0, 60) && !in_any(0, 90) && !in_any(5, 23 * 60));
+    // every day 12:00 to 12:15
+    assert!(in_any(2, 12 * 60 + 14) && !in_any(2, 12 * 60 + 15));
+    assert_eq!(String::from(windows[0].end), "01:30");
+    assert!(toml::from_str::<MaintenanceWindow>("start = \"2:00\"\nend = \"4:0\"").is_err());
+    for bad in ["001:30", "+1:30", "1:+5", "24:00", ":30"] {
+        assert!(TimeOfDay::try_from(bad.to_owned()).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn display_names_and_notes_are_declared_and_forgotten_with_their_addr() {
+    let declared: TargetDeclaration = toml::from_str(r#"
+        [names]
+        "vpn:443" = "Office VPN gateway"
+
+        [notes]
+        "vpn:443" = "Reboots on Sundays."
+    "#).unwrap();
+    let defaults = tcpping::KIND.default_options();
+    assert!(!serde_json::to_string(&defaults).unwrap().contains("names"));
+
+    let mut opt = declared.apply_to(&defaults).unwrap();
+    assert_eq!(opt.names["vpn:443"], "Office VPN gateway");
+    let json = serde_json::to_string(&opt).unwrap();
+    assert!(json.contains(r#""notes":{"vpn:443":"Reboots on Sundays."}"#));
+
+    opt.forget_addr("vpn:443");
+    assert!(opt.names.is_empty() && opt.notes.is_empty());
+}
+
+#[test]
+fn columns_take_the_units_of_what_they_measure() {
+    assert_eq!(tcpping::KIND.unit_of(""), Unit::Duration);
+    assert_eq!(tcpping::KIND.unit_of("jitter"), Unit::Duration);
+    assert_eq!(tls::KIND.unit_of("connect"), Unit::Duration);
+    assert_eq!(tls::KIND.unit_of(EXPIRY_COLUMN), Unit::Gauge);
+    assert_eq!(throughput::KIND.unit_of(""), Unit::Rate);
+    assert_eq!(throughput::KIND.unit_of("ttfb"), Unit::Duration);
+    for column in ["jitter", "p95", "ipv6", "sample2", "bloat"] {
+        assert_eq!(throughput::KIND.unit_of(column), Unit::Rate);
+    }
+    assert_eq!(throughput::KIND.unit_of("loss"), Unit::Gauge);
+    assert_eq!(throughput::KIND.unit_of(RACE_COLUMN), Unit::Gauge);
+
+    assert_eq!(speedtest::KIND.unit_of(""), Unit::Rate);
+    assert_eq!(speedtest::KIND.unit_of("upload"), Unit::Rate);
+    assert_eq!(speedtest::KIND.unit_of("idle"), Unit::Duration);
+    assert_eq!(speedtest::KIND.unit_of("loaded"), Unit::Duration);
+
+    assert_eq!(grpc::KIND.unit_of(""), Unit::Duration);
+    assert_eq!(grpc::KIND.unit_of("status"), Unit::Gauge);
+}
@@ -0,0 +1,399 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Periodic export of the metrics of every address (those of /metrics, see
+ * `metrics.rs`) to an OpenTelemetry collector over OTLP/HTTP, in protobuf or
+ * JSON, for observability stacks built around the collector rather than
+ * scraping.
+ *
+ * Latest values are gauges (`stabping.latency`, by `column`,
+ * `stabping.certificate_expiry`, `stabping.clock_offset`, `stabping.loss`,
+ * `stabping.jitter` and `stabping.up`), and running counts cumulative sums since stabping started
+ * (`stabping.rounds`, `stabping.attempts` and `stabping.failed_attempts`).
+ * Every data point carries the `target` and `addr` it is of, the address's
+ * display `name` (if any) and its tags as attributes.
+ *
+ * Since every export supersedes the one before, exports that fail are
+ * dropped rather than buffered.
+ */
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::http;
+use crate::http::Url;
+use crate::metrics::{AddrSnapshot, Metrics};
+use crate::options::{OtlpConfiguration, OtlpProtocol, EXPIRY_COLUMN, signed_offset};
+use crate::probe::Unit;
+use crate::protobuf::{field_bytes, field_fixed64, field_varint};
+
+/**
+ * `AGGREGATION_TEMPORALITY_CUMULATIVE` of OTLP sums.
+ */
+const CUMULATIVE: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PointValue {
+    Double(f64),
+    Int(i64),
+}
+
+/**
+ * A single value of a metric, with the attributes telling what it is of.
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct DataPoint {
+    attributes: Vec<(String, String)>,
+    value: PointValue,
+}
+
+/**
+ * A metric and its data points: a gauge, or a monotonic cumulative sum.
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct Metric {
+    name: &'static str,
+    description: &'static str,
+    unit: &'static str,
+    sum: bool,
+    points: Vec<DataPoint>,
+}
+
+/**
+ * Attributes of the data points of the given address.
+ */
+fn addr_attributes(a: &AddrSnapshot) -> Vec<(String, String)> {
+    let mut attributes = vec![("target".to_owned(), a.kind.compact_name().to_owned()), ("addr".to_owned(), a.addr.clone())];
+    if let Some(ref name) = a.name {
+        attributes.push(("name".to_owned(), name.clone()));
+    }
+    for (tag, value) in a.tags.iter().flatten() {
+        // tags can't take the names of the other attributes
+        if !["target", "addr", "name", "column"].contains(&tag.as_str()) {
+            attributes.push((tag.clone(), value.clone()));
+        }
+    }
+    attributes
+}
+
+/**
+ * Turns the given snapshot into the exported metrics.
+ */
+fn collect(snapshot: &[AddrSnapshot]) -> Vec<Metric> {
+    let metric = |name, description, unit, sum| Metric { name, description, unit, sum, points: Vec::new() };
+    let mut latency = metric("stabping.latency",
+                             "Latest measured value of each column of an address measuring a time.", "s", false);
+    let mut throughput = metric("stabping.throughput",
+                                "Latest measured value of each column of an address measuring a rate of transfer.",
+                                "kbit/s", false);
+    let mut gauge = metric("stabping.value",
+                           "Latest measured value of each other column of an address (e.g. a status), as is.",
+                           "1", false);
+    let mut expiry = metric("stabping.certificate_expiry",
+                            "Whole days until the first certificate presented by an address expires.", "d", false);
+    let mut offset = metric("stabping.clock_offset",
+                            "Latest offset of the local clock from an NTP server, positive if it is ahead.", "s", false);
+    let mut loss = metric("stabping.loss", "Fraction of the attempts of the latest round of an address that failed.",
+                          "1", false);
+    let mut jitter = metric("stabping.jitter",
+                            "Mean difference between consecutive attempts of the latest round of an address.",
+                            "s", false);
+    let mut up = metric("stabping.up", "Whether the latest round of an address produced a value.", "1", false);
+    let mut rounds = metric("stabping.rounds", "Rounds of collection completed for an address.", "{round}", true);
+    let mut attempts = metric("stabping.attempts", "Attempts made against an address.", "{attempt}", true);
+    let mut failed = metric("stabping.failed_attempts", "Attempts against an address that failed.", "{attempt}", true);
+
+    for a in snapshot.iter() {
+        let attributes = addr_attributes(a);
+        let point = |value| DataPoint { attributes: attributes.clone(), value };
+        for &(ref column, val) in a.values.iter().filter(|&(_, v)| *v >= 0) {
+            if column == EXPIRY_COLUMN {
+                expiry.points.push(point(PointValue::Int(val as i64)));
+                continue;
+            }
+            let mut attributes = attributes.clone();
+            attributes.push(("column".to_owned(), column.clone()));
+            let (of, value) = match a.kind.unit_of(column) {
+                Unit::Duration => (&mut latency, PointValue::Double(val as f64 / 1e6)),
+                Unit::Rate => (&mut throughput, PointValue::Double(val as f64)),
+                Unit::Gauge => (&mut gauge, PointValue::Int(val as i64)),
+            };
+            of.points.push(DataPoint { attributes, value });
+        }
+        if let Some(o) = signed_offset(a.values.iter().map(|(c, v)| (c.as_str(), *v))) {
+            offset.points.push(point(PointValue::Double(o as f64 / 1e6)));
+        }
+        loss.points.push(point(PointValue::Double(a.loss as f64 / 100.0)));
+        if a.jitter >= 0 && a.kind.unit_of("jitter") == Unit::Duration {
+            jitter.points.push(point(PointValue::Double(a.jitter as f64 / 1e6)));
+        }
+        let is_up = a.values.first().is_some_and(|&(_, v)| v >= 0);
+        up.points.push(point(PointValue::Int(is_up as i64)));
+        rounds.points.push(point(PointValue::Int(a.rounds as i64)));
+        attempts.points.push(point(PointValue::Int(a.attempts as i64)));
+        failed.points.push(point(PointValue::Int(a.failed_attempts as i64)));
+    }
+    vec![latency, throughput, gauge, expiry, offset, loss, jitter, up, rounds, attempts, failed]
+}
+
+fn key_value(key: &str, value: &str) -> Vec<u8> {
+    let mut any = Vec::new();
+    field_bytes(&mut any, 1, value.as_bytes());
+    let mut kv = Vec::new();
+    field_bytes(&mut kv, 1, key.as_bytes());
+    field_bytes(&mut kv, 2, &any);
+    kv
+}
+
+/**
+ * Encodes the given metrics of the given resource as an OTLP
+ * `ExportMetricsServiceRequest`, sums counting from `start` and all taken at
+ * `time` (in nanoseconds since the epoch).
+ */
+fn encode_protobuf(resource: &[(String, String)], metrics: &[Metric], start: u64, time: u64) -> Vec<u8> {
+    let mut scope_metrics = Vec::new();
+    let mut scope = Vec::new();
+    field_bytes(&mut scope, 1, b"stabping");
+    field_bytes(&mut scope, 2, env!("CARGO_PKG_VERSION").as_bytes());
+    field_bytes(&mut scope_metrics, 1, &scope);
+
+    for m in metrics.iter().filter(|m| !m.points.is_empty()) {
+        let mut data = Vec::new();
+        for p in m.points.iter() {
+            let mut point = Vec::new();
+            if m.sum {
+                field_fixed64(&mut point, 2, start);
+            }
+            field_fixed64(&mut point, 3, time);
+            match p.value {
+                PointValue::Double(v) => field_fixed64(&mut point, 4, v.to_bits()),
+                PointValue::Int(v) => field_fixed64(&mut point, 6, v as u64),
+            }
+            for (k, v) in p.attributes.iter() {
+                field_bytes(&mut point, 7, &key_value(k, v));
+            }
+            field_bytes(&mut data, 1, &point);
+        }
+        if m.sum {
+            field_varint(&mut data, 2, CUMULATIVE);
+            field_varint(&mut data, 3, 1);
+        }
+
+        let mut metric = Vec::new();
+        field_bytes(&mut metric, 1, m.name.as_bytes());
+        field_bytes(&mut metric, 2, m.description.as_bytes());
+        field_bytes(&mut metric, 3, m.unit.as_bytes());
+        field_bytes(&mut metric, if m.sum { 7 } else { 5 }, &data);
+        field_bytes(&mut scope_metrics, 2, &metric);
+    }
+
+    let mut res = Vec::new();
+    for (k, v) in resource.iter() {
+        field_bytes(&mut res, 1, &key_value(k, v));
+    }
+    let mut resource_metrics = Vec::new();
+    field_bytes(&mut resource_metrics, 1, &res);
+    field_bytes(&mut resource_metrics, 2, &scope_metrics);
+    let mut out = Vec::new();
+    field_bytes(&mut out, 1, &resource_metrics);
+    out
+}
+
+/**
+ * Encodes the same request as `encode_protobuf` in the JSON encoding of
+ * OTLP.
+ */
+fn encode_json(resource: &[(String, String)], metrics: &[Metric], start: u64, time: u64) -> Value {
+    let attributes = |attributes: &[(String, String)]| -> Vec<Value> {
+        attributes.iter().map(|(k, v)| json!({ "key": k, "value": { "stringValue": v } })).collect()
+    };
+    let metrics: Vec<Value> = metrics.iter().filter(|m| !m.points.is_empty()).map(|m| {
+        let points: Vec<Value> = m.points.iter().map(|p| {
+            // 64-bit integers as strings, as in the protobuf JSON mapping
+            let mut point = json!({ "attributes": attributes(&p.attributes), "timeUnixNano": time.to_string() });
+            if m.sum {
+                point["startTimeUnixNano"] = json!(start.to_string());
+            }
+            match p.value {
+                PointValue::Double(v) => point["asDouble"] = json!(v),
+                PointValue::Int(v) => point["asInt"] = json!(v.to_string()),
+            }
+            point
+        }).collect();
+        let mut metric = json!({ "name": m.name, "description": m.description, "unit": m.unit });
+        if m.sum {
+            metric["sum"] = json!({ "dataPoints": points, "aggregationTemporality": CUMULATIVE, "isMonotonic": true });
+        } else {
+            metric["gauge"] = json!({ "dataPoints": points });
+        }
+        metric
+    }).collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": { "attributes": attributes(resource) },
+            "scopeMetrics": [{
+                "scope": { "name": "stabping", "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+/**
+ * Starts a thread exporting the given metrics as configured, unless the
+ * configuration can't be exported to.
+ */
+pub fn run_exporter(config: OtlpConfiguration, metrics: Arc<Metrics>) {
+    if config.protocol == OtlpProtocol::Grpc {
+        warn!("OTLP over gRPC is not supported, not exporting metrics (use the collector's OTLP/HTTP receiver).");
+        return;
+    }
+    let url = match Url::parse(&format!("{}/v1/metrics", config.endpoint.trim_end_matches('/'))) {
+        Some(u) => u,
+        None => {
+            warn!("Invalid OTLP endpoint '{}', not exporting metrics.", config.endpoint);
+            return;
+        },
+    };
+
+    let mut resource = vec![("service.name".to_owned(), "stabping".to_owned())];
+    let extra: BTreeMap<_, _> = config.resource_attributes.into_iter().filter(|(k, _)| k != "service.name").collect();
+    resource.extend(extra);
+    let content_type = match config.protocol {
+        OtlpProtocol::HttpJson => "application/json",
+        _ => "application/x-protobuf",
+    };
+
+    let start = unix_nanos();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(config.interval.max(1) as u64));
+            let exported = collect(&metrics.snapshot());
+            let time = unix_nanos();
+            let body = match config.protocol {
+                OtlpProtocol::HttpJson => encode_json(&resource, &exported, start, time).to_string().into_bytes(),
+                _ => encode_protobuf(&resource, &exported, start, time),
+            };
+            let mut headers = vec![("Content-Type", content_type)];
+            headers.extend(config.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            match http::send("POST", &url, &headers, &body, Duration::from_secs(10)) {
+                Ok(status) if (200..300).contains(&status) => {},
+                Ok(status) => warn!("OTLP collector refused metrics with status {}.", status),
+                Err(e) => warn!("Failed to export metrics to the OTLP collector: {}", e),
+            }
+        }
+    });
+}
+
+#[test]
+fn metrics_encode_as_otlp() {
+    let mut tags = crate::tags::Tags::new();
+    tags.insert("site".to_owned(), "home".to_owned());
+    tags.insert("addr".to_owned(), "clash".to_owned());
+    let snapshot = vec![AddrSnapshot {
+        kind: &crate::tcpping::KIND,
+        addr: "a:80".to_owned(),
+        name: None,
+        tags: Some(tags),
+        values: vec![("".to_owned(), 1500), ("loss".to_owned(), -2_100_000_005)],
+        loss: 25,
+        jitter: -1,
+        rounds: 3,
+        attempts: 12,
+        failed_attempts: 3,
+    }];
+    let exported = collect(&snapshot);
+    let latency = &exported[0];
+    assert_eq!(latency.points.len(), 1);
+    assert_eq!(latency.points[0].attributes, vec![
+        ("target".to_owned(), "tcpping".to_owned()), ("addr".to_owned(), "a:80".to_owned()),
+        ("site".to_owned(), "home".to_owned()), ("column".to_owned(), "".to_owned()),
+    ]);
+    assert_eq!(latency.points[0].value, PointValue::Double(0.0015));
+    assert!(exported[1..5].iter().all(|m| m.points.is_empty()) && exported[6].points.is_empty());
+    assert_eq!(exported[5].points[0].value, PointValue::Double(0.25));
+    assert_eq!(exported[8].points[0].value, PointValue::Int(3));
+
+    // rates of transfer are exported as such, rather than as times
+    let throughput = collect(&[AddrSnapshot {
+        kind: &crate::throughput::KIND,
+        addr: "http://a/".to_owned(),
+        name: None,
+        tags: None,
+        values: vec![("".to_owned(), 25_000), ("ttfb".to_owned(), 40_000)],
+        loss: 0,
+        jitter: 800,
+        rounds: 1,
+        attempts: 1,
+        failed_attempts: 0,
+    }]);
+    assert_eq!(throughput[0].points.len(), 1);
+    assert_eq!(throughput[0].points[0].value, PointValue::Double(0.04));
+    assert_eq!(throughput[1].points[0].attributes.last().unwrap(), &("column".to_owned(), "".to_owned()));
+    assert_eq!(throughput[1].points[0].value, PointValue::Double(25_000.0));
+    assert!(throughput[6].points.is_empty());
+
+    // as are other values, such as serving statuses, as they are
+    let grpc = collect(&[AddrSnapshot {
+        kind: &crate::grpc::KIND,
+        addr: "grpc://a:50051".to_owned(),
+        name: None,
+        tags: None,
+        values: vec![("".to_owned(), 3_000), ("status".to_owned(), 2)],
+        loss: 0,
+        jitter: 0,
+        rounds: 1,
+        attempts: 1,
+        failed_attempts: 0,
+    }]);
+    assert_eq!(grpc[0].points.len(), 1);
+    assert_eq!(grpc[2].points[0].attributes.last().unwrap(), &("column".to_owned(), "status".to_owned()));
+    assert_eq!(grpc[2].points[0].value, PointValue::Int(2));
+
+    // and the offsets of the local clock, signed again
+    let ntp = collect(&[AddrSnapshot {
+        kind: &crate::ntp::KIND,
+        addr: "pool.ntp.org".to_owned(),
+        name: None,
+        tags: None,
+        values: vec![("".to_owned(), 30_000), ("delay".to_owned(), 20_000), ("ahead".to_owned(), 0),
+                     ("behind".to_owned(), 30_000)],
+        loss: 0,
+        jitter: 0,
+        rounds: 1,
+        attempts: 1,
+        failed_attempts: 0,
+    }]);
+    assert_eq!(ntp[4].points[0].value, PointValue::Double(-0.03));
+
+    let resource = vec![("service.name".to_owned(), "stabping".to_owned())];
+    let encoded = encode_protobuf(&resource, &exported[8..9], 7, 9);
+    let mut point = vec![0x11];  // start_time_unix_nano
+    point.extend_from_slice(&7u64.to_le_bytes());
+    point.push(0x19);  // time_unix_nano
+    point.extend_from_slice(&9u64.to_le_bytes());
+    point.push(0x31);  // as_int
+    point.extend_from_slice(&3u64.to_le_bytes());
+    assert!(encoded.windows(point.len()).any(|w| w == &point[..]));
+    assert!(encoded.ends_with(&[0x10, 0x02, 0x18, 0x01]));  // cumulative, monotonic
+
+    let body = encode_json(&resource, &exported, 7, 9);
+    let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+    assert_eq!(metrics.as_array().unwrap().len(), 6);
+    assert_eq!(metrics[0]["gauge"]["dataPoints"][0]["asDouble"], json!(0.0015));
+    assert_eq!(metrics[3]["sum"]["dataPoints"][0]["startTimeUnixNano"], json!("7"));
+    assert_eq!(metrics[3]["sum"]["dataPoints"][0]["asInt"], json!("3"));
+}
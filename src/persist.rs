@@ -12,15 +12,24 @@ use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fs::OpenOptions;
 use std::fs::File;
+use std::io;
 use std::io::Write;
 use std::io::BufReader;
-use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 use std::ops::Deref;
-use std::iter;
-use std::iter::Extend;
 
-use crate::helpers::{SPIOError, SPFile, VecIntoRawBytes, overwrite_json};
-use crate::options::{TargetKind, TargetOptions, TargetResults, SENTINEL_NODATA};
+use chrono::Local;
+
+use crate::helpers::{SPIOError, SPFile, PushRawBytes, overwrite_json, discard_incomplete};
+use crate::histogram::{Histogram, RollingHistogram};
+use crate::hops::{Hop, HopLog, HopRound};
+use crate::rollup::{Rollups, RollupElement};
+use crate::schema::{Layout, SchemaLog};
+use crate::options::{TargetKind, TargetOptions, TargetResults, StorageBackend, SENTINEL_NODATA,
+                     column_key};
+use crate::storage::{Storage, open_storage};
+use crate::sink::{ResultsSink, SinkError};
 
 /**
  * A stabping-specific error container for errors incurred during TargetManager
@@ -31,6 +40,11 @@ pub enum ManagerError {
     IndexFileIO(SPIOError),
     DataFileIO(SPIOError),
     OptionsFileIO(SPIOError),
+    HopsFileIO(SPIOError),
+    IpsFileIO(SPIOError),
+    RollupFileIO(SPIOError),
+    SchemaFileIO(SPIOError),
+    InvalidOptions(String),
 }
 
 impl ManagerError {
@@ -39,6 +53,11 @@ impl ManagerError {
             ManagerError::IndexFileIO(ref e) => format!("{} index file", e.description()),
             ManagerError::DataFileIO(ref e) => format!("{} data file", e.description()),
             ManagerError::OptionsFileIO(ref e) => format!("{} options file", e.description()),
+            ManagerError::HopsFileIO(ref e) => format!("{} hops file", e.description()),
+            ManagerError::IpsFileIO(ref e) => format!("{} IPs file", e.description()),
+            ManagerError::RollupFileIO(ref e) => format!("{} rollup file", e.description()),
+            ManagerError::SchemaFileIO(ref e) => format!("{} schema file", e.description()),
+            ManagerError::InvalidOptions(ref e) => format!("Invalid options: {}", e),
         }
     }
 }
@@ -49,6 +68,28 @@ impl Display for ManagerError {
     }
 }
 
+/**
+ * Representation of data elements on-disk in a target's data file. They are
+ * a 64-bit time followed by a 32-bit index and value, back-to-back.
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataElement {
+    pub time: i64,
+    pub index: i32,
+    pub val: i32,
+}
+
+impl DataElement {
+    /**
+     * Appends the on-disk representation of this element to the given buffer.
+     */
+    pub fn push_to(&self, buf: &mut Vec<u8>) {
+        buf.push_i64(self.time);
+        buf.push_i32(self.index);
+        buf.push_i32(self.val);
+    }
+}
 
 /**
  * A per-target global persistent mapping of index (an integer) to an address
@@ -66,28 +107,37 @@ impl AddrIndex {
      * Creates an `AddrIndex` backed by the index file residing at the given
      * path.
      */
-    fn from_path<'b>(path: &'b Path) -> Result<Self, ManagerError> {
+    fn from_path(path: &Path) -> Result<Self, ManagerError> {
         // attempt to open the index file
         let mut index_file =
             File::open_from(OpenOptions::new().read(true).append(true).create(true), path)
-            .map_err(|e| ManagerError::IndexFileIO(e))?;
+            .map_err(ManagerError::IndexFileIO)?;
 
         let mut index_data = Vec::new();
         /*
          * if the index file is non-empty, read the data into a list that will
          * function as the index -> addr mapping
          */
-        if index_file.length_p(path)
-                .map_err(|e| ManagerError::IndexFileIO(e))? > 0 {
+        let len = index_file.length_p(path).map_err(ManagerError::IndexFileIO)?;
+        if len > 0 {
             use std::io::BufRead;
-            let reader = BufReader::new(&mut index_file);
+            let mut reader = BufReader::new(&mut index_file);
 
-            for line_res in reader.lines() {
-                let line = line_res
+            let mut complete_len = 0;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let read = reader.read_line(&mut line)
                                 .map_err(|_| ManagerError::IndexFileIO(
                                              SPIOError::Parse(Some(path.to_owned()))))?;
-                index_data.push(line);
+                // stop at the end, or at a line left incomplete
+                if read == 0 || !line.ends_with('\n') {
+                    break;
+                }
+                complete_len += read as u64;
+                index_data.push(line.trim_end_matches('\n').to_owned());
             }
+            discard_incomplete(&index_file, path, len, complete_len).map_err(ManagerError::IndexFileIO)?;
         }
 
         // create the map that will contain the reverse addr -> index mapping
@@ -108,7 +158,7 @@ impl AddrIndex {
      * exist in the index).
      */
     fn add_addr(&mut self, addr: &str) -> Result<(), ManagerError> {
-        if let None = self.map.get(addr) {
+        if !self.map.contains_key(addr) {
             // only deal with it if we don't already have it
             self.map.insert(addr.to_owned(), self.data.len() as i32);
             self.data.push(addr.to_owned());
@@ -126,7 +176,7 @@ impl AddrIndex {
     fn ensure_for_addrs<'a, I, K>(&mut self, addrs: I) -> Result<(), ManagerError>
             where I: Iterator<Item=&'a K>, K: 'a + Deref<Target=str> {
         for addr in addrs {
-            self.add_addr(&addr)?;
+            self.add_addr(addr)?;
         }
         Ok(())
     }
@@ -138,9 +188,17 @@ impl AddrIndex {
         self.map.get(addr).cloned().expect("Non-existant addr requested from AddrIndex!")
     }
 
+    /**
+     * Retrieves the index associated with the given address, if it has one.
+     */
+    fn find_index(&self, addr: &str) -> Option<i32> {
+        self.map.get(addr).cloned()
+    }
+
     /**
      * Retrieves the adress associated with the given index.
      */
+    #[allow(dead_code)]
     fn get_addr(&self, index: i32) -> &String {
         self.data.get(index as usize).expect("Non-existant index requested from AddrIndex!")
     }
@@ -164,9 +222,17 @@ impl AddrIndex {
 pub struct TargetManager {
     pub kind: &'static TargetKind,
     index: RwLock<AddrIndex>,
-    data_file: RwLock<File>,
+    storage: Box<dyn Storage>,
     options_path: Mutex<PathBuf>,
     options: RwLock<TargetOptions>,
+    skipped_rounds: Mutex<HashMap<String, u64>>,  // rounds skipped (by addr) after overruns
+    last_write: Mutex<Option<i64>>,  // time of the latest results appended since startup
+    histograms: Mutex<HashMap<String, RollingHistogram>>,  // latencies of attempts (by addr)
+    hops: Option<HopLog>,  // paths mapped to each address, if this kind keeps them
+    ips: Option<HopLog>,  // IPs probed in each round of each address, if this kind keeps them
+    noted_ips: Mutex<HashMap<String, Vec<Hop>>>,  // IPs probed in the current rounds (by addr)
+    rollups: Rollups,
+    schema: SchemaLog,
 }
 
 impl TargetManager {
@@ -174,35 +240,32 @@ impl TargetManager {
      * Creates a new `TargetManager` for the given target kind that will store
      * persistent data at the given location path.
      */
-    pub fn new<'b>(kind: &'static TargetKind, data_path: &'b Path) -> Result<Self, ManagerError> {
+    pub fn new(kind: &'static TargetKind, data_path: &Path,
+               backend: StorageBackend) -> Result<Self, ManagerError> {
         let mut path = data_path.to_owned();
 
-        // attempt to open the target's data file
-        path.push(format!("{}.data.dat", kind.compact_name()));
-        let data_file =
-            File::open_from(OpenOptions::new().read(true).append(true).create(true), &path)
-            .map_err(|e| ManagerError::DataFileIO(e))?;
-        path.pop();
+        // attempt to open the target's storage
+        let storage = open_storage(kind, data_path, backend)?;
 
         // attempt to open the target's options file
         let options_file_name = format!("{}.options.json", kind.compact_name());
         path.push(&options_file_name);
         let mut options_file =
             File::open_from(OpenOptions::new().read(true).write(true).create(true), &path)
-            .map_err(|e| ManagerError::OptionsFileIO(e))?;
+            .map_err(ManagerError::OptionsFileIO)?;
 
         /*
          * read back existing options from the options file, or write out
          * default options for this target to the options file
          */
         let options = if options_file.length_p(&path)
-                              .map_err(|e| ManagerError::OptionsFileIO(e))? > 0 {
+                              .map_err(ManagerError::OptionsFileIO)? > 0 {
             options_file.read_json_p(&path)
-                .map_err(|e| ManagerError::OptionsFileIO(e))?
+                .map_err(ManagerError::OptionsFileIO)?
         } else {
             let default_options = kind.default_options();
             options_file.write_json_p(&default_options, &path)
-                .map_err(|e| ManagerError::OptionsFileIO(e))?;
+                .map_err(ManagerError::OptionsFileIO)?;
             default_options
         };
 
@@ -215,21 +278,72 @@ impl TargetManager {
          */
         path.push(format!("{}.index.json", kind.compact_name()));
         let mut index = AddrIndex::from_path(&path)?;
-        index.ensure_for_addrs(options.addrs.iter())?;
+        index.ensure_for_addrs(kind.column_keys(&options).iter())?;
+        storage.describe_keys(&index.data)?;
         path.pop();
 
+        // attempt to open the target's schema file, and record the current layout in it
+        path.push(format!("{}.schema.json", kind.compact_name()));
+        let schema = SchemaLog::open(&path).map_err(ManagerError::SchemaFileIO)?;
+        schema.record(Self::layout_of(kind, &options)).map_err(ManagerError::SchemaFileIO)?;
+        path.pop();
+
+        // attempt to open the target's hops file, if it keeps one
+        let hops = if kind.keeps_hops() {
+            path.push(format!("{}.hops.dat", kind.compact_name()));
+            let log = HopLog::open(&path).map_err(ManagerError::HopsFileIO)?;
+            path.pop();
+            Some(log)
+        } else {
+            None
+        };
+
+        // attempt to open the target's IPs file, if it keeps one
+        let ips = if kind.keeps_ips() {
+            path.push(format!("{}.ips.dat", kind.compact_name()));
+            let log = HopLog::open(&path).map_err(ManagerError::IpsFileIO)?;
+            path.pop();
+            Some(log)
+        } else {
+            None
+        };
+
+        // attempt to open the target's rollup files
+        let rollups = Rollups::open(kind, data_path)?;
+
         // leave the path to the options file here so we can store it
         path.push(options_file_name);
 
         Ok(TargetManager {
-            kind: kind,
+            kind,
             index: RwLock::new(index),
-            data_file: RwLock::new(data_file),
+            storage,
             options_path: Mutex::new(path),
             options: RwLock::new(options),
+            skipped_rounds: Mutex::new(HashMap::new()),
+            last_write: Mutex::new(None),
+            histograms: Mutex::new(HashMap::new()),
+            hops,
+            ips,
+            noted_ips: Mutex::new(HashMap::new()),
+            rollups,
+            schema,
         })
     }
 
+    /**
+     * Returns the layout of the results of the given kind with the given
+     * options, as of now.
+     */
+    fn layout_of(kind: &TargetKind, options: &TargetOptions) -> Layout {
+        Layout {
+            nonce: options.nonce,
+            since: Local::now().timestamp(),
+            addrs: options.addrs.clone(),
+            columns: kind.columns(options),
+        }
+    }
+
     /**
      * Acquires a read lock on this target's options.
      */
@@ -238,24 +352,66 @@ impl TargetManager {
     }
 
     /**
-     * Attempts to update this target's options with the given new options.
+     * Attempts to update this target's options with the given new options,
+     * refusing those that don't make sense to probe with (see
+     * `TargetOptions::check`).
      */
     pub fn options_update(&self, new_options: TargetOptions) -> Result<(), ManagerError> {
+        new_options.check().map_err(ManagerError::InvalidOptions)?;
         let mut guard = self.options.write().unwrap();
         let options_path = self.options_path.lock().unwrap();
         *guard = new_options;
-        overwrite_json(&*guard, &*options_path)
-            .map_err(|e| ManagerError::OptionsFileIO(e))?;
-        self.index.write().unwrap().ensure_for_addrs(guard.addrs.iter())?;
-        println!("Updated {} options: {:?}", self.kind.compact_name(), *guard);
+        overwrite_json(&*guard, &options_path)
+            .map_err(ManagerError::OptionsFileIO)?;
+        {
+            let mut index = self.index.write().unwrap();
+            index.ensure_for_addrs(self.kind.column_keys(&guard).iter())?;
+            self.storage.describe_keys(&index.data)?;
+        }
+        self.schema.record(Self::layout_of(self.kind, &guard)).map_err(ManagerError::SchemaFileIO)?;
+        info!("Updated {} options: {:?}", self.kind.compact_name(), *guard);
         Ok(())
     }
 
     /**
-     * Acquires a read lock on this target's data file.
+     * Calls `f` with this target's data elements with times from `lower` to
+     * `upper` (inclusive), ordered by time, possibly in several chunks.
+     */
+    pub fn read_data_range(&self, lower: i64, upper: i64,
+                           f: &mut dyn FnMut(&[DataElement]) -> io::Result<()>) -> io::Result<()> {
+        self.storage.read_range(lower, upper, f)
+    }
+
+    /**
+     * Calls `f` with this target's data elements with times from `lower` to
+     * `upper` (inclusive) like `read_data_range`, but with them downsampled
+     * into rollups if the range is large (see `Rollups::read_downsampled`).
      */
-    pub fn data_file_read<'a>(&'a self) -> RwLockReadGuard<'a, File> {
-        self.data_file.read().unwrap()
+    pub fn read_data_downsampled(&self, lower: i64, upper: i64,
+                                 f: &mut dyn FnMut(&[DataElement]) -> io::Result<()>) -> io::Result<()> {
+        let interval = self.options_read().interval as i64 / 1000;
+        self.rollups.read_downsampled(&*self.storage, interval, lower, upper, f)
+    }
+
+    /**
+     * Rolls up this target's data that is complete at the given time and isn't
+     * yet.
+     */
+    pub fn roll_up(&self, now: i64) -> Result<(), ManagerError> {
+        self.rollups.update(&*self.storage, now)
+    }
+
+    /**
+     * Reads back the buckets of the given rollup tier (by name) of the given
+     * key (see `column_key`) from `from` to `to` (inclusive). Returns None for
+     * an unknown tier.
+     */
+    pub fn rollups_in_range(&self, tier: &str, key: &str, from: i64,
+                            to: i64) -> Option<Result<Vec<RollupElement>, ManagerError>> {
+        // an index never seen has no buckets (in any tier)
+        let index = self.index.read().unwrap().find_index(key).unwrap_or(-1);
+        self.rollups.read_tier(tier, index, from, to)
+            .map(|r| r.map_err(|_| ManagerError::RollupFileIO(SPIOError::Read(None))))
     }
 
     /**
@@ -263,60 +419,396 @@ impl TargetManager {
      * data file.
      */
     pub fn append_data(&self, data_res: &TargetResults) -> Result<(), ManagerError> {
-        let ref in_data = data_res.0;
-
-        assert!(in_data[0] == self.kind.kind_id());
+        assert!(data_res.kind == self.kind.kind_id());
 
-        let nonce = in_data[1];
-        if nonce != self.options_read().nonce {
-            println!("Nonce mismatch for data append! Silently ignoring.");
+        if data_res.nonce != self.options_read().nonce {
+            warn!("Nonce mismatch for data append! Silently ignoring.");
             return Ok(());
         }
 
-        let mut out_data: Vec<i32> = Vec::with_capacity((in_data.len() - 3) * 3);
-        let time = in_data[2];
         let index = self.index.read().unwrap();
-        for (addr, val) in self.options_read().addrs.iter().zip(in_data[3..].iter()) {
-            out_data.push(time);
-            out_data.push(index.get_index(addr));
-            out_data.push(*val);
+        let keys = self.kind.column_keys(&self.options_read());
+        let elements: Vec<DataElement> = keys.iter().zip(data_res.vals.iter())
+            // addresses are probed on their own schedules, so skip the others
+            .filter(|&(_, &val)| val != SENTINEL_NODATA)
+            .map(|(key, &val)| DataElement {
+                time: data_res.timestamp,
+                index: index.get_index(key),
+                val,
+            })
+            .collect();
+        self.storage.append(&elements)?;
+        if !elements.is_empty() {
+            *self.last_write.lock().unwrap() = Some(data_res.timestamp);
+        }
+        Ok(())
+    }
+
+    /**
+     * Merges the given results, collected elsewhere at any time (e.g. replayed
+     * by an agent after an outage), into this target's data and rollups like
+     * `import_data`, rather than appending them. Returns how many values were
+     * merged in.
+     */
+    pub fn merge_results(&self, data_res: &TargetResults) -> Result<u64, ManagerError> {
+        assert!(data_res.kind == self.kind.kind_id());
+
+        if data_res.nonce != self.options_read().nonce {
+            warn!("Nonce mismatch for data merge! Silently ignoring.");
+            return Ok(0);
+        }
+
+        let mut elements: Vec<DataElement> = {
+            let index = self.index.read().unwrap();
+            let keys = self.kind.column_keys(&self.options_read());
+            keys.iter().zip(data_res.vals.iter())
+                .filter(|&(_, &val)| val != SENTINEL_NODATA)
+                .map(|(key, &val)| DataElement {
+                    time: data_res.timestamp,
+                    index: index.get_index(key),
+                    val,
+                })
+                .collect()
+        };
+        self.import_data(&mut elements)
+    }
+
+    /**
+     * Gets the time of the latest results appended to the data file since
+     * startup, if any.
+     */
+    pub fn last_write(&self) -> Option<i64> {
+        *self.last_write.lock().unwrap()
+    }
+
+    /**
+     * Gets the indices of the keys (see `column_key`) of every column of the
+     * given address, in order of `TargetKind::columns`, adding them to the
+     * index as necessary (even if the address isn't a current one).
+     */
+    pub fn indices_for(&self, addr: &str) -> Result<Vec<i32>, ManagerError> {
+        let keys: Vec<String> = self.kind.columns(&self.options_read()).iter()
+            .map(|column| column_key(addr, column))
+            .collect();
+        let mut index = self.index.write().unwrap();
+        if keys.iter().any(|key| index.find_index(key).is_none()) {
+            index.ensure_for_addrs(keys.iter())?;
+            self.storage.describe_keys(&index.data)?;
+        }
+        Ok(keys.iter().map(|key| index.get_index(key)).collect())
+    }
+
+    /**
+     * Merges the given data elements (e.g. imported from elsewhere, of any
+     * times) into this target's data and rollups, skipping any of an index
+     * and time already stored. Returns how many were merged in.
+     */
+    pub fn import_data(&self, elements: &mut [DataElement]) -> Result<u64, ManagerError> {
+        elements.sort_by_key(|e| e.time);
+        let merged = self.storage.import(elements)?;
+        self.rollups.merge(&merged)?;
+        Ok(merged.len() as u64)
+    }
+
+    /**
+     * Records that the given number of rounds of the given address were
+     * skipped because its previous round overran.
+     */
+    pub fn record_skipped(&self, addr: &str, skipped: u64) {
+        *self.skipped_rounds.lock().unwrap().entry(addr.to_owned()).or_insert(0) += skipped;
+    }
+
+    /**
+     * Gets the number of rounds skipped of every address so far.
+     */
+    pub fn skipped_rounds(&self) -> HashMap<String, u64> {
+        self.skipped_rounds.lock().unwrap().clone()
+    }
+
+    /**
+     * Records the primary values of the given attempts (those that succeeded)
+     * of a round of the given address, made at the given time, into its
+     * histogram.
+     */
+    pub fn record_attempts(&self, addr: &str, samples: &[i32], time: i64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let h = histograms.entry(addr.to_owned()).or_insert_with(RollingHistogram::new);
+        for &s in samples.iter().filter(|&&s| s >= 0) {
+            h.record(s as u32, time);
+        }
+    }
+
+    /**
+     * Gets the histograms (over the rolling window as of the given time, and
+     * since startup) of every current address that has any, in order of addrs.
+     */
+    pub fn histograms(&self, time: i64) -> Vec<(String, Histogram, Histogram)> {
+        let options = self.options_read();
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms.retain(|addr, _| options.addrs.contains(addr));
+        options.addrs.iter()
+            .filter_map(|addr| {
+                histograms.get_mut(addr).map(|h| (addr.clone(), h.window(time), h.total().clone()))
+            })
+            .collect()
+    }
+
+    /**
+     * Appends the path mapped to the given address at the given time to this
+     * target's hops file (if it keeps one), adding the address to the index
+     * if this is its first round.
+     */
+    pub fn record_hops(&self, addr: &str, time: i64, hops: &[Hop]) -> Result<(), ManagerError> {
+        let log = match self.hops {
+            Some(ref log) => log,
+            None => return Ok(()),
+        };
+        // the key of the primary column is the address itself
+        let index = self.indices_for(addr)?[0];
+        log.append(time, index, hops).map_err(ManagerError::HopsFileIO)
+    }
+
+    /**
+     * Reads back the paths mapped to the given address from `from` to `to`
+     * (inclusive) from this target's hops file, in order of time.
+     */
+    pub fn hops_in_range(&self, addr: &str, from: i64, to: i64) -> Result<Vec<HopRound>, ManagerError> {
+        let log = match self.hops {
+            Some(ref log) => log,
+            None => return Ok(Vec::new()),
+        };
+        match self.index.read().unwrap().find_index(addr) {
+            Some(index) => log.read_range(index, from, to).map_err(ManagerError::HopsFileIO),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /**
+     * Notes that an attempt of the current round of the given address probed
+     * the given IP, measuring the given primary value (None if it failed).
+     */
+    pub fn note_ip(&self, addr: &str, ip: IpAddr, value: Option<i32>) {
+        if self.ips.is_none() {
+            return;
+        }
+        let mut noted = self.noted_ips.lock().unwrap();
+        let ips = noted.entry(addr.to_owned()).or_default();
+        match ips.iter_mut().find(|h| h.ip == Some(ip)) {
+            Some(h) => h.rtt = value,
+            None => ips.push(Hop { ip: Some(ip), rtt: value }),
+        }
+    }
+
+    /**
+     * Appends the IPs (see `note_ip`) probed in the round of the given address
+     * just finished at the given time, each with the primary value of its last
+     * attempt, to this target's IPs file (if it keeps one).
+     */
+    pub fn record_ips(&self, addr: &str, time: i64) -> Result<(), ManagerError> {
+        let ips = match self.noted_ips.lock().unwrap().remove(addr) {
+            Some(ips) => ips,
+            None => return Ok(()),
+        };
+        let (log, index) = match (&self.ips, self.index.read().unwrap().find_index(addr)) {
+            (Some(log), Some(index)) => (log, index),
+            _ => return Ok(()),
+        };
+        log.append(time, index, &ips).map_err(ManagerError::IpsFileIO)
+    }
+
+    /**
+     * Reads back the IPs probed in the rounds of the given address from `from`
+     * to `to` (inclusive) from this target's IPs file, in order of time.
+     */
+    pub fn ips_in_range(&self, addr: &str, from: i64, to: i64) -> Result<Vec<HopRound>, ManagerError> {
+        let log = match self.ips {
+            Some(ref log) => log,
+            None => return Ok(Vec::new()),
+        };
+        match self.index.read().unwrap().find_index(addr) {
+            Some(index) => log.read_range(index, from, to).map_err(ManagerError::IpsFileIO),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /**
+     * Removes this target's data elements (and paths and IPs, if it keeps
+     * any) with times before `before`, returning how many data elements were
+     * removed.
+     */
+    pub fn prune_data(&self, before: i64) -> Result<u64, ManagerError> {
+        let pruned = self.storage.prune(before)?;
+        if let Some(ref log) = self.hops {
+            log.prune(before).map_err(ManagerError::HopsFileIO)?;
         }
+        if let Some(ref log) = self.ips {
+            log.prune(before).map_err(ManagerError::IpsFileIO)?;
+        }
+        Ok(pruned)
+    }
+
+    /**
+     * Compresses this target's data elements with times before `before` (see
+     * `compress.rs`), returning how many were.
+     */
+    pub fn compress_data(&self, before: i64) -> Result<u64, ManagerError> {
+        self.storage.compress(before)
+    }
 
-        let ref mut file = *self.data_file.write().unwrap();
-        file.write_all(&out_data.into_raw_bytes())
-             .map_err(|_| ManagerError::DataFileIO(
-                          SPIOError::Write(None)))?;
+    /**
+     * Flushes this target's data (and hops and IPs files, if any) to disk.
+     */
+    pub fn sync_data(&self) -> Result<(), ManagerError> {
+        self.storage.sync()?;
+        if let Some(ref log) = self.hops {
+            log.sync().map_err(ManagerError::HopsFileIO)?;
+        }
+        if let Some(ref log) = self.ips {
+            log.sync().map_err(ManagerError::IpsFileIO)?;
+        }
         Ok(())
     }
 
     /**
      * Gets the current addrs in options as (nonce, ordered_list, membership)
-     * where 'ordered_list' is the list of address indices in order in which
-     * they appear in options, and where 'membership' is the set of indices
-     * present (i.e. if membership[i] != 0, then the addr with index i is
-     * currently present in options).
+     * where 'ordered_list' is the list of address (column) indices in order in
+     * which they appear in `TargetResults`, and where 'membership' is the set
+     * of indices present (i.e. if membership[i] != 0, then the addr column with
+     * index i is currently present in options).
      */
     pub fn get_current_indices(&self) -> (i32, Vec<i32>, Vec<i32>) {
         let options = self.options_read();
+        let (ordered_list, membership) = self.indices_of_keys(&self.kind.column_keys(&options))
+            .expect("Current addrs missing from AddrIndex!");
+        (options.nonce, ordered_list, membership)
+    }
 
-        let index = self.index.read().unwrap();
-
-        let mut ordered_list = Vec::with_capacity(options.addrs.len());
-
-        let mut membership = {
-            let len = index.len();
-            let mut v = Vec::with_capacity(len);
-            v.extend(iter::repeat(0).take(len));
-            v
+    /**
+     * Gets the addrs as of the given nonce (the current one, or one recorded
+     * in the schema file) as (ordered_list, membership) like
+     * `get_current_indices`, if it is known.
+     */
+    pub fn get_layout_indices(&self, nonce: i32) -> Option<(Vec<i32>, Vec<i32>)> {
+        let keys = {
+            let options = self.options_read();
+            if nonce == options.nonce {
+                self.kind.column_keys(&options)
+            } else {
+                self.schema.layout(nonce)?.keys()
+            }
         };
+        self.indices_of_keys(&keys)
+    }
 
-        for addr in options.addrs.iter() {
-            let i = index.get_index(addr);
+    /**
+     * Gets the layout of results as of the given nonce (the current one, or
+     * one recorded in the schema file), if it is known.
+     */
+    pub fn layout(&self, nonce: i32) -> Option<Layout> {
+        let options = self.options_read();
+        if nonce == options.nonce {
+            Some(Self::layout_of(self.kind, &options))
+        } else {
+            self.schema.layout(nonce)
+        }
+    }
+
+    fn indices_of_keys(&self, keys: &[String]) -> Option<(Vec<i32>, Vec<i32>)> {
+        let index = self.index.read().unwrap();
+        let mut membership = vec![0; index.len()];
+        let mut ordered_list = Vec::with_capacity(keys.len());
+        for key in keys.iter() {
+            let i = index.find_index(key)?;
             ordered_list.push(i);
             membership[i as usize] = SENTINEL_NODATA;
         }
+        Some((ordered_list, membership))
+    }
 
-        (options.nonce, ordered_list, membership)
+    /**
+     * Gets the indices of the given keys (see `column_key`), where they have
+     * any.
+     */
+    pub fn find_indices(&self, keys: &[String]) -> Vec<Option<i32>> {
+        let index = self.index.read().unwrap();
+        keys.iter().map(|key| index.find_index(key)).collect()
+    }
+
+    /**
+     * Gets every layout of this target's results recorded so far (see
+     * `schema.rs`), oldest first.
+     */
+    pub fn layouts(&self) -> Vec<Layout> {
+        self.schema.layouts()
+    }
+
+    /**
+     * Gets the current addrs, followed by those of earlier layouts since
+     * removed (most recently removed first).
+     */
+    pub fn known_addrs(&self) -> Vec<String> {
+        let mut addrs = self.options_read().addrs.clone();
+        for layout in self.schema.layouts().iter().rev() {
+            for addr in layout.addrs.iter() {
+                if !addrs.contains(addr) {
+                    addrs.push(addr.clone());
+                }
+            }
+        }
+        addrs
+    }
+}
+
+/**
+ * Sink appending all results to the data files of their targets.
+ */
+pub struct PersistSink {
+    managers: Vec<Arc<TargetManager>>,
+}
+
+impl PersistSink {
+    pub fn new(managers: &[Arc<TargetManager>]) -> Self {
+        PersistSink {
+            managers: managers.to_vec(),
+        }
     }
 }
 
+impl ResultsSink for PersistSink {
+    fn name(&self) -> &'static str {
+        "Persistence"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        self.managers[results.kind as usize].append_data(results)
+            .map_err(|e| SinkError::Fatal(e.description()))
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        for tm in self.managers.iter() {
+            tm.sync_data().map_err(|e| SinkError::Fatal(e.description()))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn the_first_path_of_a_new_address_is_kept() {
+    let dir = std::env::temp_dir().join(format!("stabping-test-{}-persist", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let manager = TargetManager::new(&crate::traceroute::KIND, &dir, StorageBackend::Files).unwrap();
+
+    let hops = vec![Hop { ip: Some("192.168.1.1".parse().unwrap()), rtt: Some(1200) }, Hop { ip: None, rtt: None }];
+    manager.record_hops("new.example.com", 100, &hops).unwrap();
+    let rounds = manager.hops_in_range("new.example.com", 0, 200).unwrap();
+    assert_eq!(rounds.len(), 1);
+    assert_eq!(rounds[0].time, 100);
+    assert_eq!(rounds[0].hops, hops);
+    drop(manager);
+
+    // the address keeps its index after a restart
+    let manager = TargetManager::new(&crate::traceroute::KIND, &dir, StorageBackend::Files).unwrap();
+    assert_eq!(manager.hops_in_range("new.example.com", 0, 200).unwrap().len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,93 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A fixed-size pool of threads running the rounds of all targets, so that the
+ * number of threads doesn't grow with the number of addresses.
+ */
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use crate::telemetry::PoolStats;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct ThreadPool {
+    jobs: Sender<Job>,
+    stats: Arc<PoolStats>,
+}
+
+impl ThreadPool {
+    /**
+     * Starts a pool of the given number (at least one) of threads.
+     */
+    pub fn new(size: usize) -> Self {
+        let (jobs, queue) = channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let stats = Arc::new(PoolStats::new(size.max(1)));
+
+        for i in 0..size.max(1) {
+            let q = queue.clone();
+            let stats = stats.clone();
+            let spawned = thread::Builder::new().name(format!("pool-{}", i)).spawn(move || {
+                loop {
+                    // only hold the lock while waiting for the next job
+                    let job = match q.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    stats.job_started();
+                    job();
+                    stats.job_done();
+                }
+            });
+            spawned.expect("Unable to start the threads of the pool.");
+        }
+
+        ThreadPool {
+            jobs,
+            stats,
+        }
+    }
+
+    /**
+     * Gets the stats of the threads and queue of this pool.
+     */
+    pub fn stats(&self) -> Arc<PoolStats> {
+        self.stats.clone()
+    }
+
+    /**
+     * Queues the given job to be run by the next idle thread of the pool.
+     */
+    pub fn execute<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        // the threads only stop when the pool is dropped, so this can't fail
+        self.stats.job_queued();
+        let _ = self.jobs.send(Box::new(job));
+    }
+}
+
+#[test]
+fn pool_runs_every_job_on_bounded_threads() {
+    use std::collections::HashSet;
+
+    let pool = ThreadPool::new(3);
+    let (tx, rx) = channel();
+    for i in 0..20 {
+        let t = tx.clone();
+        pool.execute(move || {
+            let _ = t.send((i, thread::current().id()));
+        });
+    }
+    drop(tx);
+
+    let results: Vec<_> = rx.iter().collect();
+    assert_eq!(results.len(), 20);
+    assert!(results.iter().map(|&(_, id)| id).collect::<HashSet<_>>().len() <= 3);
+}
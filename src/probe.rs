@@ -0,0 +1,190 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The plugin trait of target kinds. Each kind implements `Probe`, describing
+ * itself (its kind_id, name, result schema and defaults) and how to perform a
+ * single timed attempt against a single address, resolving it first, and
+ * registers itself as a `TargetKind` in `ALL_KINDS` (see `options.rs`).
+ * Everything else, scheduling rounds and persisting their results included,
+ * is shared by all kinds (see `worker.rs`).
+ */
+use std::net::IpAddr;
+
+use time::precise_time_ns;
+
+use crate::options::AddressFamily;
+use crate::persist::TargetManager;
+use crate::resolve;
+use crate::worker::ProbeSettings;
+
+/**
+ * What the values of a column measure, for exporting them as what they are.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Duration,  // a time, in micros
+    Rate,  // a rate of transfer, in kbit/s
+    Gauge,  // anything else, as is (e.g. whole days, or a status code)
+}
+
+/**
+ * What a kind of target is, and what its options default to.
+ */
+#[derive(Debug)]
+pub struct ProbeSchema {
+    pub kind_id: i32,  // identifying its results, also its index in `ALL_KINDS`
+    pub name: &'static str,  // compact name, used in paths and the API
+    pub columns: &'static [&'static str],  // columns measured by each attempt, the primary value first
+    pub units: &'static [(&'static str, Unit)],  // units of the columns that aren't durations
+    pub default_timeout: u32,  // time to give each attempt, in millis, when the options don't say
+    pub keeps_hops: bool,  // whether it maps the path to each address (see `hops`)
+    pub keeps_ips: bool,  // whether it records the IPs its addresses resolved to (see `ip_of`)
+    pub default_addrs: &'static [&'static str],
+    pub default_interval: u32,
+    pub default_avg_across: u32,
+}
+
+/**
+ * What an attempt is given beyond its address and resolved target.
+ */
+pub struct Attempt<'a> {
+    pub settings: &'a ProbeSettings,
+    pub resolve_nanos: u64,  // time spent resolving the address
+    pub manager: Option<&'a TargetManager>,  // to store more than values with, None for on-demand rounds
+}
+
+/**
+ * A kind of target, probing its addresses.
+ */
+pub trait Probe: Send + Sync {
+    /**
+     * What an address resolves to, e.g. the socket address of a `host:port`.
+     */
+    type Target;
+
+    const SCHEMA: ProbeSchema;
+
+    /**
+     * Parses and resolves the given address, or returns the sentinel to
+     * record for the attempt if it can't be.
+     */
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32>;
+
+    /**
+     * The host (and port) the given address resolves, for fanning it out to
+     * each of the host's IPs (see `fanout.rs`), if it has one.
+     */
+    fn host_of(&self, _addr: &str) -> Option<(String, u16)> {
+        None
+    }
+
+    /**
+     * The IP the given (resolved) address is probed at, recorded round by
+     * round for kinds that keep them (see `TargetManager::record_ips`).
+     */
+    fn ip_of(&self, _target: &Self::Target) -> Option<IpAddr> {
+        None
+    }
+
+    /**
+     * Performs a single timed attempt against the given (resolved) address,
+     * returning the measured durations in nanoseconds (one for each of the
+     * schema's columns), or the sentinel to record if it failed.
+     */
+    fn probe_once(&self, addr: &str, target: Self::Target, attempt: &Attempt) -> Result<Vec<u64>, i32>;
+}
+
+/**
+ * A `Probe` of any target, as the shared machinery holds it.
+ */
+pub trait AnyProbe: Send + Sync {
+    /**
+     * Resolves the given address and performs a single attempt against it
+     * (see `Probe`).
+     */
+    fn attempt(&self, addr: &str, settings: &ProbeSettings,
+               manager: Option<&TargetManager>) -> Result<Vec<u64>, i32>;
+
+    /**
+     * Resolves the host of the given address afresh to all of its IPs of the
+     * given family, none if the address is of an IP literal (or has no host),
+     * or None if it can't be resolved.
+     */
+    fn ips_of(&self, addr: &str, family: AddressFamily) -> Option<Vec<IpAddr>>;
+}
+
+impl<P: Probe> AnyProbe for P {
+    fn attempt(&self, addr: &str, settings: &ProbeSettings,
+               manager: Option<&TargetManager>) -> Result<Vec<u64>, i32> {
+        let start = precise_time_ns();
+        let target = self.resolve(addr, settings)?;
+        let ip = self.ip_of(&target);
+        let attempt = Attempt {
+            settings,
+            resolve_nanos: precise_time_ns() - start,
+            manager,
+        };
+        let result = self.probe_once(addr, target, &attempt);
+        // attempts pinned to an IP are of a fanned out address, which always is at that IP
+        if let (Some(m), Some(ip), None) = (manager, ip, settings.pin) {
+            m.note_ip(addr, ip, result.as_ref().ok().map(|elapsed| (elapsed[0] / 1000) as i32));
+        }
+        result
+    }
+
+    fn ips_of(&self, addr: &str, family: AddressFamily) -> Option<Vec<IpAddr>> {
+        match self.host_of(addr) {
+            Some((host, _)) if host.parse::<IpAddr>().is_ok() => Some(Vec::new()),
+            Some((host, port)) => resolve::resolve_all(&host, port, family),
+            None => Some(Vec::new()),
+        }
+    }
+}
+
+#[test]
+fn attempts_resolve_before_probing() {
+    use std::time::Duration;
+
+    struct Length;
+    impl Probe for Length {
+        type Target = usize;
+        const SCHEMA: ProbeSchema = ProbeSchema {
+            kind_id: 0,
+            name: "length",
+            columns: &["", "dns"],
+            units: &[],
+            default_timeout: 1_000,
+            keeps_hops: false,
+            keeps_ips: false,
+            default_addrs: &[],
+            default_interval: 1_000,
+            default_avg_across: 1,
+        };
+        fn resolve(&self, addr: &str, _: &ProbeSettings) -> Result<usize, i32> {
+            if addr.is_empty() { Err(-5) } else { Ok(addr.len()) }
+        }
+        fn probe_once(&self, _: &str, len: usize, attempt: &Attempt) -> Result<Vec<u64>, i32> {
+            Ok(vec![len as u64, attempt.resolve_nanos])
+        }
+    }
+
+    let settings = ProbeSettings {
+        timeout: Duration::from_secs(1),
+        resolve_ttl: Duration::from_secs(1),
+        family: AddressFamily::Any,
+        pin: None,
+        source: None,
+        proxy: None,
+        dscp: None,
+        tcp: Default::default(),
+    };
+    let probe: &dyn AnyProbe = &Length;
+    assert_eq!(probe.attempt("", &settings, None), Err(-5));
+    assert_eq!(probe.attempt("a:80", &settings, None).unwrap()[0], 4);
+}
@@ -0,0 +1,53 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Just enough of the protobuf wire format to encode the messages stabping
+ * sends (rounds published to message buses, see `publish.rs`, and metrics
+ * exported over OTLP, see `otlp.rs`), and Kafka's varints.
+ */
+
+/**
+ * Appends the given number as a protobuf (and Kafka) base 128 varint.
+ */
+pub fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/**
+ * Maps the given signed number to an unsigned one with small magnitudes
+ * staying small (for `sint` fields and Kafka's varints).
+ */
+#[cfg(any(feature = "nats", feature = "kafka"))]
+pub fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+pub fn field_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+pub fn field_varint(out: &mut Vec<u8>, field: u64, v: u64) {
+    varint(out, field << 3);
+    varint(out, v);
+}
+
+/**
+ * Appends a `fixed64`, `sfixed64` (as its two's complement) or `double` (as
+ * its bits) field.
+ */
+pub fn field_fixed64(out: &mut Vec<u8>, field: u64, v: u64) {
+    varint(out, field << 3 | 1);
+    out.extend_from_slice(&v.to_le_bytes());
+}
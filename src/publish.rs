@@ -0,0 +1,173 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Publishing of every round of results to a message bus, a NATS subject (see
+ * `nats.rs`) or Kafka topic (see `kafka.rs`), to feed larger observability
+ * pipelines. Each is behind a cargo feature of its own.
+ *
+ * Rounds are encoded as JSON, like those of the live results stream (see
+ * `round_json`), or as protobuf, as the `Round` message of `stabping.proto`.
+ * Rounds that fail to publish are buffered and replayed (see `spool.rs`).
+ */
+use std::sync::Arc;
+
+use crate::options::{PublishFormat, TargetResults, SENTINEL_NODATA, sentinel_name};
+use crate::persist::TargetManager;
+use crate::protobuf::{field_bytes, field_varint, zigzag};
+use crate::sink::{ResultsSink, SinkError};
+use crate::spool::{PushError, Spool};
+use crate::webserver::round_json;
+
+/**
+ * A message bus rounds are published to.
+ */
+pub trait Publisher: Send {
+    /**
+     * A short name for the bus, used when reporting its errors.
+     */
+    fn name(&self) -> &'static str;
+
+    /**
+     * Publishes the given encoded round of the given target, collected at
+     * the given time.
+     */
+    fn publish(&mut self, target: &str, timestamp: i64, payload: &[u8]) -> Result<(), PushError>;
+}
+
+/**
+ * Sink publishing all results with the given publisher, in the given format.
+ * Failed publishes are buffered in the given spool.
+ */
+pub struct PublishSink<P: Publisher> {
+    publisher: P,
+    format: PublishFormat,
+    managers: Vec<Arc<TargetManager>>,
+    spool: Spool,
+}
+
+impl<P: Publisher> PublishSink<P> {
+    pub fn new(publisher: P, format: PublishFormat, managers: &[Arc<TargetManager>], spool: Spool) -> Self {
+        PublishSink {
+            publisher,
+            format,
+            managers: managers.to_vec(),
+            spool,
+        }
+    }
+}
+
+impl<P: Publisher> ResultsSink for PublishSink<P> {
+    fn name(&self) -> &'static str {
+        self.publisher.name()
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        let (target, payload) = {
+            let manager = &self.managers[results.kind as usize];
+            let options = manager.options_read();
+            if results.nonce != options.nonce {
+                return Ok(());
+            }
+            let target = manager.kind.compact_name();
+            let columns = manager.kind.columns(&options);
+            let payload = match self.format {
+                PublishFormat::Json => round_json(target, results, &options.addrs, &columns).to_string().into_bytes(),
+                PublishFormat::Protobuf => round_protobuf(target, results, &options.addrs, &columns),
+            };
+            (target, payload)
+        };
+
+        let publisher = &mut self.publisher;
+        self.spool.send(spool_entry(target, results.timestamp, &payload), |entry, _| {
+            let (target, timestamp, payload) = parse_spool_entry(entry)
+                .ok_or_else(|| PushError::Reject("malformed buffered round".to_owned()))?;
+            publisher.publish(target, timestamp, payload)
+        })
+    }
+}
+
+/**
+ * Makes what is buffered of a round: its target and timestamp on a line of
+ * their own, followed by its encoding.
+ */
+fn spool_entry(target: &str, timestamp: i64, payload: &[u8]) -> Vec<u8> {
+    let mut entry = format!("{} {}\n", target, timestamp).into_bytes();
+    entry.extend_from_slice(payload);
+    entry
+}
+
+fn parse_spool_entry(entry: &[u8]) -> Option<(&str, i64, &[u8])> {
+    let newline = entry.iter().position(|&b| b == b'\n')?;
+    let (target, timestamp) = std::str::from_utf8(&entry[..newline]).ok()?.split_once(' ')?;
+    Some((target, timestamp.parse().ok()?, &entry[newline + 1..]))
+}
+
+/**
+ * Encodes the given round as the `Round` message of `stabping.proto`, with
+ * the same contents as `round_json`.
+ */
+pub fn round_protobuf(target: &str, results: &TargetResults, addrs: &[String], columns: &[String]) -> Vec<u8> {
+    let mut out = Vec::new();
+    field_bytes(&mut out, 1, target.as_bytes());
+    field_varint(&mut out, 2, results.timestamp as u64);
+    field_varint(&mut out, 3, results.nonce as i64 as u64);
+
+    for (addr, vals) in addrs.iter().zip(results.vals.chunks(columns.len().max(1))) {
+        // addrs not probed in this round have no values
+        if vals[0] == SENTINEL_NODATA {
+            continue;
+        }
+        let mut a = Vec::new();
+        field_bytes(&mut a, 1, addr.as_bytes());
+        for (column, &v) in columns.iter().zip(vals) {
+            let name = if column.is_empty() { "value" } else { column.as_str() };
+            let mut entry = Vec::new();
+            field_bytes(&mut entry, 1, name.as_bytes());
+            field_varint(&mut entry, 2, zigzag(v as i64));
+            field_bytes(&mut a, 2, &entry);
+        }
+        if vals[0] < 0 {
+            field_bytes(&mut a, 3, sentinel_name(vals[0]).as_bytes());
+        }
+        field_bytes(&mut out, 4, &a);
+    }
+    out
+}
+
+#[test]
+fn rounds_encode_as_protobuf() {
+    let r = TargetResults {
+        kind: 0,
+        nonce: 1,
+        timestamp: 1_500_000_000,
+        vals: vec![1200, 0, SENTINEL_NODATA, SENTINEL_NODATA, -2_100_000_005, 100],
+    };
+    let columns = vec!["".to_owned(), "loss".to_owned()];
+    let addrs = vec!["a:80".to_owned(), "b:80".to_owned(), "c:80".to_owned()];
+    let encoded = round_protobuf("tcpping", &r, &addrs, &columns);
+
+    let mut expected = vec![0x0a, 7];
+    expected.extend_from_slice(b"tcpping");
+    expected.extend_from_slice(&[0x10, 0x80, 0xde, 0xa0, 0xcb, 0x05, 0x18, 0x01]);
+    // a:80 with value 1200 (zigzag 2400) and loss 0, and c:80 failed
+    expected.extend_from_slice(&[0x22, 28, 0x0a, 4]);
+    expected.extend_from_slice(b"a:80");
+    expected.extend_from_slice(&[0x12, 10, 0x0a, 5]);
+    expected.extend_from_slice(b"value");
+    expected.extend_from_slice(&[0x10, 0xe0, 0x12, 0x12, 8, 0x0a, 4]);
+    expected.extend_from_slice(b"loss");
+    expected.extend_from_slice(&[0x10, 0x00]);
+    assert_eq!(&encoded[..expected.len()], &expected[..]);
+    assert_eq!(encoded[expected.len()], 0x22);
+    assert!(encoded.windows(4).any(|w| w == b"c:80") && !encoded.windows(4).any(|w| w == b"b:80"));
+    assert!(encoded.ends_with(sentinel_name(-2_100_000_005).as_bytes()));
+
+    let entry = spool_entry("tcpping", 1_500_000_000, &encoded);
+    assert_eq!(parse_spool_entry(&entry), Some(("tcpping", 1_500_000_000, &encoded[..])));
+}
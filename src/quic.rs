@@ -0,0 +1,990 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * QUIC handshake data collection, timing the handshake of a QUIC (RFC 9000)
+ * connection, and for URLs, the HTTP/3 (RFC 9114) GET following it, since
+ * more and more traffic goes over UDP, which TCP handshakes say nothing
+ * about.
+ *
+ * Only as much of QUIC is done as a client needs to get through the
+ * handshake (and a single request) on a working path: what the server sends
+ * is acknowledged (so that it may send past its anti-amplification limit) and
+ * reassembled, but nothing is retransmitted but the first flight, and the
+ * connection is closed as soon as it's measured. TLS (and the keys protecting
+ * packets) is left to rustls.
+ */
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use rustls::{ClientConfig, Side};
+use rustls::pki_types::ServerName;
+use rustls::quic::{self, KeyChange, Keys, Version};
+use time::precise_time_ns;
+
+use crate::http;
+use crate::http::Url;
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_REFUSED, SENTINEL_RESOLVE, SENTINEL_TIMEOUT,
+                     SENTINEL_TLS, io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::resolve;
+use crate::sockopt;
+use crate::worker::ProbeSettings;
+
+const VERSION_1: u32 = 1;
+
+/**
+ * Length of the connection IDs chosen by either end here.
+ */
+const CID_LEN: usize = 8;
+
+/**
+ * Size datagrams carrying Initial packets from the client are padded to.
+ */
+const MIN_INITIAL_SIZE: usize = 1200;
+
+/**
+ * Delay after which the first flight is sent again while nothing came back.
+ */
+const RESEND_AFTER: Duration = Duration::from_secs(1);
+
+const DEFAULT_PORT: u16 = 443;
+
+pub static KIND: TargetKind = TargetKind::of(&Quic);
+
+/**
+ * The QUIC Handshake target, timing a single QUIC handshake with each addr, a
+ * `host:port` (or `host` if on port 443), or the `https://` URL to make an
+ * HTTP/3 GET for once the handshake is done.
+ *
+ * Returns the handshake time followed by the time spent resolving the host
+ * beforehand, and for URLs, the time to first byte of the response after the
+ * handshake (0 otherwise).
+ */
+pub struct Quic;
+
+impl Probe for Quic {
+    type Target = (String, Option<String>, SocketAddr);  // the host, the path to GET (if any), and its address
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 11,
+        name: "quic",
+        columns: &["", "dns", "ttfb"],
+        units: &[],
+        default_timeout: 30_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &["google.com:443", "cloudflare-quic.com:443"],
+        default_interval: 60_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32> {
+        let (host, port, path) = parse_addr(addr).ok_or(SENTINEL_ERROR)?;
+        let sock_addr = resolve::resolve(&host, port, settings).ok_or(SENTINEL_RESOLVE)?;
+        Ok((host, path, sock_addr))
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        parse_addr(addr).map(|(host, port, _)| (host, port))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.2.ip())
+    }
+
+    fn probe_once(&self, _: &str, (host, path, sock_addr): Self::Target,
+                  attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        let (handshake, ttfb) = quic_once(client_config(), &host, path.as_deref(), sock_addr, attempt.settings)?;
+        Ok(vec![handshake, attempt.resolve_nanos, ttfb])
+    }
+}
+
+/**
+ * Splits the given addr into its host, port and the path to GET (for URLs).
+ */
+fn parse_addr(addr: &str) -> Option<(String, u16, Option<String>)> {
+    if addr.contains("://") {
+        let url = Url::parse(addr).filter(|u| u.https)?;
+        return Some((url.host, url.port, Some(url.path)));
+    }
+    match resolve::split_host_port(addr) {
+        Some((host, port)) => Some((host.to_owned(), port, None)),
+        None if !addr.is_empty() && !addr.contains(' ') => Some((addr.to_owned(), DEFAULT_PORT, None)),
+        None => None,
+    }
+}
+
+/**
+ * Returns the TLS client configuration of QUIC connections: trusting the
+ * Mozilla root certificates, and offering HTTP/3.
+ */
+fn client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        with_h3(http::client_config(None, None).expect("the Mozilla roots make a configuration"))
+    }).clone()
+}
+
+/**
+ * Makes the given TLS client configuration offer HTTP/3 (as servers reject
+ * QUIC connections without an application protocol).
+ */
+fn with_h3(config: Arc<ClientConfig>) -> Arc<ClientConfig> {
+    let mut config = (*config).clone();
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    Arc::new(config)
+}
+
+fn put_varint(buf: &mut Vec<u8>, v: u64) {
+    match v {
+        0..=0x3f => buf.push(v as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(v as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(v as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(v | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let first = *buf.get(*pos)?;
+    let len = 1 << (first >> 6);
+    let bytes = buf.get(*pos..*pos + len)?;
+    *pos += len;
+    Some(bytes[1..].iter().fold((first & 0x3f) as u64, |v, &b| v << 8 | b as u64))
+}
+
+fn get_bytes<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let bytes = buf.get(*pos..pos.checked_add(len)?)?;
+    *pos += len;
+    Some(bytes)
+}
+
+/**
+ * Recovers the full packet number from the given truncated one, given the
+ * largest received so far in its space (RFC 9000, Appendix A.3).
+ */
+fn decode_pn(largest: Option<u64>, truncated: u64, bits: u32) -> u64 {
+    let expected = largest.map_or(0, |l| l + 1);
+    let window = 1u64 << bits;
+    let candidate = (expected & !(window - 1)) | truncated;
+    if candidate + window / 2 <= expected && candidate < (1 << 62) - window {
+        candidate + window
+    } else if candidate > expected + window / 2 && candidate >= window {
+        candidate - window
+    } else {
+        candidate
+    }
+}
+
+/**
+ * The packet number spaces (and encryption levels), in the order their
+ * packets are coalesced.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    Initial,
+    Handshake,
+    OneRtt,
+}
+
+/**
+ * Builds a packet of the given level with the given frames, protected with
+ * the given keys. Long headers carry both connection IDs (and Initial packets
+ * the given token, padded to `MIN_INITIAL_SIZE`), short ones just the
+ * destination's.
+ */
+pub fn seal(level: Level, keys: &Keys, dcid: &[u8], scid: &[u8], token: &[u8], pn: u64,
+            mut payload: Vec<u8>) -> Vec<u8> {
+    let tag_len = keys.local.packet.tag_len();
+    let mut header = Vec::new();
+    match level {
+        Level::OneRtt => {
+            header.push(0x40 | 0x03);  // fixed bit, and a 4-byte packet number
+            header.extend_from_slice(dcid);
+        },
+        _ => {
+            let packet_type = if level == Level::Initial { 0 } else { 2 };
+            header.push(0xc0 | packet_type << 4 | 0x03);
+            header.extend_from_slice(&VERSION_1.to_be_bytes());
+            header.push(dcid.len() as u8);
+            header.extend_from_slice(dcid);
+            header.push(scid.len() as u8);
+            header.extend_from_slice(scid);
+            if level == Level::Initial {
+                put_varint(&mut header, token.len() as u64);
+                header.extend_from_slice(token);
+            }
+            // the length (always as two bytes) is of the packet number and protected payload
+            let unpadded = header.len() + 2 + 4 + payload.len() + tag_len;
+            if level == Level::Initial && unpadded < MIN_INITIAL_SIZE {
+                payload.resize(payload.len() + MIN_INITIAL_SIZE - unpadded, 0);
+            }
+            header.extend_from_slice(&(0x4000 | (4 + payload.len() + tag_len) as u16).to_be_bytes());
+        },
+    }
+    let pn_offset = header.len();
+    header.extend_from_slice(&(pn as u32).to_be_bytes());
+    // the header protection sample is taken 4 bytes past the start of the packet number
+    if payload.len() + tag_len < 4 + keys.local.header.sample_len() {
+        payload.resize(4 + keys.local.header.sample_len() - tag_len, 0);
+    }
+
+    let tag = keys.local.packet.encrypt_in_place(pn, &header, &mut payload)
+        .expect("packets within the confidentiality limit seal");
+    let mut packet = header;
+    packet.extend_from_slice(&payload);
+    packet.extend_from_slice(tag.as_ref());
+
+    let sample_at = pn_offset + 4;
+    let sample = packet[sample_at..sample_at + keys.local.header.sample_len()].to_vec();
+    let (head, rest) = packet.split_at_mut(pn_offset);
+    keys.local.header.encrypt_in_place(&sample, &mut head[0], &mut rest[..4])
+        .expect("samples are of the right length");
+    packet
+}
+
+/**
+ * A packet received, before it's opened.
+ */
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    Protected { level: Level, dcid: Vec<u8>, scid: Vec<u8>, start: usize, pn_offset: usize, end: usize },
+    Retry { scid: Vec<u8>, token: Vec<u8> },
+    VersionNegotiation,
+}
+
+/**
+ * Parses the header of the packet at the given offset of the given datagram
+ * (whose short-header packets have destination connection IDs of `CID_LEN`),
+ * or returns None if it isn't one.
+ */
+pub fn parse_packet(datagram: &[u8], start: usize) -> Option<Incoming> {
+    let mut pos = start;
+    let first = *datagram.get(pos)?;
+    pos += 1;
+    if first & 0x80 == 0 {
+        let dcid = get_bytes(datagram, &mut pos, CID_LEN)?.to_vec();
+        return Some(Incoming::Protected {
+            level: Level::OneRtt, dcid, scid: Vec::new(), start, pn_offset: pos, end: datagram.len(),
+        });
+    }
+
+    let version = u32::from_be_bytes(get_bytes(datagram, &mut pos, 4)?.try_into().ok()?);
+    let dcid_len = *get_bytes(datagram, &mut pos, 1)?.first()? as usize;
+    let dcid = get_bytes(datagram, &mut pos, dcid_len)?.to_vec();
+    let scid_len = *get_bytes(datagram, &mut pos, 1)?.first()? as usize;
+    let scid = get_bytes(datagram, &mut pos, scid_len)?.to_vec();
+    if version == 0 {
+        return Some(Incoming::VersionNegotiation);
+    }
+    if version != VERSION_1 {
+        return None;
+    }
+    let level = match (first >> 4) & 0x03 {
+        0 => {
+            let token_len = get_varint(datagram, &mut pos)? as usize;
+            get_bytes(datagram, &mut pos, token_len)?;
+            Level::Initial
+        },
+        2 => Level::Handshake,
+        3 => {
+            // the token, followed by the integrity tag
+            let token = datagram.get(pos..datagram.len().checked_sub(16)?)?.to_vec();
+            return Some(Incoming::Retry { scid, token });
+        },
+        _ => return None,  // 0-RTT, which servers don't send
+    };
+    let len = get_varint(datagram, &mut pos)? as usize;
+    let end = pos.checked_add(len).filter(|&e| e <= datagram.len())?;
+    Some(Incoming::Protected { level, dcid, scid, start, pn_offset: pos, end })
+}
+
+/**
+ * Removes the protection of the packet spanning the given range of the given
+ * datagram (its packet number starting at `pn_offset`) with the given keys,
+ * returning its packet number and frames, or None if it doesn't open.
+ */
+pub fn open(datagram: &mut [u8], start: usize, pn_offset: usize, end: usize, keys: &Keys,
+            largest: Option<u64>) -> Option<(u64, Vec<u8>)> {
+    let sample_len = keys.remote.header.sample_len();
+    if pn_offset + 4 + sample_len > end {
+        return None;
+    }
+    let sample = datagram[pn_offset + 4..pn_offset + 4 + sample_len].to_vec();
+    let packet = &mut datagram[start..end];
+    let pn_at = pn_offset - start;
+    let (head, rest) = packet.split_at_mut(pn_at);
+    keys.remote.header.decrypt_in_place(&sample, &mut head[0], &mut rest[..4]).ok()?;
+    let pn_len = (packet[0] & 0x03) as usize + 1;
+    let truncated = packet[pn_at..pn_at + pn_len].iter().fold(0u64, |v, &b| v << 8 | b as u64);
+    let pn = decode_pn(largest, truncated, pn_len as u32 * 8);
+
+    let (header, payload) = packet.split_at_mut(pn_at + pn_len);
+    let frames = keys.remote.packet.decrypt_in_place(pn, header, payload).ok()?;
+    Some((pn, frames.to_vec()))
+}
+
+/**
+ * What a frame said, as far as it matters here.
+ */
+#[derive(Debug, PartialEq)]
+pub enum Frame<'a> {
+    Padding,
+    Ack,
+    Crypto(u64, &'a [u8]),  // data at an offset of the handshake
+    Stream(u64, u64, &'a [u8], bool),  // data at an offset of a stream, and whether it ends there
+    Close(u64, bool),  // error code, and whether it's the application's (rather than QUIC's)
+    Other,
+}
+
+/**
+ * Parses the given frames, or returns None if they're malformed (or of a
+ * kind unknown here).
+ */
+pub fn parse_frames(payload: &[u8]) -> Option<Vec<Frame<'_>>> {
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while pos < payload.len() {
+        let frame_type = get_varint(payload, &mut pos)?;
+        let varints = |pos: &mut usize, n: usize| -> Option<()> {
+            for _ in 0..n {
+                get_varint(payload, pos)?;
+            }
+            Some(())
+        };
+        frames.push(match frame_type {
+            0x00 => Frame::Padding,
+            0x01 | 0x1e => Frame::Other,  // PING and HANDSHAKE_DONE
+            0x02 | 0x03 => {
+                varints(&mut pos, 2)?;
+                let ranges = get_varint(payload, &mut pos)? as usize;
+                varints(&mut pos, 1 + ranges.checked_mul(2)?)?;
+                if frame_type == 0x03 {
+                    varints(&mut pos, 3)?;
+                }
+                Frame::Ack
+            },
+            0x04 => { varints(&mut pos, 3)?; Frame::Other },  // RESET_STREAM
+            0x05 => { varints(&mut pos, 2)?; Frame::Other },  // STOP_SENDING
+            0x06 => {
+                let offset = get_varint(payload, &mut pos)?;
+                let len = get_varint(payload, &mut pos)? as usize;
+                Frame::Crypto(offset, get_bytes(payload, &mut pos, len)?)
+            },
+            0x07 => {
+                let len = get_varint(payload, &mut pos)? as usize;
+                get_bytes(payload, &mut pos, len)?;
+                Frame::Other
+            },
+            0x08..=0x0f => {
+                let id = get_varint(payload, &mut pos)?;
+                let offset = if frame_type & 0x04 != 0 { get_varint(payload, &mut pos)? } else { 0 };
+                let len = if frame_type & 0x02 != 0 {
+                    get_varint(payload, &mut pos)? as usize
+                } else {
+                    payload.len() - pos
+                };
+                Frame::Stream(id, offset, get_bytes(payload, &mut pos, len)?, frame_type & 0x01 != 0)
+            },
+            0x10 | 0x12 | 0x13 | 0x14 | 0x16 | 0x17 | 0x19 => { varints(&mut pos, 1)?; Frame::Other },
+            0x11 | 0x15 => { varints(&mut pos, 2)?; Frame::Other },
+            0x18 => {
+                varints(&mut pos, 2)?;
+                let len = *get_bytes(payload, &mut pos, 1)?.first()? as usize;
+                get_bytes(payload, &mut pos, len + 16)?;
+                Frame::Other
+            },
+            0x1a | 0x1b => { get_bytes(payload, &mut pos, 8)?; Frame::Other },
+            0x1c | 0x1d => {
+                let code = get_varint(payload, &mut pos)?;
+                if frame_type == 0x1c {
+                    varints(&mut pos, 1)?;
+                }
+                let len = get_varint(payload, &mut pos)? as usize;
+                get_bytes(payload, &mut pos, len)?;
+                Frame::Close(code, frame_type == 0x1d)
+            },
+            _ => return None,
+        });
+    }
+    Some(frames)
+}
+
+/**
+ * Builds an ACK frame acknowledging all of the given packet numbers.
+ */
+pub fn ack_frame(received: &BTreeSet<u64>) -> Vec<u8> {
+    // runs of consecutive packet numbers, from the largest down
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &pn in received.iter().rev() {
+        match ranges.last_mut() {
+            Some(&mut (_, ref mut low)) if *low == pn + 1 => *low = pn,
+            _ => ranges.push((pn, pn)),
+        }
+    }
+    let mut frame = vec![0x02];
+    let (largest, low) = ranges[0];
+    put_varint(&mut frame, largest);
+    put_varint(&mut frame, 0);  // delay
+    put_varint(&mut frame, ranges.len() as u64 - 1);
+    put_varint(&mut frame, largest - low);
+    for w in ranges.windows(2) {
+        let ((_, prev_low), (high, low)) = (w[0], w[1]);
+        put_varint(&mut frame, prev_low - high - 2);
+        put_varint(&mut frame, high - low);
+    }
+    frame
+}
+
+pub fn crypto_frame(offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x06];
+    put_varint(&mut frame, offset);
+    put_varint(&mut frame, data.len() as u64);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/**
+ * Builds a STREAM frame of the given data at the start of the given stream,
+ * ending it if asked to.
+ */
+pub fn stream_frame(id: u64, data: &[u8], fin: bool) -> Vec<u8> {
+    let mut frame = vec![0x0a | fin as u8];
+    put_varint(&mut frame, id);
+    put_varint(&mut frame, data.len() as u64);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/**
+ * Builds the transport parameters (RFC 9000, section 18.2) of a client with
+ * the given source connection ID, accepting the response to a request and
+ * the server's control and QPACK streams.
+ */
+fn transport_parameters(scid: &[u8]) -> Vec<u8> {
+    let mut params = Vec::new();
+    let mut put = |id: u64, value: &[u8]| {
+        put_varint(&mut params, id);
+        put_varint(&mut params, value.len() as u64);
+        params.extend_from_slice(value);
+    };
+    let varint = |v: u64| {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, v);
+        buf
+    };
+    put(0x01, &varint(30_000));  // max_idle_timeout
+    put(0x04, &varint(1 << 20));  // initial_max_data
+    put(0x05, &varint(1 << 18));  // initial_max_stream_data_bidi_local
+    put(0x07, &varint(1 << 16));  // initial_max_stream_data_uni
+    put(0x09, &varint(3));  // initial_max_streams_uni
+    put(0x0f, scid);  // initial_source_connection_id
+    params
+}
+
+/**
+ * Appends the given integer with an N-bit prefix (RFC 7541, section 5.1) to
+ * the given QPACK field section, the bits above it set as given.
+ */
+fn put_prefixed(buf: &mut Vec<u8>, flags: u8, bits: u32, v: usize) {
+    let max = (1usize << bits) - 1;
+    if v < max {
+        buf.push(flags | v as u8);
+        return;
+    }
+    buf.push(flags | max as u8);
+    let mut rest = v - max;
+    while rest >= 0x80 {
+        buf.push((rest % 0x80) as u8 | 0x80);
+        rest /= 0x80;
+    }
+    buf.push(rest as u8);
+}
+
+/**
+ * Builds the HEADERS frame of a GET for the given path of the given host,
+ * its fields encoded against the QPACK static table alone (RFC 9204).
+ */
+pub fn get_request(host: &str, path: &str) -> Vec<u8> {
+    let mut fields = vec![0x00, 0x00];  // no dynamic table entries referred to
+    fields.push(0xc0 | 17);  // :method GET
+    fields.push(0xc0 | 23);  // :scheme https
+    for (name, value) in [(0, host), (1, path)] {  // :authority and :path
+        put_prefixed(&mut fields, 0x50, 4, name);
+        put_prefixed(&mut fields, 0x00, 7, value.len());
+        fields.extend_from_slice(value.as_bytes());
+    }
+    let mut frame = vec![0x01];
+    put_varint(&mut frame, fields.len() as u64);
+    frame.extend_from_slice(&fields);
+    frame
+}
+
+/**
+ * The state of a packet number space on our end.
+ */
+#[derive(Default)]
+struct Space {
+    keys: Option<Keys>,
+    next_pn: u64,
+    received: BTreeSet<u64>,
+    ack_pending: bool,
+    crypto_sent: Vec<u8>,  // the handshake data sent so far
+    crypto_unsent: usize,  // how much of it at the end is yet to be sent
+    crypto_in: BTreeMap<u64, Vec<u8>>,  // handshake data received ahead of the rest, by offset
+    crypto_read: u64,  // how much of the handshake data received was handed to TLS
+}
+
+/**
+ * A client end of a QUIC connection, through its handshake.
+ */
+struct Connection {
+    tls: quic::ClientConnection,
+    spaces: [Space; 3],
+    writing: Level,  // the level TLS writes at
+    dcid: Vec<u8>,
+    scid: Vec<u8>,
+    token: Vec<u8>,
+    heard_from: bool,  // whether the server sent a packet that opened yet
+    initial_dropped: bool,
+    streams_out: Vec<u8>,  // STREAM frames to send in the next 1-RTT packet
+}
+
+/**
+ * Gets a connection ID chosen at random.
+ */
+fn random_cid() -> Vec<u8> {
+    // hashers of new `RandomState`s are randomly seeded, which is random enough here
+    RandomState::new().build_hasher().finish().to_be_bytes().to_vec()
+}
+
+fn initial_keys(dcid: &[u8], side: Side) -> Keys {
+    let suite = rustls::crypto::ring::cipher_suite::TLS13_AES_128_GCM_SHA256.tls13()
+        .and_then(|s| s.quic_suite())
+        .expect("TLS_AES_128_GCM_SHA256 is a QUIC suite");
+    suite.keys(dcid, side, Version::V1)
+}
+
+impl Connection {
+    fn new(config: Arc<ClientConfig>, host: &str) -> Result<Self, i32> {
+        let name = ServerName::try_from(host.to_owned()).map_err(|_| SENTINEL_ERROR)?;
+        let (dcid, scid) = (random_cid(), random_cid());
+        let tls = quic::ClientConnection::new(config, Version::V1, name, transport_parameters(&scid))
+            .map_err(|_| SENTINEL_TLS)?;
+        let mut conn = Connection {
+            tls,
+            spaces: Default::default(),
+            writing: Level::Initial,
+            dcid,
+            scid,
+            token: Vec::new(),
+            heard_from: false,
+            initial_dropped: false,
+            streams_out: Vec::new(),
+        };
+        conn.spaces[0].keys = Some(initial_keys(&conn.dcid, Side::Client));
+        conn.write_tls();
+        Ok(conn)
+    }
+
+    /**
+     * Takes what TLS has to send, installing the keys it hands out.
+     */
+    fn write_tls(&mut self) {
+        loop {
+            let mut buf = Vec::new();
+            let change = self.tls.write_hs(&mut buf);
+            let space = &mut self.spaces[self.writing as usize];
+            space.crypto_unsent += buf.len();
+            space.crypto_sent.extend_from_slice(&buf);
+            match change {
+                Some(KeyChange::Handshake { keys }) => {
+                    self.spaces[Level::Handshake as usize].keys = Some(keys);
+                    self.writing = Level::Handshake;
+                },
+                Some(KeyChange::OneRtt { keys, .. }) => {
+                    self.spaces[Level::OneRtt as usize].keys = Some(keys);
+                    self.writing = Level::OneRtt;
+                },
+                None => return,
+            }
+        }
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.tls.is_handshaking()
+    }
+
+    /**
+     * Builds the next datagram, coalescing a packet of each level with
+     * anything to send (acknowledgments, handshake data, STREAM frames and
+     * the closing frame, if given one, at the highest level), or returns None
+     * if there's nothing to send.
+     */
+    fn datagram(&mut self, close: Option<(u8, u64)>) -> Option<Vec<u8>> {
+        let close_level = [Level::OneRtt, Level::Handshake, Level::Initial].into_iter()
+            .find(|&l| self.spaces[l as usize].keys.is_some() && !(l == Level::Initial && self.initial_dropped));
+        let mut datagram = Vec::new();
+        for level in [Level::Initial, Level::Handshake, Level::OneRtt] {
+            if level == Level::Initial && self.initial_dropped {
+                continue;
+            }
+            let space = &mut self.spaces[level as usize];
+            let keys = match space.keys {
+                Some(ref keys) => keys,
+                None => continue,
+            };
+            let mut frames = Vec::new();
+            if space.ack_pending && !space.received.is_empty() {
+                frames.extend(ack_frame(&space.received));
+                space.ack_pending = false;
+            }
+            if space.crypto_unsent > 0 {
+                let offset = space.crypto_sent.len() - space.crypto_unsent;
+                frames.extend(crypto_frame(offset as u64, &space.crypto_sent[offset..]));
+                space.crypto_unsent = 0;
+            }
+            if level == Level::OneRtt {
+                frames.append(&mut self.streams_out);
+            }
+            if let (Some((frame_type, code)), true) = (close, close_level == Some(level)) {
+                frames.push(frame_type);
+                put_varint(&mut frames, code);
+                if frame_type == 0x1c {
+                    frames.push(0x00);  // frame type that caused it
+                }
+                frames.push(0x00);  // no reason phrase
+            }
+            if frames.is_empty() {
+                continue;
+            }
+            let pn = space.next_pn;
+            space.next_pn += 1;
+            datagram.extend(seal(level, keys, &self.dcid, &self.scid, &self.token, pn, frames));
+            // clients stop using Initial packets once they send a Handshake one
+            if level == Level::Handshake {
+                self.initial_dropped = true;
+            }
+        }
+        if datagram.is_empty() { None } else { Some(datagram) }
+    }
+
+    /**
+     * Processes the given datagram, returning when the response on stream 0
+     * started (if it did in it), or the sentinel to record if the connection
+     * failed.
+     */
+    fn receive(&mut self, datagram: &mut [u8]) -> Result<bool, i32> {
+        let mut responded = false;
+        let mut start = 0;
+        while start < datagram.len() {
+            let (level, scid, pn_offset, end) = match parse_packet(datagram, start) {
+                Some(Incoming::Protected { level, scid, pn_offset, end, .. }) => (level, scid, pn_offset, end),
+                Some(Incoming::Retry { scid, token }) if !self.heard_from && self.token.is_empty() => {
+                    // starting over towards the connection ID the server chose, with its token
+                    self.dcid = scid;
+                    self.token = token;
+                    let initial = &mut self.spaces[Level::Initial as usize];
+                    initial.keys = Some(initial_keys(&self.dcid, Side::Client));
+                    initial.crypto_unsent = initial.crypto_sent.len();
+                    return Ok(false);
+                },
+                Some(Incoming::VersionNegotiation) if !self.heard_from => return Err(SENTINEL_ERROR),
+                _ => return Ok(responded),
+            };
+            if level == Level::Initial && self.initial_dropped {
+                start = end;
+                continue;
+            }
+            let space = &mut self.spaces[level as usize];
+            let opened = match space.keys {
+                Some(ref keys) => open(datagram, start, pn_offset, end, keys, space.received.last().copied()),
+                None => None,
+            };
+            start = end;
+            let (pn, payload) = match opened {
+                Some(o) => o,
+                None => continue,
+            };
+            if level != Level::OneRtt && !self.heard_from {
+                self.dcid = scid;
+            }
+            self.heard_from = true;
+            space.received.insert(pn);
+
+            let frames = parse_frames(&payload).ok_or(SENTINEL_ERROR)?;
+            for frame in frames {
+                match frame {
+                    Frame::Padding | Frame::Ack => (),
+                    Frame::Crypto(offset, data) => {
+                        space.ack_pending = true;
+                        if offset + data.len() as u64 > space.crypto_read {
+                            space.crypto_in.insert(offset, data.to_vec());
+                        }
+                    },
+                    Frame::Stream(0, _, data, fin) => {
+                        space.ack_pending = true;
+                        responded |= !data.is_empty() || fin;
+                    },
+                    Frame::Stream(..) | Frame::Other => space.ack_pending = true,
+                    // crypto errors (TLS alerts) are 0x100 and up
+                    Frame::Close(code, false) if (0x100..0x200).contains(&code) => return Err(SENTINEL_TLS),
+                    Frame::Close(0x02, false) => return Err(SENTINEL_REFUSED),
+                    Frame::Close(..) => return Err(SENTINEL_ERROR),
+                }
+            }
+
+            // hand TLS what's contiguous of the handshake data
+            while let Some((&offset, _)) = space.crypto_in.iter().next() {
+                if offset > space.crypto_read {
+                    break;
+                }
+                let data = space.crypto_in.remove(&offset).unwrap_or_default();
+                let skip = (space.crypto_read - offset) as usize;
+                if skip < data.len() {
+                    self.tls.read_hs(&data[skip..]).map_err(|_| SENTINEL_TLS)?;
+                    space.crypto_read += (data.len() - skip) as u64;
+                }
+            }
+            self.write_tls();
+        }
+        Ok(responded)
+    }
+}
+
+/**
+ * Receives a datagram on the given socket within the given deadline.
+ */
+fn recv(socket: &UdpSocket, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed"));
+    }
+    socket.set_read_timeout(Some(remaining))?;
+    socket.recv(buf)
+}
+
+/**
+ * Times a single QUIC handshake with the given host at the given address, and
+ * if given a path, the GET for it following, returning the handshake time and
+ * the time to first byte of the response (0 without a path).
+ */
+fn quic_once(config: Arc<ClientConfig>, host: &str, path: Option<&str>, sock_addr: SocketAddr,
+             settings: &ProbeSettings) -> Result<(u64, u64), i32> {
+    let socket = sockopt::udp_socket(&sock_addr, settings).map_err(|_| SENTINEL_ERROR)?;
+    socket.connect(sock_addr).map_err(|e| io_error_sentinel(&e))?;
+    let mut conn = Connection::new(config, host)?;
+
+    let first_flight = conn.datagram(None).ok_or(SENTINEL_ERROR)?;
+    let start = precise_time_ns();
+    let deadline = Instant::now() + settings.timeout;
+    socket.send(&first_flight).map_err(|e| io_error_sentinel(&e))?;
+
+    let mut buf = vec![0u8; 65536];
+    let mut handshaken = None;
+    loop {
+        let wait_until = if conn.heard_from { deadline } else { deadline.min(Instant::now() + RESEND_AFTER) };
+        let len = match recv(&socket, &mut buf, wait_until) {
+            Ok(len) => len,
+            Err(ref e) if !conn.heard_from && Instant::now() < deadline
+                && matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                socket.send(&first_flight).map_err(|e| io_error_sentinel(&e))?;
+                continue;
+            },
+            Err(e) => return Err(match io_error_sentinel(&e) {
+                s if s == SENTINEL_ERROR => SENTINEL_TIMEOUT,
+                s => s,
+            }),
+        };
+        let was_handshaking = conn.is_handshaking();
+        let responded = conn.receive(&mut buf[..len])?;
+        let now = precise_time_ns();
+
+        let done = match (handshaken, path) {
+            (None, _) if was_handshaking && !conn.is_handshaking() => {
+                handshaken = Some(now);
+                match path {
+                    Some(path) => {
+                        // the control stream (with empty SETTINGS), and the request
+                        conn.streams_out.extend(stream_frame(2, &[0x00, 0x04, 0x00], false));
+                        conn.streams_out.extend(stream_frame(0, &get_request(host, path), true));
+                        None
+                    },
+                    None => Some(0),
+                }
+            },
+            (Some(at), Some(_)) if responded => Some(now - at),
+            _ => None,
+        };
+        if let (Some(ttfb), Some(at)) = (done, handshaken) {
+            // H3_NO_ERROR, in an application close once there are 1-RTT keys
+            if let Some(d) = conn.datagram(Some(if path.is_some() { (0x1d, 0x100) } else { (0x1c, 0) })) {
+                let _ = socket.send(&d);
+            }
+            return Ok((at - start, ttfb));
+        }
+        if let Some(d) = conn.datagram(None) {
+            socket.send(&d).map_err(|e| io_error_sentinel(&e))?;
+        }
+        if conn.is_handshaking() && Instant::now() >= deadline {
+            return Err(SENTINEL_TIMEOUT);
+        }
+    }
+}
+
+#[test]
+fn initial_packets_are_protected_like_rfc_9001_says() {
+    // RFC 9001, Appendix A.2
+    let keys = initial_keys(&[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08], Side::Client);
+    let sample = [0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68, 0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc, 0x9b];
+    let (mut first, mut pn) = (0xc3, [0x00, 0x00, 0x00, 0x02]);
+    keys.local.header.encrypt_in_place(&sample, &mut first, &mut pn).unwrap();
+    assert_eq!((first, pn), (0xc0, [0x7b, 0x9a, 0xec, 0x34]));
+
+    let mut buf = Vec::new();
+    for v in [0, 63, 64, 16383, 16384, 1 << 40] {
+        put_varint(&mut buf, v);
+    }
+    let mut pos = 0;
+    let decoded: Vec<u64> = (0..6).filter_map(|_| get_varint(&buf, &mut pos)).collect();
+    assert_eq!(decoded, [0, 63, 64, 16383, 16384, 1 << 40]);
+    // RFC 9000, Appendix A.3
+    assert_eq!(decode_pn(Some(0xa82f30ea), 0x9b32, 16), 0xa82f9b32);
+
+    let ack = ack_frame(&[0, 1, 2, 5, 6, 9].into_iter().collect());
+    assert_eq!(ack, [0x02, 9, 0, 2, 0, 1, 1, 1, 2]);
+    assert_eq!(parse_frames(&ack), Some(vec![Frame::Ack]));
+
+    assert_eq!(parse_addr("cloudflare-quic.com"), Some(("cloudflare-quic.com".to_owned(), 443, None)));
+    assert_eq!(parse_addr("[::1]:8443"), Some(("::1".to_owned(), 8443, None)));
+    assert_eq!(parse_addr("https://example.com/x"), Some(("example.com".to_owned(), 443, Some("/x".to_owned()))));
+    assert_eq!(parse_addr("http://example.com/"), None);
+}
+
+#[test]
+fn handshakes_and_requests_complete() {
+    use std::thread;
+    use rustls::ServerConfig;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::pki_types::pem::PemObject;
+
+    let (cert, key) = crate::https::self_signed(&["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("stabping-test-{}-quic", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("cert.pem"), &cert).unwrap();
+    let client = with_h3(http::client_config(Some(&dir.join("cert.pem")), None).unwrap());
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13]).unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![CertificateDer::from_pem_slice(cert.as_bytes()).unwrap()],
+                          PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap())
+        .unwrap();
+    server_config.alpn_protocols = vec![b"h3".to_vec()];
+    let server_config = Arc::new(server_config);
+
+    // a server answering a single connection, its handshake data sent out of order
+    let serve = |socket: UdpSocket| {
+        let config = server_config.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; 65536];
+            let (len, peer) = socket.recv_from(&mut buf).unwrap();
+            let (client_dcid, client_scid, pn_offset, end) = match parse_packet(&buf[..len], 0) {
+                Some(Incoming::Protected { level: Level::Initial, dcid, scid, pn_offset, end, .. }) => {
+                    (dcid, scid, pn_offset, end)
+                },
+                p => panic!("expected an Initial packet, got {:?}", p),
+            };
+            assert!(len >= MIN_INITIAL_SIZE);
+            let mut keys = vec![initial_keys(&client_dcid, Side::Server)];
+            let (_, payload) = open(&mut buf[..len], 0, pn_offset, end, &keys[0], None).unwrap();
+            let hello = match parse_frames(&payload).unwrap()[0] {
+                Frame::Crypto(0, data) => data.to_vec(),
+                ref f => panic!("expected the ClientHello, got {:?}", f),
+            };
+            let scid = random_cid();
+            let mut tls = quic::ServerConnection::new(config, Version::V1, transport_parameters(&scid)).unwrap();
+            tls.read_hs(&hello).unwrap();
+            let mut flights = Vec::new();
+            loop {
+                let mut data = Vec::new();
+                let change = tls.write_hs(&mut data);
+                flights.push(data);
+                match change {
+                    Some(KeyChange::Handshake { keys: k }) | Some(KeyChange::OneRtt { keys: k, .. }) => keys.push(k),
+                    None => break,
+                }
+            }
+            let (hello, handshake) = (&flights[0], &flights[1]);
+            let half = handshake.len() / 2;
+            let packet = |level: Level, keys: &Keys, pn: u64, frames: Vec<u8>| {
+                seal(level, keys, &client_scid, &scid, &[], pn, frames)
+            };
+            let mut first = packet(Level::Initial, &keys[0], 0, crypto_frame(0, hello));
+            first.extend(packet(Level::Handshake, &keys[1], 0, crypto_frame(half as u64, &handshake[half..])));
+            socket.send_to(&first, peer).unwrap();
+            socket.send_to(&packet(Level::Handshake, &keys[1], 1, crypto_frame(0, &handshake[..half])), peer)
+                .unwrap();
+
+            let mut pn = 0;
+            loop {
+                let len = socket.recv(&mut buf).unwrap();
+                let mut start = 0;
+                while let Some(Incoming::Protected { level, pn_offset, end, .. }) = parse_packet(&buf[..len], start) {
+                    let k = &keys[level as usize];
+                    let (_, payload) = open(&mut buf[..len], start, pn_offset, end, k, None).unwrap();
+                    start = end;
+                    for frame in parse_frames(&payload).unwrap() {
+                        match frame {
+                            Frame::Crypto(_, data) => tls.read_hs(data).unwrap(),
+                            Frame::Stream(0, 0, data, true) => {
+                                assert_eq!(data[0], 0x01);  // a HEADERS frame
+                                let response = stream_frame(0, &[0x01, 0x03, 0x00, 0x00, 0xc0 | 25], true);
+                                socket.send_to(&packet(Level::OneRtt, &keys[2], pn, response), peer).unwrap();
+                                pn += 1;
+                            },
+                            Frame::Close(..) => return tls.is_handshaking(),
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        })
+    };
+
+    let settings = ProbeSettings {
+        timeout: Duration::from_secs(2),
+        resolve_ttl: Duration::from_secs(0),
+        family: crate::options::AddressFamily::Any,
+        pin: None,
+        source: None,
+        proxy: None,
+        dscp: None,
+        tcp: Default::default(),
+    };
+    for path in [None, Some("/")] {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = serve(socket);
+        let (handshake, ttfb) = quic_once(client.clone(), "localhost", path, addr, &settings).unwrap();
+        assert!(handshake > 0 && (ttfb > 0) == path.is_some());
+        assert!(!server.join().unwrap(), "the server completed its handshake");
+    }
+
+    // a server with another name fails the handshake, and none at all times out
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let _server = serve(socket);
+    assert_eq!(quic_once(client.clone(), "example.com", None, addr, &settings), Err(SENTINEL_TLS));
+}
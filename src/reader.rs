@@ -13,6 +13,7 @@
 use std::mem;
 use std::slice;
 use std::io;
+use std::fs::File;
 use std::io::{Write, BufWriter};
 use std::sync::Arc;
 
@@ -20,31 +21,20 @@ use memmap::{Mmap, Protection};
 use iron::response::{WriteBody};
 use serde::{Serialize, Deserialize};
 
-use crate::helpers::VecIntoRawBytes;
-use crate::persist::TargetManager;
+use crate::helpers::PushRawBytes;
+use crate::persist::{TargetManager, DataElement};
 use crate::options::SENTINEL_NODATA;
 
 /**
  * A request from the client for persistent data for a target in the time range
- * `lower` to `upper` in context of the target's current options, verified
- * with `nonce`.
+ * `lower` to `upper` in context of the target's options as of `nonce` (the
+ * current one, or an earlier one recorded in the schema file).
  */
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DataRequest {
     nonce: i32,
-    lower: i32,
-    upper: i32,
-}
-
-/**
- * Representation of data elements on-disk in a target's data file. They are
- * 32-bit back-to-back integers.
- */
-#[repr(C, packed)]
-struct DataElement {
-    time: i32,
-    index: i32,
-    val: i32,
+    lower: i64,
+    upper: i64,
 }
 
 /**
@@ -52,110 +42,114 @@ struct DataElement {
  * data of a target.
  */
 pub struct SPDataReader {
-    lower: i32,
-    upper: i32,
+    lower: i64,
+    upper: i64,
     tm: Arc<TargetManager>,
+    ordered_list: Vec<i32>,
+    membership: Vec<i32>,
 }
 
 impl SPDataReader {
     pub fn new(tm: Arc<TargetManager>, dr: DataRequest) -> Option<Self> {
-        // verify the nonce, and refuse to create a reader if it doesn't match
-        if dr.nonce != tm.options_read().nonce {
-            return None;
-        }
+        // look up the addrs as of the nonce, and refuse to create a reader if it isn't known
+        let (ordered_list, membership) = tm.get_layout_indices(dr.nonce)?;
 
         Some(SPDataReader{
             lower: dr.lower,
             upper: dr.upper,
-            tm: tm,
+            tm,
+            ordered_list,
+            membership,
         })
     }
 }
 
-impl WriteBody for SPDataReader {
-    /**
-     * Writes the body of the response with the requested persistent data.
-     */
-    fn write_body(&mut self, res: &mut dyn io::Write) -> io::Result<()> {
-        /*
-         * acquire nonce and current indices (current state of addrs for this
-         * target) from the TargetManager
-         */
-        let (nonce, ordered_list, mut membership): (i32, Vec<i32>, Vec<i32>) = self.tm.get_current_indices();
-
-        // verify that the request nonce and the manager's nonce match
-        if nonce != self.tm.options_read().nonce {
-            println!("Nonce mismatch in WriteBody for SPDatReader!");
-            return Ok(())
-        }
+/**
+ * Maps the (locked) data file of a target into memory.
+ */
+pub fn map_data_file(file: &File) -> io::Result<Mmap> {
+    Mmap::open(file, Protection::Read)
+        .inspect_err(|_e| {
+            error!("Mmap failed!");
+        })
+}
 
-        // get a lock on the target's data file
-        let guard = self.tm.data_file_read();
+/**
+ * Fixed-size elements stored back-to-back in order of time, as in data files.
+ */
+pub trait TimedElement {
+    fn time(&self) -> i64;
+}
 
-        // attempt to mmap the target's data file
-        let map =
-            Mmap::open(&*guard, Protection::Read)
-            .map_err(|e| {
-                println!("ERROR: Mmap failed!");
-                e
-            })?;
+impl TimedElement for DataElement {
+    fn time(&self) -> i64 {
+        self.time
+    }
+}
 
-        /*
-         * attempt to read the raw bytes of the mapped data file as a series of
-         * DataElements (three 32-bit integers back-to-back)
-         */
-        let data: &[DataElement] = unsafe {
-            let orig = map.as_slice();
-            let raw_ptr = orig.as_ptr();
-
-            let orig_len = orig.len();
-            if orig_len % mem::size_of::<DataElement>() != 0 {
-                println!("ERROR: data file not a multiple 3 * 4 bytes!");
-                return Err(io::Error::new(io::ErrorKind::Other, "Data file incorrect multiple!"));
-            }
-            let new_len = orig.len() / mem::size_of::<DataElement>();
+/**
+ * Reads the raw bytes of a mapped data file as a series of `DataElement`s (or
+ * other elements stored likewise), returning those with times from `lower` to
+ * `upper` (inclusive).
+ */
+pub fn elements_in_range<T: TimedElement>(map: &Mmap, lower: i64, upper: i64) -> io::Result<&[T]> {
+    let data: &[T] = unsafe {
+        let orig = map.as_slice();
+        let raw_ptr = orig.as_ptr();
+
+        let orig_len = orig.len();
+        if !orig_len.is_multiple_of(mem::size_of::<T>()) {
+            error!("data file not a multiple of {} bytes!",
+                   mem::size_of::<T>());
+            return Err(io::Error::other("Data file incorrect multiple!"));
+        }
+        let new_len = orig.len() / mem::size_of::<T>();
 
-            let _ = orig;
-            slice::from_raw_parts(raw_ptr as *const DataElement, new_len)
-        };
+        let _ = orig;
+        slice::from_raw_parts(raw_ptr as *const T, new_len)
+    };
 
-        // search for the requested start/lower/begin time of the data
-        let begin = match data.binary_search_by_key(&self.lower, |d| d.time) {
-            Ok(mut i) => {
-                /*
-                 * we may end up in the middle of a series of data points taken
-                 * at the same time; we seek to the first
-                 */
-                while i > 0 && data[i].time == self.lower {
-                    i -= 1;
-                }
-                i
-            },
-            Err(i) => i
-        };
+    // search for the requested start/lower/begin time of the data
+    let begin = match data.binary_search_by_key(&lower, |d| d.time()) {
+        Ok(mut i) => {
+            /*
+             * we may end up in the middle of a series of data points taken
+             * at the same time; we seek to the first
+             */
+            while i > 0 && data[i - 1].time() == lower {
+                i -= 1;
+            }
+            i
+        },
+        Err(i) => i
+    };
+
+    // search for the requested end/upper time of the data
+    let end = match data.binary_search_by_key(&upper, |d| d.time()) {
+        Ok(mut i) => {
+            /*
+             * we may end up in the middle of a series of data points taken
+             * at the same time; we seek to the last
+             */
+            while i < data.len() && data[i].time() == upper {
+                i += 1;
+            }
+            i
+        },
+        Err(i) => i
+    };
 
-        // search for the requested end/upper time of the data
-        let end = match data.binary_search_by_key(&self.upper, |d| d.time) {
-            Ok(mut i) => {
-                /*
-                 * we may end up in the middle of a series of data points taken
-                 * at the same time; we seek to the last
-                 */
-                while i < data.len() && data[i].time == self.upper {
-                    i += 1;
-                }
-                i
-            },
-            Err(i) => i
-        };
+    Ok(if begin < end { &data[begin..end] } else { &[] })
+}
 
-        /*
-         * if our search reveals that we need to start past the data we have,
-         * then we don't have that data
-         */
-        if begin >= data.len() {
-            return Ok(())
-        }
+impl WriteBody for SPDataReader {
+    /**
+     * Writes the body of the response with the requested persistent data.
+     */
+    fn write_body(&mut self, res: &mut dyn io::Write) -> io::Result<()> {
+        // the indices of the addrs as of the requested nonce
+        let ordered_list = &self.ordered_list;
+        let membership = &mut self.membership;
 
         // initialize a buffered writer to actually write the response body
         let mut writer = BufWriter::new(res);
@@ -164,54 +158,71 @@ impl WriteBody for SPDataReader {
          * we process the data in time-based segments, initialize a buffer of
          * the appropriate size to store that data until we write it
          */
-        let mut buf: Vec<i32> = Vec::with_capacity(1 + ordered_list.len());
-        let mut cur = data[begin].time;
-
-        // loop through all the data points we have between begin and end
-        for d in &data[begin..end] {
-            /*
-             * if we encounter a different time, process one complete time
-             * segment and write it
-             */
-            if cur != d.time {
-                // first element is time
-                buf.push(cur);
-
+        let segment_len = 8 + 4 * ordered_list.len();
+        let mut buf: Vec<u8> = Vec::with_capacity(segment_len);
+        // the time of the current segment (None until we've seen any data)
+        let mut cur: Option<i64> = None;
+
+        // loop through all the data points we have in the range
+        self.tm.read_data_downsampled(self.lower, self.upper, &mut |data| {
+            for d in data {
                 /*
-                 * followed by data values in-order in which they appear in the
-                 * target's current addrs (here tracked by the ordered_list
-                 * of indices obtained from manager)
+                 * if we encounter a different time, process one complete time
+                 * segment and write it
                  */
-                for &i in ordered_list.iter() {
-                    buf.push(membership[i as usize]);
-                    membership[i as usize] = SENTINEL_NODATA;
+                match cur {
+                    Some(t) if t != d.time => {
+                        // first element is time (64-bit)
+                        buf.push_i64(t);
+
+                        /*
+                         * followed by data values (32-bit) in-order in which
+                         * they appear in the target's addrs as of the nonce (here
+                         * tracked by the ordered_list of indices obtained from
+                         * manager)
+                         */
+                        for &i in ordered_list.iter() {
+                            buf.push_i32(membership[i as usize]);
+                            membership[i as usize] = SENTINEL_NODATA;
+                        }
+
+                        // write out the data and reset our buffer and time tracker
+                        writer.write_all(&buf)?;
+                        buf.clear();
+                        cur = Some(d.time);
+                    },
+                    Some(_) => {},
+                    None => cur = Some(d.time),
                 }
 
-                // write out the data and reset our buffer and time tracker
-                writer.write_all(&buf.into_raw_bytes())?;
-                buf = Vec::with_capacity(1 + ordered_list.len());
-                cur = d.time;
+                /*
+                 * if this data point is relevant to us, meaning the addr
+                 * represented by its index is in the target's addrs as of the
+                 * nonce (here tracked by membership), then we store it
+                 * (cheatingly in membership indexed by its index -- this way
+                 * we don't need to allocate another buffer to store it); any
+                 * index added since isn't
+                 */
+                if let Some(m) = membership.get_mut(d.index as usize).filter(|m| **m != 0) {
+                    *m = d.val;
+                }
             }
+            Ok(())
+        })?;
 
-            /*
-             * if this data point is relevant to us, meaning the addr
-             * represented by its index is in the target's current addrs (here
-             * tracked by membership), then we store it (cheatingly in
-             * membership indexed by its index -- this way we don't need to
-             * allocate another buffer to store it)
-             */
-            if membership[d.index as usize] != 0 {
-                membership[d.index as usize] = d.val;
-            }
-        }
+        // if there is no data in the requested range, we don't have it
+        let cur = match cur {
+            Some(t) => t,
+            None => return Ok(()),
+        };
 
         // process the final time segment, and flush our writer
-        buf.push(cur);
+        buf.push_i64(cur);
         for &i in ordered_list.iter() {
-            buf.push(membership[i as usize]);
+            buf.push_i32(membership[i as usize]);
             membership[i as usize] = SENTINEL_NODATA;
         }
-        writer.write_all(&buf.into_raw_bytes())?;
+        writer.write_all(&buf)?;
         writer.flush()?;
 
         Ok(())
@@ -0,0 +1,121 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Reloading of the configuration file when it changes (or on SIGHUP),
+ * reconciling the targets with their newly declared options while running.
+ */
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::options::MainConfiguration;
+use crate::persist::TargetManager;
+
+/**
+ * How often the modification time of the configuration file is checked.
+ */
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/**
+ * Checks the modification time of the configuration file, requesting a reload
+ * whenever it changes.
+ */
+fn watch_file(path: PathBuf, requests: Sender<&'static str>) {
+    thread::spawn(move || {
+        let mut last = modified(&path);
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let current = modified(&path);
+            if current.is_some() && current != last {
+                last = current;
+                if requests.send("configuration file changed").is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/**
+ * Requests a reload on every SIGHUP.
+ */
+#[cfg(unix)]
+fn watch_sighup(requests: Sender<&'static str>) {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to handle SIGHUP, only reloading on changes: {}", e);
+            return;
+        },
+    };
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if requests.send("SIGHUP").is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn watch_sighup(_requests: Sender<&'static str>) {}
+
+/**
+ * Reads back the configuration file (with environment overrides) and
+ * reconciles the targets with its declared options. Targets whose options end
+ * up the same keep their nonce, so their rounds in flight are kept.
+ */
+fn reload(path: &Path, configuration: &RwLock<MainConfiguration>,
+          targets: &[Arc<TargetManager>]) {
+    let mut mc = match crate::read_configuration(path) {
+        Ok(mc) => mc,
+        Err(e) => {
+            warn!("{} configuration file '{}', keeping the current one.",
+                  e.description(), path.display());
+            return;
+        },
+    };
+    if let Err(e) = mc.apply_env(env::vars()) {
+        warn!("{}, keeping the current configuration.", e);
+        return;
+    }
+
+    crate::reconcile_targets(targets, &mc);
+    configuration.write().unwrap().targets = mc.targets;
+    info!("Reloaded targets from '{}' (other changes apply once restarted).", path.display());
+}
+
+/**
+ * Reloads the configuration file at the given path whenever it changes or on
+ * SIGHUP.
+ */
+pub fn watch_configuration(path: PathBuf, configuration: Arc<RwLock<MainConfiguration>>,
+                           targets: Vec<Arc<TargetManager>>) {
+    let (requests, reloads) = channel();
+    watch_file(path.clone(), requests.clone());
+    watch_sighup(requests);
+
+    thread::spawn(move || {
+        for reason in reloads {
+            info!("Reloading configuration ({}).", reason);
+            reload(&path, &configuration, &targets);
+        }
+    });
+}
@@ -0,0 +1,205 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Uptime (SLA) reports summarizing the persisted data of a target over a
+ * range of time.
+ */
+use std::io;
+
+use chrono::{Local, NaiveDate, TimeZone};
+use serde::Serialize;
+
+use crate::persist::TargetManager;
+use crate::options::was_probed;
+
+/**
+ * Summary of the primary values of a single address over a range of time.
+ * Latencies are in the target's own unit (e.g. microseconds).
+ */
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AddrReport {
+    pub addr: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,  // display name of the address, if it has one
+    pub rounds: u64,  // rounds with data
+    pub failed_rounds: u64,  // rounds without a value
+    pub uptime: Option<f64>,  // percentage of rounds with a value
+    pub mean: Option<f64>,
+    pub p95: Option<i32>,
+    pub p99: Option<i32>,
+    pub downtime: i64,  // seconds spent failing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worst_hour: Option<WorstHour>,
+}
+
+/**
+ * The (clock) hour with the highest mean of the values of an address.
+ */
+#[derive(Serialize, Debug, PartialEq)]
+pub struct WorstHour {
+    pub start: i64,
+    pub mean: f64,
+}
+
+/**
+ * Summary of every current address of a target over a range of time.
+ */
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub target: &'static str,
+    pub from: i64,
+    pub to: i64,
+    pub addrs: Vec<AddrReport>,
+}
+
+/**
+ * Parses a time given as seconds since the epoch, or as a `YYYY-MM-DD` date
+ * (meaning local midnight at its start).
+ */
+pub fn parse_time(s: &str) -> Option<i64> {
+    if let Ok(t) = s.parse() {
+        return Some(t);
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Local.from_local_datetime(&date.and_hms(0, 0, 0)).earliest().map(|t| t.timestamp())
+}
+
+/**
+ * Parses the (optional) bounds of the time range of a report (see
+ * `parse_time`), defaulting to the 30 days up to now.
+ */
+pub fn parse_range(from: Option<&str>, to: Option<&str>) -> Option<(i64, i64)> {
+    let to = match to {
+        Some(t) => parse_time(t)?,
+        None => Local::now().timestamp(),
+    };
+    let from = match from {
+        Some(f) => parse_time(f)?,
+        None => to - 30 * 86400,
+    };
+    Some((from, to))
+}
+
+/**
+ * Returns the value at the given percentile (nearest-rank) of the given sorted
+ * values.
+ */
+fn percentile(sorted: &[i32], p: usize) -> Option<i32> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+/**
+ * Summarizes the given (time, primary value) rounds of an address, ordered by
+ * time. Each failed round counts as downtime until the next round (or until
+ * `end`), but for no longer than `interval` seconds, so that time stabping
+ * wasn't running isn't counted.
+ */
+fn summarize(addr: &str, rounds: &[(i64, i32)], end: i64, interval: i64) -> AddrReport {
+    let mut values: Vec<i32> = rounds.iter().map(|&(_, v)| v).filter(|&v| v >= 0).collect();
+    values.sort_unstable();
+
+    let mut downtime = 0;
+    for (i, &(time, val)) in rounds.iter().enumerate() {
+        if val < 0 {
+            let next = rounds.get(i + 1).map_or(end, |&(t, _)| t);
+            downtime += (next - time).clamp(0, interval);
+        }
+    }
+
+    let mut hours: Vec<(i64, f64, u32)> = Vec::new();
+    for &(time, val) in rounds.iter().filter(|&&(_, v)| v >= 0) {
+        let start = time - time.rem_euclid(3600);
+        match hours.last_mut() {
+            Some(h) if h.0 == start => {
+                h.1 += val as f64;
+                h.2 += 1;
+            },
+            _ => hours.push((start, val as f64, 1)),
+        }
+    }
+    let worst_hour = hours.into_iter()
+        .map(|(start, sum, n)| WorstHour { start, mean: sum / n as f64 })
+        .max_by(|a, b| a.mean.total_cmp(&b.mean));
+
+    let n = rounds.len() as u64;
+    AddrReport {
+        addr: addr.to_owned(),
+        name: None,
+        rounds: n,
+        failed_rounds: n - values.len() as u64,
+        uptime: if n > 0 { Some(100.0 * values.len() as f64 / n as f64) } else { None },
+        mean: if values.is_empty() {
+            None
+        } else {
+            Some(values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64)
+        },
+        p95: percentile(&values, 95),
+        p99: percentile(&values, 99),
+        downtime,
+        worst_hour,
+    }
+}
+
+/**
+ * Builds a report of the primary values of every current address of the
+ * given target from `from` to `to` (inclusive).
+ */
+pub fn report(tm: &TargetManager, from: i64, to: i64) -> io::Result<Report> {
+    let (_, ordered_list, _) = tm.get_current_indices();
+    let (addrs, names, num_columns, interval) = {
+        let options = tm.options_read();
+        (options.addrs.clone(), options.names.clone(), tm.kind.columns(&options).len(),
+         (options.interval / 1000).max(1) as i64)
+    };
+
+    // the index of the primary column of each address, in order of addrs
+    let primary: Vec<i32> = ordered_list.iter().step_by(num_columns).cloned().collect();
+    let mut rounds: Vec<Vec<(i64, i32)>> = vec![Vec::new(); addrs.len()];
+
+    tm.read_data_range(from, to, &mut |elements| {
+        for d in elements.iter().filter(|d| was_probed(d.val)) {
+            if let Some(a) = primary.iter().position(|&i| i == d.index) {
+                rounds[a].push((d.time, d.val));
+            }
+        }
+        Ok(())
+    })?;
+
+    let end = to.min(Local::now().timestamp());
+    Ok(Report {
+        target: tm.kind.compact_name(),
+        from,
+        to,
+        addrs: addrs.iter().zip(rounds.iter())
+            .map(|(addr, r)| AddrReport { name: names.get(addr).cloned(), ..summarize(addr, r, end, interval) })
+            .collect(),
+    })
+}
+
+#[test]
+fn summarize_counts_uptime_percentiles_and_downtime() {
+    let timeout = -2_100_000_005;
+    let mut rounds: Vec<(i64, i32)> = (0..20).map(|i| (i * 10, 1000 * (i as i32 + 1))).collect();
+    rounds[4].1 = timeout;
+    rounds[5].1 = timeout;
+    rounds[19].1 = timeout;
+
+    let r = summarize("a:80", &rounds, 1000, 10);
+    assert_eq!((r.rounds, r.failed_rounds, r.downtime), (20, 3, 30));
+    assert_eq!(r.uptime, Some(85.0));
+    assert_eq!((r.p95, r.p99), (Some(19_000), Some(19_000)));
+    assert_eq!(r.worst_hour, Some(WorstHour { start: 0, mean: r.mean.unwrap() }));
+    let r = summarize("a:80", &[(0, 1000), (3600, 5000), (3700, 7000)], 4000, 100);
+    assert_eq!(r.worst_hour, Some(WorstHour { start: 3600, mean: 6000.0 }));
+    assert_eq!(percentile(&[1, 2, 3, 4], 50), Some(2));
+}
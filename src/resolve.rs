@@ -0,0 +1,87 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Host name resolution shared by the probes, with a process-wide cache so
+ * that (within a target's `resolve_ttl`) attempts don't each wait on the
+ * resolver.
+ */
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use crate::options::AddressFamily;
+use crate::worker::ProbeSettings;
+
+// resolved addresses (and when they were resolved) by host, port and family
+type Cache = HashMap<(String, u16, AddressFamily), (Instant, SocketAddr)>;
+
+fn cache() -> &'static Mutex<Cache> {
+    static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/**
+ * Resolves the given host and port to a single socket address of the family
+ * of the given settings (the first the resolver returns for `any`), reusing
+ * an earlier resolution if it is younger than their `resolve_ttl` (a TTL of
+ * zero always resolves afresh). Settings pinned to an IP (see
+ * `ProbeSettings::pin`) get it, without resolving at all.
+ */
+pub fn resolve(host: &str, port: u16, settings: &ProbeSettings) -> Option<SocketAddr> {
+    if let Some(ip) = settings.pin {
+        return Some(SocketAddr::new(ip, port));
+    }
+    let (ttl, family) = (settings.resolve_ttl, settings.family);
+    let key = (host.to_owned(), port, family);
+    if !ttl.is_zero() {
+        if let Some(&(at, addr)) = cache().lock().unwrap().get(&key) {
+            if at.elapsed() < ttl {
+                return Some(addr);
+            }
+        }
+    }
+
+    let addr = (host, port).to_socket_addrs().ok()?.find(|a| family.admits(a))?;
+    if !ttl.is_zero() {
+        cache().lock().unwrap().insert(key, (Instant::now(), addr));
+    }
+    Some(addr)
+}
+
+/**
+ * Resolves the given host afresh to every IP (of the given family) it has, in
+ * the order the resolver returns them, or None if it can't be.
+ */
+pub fn resolve_all(host: &str, port: u16, family: AddressFamily) -> Option<Vec<IpAddr>> {
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for addr in (host, port).to_socket_addrs().ok()?.filter(|a| family.admits(a)) {
+        if !ips.contains(&addr.ip()) {
+            ips.push(addr.ip());
+        }
+    }
+    Some(ips)
+}
+
+/**
+ * Splits a `host:port` address (where an IPv6 host is bracketed, e.g.
+ * `[::1]:80`) into its host and port.
+ */
+pub fn split_host_port(addr: &str) -> Option<(&str, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Some((host, port.parse().ok()?))
+}
+
+#[test]
+fn split_host_port_handles_ipv6_literals() {
+    assert_eq!(split_host_port("google.com:80"), Some(("google.com", 80)));
+    assert_eq!(split_host_port("[::1]:8080"), Some(("::1", 8080)));
+    assert_eq!(split_host_port("google.com"), None);
+}
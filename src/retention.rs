@@ -0,0 +1,92 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Pruning of the data of targets with a `retention`, periodically removing
+ * (and compacting away) whatever was collected longer ago than that, and
+ * likewise compression of the data of those with a `compress_after`.
+ */
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+
+use crate::persist::TargetManager;
+
+/**
+ * How often the data of every target is pruned (and compressed).
+ */
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/**
+ * Returns the time before which data is pruned (or compressed) at the given
+ * time, when kept (uncompressed) for the given number of days.
+ */
+fn prune_before(now: i64, retention_days: u32) -> i64 {
+    now - retention_days as i64 * 86400
+}
+
+/**
+ * Prunes the data of each of the given targets that has a retention.
+ */
+fn prune(targets: &[Arc<TargetManager>]) {
+    let now = Local::now().timestamp();
+    for tm in targets.iter() {
+        let days = match tm.options_read().retention {
+            Some(d) => d,
+            None => continue,
+        };
+        match tm.prune_data(prune_before(now, days)) {
+            Ok(0) => {},
+            Ok(n) => info!("Pruned {} {} data elements older than {} days.",
+                           n, tm.kind.compact_name(), days),
+            Err(e) => error!("Failed to prune {} data: {}", tm.kind.compact_name(), e),
+        }
+    }
+}
+
+/**
+ * Compresses the data of each of the given targets that has a
+ * `compress_after`.
+ */
+fn compress(targets: &[Arc<TargetManager>]) {
+    let now = Local::now().timestamp();
+    for tm in targets.iter() {
+        let days = match tm.options_read().compress_after {
+            Some(d) => d,
+            None => continue,
+        };
+        match tm.compress_data(prune_before(now, days)) {
+            Ok(0) => {},
+            Ok(n) => info!("Compressed {} {} data elements older than {} days.",
+                           n, tm.kind.compact_name(), days),
+            Err(e) => error!("Failed to compress {} data: {}", tm.kind.compact_name(), e),
+        }
+    }
+}
+
+/**
+ * Prunes (and then compresses) the data of the given targets on startup, and
+ * then every `PRUNE_INTERVAL`.
+ */
+pub fn run_pruner(targets: Vec<Arc<TargetManager>>) {
+    thread::spawn(move || {
+        loop {
+            prune(&targets);
+            compress(&targets);
+            thread::sleep(PRUNE_INTERVAL);
+        }
+    });
+}
+
+#[test]
+fn prune_before_keeps_whole_days() {
+    assert_eq!(prune_before(1_500_000_000, 30), 1_500_000_000 - 30 * 86400);
+    assert_eq!(prune_before(1_500_000_000, 0), 1_500_000_000);
+}
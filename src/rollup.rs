@@ -0,0 +1,523 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Rollups of the data of a target into tiers of 1-minute and 1-hour
+ * aggregates (min/mean/max and the number of rounds that failed), so that
+ * large ranges can be read back without every raw data element.
+ *
+ * Each tier is stored in its own rollup file (`<kind>.rollup-<tier>.dat`) of
+ * fixed-size `RollupElement`s in order of time, like the data file. The first
+ * tier is rolled up from the target's data, and each further one from the
+ * tier before it, once all of a bucket's rounds are in.
+ */
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+
+use crate::helpers::{SPIOError, SPFile, PushRawBytes, discard_incomplete};
+use crate::options::{TargetKind, was_probed};
+use crate::persist::{TargetManager, DataElement, ManagerError};
+use crate::reader::{map_data_file, elements_in_range, TimedElement};
+use crate::storage::Storage;
+
+/**
+ * A tier of rollups, aggregating the data into buckets of `secs` seconds.
+ */
+pub struct RollupTier {
+    pub name: &'static str,
+    pub secs: i64,
+}
+
+pub static TIERS: [RollupTier; 2] = [
+    RollupTier { name: "1m", secs: 60 },
+    RollupTier { name: "1h", secs: 60 * 60 },
+];
+
+/**
+ * How long after a bucket ends its rounds are considered all in.
+ */
+const LAG: i64 = 5 * 60;
+
+/**
+ * The most data points read back for a range before rollups are used instead.
+ */
+const MAX_POINTS: i64 = 1500;
+
+/**
+ * How often the rollups of every target are brought up to date.
+ */
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/**
+ * Representation of rollup elements on-disk in a rollup file: the aggregates
+ * of the values of an index over the bucket starting at `time`. Buckets in
+ * which all rounds failed have the last failure's sentinel as their min, mean
+ * and max.
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RollupElement {
+    pub time: i64,
+    pub index: i32,
+    pub count: i32,  // rounds probed
+    pub failed: i32,  // of which failed
+    pub min: i32,
+    pub max: i32,
+    pub mean: i32,
+}
+
+impl RollupElement {
+    fn push_to(&self, buf: &mut Vec<u8>) {
+        buf.push_i64(self.time);
+        for v in [self.index, self.count, self.failed, self.min, self.max, self.mean] {
+            buf.push_i32(v);
+        }
+    }
+
+    /**
+     * The element of the data file this bucket is read back as.
+     */
+    fn as_data(&self) -> DataElement {
+        DataElement {
+            time: self.time,
+            index: self.index,
+            val: self.mean,
+        }
+    }
+}
+
+impl TimedElement for RollupElement {
+    fn time(&self) -> i64 {
+        self.time
+    }
+}
+
+/**
+ * Running aggregates of the values that fall into a bucket.
+ */
+#[derive(Default)]
+struct Accumulator {
+    count: i32,
+    failed: i32,
+    min: i32,
+    max: i32,
+    sum: i64,
+    last_failure: i32,
+}
+
+impl Accumulator {
+    fn add_success(&mut self, min: i32, max: i32, sum: i64) {
+        if self.count == self.failed {
+            self.min = min;
+            self.max = max;
+        } else {
+            self.min = self.min.min(min);
+            self.max = self.max.max(max);
+        }
+        self.sum += sum;
+    }
+
+    /**
+     * Adds a value of the data file (skipping those not probed).
+     */
+    fn add(&mut self, val: i32) {
+        if !was_probed(val) {
+            return;
+        }
+        if val < 0 {
+            self.failed += 1;
+            self.last_failure = val;
+        } else {
+            self.add_success(val, val, val as i64);
+        }
+        self.count += 1;
+    }
+
+    /**
+     * Adds the aggregates of a bucket of the tier before.
+     */
+    fn add_rollup(&mut self, e: &RollupElement) {
+        if e.count > e.failed {
+            self.add_success(e.min, e.max, e.mean as i64 * (e.count - e.failed) as i64);
+        } else {
+            self.last_failure = e.mean;
+        }
+        self.count += e.count;
+        self.failed += e.failed;
+    }
+
+    fn finish(&self, time: i64, index: i32) -> RollupElement {
+        let succeeded = self.count - self.failed;
+        let (min, max, mean) = if succeeded > 0 {
+            (self.min, self.max, (self.sum / succeeded as i64) as i32)
+        } else {
+            (self.last_failure, self.last_failure, self.last_failure)
+        };
+        RollupElement { time, index, count: self.count, failed: self.failed, min, max, mean }
+    }
+}
+
+/**
+ * The rollup file of a single tier.
+ */
+struct RollupLog {
+    path: PathBuf,
+    file: RwLock<File>,
+}
+
+impl RollupLog {
+    /**
+     * Opens (creating it if necessary) the rollup file at the given path,
+     * discarding any incomplete element at its end.
+     */
+    fn open(path: &Path) -> Result<Self, SPIOError> {
+        let file = File::open_from(OpenOptions::new().read(true).append(true).create(true), path)?;
+        let len = file.metadata().map_err(|_| SPIOError::Metadata(Some(path.to_owned())))?.len();
+        let size = mem::size_of::<RollupElement>() as u64;
+        discard_incomplete(&file, path, len, len / size * size)?;
+        Ok(RollupLog {
+            path: path.to_owned(),
+            file: RwLock::new(file),
+        })
+    }
+
+    fn append(&self, elements: &[RollupElement]) -> Result<(), SPIOError> {
+        let mut out_data: Vec<u8> = Vec::with_capacity(mem::size_of_val(elements));
+        for e in elements.iter() {
+            e.push_to(&mut out_data);
+        }
+        self.file.write().unwrap().write_all(&out_data)
+            .map_err(|_| SPIOError::Write(Some(self.path.clone())))
+    }
+
+    /**
+     * Returns the time of the last bucket rolled up, if any.
+     */
+    fn last_time(&self) -> Result<Option<i64>, SPIOError> {
+        let read_error = || SPIOError::Read(Some(self.path.clone()));
+        let guard = self.file.read().unwrap();
+        let len = guard.metadata().map_err(|_| read_error())?.len();
+        let size = mem::size_of::<RollupElement>() as u64;
+        if len < size {
+            return Ok(None);
+        }
+        let mut time = [0; 8];
+        let mut file: &File = &guard;
+        file.seek(SeekFrom::Start(len / size * size - size))
+            .and_then(|_| file.read_exact(&mut time))
+            .map_err(|_| read_error())?;
+        Ok(Some(i64::from_ne_bytes(time)))
+    }
+
+    fn read_range(&self, lower: i64, upper: i64,
+                  f: &mut dyn FnMut(&[RollupElement]) -> io::Result<()>) -> io::Result<()> {
+        let guard = self.file.read().unwrap();
+        if guard.metadata()?.len() == 0 {
+            return f(&[]);
+        }
+        let map = map_data_file(&guard)?;
+        f(elements_in_range(&map, lower, upper)?)
+    }
+
+    /**
+     * Merges the given buckets (ordered by time and index) into those of this
+     * tier, combining any of the same time and index with those there.
+     */
+    fn merge(&self, buckets: &[RollupElement]) -> Result<(), SPIOError> {
+        let write_error = || SPIOError::Write(Some(self.path.clone()));
+        let mut guard = self.file.write().unwrap();
+        let len = guard.metadata().map_err(|_| write_error())?.len();
+
+        let mut out_data: Vec<u8> = Vec::new();
+        {
+            let map = if len > 0 { Some(map_data_file(&guard).map_err(|_| write_error())?) } else { None };
+            let stored: &[RollupElement] = match map {
+                Some(ref map) => elements_in_range(map, i64::MIN, i64::MAX).map_err(|_| write_error())?,
+                None => &[],
+            };
+            let mut s = 0;
+            for b in buckets.iter() {
+                while s < stored.len() && (stored[s].time, stored[s].index) < (b.time, b.index) {
+                    stored[s].push_to(&mut out_data);
+                    s += 1;
+                }
+                if s < stored.len() && (stored[s].time, stored[s].index) == (b.time, b.index) {
+                    let mut a = Accumulator::default();
+                    a.add_rollup(&stored[s]);
+                    a.add_rollup(b);
+                    a.finish(b.time, b.index).push_to(&mut out_data);
+                    s += 1;
+                } else {
+                    b.push_to(&mut out_data);
+                }
+            }
+            for e in stored[s..].iter() {
+                e.push_to(&mut out_data);
+            }
+        }
+
+        // replace the rollup file, like the data file when pruning
+        let tmp_path = self.path.with_extension("dat.tmp");
+        File::create(&tmp_path)
+            .and_then(|mut f| f.write_all(&out_data).and_then(|_| f.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|_| write_error())?;
+        *guard = File::open_from(OpenOptions::new().read(true).append(true).create(true), &self.path)?;
+        Ok(())
+    }
+
+    /**
+     * Returns the time of the first bucket rolled up, if any.
+     */
+    fn first_time(&self) -> io::Result<Option<i64>> {
+        let mut first = None;
+        self.read_range(i64::MIN, i64::MAX, &mut |elements| {
+            first = elements.first().map(|e| e.time);
+            Ok(())
+        })?;
+        Ok(first)
+    }
+}
+
+/**
+ * The rollups of a target, in every tier.
+ */
+pub struct Rollups {
+    tiers: Vec<RollupLog>,
+}
+
+impl Rollups {
+    /**
+     * Opens (creating them if necessary) the rollup files of the given target
+     * kind in the given data directory.
+     */
+    pub fn open(kind: &TargetKind, data_path: &Path) -> Result<Self, ManagerError> {
+        let tiers = TIERS.iter()
+            .map(|t| RollupLog::open(&data_path.join(
+                format!("{}.rollup-{}.dat", kind.compact_name(), t.name))))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(ManagerError::RollupFileIO)?;
+        Ok(Rollups { tiers })
+    }
+
+    /**
+     * Returns the (exclusive) end of the buckets of the given tier rolled up
+     * so far, if any.
+     */
+    fn rolled_until(&self, tier: usize) -> Result<Option<i64>, SPIOError> {
+        Ok(self.tiers[tier].last_time()?.map(|t| t + TIERS[tier].secs))
+    }
+
+    /**
+     * Rolls up every bucket (of every tier) that is complete at the given
+     * time and not yet rolled up, from the given storage of the data.
+     */
+    pub fn update(&self, storage: &dyn Storage, now: i64) -> Result<(), ManagerError> {
+        let read_error = |_| ManagerError::DataFileIO(SPIOError::Read(None));
+        for (i, tier) in TIERS.iter().enumerate() {
+            let secs = tier.secs;
+            let log = &self.tiers[i];
+
+            // pick up after the last bucket, or from the earliest data
+            let start = match log.last_time().map_err(ManagerError::RollupFileIO)? {
+                Some(t) => t + secs,
+                None => {
+                    let first = if i == 0 {
+                        storage.first_time().map_err(read_error)?
+                    } else {
+                        self.tiers[i - 1].first_time().map_err(read_error)?
+                    };
+                    match first {
+                        Some(t) => t.div_euclid(secs) * secs,
+                        None => continue,
+                    }
+                },
+            };
+            // up to the last complete bucket (and what the tier before covers)
+            let mut end = (now - LAG).div_euclid(secs) * secs;
+            if i > 0 {
+                match self.rolled_until(i - 1).map_err(ManagerError::RollupFileIO)? {
+                    Some(t) => end = end.min(t),
+                    None => continue,
+                }
+            }
+
+            // a day's worth of buckets at a time
+            let mut from = start;
+            while from < end {
+                let to = (from + secs * 1440).min(end);
+                let mut buckets: BTreeMap<(i64, i32), Accumulator> = BTreeMap::new();
+                if i == 0 {
+                    storage.read_range(from, to - 1, &mut |elements| {
+                        for e in elements.iter() {
+                            buckets.entry((e.time.div_euclid(secs) * secs, e.index))
+                                .or_default().add(e.val);
+                        }
+                        Ok(())
+                    }).map_err(read_error)?;
+                } else {
+                    self.tiers[i - 1].read_range(from, to - 1, &mut |elements| {
+                        for e in elements.iter() {
+                            buckets.entry((e.time.div_euclid(secs) * secs, e.index))
+                                .or_default().add_rollup(e);
+                        }
+                        Ok(())
+                    }).map_err(read_error)?;
+                }
+
+                let elements: Vec<RollupElement> = buckets.iter()
+                    .filter(|&(_, a)| a.count > 0)
+                    .map(|(&(time, index), a)| a.finish(time, index))
+                    .collect();
+                log.append(&elements).map_err(ManagerError::RollupFileIO)?;
+                from = to;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Merges the given data elements (e.g. imported from before the data
+     * was rolled up) into the buckets of every tier already rolled up past
+     * them. Those of buckets not yet rolled up are left to `update`.
+     */
+    pub fn merge(&self, elements: &[DataElement]) -> Result<(), ManagerError> {
+        let mut buckets: BTreeMap<(i64, i32), Accumulator> = BTreeMap::new();
+        for e in elements.iter() {
+            buckets.entry((e.time.div_euclid(TIERS[0].secs) * TIERS[0].secs, e.index))
+                .or_default().add(e.val);
+        }
+        for (i, log) in self.tiers.iter().enumerate() {
+            let until = match self.rolled_until(i).map_err(ManagerError::RollupFileIO)? {
+                Some(t) => t,
+                None => break,
+            };
+            let rolled: Vec<RollupElement> = buckets.iter()
+                .filter(|&(&(time, _), a)| a.count > 0 && time < until)
+                .map(|(&(time, index), a)| a.finish(time, index))
+                .collect();
+            if rolled.is_empty() {
+                break;
+            }
+            log.merge(&rolled).map_err(ManagerError::RollupFileIO)?;
+
+            // which go on into the buckets of the next tier
+            if let Some(next) = TIERS.get(i + 1) {
+                buckets = BTreeMap::new();
+                for e in rolled.iter() {
+                    buckets.entry((e.time.div_euclid(next.secs) * next.secs, e.index))
+                        .or_default().add_rollup(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Calls `f` with the data elements of the given storage with times from
+     * `lower` to `upper` (inclusive), ordered by time, as `Storage::read_range`
+     * does. If that range would take more than `MAX_POINTS` rounds (of the
+     * given interval, in seconds), the rollups of the coarsest tier that
+     * doesn't are read back as elements instead (their means), followed by
+     * finer tiers and then the data itself for whatever is not yet rolled up.
+     */
+    pub fn read_downsampled(&self, storage: &dyn Storage, interval: i64, lower: i64, upper: i64,
+                            f: &mut dyn FnMut(&[DataElement]) -> io::Result<()>) -> io::Result<()> {
+        let span = upper.saturating_sub(lower);
+        if span / interval.max(1) <= MAX_POINTS {
+            return storage.read_range(lower, upper, f);
+        }
+        let coarsest = TIERS.iter().position(|t| span / t.secs <= MAX_POINTS)
+            .unwrap_or(TIERS.len() - 1);
+
+        let mut from = lower;
+        for i in (0..=coarsest).rev() {
+            let until = match self.rolled_until(i).map_err(|e| io::Error::other(e.description()))? {
+                Some(t) if t > from => t,
+                _ => continue,
+            };
+            let to = upper.min(until - 1);
+            let mut converted = Vec::new();
+            self.tiers[i].read_range(from, to, &mut |elements| {
+                converted.clear();
+                converted.extend(elements.iter().map(|e| e.as_data()));
+                f(&converted)
+            })?;
+            if to >= upper {
+                return Ok(());
+            }
+            from = to + 1;
+        }
+        storage.read_range(from, upper, f)
+    }
+
+    /**
+     * Reads back the buckets of the given tier (by name) of the given index
+     * from `from` to `to` (inclusive). Returns None for an unknown tier.
+     */
+    pub fn read_tier(&self, tier: &str, index: i32, from: i64,
+                     to: i64) -> Option<io::Result<Vec<RollupElement>>> {
+        let i = TIERS.iter().position(|t| t.name == tier)?;
+        let mut buckets = Vec::new();
+        let res = self.tiers[i].read_range(from, to, &mut |elements| {
+            buckets.extend(elements.iter().filter(|e| e.index == index));
+            Ok(())
+        });
+        Some(res.map(|_| buckets))
+    }
+}
+
+/**
+ * Brings the rollups of the given targets up to date on startup, and then
+ * every `ROLLUP_INTERVAL`.
+ */
+pub fn run_rollups(targets: Vec<Arc<TargetManager>>) {
+    thread::spawn(move || {
+        loop {
+            let now = Local::now().timestamp();
+            for tm in targets.iter() {
+                if let Err(e) = tm.roll_up(now) {
+                    error!("Failed to roll up {} data: {}", tm.kind.compact_name(), e);
+                }
+            }
+            thread::sleep(ROLLUP_INTERVAL);
+        }
+    });
+}
+
+#[test]
+fn accumulators_aggregate_values_and_rollups() {
+    let mut a = Accumulator::default();
+    for val in [100, crate::options::SENTINEL_TIMEOUT, 300, crate::options::SENTINEL_NODATA, 200] {
+        a.add(val);
+    }
+    let e = a.finish(60, 2);
+    assert_eq!((e.count, e.failed, e.min, e.max, e.mean), (4, 1, 100, 300, 200));
+
+    let mut b = Accumulator::default();
+    b.add(crate::options::SENTINEL_TIMEOUT);
+    let failed = b.finish(120, 2);
+    assert_eq!((failed.count, failed.failed, failed.mean), (1, 1, crate::options::SENTINEL_TIMEOUT));
+
+    let mut c = Accumulator::default();
+    c.add_rollup(&failed);
+    c.add_rollup(&e);
+    let rolled = c.finish(0, 2);
+    assert_eq!((rolled.count, rolled.failed, rolled.min, rolled.max, rolled.mean),
+               (5, 2, 100, 300, 200));
+}
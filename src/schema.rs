@@ -0,0 +1,153 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The history of the column layout of a target's results: which addresses
+ * (and columns of each) were there as of each nonce.
+ *
+ * Results, and data read back for a client, are laid out by the addresses and
+ * columns of the target's options at the time, which change with them. So
+ * that data requested (or results received) in the layout of an earlier nonce
+ * can still be told apart and labeled correctly, each layout is appended (as
+ * a line of JSON) to the schema file whenever the nonce changes.
+ */
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use serde::{Serialize, Deserialize};
+
+use crate::helpers::{SPIOError, SPFile, discard_incomplete};
+use crate::options::column_key;
+
+/**
+ * The layout of a target's results as of a nonce: its addresses, each with
+ * the given columns (see `TargetKind::columns`), in order.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub nonce: i32,
+    pub since: i64,  // when the target's options first had this nonce
+    pub addrs: Vec<String>,
+    pub columns: Vec<String>,
+}
+
+impl Layout {
+    /**
+     * Returns the keys (see `column_key`) of every column of this layout, in
+     * order.
+     */
+    pub fn keys(&self) -> Vec<String> {
+        self.addrs.iter()
+            .flat_map(|addr| self.columns.iter().map(move |column| column_key(addr, column)))
+            .collect()
+    }
+}
+
+/**
+ * The schema file of a target, appended to with each new layout.
+ */
+pub struct SchemaLog {
+    path: PathBuf,
+    file: RwLock<(File, Vec<Layout>)>,
+}
+
+impl SchemaLog {
+    /**
+     * Opens (creating it if necessary) the schema file at the given path,
+     * discarding any incomplete line at its end.
+     */
+    pub fn open(path: &Path) -> Result<Self, SPIOError> {
+        let mut file = File::open_from(OpenOptions::new().read(true).append(true).create(true), path)?;
+        let len = file.length_p(path)?;
+
+        let mut layouts = Vec::new();
+        let mut complete_len = 0;
+        let mut reader = BufReader::new(&mut file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(|_| SPIOError::Parse(Some(path.to_owned())))?;
+            // stop at the end, or at a line left incomplete
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            complete_len += read as u64;
+            layouts.push(serde_json::from_str(&line).map_err(|_| SPIOError::Parse(Some(path.to_owned())))?);
+        }
+        discard_incomplete(&file, path, len, complete_len)?;
+
+        Ok(SchemaLog {
+            path: path.to_owned(),
+            file: RwLock::new((file, layouts)),
+        })
+    }
+
+    /**
+     * Appends the given layout, unless it is the same as the last (but for
+     * when it was first seen).
+     */
+    pub fn record(&self, layout: Layout) -> Result<(), SPIOError> {
+        let mut guard = self.file.write().unwrap();
+        let (ref mut file, ref mut layouts) = *guard;
+        let same = layouts.last().is_some_and(|last| {
+            last.nonce == layout.nonce && last.addrs == layout.addrs && last.columns == layout.columns
+        });
+        if same {
+            return Ok(());
+        }
+
+        let mut line = serde_json::to_string(&layout).unwrap();
+        line.push('\n');
+        file.write_all(line.as_bytes()).map_err(|_| SPIOError::Write(Some(self.path.clone())))?;
+        layouts.push(layout);
+        Ok(())
+    }
+
+    /**
+     * Gets the latest layout of the given nonce, if there was one.
+     */
+    pub fn layout(&self, nonce: i32) -> Option<Layout> {
+        self.file.read().unwrap().1.iter().rev().find(|l| l.nonce == nonce).cloned()
+    }
+
+    /**
+     * Gets every layout, oldest first.
+     */
+    pub fn layouts(&self) -> Vec<Layout> {
+        self.file.read().unwrap().1.clone()
+    }
+}
+
+#[test]
+fn layouts_are_recorded_by_nonce() {
+    let path = std::env::temp_dir().join(format!("stabping-test-{}.schema.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let layout = |nonce, addrs: &[&str]| Layout {
+        nonce,
+        since: nonce as i64 * 10,
+        addrs: addrs.iter().map(|a| a.to_string()).collect(),
+        columns: vec!["".to_owned(), "loss".to_owned()],
+    };
+
+    let log = SchemaLog::open(&path).unwrap();
+    log.record(layout(0, &["a:80", "b:80"])).unwrap();
+    log.record(layout(0, &["a:80", "b:80"])).unwrap();
+    log.record(layout(1, &["b:80"])).unwrap();
+    drop(log);
+
+    // a layout left half-written is discarded
+    std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"nonce\":2").unwrap();
+    let log = SchemaLog::open(&path).unwrap();
+    assert_eq!(log.layouts().len(), 2);
+    assert_eq!(log.layout(0).unwrap().keys(), vec!["a:80", "a:80\tloss", "b:80", "b:80\tloss"]);
+    assert_eq!(log.layout(1).unwrap().addrs, vec!["b:80"]);
+    assert!(log.layout(2).is_none());
+    let _ = std::fs::remove_file(&path);
+}
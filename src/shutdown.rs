@@ -0,0 +1,83 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Graceful shutdown on SIGINT (Ctrl-C) or SIGTERM: workers stop scheduling new
+ * rounds, rounds in flight get a grace period to finish, and the sinks are
+ * drained and flushed before exiting.
+ */
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * How long rounds in flight may take to finish once shutdown is requested.
+ */
+pub const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static REQUESTED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/**
+ * Whether shutdown has been requested.
+ */
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/**
+ * Requests shutdown (if not already requested).
+ */
+pub fn request() {
+    let mut at = REQUESTED_AT.lock().unwrap();
+    if at.is_none() {
+        *at = Some(Instant::now());
+    }
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/**
+ * The time by which rounds in flight should be done, if shutdown has been
+ * requested.
+ */
+pub fn deadline() -> Option<Instant> {
+    REQUESTED_AT.lock().unwrap().map(|at| at + GRACE_PERIOD)
+}
+
+/**
+ * Requests shutdown on the first SIGINT or SIGTERM, and exits right away on
+ * the second.
+ */
+#[cfg(unix)]
+pub fn handle_signals() {
+    use std::process;
+    use std::thread;
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to handle SIGINT and SIGTERM, not shutting down gracefully: {}", e);
+            return;
+        },
+    };
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if requested() {
+                warn!("Shutting down immediately.");
+                process::exit(1);
+            }
+            info!("Shutting down (again to shut down immediately).");
+            request();
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn handle_signals() {}
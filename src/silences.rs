@@ -0,0 +1,180 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Silences of the alerts of a target and/or of addresses carrying a tag for a
+ * while (e.g. during known outages), persisted to `silences.json` in the data
+ * directory. Silenced alerts are still evaluated, only not notified of.
+ */
+use std::fs::{OpenOptions, File};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::helpers::{SPIOError, SPFile, overwrite_json};
+use crate::tags::{TagFilter, Tags};
+
+/**
+ * A silence as requested over the API, of a target and/or tag (at least one
+ * of them) for a number of seconds.
+ */
+#[derive(Deserialize, Debug, Clone)]
+pub struct SilenceRequest {
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    pub duration: u32,
+    #[serde(default)]
+    pub comment: String,
+}
+
+/**
+ * The alerts of the given target (any, if `None`), of the addresses with the
+ * given tag (`name:value` or just `name`, as in tag filters; any, if
+ * `None`), silenced from `start` to `end`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Silence {
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    pub start: i64,
+    pub end: i64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+}
+
+impl Silence {
+    /**
+     * Returns whether the silence applies, at the given time, to the alerts
+     * of the given target for an address with the given tags (if any).
+     */
+    pub fn silences(&self, target: &str, tags: Option<&Tags>, time: i64) -> bool {
+        self.start <= time && time < self.end &&
+            self.target.as_ref().is_none_or(|t| t == target) &&
+            self.tag.as_ref().is_none_or(|t| TagFilter::of_tag(t).matches(tags))
+    }
+}
+
+/**
+ * The silences not yet over, persisted to the given path (nowhere, by
+ * default).
+ */
+#[derive(Default)]
+pub struct Silences {
+    path: Option<PathBuf>,
+    silences: Vec<Silence>,
+}
+
+impl Silences {
+    /**
+     * Reads back any silences persisted to the given data directory.
+     */
+    pub fn load(data_path: &Path) -> Result<Self, SPIOError> {
+        let path = data_path.join("silences.json");
+        let silences = if path.exists() {
+            File::open_from(OpenOptions::new().read(true), &path)?.read_json_p(&path)?
+        } else {
+            Vec::new()
+        };
+        Ok(Silences {
+            path: Some(path),
+            silences,
+        })
+    }
+
+    fn save(&self) -> Result<(), SPIOError> {
+        match self.path {
+            Some(ref p) => overwrite_json(&self.silences, p),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Adds a silence as requested at the given time, returning it, or `None`
+     * if it would silence everything.
+     */
+    pub fn add(&mut self, request: SilenceRequest, time: i64) -> Result<Option<Silence>, SPIOError> {
+        if request.target.is_none() && request.tag.is_none() {
+            return Ok(None);
+        }
+        self.silences.retain(|s| s.end > time);
+        let silence = Silence {
+            id: self.silences.iter().map(|s| s.id + 1).max().unwrap_or(1),
+            target: request.target,
+            tag: request.tag,
+            start: time,
+            end: time + request.duration as i64,
+            comment: request.comment,
+        };
+        info!("Silenced alerts of {} (tag {}) for {}s.", silence.target.as_deref().unwrap_or("every target"),
+              silence.tag.as_deref().unwrap_or("any"), request.duration);
+        self.silences.push(silence.clone());
+        self.save()?;
+        Ok(Some(silence))
+    }
+
+    /**
+     * Ends the silence of the given id before its time, returning whether
+     * there was one.
+     */
+    pub fn remove(&mut self, id: u32) -> Result<bool, SPIOError> {
+        let before = self.silences.len();
+        self.silences.retain(|s| s.id != id);
+        if self.silences.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /**
+     * Returns the silences not yet over at the given time.
+     */
+    pub fn list(&self, time: i64) -> Vec<Silence> {
+        self.silences.iter().filter(|s| s.end > time).cloned().collect()
+    }
+
+    /**
+     * Returns whether any silence applies, at the given time, to the alerts of
+     * the given target for an address with the given tags (if any).
+     */
+    pub fn silences(&self, target: &str, tags: Option<&Tags>, time: i64) -> bool {
+        self.silences.iter().any(|s| s.silences(target, tags, time))
+    }
+}
+
+#[test]
+fn silences_apply_to_their_target_and_tag_until_over() {
+    let mut silences = Silences::default();
+    let request = |target: Option<&str>, tag: Option<&str>| SilenceRequest {
+        target: target.map(|t| t.to_owned()),
+        tag: tag.map(|t| t.to_owned()),
+        duration: 600,
+        comment: String::new(),
+    };
+    assert!(silences.add(request(None, None), 100).unwrap().is_none());
+    let site = silences.add(request(Some("tcpping"), Some("site:home")), 100).unwrap().unwrap();
+    silences.add(request(Some("icmpping"), None), 200).unwrap();
+
+    let mut tags = Tags::new();
+    tags.insert("site".to_owned(), "home".to_owned());
+    assert!(silences.silences("tcpping", Some(&tags), 100));
+    assert!(!silences.silences("tcpping", None, 100));
+    assert!(!silences.silences("httpping", Some(&tags), 100));
+    assert!(!silences.silences("tcpping", Some(&tags), 700));
+    assert!(silences.silences("icmpping", None, 700));
+    assert_eq!(silences.list(700).len(), 1);
+
+    assert!(silences.remove(site.id).unwrap());
+    assert!(!silences.remove(site.id).unwrap());
+    assert!(!silences.silences("tcpping", Some(&tags), 300));
+}
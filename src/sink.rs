@@ -0,0 +1,155 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Fan-out of the results collected by the workers to any number of
+ * `ResultsSink`s (local persistence, websocket broadcast, exporters, etc.),
+ * each fed on its own thread so that a slow sink can't hold up the others.
+ */
+use std::fmt;
+use std::fmt::Display;
+use std::process;
+use std::thread;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::options::TargetResults;
+use crate::telemetry::{self, SinkStats, SLOW_DELIVERY};
+
+/**
+ * Error container for failures of a sink to deliver results.
+ */
+#[derive(Debug)]
+pub enum SinkError {
+    // these results could not be delivered, but later ones may be
+    Dropped(String),
+    // these results could not be delivered yet, but were kept to be later (see `spool.rs`)
+    Buffered(String),
+    // the sink can not continue, and neither can stabping
+    Fatal(String),
+}
+
+impl SinkError {
+    pub fn description(&self) -> String {
+        match *self {
+            SinkError::Dropped(ref e) => format!("Dropped results: {}", e),
+            SinkError::Buffered(ref e) => format!("Buffered results: {}", e),
+            SinkError::Fatal(ref e) => format!("Fatal error: {}", e),
+        }
+    }
+}
+
+impl Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.description())
+    }
+}
+
+/**
+ * A destination for every round of results collected by the workers.
+ */
+pub trait ResultsSink: Send {
+    /**
+     * A short name for this sink, used when reporting its errors.
+     */
+    fn name(&self) -> &'static str;
+
+    /**
+     * Delivers a round of results to this sink. Results are delivered in the
+     * order they were collected.
+     */
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError>;
+
+    /**
+     * Flushes anything this sink buffers, once all results have been
+     * delivered (on shutdown).
+     */
+    fn flush(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/**
+ * The bus that results are published to, and all sinks subscribe to.
+ */
+pub struct ResultsBus {
+    subscribers: Vec<(Sender<Arc<TargetResults>>, Arc<SinkStats>)>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ResultsBus {
+    pub fn new() -> Self {
+        ResultsBus {
+            subscribers: Vec::new(),
+            threads: Vec::new(),
+        }
+    }
+
+    /**
+     * Subscribes the given sink to all results published from now on,
+     * starting a thread to deliver them.
+     */
+    pub fn subscribe<S: ResultsSink + 'static>(&mut self, mut sink: S) {
+        let (tx, rx) = channel::<Arc<TargetResults>>();
+        let stats = telemetry::register_sink(sink.name());
+        self.subscribers.push((tx, stats.clone()));
+
+        let thread = thread::Builder::new().name(format!("sink-{}", sink.name())).spawn(move || {
+            let _span = info_span!("sink", sink = %sink.name()).entered();
+            for r in rx {
+                let started = Instant::now();
+                let result = sink.deliver(&r);
+                let took = started.elapsed();
+                stats.delivered(took, result.is_err());
+                if took >= SLOW_DELIVERY {
+                    warn!("{} sink took {}ms to deliver results.", sink.name(), took.as_millis());
+                }
+                match result {
+                    Ok(()) => (),
+                    Err(e @ SinkError::Dropped(_)) => warn!("{} sink: {}", sink.name(), e),
+                    // the spool warns as it starts buffering, rather than for every round
+                    Err(e @ SinkError::Buffered(_)) => debug!("{} sink: {}", sink.name(), e),
+                    Err(e @ SinkError::Fatal(_)) => {
+                        error!("{} sink: {}", sink.name(), e);
+                        process::exit(1);
+                    },
+                }
+            }
+
+            // the bus was closed
+            if let Err(e) = sink.flush() {
+                error!("{} sink: {}", sink.name(), e);
+            }
+        });
+        self.threads.push(thread.expect("Unable to start the thread of a sink."));
+    }
+
+    /**
+     * Publishes a round of results to all subscribed sinks.
+     */
+    pub fn publish(&self, results: TargetResults) {
+        let r = Arc::new(results);
+        for (s, stats) in self.subscribers.iter() {
+            // a sink's thread only exits by taking stabping down with it
+            stats.queued();
+            let _ = s.send(r.clone());
+        }
+    }
+
+    /**
+     * Closes the bus, waiting for every sink to be delivered all results
+     * published so far and flushed.
+     */
+    pub fn close(self) {
+        drop(self.subscribers);
+        for t in self.threads {
+            let _ = t.join();
+        }
+    }
+}
@@ -0,0 +1,195 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A minimal blocking SMTP client for sending plain text mail, optionally over
+ * TLS (implicit or via STARTTLS) and authenticating with AUTH PLAIN.
+ */
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use chrono::Local;
+
+use crate::http::{tls_handshake, HttpStream};
+use crate::options::{SmtpConfiguration, SmtpTls};
+
+/**
+ * Reads a (possibly multi-line) reply, returning its code and last line.
+ */
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<(u16, String)> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        let code = line.get(..3).and_then(|c| c.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed reply"))?;
+        // "250-..." is followed by more lines, "250 ..." is the last
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, line.trim_end().to_owned()));
+        }
+    }
+}
+
+/**
+ * Sends the given command (unless empty) and reads its reply, failing unless
+ * the reply's code is the given expected one.
+ */
+fn command<S: Read + Write>(conn: &mut BufReader<S>, cmd: &str, expect: u16) -> io::Result<()> {
+    if !cmd.is_empty() {
+        conn.get_mut().write_all(format!("{}\r\n", cmd).as_bytes())?;
+        conn.get_mut().flush()?;
+    }
+    let (code, line) = read_reply(conn)?;
+    if code != expect {
+        return Err(io::Error::other(format!("server replied '{}'", line)));
+    }
+    Ok(())
+}
+
+/**
+ * Encodes the given bytes as (padded) base64.
+ */
+pub fn base64(bytes: &[u8]) -> String {
+    static ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 |
+            (*chunk.get(1).unwrap_or(&0) as u32) << 8 |
+            *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/**
+ * Decodes the given base64 (as made by `base64`), or returns `None` if it
+ * isn't.
+ */
+pub fn unbase64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    for chunk in s.chunks(4) {
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let v = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            n |= (v as u32) << (18 - 6 * i);
+        }
+        if chunk.len() == 1 {
+            return None;
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+/**
+ * Builds the message (headers and dot-stuffed body, without the terminating
+ * `.`) of a plain text mail.
+ */
+fn message(from: &str, to: &[String], subject: &str, body: &str) -> String {
+    let mut msg = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\r\n",
+        from, to.join(", "), subject, Local::now().to_rfc2822()
+    );
+    for line in body.lines() {
+        if line.starts_with('.') {
+            msg.push('.');
+        }
+        msg.push_str(line);
+        msg.push_str("\r\n");
+    }
+    msg
+}
+
+/**
+ * Greets the server, authenticates (if configured) and sends the mail over
+ * the given connection.
+ */
+fn deliver<S: Read + Write>(conn: &mut BufReader<S>, config: &SmtpConfiguration,
+                            msg: &str) -> io::Result<()> {
+    command(conn, "EHLO stabping", 250)?;
+    if let (Some(u), Some(p)) = (&config.username, &config.password) {
+        let auth = base64(format!("\0{}\0{}", u, p).as_bytes());
+        command(conn, &format!("AUTH PLAIN {}", auth), 235)?;
+    }
+    command(conn, &format!("MAIL FROM:<{}>", config.from), 250)?;
+    for to in config.to.iter() {
+        command(conn, &format!("RCPT TO:<{}>", to), 250)?;
+    }
+    command(conn, "DATA", 354)?;
+    command(conn, &format!("{}.", msg), 250)?;
+    command(conn, "QUIT", 221)
+}
+
+/**
+ * Sends a plain text mail with the given subject and body through the
+ * configured server.
+ */
+pub fn send_mail(config: &SmtpConfiguration, subject: &str, body: &str) -> io::Result<()> {
+    let timeout = Duration::from_secs(30);
+    let (host, _) = config.server.rsplit_once(':')
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "server has no port"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let sock_addr = config.server.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))?;
+    let tcp = TcpStream::connect_timeout(&sock_addr, timeout)?;
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+
+    let msg = message(&config.from, &config.to, subject, body);
+
+    let mut conn = match config.tls {
+        SmtpTls::Implicit => BufReader::new(tls_handshake(host, tcp)?),
+        SmtpTls::None => BufReader::new(HttpStream::Plain(tcp)),
+        SmtpTls::Starttls => {
+            let mut plain = BufReader::new(tcp);
+            command(&mut plain, "", 220)?;
+            command(&mut plain, "EHLO stabping", 250)?;
+            command(&mut plain, "STARTTLS", 220)?;
+            let mut conn = BufReader::new(tls_handshake(host, plain.into_inner())?);
+            // the greeting was already read, so only the rest of the session remains
+            return deliver(&mut conn, config, &msg);
+        },
+    };
+
+    command(&mut conn, "", 220)?;
+    deliver(&mut conn, config, &msg)
+}
+
+#[test]
+fn base64_pads_partial_chunks() {
+    assert_eq!(base64(b"\0user\0pass"), "AHVzZXIAcGFzcw==");
+    assert_eq!(base64(b"abc"), "YWJj");
+    assert_eq!(base64(b"ab"), "YWI=");
+}
+
+#[test]
+fn unbase64_undoes_base64() {
+    for s in [&b""[..], b"a", b"ab", b"abc", b"\0user\0pass", &[0xff, 0xfe, 0x00, 0x80]] {
+        assert_eq!(unbase64(&base64(s)).as_deref(), Some(s));
+    }
+    assert_eq!(unbase64("YW*j"), None);
+    assert_eq!(unbase64("YWJjZ"), None);
+}
@@ -0,0 +1,257 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Opening of the sockets probes send from, as their settings say: bound to
+ * their source (see `source.rs`), with their packets marked with a DSCP (the
+ * upper six bits of the IPv4 ToS or IPv6 Traffic Class), to see whether a
+ * router's QoS classes get different treatment under load, and for TCP, with
+ * the options (see `TcpOptions`) of the application whose traffic is being
+ * debugged.
+ */
+use std::io;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::source;
+use crate::worker::ProbeSettings;
+
+/**
+ * A Differentiated Services Code Point (RFC 2474), written as a number from 0
+ * to 63 or by the name of a standard one (e.g. `ef`, `af41` or `cs1`).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "DscpValue", into = "u8")]
+pub struct Dscp(pub u8);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DscpValue {
+    Number(u8),
+    Name(String),
+}
+
+impl TryFrom<DscpValue> for Dscp {
+    type Error = String;
+
+    fn try_from(v: DscpValue) -> Result<Self, String> {
+        match v {
+            DscpValue::Number(n) if n < 64 => Ok(Dscp(n)),
+            DscpValue::Number(n) => Err(format!("invalid DSCP {} (expected 0 to 63)", n)),
+            DscpValue::Name(s) => Dscp::named(&s).ok_or_else(|| format!("unknown DSCP '{}'", s)),
+        }
+    }
+}
+
+impl From<Dscp> for u8 {
+    fn from(d: Dscp) -> u8 {
+        d.0
+    }
+}
+
+impl Dscp {
+    /**
+     * Gets the standard code point of the given name: `cs0` to `cs7`, `af11`
+     * to `af43` (RFC 2597), `ef` (RFC 3246), `va` (RFC 5865), or `le` (RFC
+     * 8622), in any case.
+     */
+    pub fn named(name: &str) -> Option<Dscp> {
+        let name = name.to_ascii_lowercase();
+        let digits = |s: &str| s.chars().map(|c| c.to_digit(10)).collect::<Option<Vec<u32>>>();
+        let n = match name.as_str() {
+            "ef" => 46,
+            "va" => 44,
+            "le" => 1,
+            n if n.starts_with("cs") => match digits(&n[2..])?[..] {
+                [class] if class < 8 => class * 8,
+                _ => return None,
+            },
+            n if n.starts_with("af") => match digits(&n[2..])?[..] {
+                [class, drop] if (1..5).contains(&class) && (1..4).contains(&drop) => class * 8 + drop * 2,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(Dscp(n as u8))
+    }
+}
+
+/**
+ * Options of the TCP connections of probes (a target's `tcp`), each left as
+ * the system has it when absent.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TcpOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodelay: Option<bool>,  // whether Nagle's algorithm is disabled (TCP_NODELAY)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linger: Option<u32>,  // seconds to linger on close with data unsent (SO_LINGER), 0 to reset instead
+    #[serde(default)]
+    pub fast_open: bool,  // whether data goes out with the SYN, given a cookie (TCP Fast Open, on Linux)
+}
+
+/**
+ * Sets the given options on the given TCP socket, before it connects.
+ */
+fn set_tcp_options(socket: &Socket, options: &TcpOptions) -> io::Result<()> {
+    if let Some(nodelay) = options.nodelay {
+        socket.set_nodelay(nodelay)?;
+    }
+    if let Some(linger) = options.linger {
+        socket.set_linger(Some(Duration::from_secs(linger as u64)))?;
+    }
+    if options.fast_open {
+        set_fast_open(socket)?;
+    }
+    Ok(())
+}
+
+/**
+ * Makes the given TCP socket connect with Fast Open (RFC 7413): `connect`
+ * returns at once, the SYN going out with the first data written.
+ */
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_fast_open(socket: &Socket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT,
+                         &enable as *const libc::c_int as *const libc::c_void,
+                         std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// elsewhere, connections are made as usual
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_fast_open(_: &Socket) -> io::Result<()> {
+    Ok(())
+}
+
+/**
+ * Marks the packets of the given socket (to send to the given address) with
+ * the given DSCP.
+ */
+fn set_dscp(socket: &Socket, dscp: Dscp, dest: &SocketAddr) -> io::Result<()> {
+    let tos = (dscp.0 as u32) << 2;
+    match *dest {
+        SocketAddr::V4(_) => socket.set_tos(tos),
+        SocketAddr::V6(_) => set_tclass(socket, tos),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd",
+          target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly"))]
+fn set_tclass(socket: &Socket, tclass: u32) -> io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd",
+              target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly")))]
+fn set_tclass(_: &Socket, _: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "marking IPv6 packets is unsupported here"))
+}
+
+/**
+ * Readies the given (unconnected) socket to send to the given address as the
+ * given settings say.
+ */
+pub fn prepare(socket: &Socket, dest: &SocketAddr, settings: &ProbeSettings) -> io::Result<()> {
+    if let Some(ref s) = settings.source {
+        source::bind(socket, s, dest)?;
+    }
+    if let Some(dscp) = settings.dscp {
+        set_dscp(socket, dscp, dest)?;
+    }
+    Ok(())
+}
+
+/**
+ * Whether the given settings ask for any more than a plain socket.
+ */
+fn is_plain(settings: &ProbeSettings) -> bool {
+    settings.source.is_none() && settings.dscp.is_none() && settings.tcp == TcpOptions::default()
+}
+
+/**
+ * Connects to the given address as the given settings say, within their
+ * timeout.
+ */
+pub fn connect_tcp(dest: &SocketAddr, settings: &ProbeSettings) -> io::Result<TcpStream> {
+    if is_plain(settings) {
+        return TcpStream::connect_timeout(dest, settings.timeout);
+    }
+    let socket = Socket::new(Domain::for_address(*dest), Type::STREAM, Some(Protocol::TCP))?;
+    prepare(&socket, dest, settings)?;
+    set_tcp_options(&socket, &settings.tcp)?;
+    socket.connect_timeout(&(*dest).into(), settings.timeout)?;
+    Ok(socket.into())
+}
+
+/**
+ * Opens a UDP socket to send to the given address from, as the given settings
+ * say.
+ */
+pub fn udp_socket(dest: &SocketAddr, settings: &ProbeSettings) -> io::Result<UdpSocket> {
+    if is_plain(settings) {
+        return UdpSocket::bind(if dest.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" });
+    }
+    let socket = Socket::new(Domain::for_address(*dest), Type::DGRAM, Some(Protocol::UDP))?;
+    prepare(&socket, dest, settings)?;
+    Ok(socket.into())
+}
+
+#[test]
+fn sockets_open_as_settings_say() {
+    use std::net::{IpAddr, TcpListener};
+    use std::time::Duration;
+    use crate::source::Source;
+
+    #[derive(Deserialize)]
+    struct Marked {
+        dscp: Dscp,
+    }
+    let parse = |s: &str| toml::from_str::<Marked>(&format!("dscp = {}", s)).map(|m| m.dscp).ok();
+    assert_eq!(parse("46"), Some(Dscp(46)));
+    assert_eq!(parse("\"EF\""), Some(Dscp(46)));
+    assert_eq!(parse("\"af41\""), Some(Dscp(34)));
+    assert_eq!(parse("\"cs1\""), Some(Dscp(8)));
+    assert_eq!((parse("64"), parse("\"af51\""), parse("\"cs\"")), (None, None, None));
+
+    let mut settings = ProbeSettings {
+        timeout: Duration::from_secs(1),
+        resolve_ttl: Duration::from_secs(0),
+        family: crate::options::AddressFamily::Any,
+        pin: None,
+        source: Some(Source::parse("127.0.0.1")),
+        proxy: None,
+        dscp: Some(Dscp(46)),
+        tcp: TcpOptions { nodelay: Some(true), linger: Some(0), fast_open: true },
+    };
+    let dest: SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let socket = Socket::from(udp_socket(&dest, &settings).unwrap());
+    assert_eq!(socket.local_addr().unwrap().as_socket().unwrap().ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+    assert_eq!(socket.tos().unwrap(), 46 << 2);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = Socket::from(connect_tcp(&listener.local_addr().unwrap(), &settings).unwrap());
+    assert_eq!(stream.tos().unwrap(), 46 << 2);
+    assert!(stream.nodelay().unwrap());
+    assert_eq!(stream.linger().unwrap(), Some(Duration::from_secs(0)));
+
+    settings.source = Some(Source::parse("::1"));
+    assert!(udp_socket(&dest, &settings).is_err());
+}
@@ -0,0 +1,96 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Binding of the sockets probes send from (see `sockopt.rs`) to a source IP or
+ * network interface (a target's `source`, or failing that, the
+ * configuration's), so that a multi-homed host can compare its paths (e.g.
+ * over WAN1 and WAN2, or over a VPN and directly), each target or address
+ * tagged with the link it takes.
+ */
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+
+use socket2::Socket;
+
+static DEFAULT: RwLock<Option<String>> = RwLock::new(None);
+
+/**
+ * Sets the source of targets without one of their own (see `source` in the
+ * configuration).
+ */
+pub fn set_default(source: Option<String>) {
+    *DEFAULT.write().unwrap() = source;
+}
+
+/**
+ * Gets the source of targets without one of their own.
+ */
+pub fn default_source() -> Option<String> {
+    DEFAULT.read().unwrap().clone()
+}
+
+/**
+ * What probe sockets are bound to: an IP of the host, or (on Linux) one of
+ * its network interfaces, by name.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Ip(IpAddr),
+    Interface(String),
+}
+
+impl Source {
+    /**
+     * Parses the given source, an IP if it is one, the name of an interface
+     * otherwise.
+     */
+    pub fn parse(source: &str) -> Source {
+        match source.parse() {
+            Ok(ip) => Source::Ip(ip),
+            Err(_) => Source::Interface(source.to_owned()),
+        }
+    }
+}
+
+/**
+ * Binds the given (unconnected) socket to the given source, to send to the
+ * given address from.
+ */
+pub fn bind(socket: &Socket, source: &Source, dest: &SocketAddr) -> io::Result<()> {
+    match *source {
+        Source::Ip(ip) if ip.is_ipv4() != dest.is_ipv4() => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "source is of another address family"))
+        },
+        Source::Ip(ip) => socket.bind(&SocketAddr::new(ip, 0).into()),
+        Source::Interface(ref name) => bind_device(socket, name),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, name: &str) -> io::Result<()> {
+    socket.bind_device(Some(name.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_: &Socket, _: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "binding to interfaces is only supported on Linux"))
+}
+
+#[test]
+fn sources_parse_and_bind() {
+    assert_eq!(Source::parse("192.0.2.1"), Source::Ip("192.0.2.1".parse().unwrap()));
+    assert_eq!(Source::parse("wg0"), Source::Interface("wg0".to_owned()));
+
+    let dest: SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let socket = Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
+    assert!(bind(&socket, &Source::parse("::1"), &dest).is_err());
+    bind(&socket, &Source::parse("127.0.0.1"), &dest).unwrap();
+    assert_eq!(socket.local_addr().unwrap().as_socket().unwrap().ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+}
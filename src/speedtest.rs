@@ -0,0 +1,251 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Speed test data collection against LibreSpeed or (legacy HTTP) Ookla
+ * servers: the download and upload throughput over parallel streams (see
+ * `throughput.rs`), and the latency to the server while idle and while the
+ * link is loaded by them, which is what tells bufferbloat apart from a slow
+ * link when lined up with the continuous latency series.
+ */
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use time::precise_time_ns;
+
+use crate::http::Url;
+use crate::options::{Aggregate, TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema, Unit};
+use crate::resolve;
+use crate::proxy;
+use crate::throughput::{Transfer, parse_size, rate, transfer_once};
+use crate::worker::{ProbeSettings, aggregate};
+
+/**
+ * Bytes transferred each way when an addr doesn't say.
+ */
+const DEFAULT_SIZE: u64 = 25_000_000;
+
+/**
+ * Parallel streams each way, as a single TCP connection rarely fills a link.
+ */
+const STREAMS: u64 = 4;
+
+/**
+ * Handshakes timed for the idle latency.
+ */
+const IDLE_PINGS: usize = 5;
+
+/**
+ * Pause between the handshakes timed while under load.
+ */
+const LOADED_GAP: Duration = Duration::from_millis(100);
+
+pub static KIND: TargetKind = TargetKind::of(&Speedtest);
+
+/**
+ * The kind of speed test server, telling the paths to transfer with.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Server {
+    LibreSpeed,  // `garbage.php` and `empty.php`
+    Ookla,  // the legacy `random4000x4000.jpg` and `upload.php`
+}
+
+/**
+ * The Speedtest target, running a speed test against each addr, the URL of
+ * the directory of a server's endpoints (e.g. LibreSpeed's `backend/`)
+ * optionally preceded by the kind of server, `librespeed` (the default) or
+ * `ookla`, and the size to transfer each way in bytes (with an optional `kB`,
+ * `MB` or `GB` suffix, 25MB if not given), e.g.
+ * `ookla 50MB http://speedtest.example.net:8080/speedtest/`.
+ *
+ * Returns the download throughput in thousandths of kbit/s (i.e. recorded in
+ * kbit/s), followed by the upload throughput likewise, and the median time of
+ * a TCP handshake with the server while the link is idle and while it's
+ * loaded by the transfers (a handshake timing out counting as the timeout).
+ */
+pub struct Speedtest;
+
+impl Probe for Speedtest {
+    type Target = (Server, u64, Url, SocketAddr);  // the server, bytes each way, its URL and address
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 10,
+        name: "speedtest",
+        columns: &["", "upload", "idle", "loaded"],
+        units: &[("", Unit::Rate), ("upload", Unit::Rate)],
+        default_timeout: 30_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &[],
+        default_interval: 3_600_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32> {
+        let (server, size, url_str) = parse_addr(addr).ok_or(SENTINEL_ERROR)?;
+        let url = Url::parse(url_str).ok_or(SENTINEL_ERROR)?;
+        let sock_addr = resolve::resolve(&url.host, url.port, settings).ok_or(SENTINEL_RESOLVE)?;
+        Ok((server, size, url, sock_addr))
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        let url = Url::parse(parse_addr(addr)?.2)?;
+        Some((url.host, url.port))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.3.ip())
+    }
+
+    fn probe_once(&self, _: &str, (server, size, url, sock_addr): Self::Target,
+                  attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        speedtest_once(server, size, &url, sock_addr, attempt.settings)
+    }
+}
+
+/**
+ * Splits the given addr into the kind of server, the size to transfer each
+ * way, and its URL.
+ */
+fn parse_addr(addr: &str) -> Option<(Server, u64, &str)> {
+    let mut words: Vec<&str> = addr.split_whitespace().collect();
+    let url = words.pop()?;
+    let (server, rest) = match words.split_first() {
+        Some((&"librespeed", rest)) => (Server::LibreSpeed, rest),
+        Some((&"ookla", rest)) => (Server::Ookla, rest),
+        _ => (Server::LibreSpeed, &words[..]),
+    };
+    let size = match *rest {
+        [] => DEFAULT_SIZE,
+        [size] => parse_size(size)?,
+        _ => return None,
+    };
+    Some((server, size, url))
+}
+
+/**
+ * Gets the URL of the given endpoint (e.g. `empty.php`) in the directory of
+ * the given URL, which is the URL itself unless it names a file (e.g. one of
+ * the endpoints).
+ */
+fn endpoint(url: &Url, name: &str) -> Url {
+    let path = url.path.split('?').next().unwrap_or("");
+    let dir = match path.rsplit_once('/') {
+        Some((dir, file)) if file.contains('.') => format!("{}/", dir),
+        _ if path.ends_with('/') => path.to_owned(),
+        _ => format!("{}/", path),
+    };
+    Url {
+        https: url.https,
+        host: url.host.clone(),
+        port: url.port,
+        path: dir + name,
+    }
+}
+
+/**
+ * Gets the URL and transfer of each stream of the given kind of server moving
+ * the given number of bytes in total, uploading or downloading.
+ */
+fn streams(server: Server, upload: bool, size: u64, url: &Url) -> (Url, Transfer) {
+    let per_stream = size.div_ceil(STREAMS);
+    let name = match (server, upload) {
+        (Server::LibreSpeed, false) => format!("garbage.php?ckSize={}", per_stream.div_ceil(1 << 20)),
+        (Server::LibreSpeed, true) => "empty.php".to_owned(),
+        (Server::Ookla, false) => "random4000x4000.jpg".to_owned(),
+        (Server::Ookla, true) => "upload.php".to_owned(),
+    };
+    (endpoint(url, &name), Transfer { upload, size: Some(per_stream) })
+}
+
+/**
+ * Times a TCP handshake with the given address.
+ */
+fn handshake(sock_addr: &SocketAddr, settings: &ProbeSettings) -> Result<u64, i32> {
+    let start = precise_time_ns();
+    proxy::connect(sock_addr, settings).map_err(|e| io_error_sentinel(&e))?;
+    Ok(precise_time_ns() - start)
+}
+
+/**
+ * Moves the given transfer with the given URL over `STREAMS` parallel
+ * streams, timing handshakes with its address meanwhile, returning the
+ * combined throughput and the handshake times.
+ */
+fn under_load(url: &Url, transfer: Transfer, sock_addr: SocketAddr,
+              settings: &ProbeSettings) -> Result<(u64, Vec<u64>), i32> {
+    let running = AtomicUsize::new(STREAMS as usize);
+    let timeout = settings.timeout.as_nanos() as u64;
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..STREAMS).map(|_| scope.spawn(|| {
+            let moved = transfer_once(transfer, url, sock_addr, settings);
+            running.fetch_sub(1, Ordering::SeqCst);
+            moved
+        })).collect();
+
+        let mut pings = Vec::new();
+        while running.load(Ordering::SeqCst) > 0 {
+            pings.push(handshake(&sock_addr, settings).unwrap_or(timeout));
+            thread::sleep(LOADED_GAP);
+        }
+
+        let mut total = 0;
+        for h in handles {
+            let (bytes, _, nanos) = h.join().unwrap_or(Err(SENTINEL_ERROR))?;
+            total += rate(bytes, nanos);
+        }
+        Ok((total, pings))
+    })
+}
+
+/**
+ * Runs a single speed test against the given kind of server at the given URL,
+ * its host having resolved to the given address.
+ */
+fn speedtest_once(server: Server, size: u64, url: &Url, sock_addr: SocketAddr,
+                  settings: &ProbeSettings) -> Result<Vec<u64>, i32> {
+    let idle = (0..IDLE_PINGS).map(|_| handshake(&sock_addr, settings)).collect::<Result<Vec<_>, _>>()?;
+
+    let (down_url, down) = streams(server, false, size, url);
+    let (download, mut loaded) = under_load(&down_url, down, sock_addr, settings)?;
+    let (up_url, up) = streams(server, true, size, url);
+    let (upload, loaded_up) = under_load(&up_url, up, sock_addr, settings)?;
+    loaded.extend(loaded_up);
+
+    Ok(vec![
+        download,
+        upload,
+        aggregate(Aggregate::Median, &idle),
+        if loaded.is_empty() { 0 } else { aggregate(Aggregate::Median, &loaded) },
+    ])
+}
+
+#[test]
+fn speedtests_find_their_endpoints() {
+    assert_eq!(parse_addr("https://speed.example.com/backend/"),
+               Some((Server::LibreSpeed, DEFAULT_SIZE, "https://speed.example.com/backend/")));
+    assert_eq!(parse_addr("ookla 50MB http://x:8080/speedtest/"),
+               Some((Server::Ookla, 50_000_000, "http://x:8080/speedtest/")));
+    assert_eq!(parse_addr("librespeed fast http://x/"), None);
+
+    let path = |url: &str, name: &str| endpoint(&Url::parse(url).unwrap(), name).path;
+    assert_eq!(path("https://speed.example.com/backend/", "empty.php"), "/backend/empty.php");
+    assert_eq!(path("https://speed.example.com/backend", "empty.php"), "/backend/empty.php");
+    assert_eq!(path("http://x:8080/speedtest/upload.php", "latency.txt"), "/speedtest/latency.txt");
+    assert_eq!(path("http://x/", "empty.php"), "/empty.php");
+
+    let url = Url::parse("http://x/backend/").unwrap();
+    let (down, transfer) = streams(Server::LibreSpeed, false, 10_000_000, &url);
+    assert_eq!(down.path, "/backend/garbage.php?ckSize=3");
+    assert_eq!(transfer, Transfer { upload: false, size: Some(2_500_000) });
+    assert_eq!(streams(Server::Ookla, true, 10_000_000, &url).0.path, "/backend/upload.php");
+}
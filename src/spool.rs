@@ -0,0 +1,232 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Buffering on disk of what the sinks pushing results elsewhere (to the
+ * central server of an agent, InfluxDB and Graphite) fail to send, e.g. while
+ * the server is down or unreachable, so that no results of an outage are lost.
+ *
+ * Each sink renders every round into what it sends (carrying the round's own
+ * timestamp) before trying to send it, and its spool keeps whatever couldn't
+ * be sent, in order, in a file of the data directory (one per line, in
+ * base64). Once sending works again, everything buffered is replayed, oldest
+ * first, before the newest round. Servers refusing what they are sent (rather
+ * than failing to take it) have it dropped instead, so that one bad round
+ * can't hold up the rest. Past the size limit, the oldest are dropped first.
+ */
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::sink::SinkError;
+use crate::smtp::{base64, unbase64};
+
+/**
+ * Why something couldn't be sent: it might be later (e.g. the server is
+ * unreachable), or it never will be (e.g. the server refused it).
+ */
+#[derive(Debug)]
+pub enum PushError {
+    Retry(String),
+    Reject(String),
+}
+
+/**
+ * Makes out whether pushing something by HTTP (given the request's status or
+ * error) worked, or might if retried: everything but statuses saying that what
+ * was sent is bad (e.g. `400 Bad Request`) might, once the server is back or
+ * its configuration fixed.
+ */
+pub fn http_outcome(outcome: io::Result<u16>) -> Result<(), PushError> {
+    match outcome {
+        Ok(status) if (200..300).contains(&status) => Ok(()),
+        Ok(status @ (400 | 413 | 422)) => Err(PushError::Reject(format!("rejected with status {}", status))),
+        Ok(status) => Err(PushError::Retry(format!("failed with status {}", status))),
+        Err(e) => Err(PushError::Retry(format!("{}", e))),
+    }
+}
+
+/**
+ * The buffer of a sink, of everything it has yet to send.
+ */
+pub struct Spool {
+    path: PathBuf,
+    limit: u64,  // in bytes, 0 not buffering at all
+    pending: VecDeque<Vec<u8>>,
+    bytes: u64,
+    dropped: u64,  // oldest dropped past the limit, since it last emptied
+}
+
+impl Spool {
+    /**
+     * Opens the spool at the given path (loading what was left buffered in
+     * it), buffering up to the given number of bytes.
+     */
+    pub fn open(path: PathBuf, limit: u64) -> Self {
+        let mut spool = Spool {
+            path,
+            limit,
+            pending: VecDeque::new(),
+            bytes: 0,
+            dropped: 0,
+        };
+        if let Ok(contents) = fs::read_to_string(&spool.path) {
+            let pending: VecDeque<Vec<u8>> = contents.lines().filter_map(unbase64).collect();
+            if !pending.is_empty() {
+                info!("Loaded {} rounds left buffered in '{}'.", pending.len(), spool.path.display());
+            }
+            spool.bytes = pending.iter().map(|p| p.len() as u64).sum();
+            spool.pending = pending;
+        }
+        spool
+    }
+
+    /**
+     * Sends the given payload with the given function, once everything
+     * buffered has been replayed (in order), buffering it instead if sending
+     * fails but might work later. The function is told whether what it sends
+     * is being replayed.
+     */
+    pub fn send<F>(&mut self, payload: Vec<u8>, mut push: F) -> Result<(), SinkError>
+        where F: FnMut(&[u8], bool) -> Result<(), PushError> {
+        let buffered = self.pending.len();
+        let mut failure = None;
+        while let Some(p) = self.pending.front() {
+            match push(p, true) {
+                Ok(()) => {},
+                Err(PushError::Reject(e)) => warn!("Dropping buffered round: {}", e),
+                Err(PushError::Retry(e)) => {
+                    failure = Some(e);
+                    break;
+                },
+            }
+            let p = self.pending.pop_front().unwrap();
+            self.bytes -= p.len() as u64;
+        }
+        let replayed = buffered - self.pending.len();
+        if replayed > 0 {
+            info!("Replayed {} buffered rounds ({} left).", replayed, self.pending.len());
+        }
+
+        let failure = match failure {
+            Some(e) => e,
+            None => match push(&payload, false) {
+                Ok(()) => {
+                    if replayed > 0 {
+                        self.dropped = 0;
+                        self.rewrite();
+                    }
+                    return Ok(());
+                },
+                Err(PushError::Reject(e)) => {
+                    if replayed > 0 {
+                        self.rewrite();
+                    }
+                    return Err(SinkError::Dropped(e));
+                },
+                Err(PushError::Retry(e)) => e,
+            },
+        };
+
+        if self.limit == 0 {
+            return Err(SinkError::Dropped(failure));
+        }
+        if self.pending.is_empty() {
+            warn!("Unable to send results ({}), buffering them in '{}' to replay later.",
+                  failure, self.path.display());
+        }
+        self.bytes += payload.len() as u64;
+        self.pending.push_back(payload);
+        let mut trimmed = false;
+        while self.bytes > self.limit && self.pending.len() > 1 {
+            let p = self.pending.pop_front().unwrap();
+            self.bytes -= p.len() as u64;
+            if self.dropped == 0 {
+                warn!("Buffer '{}' is full, dropping its oldest rounds.", self.path.display());
+            }
+            self.dropped += 1;
+            trimmed = true;
+        }
+        if replayed > 0 || trimmed {
+            self.rewrite();
+        } else if let Err(e) = self.append(self.pending.back().unwrap()) {
+            warn!("Unable to write to buffer '{}': {}", self.path.display(), e);
+        }
+        Err(SinkError::Buffered(failure))
+    }
+
+    fn append(&self, payload: &[u8]) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", base64(payload))
+    }
+
+    /**
+     * Rewrites the file of the spool with what is buffered, removing it once
+     * nothing is.
+     */
+    fn rewrite(&self) {
+        let result = if self.pending.is_empty() {
+            match fs::remove_file(&self.path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                r => r,
+            }
+        } else {
+            let tmp = self.path.with_extension("spool.tmp");
+            let mut contents = String::with_capacity(self.bytes as usize * 4 / 3 + self.pending.len() * 4);
+            for p in self.pending.iter() {
+                contents.push_str(&base64(p));
+                contents.push('\n');
+            }
+            fs::write(&tmp, contents).and_then(|_| fs::rename(&tmp, &self.path))
+        };
+        if let Err(e) = result {
+            warn!("Unable to write to buffer '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+#[test]
+fn buffered_rounds_replay_in_order_once_sending_works() {
+    let dir = std::env::temp_dir().join(format!("stabping-test-{}-spool", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("test.spool");
+    let _ = fs::remove_file(&path);
+
+    let mut sent: Vec<(Vec<u8>, bool)> = Vec::new();
+    let mut spool = Spool::open(path.clone(), 1024);
+    let down = |_: &[u8], _| Err(PushError::Retry("down".to_owned()));
+    assert!(matches!(spool.send(b"a\nmultiline".to_vec(), down), Err(SinkError::Buffered(_))));
+    assert!(matches!(spool.send(b"b".to_vec(), down), Err(SinkError::Buffered(_))));
+
+    // what's buffered survives a restart
+    let mut spool = Spool::open(path.clone(), 1024);
+    assert_eq!(spool.pending.len(), 2);
+    let up = |p: &[u8], replayed| match p {
+        b"b" => Err(PushError::Reject("bad".to_owned())),
+        p => {
+            sent.push((p.to_vec(), replayed));
+            Ok(())
+        },
+    };
+    assert!(spool.send(b"c".to_vec(), up).is_ok());
+    assert_eq!(sent, vec![(b"a\nmultiline".to_vec(), true), (b"c".to_vec(), false)]);
+    assert_eq!(spool.pending.len(), 0);
+    assert!(!path.exists());
+
+    // past the limit the oldest go first, and without a limit nothing is buffered
+    let mut spool = Spool::open(path.clone(), 2);
+    for p in [b"x", b"y", b"z"] {
+        assert!(spool.send(p.to_vec(), down).is_err());
+    }
+    assert_eq!(Spool::open(path.clone(), 2).pending, vec![b"y".to_vec(), b"z".to_vec()]);
+    assert!(matches!(Spool::open(dir.join("none.spool"), 0).send(b"a".to_vec(), down),
+                     Err(SinkError::Dropped(_))));
+    let _ = fs::remove_dir_all(&dir);
+}
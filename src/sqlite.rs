@@ -0,0 +1,230 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Storage of a target's data elements in an SQLite database (built with the
+ * `sqlite` feature), so that they can be queried with SQL and backed up with
+ * standard tooling. Each database holds a `data` table of (time, idx, value)
+ * rows, a `keys` table naming the address and column of each index, and a
+ * `samples` view joining the two.
+ */
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection};
+
+use crate::helpers::SPIOError;
+use crate::persist::{DataElement, ManagerError};
+use crate::storage::Storage;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS data (
+        time INTEGER NOT NULL,
+        idx INTEGER NOT NULL,
+        value INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS data_time ON data (time);
+    CREATE TABLE IF NOT EXISTS keys (
+        idx INTEGER PRIMARY KEY,
+        addr TEXT NOT NULL,
+        column TEXT NOT NULL
+    );
+    CREATE VIEW IF NOT EXISTS samples AS
+        SELECT time, addr, column, value FROM data JOIN keys USING (idx);
+";
+
+pub struct SqliteStorage {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /**
+     * Opens (creating it and its tables if necessary) the database at the
+     * given path.
+     */
+    pub fn open(path: &Path) -> Result<Self, ManagerError> {
+        let open_error = || ManagerError::DataFileIO(SPIOError::Open(Some(path.to_owned())));
+        let conn = Connection::open(path).map_err(|_| open_error())?;
+        conn.execute_batch(SCHEMA).map_err(|e| {
+            error!("Failed to set up database '{}': {}", path.display(), e);
+            open_error()
+        })?;
+        Ok(SqliteStorage {
+            path: path.to_owned(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn write_error(&self, e: rusqlite::Error) -> ManagerError {
+        error!("Failed to write to database '{}': {}", self.path.display(), e);
+        ManagerError::DataFileIO(SPIOError::Write(Some(self.path.clone())))
+    }
+}
+
+/**
+ * Writes a consistent copy of the database at the given path (even while it
+ * is being written to elsewhere) to the given destination.
+ */
+pub fn snapshot(path: &Path, dest: &Path) -> Result<(), SPIOError> {
+    let conn = Connection::open(path).map_err(|_| SPIOError::Open(Some(path.to_owned())))?;
+    conn.execute("VACUUM INTO ?1", params![dest.to_string_lossy()])
+        .map(|_| ())
+        .map_err(|e| {
+            error!("Failed to copy database '{}': {}", path.display(), e);
+            SPIOError::Write(Some(dest.to_owned()))
+        })
+}
+
+/**
+ * Checks the integrity of the database at the given path (rebuilding its
+ * indices first, if asked to), returning the problems found, if any.
+ */
+pub fn check_integrity(path: &Path, reindex: bool) -> Result<Vec<String>, SPIOError> {
+    let conn = Connection::open(path).map_err(|_| SPIOError::Open(Some(path.to_owned())))?;
+    if reindex {
+        conn.execute_batch("REINDEX").map_err(|_| SPIOError::Write(Some(path.to_owned())))?;
+    }
+    let mut check = conn.prepare("PRAGMA integrity_check")
+        .map_err(|_| SPIOError::Read(Some(path.to_owned())))?;
+    let problems = check.query_map([], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+        .map_err(|_| SPIOError::Read(Some(path.to_owned())))?;
+    Ok(problems.into_iter().filter(|p| p != "ok").collect())
+}
+
+impl Storage for SqliteStorage {
+    fn append(&self, elements: &[DataElement]) -> Result<(), ManagerError> {
+        let mut conn = self.conn.lock().unwrap();
+        // insert each round in a single transaction
+        let tx = conn.transaction().map_err(|e| self.write_error(e))?;
+        {
+            let mut insert = tx.prepare_cached("INSERT INTO data (time, idx, value) VALUES (?1, ?2, ?3)")
+                .map_err(|e| self.write_error(e))?;
+            for e in elements.iter() {
+                insert.execute(params![e.time, e.index, e.val]).map_err(|e| self.write_error(e))?;
+            }
+        }
+        tx.commit().map_err(|e| self.write_error(e))
+    }
+
+    fn read_range(&self, lower: i64, upper: i64,
+                  f: &mut dyn FnMut(&[DataElement]) -> io::Result<()>) -> io::Result<()> {
+        let elements = {
+            let conn = self.conn.lock().unwrap();
+            let mut select = conn.prepare_cached(
+                    "SELECT time, idx, value FROM data WHERE time BETWEEN ?1 AND ?2 ORDER BY time, rowid")
+                .map_err(io::Error::other)?;
+            let rows = select.query_map(params![lower, upper], |row| {
+                Ok(DataElement {
+                    time: row.get(0)?,
+                    index: row.get(1)?,
+                    val: row.get(2)?,
+                })
+            }).map_err(io::Error::other)?;
+            rows.collect::<Result<Vec<_>, _>>().map_err(io::Error::other)?
+        };
+        f(&elements)
+    }
+
+    fn first_time(&self) -> io::Result<Option<i64>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT MIN(time) FROM data", [], |row| row.get(0))
+            .map_err(io::Error::other)
+    }
+
+    fn prune(&self, before: i64) -> Result<u64, ManagerError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM data WHERE time < ?1", params![before])
+            .map(|n| n as u64)
+            .map_err(|e| self.write_error(e))
+    }
+
+    fn import(&self, elements: &[DataElement]) -> Result<Vec<DataElement>, ManagerError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| self.write_error(e))?;
+        let mut merged = Vec::new();
+        {
+            let mut insert = tx.prepare_cached(
+                    "INSERT INTO data (time, idx, value) SELECT ?1, ?2, ?3
+                     WHERE NOT EXISTS (SELECT 1 FROM data WHERE time = ?1 AND idx = ?2)")
+                .map_err(|e| self.write_error(e))?;
+            for e in elements.iter() {
+                if insert.execute(params![e.time, e.index, e.val]).map_err(|e| self.write_error(e))? > 0 {
+                    merged.push(*e);
+                }
+            }
+        }
+        tx.commit().map_err(|e| self.write_error(e))?;
+        Ok(merged)
+    }
+
+    fn describe_keys(&self, keys: &[String]) -> Result<(), ManagerError> {
+        let conn = self.conn.lock().unwrap();
+        let mut insert = conn.prepare_cached(
+                "INSERT OR IGNORE INTO keys (idx, addr, column) VALUES (?1, ?2, ?3)")
+            .map_err(|e| self.write_error(e))?;
+        for (i, key) in keys.iter().enumerate() {
+            let (addr, column) = key.split_once('\t').unwrap_or((key, ""));
+            insert.execute(params![i as i32, addr, column]).map_err(|e| self.write_error(e))?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), ManagerError> {
+        // every round is committed as it is appended
+        Ok(())
+    }
+}
+
+#[test]
+fn sqlite_storage_reads_back_range_by_time() {
+    let path = std::env::temp_dir().join(format!("stabping-test-{}.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let storage = SqliteStorage::open(&path).unwrap();
+
+    for time in 1..=5 {
+        let round: Vec<DataElement> = (0..2)
+            .map(|index| DataElement { time, index, val: time as i32 * 10 + index })
+            .collect();
+        storage.append(&round).unwrap();
+    }
+    storage.describe_keys(&["a:80".to_owned(), "a:80\tdns".to_owned()]).unwrap();
+
+    let mut vals = Vec::new();
+    storage.read_range(2, 3, &mut |elements| {
+        vals.extend(elements.iter().map(|e| (e.time, e.index, e.val)));
+        Ok(())
+    }).unwrap();
+    assert_eq!(vals, vec![(2, 0, 20), (2, 1, 21), (3, 0, 30), (3, 1, 31)]);
+
+    let column: String = storage.conn.lock().unwrap()
+        .query_row("SELECT column FROM samples WHERE time = 4 AND value = 41", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(column, "dns");
+
+    let imported = [DataElement { time: 0, index: 0, val: 0 }, DataElement { time: 2, index: 1, val: 0 }];
+    assert_eq!(storage.import(&imported).unwrap(), vec![imported[0]]);
+    assert_eq!(storage.first_time().unwrap(), Some(0));
+    assert_eq!(storage.prune(1).unwrap(), 1);
+    assert_eq!(storage.first_time().unwrap(), Some(1));
+    assert_eq!(storage.prune(3).unwrap(), 4);
+    let mut times = Vec::new();
+    storage.read_range(0, i64::MAX, &mut |elements| {
+        times.extend(elements.iter().map(|e| e.time));
+        Ok(())
+    }).unwrap();
+    assert_eq!(times, vec![3, 3, 4, 4, 5, 5]);
+    drop(storage);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
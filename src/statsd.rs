@@ -0,0 +1,241 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Emitting of every round of results as StatsD metrics over UDP, for
+ * environments built around StatsD (or the Datadog agent's DogStatsD).
+ *
+ * Each column of each address probed in a round is a metric of its own:
+ * timings (`|ms`, in milliseconds) for the probe's times and jitter, and
+ * gauges (`|g`) for its rates of transfer (in kbit/s) and other values (such
+ * as certificate expiry, in days), loss (as a percentage) and `up` (1 or 0),
+ * along with the signed `offset` of the local clock (in milliseconds) for the
+ * NTP Offset target, with each failed round counted (`|c`) by its class of failure. Metrics are named `<prefix>.<target>.<addr>.<column>` (the primary
+ * column being `value`), or with DogStatsD, `<prefix>.<target>.<column>`
+ * tagged with `addr`, the address's tags and any configured ones.
+ *
+ * StatsD being fire-and-forget, rounds that fail to send are dropped rather
+ * than buffered.
+ */
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+
+use crate::graphite::path_component;
+use crate::options::{StatsdConfiguration, TargetKind, TargetResults, sentinel_name, signed_offset, was_probed};
+use crate::probe::Unit;
+use crate::persist::TargetManager;
+use crate::sink::{ResultsSink, SinkError};
+use crate::tags::Tags;
+
+/**
+ * Largest datagram sent, to fit the MTU of most networks unfragmented.
+ */
+const MAX_PACKET: usize = 1432;
+
+/**
+ * Sink emitting all results to the configured StatsD server, resolved lazily
+ * (and again after failures).
+ */
+pub struct StatsdSink {
+    config: StatsdConfiguration,
+    managers: Vec<Arc<TargetManager>>,
+    conn: Option<(UdpSocket, SocketAddr)>,
+}
+
+impl StatsdSink {
+    pub fn new(config: StatsdConfiguration, managers: &[Arc<TargetManager>]) -> Self {
+        StatsdSink {
+            config,
+            managers: managers.to_vec(),
+            conn: None,
+        }
+    }
+}
+
+impl ResultsSink for StatsdSink {
+    fn name(&self) -> &'static str {
+        "StatsD"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        let lines = {
+            let manager = &self.managers[results.kind as usize];
+            let options = manager.options_read();
+            if results.nonce != options.nonce {
+                return Ok(());
+            }
+            let columns = manager.kind.columns(&options);
+            let num_probe_columns = manager.kind.probe_columns().len();
+            lines(&self.config, manager.kind, &options.addrs, &options.tags,
+                  &columns[..num_probe_columns + 2], columns.len(), results)
+        };
+
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        if self.conn.is_none() {
+            self.conn = Some(connect(&self.config.address)
+                .map_err(|e| SinkError::Dropped(format!("failed to resolve {}: {}", self.config.address, e)))?);
+        }
+        let (socket, addr) = self.conn.as_ref().unwrap();
+        for packet in packets(&lines) {
+            if let Err(e) = socket.send_to(packet.as_bytes(), addr) {
+                self.conn = None;
+                return Err(SinkError::Dropped(format!("{}", e)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Resolves the StatsD server at the given `host:port`, binding a socket to
+ * send to it from.
+ */
+fn connect(address: &str) -> io::Result<(UdpSocket, SocketAddr)> {
+    let addr = address.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))?;
+    let socket = UdpSocket::bind(if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+    Ok((socket, addr))
+}
+
+/**
+ * Makes the given string safe as (part of) a DogStatsD tag.
+ */
+fn tag_component(s: &str) -> String {
+    s.chars().map(|c| if c == ',' || c == '|' || c == '\n' { '_' } else { c }).collect()
+}
+
+/**
+ * Formats the given (probe and round) columns (out of the given total number
+ * of columns for each address) of the addresses probed in the given round of
+ * the given kind as StatsD lines, with DogStatsD tags if so configured.
+ */
+fn lines(config: &StatsdConfiguration, kind: &TargetKind, addrs: &[String], tags: &BTreeMap<String, Tags>,
+         columns: &[String], num_columns: usize, r: &TargetResults) -> Vec<String> {
+    let mut out = Vec::new();
+    for (addr, vals) in addrs.iter().zip(r.vals.chunks(num_columns)) {
+        if !was_probed(vals[0]) {
+            continue;
+        }
+        let (name, suffix) = if config.dogstatsd {
+            let mut all = vec![format!("addr:{}", tag_component(addr))];
+            for (k, v) in tags.get(addr).into_iter().flatten().chain(config.tags.iter()) {
+                all.push(format!("{}:{}", tag_component(k), tag_component(v)));
+            }
+            (format!("{}.{}", config.prefix, kind.compact_name()), format!("|#{}", all.join(",")))
+        } else {
+            (format!("{}.{}.{}", config.prefix, kind.compact_name(), path_component(addr)), String::new())
+        };
+
+        for (column, &val) in columns.iter().zip(vals).filter(|&(_, &v)| v >= 0) {
+            let stat = if column.is_empty() { "value" } else { column.as_str() };
+            let line = match kind.unit_of(column) {
+                Unit::Duration => format!("{}.{}:{}|ms", name, stat, val as f64 / 1000.0),
+                Unit::Rate | Unit::Gauge => format!("{}.{}:{}|g", name, stat, val),
+            };
+            out.push(line + &suffix);
+        }
+        if let Some(offset) = signed_offset(columns.iter().map(String::as_str).zip(vals.iter().cloned())) {
+            // StatsD (unlike DogStatsD) takes a signed gauge for a change to it, unless it is set to 0 first
+            if offset < 0 && !config.dogstatsd {
+                out.push(format!("{}.offset:0|g{}", name, suffix));
+            }
+            out.push(format!("{}.offset:{}|g{}", name, offset as f64 / 1000.0, suffix));
+        }
+        out.push(format!("{}.up:{}|g{}", name, (vals[0] >= 0) as u8, suffix));
+        if vals[0] < 0 {
+            let failure = sentinel_name(vals[0]);
+            out.push(if config.dogstatsd {
+                format!("{}.failures:1|c{},failure:{}", name, suffix, failure)
+            } else {
+                format!("{}.failures.{}:1|c", name, failure)
+            });
+        }
+    }
+    out
+}
+
+/**
+ * Joins the given lines into as few datagrams of at most `MAX_PACKET` bytes
+ * as they fit in (a longer line taking one of its own).
+ */
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines.iter() {
+        match packets.last_mut() {
+            Some(p) if p.len() + 1 + line.len() <= MAX_PACKET => {
+                p.push('\n');
+                p.push_str(line);
+            },
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+#[test]
+fn lines_carry_addrs_in_names_or_tags() {
+    let r = TargetResults {
+        kind: 0,
+        nonce: 0,
+        timestamp: 1_500_000_000,
+        vals: vec![1250, 0, 300, -2_100_000_005, 100, -1, crate::options::SENTINEL_NODATA, 0, 0],
+    };
+    let columns = vec!["".to_owned(), "loss".to_owned(), "jitter".to_owned()];
+    let addrs = vec!["google.com:80".to_owned(), "[::1]:80".to_owned(), "c:80".to_owned()];
+    let mut config: StatsdConfiguration = toml::from_str(r#"address = "localhost:8125""#).unwrap();
+    let mut tags = BTreeMap::new();
+    tags.insert("google.com:80".to_owned(), Tags::from([("site".to_owned(), "a,b".to_owned())]));
+
+    let tcpping = &crate::tcpping::KIND;
+    assert_eq!(lines(&config, tcpping, &addrs, &tags, &columns, 3, &r), vec![
+        "stabping.tcpping.google_com_80.value:1.25|ms",
+        "stabping.tcpping.google_com_80.loss:0|g",
+        "stabping.tcpping.google_com_80.jitter:0.3|ms",
+        "stabping.tcpping.google_com_80.up:1|g",
+        "stabping.tcpping.___1__80.loss:100|g",
+        "stabping.tcpping.___1__80.up:0|g",
+        "stabping.tcpping.___1__80.failures.timeout:1|c",
+    ]);
+
+    config.dogstatsd = true;
+    config.tags.insert("env".to_owned(), "lab".to_owned());
+    let dog = lines(&config, tcpping, &addrs, &tags, &columns, 3, &r);
+    assert_eq!(dog[0], "stabping.tcpping.value:1.25|ms|#addr:google.com:80,site:a_b,env:lab");
+    assert_eq!(dog[6], "stabping.tcpping.failures:1|c|#addr:[::1]:80,env:lab,failure:timeout");
+
+    // rates of transfer (and their jitter) are gauges, in kbit/s
+    let r = TargetResults { kind: 9, vals: vec![25_000, 40_000, 0, 0, 800], ..r };
+    let columns: Vec<String> = ["", "ttfb", "transfer", "loss", "jitter"].iter().map(|c| c.to_string()).collect();
+    assert_eq!(lines(&config, &crate::throughput::KIND, &addrs[..1], &tags, &columns, 5, &r)[..5], [
+        "stabping.throughput.value:25000|g|#addr:google.com:80,site:a_b,env:lab",
+        "stabping.throughput.ttfb:40|ms|#addr:google.com:80,site:a_b,env:lab",
+        "stabping.throughput.transfer:0|ms|#addr:google.com:80,site:a_b,env:lab",
+        "stabping.throughput.loss:0|g|#addr:google.com:80,site:a_b,env:lab",
+        "stabping.throughput.jitter:800|g|#addr:google.com:80,site:a_b,env:lab",
+    ]);
+
+    // the offset of the local clock is signed again
+    config.dogstatsd = false;
+    let r = TargetResults { kind: 8, vals: vec![30_000, 20_000, 0, 30_000, 0, 0], ..r };
+    let columns: Vec<String> = ["", "delay", "ahead", "behind", "loss", "jitter"].iter().map(|c| c.to_string())
+        .collect();
+    assert_eq!(lines(&config, &crate::ntp::KIND, &addrs[..1], &tags, &columns, 6, &r)[6..8], [
+        "stabping.ntp.google_com_80.offset:0|g",
+        "stabping.ntp.google_com_80.offset:-30|g",
+    ]);
+
+    let long: Vec<String> = (0..100).map(|i| format!("stabping.tcpping.addr{}.value:1|ms", i)).collect();
+    let joined = packets(&long);
+    assert!(joined.len() > 1 && joined.iter().all(|p| p.len() <= MAX_PACKET));
+    assert_eq!(joined.join("\n"), long.join("\n"));
+}
@@ -0,0 +1,362 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A read-only status page, to share how things are going (with family, or a
+ * small team) without giving access to anything else: whether each address
+ * is up, and a sparkline of its recent values, by group (see `GROUP_TAG`).
+ *
+ * Compiled from the persisted data of every target (so that it can also be
+ * written out as static HTML without stabping running), and served without
+ * authorization at /status (and as JSON at /status.json) if configured.
+ */
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Local, TimeZone};
+use serde::Serialize;
+
+use crate::options::{StatusPageConfiguration, SENTINEL_MAINTENANCE, SENTINEL_NODATA, SENTINEL_PAUSED};
+use crate::persist::TargetManager;
+use crate::tags::GROUP_TAG;
+
+/**
+ * Points of each sparkline, each the mean of the values of its share of the
+ * hours shown.
+ */
+const BUCKETS: usize = 48;
+
+/**
+ * Seconds a served status page is reused for, so that a popular page doesn't
+ * read through the data of every target on each visit.
+ */
+const CACHE_SECS: i64 = 30;
+
+/**
+ * Group of the addresses without a group tag.
+ */
+const UNGROUPED: &str = "Other";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Up,
+    Degraded,  // of groups, with some addresses down
+    Down,
+    Maintenance,
+    Paused,
+    Unknown,  // without any rounds in the hours shown
+}
+
+impl State {
+    fn label(self) -> &'static str {
+        match self {
+            State::Up => "Up",
+            State::Degraded => "Degraded",
+            State::Down => "Down",
+            State::Maintenance => "Maintenance",
+            State::Paused => "Paused",
+            State::Unknown => "No data",
+        }
+    }
+
+    fn class(self) -> &'static str {
+        match self {
+            State::Up => "up",
+            State::Degraded => "degraded",
+            State::Down => "down",
+            State::Maintenance => "maintenance",
+            State::Paused => "paused",
+            State::Unknown => "unknown",
+        }
+    }
+}
+
+/**
+ * The status of a single address, labelled with its display name (or the
+ * address itself, if it has none).
+ */
+#[derive(Serialize, Debug)]
+pub struct EntryStatus {
+    pub name: String,
+    pub target: &'static str,
+    pub state: State,
+    pub latest: Option<i32>,  // primary value of the latest successful round
+    pub uptime: Option<f64>,  // percentage of rounds with a value, in the hours shown
+    pub sparkline: Vec<Option<f64>>,  // mean value of each bucket (none if it had no successes)
+    pub failures: Vec<bool>,  // whether each bucket had failed rounds
+}
+
+#[derive(Serialize, Debug)]
+pub struct GroupStatus {
+    pub name: String,
+    pub state: State,
+    pub entries: Vec<EntryStatus>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatusPage {
+    pub title: String,
+    pub generated: i64,
+    pub hours: u32,
+    pub groups: Vec<GroupStatus>,
+}
+
+/**
+ * Summarizes the given (time, primary value) rounds of an address, from
+ * `from` to `to` and in order of time, with the state of its latest round.
+ */
+fn entry(name: String, target: &'static str, rounds: &[(i64, i32)], from: i64, to: i64) -> EntryStatus {
+    let mut sums = vec![(0.0, 0u32, false); BUCKETS];
+    let span = (to - from).max(1);
+    let probed: Vec<i32> = rounds.iter().map(|&(_, v)| v)
+        .filter(|&v| v != SENTINEL_PAUSED && v != SENTINEL_MAINTENANCE)
+        .collect();
+    for &(time, val) in rounds.iter().filter(|&&(_, v)| v != SENTINEL_PAUSED && v != SENTINEL_MAINTENANCE) {
+        let b = (((time - from).max(0) * BUCKETS as i64) / span).min(BUCKETS as i64 - 1) as usize;
+        if val >= 0 {
+            sums[b].0 += val as f64;
+            sums[b].1 += 1;
+        } else {
+            sums[b].2 = true;
+        }
+    }
+
+    let state = match rounds.last() {
+        None => State::Unknown,
+        Some(&(_, v)) if v >= 0 => State::Up,
+        Some(&(_, v)) if v == SENTINEL_MAINTENANCE => State::Maintenance,
+        Some(&(_, v)) if v == SENTINEL_PAUSED => State::Paused,
+        Some(_) => State::Down,
+    };
+    let up = probed.iter().filter(|&&v| v >= 0).count();
+    EntryStatus {
+        name,
+        target,
+        state,
+        latest: probed.iter().rev().find(|&&v| v >= 0).cloned(),
+        uptime: if probed.is_empty() { None } else { Some(up as f64 * 100.0 / probed.len() as f64) },
+        sparkline: sums.iter().map(|&(sum, n, _)| if n > 0 { Some(sum / n as f64) } else { None }).collect(),
+        failures: sums.iter().map(|&(_, _, failed)| failed).collect(),
+    }
+}
+
+/**
+ * The state of a group of addresses in the given states.
+ */
+fn group_state(states: &[State]) -> State {
+    let down = states.iter().filter(|&&s| s == State::Down).count();
+    if states.iter().all(|&s| s == State::Unknown) {
+        State::Unknown
+    } else if down == 0 {
+        State::Up
+    } else if down == states.len() {
+        State::Down
+    } else {
+        State::Degraded
+    }
+}
+
+/**
+ * Compiles the status page of the given targets at the given time.
+ */
+pub fn compile(managers: &[Arc<TargetManager>], config: &StatusPageConfiguration, now: i64) -> io::Result<StatusPage> {
+    let from = now - config.hours as i64 * 3600;
+    let mut groups: BTreeMap<String, Vec<EntryStatus>> = BTreeMap::new();
+    for tm in managers.iter() {
+        let (_, ordered_list, _) = tm.get_current_indices();
+        let (addrs, num_columns, labels) = {
+            let options = tm.options_read();
+            let labels: Vec<(String, String)> = options.addrs.iter().map(|addr| {
+                let group = options.tags_of(addr).and_then(|t| t.get(GROUP_TAG)).cloned();
+                (group.unwrap_or_else(|| UNGROUPED.to_owned()), options.names.get(addr).unwrap_or(addr).clone())
+            }).collect();
+            (options.addrs.len(), tm.kind.columns(&options).len(), labels)
+        };
+        if addrs == 0 {
+            continue;
+        }
+        let shown: Vec<bool> = labels.iter()
+            .map(|(group, _)| config.groups.is_empty() || config.groups.contains(group))
+            .collect();
+        if !shown.contains(&true) {
+            continue;
+        }
+
+        // the index of the primary column of each address, in order of addrs
+        let primary: Vec<i32> = ordered_list.iter().step_by(num_columns).cloned().collect();
+        let mut rounds: Vec<Vec<(i64, i32)>> = vec![Vec::new(); addrs];
+        tm.read_data_range(from, now, &mut |elements| {
+            for d in elements.iter().filter(|d| d.val != SENTINEL_NODATA) {
+                if let Some(a) = primary.iter().position(|&i| i == d.index) {
+                    rounds[a].push((d.time, d.val));
+                }
+            }
+            Ok(())
+        })?;
+
+        for (((group, name), r), _) in labels.into_iter().zip(rounds.iter()).zip(shown.iter()).filter(|(_, s)| **s) {
+            groups.entry(group).or_default().push(entry(name, tm.kind.compact_name(), r, from, now));
+        }
+    }
+
+    // groups in the order configured, if any, with the ungrouped last
+    let rank = |name: &str| (name == UNGROUPED, config.groups.iter().position(|g| g == name));
+    let mut groups: Vec<GroupStatus> = groups.into_iter().map(|(name, entries)| GroupStatus {
+        state: group_state(&entries.iter().map(|e| e.state).collect::<Vec<_>>()),
+        name,
+        entries,
+    }).collect();
+    groups.sort_by_key(|g| rank(&g.name));
+    Ok(StatusPage {
+        title: config.title.clone(),
+        generated: now,
+        hours: config.hours,
+        groups,
+    })
+}
+
+/**
+ * The status page of the given targets as served, compiled at most every
+ * `CACHE_SECS`.
+ */
+pub struct StatusPages {
+    managers: Vec<Arc<TargetManager>>,
+    config: StatusPageConfiguration,
+    cached: Mutex<Option<Arc<StatusPage>>>,
+}
+
+impl StatusPages {
+    pub fn new(managers: Vec<Arc<TargetManager>>, config: StatusPageConfiguration) -> Self {
+        StatusPages {
+            managers,
+            config,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /**
+     * Gets the status page as of the given time (or a little earlier).
+     */
+    pub fn get(&self, now: i64) -> io::Result<Arc<StatusPage>> {
+        let mut cached = self.cached.lock().unwrap();
+        match *cached {
+            Some(ref page) if now - page.generated < CACHE_SECS => Ok(page.clone()),
+            _ => {
+                let page = Arc::new(compile(&self.managers, &self.config, now)?);
+                *cached = Some(page.clone());
+                Ok(page)
+            },
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/**
+ * Renders the given sparkline as an inline SVG, a line through the values
+ * (broken where there are none) over red marks where rounds failed.
+ */
+fn sparkline(values: &[Option<f64>], failures: &[bool]) -> String {
+    let (width, height) = (4 * BUCKETS, 24);
+    let max = values.iter().flatten().cloned().fold(0.0, f64::max).max(1.0);
+    let mut svg = format!("<svg class=\"spark\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">",
+                          width, height, width, height);
+    for (i, _) in failures.iter().enumerate().filter(|(_, &f)| f) {
+        let _ = write!(svg, "<rect x=\"{}\" y=\"0\" width=\"4\" height=\"{}\" fill=\"#f2c4c4\"/>", i * 4, height);
+    }
+    let mut line = Vec::new();
+    for (i, v) in values.iter().chain(std::iter::once(&None)).enumerate() {
+        match *v {
+            Some(v) => line.push(format!("{},{:.1}", i * 4 + 2, height as f64 - 2.0 - v / max * (height as f64 - 4.0))),
+            None if !line.is_empty() => {
+                let _ = write!(svg, "<polyline points=\"{}\" fill=\"none\" stroke=\"#3b7dd8\" stroke-width=\"1.5\"/>",
+                               line.join(" "));
+                line.clear();
+            },
+            None => {},
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/**
+ * Renders the given status page as a self-contained HTML page (refreshing
+ * itself every minute).
+ */
+pub fn render_html(page: &StatusPage) -> String {
+    let time = Local.timestamp(page.generated, 0).format("%Y-%m-%d %H:%M").to_string();
+    let down = page.groups.iter().any(|g| g.state == State::Down || g.state == State::Degraded);
+    let mut html = String::new();
+    let _ = write!(html, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <meta http-equiv=\"refresh\" content=\"60\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>{}</title>\
+        <style>body{{font-family:sans-serif;max-width:48em;margin:2em auto;padding:0 1em;color:#222}}\
+        h2{{margin-top:1.5em}}table{{width:100%;border-collapse:collapse}}td{{padding:.4em;border-top:1px solid #ddd}}\
+        .state{{font-weight:bold}}.up{{color:#1a7f37}}.down{{color:#cf222e}}.degraded{{color:#bf8700}}\
+        .maintenance,.paused,.unknown{{color:#777}}.num{{text-align:right;white-space:nowrap}}\
+        .banner{{padding:1em;border-radius:4px;color:#fff;background:#1a7f37}}.banner.down{{background:#cf222e}}\
+        footer{{margin-top:2em;color:#777;font-size:small}}</style></head><body>\n<h1>{}</h1>\n",
+        escape(&page.title), escape(&page.title));
+    let _ = writeln!(html, "<p class=\"banner{}\">{}</p>", if down { " down" } else { "" },
+                     if down { "Some things are down." } else { "Everything is up." });
+
+    for g in page.groups.iter() {
+        let _ = writeln!(html, "<h2>{} <span class=\"state {}\">{}</span></h2>\n<table>", escape(&g.name),
+                         g.state.class(), g.state.label());
+        for e in g.entries.iter() {
+            let latest = e.latest.map_or_else(String::new, |v| format!("{:.1} ms", v as f64 / 1000.0));
+            let uptime = e.uptime.map_or_else(String::new, |u| format!("{:.2}%", u));
+            let _ = writeln!(html, "<tr><td>{}</td><td class=\"state {}\">{}</td><td>{}</td>\
+                                    <td class=\"num\">{}</td><td class=\"num\">{}</td></tr>",
+                             escape(&e.name), e.state.class(), e.state.label(),
+                             sparkline(&e.sparkline, &e.failures), latest, uptime);
+        }
+        html.push_str("</table>\n");
+    }
+    let _ = writeln!(html, "<footer>Uptime and latency over the last {} hours. Updated {}.</footer>\n</body></html>",
+                     page.hours, time);
+    html
+}
+
+#[test]
+fn entries_summarize_recent_rounds_by_bucket() {
+    let timeout = -2_100_000_005;
+    let rounds: Vec<(i64, i32)> = (0..96).map(|i| (i * 100, if i == 50 { timeout } else { 1000 })).collect();
+    let e = entry("Router".to_owned(), "icmpping", &rounds, 0, 9600);
+    assert_eq!((e.state, e.latest), (State::Up, Some(1000)));
+    assert!((e.uptime.unwrap() - 98.958).abs() < 0.001);
+    assert_eq!(e.sparkline.len(), BUCKETS);
+    assert_eq!((e.sparkline[25], e.failures[25], e.failures[24]), (Some(1000.0), true, false));
+
+    let mut rounds = rounds;
+    rounds.push((9550, timeout));
+    rounds.push((9560, SENTINEL_MAINTENANCE));
+    let e = entry("Router".to_owned(), "icmpping", &rounds, 0, 9600);
+    assert_eq!(e.state, State::Maintenance);
+    assert_eq!(entry("Router".to_owned(), "icmpping", &rounds[..97], 0, 9600).state, State::Down);
+    assert_eq!(entry("Router".to_owned(), "icmpping", &[], 0, 9600).state, State::Unknown);
+
+    assert_eq!(group_state(&[State::Up, State::Down]), State::Degraded);
+    assert_eq!(group_state(&[State::Down, State::Unknown]), State::Degraded);
+    assert_eq!(group_state(&[State::Up, State::Maintenance]), State::Up);
+
+    let page = StatusPage {
+        title: "Home <network>".to_owned(),
+        generated: 9600,
+        hours: 24,
+        groups: vec![GroupStatus { name: "Internet".to_owned(), state: State::Maintenance, entries: vec![e] }],
+    };
+    let html = render_html(&page);
+    assert!(html.contains("<h1>Home &lt;network&gt;</h1>") && html.contains("<polyline"));
+}
@@ -0,0 +1,799 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Backends storing the data elements of a target behind the `Storage` trait:
+ * its data file by default, or (with the `sqlite` feature) an SQLite database.
+ */
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::collections::HashSet;
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom, BufWriter};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use crate::compress::Archive;
+use crate::helpers::{SPIOError, SPFile, PushRawBytes, discard_incomplete};
+use crate::options::{TargetKind, StorageBackend};
+use crate::persist::{DataElement, ManagerError};
+use crate::reader::{map_data_file, elements_in_range};
+#[cfg(feature = "sqlite")]
+use crate::sqlite::SqliteStorage;
+
+/**
+ * Where the data elements of a target are stored.
+ */
+pub trait Storage: Send + Sync {
+    /**
+     * Appends the given elements (of a single round, so all of the same time
+     * and no earlier than any stored before).
+     */
+    fn append(&self, elements: &[DataElement]) -> Result<(), ManagerError>;
+
+    /**
+     * Calls `f` with the elements stored with times from `lower` to `upper`
+     * (inclusive), ordered by time, possibly in several chunks.
+     */
+    fn read_range(&self, lower: i64, upper: i64,
+                  f: &mut dyn FnMut(&[DataElement]) -> io::Result<()>) -> io::Result<()>;
+
+    /**
+     * Returns the time of the earliest element stored, if any.
+     */
+    fn first_time(&self) -> io::Result<Option<i64>> {
+        let mut first = None;
+        self.read_range(i64::MIN, i64::MAX, &mut |elements| {
+            first = first.or(elements.first().map(|e| e.time));
+            Ok(())
+        })?;
+        Ok(first)
+    }
+
+    /**
+     * Removes the elements stored with times before `before`, returning how
+     * many were removed.
+     */
+    fn prune(&self, before: i64) -> Result<u64, ManagerError>;
+
+    /**
+     * Merges the given elements (ordered by time, but of any times, e.g. from
+     * before those stored) into those stored, skipping any of an index and
+     * time already stored. Returns the elements that were merged in.
+     */
+    fn import(&self, elements: &[DataElement]) -> Result<Vec<DataElement>, ManagerError>;
+
+    /**
+     * Compresses the elements stored with times before `before`, for backends
+     * that can, returning how many were.
+     */
+    fn compress(&self, _before: i64) -> Result<u64, ManagerError> {
+        Ok(0)
+    }
+
+    /**
+     * Records the keys (see `column_key`) of the given indices, for backends
+     * that can make use of them (e.g. to be queried by name).
+     */
+    fn describe_keys(&self, _keys: &[String]) -> Result<(), ManagerError> {
+        Ok(())
+    }
+
+    /**
+     * Flushes all elements appended so far to disk.
+     */
+    fn sync(&self) -> Result<(), ManagerError>;
+}
+
+/**
+ * Opens the storage of the given target kind in the given data directory,
+ * with the given backend.
+ */
+pub fn open_storage(kind: &TargetKind, data_path: &Path,
+                    backend: StorageBackend) -> Result<Box<dyn Storage>, ManagerError> {
+    match backend {
+        StorageBackend::Files => {
+            // migrate any data file from before 64-bit timestamps
+            let path = data_path.join(format!("{}.data64.dat", kind.compact_name()));
+            let legacy_path = data_path.join(format!("{}.data.dat", kind.compact_name()));
+            if !path.exists() && legacy_path.exists() {
+                migrate_legacy_data(&legacy_path, &path)?;
+            }
+            Ok(Box::new(FileStorage::open(&path)?))
+        },
+        StorageBackend::Sqlite =>
+            open_sqlite(&data_path.join(format!("{}.sqlite", kind.compact_name()))),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &Path) -> Result<Box<dyn Storage>, ManagerError> {
+    Ok(Box::new(SqliteStorage::open(path)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(path: &Path) -> Result<Box<dyn Storage>, ManagerError> {
+    error!("SQLite storage was configured, but stabping was built without the sqlite feature.");
+    Err(ManagerError::DataFileIO(SPIOError::Open(Some(path.to_owned()))))
+}
+
+/**
+ * Converts the contents of a data file written by earlier versions of
+ * stabping (elements of three 32-bit integers, including the time) into the
+ * current `DataElement` representation. Returns `None` if the given contents
+ * are not a whole number of such elements.
+ */
+fn convert_legacy_data(raw: &[u8]) -> Option<Vec<u8>> {
+    if !raw.len().is_multiple_of(12) {
+        return None;
+    }
+    let int_at = |i: usize| i32::from_ne_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+
+    let mut out = Vec::with_capacity(raw.len() / 12 * mem::size_of::<DataElement>());
+    for i in (0..raw.len()).step_by(12) {
+        DataElement {
+            time: int_at(i) as i64,
+            index: int_at(i + 4),
+            val: int_at(i + 8),
+        }.push_to(&mut out);
+    }
+    Some(out)
+}
+
+/**
+ * Migrates the legacy (32-bit time) data file at the given path into a
+ * current data file at the given new path. The legacy file itself is left
+ * untouched.
+ */
+fn migrate_legacy_data(legacy_path: &Path, path: &Path) -> Result<(), ManagerError> {
+    info!("Migrating data file '{}' to 64-bit timestamps.", legacy_path.display());
+    let raw = fs::read(legacy_path)
+        .map_err(|_| ManagerError::DataFileIO(SPIOError::Read(Some(legacy_path.to_owned()))))?;
+    let converted = convert_legacy_data(&raw)
+        .ok_or_else(|| ManagerError::DataFileIO(SPIOError::Parse(Some(legacy_path.to_owned()))))?;
+
+    // write out to a temporary file first so a failed migration is retried
+    let tmp_path = path.with_extension("dat.tmp");
+    fs::write(&tmp_path, converted)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|_| ManagerError::DataFileIO(SPIOError::Write(Some(path.to_owned()))))?;
+
+    info!("Migrated data to '{}', '{}' may now be removed.",
+          path.display(), legacy_path.display());
+    Ok(())
+}
+
+/**
+ * Merges the given imported elements (ordered by time) into the given stored
+ * ones, passing each of the merged elements to `out` in order of time (those
+ * stored first, among those of the same time). Imported elements of an index
+ * and time already stored (or imported) are skipped; the others are returned.
+ */
+pub fn merge_elements(stored: &[DataElement], imported: &[DataElement],
+                  out: &mut dyn FnMut(&DataElement) -> io::Result<()>) -> io::Result<Vec<DataElement>> {
+    let mut merged = Vec::new();
+    let mut s = 0;
+    let mut i = 0;
+    while i < imported.len() {
+        let time = imported[i].time;
+        while s < stored.len() && stored[s].time < time {
+            out(&stored[s])?;
+            s += 1;
+        }
+        // the indices of this time already stored
+        let mut seen = HashSet::new();
+        while s < stored.len() && stored[s].time == time {
+            seen.insert(stored[s].index);
+            out(&stored[s])?;
+            s += 1;
+        }
+        while i < imported.len() && imported[i].time == time {
+            if seen.insert(imported[i].index) {
+                out(&imported[i])?;
+                merged.push(imported[i]);
+            }
+            i += 1;
+        }
+    }
+    for e in stored[s..].iter() {
+        out(e)?;
+    }
+    Ok(merged)
+}
+
+/**
+ * Reads the time of the element of the given data file ending at the given
+ * length, if there is one.
+ */
+fn time_before(file: &File, len: u64) -> io::Result<Option<i64>> {
+    let size = mem::size_of::<DataElement>() as u64;
+    if len < size {
+        return Ok(None);
+    }
+    let mut time = [0; 8];
+    let mut f = file;
+    f.seek(SeekFrom::Start(len - size))?;
+    f.read_exact(&mut time)?;
+    Ok(Some(i64::from_ne_bytes(time)))
+}
+
+/**
+ * The commit file of a data file (`<kind>.data64.commit`), holding the length
+ * of the data file up to the end of the last round appended completely and
+ * the time of that round, rewritten after each round is appended.
+ */
+struct Commit {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl Commit {
+    /**
+     * Opens (creating it if necessary) the commit file at the given path,
+     * returning it along with the length and time it holds, if any.
+     */
+    fn open(path: &Path) -> Result<(Self, Option<(u64, i64)>), ManagerError> {
+        let mut file = File::open_from(OpenOptions::new().read(true).write(true).create(true), path)
+            .map_err(ManagerError::DataFileIO)?;
+        let committed = Self::read(&mut file);
+        Ok((Commit { path: path.to_owned(), file, len: 0 }, committed))
+    }
+
+    fn read(file: &mut File) -> Option<(u64, i64)> {
+        let mut record = [0; 16];
+        file.read_exact(&mut record).ok()?;
+        Some((u64::from_ne_bytes(record[..8].try_into().unwrap()),
+              i64::from_ne_bytes(record[8..].try_into().unwrap())))
+    }
+
+    /**
+     * Records that the data file is complete up to the given length, ending
+     * with a round of the given time.
+     */
+    fn write(&mut self, len: u64, time: Option<i64>) -> Result<(), ManagerError> {
+        let mut record = Vec::with_capacity(16);
+        record.push_i64(len as i64);
+        record.push_i64(time.unwrap_or(0));
+        self.file.seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.write_all(&record))
+            .map_err(|_| ManagerError::DataFileIO(SPIOError::Write(Some(self.path.clone()))))?;
+        self.len = len;
+        Ok(())
+    }
+}
+
+/**
+ * Reads the length and time held by the commit file of the data file at the
+ * given path (without creating it), if it has one.
+ */
+pub fn read_commit(path: &Path) -> Option<(u64, i64)> {
+    Commit::read(&mut File::open(path.with_extension("commit")).ok()?)
+}
+
+/**
+ * Gets the length of the given data file that the given length and time of
+ * its commit file commits, if they match it (aren't of some other data file,
+ * e.g. one since replaced by compressing it).
+ */
+pub fn committed_len(file: &File, committed: Option<(u64, i64)>) -> io::Result<Option<u64>> {
+    let size = mem::size_of::<DataElement>() as u64;
+    let whole = file.metadata()?.len() / size * size;
+    Ok(match committed {
+        Some((c, time)) if c > 0 && c <= whole && time_before(file, c).ok() == Some(Some(time)) => Some(c),
+        _ => None,
+    })
+}
+
+/**
+ * The length (in bytes) of the blocks of a data file checksummed.
+ */
+pub const BLOCK_LEN: usize = 4096 * mem::size_of::<DataElement>();
+
+/**
+ * Gets the checksum of a block of a data file.
+ */
+pub fn block_checksum(block: &[u8]) -> u32 {
+    crc32fast::hash(block)
+}
+
+/**
+ * The checksums file of a data file (`<kind>.data64.sums`): the CRC-32 of each
+ * complete block of `BLOCK_LEN` bytes of the data file, in order, so that its
+ * corruption (e.g. by bit rot) can be detected (see `fsck.rs`).
+ */
+struct Checksums {
+    path: PathBuf,
+    file: File,
+    block: crc32fast::Hasher,  // of the block being appended to
+    block_len: usize,  // and how much of it has been
+}
+
+impl Checksums {
+    /**
+     * Opens (creating it if necessary) the checksums file at the given path,
+     * of the given data file of the given length, checksumming any blocks of
+     * the data file it doesn't have yet (and forgetting any it no longer has).
+     */
+    fn open(path: &Path, data: &File, data_len: u64) -> Result<Self, ManagerError> {
+        let mut file = File::open_from(OpenOptions::new().read(true).append(true).create(true), path)
+            .map_err(ManagerError::DataFileIO)?;
+        let len = file.length_p(path).map_err(ManagerError::DataFileIO)?;
+        let mut sums = Checksums {
+            path: path.to_owned(),
+            file,
+            block: crc32fast::Hasher::new(),
+            block_len: 0,
+        };
+        let blocks = (len / 4).min(data_len / BLOCK_LEN as u64);
+        sums.checksum_from(data, blocks)?;
+        Ok(sums)
+    }
+
+    fn write_error(&self) -> ManagerError {
+        ManagerError::DataFileIO(SPIOError::Write(Some(self.path.clone())))
+    }
+
+    /**
+     * Checksums the given data file again from the given block on.
+     */
+    fn checksum_from(&mut self, data: &File, block: u64) -> Result<(), ManagerError> {
+        self.file.set_len(block * 4).map_err(|_| self.write_error())?;
+        self.block = crc32fast::Hasher::new();
+        self.block_len = 0;
+
+        let mut data = data;
+        data.seek(SeekFrom::Start(block * BLOCK_LEN as u64)).map_err(|_| self.write_error())?;
+        let mut buf = vec![0; BLOCK_LEN];
+        loop {
+            let n = data.read(&mut buf).map_err(|_| self.write_error())?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.append(&buf[..n])?;
+        }
+    }
+
+    /**
+     * Checksums the given bytes appended to the data file.
+     */
+    fn append(&mut self, mut bytes: &[u8]) -> Result<(), ManagerError> {
+        while !bytes.is_empty() {
+            let n = (BLOCK_LEN - self.block_len).min(bytes.len());
+            self.block.update(&bytes[..n]);
+            self.block_len += n;
+            bytes = &bytes[n..];
+            if self.block_len == BLOCK_LEN {
+                let sum = mem::take(&mut self.block).finalize();
+                self.file.write_all(&sum.to_ne_bytes()).map_err(|_| self.write_error())?;
+                self.block_len = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Storage in a target's data file (see `DataElement`).
+ *
+ * So that a round left half-appended (e.g. by being killed while writing it)
+ * doesn't corrupt the end of the data file, the length of the data file up to
+ * the last complete round is kept in its commit file. On opening the data
+ * file, anything after that (or any incomplete element, without a commit
+ * file) is discarded.
+ *
+ * Elements compressed (see `compress.rs`) are moved out of the data file into
+ * its archive file, all of whose elements are from before those left in the
+ * data file. Reading the data file (or replacing it) is done while holding
+ * its lock, so that elements being moved aren't seen twice or not at all.
+ */
+pub struct FileStorage {
+    path: PathBuf,
+    file: RwLock<File>,
+    commit: Mutex<Commit>,
+    sums: Mutex<Checksums>,
+    archive: RwLock<Archive>,
+}
+
+impl FileStorage {
+    /**
+     * Opens (creating it if necessary) the data file at the given path,
+     * recovering it from any round left incomplete.
+     */
+    pub fn open(path: &Path) -> Result<Self, ManagerError> {
+        let file = Self::open_file(path)?;
+        let (mut commit, committed) = Commit::open(&path.with_extension("commit"))?;
+
+        let read_error = || ManagerError::DataFileIO(SPIOError::Read(Some(path.to_owned())));
+        let len = file.metadata().map_err(|_| read_error())?.len();
+        let size = mem::size_of::<DataElement>() as u64;
+        // the commit file is only trusted if it matches (isn't of some other data file)
+        let complete = committed_len(&file, committed).map_err(|_| read_error())?
+            .unwrap_or(len / size * size);
+        discard_incomplete(&file, path, len, complete).map_err(ManagerError::DataFileIO)?;
+        commit.write(complete, time_before(&file, complete).map_err(|_| read_error())?)?;
+        let sums = Checksums::open(&path.with_extension("sums"), &file, complete)?;
+        let archive = Archive::open(&path.with_extension("archive"))?;
+
+        let storage = FileStorage {
+            path: path.to_owned(),
+            file: RwLock::new(file),
+            commit: Mutex::new(commit),
+            sums: Mutex::new(sums),
+            archive: RwLock::new(archive),
+        };
+        storage.discard_archived()?;
+        Ok(storage)
+    }
+
+    /**
+     * Discards the elements of the data file left there after being archived
+     * (by being killed in the middle of compressing them).
+     */
+    fn discard_archived(&self) -> Result<(), ManagerError> {
+        let last = match self.archive.read().unwrap().last_time() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let mut guard = self.file.write().unwrap();
+        if guard.metadata().map_err(|_| self.write_error())?.len() == 0 {
+            return Ok(());
+        }
+        let kept = {
+            let map = map_data_file(&guard).map_err(|_| self.write_error())?;
+            let all: &[DataElement] = elements_in_range(&map, i64::MIN, i64::MAX)
+                .map_err(|_| self.write_error())?;
+            let archived = all.partition_point(|e| e.time <= last);
+            if archived == 0 {
+                return Ok(());
+            }
+            warn!("Discarding {} elements of '{}', which were already archived.",
+                  archived, self.path.display());
+            Self::encode_elements(&all[archived..])
+        };
+        self.write_replacing(&mut guard, &kept)
+    }
+
+    fn encode_elements(elements: &[DataElement]) -> Vec<u8> {
+        let mut out_data: Vec<u8> = Vec::with_capacity(mem::size_of_val(elements));
+        for e in elements.iter() {
+            e.push_to(&mut out_data);
+        }
+        out_data
+    }
+
+    /**
+     * Writes the given contents out to a temporary file, and then replaces
+     * the data file with it, so a failure part way leaves the data file be.
+     */
+    fn write_replacing(&self, file: &mut File, contents: &[u8]) -> Result<(), ManagerError> {
+        let tmp_path = self.path.with_extension("dat.tmp");
+        File::create(&tmp_path)
+            .and_then(|mut f| f.write_all(contents).and_then(|_| f.sync_all()))
+            .map_err(|_| self.write_error())?;
+        self.replace(file, &tmp_path)
+    }
+
+    fn open_file(path: &Path) -> Result<File, ManagerError> {
+        File::open_from(OpenOptions::new().read(true).append(true).create(true), path)
+            .map_err(ManagerError::DataFileIO)
+    }
+
+    fn write_error(&self) -> ManagerError {
+        ManagerError::DataFileIO(SPIOError::Write(Some(self.path.clone())))
+    }
+
+    /**
+     * Replaces the data file with the one written to the given temporary
+     * path, and commits (and checksums) all of it.
+     */
+    fn replace(&self, file: &mut File, tmp_path: &Path) -> Result<(), ManagerError> {
+        fs::rename(tmp_path, &self.path).map_err(|_| self.write_error())?;
+        *file = Self::open_file(&self.path)?;
+        let len = file.metadata().map_err(|_| self.write_error())?.len();
+        let time = time_before(file, len).map_err(|_| self.write_error())?;
+        self.commit.lock().unwrap().write(len, time)?;
+        self.sums.lock().unwrap().checksum_from(file, 0)
+    }
+}
+
+impl Storage for FileStorage {
+    fn append(&self, elements: &[DataElement]) -> Result<(), ManagerError> {
+        if elements.is_empty() {
+            return Ok(());
+        }
+        let mut out_data: Vec<u8> = Vec::with_capacity(mem::size_of_val(elements));
+        for e in elements.iter() {
+            e.push_to(&mut out_data);
+        }
+        let mut file = self.file.write().unwrap();
+        file.write_all(&out_data).map_err(|_| self.write_error())?;
+        // only once all of the round is written is it committed
+        let mut commit = self.commit.lock().unwrap();
+        let len = commit.len + out_data.len() as u64;
+        commit.write(len, elements.last().map(|e| e.time))?;
+        self.sums.lock().unwrap().append(&out_data)
+    }
+
+    fn read_range(&self, lower: i64, upper: i64,
+                  f: &mut dyn FnMut(&[DataElement]) -> io::Result<()>) -> io::Result<()> {
+        // hold the lock so the file isn't appended to while it is mapped
+        let guard = self.file.read().unwrap();
+        // those archived are all from before those in the data file
+        self.archive.read().unwrap().read_range(lower, upper, f)?;
+        // an empty file can't be mapped
+        if guard.metadata()?.len() == 0 {
+            return f(&[]);
+        }
+        let map = map_data_file(&guard)?;
+        f(elements_in_range(&map, lower, upper)?)
+    }
+
+    fn first_time(&self) -> io::Result<Option<i64>> {
+        let guard = self.file.read().unwrap();
+        if let Some(first) = self.archive.read().unwrap().first_time() {
+            return Ok(Some(first));
+        }
+        // the first element is the one before the end of the first (if there is one)
+        let size = mem::size_of::<DataElement>() as u64;
+        time_before(&guard, if guard.metadata()?.len() < size { 0 } else { size })
+    }
+
+    fn prune(&self, before: i64) -> Result<u64, ManagerError> {
+        let mut guard = self.file.write().unwrap();
+        let archived = self.archive.write().unwrap().prune(before)?;
+        let total = guard.metadata().map_err(|_| self.write_error())?.len()
+            / mem::size_of::<DataElement>() as u64;
+        if total == 0 {
+            return Ok(archived);
+        }
+
+        // copy the elements that are kept out to a temporary file
+        let kept = {
+            let map = map_data_file(&guard).map_err(|_| self.write_error())?;
+            let kept: &[DataElement] = elements_in_range(&map, before, i64::MAX)
+                .map_err(|_| self.write_error())?;
+            if kept.len() as u64 == total {
+                return Ok(archived);
+            }
+            Self::encode_elements(kept)
+        };
+        // then replace the data file with it, so a failed prune leaves it be
+        self.write_replacing(&mut guard, &kept)?;
+
+        Ok(archived + total - (kept.len() / mem::size_of::<DataElement>()) as u64)
+    }
+
+    fn import(&self, elements: &[DataElement]) -> Result<Vec<DataElement>, ManagerError> {
+        let mut guard = self.file.write().unwrap();
+
+        // those from before the last archived are merged into the archive instead
+        let mut archive = self.archive.write().unwrap();
+        let split = archive.last_time().map_or(0, |last| elements.partition_point(|e| e.time <= last));
+        let mut merged = if split > 0 { archive.import(&elements[..split])? } else { Vec::new() };
+        let elements = &elements[split..];
+        if elements.is_empty() {
+            return Ok(merged);
+        }
+
+        let len = guard.metadata().map_err(|_| self.write_error())?.len();
+
+        // merge the elements stored with those imported into a temporary file
+        let tmp_path = self.path.with_extension("dat.tmp");
+        let merged_stored = {
+            let map = if len > 0 {
+                Some(map_data_file(&guard).map_err(|_| self.write_error())?)
+            } else {
+                None
+            };
+            let stored: &[DataElement] = match map {
+                Some(ref map) => elements_in_range(map, i64::MIN, i64::MAX)
+                    .map_err(|_| self.write_error())?,
+                None => &[],
+            };
+            let mut writer = BufWriter::new(File::create(&tmp_path).map_err(|_| self.write_error())?);
+            let mut buf = Vec::with_capacity(mem::size_of::<DataElement>());
+            let merged = merge_elements(stored, elements, &mut |e| {
+                buf.clear();
+                e.push_to(&mut buf);
+                writer.write_all(&buf)
+            }).map_err(|_| self.write_error())?;
+            writer.into_inner().map_err(|_| self.write_error())?
+                .sync_all().map_err(|_| self.write_error())?;
+            merged
+        };
+        // then replace the data file with it, like when pruning
+        self.replace(&mut guard, &tmp_path)?;
+
+        merged.extend(merged_stored);
+        Ok(merged)
+    }
+
+    fn compress(&self, before: i64) -> Result<u64, ManagerError> {
+        let mut guard = self.file.write().unwrap();
+        if guard.metadata().map_err(|_| self.write_error())?.len() == 0 {
+            return Ok(0);
+        }
+
+        /*
+         * archive the (whole rounds of) elements before the given time, and
+         * only then remove them from the data file; if killed in between,
+         * they are discarded from it on opening it again
+         */
+        let (archived, kept) = {
+            let map = map_data_file(&guard).map_err(|_| self.write_error())?;
+            let all: &[DataElement] = elements_in_range(&map, i64::MIN, i64::MAX)
+                .map_err(|_| self.write_error())?;
+            let split = all.partition_point(|e| e.time < before);
+            if split == 0 {
+                return Ok(0);
+            }
+            self.archive.write().unwrap().append(&all[..split])?;
+            (split as u64, Self::encode_elements(&all[split..]))
+        };
+        self.write_replacing(&mut guard, &kept)?;
+        Ok(archived)
+    }
+
+    fn sync(&self) -> Result<(), ManagerError> {
+        self.file.write().unwrap().sync_all().map_err(|_| self.write_error())?;
+        self.commit.lock().unwrap().file.sync_all().map_err(|_| self.write_error())?;
+        self.sums.lock().unwrap().file.sync_all().map_err(|_| self.write_error())?;
+        self.archive.read().unwrap().sync()
+    }
+}
+
+#[test]
+fn convert_legacy_data_widens_times() {
+    let mut legacy = Vec::new();
+    for n in [1_500_000_000, 2, -2_100_000_000] {
+        legacy.extend_from_slice(&i32::to_ne_bytes(n));
+    }
+
+    let converted = convert_legacy_data(&legacy).unwrap();
+    let mut expected = Vec::new();
+    DataElement { time: 1_500_000_000, index: 2, val: -2_100_000_000 }.push_to(&mut expected);
+    assert_eq!(converted, expected);
+
+    assert!(convert_legacy_data(&legacy[..8]).is_none());
+}
+
+#[test]
+fn merge_elements_skips_those_already_stored() {
+    let e = |time, index| DataElement { time, index, val: index };
+    let stored = [e(2, 0), e(2, 1), e(4, 0)];
+    let imported = [e(1, 0), e(2, 1), e(2, 2), e(3, 0), e(3, 0), e(5, 1)];
+
+    let mut out = Vec::new();
+    let merged = merge_elements(&stored, &imported, &mut |e| {
+        out.push((e.time, e.index));
+        Ok(())
+    }).unwrap();
+    assert_eq!(out, vec![(1, 0), (2, 0), (2, 1), (2, 2), (3, 0), (4, 0), (5, 1)]);
+    assert_eq!(merged, vec![e(1, 0), e(2, 2), e(3, 0), e(5, 1)]);
+}
+
+#[test]
+fn file_storage_prunes_elements_before_time() {
+    let path = std::env::temp_dir().join(format!("stabping-test-{}.data64.dat", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let storage = FileStorage::open(&path).unwrap();
+    assert_eq!(storage.prune(10).unwrap(), 0);
+
+    for time in 1..=5 {
+        let round: Vec<DataElement> = (0..2)
+            .map(|index| DataElement { time, index, val: index })
+            .collect();
+        storage.append(&round).unwrap();
+    }
+    assert_eq!(storage.prune(3).unwrap(), 4);
+    assert_eq!(storage.prune(3).unwrap(), 0);
+    storage.append(&[DataElement { time: 6, index: 0, val: 0 }]).unwrap();
+
+    let mut times = Vec::new();
+    storage.read_range(0, i64::MAX, &mut |elements| {
+        times.extend(elements.iter().map(|e| e.time));
+        Ok(())
+    }).unwrap();
+    assert_eq!(times, vec![3, 3, 4, 4, 5, 5, 6]);
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(path.with_extension("commit"));
+    let _ = fs::remove_file(path.with_extension("sums"));
+    let _ = fs::remove_file(path.with_extension("archive"));
+}
+
+#[test]
+fn file_storage_discards_incomplete_rounds() {
+    let path = std::env::temp_dir().join(format!("stabping-test-{}-torn.data64.dat", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let read_times = |storage: &FileStorage| {
+        let mut times = Vec::new();
+        storage.read_range(0, i64::MAX, &mut |elements| {
+            times.extend(elements.iter().map(|e| e.time));
+            Ok(())
+        }).unwrap();
+        times
+    };
+
+    let storage = FileStorage::open(&path).unwrap();
+    for time in 1..=2 {
+        storage.append(&[DataElement { time, index: 0, val: 0 }, DataElement { time, index: 1, val: 0 }])
+            .unwrap();
+    }
+    drop(storage);
+    // as if killed after writing an element and a half of the third round
+    let mut torn = Vec::new();
+    DataElement { time: 3, index: 0, val: 0 }.push_to(&mut torn);
+    torn.extend_from_slice(&[0; 5]);
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&torn).unwrap();
+
+    let storage = FileStorage::open(&path).unwrap();
+    assert_eq!(read_times(&storage), vec![1, 1, 2, 2]);
+    storage.append(&[DataElement { time: 3, index: 0, val: 0 }]).unwrap();
+    assert_eq!(read_times(&storage), vec![1, 1, 2, 2, 3]);
+    drop(storage);
+
+    // a commit file not of this data file (e.g. after it was replaced) is ignored
+    let mut other = Vec::new();
+    for time in 5..=7 {
+        DataElement { time, index: 0, val: 0 }.push_to(&mut other);
+    }
+    fs::write(&path, &other).unwrap();
+    assert_eq!(read_times(&FileStorage::open(&path).unwrap()), vec![5, 6, 7]);
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(path.with_extension("commit"));
+    let _ = fs::remove_file(path.with_extension("sums"));
+    let _ = fs::remove_file(path.with_extension("archive"));
+}
+
+#[test]
+fn file_storage_reads_prunes_and_imports_across_compressed_elements() {
+    let path = std::env::temp_dir().join(format!("stabping-test-{}-z.data64.dat", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(path.with_extension("archive"));
+    let read_all = |storage: &FileStorage| {
+        let mut all = Vec::new();
+        storage.read_range(i64::MIN, i64::MAX, &mut |elements| {
+            all.extend(elements.iter().map(|e| (e.time, e.index)));
+            Ok(())
+        }).unwrap();
+        all
+    };
+
+    let storage = FileStorage::open(&path).unwrap();
+    assert_eq!(storage.first_time().unwrap(), None);
+    for time in 1..=6 {
+        storage.append(&[DataElement { time, index: 0, val: 100 + time as i32 }]).unwrap();
+    }
+    assert_eq!(storage.compress(4).unwrap(), 3);
+    assert_eq!(storage.compress(4).unwrap(), 0);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 3 * mem::size_of::<DataElement>() as u64);
+    assert_eq!(read_all(&storage), (1..=6).map(|t| (t, 0)).collect::<Vec<_>>());
+    assert_eq!(storage.first_time().unwrap(), Some(1));
+
+    // older elements are merged into the archive, the rest into the data file
+    let imported = storage.import(&[DataElement { time: 2, index: 1, val: 0 },
+                                    DataElement { time: 5, index: 1, val: 0 }]).unwrap();
+    assert_eq!(imported.len(), 2);
+    assert_eq!(read_all(&storage), vec![(1, 0), (2, 0), (2, 1), (3, 0), (4, 0), (5, 0), (5, 1), (6, 0)]);
+    assert_eq!(storage.prune(3).unwrap(), 3);
+    assert_eq!(read_all(&storage), vec![(3, 0), (4, 0), (5, 0), (5, 1), (6, 0)]);
+
+    // as if killed after archiving elements but before removing them from the data file
+    let data = fs::read(&path).unwrap();
+    storage.compress(5).unwrap();
+    drop(storage);
+    fs::write(&path, data).unwrap();
+    let storage = FileStorage::open(&path).unwrap();
+    assert_eq!(read_all(&storage), vec![(3, 0), (4, 0), (5, 0), (5, 1), (6, 0)]);
+    drop(storage);
+    for ext in ["dat", "commit", "sums", "archive"] {
+        let _ = fs::remove_file(path.with_extension(ext));
+    }
+}
@@ -0,0 +1,195 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Half-open TCP Ping (addresses in `syn` mode), timing from a SYN sent over a
+ * raw socket to the SYN-ACK answering it, without ever completing the
+ * handshake, so that neither end keeps a connection around (the kernel, not
+ * knowing of the connection, resets it as the SYN-ACK arrives).
+ *
+ * The port SYNs are sent from is reserved with a bound (but unconnected) TCP
+ * socket for as long as the attempt lasts, so that the kernel's own
+ * connections can't collide with it. Without raw sockets (e.g. missing
+ * `CAP_NET_RAW`), or through a proxy, full connections are made instead.
+ */
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Once;
+use std::time::Instant;
+
+use socket2::{Domain, Protocol, Socket, SockAddr, Type};
+use time::precise_time_ns;
+
+use crate::icmp::checksum;
+use crate::options::{SENTINEL_REFUSED, SENTINEL_TIMEOUT, io_error_sentinel};
+use crate::sockopt;
+use crate::worker::ProbeSettings;
+
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+/**
+ * Builds a SYN (with the MSS option, as real ones carry) from the given port
+ * and address to the given address, with the given sequence number.
+ */
+pub fn syn_segment(src: &SocketAddr, dst: &SocketAddr, seq: u32) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(24);
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&[0; 4]);  // acknowledgment number
+    segment.extend_from_slice(&[6 << 4, SYN]);  // data offset (in words) and flags
+    segment.extend_from_slice(&64240u16.to_be_bytes());  // window
+    segment.extend_from_slice(&[0; 4]);  // checksum and urgent pointer
+    segment.extend_from_slice(&[2, 4, 0x05, 0xb4]);  // MSS of 1460
+
+    let sum = checksum(&[pseudo_header(src.ip(), dst.ip(), segment.len()), segment.clone()].concat());
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+/**
+ * The pseudo-header of a TCP segment of the given length between the given
+ * IPs (RFC 793, RFC 8200), covered by its checksum.
+ */
+fn pseudo_header(src: IpAddr, dst: IpAddr, len: usize) -> Vec<u8> {
+    match (src, dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            [&s.octets()[..], &d.octets()[..], &[0, 6], &(len as u16).to_be_bytes()].concat()
+        },
+        (s, d) => {
+            let octets = |ip: IpAddr| match ip {
+                IpAddr::V4(a) => a.to_ipv6_mapped().octets(),
+                IpAddr::V6(a) => a.octets(),
+            };
+            [&octets(s)[..], &octets(d)[..], &(len as u32).to_be_bytes(), &[0, 0, 0, 6]].concat()
+        },
+    }
+}
+
+/**
+ * Whether the given TCP segment (from the given address) answers the SYN
+ * with the given sequence number from the given port: `Some(true)` if with a
+ * SYN-ACK, `Some(false)` if with a reset, and None if it doesn't.
+ */
+pub fn answer_to(segment: &[u8], dst: &SocketAddr, port: u16, seq: u32) -> Option<bool> {
+    if segment.len() < 20
+        || u16::from_be_bytes([segment[0], segment[1]]) != dst.port()
+        || u16::from_be_bytes([segment[2], segment[3]]) != port
+        || u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]) != seq.wrapping_add(1) {
+        return None;
+    }
+    match segment[13] {
+        f if f & (SYN | ACK) == SYN | ACK => Some(true),
+        f if f & RST != 0 => Some(false),
+        _ => None,
+    }
+}
+
+/**
+ * Times a single half-open handshake with the given address as the given
+ * settings say, or returns None if raw sockets aren't available.
+ */
+pub fn syn_once(dst: &SocketAddr, settings: &ProbeSettings) -> Option<Result<u64, i32>> {
+    let raw = match Socket::new(Domain::for_address(*dst), Type::RAW, Some(Protocol::TCP)) {
+        Ok(s) => s,
+        Err(_) => {
+            static WARNED: Once = Once::new();
+            WARNED.call_once(|| warn!("Half-open TCP Ping needs a raw socket (e.g. CAP_NET_RAW), \
+                                       making full connections instead."));
+            return None;
+        },
+    };
+    Some(half_open(raw, dst, settings).unwrap_or_else(|e| Err(io_error_sentinel(&e))))
+}
+
+fn half_open(raw: Socket, dst: &SocketAddr, settings: &ProbeSettings) -> io::Result<Result<u64, i32>> {
+    sockopt::prepare(&raw, dst, settings)?;
+
+    // the IP the SYN goes out from, as routing (or the source) has it
+    let route = sockopt::udp_socket(dst, settings)?;
+    route.connect(dst)?;
+    let local_ip = route.local_addr()?.ip();
+
+    // reserving a port to send from
+    let reserved = Socket::new(Domain::for_address(*dst), Type::STREAM, Some(Protocol::TCP))?;
+    reserved.bind(&SocketAddr::new(local_ip, 0).into())?;
+    let src = reserved.local_addr()?.as_socket()
+        .ok_or_else(|| io::Error::other("reserved socket has no address"))?;
+
+    // hashers of new `RandomState`s are randomly seeded, which is random enough here
+    let seq = RandomState::new().build_hasher().finish() as u32;
+    let segment = syn_segment(&src, dst, seq);
+
+    let start = precise_time_ns();
+    raw.send_to(&segment, &SockAddr::from(SocketAddr::new(dst.ip(), 0)))?;
+
+    let deadline = Instant::now() + settings.timeout;
+    let mut buf = [0u8; 1500];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(Err(SENTINEL_TIMEOUT));
+        }
+        raw.set_read_timeout(Some(remaining))?;
+        let len = match (&raw).read(&mut buf) {
+            Ok(len) => len,
+            Err(e) => return Ok(Err(io_error_sentinel(&e))),
+        };
+        let elapsed = precise_time_ns() - start;
+
+        // raw IPv4 sockets hand us the IP header as well, which tells who it's from
+        let received = match *dst {
+            SocketAddr::V4(a) => {
+                let ihl = ((buf[0] & 0x0f) as usize) * 4;
+                if len < ihl || len < 20 || buf[12..16] != a.ip().octets() {
+                    continue;
+                }
+                &buf[ihl..len]
+            },
+            SocketAddr::V6(_) => &buf[..len],
+        };
+
+        // raw sockets see all TCP traffic, keep waiting for the answer
+        match answer_to(received, dst, src.port(), seq) {
+            Some(true) => return Ok(Ok(elapsed)),
+            Some(false) => return Ok(Err(SENTINEL_REFUSED)),
+            None => continue,
+        }
+    }
+}
+
+#[test]
+fn syns_are_answered() {
+    let src: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+    let dst: SocketAddr = "198.51.100.2:443".parse().unwrap();
+    let syn = syn_segment(&src, &dst, 0xfffffffe);
+    assert_eq!(syn.len(), 24);
+    assert_eq!(checksum(&[pseudo_header(src.ip(), dst.ip(), syn.len()), syn.clone()].concat()), 0);
+
+    let v6 = syn_segment(&"[2001:db8::1]:40000".parse().unwrap(), &"[2001:db8::2]:80".parse().unwrap(), 7);
+    assert_eq!(checksum(&[pseudo_header("2001:db8::1".parse().unwrap(), "2001:db8::2".parse().unwrap(), 24),
+                          v6].concat()), 0);
+
+    // the answer comes from the destination's port to ours, acknowledging the sequence number
+    let answer = |flags: u8, ack: u32| {
+        let mut segment = syn_segment(&dst, &src, 1234);
+        segment[8..12].copy_from_slice(&ack.to_be_bytes());
+        segment[13] = flags;
+        segment
+    };
+    assert_eq!(answer_to(&answer(SYN | ACK, 0xffffffff), &dst, 40000, 0xfffffffe), Some(true));
+    assert_eq!(answer_to(&answer(RST | ACK, 0xffffffff), &dst, 40000, 0xfffffffe), Some(false));
+    assert_eq!(answer_to(&answer(SYN | ACK, 5), &dst, 40000, 0xfffffffe), None);
+    assert_eq!(answer_to(&answer(SYN | ACK, 0xffffffff), &dst, 40001, 0xfffffffe), None);
+    assert_eq!(answer_to(&syn, &dst, 40000, 0xfffffffe), None);
+}
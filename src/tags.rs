@@ -0,0 +1,139 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Tags (e.g. `site = "home"`, `isp = "comcast"`) carried by the addresses of
+ * a target, the `group` tag grouping them, and filtering by them in the query
+ * parameters of the API (`tag=site:home`, `tag=isp`, `group=vpn`).
+ */
+use std::collections::BTreeMap;
+
+/**
+ * The tags of a single address, by name.
+ */
+pub type Tags = BTreeMap<String, String>;
+
+/**
+ * The tag identifying the group of an address.
+ */
+pub const GROUP_TAG: &str = "group";
+
+/**
+ * Label names of the metrics of an address, which its tags can't take.
+ */
+const RESERVED_LABELS: &[&str] = &["target", "addr", "column", "class", "quantile", "le"];
+
+/**
+ * A filter of addresses by their tags, each of the required tags (with the
+ * given value, or any) being needed to match. Empty filters match every
+ * address.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TagFilter {
+    required: Vec<(String, Option<String>)>,
+}
+
+impl TagFilter {
+    /**
+     * Returns whether the given query parameter is one of a tag filter.
+     */
+    pub fn accepts(param: &str) -> bool {
+        param == "tag" || param == GROUP_TAG
+    }
+
+    /**
+     * Adds the given query parameter (see `accepts`) to the filter, its
+     * (decoded) value being `name:value` or just `name` for `tag`, and the
+     * name of the group for `group`.
+     */
+    pub fn add(&mut self, param: &str, val: &str) {
+        let (name, value) = if param == GROUP_TAG {
+            (GROUP_TAG.to_owned(), Some(val.to_owned()))
+        } else {
+            match val.split_once(':') {
+                Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                None => (val.to_owned(), None),
+            }
+        };
+        self.required.push((name, value));
+    }
+
+    /**
+     * Creates a filter of the given tag, `name:value` or just `name` (not
+     * URL-encoded).
+     */
+    pub fn of_tag(tag: &str) -> Self {
+        let (name, value) = match tag.split_once(':') {
+            Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+            None => (tag.to_owned(), None),
+        };
+        TagFilter {
+            required: vec![(name, value)],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.required.is_empty()
+    }
+
+    /**
+     * Returns whether an address with the given tags (if any) matches.
+     */
+    pub fn matches(&self, tags: Option<&Tags>) -> bool {
+        self.required.iter().all(|(name, value)| {
+            match (tags.and_then(|t| t.get(name)), value) {
+                (Some(v), Some(value)) => v == value,
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
+        })
+    }
+}
+
+/**
+ * Gets the label name a tag of the given name takes on the metrics of an
+ * address: the name with whatever Prometheus doesn't allow replaced by `_`,
+ * or None if it is taken (see `RESERVED_LABELS`).
+ */
+pub fn label_name(name: &str) -> Option<String> {
+    let mut label: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if label.is_empty() || label.starts_with(|c: char| c.is_ascii_digit()) {
+        label.insert(0, '_');
+    }
+    if label.starts_with("__") || RESERVED_LABELS.contains(&label.as_str()) {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+#[test]
+fn filters_match_every_required_tag() {
+    let tags: Tags = [("site", "home"), ("isp", "comcast"), ("group", "new york")].iter()
+        .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+
+    let mut filter = TagFilter::default();
+    assert!(filter.matches(None));
+    filter.add("tag", "site:home");
+    filter.add("tag", "isp");
+    assert!(filter.matches(Some(&tags)));
+    assert!(!filter.matches(None));
+    filter.add("group", "new york");
+    assert!(filter.matches(Some(&tags)));
+    filter.add("tag", "site:work");
+    assert!(!filter.matches(Some(&tags)));
+
+    assert_eq!(label_name("isp"), Some("isp".to_owned()));
+    assert_eq!(label_name("data-center"), Some("data_center".to_owned()));
+    assert_eq!(label_name("1st"), Some("_1st".to_owned()));
+    assert_eq!(label_name("addr"), None);
+    assert_eq!(label_name("__name__"), None);
+}
@@ -6,130 +6,84 @@
  * details.
  */
 
-use std::thread;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
 
-use std::time::{Duration, Instant};
 use time::precise_time_ns;
-use chrono::Local;
 
-use std::net::{TcpStream, ToSocketAddrs};
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::resolve;
+use crate::proxy;
+use crate::sockopt::TcpOptions;
+use crate::syn;
+use crate::worker::ProbeSettings;
 
-use crate::options::SENTINEL_ERROR;
-use crate::options::TargetResults;
-use crate::persist::TargetManager;
+pub static KIND: TargetKind = TargetKind::of(&TcpPing);
 
 /**
- * Runs the TCP Ping target's data-collection worker.
+ * The TCP Ping target, timing the duration of a single TCP handshake to each
+ * address, a `host:port` optionally preceded by the mode, `connect` (the
+ * default) or `syn` (half-open, see `syn.rs`), e.g. `syn google.com:443`.
+ *
+ * Returns the handshake time followed by the time spent resolving the
+ * address beforehand (close to 0 when the resolution was cached).
  */
-pub fn run_tcpping_worker(manager: Arc<TargetManager>,
-                          results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
-    // start a new thread for the worker
-    thread::spawn(move || {
-        let mut handles = Vec::new();
-
-        // continue to collect data forever
-        loop {
-            let loop_start = Instant::now();
-
-            // retrieve the target's current options
-            let (dur_interval, avg_across, dur_pause, num_addrs) = {
-                let ref opt = manager.options_read();
-                (
-                    Duration::from_millis(opt.interval as u64),
-                    opt.avg_across,
-                    Duration::from_millis(opt.pause as u64),
-                    opt.addrs.len(),
-                )
-            };
-
-            // get the current time (to timestamp this round of data with)
-            let timestamp: i32 = Local::now().timestamp() as i32;
-
-            let nonce = {
-                let ref t_opt = manager.options_read();
-                for addr in t_opt.addrs.iter() {
-                    let a = addr.clone();
-
-                    /*
-                     * create channels so the per-addr threads can send back
-                     * their data to the worker thread
-                     */
-                    let (tx, rx) = channel();
-                    handles.push(rx);
-
-                    /*
-                     * spawn a thread to actually collect the data for each
-                     * separate address
-                     */
-                    thread::spawn(move || {
-                        let mut sum = 0;
-                        let mut denom = 0;
-                        // average the results across the given number of times
-                        for _ in 0..avg_across {
-                            /*
-                             * time the duration of a TCP handshake to the
-                             * address
-                             */
-                            let start = precise_time_ns();
-                            // Set a 30 second timeout for the TCP connection
-                            let timeout = Duration::from_secs(30);
-                            if let Ok(mut addrs) = a.as_str().to_socket_addrs() {
-                                if let Some(addr) = addrs.next() {
-                                    if TcpStream::connect_timeout(&addr, timeout).is_ok() {
-                                        sum += precise_time_ns() - start;
-                                        denom += 1;
-                                    }
-                                }
-                            }
-                            thread::sleep(dur_pause);
-                        }
-
-                        if denom != 0 {
-                            /*
-                             * send back micro-second average.
-                             *
-                             * we don't care if send fails as that likely means
-                             * we took too long and the control thread is no longer
-                             * waiting for us
-                             */
-                            let _ = tx.send((sum / denom / 1000) as i32);
-                        }
-                    });
-                }
-                t_opt.nonce
-            };
-
-            let mut data: Vec<i32> = Vec::with_capacity(3 + num_addrs);
-
-            data.push(manager.kind.kind_id());
-            data.push(nonce);
-            data.push(timestamp);
-
-            // read back the data from the per-addr subthreads, blocking
-            // until each one completes (they always terminate due to the
-            // TCP connect timeout)
-            for h in handles.drain(..) {
-                if let Ok(val) = h.recv() {
-                    data.push(val);
-                } else {
-                    // all sub-attempts failed (sender dropped without sending)
-                    data.push(SENTINEL_ERROR);
-                }
-            }
-
-            // send off our results to the main thread
-            if results_out.send(TargetResults(data)).is_err() {
-                println!("Worker Control: failed to send final results back.");
-            }
-
-            // sleep for the remainder of the interval
-            let elapsed = loop_start.elapsed();
-            if elapsed < dur_interval {
-                thread::sleep(dur_interval - elapsed);
+pub struct TcpPing;
+
+impl Probe for TcpPing {
+    type Target = (bool, SocketAddr);  // whether in `syn` mode, and the address
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 0,
+        name: "tcpping",
+        columns: &["", "dns"],
+        units: &[],
+        default_timeout: 30_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &["google.com:80", "8.8.8.8:53"],
+        default_interval: 10_000,
+        default_avg_across: 3,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32> {
+        let (syn_mode, host_port) = match addr.split_once(' ') {
+            Some(("syn", hp)) => (true, hp.trim()),
+            Some(("connect", hp)) => (false, hp.trim()),
+            Some(_) => return Err(SENTINEL_ERROR),
+            None => (false, addr),
+        };
+
+        let (host, port) = resolve::split_host_port(host_port).ok_or(SENTINEL_ERROR)?;
+        let sock_addr = resolve::resolve(host, port, settings).ok_or(SENTINEL_RESOLVE)?;
+        Ok((syn_mode, sock_addr))
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        let host_port = addr.split_once(' ').map_or(addr, |(_, hp)| hp.trim());
+        resolve::split_host_port(host_port).map(|(host, port)| (host.to_owned(), port))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.1.ip())
+    }
+
+    fn probe_once(&self, _: &str, (syn_mode, sock_addr): Self::Target,
+                  attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        // falling back to a full connection where a half-open one can't be made
+        if syn_mode && attempt.settings.proxy.is_none() {
+            if let Some(result) = syn::syn_once(&sock_addr, attempt.settings) {
+                return Ok(vec![result?, attempt.resolve_nanos]);
             }
         }
-    })
+        // Fast Open would put off the handshake until data is written, and none is
+        let settings = ProbeSettings {
+            tcp: TcpOptions { fast_open: false, ..attempt.settings.tcp },
+            ..attempt.settings.clone()
+        };
+        let start = precise_time_ns();
+        proxy::connect(&sock_addr, &settings)
+            .map_err(|e| io_error_sentinel(&e))?;
+        Ok(vec![precise_time_ns() - start, attempt.resolve_nanos])
+    }
 }
-
@@ -0,0 +1,317 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Telemetry of stabping itself, to tell where results go missing or are held
+ * up: rounds overrunning their deadlines and results dropped by the workers,
+ * the pool the rounds run on, and the queues (and delivery latency) of the
+ * results channel and each sink, persistence included.
+ *
+ * Counted process-wide (like shutdown), periodically logged, and exposed on
+ * /metrics (see `Metrics::render`).
+ */
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/**
+ * How often telemetry is logged.
+ */
+const LOG_INTERVAL: Duration = Duration::from_secs(300);
+
+/**
+ * Depth of a queue past which it is logged as backed up.
+ */
+const QUEUE_WARN_DEPTH: usize = 1000;
+
+/**
+ * Time a sink may take to deliver a round of results before it is logged as
+ * slow.
+ */
+pub const SLOW_DELIVERY: Duration = Duration::from_secs(1);
+
+/**
+ * Counts of the worker of a single target.
+ */
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct WorkerCounts {
+    pub overruns: u64,  // rounds that overran their address's next deadline
+    pub dropped: u64,  // results that couldn't be sent to the main thread
+}
+
+static WORKERS: Mutex<BTreeMap<&'static str, WorkerCounts>> = Mutex::new(BTreeMap::new());
+static RESULTS_QUEUED: AtomicUsize = AtomicUsize::new(0);
+static SINKS: Mutex<Vec<Arc<SinkStats>>> = Mutex::new(Vec::new());
+static POOL: Mutex<Option<Arc<PoolStats>>> = Mutex::new(None);
+
+/**
+ * Records that a round of the given target overran.
+ */
+pub fn record_overrun(target: &'static str) {
+    WORKERS.lock().unwrap().entry(target).or_default().overruns += 1;
+}
+
+/**
+ * Records that results of the given target were dropped.
+ */
+pub fn record_dropped(target: &'static str) {
+    WORKERS.lock().unwrap().entry(target).or_default().dropped += 1;
+}
+
+/**
+ * Records that results are about to be sent to the main thread (before
+ * sending, so that they are never received before being counted).
+ */
+pub fn result_sending() {
+    RESULTS_QUEUED.fetch_add(1, Ordering::SeqCst);
+}
+
+/**
+ * Records that results sent to the main thread were received, or couldn't be
+ * sent after all.
+ */
+pub fn result_received() {
+    RESULTS_QUEUED.fetch_sub(1, Ordering::SeqCst);
+}
+
+/**
+ * The queue and deliveries of a single sink (see `ResultsBus`).
+ */
+#[derive(Debug, Default)]
+pub struct SinkStats {
+    pub name: &'static str,
+    queued: AtomicUsize,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    delivery_nanos: AtomicU64,  // total time spent delivering
+    max_delivery_nanos: AtomicU64,
+}
+
+impl SinkStats {
+    /**
+     * Records that a round of results was queued for the sink.
+     */
+    pub fn queued(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /**
+     * Records that a round of results was taken off the queue and delivered
+     * (or dropped) in the given time.
+     */
+    pub fn delivered(&self, took: Duration, dropped: bool) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        let nanos = took.as_nanos() as u64;
+        self.delivered.fetch_add(1, Ordering::SeqCst);
+        self.delivery_nanos.fetch_add(nanos, Ordering::SeqCst);
+        self.max_delivery_nanos.fetch_max(nanos, Ordering::SeqCst);
+        if dropped {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/**
+ * Registers a sink of the given name, returning the stats to keep for it.
+ */
+pub fn register_sink(name: &'static str) -> Arc<SinkStats> {
+    let stats = Arc::new(SinkStats { name, ..SinkStats::default() });
+    SINKS.lock().unwrap().push(stats.clone());
+    stats
+}
+
+/**
+ * The threads and queue of the pool the rounds run on (see `ThreadPool`).
+ */
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    pub size: usize,
+    busy: AtomicUsize,
+    queued: AtomicUsize,
+}
+
+impl PoolStats {
+    pub fn new(size: usize) -> Self {
+        PoolStats { size, ..PoolStats::default() }
+    }
+
+    pub fn job_queued(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn job_started(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        self.busy.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn job_done(&self) {
+        self.busy.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/**
+ * Reports on the given pool (the one the rounds of all targets run on).
+ */
+pub fn watch_pool(stats: Arc<PoolStats>) {
+    *POOL.lock().unwrap() = Some(stats);
+}
+
+/**
+ * A sink as of a `Snapshot`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkSnapshot {
+    pub name: &'static str,
+    pub queued: usize,
+    pub delivered: u64,
+    pub dropped: u64,
+    pub delivery_seconds: f64,  // total
+    pub max_delivery_seconds: f64,
+}
+
+/**
+ * All telemetry as of some moment.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub workers: BTreeMap<&'static str, WorkerCounts>,
+    pub results_queued: usize,
+    pub sinks: Vec<SinkSnapshot>,
+    pub pool: Option<(usize, usize, usize)>,  // size, busy threads and queued rounds
+    pub threads: Option<u64>,  // of the whole process
+}
+
+/**
+ * Takes a snapshot of all telemetry.
+ */
+pub fn snapshot() -> Snapshot {
+    let sinks = SINKS.lock().unwrap().iter()
+        .map(|s| SinkSnapshot {
+            name: s.name,
+            queued: s.queued.load(Ordering::SeqCst),
+            delivered: s.delivered.load(Ordering::SeqCst),
+            dropped: s.dropped.load(Ordering::SeqCst),
+            delivery_seconds: s.delivery_nanos.load(Ordering::SeqCst) as f64 / 1e9,
+            max_delivery_seconds: s.max_delivery_nanos.load(Ordering::SeqCst) as f64 / 1e9,
+        })
+        .collect();
+    let pool = POOL.lock().unwrap().as_ref()
+        .map(|p| (p.size, p.busy.load(Ordering::SeqCst), p.queued.load(Ordering::SeqCst)));
+    Snapshot {
+        workers: WORKERS.lock().unwrap().clone(),
+        results_queued: RESULTS_QUEUED.load(Ordering::SeqCst),
+        sinks,
+        pool,
+        threads: process_threads(),
+    }
+}
+
+/**
+ * Gets the number of threads of this process (where the OS tells us).
+ */
+fn process_threads() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|n| n.trim().parse().ok())
+}
+
+/**
+ * Describes the given snapshot in one line, along with what got worse since
+ * the previous one (for logging at warning level), if anything did.
+ */
+pub fn describe(snapshot: &Snapshot, previous: &Snapshot) -> (String, Option<String>) {
+    let mut problems = Vec::new();
+    let (overruns, dropped) = snapshot.workers.iter().fold((0, 0), |(o, d), (target, c)| {
+        let before = previous.workers.get(target).copied().unwrap_or_default();
+        if c.dropped > before.dropped {
+            problems.push(format!("{} dropped {} results", target, c.dropped - before.dropped));
+        }
+        (o + c.overruns - before.overruns, d + c.dropped)
+    });
+    if snapshot.results_queued >= QUEUE_WARN_DEPTH {
+        problems.push(format!("{} results queued for the sinks", snapshot.results_queued));
+    }
+
+    let mut line = format!("Telemetry: {} threads", snapshot.threads.map_or("?".to_owned(), |n| n.to_string()));
+    if let Some((size, busy, queued)) = snapshot.pool {
+        line.push_str(&format!(", pool {}/{} busy ({} rounds queued)", busy, size, queued));
+        if queued >= QUEUE_WARN_DEPTH {
+            problems.push(format!("{} rounds queued for the pool", queued));
+        }
+    }
+    line.push_str(&format!(", {} rounds overran (+{}), {} results dropped, {} results queued",
+                           snapshot.workers.values().map(|c| c.overruns).sum::<u64>(), overruns,
+                           dropped, snapshot.results_queued));
+    for s in snapshot.sinks.iter() {
+        let mean = if s.delivered > 0 { s.delivery_seconds / s.delivered as f64 } else { 0.0 };
+        line.push_str(&format!("; {}: {} queued, {:.1}ms mean ({:.1}ms max) delivery",
+                               s.name, s.queued, mean * 1000.0, s.max_delivery_seconds * 1000.0));
+        if s.queued >= QUEUE_WARN_DEPTH {
+            problems.push(format!("{} sink has {} results queued", s.name, s.queued));
+        }
+        let before = previous.sinks.iter().find(|p| p.name == s.name).map_or(0, |p| p.dropped);
+        if s.dropped > before {
+            problems.push(format!("{} sink dropped {} results", s.name, s.dropped - before));
+        }
+    }
+
+    (line, if problems.is_empty() { None } else { Some(problems.join(", ")) })
+}
+
+/**
+ * Logs telemetry every `LOG_INTERVAL`, warning of anything that got worse.
+ */
+pub fn run_logger() {
+    thread::spawn(move || {
+        let mut previous = snapshot();
+        loop {
+            thread::sleep(LOG_INTERVAL);
+            let current = snapshot();
+            let (line, problems) = describe(&current, &previous);
+            info!("{}", line);
+            if let Some(p) = problems {
+                warn!("Telemetry: {}.", p);
+            }
+            previous = current;
+        }
+    });
+}
+
+#[test]
+fn describe_warns_of_what_got_worse() {
+    let sink = SinkSnapshot {
+        name: "Persistence",
+        queued: 2,
+        delivered: 4,
+        dropped: 0,
+        delivery_seconds: 0.01,
+        max_delivery_seconds: 0.004,
+    };
+    let previous = Snapshot {
+        workers: vec![("tcpping", WorkerCounts { overruns: 1, dropped: 0 })].into_iter().collect(),
+        results_queued: 0,
+        sinks: vec![sink.clone()],
+        pool: Some((64, 3, 0)),
+        threads: Some(80),
+    };
+    let (line, problems) = describe(&previous, &previous);
+    assert_eq!(line, "Telemetry: 80 threads, pool 3/64 busy (0 rounds queued), 1 rounds overran (+0), \
+                      0 results dropped, 0 results queued; Persistence: 2 queued, 2.5ms mean (4.0ms max) delivery");
+    assert_eq!(problems, None);
+
+    let mut current = previous.clone();
+    current.workers.insert("tcpping", WorkerCounts { overruns: 3, dropped: 2 });
+    current.sinks[0].queued = QUEUE_WARN_DEPTH;
+    let (line, problems) = describe(&current, &previous);
+    assert!(line.contains("3 rounds overran (+2), 2 results dropped"));
+    assert_eq!(problems.unwrap(), "tcpping dropped 2 results, Persistence sink has 1000 results queued");
+}
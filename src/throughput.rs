@@ -0,0 +1,269 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Throughput data collection, timing the transfer of a bounded payload to or
+ * from an HTTP(S) endpoint, on a slower schedule than the latency targets
+ * since every round costs real bandwidth.
+ */
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+
+use time::precise_time_ns;
+
+use crate::http;
+use crate::http::{HttpStream, Url};
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, SENTINEL_TLS, io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema, Unit};
+use crate::resolve;
+use crate::proxy;
+use crate::worker::ProbeSettings;
+
+/**
+ * Bytes uploaded when an `upload` addr doesn't say.
+ */
+const DEFAULT_UPLOAD: u64 = 1_000_000;
+
+const CHUNK: usize = 64 * 1024;
+
+pub static KIND: TargetKind = TargetKind::of(&Throughput);
+
+/**
+ * What an addr transfers: whether it uploads (rather than downloads), and
+ * how many bytes at most.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transfer {
+    pub upload: bool,
+    pub size: Option<u64>,
+}
+
+/**
+ * The Throughput target, timing a single transfer with each addr, a URL
+ * optionally preceded by the direction, `download` (the default, reading the
+ * body of a GET) or `upload` (POSTing a body of zeros), and the size of the
+ * transfer in bytes (with an optional `kB`, `MB` or `GB` suffix), e.g.
+ * `download 5MB https://speed.example.com/big.bin`. Downloads stop at the
+ * size if the body is larger, and uploads are 1MB unless it's given.
+ *
+ * Returns the throughput in thousandths of kbit/s (i.e. recorded in kbit/s),
+ * followed by the time to first byte of the response (after the request, body
+ * and all) and the time the payload took to move.
+ */
+pub struct Throughput;
+
+impl Probe for Throughput {
+    type Target = (Transfer, Url, SocketAddr);  // the transfer, its URL and the address of its host
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 9,
+        name: "throughput",
+        columns: &["", "ttfb", "transfer"],
+        units: &[("", Unit::Rate)],
+        default_timeout: 30_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &[],
+        default_interval: 900_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32> {
+        let (transfer, url_str) = parse_addr(addr).ok_or(SENTINEL_ERROR)?;
+        let url = Url::parse(url_str).ok_or(SENTINEL_ERROR)?;
+        let sock_addr = resolve::resolve(&url.host, url.port, settings).ok_or(SENTINEL_RESOLVE)?;
+        Ok((transfer, url, sock_addr))
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        let url = Url::parse(parse_addr(addr)?.1)?;
+        Some((url.host, url.port))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.2.ip())
+    }
+
+    fn probe_once(&self, _: &str, (transfer, url, sock_addr): Self::Target,
+                  attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        let (bytes, ttfb, nanos) = transfer_once(transfer, &url, sock_addr, attempt.settings)?;
+        Ok(vec![rate(bytes, nanos), ttfb, nanos])
+    }
+}
+
+/**
+ * Parses a size in bytes, e.g. `500000`, `500kB` or `1.5MB` (in powers of
+ * 1000).
+ */
+pub fn parse_size(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1e3,
+        "m" | "mb" => 1e6,
+        "g" | "gb" => 1e9,
+        _ => return None,
+    };
+    let bytes = number.parse::<f64>().ok()? * multiplier;
+    if bytes >= 1.0 { Some(bytes as u64) } else { None }
+}
+
+/**
+ * Splits the given addr into the transfer it asks for and its URL.
+ */
+fn parse_addr(addr: &str) -> Option<(Transfer, &str)> {
+    let mut words: Vec<&str> = addr.split_whitespace().collect();
+    let url = words.pop()?;
+    let (upload, rest) = match words.split_first() {
+        Some((&"upload", rest)) => (true, rest),
+        Some((&"download", rest)) => (false, rest),
+        _ => (false, &words[..]),
+    };
+    let size = match *rest {
+        [] => None,
+        [size] => Some(parse_size(size)?),
+        _ => return None,
+    };
+    Some((Transfer { upload, size }, url))
+}
+
+/**
+ * Reads the status line and headers of a response, returning its status code
+ * and Content-Length (if any).
+ */
+fn read_head<R: BufRead>(reader: &mut R) -> std::io::Result<(u16, Option<u64>)> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split_whitespace().nth(1).and_then(|code| code.parse().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed status line"))?;
+
+    let mut length = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            return Ok((status, length));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+}
+
+/**
+ * The throughput of moving the given number of bytes in the given time (in
+ * nanoseconds), in thousandths of kbit/s.
+ */
+pub fn rate(bytes: u64, nanos: u64) -> u64 {
+    (bytes as u128 * 8_000_000_000 / nanos.max(1) as u128) as u64
+}
+
+/**
+ * Makes a single transfer with the given URL, its host having resolved to the
+ * given address, returning the bytes moved, the time to first byte of the
+ * response, and the time the payload took to move (in nanoseconds).
+ */
+pub fn transfer_once(transfer: Transfer, url: &Url, sock_addr: SocketAddr,
+                     settings: &ProbeSettings) -> Result<(u64, u64, u64), i32> {
+    // the timeout applies to each read and write separately
+    let tcp = proxy::connect(&sock_addr, settings).map_err(|e| io_error_sentinel(&e))?;
+    tcp.set_read_timeout(Some(settings.timeout)).map_err(|_| SENTINEL_ERROR)?;
+    tcp.set_write_timeout(Some(settings.timeout)).map_err(|_| SENTINEL_ERROR)?;
+
+    let mut stream = if url.https {
+        http::tls_handshake(&url.host, tcp).map_err(|e| match io_error_sentinel(&e) {
+            s if s == SENTINEL_ERROR => SENTINEL_TLS,
+            s => s,
+        })?
+    } else {
+        HttpStream::Plain(tcp)
+    };
+
+    let io_err = |e: std::io::Error| io_error_sentinel(&e);
+    let chunk = vec![0u8; CHUNK];
+    let (sent, upload_start) = if transfer.upload {
+        let size = transfer.size.unwrap_or(DEFAULT_UPLOAD);
+        let length = size.to_string();
+        http::write_request(&mut stream, "POST", url, &[("Content-Type", "application/octet-stream"),
+                                                        ("Content-Length", &length)], &[]).map_err(io_err)?;
+        let start = precise_time_ns();
+        let mut left = size;
+        while left > 0 {
+            let n = left.min(CHUNK as u64) as usize;
+            stream.write_all(&chunk[..n]).map_err(io_err)?;
+            left -= n as u64;
+        }
+        stream.flush().map_err(io_err)?;
+        (precise_time_ns(), Some((start, size)))
+    } else {
+        http::write_request(&mut stream, "GET", url, &[], &[]).map_err(io_err)?;
+        (precise_time_ns(), None)
+    };
+
+    let mut reader = BufReader::with_capacity(CHUNK, stream);
+    if reader.fill_buf().map_err(io_err)?.is_empty() {
+        return Err(SENTINEL_ERROR);
+    }
+    let first_byte = precise_time_ns();
+    let (status, length) = read_head(&mut reader).map_err(io_err)?;
+    if !(200..300).contains(&status) {
+        return Err(SENTINEL_ERROR);
+    }
+
+    let (bytes, transfer_nanos) = match upload_start {
+        // the server answering is what tells the body has arrived
+        Some((start, size)) => (size, first_byte - start),
+        None => {
+            let limit = match (transfer.size, length) {
+                (Some(s), Some(l)) => Some(s.min(l)),
+                (s, l) => s.or(l),
+            };
+            let mut buf = vec![0u8; CHUNK];
+            let mut read = 0u64;
+            while limit.is_none_or(|l| read < l) {
+                match reader.read(&mut buf).map_err(io_err)? {
+                    0 => break,
+                    n => read += n as u64,
+                }
+            }
+            if read == 0 {
+                return Err(SENTINEL_ERROR);
+            }
+            (limit.map_or(read, |l| read.min(l)), precise_time_ns() - first_byte)
+        },
+    };
+
+    Ok((bytes, first_byte - sent, transfer_nanos))
+}
+
+#[test]
+fn transfers_are_parsed_and_rated() {
+    assert_eq!(parse_size("1500"), Some(1500));
+    assert_eq!(parse_size("500kB"), Some(500_000));
+    assert_eq!(parse_size("1.5MB"), Some(1_500_000));
+    assert_eq!(parse_size("2G"), Some(2_000_000_000));
+    assert_eq!((parse_size("5TB"), parse_size("MB"), parse_size("0")), (None, None, None));
+
+    let download = Transfer { upload: false, size: None };
+    assert_eq!(parse_addr("https://example.com/a.bin"), Some((download, "https://example.com/a.bin")));
+    assert_eq!(parse_addr("download 5MB http://x/"),
+               Some((Transfer { upload: false, size: Some(5_000_000) }, "http://x/")));
+    assert_eq!(parse_addr("upload http://x/up"), Some((Transfer { upload: true, size: None }, "http://x/up")));
+    assert_eq!(parse_addr("upload 5MB 6MB http://x/"), None);
+    assert_eq!(parse_addr("sideways http://x/"), None);
+    assert_eq!(Throughput.host_of("upload 1MB https://[::1]:8443/up"), Some(("::1".to_owned(), 8443)));
+
+    let mut head = &b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\ncontent-length: 12\r\n\r\nhello world!"[..];
+    assert_eq!(read_head(&mut head).unwrap(), (200, Some(12)));
+    assert_eq!(head, b"hello world!");
+
+    // 1MB in a second is 8000 kbit/s
+    assert_eq!(rate(1_000_000, 1_000_000_000), 8_000_000);
+}
@@ -0,0 +1,199 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * TLS handshake data collection, timing a complete (verified) handshake with
+ * each address, and reading how long until the certificates it presented
+ * expire.
+ */
+use chrono::{Local, NaiveDate};
+use time::precise_time_ns;
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::http;
+use crate::http::HttpStream;
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, SENTINEL_TLS,
+                     io_error_sentinel};
+use crate::options::EXPIRY_COLUMN;
+use crate::probe::{Attempt, Probe, ProbeSchema, Unit};
+use crate::resolve;
+use crate::proxy;
+use crate::worker::ProbeSettings;
+
+pub static KIND: TargetKind = TargetKind::of(&Tls);
+
+/**
+ * The TLS Handshake target, timing a single TLS handshake with each
+ * `host:port` address.
+ *
+ * Returns the handshake time followed by the time spent in DNS resolution and
+ * TCP connect beforehand, and the whole days until the first of the presented
+ * certificates expires (scaled like the durations, so that it is recorded in
+ * days).
+ */
+pub struct Tls;
+
+impl Probe for Tls {
+    type Target = (String, SocketAddr);  // the host, and its address
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 6,
+        name: "tls",
+        columns: &["", "dns", "connect", EXPIRY_COLUMN],
+        units: &[(EXPIRY_COLUMN, Unit::Gauge)],
+        default_timeout: 30_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &["google.com:443", "example.com:443"],
+        default_interval: 60_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32> {
+        let (host, port) = resolve::split_host_port(addr).ok_or(SENTINEL_ERROR)?;
+        let sock_addr = resolve::resolve(host, port, settings).ok_or(SENTINEL_RESOLVE)?;
+        Ok((host.to_owned(), sock_addr))
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        resolve::split_host_port(addr).map(|(host, port)| (host.to_owned(), port))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.1.ip())
+    }
+
+    fn probe_once(&self, _: &str, (host, sock_addr): Self::Target,
+                  attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        tls_once(&host, sock_addr, attempt)
+    }
+}
+
+/**
+ * Times a single TLS handshake with the given host at the given address.
+ */
+fn tls_once(host: &str, sock_addr: SocketAddr, attempt: &Attempt) -> Result<Vec<u64>, i32> {
+    // the timeout applies to each phase of the handshake separately
+    let timeout = attempt.settings.timeout;
+
+    let resolved = precise_time_ns();
+    let tcp = proxy::connect(&sock_addr, attempt.settings).map_err(|e| io_error_sentinel(&e))?;
+    tcp.set_read_timeout(Some(timeout)).map_err(|_| SENTINEL_ERROR)?;
+    tcp.set_write_timeout(Some(timeout)).map_err(|_| SENTINEL_ERROR)?;
+    let connected = precise_time_ns();
+
+    // anything but an unresponsive server is a failed TLS negotiation
+    let stream = http::tls_handshake(host, tcp).map_err(|e| match io_error_sentinel(&e) {
+        s if s == SENTINEL_ERROR => SENTINEL_TLS,
+        s => s,
+    })?;
+    let handshaken = precise_time_ns();
+
+    let expires = match stream {
+        HttpStream::Tls(ref s) => s.conn.peer_certificates().unwrap_or(&[]).iter()
+            .filter_map(|cert| not_after(cert))
+            .min(),
+        HttpStream::Plain(_) => None,
+    }.ok_or(SENTINEL_ERROR)?;
+    let days = ((expires - Local::now().timestamp()) / 86400).max(0) as u64;
+
+    Ok(vec![
+        handshaken - connected,
+        attempt.resolve_nanos,
+        connected - resolved,
+        days * 1000,
+    ])
+}
+
+/**
+ * Splits the first DER element off the given bytes, returning its tag, its
+ * contents and the bytes following it.
+ */
+fn der_element(bytes: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *bytes.first()?;
+    let first = *bytes.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        // long form, the low bits giving how many bytes the length takes
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = bytes.get(2..2 + n)?.iter().fold(0, |l, &b| l << 8 | b as usize);
+        (len, 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    Some((tag, bytes.get(header..end)?, &bytes[end..]))
+}
+
+/**
+ * Parses an ASN.1 UTCTime (`YYMMDDHHMMSSZ`) or GeneralizedTime
+ * (`YYYYMMDDHHMMSSZ`) of the given tag into seconds since the epoch.
+ */
+fn parse_asn1_time(tag: u8, s: &[u8]) -> Option<i64> {
+    let s = std::str::from_utf8(s).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let yy: i32 = s.get(..2)?.parse().ok()?;
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, s.get(2..)?)
+        },
+        0x18 => (s.get(..4)?.parse().ok()?, s.get(4..)?),
+        _ => return None,
+    };
+    let field = |i: usize| rest.get(i..i + 2).and_then(|f| f.parse::<u32>().ok());
+    let date = NaiveDate::from_ymd_opt(year, field(0)?, field(2)?)?;
+    Some(date.and_hms_opt(field(4)?, field(6)?, field(8)?)?.timestamp())
+}
+
+/**
+ * Reads the end of the validity period (`notAfter`) of the given DER-encoded
+ * X.509 certificate, in seconds since the epoch.
+ */
+fn not_after(cert: &[u8]) -> Option<i64> {
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(certificate)?;
+
+    // skip the (optional, explicitly tagged) version and the serial number
+    let (tag, _, mut rest) = der_element(tbs)?;
+    if tag == 0xa0 {
+        rest = der_element(rest)?.2;
+    }
+    // then the signature algorithm and the issuer, up to the validity
+    for _ in 0..2 {
+        rest = der_element(rest)?.2;
+    }
+    let (_, validity, _) = der_element(rest)?;
+    let (_, _, validity) = der_element(validity)?;
+    let (tag, time, _) = der_element(validity)?;
+    parse_asn1_time(tag, time)
+}
+
+#[test]
+fn not_after_reads_validity_of_certificate() {
+    // the skeleton of a certificate, down to its validity
+    let mut tbs = vec![0xa0, 0x03, 0x02, 0x01, 0x02];  // version 3
+    tbs.extend_from_slice(&[0x02, 0x01, 0x07]);  // serial number
+    tbs.extend_from_slice(&[0x30, 0x00, 0x30, 0x00]);  // signature algorithm, issuer
+    let mut validity = vec![0x17, 13];
+    validity.extend_from_slice(b"160101000000Z");
+    validity.extend_from_slice(&[0x18, 15]);
+    validity.extend_from_slice(b"20300615123000Z");
+    tbs.extend_from_slice(&[0x30, validity.len() as u8]);
+    tbs.extend(validity);
+
+    let mut certificate = vec![0x30, 0x81, tbs.len() as u8];
+    certificate.extend(tbs);
+    let mut cert = vec![0x30, certificate.len() as u8];
+    cert.extend(certificate);
+
+    assert_eq!(not_after(&cert), Some(1_907_757_000));
+    assert_eq!(parse_asn1_time(0x17, b"160101000000Z"), Some(1_451_606_400));
+    assert_eq!(not_after(&cert[..10]), None);
+}
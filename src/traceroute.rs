@@ -0,0 +1,228 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Traceroute data collection. Echo requests are sent at once with every TTL
+ * (hop limit) up to `MAX_HOPS` over a raw ICMP socket, and the routers along
+ * the path answer those expiring on them with time exceeded messages, mapping
+ * the path hop by hop. The round-trip to the destination itself is collected
+ * as data, while the hops are stored in the target's hops file (see `hops`).
+ */
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::process;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use chrono::Local;
+use socket2::SockAddr;
+use time::precise_time_ns;
+
+use crate::hops::Hop;
+use crate::icmp::{open_icmp_socket, echo_request, SEQUENCE, ICMP_ECHO_REPLY, ICMPV6_ECHO_REPLY,
+                  ICMP_DEST_UNREACHABLE, ICMPV6_DEST_UNREACHABLE};
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, SENTINEL_TIMEOUT,
+                     SENTINEL_UNREACHABLE, io_error_sentinel};
+use crate::persist::TargetManager;
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::resolve;
+use crate::worker::ProbeSettings;
+
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+
+/**
+ * Longest path (in hops) mapped.
+ */
+const MAX_HOPS: u16 = 30;
+
+/**
+ * What an ICMP message received says about one of our echo requests.
+ */
+#[derive(PartialEq)]
+enum Answer {
+    Reply,
+    TimeExceeded,
+    Unreachable,
+}
+
+pub static KIND: TargetKind = TargetKind::of(&Traceroute);
+
+/**
+ * The Traceroute target, mapping the path to each host (storing it in the
+ * target's hops file, except in on-demand rounds) and timing the round-trip to
+ * the host itself.
+ */
+pub struct Traceroute;
+
+impl Probe for Traceroute {
+    type Target = SocketAddr;
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 5,
+        name: "traceroute",
+        columns: &[""],
+        units: &[],
+        default_timeout: 5_000,
+        keeps_hops: true,
+        keeps_ips: true,
+        default_addrs: &[],
+        default_interval: 60_000,
+        default_avg_across: 1,
+    };
+
+    fn resolve(&self, host: &str, settings: &ProbeSettings) -> Result<SocketAddr, i32> {
+        // addrs for this kind are bare hosts, so resolve with a dummy port
+        resolve::resolve(host, 0, settings).ok_or(SENTINEL_RESOLVE)
+    }
+
+    fn host_of(&self, host: &str) -> Option<(String, u16)> {
+        Some((host.to_owned(), 0))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.ip())
+    }
+
+    fn probe_once(&self, host: &str, addr: SocketAddr, attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        traceroute_once(host, addr, attempt.settings, attempt.manager)
+    }
+}
+
+/**
+ * Maps the path to the given host (at the given address), storing it with the
+ * given manager (if any), and times the round-trip to the host itself.
+ */
+fn traceroute_once(host: &str, addr: SocketAddr, settings: &ProbeSettings,
+                   manager: Option<&TargetManager>) -> Result<Vec<u64>, i32> {
+    let time = Local::now().timestamp();
+
+    let (socket, raw) = open_icmp_socket(&addr, settings).map_err(|_| SENTINEL_ERROR)?;
+    if !raw {
+        // only raw sockets receive the time exceeded messages of routers
+        warn!("Traceroute needs a raw ICMP socket (e.g. CAP_NET_RAW), skipping '{}'.", host);
+        return Err(SENTINEL_ERROR);
+    }
+
+    let ident = process::id() as u16;
+    let base_seq = SEQUENCE.fetch_add(MAX_HOPS, Ordering::Relaxed);
+    let mut sent_at = Vec::with_capacity(MAX_HOPS as usize);
+    for ttl in 1..=MAX_HOPS {
+        match addr {
+            SocketAddr::V4(_) => socket.set_ttl(ttl as u32),
+            SocketAddr::V6(_) => socket.set_unicast_hops_v6(ttl as u32),
+        }.map_err(|_| SENTINEL_ERROR)?;
+        let request = echo_request(&addr, ident, base_seq.wrapping_add(ttl - 1));
+        sent_at.push(precise_time_ns());
+        socket.send_to(&request, &SockAddr::from(addr)).map_err(|e| io_error_sentinel(&e))?;
+    }
+
+    // round-trips (in nanos) and addresses of the hops that answered, by TTL
+    let mut answers: Vec<Option<(u64, SocketAddr)>> = vec![None; MAX_HOPS as usize];
+    // the TTL (index) at which the path ended, and how
+    let mut end: Option<(usize, Answer)> = None;
+
+    let socket = UdpSocket::from(socket);
+    let deadline = Instant::now() + settings.timeout;
+    let mut buf = [0u8; 1500];
+    loop {
+        let done = match end {
+            Some((i, _)) => answers[..i].iter().all(|a| a.is_some()),
+            None => false,
+        };
+        let now = Instant::now();
+        if done || now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now)).map_err(|_| SENTINEL_ERROR)?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(_) => break,
+        };
+        let elapsed_at = precise_time_ns();
+
+        /*
+         * raw IPv4 sockets hand us the IP header as well, skip past it to get
+         * to the ICMP message
+         */
+        let msg = match addr {
+            SocketAddr::V4(_) => {
+                let ihl = ((buf[0] & 0x0f) as usize) * 4;
+                if len < ihl {
+                    continue;
+                }
+                &buf[ihl..len]
+            },
+            SocketAddr::V6(_) => &buf[..len],
+        };
+
+        // raw sockets see all ICMP traffic, only consider answers to our requests
+        let (answer, seq) = match classify(&addr, msg, ident) {
+            Some(a) => a,
+            None => continue,
+        };
+        let i = seq.wrapping_sub(base_seq) as usize;
+        if i >= MAX_HOPS as usize || answers[i].is_some() {
+            continue;
+        }
+        answers[i] = Some((elapsed_at - sent_at[i], from));
+
+        // the path ends at the nearest hop that didn't just pass it on
+        if answer != Answer::TimeExceeded && end.as_ref().is_none_or(|&(e, _)| i < e) {
+            end = Some((i, answer));
+        }
+    }
+
+    /*
+     * keep the hops up to where the path ended, or (if it didn't) up to the
+     * farthest one that answered
+     */
+    let num_hops = match end {
+        Some((i, _)) => i + 1,
+        None => answers.iter().rposition(|a| a.is_some()).map_or(0, |i| i + 1),
+    };
+    if let Some(m) = manager {
+        let hops: Vec<Hop> = answers[..num_hops].iter()
+            .map(|a| Hop {
+                ip: a.map(|(_, from)| from.ip()),
+                rtt: a.map(|(ns, _)| (ns / 1000).min(i32::MAX as u64) as i32),
+            })
+            .collect();
+        if let Err(e) = m.record_hops(host, time, &hops) {
+            warn!("Failed to store the path to '{}': {}", host, e);
+        }
+    }
+
+    match end {
+        Some((i, Answer::Reply)) => Ok(vec![answers[i].unwrap().0]),
+        Some((_, _)) => Err(SENTINEL_UNREACHABLE),
+        None => Err(SENTINEL_TIMEOUT),
+    }
+}
+
+/**
+ * Determines what the given ICMP message says about which of our echo
+ * requests (by sequence number), if any. Time exceeded and unreachable
+ * messages quote the IP header and start of the ICMP message they are about.
+ */
+fn classify(addr: &SocketAddr, msg: &[u8], ident: u16) -> Option<(Answer, u16)> {
+    let (reply, time_exceeded, unreachable, ip_header_len) = match *addr {
+        SocketAddr::V4(_) => (ICMP_ECHO_REPLY, ICMP_TIME_EXCEEDED, ICMP_DEST_UNREACHABLE,
+                              (*msg.get(8)? & 0x0f) as usize * 4),
+        SocketAddr::V6(_) => (ICMPV6_ECHO_REPLY, ICMPV6_TIME_EXCEEDED, ICMPV6_DEST_UNREACHABLE, 40),
+    };
+    let (answer, about) = match *msg.first()? {
+        t if t == reply => (Answer::Reply, msg),
+        t if t == time_exceeded => (Answer::TimeExceeded, msg.get(8 + ip_header_len..)?),
+        t if t == unreachable => (Answer::Unreachable, msg.get(8 + ip_header_len..)?),
+        _ => return None,
+    };
+    if about.len() < 8 || u16::from_be_bytes([about[4], about[5]]) != ident {
+        return None;
+    }
+    Some((answer, u16::from_be_bytes([about[6], about[7]])))
+}
@@ -0,0 +1,118 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * UDP round-trip data collection, against either a UDP echo server (which
+ * sends back whatever we send it) or a DNS server (which answers a minimal
+ * query).
+ */
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use time::precise_time_ns;
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::dns;
+use crate::options::{TargetKind, SENTINEL_ERROR, SENTINEL_RESOLVE, io_error_sentinel};
+use crate::probe::{Attempt, Probe, ProbeSchema};
+use crate::resolve;
+use crate::sockopt;
+use crate::worker::ProbeSettings;
+
+// sequence numbers shared across all attempts so replies can't be confused
+static SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+pub static KIND: TargetKind = TargetKind::of(&UdpPing);
+
+/**
+ * The UDP Ping target, timing a single UDP round-trip to each addr, which is a
+ * `host:port` optionally preceded by the mode, `echo` (the default) or `dns`,
+ * e.g. `dns 8.8.8.8:53`.
+ */
+pub struct UdpPing;
+
+impl Probe for UdpPing {
+    type Target = (bool, SocketAddr);  // whether in `dns` mode, and the address
+
+    const SCHEMA: ProbeSchema = ProbeSchema {
+        kind_id: 4,
+        name: "udpping",
+        columns: &[""],
+        units: &[],
+        default_timeout: 5_000,
+        keeps_hops: false,
+        keeps_ips: true,
+        default_addrs: &["dns 8.8.8.8:53", "dns 1.1.1.1:53"],
+        default_interval: 10_000,
+        default_avg_across: 3,
+    };
+
+    fn resolve(&self, addr: &str, settings: &ProbeSettings) -> Result<Self::Target, i32> {
+        let (dns_mode, host_port) = match addr.split_once(' ') {
+            Some(("dns", hp)) => (true, hp.trim()),
+            Some(("echo", hp)) => (false, hp.trim()),
+            Some(_) => return Err(SENTINEL_ERROR),
+            None => (false, addr),
+        };
+
+        let (host, port) = resolve::split_host_port(host_port).ok_or(SENTINEL_ERROR)?;
+        let sock_addr = resolve::resolve(host, port, settings).ok_or(SENTINEL_RESOLVE)?;
+        Ok((dns_mode, sock_addr))
+    }
+
+    fn host_of(&self, addr: &str) -> Option<(String, u16)> {
+        let host_port = addr.split_once(' ').map_or(addr, |(_, hp)| hp.trim());
+        resolve::split_host_port(host_port).map(|(host, port)| (host.to_owned(), port))
+    }
+
+    fn ip_of(&self, target: &Self::Target) -> Option<IpAddr> {
+        Some(target.1.ip())
+    }
+
+    fn probe_once(&self, _: &str, (dns_mode, sock_addr): Self::Target,
+                  attempt: &Attempt) -> Result<Vec<u64>, i32> {
+        udpping_once(dns_mode, sock_addr, attempt.settings)
+    }
+}
+
+/**
+ * Times a single UDP round-trip to the given address.
+ */
+fn udpping_once(dns_mode: bool, sock_addr: SocketAddr, settings: &ProbeSettings) -> Result<Vec<u64>, i32> {
+    let socket = sockopt::udp_socket(&sock_addr, settings).map_err(|_| SENTINEL_ERROR)?;
+    socket.set_read_timeout(Some(settings.timeout)).map_err(|_| SENTINEL_ERROR)?;
+    socket.connect(sock_addr).map_err(|e| io_error_sentinel(&e))?;
+
+    let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let request = if dns_mode {
+        // ask for the root nameservers, which any DNS server can answer
+        dns::query_packet(seq, "", 2)
+    } else {
+        format!("stabping {}", seq).into_bytes()
+    };
+
+    let start = precise_time_ns();
+    socket.send(&request).map_err(|e| io_error_sentinel(&e))?;
+
+    let mut buf = [0u8; 4096];
+    loop {
+        // an ICMP port unreachable reply shows up as a refused connection
+        let len = socket.recv(&mut buf).map_err(|e| io_error_sentinel(&e))?;
+        let elapsed = precise_time_ns() - start;
+
+        // ignore stale replies to earlier (timed out) requests
+        let matches = if dns_mode {
+            len >= 12 && buf[..2] == request[..2]
+        } else {
+            buf[..len] == request[..]
+        };
+        if matches {
+            return Ok(vec![elapsed]);
+        }
+    }
+}
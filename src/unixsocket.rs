@@ -0,0 +1,157 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Serving the web server on a Unix domain socket, for local tooling and
+ * reverse proxies on the same machine.
+ *
+ * The web server (hyper, under Iron) accepts connections from any
+ * `NetworkListener`, but addresses them (and itself) by IP, so connections on
+ * the socket are given the loopback address (with port 0).
+ */
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::net::{NetworkListener, NetworkStream};
+
+/**
+ * The address connections on the socket are given.
+ */
+fn local_addr() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+}
+
+/**
+ * A listener on a Unix domain socket, for the web server to accept
+ * connections from.
+ */
+#[derive(Clone)]
+pub struct UnixSocketListener {
+    listener: Arc<UnixListener>,
+    read_timeout: Option<Duration>,  // of each connection accepted
+    write_timeout: Option<Duration>,
+}
+
+impl UnixSocketListener {
+    /**
+     * Listens on the socket at the given path, replacing the socket left
+     * there by an earlier run (but nothing else).
+     */
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket"));
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(UnixSocketListener {
+            listener: Arc::new(UnixListener::bind(path)?),
+            read_timeout: None,
+            write_timeout: None,
+        })
+    }
+}
+
+impl NetworkListener for UnixSocketListener {
+    type Stream = UnixSocketStream;
+
+    fn accept(&mut self) -> hyper::Result<UnixSocketStream> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        Ok(UnixSocketStream(Arc::new(stream)))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(local_addr())
+    }
+
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.read_timeout = dur;
+    }
+
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.write_timeout = dur;
+    }
+}
+
+/**
+ * A connection accepted on the socket, shared by the clones the web server
+ * reads from and writes to.
+ */
+#[derive(Clone)]
+pub struct UnixSocketStream(Arc<UnixStream>);
+
+impl Read for UnixSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for UnixSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl NetworkStream for UnixSocketStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(local_addr())
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        match self.0.shutdown(how) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            r => r,
+        }
+    }
+}
+
+#[test]
+fn sockets_are_replaced_but_not_other_files() {
+    let dir = std::env::temp_dir().join(format!("stabping-test-{}-unixsocket", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("stabping.sock");
+
+    // a socket left behind is replaced, and connections on it are accepted
+    drop(UnixSocketListener::bind(&path).unwrap());
+    let mut listener = UnixSocketListener::bind(&path).unwrap();
+    let mut client = UnixStream::connect(&path).unwrap();
+    let mut stream = listener.accept().unwrap();
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    stream.clone().read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    assert_eq!(stream.peer_addr().unwrap(), local_addr());
+
+    // anything else at the path is left alone
+    let other = dir.join("other");
+    fs::write(&other, "").unwrap();
+    assert!(UnixSocketListener::bind(&other).is_err());
+    assert!(other.exists());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -6,27 +6,59 @@
  * details.
  */
 
+use std::collections::{BTreeMap, HashMap};
 use std::thread;
 use std::error::Error;
 use std::fmt;
-use std::io::Read;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::sync::mpsc::RecvTimeoutError;
 
-use iron::prelude::{Request, Response, Iron, IronResult, IronError};
-use iron::middleware::Handler;
+use iron::prelude::{Request, Response, Iron, IronResult, IronError, Chain};
+use iron::{Listening, Protocol};
+use iron::middleware::{Handler, BeforeMiddleware};
 use iron::method::Method;
-use iron::headers::ContentType;
+use iron::headers::{ContentType, CacheControl, CacheDirective, Location};
+use iron::response::WriteBody;
 use iron::modifiers::Header;
 use iron::request::Body;
 use iron::status;
 use router::Router;
-use mount::Mount;
+use mount::{Mount, OriginalUrl};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
 
 use crate::reader::{SPDataReader, DataRequest};
-use crate::persist::TargetManager;
-use crate::options::{MainConfiguration, TargetOptions};
+use crate::persist::{ManagerError, TargetManager};
+use crate::metrics::Metrics;
+use crate::alerts::{Alerts, AlertState, AckRequest};
+use crate::silences::SilenceRequest;
+use crate::incidents::Incidents;
+use crate::health::Health;
+use crate::tags::{TagFilter, Tags};
+use crate::report;
+use crate::grafana;
+use crate::badge;
+use crate::status::{self as status_page, StatusPages};
+use crate::export::{Export, ExportFormat};
+use crate::hosts::{self, HostsError};
+use crate::agent::{Aggregator, AgentError, AgentRound};
+use crate::hops;
+use crate::mtr;
+use crate::auth::{Auth, Access};
+use crate::http;
+use crate::https;
+use crate::https::HttpsServer;
+#[cfg(unix)]
+use crate::unixsocket::UnixSocketListener;
+use crate::wsserver::{Broadcaster, EventStream, Subscription, parse_targets, EVENT_KEEPALIVE};
+use crate::histogram::{QUANTILES, NUM_SLICES, SLICE_SECS};
+use crate::options::{MainConfiguration, TargetOptions, TargetDeclaration, next_nonce, sentinel_name,
+                     column_key, TargetResults, SENTINEL_NODATA};
 
 /**
  * Stabping-specific web error container for use in Iron web responses.
@@ -35,10 +67,15 @@ use crate::options::{MainConfiguration, TargetOptions};
 enum SPWebError {
     NotFound,
     InvalidMethod,
+    #[allow(dead_code)]
     NotImplemented,
     BadRequest,
     ServerError,
     NonceConflict,
+    AddrConflict,
+    TooManyStreams,
+    Unauthorized,
+    Forbidden,
 }
 
 impl Error for SPWebError {
@@ -50,6 +87,10 @@ impl Error for SPWebError {
             SPWebError::BadRequest => "Bad request (malformed or missing fields).",
             SPWebError::ServerError => "Server encountered an error.",
             SPWebError::NonceConflict => "The nonce given does not match the current nonce, refusing update.",
+            SPWebError::AddrConflict => "The address given is already monitored by the target.",
+            SPWebError::TooManyStreams => "Too many event streams are open, try again later.",
+            SPWebError::Unauthorized => "Missing or invalid credentials.",
+            SPWebError::Forbidden => "The credentials given may only be used to read.",
         }
     }
 }
@@ -63,11 +104,102 @@ impl fmt::Display for SPWebError {
             SPWebError::BadRequest => "Bad request (malformed or missing fields).",
             SPWebError::ServerError => "Server encountered an error.",
             SPWebError::NonceConflict => "The nonce given does not match the current nonce, refusing update.",
+            SPWebError::AddrConflict => "The address given is already monitored by the target.",
+            SPWebError::TooManyStreams => "Too many event streams are open, try again later.",
+            SPWebError::Unauthorized => "Missing or invalid credentials.",
+            SPWebError::Forbidden => "The credentials given may only be used to read.",
         })
     }
 }
 
 
+/**
+ * Middleware refusing every request not allowed by the configured auth, asking
+ * browsers for credentials when basic auth is configured, and refusing those
+ * only allowed to read any request changing something.
+ */
+struct AuthCheck {
+    auth: Arc<Auth>,
+    prefix_len: usize,  // segments of the path prefix (see `MainConfiguration::path_prefix`)
+    public_status: bool,  // whether the status page is served (to anyone)
+    public_badges: bool,  // whether badges are served to anyone
+}
+
+/**
+ * Gets what the given request (to the given path, under the path prefix)
+ * needs to be allowed to do: only reading, for getting anything (and
+ * retrieving data from /api/target/<kind> or /grafana).
+ */
+fn required_access(method: &Method, path: &[&str]) -> Access {
+    match *method {
+        Method::Get | Method::Head => Access::Read,
+        Method::Post if path.starts_with(&["api", "target"]) || path.starts_with(&["grafana"]) => Access::Read,
+        _ => Access::Admin,
+    }
+}
+
+impl BeforeMiddleware for AuthCheck {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        // supervisors checking on the health of stabping needn't be authorized
+        let path = req.url.path();
+        if let [endpoint] = path.get(self.prefix_len..).unwrap_or(&[]) {
+            if *endpoint == "healthz" || *endpoint == "readyz" {
+                return Ok(());
+            }
+            // nor anyone looking at the status page, which is public
+            if self.public_status && (*endpoint == "status" || *endpoint == "status.json") && req.method == Method::Get {
+                return Ok(());
+            }
+        }
+        // and badges may be too, to embed them anywhere
+        if let ["badge", _] = path.get(self.prefix_len..).unwrap_or(&[]) {
+            if self.public_badges && req.method == Method::Get {
+                return Ok(());
+            }
+        }
+        // and agents are authorized by the agents endpoint itself (see `agent.rs`)
+        if let ["api", "agents", _] = path.get(self.prefix_len..).unwrap_or(&[]) {
+            if req.method == Method::Post {
+                return Ok(());
+            }
+        }
+
+        let authorization = req.headers.get_raw("Authorization")
+            .and_then(|values| values.first())
+            .and_then(|value| std::str::from_utf8(value).ok());
+        match self.auth.access(authorization) {
+            Some(access) if access >= required_access(&req.method, req.url.path().get(self.prefix_len..).unwrap_or(&[])) =>
+                return Ok(()),
+            Some(_) => {
+                debug!("Refusing {} {} with read-only access.", req.method, req.url);
+                return Err(IronError::new(SPWebError::Forbidden, status::Forbidden));
+            },
+            None => {},
+        }
+
+        debug!("Refusing unauthorized request for {}.", req.url);
+        let mut response = Response::with(status::Unauthorized);
+        let challenge = if self.auth.has_basic() { "Basic realm=\"stabping\"" } else { "Bearer" };
+        response.headers.set_raw("WWW-Authenticate", vec![challenge.as_bytes().to_vec()]);
+        Err(IronError {
+            error: Box::new(SPWebError::Unauthorized),
+            response,
+        })
+    }
+}
+
+/**
+ * The handler of the web server, shared by each listener it is served on.
+ */
+#[derive(Clone)]
+struct SharedChain(Arc<Chain>);
+
+impl Handler for SharedChain {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        self.0.handle(req)
+    }
+}
+
 /**
  * A container for compiled-in binary and text web assets that are to be
  * statically served at the /assets endpoint.
@@ -90,7 +222,7 @@ fn webassets_handler(req: &mut Request) -> IronResult<Response> {
     // serve the appropriate path, or index.html if none specified
     let path = {
         let p = req.url.path()[0];
-        if p.len() > 0 {
+        if !p.is_empty() {
             p
         } else {
             "index.html"
@@ -124,13 +256,13 @@ impl<'a, 'b> JsonBody for Body<'a, 'b> {
 
         self.read_to_string(&mut buf)
             .map_err(|_| {
-                println!("Failed to read request body.");
+                warn!("Failed to read request body.");
                 IronError::new(SPWebError::ServerError, status::InternalServerError)
             })?;
 
         serde_json::from_str::<T>(&buf)
             .map_err(|_| {
-                println!("Failed to parse request body.");
+                warn!("Failed to parse request body.");
                 IronError::new(SPWebError::BadRequest, status::BadRequest)
             })
     }
@@ -147,7 +279,7 @@ struct TargetHandler {
 impl TargetHandler {
     fn new(manager: Arc<TargetManager>) -> Self {
         TargetHandler {
-            manager: manager,
+            manager,
         }
     }
 }
@@ -156,7 +288,7 @@ impl Handler for TargetHandler {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
         match req.method {
             Method::Get => { /* Get Options */
-                println!("Request for {} options.", self.manager.kind.compact_name());
+                debug!("Request for {} options.", self.manager.kind.compact_name());
                 let options_ser = {
                     let options_guard = self.manager.options_read();
                     serde_json::to_string(&*options_guard).unwrap()
@@ -166,13 +298,13 @@ impl Handler for TargetHandler {
             Method::Post => { /* Retrieve Data */
                 // try and get the parameters of the request
                 let dr: DataRequest = req.body.read_json()?;
-                println!("Request for {} data: {:?}", self.manager.kind.compact_name(), dr);
+                debug!("Request for {} data: {:?}", self.manager.kind.compact_name(), dr);
 
                 let body_writer =
                     // try and create a data reader out of this request
                     SPDataReader::new(self.manager.clone(), dr)
                     .ok_or_else(|| {
-                        println!("Failed to create SPDataReader.");
+                        warn!("Failed to create SPDataReader.");
                         IronError::new(SPWebError::BadRequest, status::BadRequest)
                     })?;
 
@@ -195,19 +327,11 @@ impl Handler for TargetHandler {
                 }
 
                 // increment (and wrap-around if necessary) the nonce
-                let new_nonce = {
-                    let (n, over) = new_options.nonce.overflowing_add(1);
-                    if over {
-                        0
-                    } else {
-                        n
-                    }
-                };
+                let new_nonce = next_nonce(new_options.nonce);
                 new_options.nonce = new_nonce;
 
                 // actually update the options via the manager
-                self.manager.options_update(new_options)
-                    .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+                self.manager.options_update(new_options).map_err(update_error)?;
                 Ok(Response::with((format!("{}", new_nonce), status::Ok)))
             },
             _ => Err(IronError::new(SPWebError::InvalidMethod, status::MethodNotAllowed))
@@ -215,18 +339,854 @@ impl Handler for TargetHandler {
     }
 }
 
+/**
+ * An address of a target, as added to or removed from /api/targets.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TargetAddr {
+    kind: String,
+    addr: String,
+    #[serde(default)]
+    tags: Tags,  // of the address, when added
+    #[serde(default)]
+    name: Option<String>,  // display name of the address, when added
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+/**
+ * Makes a response of the given status with the given body as JSON.
+ */
+fn json_response<T: Serialize + ?Sized>(s: status::Status, body: &T) -> Response {
+    let ct = Header(ContentType("application/json".parse().unwrap()));
+    Response::with((s, ct, serde_json::to_string(body).unwrap()))
+}
+
+/**
+ * Parses the query of the given request into its parameters, their values
+ * decoded (see `http::query_params`).
+ */
+fn query_params(req: &Request) -> HashMap<String, String> {
+    http::query_params(req.url.query().unwrap_or(""))
+}
+
+/**
+ * Refuses query parameters other than the given ones (or, if `tags`, those of
+ * a tag filter).
+ */
+fn check_params(params: &HashMap<String, String>, names: &[&str], tags: bool) -> IronResult<()> {
+    match params.keys().all(|k| names.contains(&k.as_str()) || (tags && TagFilter::accepts(k))) {
+        true => Ok(()),
+        false => Err(IronError::new(SPWebError::BadRequest, status::BadRequest)),
+    }
+}
+
+/**
+ * Gets the tag filter (see `TagFilter`) made of the query parameters of the
+ * given request, which may each be given more than once.
+ */
+fn tag_filter(req: &Request) -> TagFilter {
+    let mut filter = TagFilter::default();
+    for (k, v) in http::query_pairs(req.url.query().unwrap_or("")) {
+        if TagFilter::accepts(&k) {
+            filter.add(&k, &v);
+        }
+    }
+    filter
+}
+
+/**
+ * Parses the query of the given request, made only of tag filter parameters.
+ */
+fn parse_tag_filter(req: &Request) -> IronResult<TagFilter> {
+    check_params(&query_params(req), &[], true)?;
+    Ok(tag_filter(req))
+}
+
+/**
+ * Returns whether the given address of the target of the given name (among
+ * the given ones) has tags matching the given filter.
+ */
+fn matches_tags(managers: &[Arc<TargetManager>], filter: &TagFilter, target: &str, addr: &str) -> bool {
+    filter.is_empty() || managers.iter()
+        .find(|tm| tm.kind.compact_name() == target)
+        .is_some_and(|tm| filter.matches(tm.options_read().tags_of(addr)))
+}
+
+/**
+ * Returns a JSON summary (kind, nonce, addrs, their tags, display names and
+ * notes, and whether paused) of the given target, listing only the addrs
+ * matching the given filter.
+ */
+fn target_summary(tm: &TargetManager, filter: &TagFilter) -> serde_json::Value {
+    let options = tm.options_read();
+    let addrs: Vec<&String> = options.addrs.iter().filter(|a| filter.matches(options.tags_of(a))).collect();
+    fn by_addr<T: serde::Serialize>(addrs: &[&String], of: &BTreeMap<String, T>) -> serde_json::Value {
+        addrs.iter().filter_map(|&a| of.get(a).map(|v| (a.clone(), serde_json::json!(v))))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+    serde_json::json!({
+        "kind": tm.kind.compact_name(),
+        "nonce": options.nonce,
+        "addrs": addrs,
+        "tags": by_addr(&addrs, &options.tags),
+        "names": by_addr(&addrs, &options.names),
+        "notes": by_addr(&addrs, &options.notes),
+        "paused": options.paused,
+    })
+}
+
+/**
+ * Responds to a failure to update options: options that don't make sense are
+ * a bad request, failing to store them the server's error.
+ */
+fn update_error(e: ManagerError) -> IronError {
+    match e {
+        ManagerError::InvalidOptions(_) => IronError::new(SPWebError::BadRequest, status::BadRequest),
+        _ => IronError::new(SPWebError::ServerError, status::InternalServerError),
+    }
+}
+
+/**
+ * Updates the options of the given target to the given ones (with the next
+ * nonce), responding with the target's new summary.
+ */
+fn update_target(tm: &TargetManager, mut new_options: TargetOptions,
+                 s: status::Status) -> IronResult<Response> {
+    new_options.nonce = next_nonce(tm.options_read().nonce);
+    tm.options_update(new_options).map_err(update_error)?;
+
+    Ok(json_response(s, &target_summary(tm, &TagFilter::default())))
+}
+
+/**
+ * Handler for the /api/targets endpoint that lists all targets, and adds
+ * (POST) or removes (DELETE) an address of a target, whose worker then starts
+ * or stops probing it with the next round.
+ */
+struct TargetsHandler {
+    managers: Vec<Arc<TargetManager>>,
+}
+
+impl TargetsHandler {
+    fn find(&self, kind: &str) -> IronResult<&TargetManager> {
+        self.managers.iter().find(|tm| tm.kind.compact_name() == kind)
+            .map(|tm| &**tm)
+            .ok_or_else(|| IronError::new(SPWebError::NotFound, status::NotFound))
+    }
+}
+
+impl Handler for TargetsHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match req.method {
+            Method::Get => { /* List Targets (only the addresses matching any tags given) */
+                let filter = parse_tag_filter(req)?;
+                let summaries: Vec<_> = self.managers.iter().map(|tm| target_summary(tm, &filter)).collect();
+                Ok(json_response(status::Ok, &summaries))
+            },
+            Method::Post => { /* Add Address */
+                let ta: TargetAddr = req.body.read_json()?;
+                let tm = self.find(&ta.kind)?;
+                if ta.addr.is_empty() {
+                    return Err(IronError::new(SPWebError::BadRequest, status::BadRequest));
+                }
+
+                let mut new_options = tm.options_read().clone();
+                if new_options.addrs.contains(&ta.addr) {
+                    return Err(IronError::new(SPWebError::AddrConflict, status::Conflict));
+                }
+                info!("Adding {} to {}.", ta.addr, ta.kind);
+                if !ta.tags.is_empty() {
+                    new_options.tags.insert(ta.addr.clone(), ta.tags);
+                }
+                if let Some(name) = ta.name.filter(|n| !n.is_empty()) {
+                    new_options.names.insert(ta.addr.clone(), name);
+                }
+                if let Some(notes) = ta.notes.filter(|n| !n.is_empty()) {
+                    new_options.notes.insert(ta.addr.clone(), notes);
+                }
+                new_options.addrs.push(ta.addr);
+                update_target(tm, new_options, status::Created)
+            },
+            Method::Delete => { /* Remove Address */
+                let ta: TargetAddr = req.body.read_json()?;
+                let tm = self.find(&ta.kind)?;
+
+                let mut new_options = tm.options_read().clone();
+                let i = new_options.addrs.iter().position(|a| *a == ta.addr)
+                    .ok_or_else(|| IronError::new(SPWebError::NotFound, status::NotFound))?;
+                info!("Removing {} from {}.", ta.addr, ta.kind);
+                new_options.addrs.remove(i);
+                new_options.forget_addr(&ta.addr);
+                update_target(tm, new_options, status::Ok)
+            },
+            _ => Err(IronError::new(SPWebError::InvalidMethod, status::MethodNotAllowed))
+        }
+    }
+}
+
+/**
+ * Handler for each /api/targets/<kind> endpoint that (PATCH) updates only the
+ * given options of the target, like those declared in the configuration.
+ */
+fn patch_target_handler(tm: &TargetManager, req: &mut Request) -> IronResult<Response> {
+    let declared: TargetDeclaration = req.body.read_json()?;
+    let new_options = declared.apply_to(&tm.options_read());
+    match new_options {
+        Some(options) => update_target(tm, options, status::Ok),
+        None => {
+            // nothing changed, so keep the current nonce (and rounds in flight)
+            Ok(json_response(status::Ok, &target_summary(tm, &TagFilter::default())))
+        },
+    }
+}
+
+/**
+ * Handler for each /api/targets/<kind>/hosts endpoint that (POST) adds the
+ * addresses listed in the hosts file given as the body (see `hosts.rs`) to
+ * the target, or updates those already there, and with `replace=true` also
+ * removes those not listed. Responds with what changed and the target's new
+ * summary, or with the line that couldn't be parsed.
+ */
+fn hosts_handler(tm: &TargetManager, req: &mut Request) -> IronResult<Response> {
+    let params = query_params(req);
+    check_params(&params, &["replace"], false)?;
+    let replace = match params.get("replace").map(String::as_str) {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => return Err(IronError::new(SPWebError::BadRequest, status::BadRequest)),
+    };
+    let mut body = String::new();
+    req.body.read_to_string(&mut body)
+        .map_err(|_| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+
+    let summary = hosts::parse_hosts(&body)
+        .and_then(|entries| hosts::import_hosts(tm, entries, replace))
+        .map_err(|e| match e {
+            HostsError::Parse(..) => IronError::new(SPWebError::BadRequest, (status::BadRequest, e.description())),
+            HostsError::Store(_) => IronError::new(SPWebError::ServerError, status::InternalServerError),
+        })?;
+    info!("Imported hosts into {}: {} added, {} updated, {} removed.", tm.kind.compact_name(),
+          summary.added.len(), summary.updated.len(), summary.removed.len());
+    let body = serde_json::json!({
+        "added": summary.added,
+        "updated": summary.updated,
+        "removed": summary.removed,
+        "target": target_summary(tm, &TagFilter::default()),
+    });
+    Ok(json_response(status::Ok, &body))
+}
+
+/**
+ * Handler for the /api/agents endpoint that (GET) lists what has been seen of
+ * each site of agents.
+ */
+fn agents_handler(aggregator: &Aggregator) -> IronResult<Response> {
+    let body = serde_json::json!({ "sites": aggregator.sites() });
+    Ok(json_response(status::Ok, &body))
+}
+
+/**
+ * Handler for the /api/agents/<site> endpoint that (POST) takes in a round of
+ * results of the given site pushed by an agent (see `agent.rs`), with
+ * `replayed=true` if it is being replayed after failing to push, responding
+ * with the addresses it is recorded under, or with `202 Accepted` if it is
+ * quarantined as that of an unknown agent.
+ */
+fn agent_push_handler(aggregator: &Aggregator, auth: &Auth, req: &mut Request) -> IronResult<Response> {
+    let site = req.extensions.get::<Router>().and_then(|r| r.find("site")).unwrap_or("").to_owned();
+    let params = query_params(req);
+    check_params(&params, &["replayed"], false)?;
+    let replayed = match params.get("replayed").map(String::as_str) {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => return Err(IronError::new(SPWebError::BadRequest, status::BadRequest)),
+    };
+    let authorization = req.headers.get_raw("Authorization")
+        .and_then(|values| values.first())
+        .and_then(|value| std::str::from_utf8(value).ok());
+    let known = aggregator.agents().knows(auth, &site, authorization, &https::client_names(&req.remote_addr));
+    if !known && !aggregator.agents().quarantines() {
+        debug!("Refusing results of unknown agent of site {}.", site);
+        return Err(IronError::new(SPWebError::Unauthorized, status::Unauthorized));
+    }
+
+    let mut body = String::new();
+    req.body.read_to_string(&mut body)
+        .map_err(|_| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+    let round: AgentRound = serde_json::from_str(&body)
+        .map_err(|_| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+
+    let to_error = |e: AgentError| match e {
+        AgentError::UnknownTarget(_) => IronError::new(SPWebError::NotFound, (status::NotFound, e.description())),
+        AgentError::InvalidSite => IronError::new(SPWebError::BadRequest, (status::BadRequest, e.description())),
+        AgentError::Store(_) => IronError::new(SPWebError::ServerError, status::InternalServerError),
+        AgentError::Closed => IronError::new(SPWebError::ServerError, status::ServiceUnavailable),
+    };
+    if !known {
+        aggregator.quarantine(&site, round).map_err(to_error)?;
+        return Ok(json_response(status::Accepted, &serde_json::json!({ "quarantined": true })));
+    }
+    let addrs = aggregator.receive(&site, round, replayed).map_err(to_error)?;
+    Ok(json_response(status::Ok, &serde_json::json!({ "addrs": addrs })))
+}
+
+/**
+ * Handler for each /api/targets/<kind>/probe endpoint that runs one round of
+ * the target immediately (neither persisted nor distributed), responding with
+ * its results once every attempt is done.
+ */
+fn probe_target_handler(tm: &TargetManager) -> IronResult<Response> {
+    info!("Running an on-demand round of {}.", tm.kind.compact_name());
+    let results = tm.kind.run_round(tm);
+
+    // the options changed during the round, so its results no longer apply
+    let (addrs, columns) = {
+        let options = tm.options_read();
+        if options.nonce != results.nonce {
+            return Err(IronError::new(SPWebError::NonceConflict, status::Conflict));
+        }
+        (options.addrs.clone(), tm.kind.columns(&options))
+    };
+    let body = round_json(tm.kind.compact_name(), &results, &addrs, &columns);
+    Ok(json_response(status::Ok, &body))
+}
+
+/**
+ * Handler for the /api/live endpoint streaming rounds (of the targets given
+ * like `targets=a,b`, or of all) as server-sent events, each an event `round`
+ * with the same JSON as sent to /ws/live, or at /api/live/raw (for the web
+ * client) every raw result as an event `raw`. Each stream holds one of the
+ * web server's threads for as long as it is open, so at most `max` are.
+ */
+fn events_handler(broadcaster: &Arc<Broadcaster>, max: usize, raw: bool,
+                  req: &mut Request) -> IronResult<Response> {
+    let params = query_params(req);
+    let subscription = if !raw {
+        parse_targets(&params).map(Subscription::Live)
+    } else if params.is_empty() {
+        Some(Subscription::Raw)
+    } else {
+        None
+    }.ok_or_else(|| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+    let stream = broadcaster.subscribe_events(subscription, max)
+        .ok_or_else(|| IronError::new(SPWebError::TooManyStreams, status::ServiceUnavailable))?;
+
+    let r = Response::with((status::Ok, Header(ContentType("text/event-stream".parse().unwrap())),
+                            Header(CacheControl(vec![CacheDirective::NoCache]))));
+    Ok(Response {
+        status: r.status,
+        headers: r.headers,
+        extensions: r.extensions,
+        body: Some(Box::new(stream)),
+    })
+}
+
+impl WriteBody for EventStream {
+    /**
+     * Writes each round as it comes, until the client goes away (or the
+     * stream is closed), with a comment whenever there is nothing to send to
+     * keep the connection open.
+     */
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        res.write_all(b"retry: 5000\n\n")?;
+        res.flush()?;
+        loop {
+            match self.next(EVENT_KEEPALIVE) {
+                Ok(data) => write!(res, "event: {}\ndata: {}\n\n", self.event(), data)?,
+                Err(RecvTimeoutError::Timeout) => res.write_all(b": keepalive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            res.flush()?;
+        }
+    }
+}
+
+/**
+ * Describes a round of results of a target as JSON: the values of each
+ * address probed in it (by the given addrs and columns of its layout), and the
+ * failure, if any.
+ */
+pub fn round_json(target: &str, results: &TargetResults, addrs: &[String], columns: &[String]) -> serde_json::Value {
+    let per_addr: Vec<_> = addrs.iter().zip(results.vals.chunks(columns.len().max(1)))
+        // addrs not probed in this round have no values
+        .filter(|(_, vals)| vals[0] != SENTINEL_NODATA)
+        .map(|(addr, vals)| {
+            let values: serde_json::Map<String, serde_json::Value> = columns.iter().zip(vals)
+                .map(|(c, &v)| {
+                    // the primary column is named `value`, like for InfluxDB
+                    let name = if c.is_empty() { "value" } else { c.as_str() };
+                    (name.to_owned(), v.into())
+                })
+                .collect();
+            serde_json::json!({
+                "addr": addr,
+                "values": values,
+                "failure": if vals[0] < 0 { Some(sentinel_name(vals[0])) } else { None },
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "target": target,
+        "nonce": results.nonce,
+        "timestamp": results.timestamp,
+        "addrs": per_addr,
+    })
+}
+
+/**
+ * Handler for the /api/alerts endpoint listing alerts, optionally filtered by
+ * `target`, `state` and/or tag (of their address) query parameters, or with
+ * `from` and/or `to` (see `report::parse_range`) their history in that range
+ * instead.
+ */
+fn alerts_handler(alerts: &Alerts, managers: &[Arc<TargetManager>], req: &mut Request) -> IronResult<Response> {
+    let params = query_params(req);
+    check_params(&params, &["target", "state", "from", "to"], true)?;
+    let state = match params.get("state").map(String::as_str) {
+        None => None,
+        Some("firing") => Some(AlertState::Firing),
+        Some("resolved") => Some(AlertState::Resolved),
+        Some(_) => return Err(IronError::new(SPWebError::BadRequest, status::BadRequest)),
+    };
+    let target = params.get("target").map(String::as_str);
+    let (from, to) = (params.get("from").map(String::as_str), params.get("to").map(String::as_str));
+    let filter = tag_filter(req);
+
+    if from.is_some() || to.is_some() {
+        let (from, to) = match (state, report::parse_range(from, to)) {
+            (None, Some(range)) => range,
+            _ => return Err(IronError::new(SPWebError::BadRequest, status::BadRequest)),
+        };
+        let mut history = alerts.history(from, to, target)
+            .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+        history.retain(|t| matches_tags(managers, &filter, &t.target, &t.addr));
+        return Ok(json_response(status::Ok, &history));
+    }
+
+    let mut list = alerts.list(target, state);
+    list.retain(|a| matches_tags(managers, &filter, &a.target, &a.addr));
+    Ok(json_response(status::Ok, &list))
+}
+
+/**
+ * Handler for the /api/alerts/ack endpoint that (POST) acknowledges the
+ * firing alert given as the body (see `AckRequest`), responding with it.
+ */
+fn ack_handler(alerts: &Alerts, req: &mut Request) -> IronResult<Response> {
+    let request: AckRequest = req.body.read_json()?;
+    let alert = alerts.acknowledge(request, Local::now().timestamp())
+        .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?
+        .ok_or_else(|| IronError::new(SPWebError::NotFound, status::NotFound))?;
+    Ok(json_response(status::Ok, &alert))
+}
+
+/**
+ * Handler for the /api/silences endpoint that lists (GET) the silences not
+ * yet over, or (POST) adds the silence given as the body (see
+ * `SilenceRequest`), responding with it.
+ */
+fn silences_handler(alerts: &Alerts, req: &mut Request) -> IronResult<Response> {
+    let now = Local::now().timestamp();
+    if req.method != Method::Post {
+        return Ok(json_response(status::Ok, &alerts.silences(now)));
+    }
+    let request: SilenceRequest = req.body.read_json()?;
+    let silence = alerts.silence(request, now)
+        .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?
+        .ok_or_else(|| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+    Ok(json_response(status::Created, &silence))
+}
+
+/**
+ * Handler for the /api/silences/<id> endpoint that (DELETE) ends the silence
+ * of the given id before its time.
+ */
+fn unsilence_handler(alerts: &Alerts, req: &mut Request) -> IronResult<Response> {
+    let id = req.extensions.get::<Router>().and_then(|r| r.find("id")).and_then(|id| id.parse().ok())
+        .ok_or_else(|| IronError::new(SPWebError::NotFound, status::NotFound))?;
+    match alerts.unsilence(id) {
+        Ok(true) => Ok(Response::with(status::NoContent)),
+        Ok(false) => Err(IronError::new(SPWebError::NotFound, status::NotFound)),
+        Err(_) => Err(IronError::new(SPWebError::ServerError, status::InternalServerError)),
+    }
+}
+
+/**
+ * Handler for the /grafana/search endpoint listing the metrics matching the
+ * search of a Grafana JSON datasource (see `grafana::search`).
+ */
+fn grafana_search_handler(managers: &[Arc<TargetManager>], req: &mut Request) -> IronResult<Response> {
+    let request: grafana::SearchRequest = req.body.read_json()?;
+    Ok(json_response(status::Ok, &grafana::search(managers, &request)))
+}
+
+/**
+ * Handler for the /grafana/query endpoint answering the query of a Grafana
+ * JSON datasource with the values of its metrics (see `grafana::query`).
+ */
+fn grafana_query_handler(managers: &[Arc<TargetManager>], req: &mut Request) -> IronResult<Response> {
+    let request: grafana::QueryRequest = req.body.read_json()?;
+    let series = grafana::query(managers, &request).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidInput => IronError::new(SPWebError::BadRequest, status::BadRequest),
+        _ => IronError::new(SPWebError::ServerError, status::InternalServerError),
+    })?;
+    Ok(json_response(status::Ok, &series))
+}
+
+/**
+ * Handler for each /badge/<kind>.svg endpoint serving a badge (see
+ * `badge.rs`) of the current state of the target's addresses (those matching
+ * any tags given, or just `addr`), or with `show=uptime`, of their uptime
+ * over the last `days` (30 by default), labelled `label` (the target's name
+ * by default).
+ */
+fn badge_handler(tm: &TargetManager, metrics: &Metrics, req: &mut Request) -> IronResult<Response> {
+    let bad_request = || IronError::new(SPWebError::BadRequest, status::BadRequest);
+    let mut params = query_params(req);
+    check_params(&params, &["show", "days", "label", "addr"], true)?;
+    let uptime = match params.get("show").map(String::as_str) {
+        None | Some("state") => false,
+        Some("uptime") => true,
+        Some(_) => return Err(bad_request()),
+    };
+    let days = match params.get("days") {
+        Some(d) => d.parse::<u32>().ok().filter(|&d| d > 0).ok_or_else(bad_request)?,
+        None => 30,
+    };
+    let label = params.remove("label").unwrap_or_else(|| tm.kind.compact_name().to_owned());
+    let addr = params.remove("addr");
+    let filter = tag_filter(req);
+
+    let addrs: Vec<String> = {
+        let options = tm.options_read();
+        options.addrs.iter()
+            .filter(|a| addr.as_ref().is_none_or(|addr| addr == *a) && filter.matches(options.tags_of(a)))
+            .cloned()
+            .collect()
+    };
+    let (message, color) = if uptime {
+        let now = Local::now().timestamp();
+        let mut r = report::report(tm, now - days as i64 * 86400, now)
+            .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+        r.addrs.retain(|a| addrs.contains(&a.addr));
+        badge::uptime(&r.addrs)
+    } else {
+        let up: Vec<bool> = metrics.snapshot().iter()
+            .filter(|a| a.kind.compact_name() == tm.kind.compact_name() && addrs.contains(&a.addr))
+            .map(|a| a.values.first().is_some_and(|&(_, v)| v >= 0))
+            .collect();
+        badge::state(&up)
+    };
+
+    // never cached, so that embedded badges stay current
+    let ct = Header(ContentType("image/svg+xml".parse().unwrap()));
+    let cc = Header(CacheControl(vec![CacheDirective::NoCache, CacheDirective::MaxAge(0)]));
+    Ok(Response::with((status::Ok, ct, cc, badge::render(&label, &message, color))))
+}
+
+/**
+ * Handler for the /status (and /status.json) endpoint serving the public
+ * status page (see `status.rs`) as HTML (or JSON).
+ */
+fn status_handler(pages: &StatusPages, json: bool) -> IronResult<Response> {
+    let page = pages.get(Local::now().timestamp())
+        .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+    Ok(if json {
+        json_response(status::Ok, &*page)
+    } else {
+        let ct = Header(ContentType("text/html; charset=utf-8".parse().unwrap()));
+        Response::with((status::Ok, ct, status_page::render_html(&page)))
+    })
+}
+
+/**
+ * Handler for the /grafana/annotations endpoint answering the annotation
+ * query of a Grafana JSON datasource with incidents and alert changes (see
+ * `grafana::annotations`).
+ */
+fn grafana_annotations_handler(incidents: &Incidents, alerts: &Alerts, req: &mut Request) -> IronResult<Response> {
+    let request: grafana::AnnotationsRequest = req.body.read_json()?;
+    let annotations = grafana::annotations(incidents, alerts, &request).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidInput => IronError::new(SPWebError::BadRequest, status::BadRequest),
+        _ => IronError::new(SPWebError::ServerError, status::InternalServerError),
+    })?;
+    Ok(json_response(status::Ok, &annotations))
+}
+
+/**
+ * Handler for the /api/incidents endpoint listing incidents, optionally
+ * filtered by `target`, `state` (`open` or `ended`) and/or tag (of their
+ * address) query parameters.
+ */
+fn incidents_handler(incidents: &Incidents, managers: &[Arc<TargetManager>],
+                     req: &mut Request) -> IronResult<Response> {
+    let params = query_params(req);
+    check_params(&params, &["target", "state"], true)?;
+    let ended = match params.get("state").map(String::as_str) {
+        None => None,
+        Some("open") => Some(false),
+        Some("ended") => Some(true),
+        Some(_) => return Err(IronError::new(SPWebError::BadRequest, status::BadRequest)),
+    };
+    let target = params.get("target").map(String::as_str);
+    let filter = tag_filter(req);
+
+    let mut list = incidents.list(target, ended);
+    list.retain(|i| matches_tags(managers, &filter, &i.target, &i.addr));
+    Ok(json_response(status::Ok, &list))
+}
+
+/**
+ * Handler for each /api/report/<kind> endpoint reporting on the target's data
+ * in the range given by the `from` and `to` query parameters (see
+ * `report::parse_range`), of the addresses matching any tags given.
+ */
+fn report_handler(tm: &TargetManager, req: &mut Request) -> IronResult<Response> {
+    let params = query_params(req);
+    check_params(&params, &["from", "to"], true)?;
+    let filter = tag_filter(req);
+    let (from, to) = report::parse_range(params.get("from").map(String::as_str), params.get("to").map(String::as_str))
+        .ok_or_else(|| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+    debug!("Request for {} report from {} to {}.", tm.kind.compact_name(), from, to);
+
+    let mut r = report::report(tm, from, to)
+        .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+    let options = tm.options_read();
+    r.addrs.retain(|a| filter.matches(options.tags_of(&a.addr)));
+    Ok(json_response(status::Ok, &r))
+}
+
+/**
+ * Handler for each /api/targets/<kind>/export.csv (.ndjson, .parquet) endpoint,
+ * streaming every round of every current address of the target (matching any
+ * tags given) from `from` to `to` (as in reports) in the given format (see
+ * `export.rs`).
+ */
+fn export_handler(tm: &Arc<TargetManager>, format: ExportFormat,
+                  req: &mut Request) -> IronResult<Response> {
+    let params = query_params(req);
+    check_params(&params, &["from", "to"], true)?;
+    let tags = tag_filter(req);
+    let (from, to) = report::parse_range(params.get("from").map(String::as_str), params.get("to").map(String::as_str))
+        .ok_or_else(|| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+    debug!("Request for {} {:?} export from {} to {}.", tm.kind.compact_name(), format, from, to);
+
+    let r = Response::with((status::Ok, Header(ContentType(format.content_type().parse().unwrap()))));
+    Ok(Response {
+        status: r.status,
+        headers: r.headers,
+        extensions: r.extensions,
+        body: Some(Box::new(Export { tm: tm.clone(), format, from, to, tags })),
+    })
+}
+
+/**
+ * Handler for each /api/histogram/<kind> endpoint, responding with the
+ * histogram of every address of the target over the rolling window: its
+ * quantiles and the (lowest value, highest value, count) of each bucket
+ * holding any values, in microseconds. Only addresses matching any tags given
+ * are.
+ */
+fn histogram_handler(tm: &TargetManager, req: &mut Request) -> IronResult<Response> {
+    let filter = parse_tag_filter(req)?;
+    let timestamp = Local::now().timestamp();
+    let tags = tm.options_read().tags.clone();
+    let per_addr: Vec<_> = tm.histograms(timestamp).into_iter()
+        .filter(|(addr, _, _)| filter.matches(tags.get(addr)))
+        .map(|(addr, window, _)| {
+            let quantiles: serde_json::Map<String, serde_json::Value> = QUANTILES.iter()
+                .filter_map(|&(q, name)| window.quantile(q).map(|v| (name.to_owned(), v.into())))
+                .collect();
+            serde_json::json!({
+                "addr": addr,
+                "count": window.count,
+                "quantiles": quantiles,
+                "buckets": window.buckets(),
+            })
+        })
+        .collect();
+
+    let body = serde_json::json!({
+        "target": tm.kind.compact_name(),
+        "timestamp": timestamp,
+        "window": NUM_SLICES * SLICE_SECS,
+        "addrs": per_addr,
+    });
+    Ok(json_response(status::Ok, &body))
+}
+
+/**
+ * Handler for each /api/hops/<kind> endpoint (of kinds keeping hops),
+ * responding with the paths mapped to the given `addr` from `from` to `to`
+ * (as in reports), hop by hop, with round-trips in microseconds.
+ */
+fn hops_handler(tm: &TargetManager, req: &mut Request) -> IronResult<Response> {
+    let mut params = query_params(req);
+    check_params(&params, &["addr", "from", "to"], false)?;
+    let addr = params.remove("addr").ok_or_else(|| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+    let (from, to) = report::parse_range(params.get("from").map(String::as_str), params.get("to").map(String::as_str))
+        .ok_or_else(|| IronError::new(SPWebError::BadRequest, status::BadRequest))?;
+    debug!("Request for {} hops of '{}' from {} to {}.", tm.kind.compact_name(), addr, from, to);
+
+    let rounds = tm.hops_in_range(&addr, from, to)
+        .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+    let body = serde_json::json!({
+        "target": tm.kind.compact_name(),
+        "addr": addr,
+        "rounds": rounds,
+    });
+    Ok(json_response(status::Ok, &body))
+}
+
+/**
+ * Handler for each /api/ips/<kind> endpoint (of kinds keeping IPs), responding
+ * with the IPs probed in each round of the given `addr` from `from` to `to`
+ * (as in reports) with the primary value of the last attempt at each, and the
+ * rounds in which the address moved to other IPs (see `hops::ip_changes`).
+ */
+fn ips_handler(tm: &TargetManager, req: &mut Request) -> IronResult<Response> {
+    let bad_request = || IronError::new(SPWebError::BadRequest, status::BadRequest);
+    let mut params = query_params(req);
+    check_params(&params, &["addr", "from", "to"], false)?;
+    let addr = params.remove("addr").ok_or_else(bad_request)?;
+    let (from, to) = report::parse_range(params.get("from").map(String::as_str), params.get("to").map(String::as_str))
+        .ok_or_else(bad_request)?;
+    debug!("Request for {} IPs of '{}' from {} to {}.", tm.kind.compact_name(), addr, from, to);
+
+    let rounds = tm.ips_in_range(&addr, from, to)
+        .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+    let changes: Vec<_> = hops::ip_changes(&rounds).into_iter()
+        .map(|(time, ips)| serde_json::json!({"time": time, "ips": ips}))
+        .collect();
+    let rounds: Vec<_> = rounds.iter()
+        .map(|r| serde_json::json!({
+            "time": r.time,
+            "ips": r.hops.iter().map(|h| serde_json::json!({"ip": h.ip, "value": h.rtt})).collect::<Vec<_>>(),
+        }))
+        .collect();
+    let body = serde_json::json!({
+        "target": tm.kind.compact_name(),
+        "addr": addr,
+        "rounds": rounds,
+        "changes": changes,
+    });
+    Ok(json_response(status::Ok, &body))
+}
+
+/**
+ * Handler for each /api/mtr/<kind> endpoint (of kinds keeping hops),
+ * responding with MTR-style statistics of each hop of the paths mapped to the
+ * given `addr` from `from` to `to`, in steps of `step` seconds (see `mtr`).
+ */
+fn mtr_handler(tm: &TargetManager, req: &mut Request) -> IronResult<Response> {
+    let bad_request = || IronError::new(SPWebError::BadRequest, status::BadRequest);
+    let mut params = query_params(req);
+    check_params(&params, &["addr", "from", "to", "step"], false)?;
+    let addr = params.remove("addr").ok_or_else(bad_request)?;
+    let step = params.get("step").map(|s| s.parse::<i64>()).transpose().map_err(|_| bad_request())?;
+    let (from, to) = report::parse_range(params.get("from").map(String::as_str), params.get("to").map(String::as_str))
+        .ok_or_else(bad_request)?;
+    debug!("Request for {} MTR statistics of '{}' from {} to {}.",
+           tm.kind.compact_name(), addr, from, to);
+
+    let r = mtr::mtr(tm, &addr, from, to, step)
+        .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+    Ok(json_response(status::Ok, &r))
+}
+
+/**
+ * Handler for each /api/rollups/<kind> endpoint, responding with the buckets
+ * of the given rollup `tier` (`1m` or `1h`) of the given `addr` and `column`
+ * (the primary value if not given) from `from` to `to` (as in reports): the
+ * min, mean and max of each, and its loss (the percentage of rounds failed).
+ */
+fn rollups_handler(tm: &TargetManager, req: &mut Request) -> IronResult<Response> {
+    let bad_request = || IronError::new(SPWebError::BadRequest, status::BadRequest);
+    let mut params = query_params(req);
+    check_params(&params, &["addr", "column", "tier", "from", "to"], false)?;
+    let addr = params.remove("addr").ok_or_else(bad_request)?;
+    let column = params.remove("column").unwrap_or_default();
+    let tier = params.remove("tier").ok_or_else(bad_request)?;
+    let (from, to) = report::parse_range(params.get("from").map(String::as_str), params.get("to").map(String::as_str))
+        .ok_or_else(bad_request)?;
+    debug!("Request for {} rollups ({}) of '{}' from {} to {}.",
+           tm.kind.compact_name(), tier, addr, from, to);
+
+    let buckets = tm.rollups_in_range(&tier, &column_key(&addr, &column), from, to)
+        .ok_or_else(bad_request)?
+        .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))?;
+    let buckets: Vec<_> = buckets.iter().map(|b| serde_json::json!({
+        "time": b.time,
+        "count": b.count,
+        "min": b.min,
+        "mean": b.mean,
+        "max": b.max,
+        "loss": 100.0 * b.failed as f64 / b.count as f64,
+    })).collect();
+    let body = serde_json::json!({
+        "target": tm.kind.compact_name(),
+        "addr": addr,
+        "column": column,
+        "tier": tier,
+        "buckets": buckets,
+    });
+    Ok(json_response(status::Ok, &body))
+}
+
+/**
+ * Handler for each /api/schema/<kind> endpoint, responding with every layout
+ * of the target's results recorded (see `schema.rs`), oldest first: the
+ * addresses and columns as of each nonce, to label data requested (or results
+ * received) with it.
+ */
+fn schema_handler(tm: &TargetManager) -> IronResult<Response> {
+    let body = serde_json::json!({
+        "target": tm.kind.compact_name(),
+        "nonce": tm.options_read().nonce,
+        "layouts": tm.layouts(),
+    });
+    Ok(json_response(status::Ok, &body))
+}
 
 /**
  * Creates and starts the web server given the configuration (with the web
- * port) and a list of target managers.
+ * port), a list of target managers, the metrics, alerts and incidents of
+ * those targets, the broadcaster of their live results, the intake of the
+ * results pushed by agents, who may use it, and what to serve HTTPS with (if
+ * at all).
  */
+#[allow(clippy::too_many_arguments)]
 pub fn web_server<'a, T>(configuration: Arc<RwLock<MainConfiguration>>,
-                         targets: T) -> thread::JoinHandle<()>
+                         targets: T,
+                         metrics: Arc<Metrics>,
+                         alerts: Arc<Alerts>,
+                         incidents: Arc<Incidents>,
+                         broadcaster: Arc<Broadcaster>,
+                         health: Arc<Health>,
+                         aggregator: Arc<Aggregator>,
+                         auth: Arc<Auth>,
+                         https: Option<HttpsServer>) -> thread::JoinHandle<()>
                          where T: Iterator<Item=&'a Arc<TargetManager>> {
     let mut router = Router::new();
 
-    // serve index.html at root
-    router.get("/", webassets_handler, "index");
+    /*
+     * serve index.html at root (under the path prefix, which is redirected to
+     * with a trailing slash so that the links of the page resolve under it)
+     */
+    let index_handler = |req: &mut Request| -> IronResult<Response> {
+        let path = req.extensions.get::<OriginalUrl>().map_or_else(String::new, |u| u.path().join("/"));
+        if !path.is_empty() && !path.ends_with('/') {
+            let mut response = Response::with(status::MovedPermanently);
+            response.headers.set(Location(format!("/{}/", path)));
+            return Ok(response);
+        }
+        webassets_handler(req)
+    };
+    router.get("/", index_handler, "index");
 
     /*
      * serve the websockets port at /api/config/ws_port so that clients know
@@ -238,25 +1198,250 @@ pub fn web_server<'a, T>(configuration: Arc<RwLock<MainConfiguration>>,
     };
     router.get("/api/config/ws_port", ws_port_handler, "api_config_ws_port");
 
+    // and the token to connect with, as browsers can't authorize websockets otherwise
+    let ws_token_auth = auth.clone();
+    let ws_token_handler = move |_: &mut Request| -> IronResult<Response> {
+        Ok(Response::with((status::Ok, ws_token_auth.ws_token())))
+    };
+    router.get("/api/config/ws_token", ws_token_handler, "api_config_ws_token");
+
+    // serve the latest results of all targets (or of the addresses matching any tags given) at /metrics
+    let badge_metrics = metrics.clone();
+    let metrics_handler = move |req: &mut Request| -> IronResult<Response> {
+        let filter = parse_tag_filter(req)?;
+        let ct = Header(ContentType("text/plain; version=0.0.4".parse().unwrap()));
+        Ok(Response::with((status::Ok, ct, metrics.render(&filter))))
+    };
+    router.get("/metrics", metrics_handler, "metrics");
+
+    /*
+     * serve the health of stabping itself at /healthz, and whether it is
+     * ready at /readyz (each unavailable when not)
+     */
+    let ready_health = health.clone();
+    let healthz_handler = move |_: &mut Request| -> IronResult<Response> {
+        let report = health.report();
+        let status = if report.healthy { status::Ok } else { status::ServiceUnavailable };
+        Ok(json_response(status, &report))
+    };
+    router.get("/healthz", healthz_handler, "healthz");
+    let readyz_handler = move |_: &mut Request| -> IronResult<Response> {
+        Ok(if ready_health.is_ready() {
+            Response::with((status::Ok, "ready"))
+        } else {
+            Response::with((status::ServiceUnavailable, "not ready"))
+        })
+    };
+    router.get("/readyz", readyz_handler, "readyz");
+
+    let (grafana_alerts, grafana_incidents) = (alerts.clone(), incidents.clone());
+
+    // serve the alerts of all targets at /api/alerts
+    let managers: Vec<Arc<TargetManager>> = targets.cloned().collect();
+    let alerts_managers = managers.clone();
+    let (ack_alerts, unsilence_alerts) = (alerts.clone(), alerts.clone());
+    let (silences_alerts, silence_alerts) = (alerts.clone(), alerts.clone());
+    router.get("/api/alerts", move |req: &mut Request| alerts_handler(&alerts, &alerts_managers, req),
+               "api_alerts");
+
+    // acknowledge alerts at /api/alerts/ack, and silence them at /api/silences
+    router.post("/api/alerts/ack", move |req: &mut Request| ack_handler(&ack_alerts, req), "api_alerts_ack");
+    router.get("/api/silences", move |req: &mut Request| silences_handler(&silences_alerts, req),
+               "api_silences");
+    router.post("/api/silences", move |req: &mut Request| silences_handler(&silence_alerts, req),
+                "api_silences_add");
+    router.delete("/api/silences/:id", move |req: &mut Request| unsilence_handler(&unsilence_alerts, req),
+                  "api_silence");
+
+    // serve the incidents of all targets at /api/incidents
+    let incidents_managers = managers.clone();
+    router.get("/api/incidents",
+               move |req: &mut Request| incidents_handler(&incidents, &incidents_managers, req),
+               "api_incidents");
+
+    // answer Grafana's JSON datasources at /grafana
+    let (search_managers, query_managers) = (managers.clone(), managers.clone());
+    router.get("/grafana", |_: &mut Request| Ok(Response::with((status::Ok, "OK"))), "grafana");
+    router.post("/grafana/search", move |req: &mut Request| grafana_search_handler(&search_managers, req),
+                "grafana_search");
+    router.post("/grafana/query", move |req: &mut Request| grafana_query_handler(&query_managers, req),
+                "grafana_query");
+    router.post("/grafana/annotations",
+                move |req: &mut Request| grafana_annotations_handler(&grafana_incidents, &grafana_alerts, req),
+                "grafana_annotations");
+
+    // serve the public status page at /status (and as JSON at /status.json), if configured
+    let status_config = configuration.read().unwrap().status_page.clone();
+    let public_status = status_config.is_some();
+    if let Some(c) = status_config {
+        let pages = Arc::new(StatusPages::new(managers.clone(), c));
+        let json_pages = pages.clone();
+        router.get("/status", move |_: &mut Request| status_handler(&pages, false), "status");
+        router.get("/status.json", move |_: &mut Request| status_handler(&json_pages, true), "status_json");
+    }
+
+    /*
+     * stream live results as server-sent events at /api/live, leaving most of
+     * the threads (8 per CPU) of the web server for everything else
+     */
+    let max_streams = thread::available_parallelism().map_or(1, |n| n.get()) * 4;
+    let raw_broadcaster = broadcaster.clone();
+    router.get("/api/live", move |req: &mut Request| events_handler(&broadcaster, max_streams, false, req),
+               "api_live");
+    router.get("/api/live/raw",
+               move |req: &mut Request| events_handler(&raw_broadcaster, max_streams, true, req),
+               "api_live_raw");
+
+    // take in the rounds pushed by agents at /api/agents/<site>, listing their sites at /api/agents
+    let push_aggregator = aggregator.clone();
+    let push_auth = auth.clone();
+    router.get("/api/agents", move |_: &mut Request| agents_handler(&aggregator), "api_agents");
+    router.post("/api/agents/:site", move |req: &mut Request| agent_push_handler(&push_aggregator, &push_auth, req),
+                "api_agents_push");
+
+    // list, add to and remove from the targets at /api/targets
+    router.any("/api/targets", TargetsHandler { managers: managers.clone() }, "api_targets");
+
     // route each /api/target/... endpoint to the appropriate TargetHandler
-    for tm in targets {
+    for tm in managers.iter() {
         router.any(format!("/api/target/{}", tm.kind.compact_name()),
                    TargetHandler::new(tm.clone()),
                    format!("target_{}", tm.kind.compact_name()));
+
+        let report_tm = tm.clone();
+        router.get(format!("/api/report/{}", tm.kind.compact_name()),
+                   move |req: &mut Request| report_handler(&report_tm, req),
+                   format!("report_{}", tm.kind.compact_name()));
+
+        let (badge_tm, badge_metrics) = (tm.clone(), badge_metrics.clone());
+        router.get(format!("/badge/{}.svg", tm.kind.compact_name()),
+                   move |req: &mut Request| badge_handler(&badge_tm, &badge_metrics, req),
+                   format!("badge_{}", tm.kind.compact_name()));
+
+        let histogram_tm = tm.clone();
+        router.get(format!("/api/histogram/{}", tm.kind.compact_name()),
+                   move |req: &mut Request| histogram_handler(&histogram_tm, req),
+                   format!("histogram_{}", tm.kind.compact_name()));
+
+        let rollups_tm = tm.clone();
+        router.get(format!("/api/rollups/{}", tm.kind.compact_name()),
+                   move |req: &mut Request| rollups_handler(&rollups_tm, req),
+                   format!("rollups_{}", tm.kind.compact_name()));
+
+        let schema_tm = tm.clone();
+        router.get(format!("/api/schema/{}", tm.kind.compact_name()),
+                   move |_: &mut Request| schema_handler(&schema_tm),
+                   format!("schema_{}", tm.kind.compact_name()));
+
+        if tm.kind.keeps_hops() {
+            let hops_tm = tm.clone();
+            router.get(format!("/api/hops/{}", tm.kind.compact_name()),
+                       move |req: &mut Request| hops_handler(&hops_tm, req),
+                       format!("hops_{}", tm.kind.compact_name()));
+
+            let mtr_tm = tm.clone();
+            router.get(format!("/api/mtr/{}", tm.kind.compact_name()),
+                       move |req: &mut Request| mtr_handler(&mtr_tm, req),
+                       format!("mtr_{}", tm.kind.compact_name()));
+        }
+
+        if tm.kind.keeps_ips() {
+            let ips_tm = tm.clone();
+            router.get(format!("/api/ips/{}", tm.kind.compact_name()),
+                       move |req: &mut Request| ips_handler(&ips_tm, req),
+                       format!("ips_{}", tm.kind.compact_name()));
+        }
+
+        let patch_tm = tm.clone();
+        router.patch(format!("/api/targets/{}", tm.kind.compact_name()),
+                     move |req: &mut Request| patch_target_handler(&patch_tm, req),
+                     format!("targets_{}", tm.kind.compact_name()));
+
+        for (extension, format) in [("csv", ExportFormat::Csv), ("ndjson", ExportFormat::Ndjson),
+                                   ("parquet", ExportFormat::Parquet)] {
+            let export_tm = tm.clone();
+            router.get(format!("/api/targets/{}/export.{}", tm.kind.compact_name(), extension),
+                       move |req: &mut Request| export_handler(&export_tm, format, req),
+                       format!("export_{}_{}", extension, tm.kind.compact_name()));
+        }
+
+        let hosts_tm = tm.clone();
+        router.post(format!("/api/targets/{}/hosts", tm.kind.compact_name()),
+                    move |req: &mut Request| hosts_handler(&hosts_tm, req),
+                    format!("hosts_{}", tm.kind.compact_name()));
+
+        let probe_tm = tm.clone();
+        router.post(format!("/api/targets/{}/probe", tm.kind.compact_name()),
+                    move |_: &mut Request| probe_target_handler(&probe_tm),
+                    format!("probe_{}", tm.kind.compact_name()));
     }
 
+    // everything is served under the path prefix
+    let prefix = configuration.read().unwrap().path_prefix();
     let mut mount = Mount::new();
-    mount.mount("/", router);
+    mount.mount(&prefix, router);
 
     // serve the web assets (via the web assets handler) at /assets
-    mount.mount("/assets/", webassets_handler);
+    mount.mount(&format!("{}assets/", prefix), webassets_handler);
 
-    let iron = Iron::new(mount);
+    // with everything behind the auth check, if any
+    let mut chain = Chain::new(mount);
+    if auth.enabled() {
+        let prefix_len = prefix.split('/').filter(|s| !s.is_empty()).count();
+        let public_badges = configuration.read().unwrap().public_badges;
+        chain.link_before(AuthCheck { auth, prefix_len, public_status, public_badges });
+    }
+    let chain = SharedChain(Arc::new(chain));
 
-    // actually spawn the Iron web server in a new thread
-    let web_port = configuration.read().unwrap().web_port;
+    // actually spawn the Iron web server (on each of its listeners) in a new thread
+    let (address, web_port, unix_socket) = {
+        let c = configuration.read().unwrap();
+        (c.listen_address.clone(), c.web_port, c.unix_socket.clone())
+    };
     thread::spawn(move || {
-        println!("Web server listening on port {}.", web_port);
-        iron.http(("0.0.0.0", web_port)).unwrap();
+        if prefix != "/" {
+            info!("Web server serving under {}.", prefix);
+        }
+        // each listener is served until it is dropped (at the end of this thread)
+        let mut listening = Vec::new();
+        if web_port != 0 {
+            let iron = Iron::new(chain.clone());
+            listening.push(match https {
+                Some(https) => {
+                    info!("Web server listening on {} port {} (HTTPS).", address, web_port);
+                    iron.https((address.as_str(), web_port), https).unwrap()
+                },
+                None => {
+                    info!("Web server listening on {} port {}.", address, web_port);
+                    iron.http((address.as_str(), web_port)).unwrap()
+                },
+            });
+        }
+        if let Some(path) = unix_socket {
+            listening.push(unix_socket_server(chain, &path));
+        }
+        if listening.is_empty() {
+            warn!("Web server has nothing to listen on (web_port is 0 and no unix_socket is given).");
+        }
     })
 }
+
+/**
+ * Serves the web server (over plain HTTP) on the Unix domain socket at the
+ * given path.
+ */
+#[cfg(unix)]
+fn unix_socket_server(chain: SharedChain, path: &Path) -> Listening {
+    let listener = match UnixSocketListener::bind(path) {
+        Ok(l) => l,
+        Err(e) => panic!("Unable to listen on '{}': {}", path.display(), e),
+    };
+    info!("Web server listening on {}.", path.display());
+    Iron::new(chain).listen(listener, Protocol::http()).unwrap()
+}
+
+#[cfg(not(unix))]
+fn unix_socket_server(chain: SharedChain, path: &Path) -> Listening {
+    let _ = chain;
+    panic!("Unable to listen on '{}': Unix domain sockets are unsupported.", path.display());
+}
@@ -0,0 +1,897 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The data-collection loops shared by the workers of all target kinds. Each
+ * kind only supplies its `Probe` (see `probe.rs`), performing a single timed
+ * attempt against a single address, yielding one value for each of the kind's
+ * columns.
+ */
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::iter;
+use std::net::IpAddr;
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+
+use std::time::{Duration, Instant};
+use chrono::Local;
+
+use crate::options::{SENTINEL_ERROR, SENTINEL_NODATA, SENTINEL_PAUSED, SENTINEL_MAINTENANCE, MIN_INTERVAL};
+use crate::options::{Aggregate, AddressFamily, TargetOptions, TargetResults, sentinel_name, was_probed};
+use crate::persist::TargetManager;
+use crate::pool::ThreadPool;
+use crate::probe::Unit;
+use crate::proxy::Proxy;
+use crate::shutdown;
+use crate::sockopt::{Dscp, TcpOptions};
+use crate::source::{self, Source};
+use crate::telemetry;
+use crate::throughput;
+
+/**
+ * How often a worker checks whether the target's options changed.
+ */
+const OPTIONS_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/**
+ * Settings (derived from the target's options for the current round) that a
+ * probe must honour in each attempt.
+ */
+#[derive(Clone, Debug)]
+pub struct ProbeSettings {
+    pub timeout: Duration,
+    pub resolve_ttl: Duration,
+    pub family: AddressFamily,
+    pub pin: Option<IpAddr>,  // IP to probe instead of resolving, for fanned out addresses (see `fanout.rs`)
+    pub source: Option<Source>,  // IP or interface to send from (see `source.rs`)
+    pub proxy: Option<Proxy>,  // proxy to tunnel TCP connections through (see `proxy.rs`)
+    pub dscp: Option<Dscp>,  // code point to mark packets with (see `sockopt.rs`)
+    pub tcp: TcpOptions,  // options of TCP connections (see `sockopt.rs`)
+}
+
+/**
+ * Settings (derived from the target's options) of a round of attempts against
+ * a single address.
+ */
+#[derive(Clone, Debug)]
+struct RoundSettings {
+    avg_across: u32,
+    pause: Duration,
+    aggregate: Aggregate,
+    aggregates: Vec<Aggregate>,
+    raw_samples: bool,
+    num_probe_columns: usize,
+    load: Option<String>,
+    probe: ProbeSettings,
+}
+
+impl RoundSettings {
+    fn new(manager: &TargetManager, opt: &TargetOptions) -> Self {
+        let timeout = opt.timeout.unwrap_or_else(|| manager.kind.default_timeout());
+        RoundSettings {
+            avg_across: opt.avg_across,
+            pause: Duration::from_millis(opt.pause as u64),
+            aggregate: opt.aggregate,
+            aggregates: opt.aggregates.clone(),
+            raw_samples: opt.raw_samples,
+            num_probe_columns: manager.kind.probe_columns().len(),
+            load: opt.load.clone(),
+            probe: ProbeSettings {
+                timeout: Duration::from_millis(timeout as u64),
+                resolve_ttl: Duration::from_millis(opt.resolve_ttl as u64),
+                family: opt.family,
+                pin: None,
+                source: opt.source.clone().or_else(source::default_source).map(|s| Source::parse(&s)),
+                proxy: opt.proxy.clone(),
+                dscp: opt.dscp,
+                tcp: opt.tcp,
+            },
+        }
+    }
+}
+
+/**
+ * Aggregates the given (non-empty) values of the successful attempts of a
+ * round with the given function, percentiles being nearest-rank.
+ */
+pub fn aggregate(how: Aggregate, vals: &[u64]) -> u64 {
+    let mut vals = vals.to_vec();
+    vals.sort_unstable();
+    let rank = |p: usize| vals[(p * vals.len()).div_ceil(100).max(1) - 1];
+    match how {
+        Aggregate::Mean => vals.iter().sum::<u64>() / vals.len() as u64,
+        // the middle value, or the mean of the middle two
+        Aggregate::Median => (vals[(vals.len() - 1) / 2] + vals[vals.len() / 2]) / 2,
+        Aggregate::Min => vals[0],
+        Aggregate::Max => vals[vals.len() - 1],
+        Aggregate::P95 => rank(95),
+        Aggregate::P99 => rank(99),
+    }
+}
+
+/**
+ * Durations measured by a single attempt (see `probe_addr`), or the sentinel
+ * it failed with.
+ */
+type AttemptResult = Result<Vec<u64>, i32>;
+
+/**
+ * Delay after which an attempt over IPv4 joins the race against one over IPv6
+ * that hasn't failed yet (the "Connection Attempt Delay" of Happy Eyeballs).
+ */
+const RACE_DELAY: Duration = Duration::from_millis(250);
+
+/**
+ * Races an attempt over IPv6 against one over IPv4, the latter started once
+ * the former failed or had a head start of `RACE_DELAY`, like a Happy
+ * Eyeballs (RFC 8305) client connecting to a dual-stack host. Both attempts
+ * are seen through rather than the loser abandoned, returning the results
+ * over IPv4 and IPv6, and that of the race, whose primary value is the time
+ * it took to win (since the race started), along with the family that won.
+ */
+fn race<P>(addr: &str, probe: &P, settings: &ProbeSettings)
+           -> (AttemptResult, AttemptResult, AttemptResult, Option<AddressFamily>)
+           where P: Fn(&str, &ProbeSettings) -> Result<Vec<u64>, i32> + Sync {
+    let start = Instant::now();
+    let (done_tx, done_rx) = channel();
+    let (v4, v4_start, v6) = thread::scope(|s| {
+        let v6 = s.spawn(move || {
+            let result = probe(addr, &ProbeSettings { family: AddressFamily::Ipv6, ..settings.clone() });
+            let _ = done_tx.send(result.is_ok());
+            result
+        });
+        // IPv4 only gets going early if IPv6 already failed
+        if let Ok(true) = done_rx.recv_timeout(RACE_DELAY) {
+            thread::sleep(RACE_DELAY.saturating_sub(start.elapsed()));
+        }
+        let v4_start = start.elapsed().as_nanos() as u64;
+        let v4 = probe(addr, &ProbeSettings { family: AddressFamily::Ipv4, ..settings.clone() });
+        (v4, v4_start, v6.join().unwrap())
+    });
+
+    let (won, winner) = match (&v4, &v6) {
+        (Ok(a), Ok(b)) if v4_start + a[0] >= b[0] => (Ok(b.clone()), Some(AddressFamily::Ipv6)),
+        (Err(_), Ok(b)) => (Ok(b.clone()), Some(AddressFamily::Ipv6)),
+        (Ok(a), _) => {
+            let mut a = a.clone();
+            a[0] += v4_start;
+            (Ok(a), Some(AddressFamily::Ipv4))
+        },
+        (Err(e), Err(_)) => (Err(*e), None),
+    };
+    (v4, v6, won, winner)
+}
+
+/**
+ * Runs a round of attempts against the given address, using `probe` (the
+ * target kind's, see `AnyProbe::attempt`) to perform each individual attempt, and returns the values of all of its
+ * columns.
+ *
+ * `probe` returns the measured durations of the attempt in nanoseconds (one
+ * for each of the target kind's columns), or if the attempt failed, the
+ * sentinel value to record for it. The durations of the successful attempts
+ * are aggregated (see `aggregate`) into the round's values, and if all
+ * attempts of a round fail, the sentinel of the last attempt is recorded
+ * instead.
+ *
+ * Following the probe's columns, the `ROUND_COLUMNS`, the further aggregates
+ * of the primary value (and if enabled, raw samples) are filled in from the
+ * round's attempts. The primary values of all attempts (the sentinels of
+ * those that failed) are returned alongside.
+ *
+ * Probing `both` families, each attempt is made over IPv4 and then IPv6, all
+ * of them counting towards the round's values, and the `FAMILY_COLUMNS` are
+ * filled in (before the raw samples) from those over each family alone.
+ * Probing `happy_eyeballs`, each attempt is a `race` instead, counting
+ * towards the round's values with the winner's, the `RACE_COLUMN` following
+ * the `FAMILY_COLUMNS`.
+ */
+fn probe_addr<P>(addr: &str, probe: &P, rs: &RoundSettings) -> (Vec<i32>, Vec<i32>)
+                 where P: Fn(&str, &ProbeSettings) -> Result<Vec<u64>, i32> + Sync {
+    // durations of the successful attempts, by column
+    let mut elapsed_by_column = vec![Vec::new(); rs.num_probe_columns];
+    let mut failure = SENTINEL_ERROR;
+    let mut samples = Vec::new();
+    // primary durations of the successful attempts (and the last failure) over each family
+    let mut by_family = vec![(Vec::new(), SENTINEL_ERROR); if rs.probe.family.keeps_apart() { 2 } else { 0 }];
+    let mut ipv6_wins = 0u32;
+    // aggregate the results across the given number of times
+    for _ in 0..rs.avg_across {
+        // results of the attempt counting towards the round, and those over each family
+        let (counted, apart) = match rs.probe.family {
+            AddressFamily::Both => {
+                let apart: Vec<_> = [AddressFamily::Ipv4, AddressFamily::Ipv6].iter()
+                    .map(|&family| probe(addr, &ProbeSettings { family, ..rs.probe.clone() }))
+                    .collect();
+                (apart.clone(), apart)
+            },
+            AddressFamily::HappyEyeballs => {
+                let (v4, v6, won, winner) = race(addr, probe, &rs.probe);
+                if winner == Some(AddressFamily::Ipv6) {
+                    ipv6_wins += 1;
+                }
+                (vec![won], vec![v4, v6])
+            },
+            _ => (vec![probe(addr, &rs.probe)], Vec::new()),
+        };
+        for result in counted {
+            match result {
+                Ok(elapsed) => {
+                    samples.push((elapsed[0] / 1000) as i32);
+                    for (column, e) in elapsed_by_column.iter_mut().zip(elapsed) {
+                        column.push(e);
+                    }
+                },
+                Err(sentinel) => {
+                    samples.push(sentinel);
+                    failure = sentinel;
+                },
+            }
+        }
+        for ((primary, family_failure), result) in by_family.iter_mut().zip(apart) {
+            match result {
+                Ok(elapsed) => primary.push(elapsed[0]),
+                Err(sentinel) => *family_failure = sentinel,
+            }
+        }
+        thread::sleep(rs.pause);
+    }
+    let attempts = samples.len() as u32;
+    let succeeded = elapsed_by_column[0].len() as u32;
+
+    // micro-second aggregates
+    let micros = |how, column: &[u64]| {
+        if column.is_empty() {
+            failure
+        } else {
+            (aggregate(how, column) / 1000) as i32
+        }
+    };
+    let mut vals: Vec<i32> = elapsed_by_column.iter()
+        .map(|column| micros(rs.aggregate, column))
+        .collect();
+
+    // percentage of attempts that failed
+    let loss = ((attempts - succeeded) * 100)
+        .checked_div(attempts)
+        .unwrap_or(100) as i32;
+    vals.push(loss);
+
+    // mean absolute delta between consecutive successful attempts
+    let primary = &elapsed_by_column[0];
+    vals.push(if primary.len() < 2 {
+        SENTINEL_NODATA
+    } else {
+        let deltas: u64 = primary.windows(2).map(|w| w[0].abs_diff(w[1])).sum();
+        (deltas / (primary.len() as u64 - 1) / 1000) as i32
+    });
+
+    for &how in rs.aggregates.iter() {
+        vals.push(micros(how, &elapsed_by_column[0]));
+    }
+
+    for (primary, family_failure) in by_family.iter() {
+        vals.push(if primary.is_empty() {
+            *family_failure
+        } else {
+            (aggregate(rs.aggregate, primary) / 1000) as i32
+        });
+    }
+    if rs.probe.family == AddressFamily::HappyEyeballs {
+        vals.push((ipv6_wins * 100).checked_div(succeeded).map_or(SENTINEL_NODATA, |w| w as i32));
+    }
+
+    if rs.raw_samples {
+        vals.extend(samples.iter().cloned());
+    }
+    (vals, samples)
+}
+
+/**
+ * Delay from starting the `load` of a round to its first loaded attempt, for
+ * the transfer to ramp up (out of TCP slow start) and fill the queues along
+ * the link.
+ */
+const LOAD_WARMUP: Duration = Duration::from_millis(500);
+
+/**
+ * Held (shared) through the usual attempts of rounds with a `load`, and
+ * (alone) through their loaded ones, so that no round's load skews another's
+ * idle values.
+ */
+static LOADING: RwLock<()> = RwLock::new(());
+
+/**
+ * Makes the attempts of a round (see `probe_addr`), and if the target has a
+ * `load`, makes them again while it runs (see `loaded`), appending the
+ * `LOAD_COLUMNS`.
+ */
+fn probe_round<P>(addr: &str, probe: &P, rs: &RoundSettings) -> (Vec<i32>, Vec<i32>)
+                  where P: Fn(&str, &ProbeSettings) -> Result<Vec<u64>, i32> + Sync {
+    let load = match rs.load {
+        Some(ref load) => load,
+        None => return probe_addr(addr, probe, rs),
+    };
+    let (mut vals, samples) = {
+        let _idle = LOADING.read().unwrap_or_else(|e| e.into_inner());
+        probe_addr(addr, probe, rs)
+    };
+    let idle = vals[0];
+    vals.extend(loaded(addr, probe, rs, load, idle));
+    (vals, samples)
+}
+
+/**
+ * Loads the link with the given load (an address of the Throughput target,
+ * e.g. `download 50MB https://speed.example.com/big.bin`), making the attempts
+ * of a round again from `LOAD_WARMUP` into it until it's done, and returns the
+ * `LOAD_COLUMNS` given the round's own primary value.
+ *
+ * Probing `both` families (or `happy_eyeballs`), the loaded attempts are made
+ * over whichever the resolver returns first.
+ */
+fn loaded<P>(addr: &str, probe: &P, rs: &RoundSettings, load: &str, idle: i32) -> Vec<i32>
+             where P: Fn(&str, &ProbeSettings) -> Result<Vec<u64>, i32> + Sync {
+    let _loading = LOADING.write().unwrap_or_else(|e| e.into_inner());
+    let family = if rs.probe.family.keeps_apart() { AddressFamily::Any } else { rs.probe.family };
+    let settings = ProbeSettings { family, ..rs.probe.clone() };
+    let load_settings = ProbeSettings { pin: None, ..settings.clone() };
+
+    let done = AtomicBool::new(false);
+    let mut elapsed = Vec::new();
+    let mut failure = None;
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            if let Err(s) = throughput::KIND.probe().attempt(load, &load_settings, None) {
+                warn!("Load {} (of a round of {}) failed: {}.", load, addr, sentinel_name(s));
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+
+        thread::sleep(LOAD_WARMUP);
+        for _ in 0..rs.avg_across {
+            if done.load(Ordering::SeqCst) {
+                break;
+            }
+            match probe(addr, &settings) {
+                Ok(e) => elapsed.push(e[0]),
+                Err(sentinel) => failure = Some(sentinel),
+            }
+            thread::sleep(rs.pause);
+        }
+    });
+
+    let loaded = match (elapsed.is_empty(), failure) {
+        (false, _) => (aggregate(rs.aggregate, &elapsed) / 1000) as i32,
+        (true, Some(sentinel)) => sentinel,
+        (true, None) => SENTINEL_NODATA,
+    };
+    let bloat = if loaded >= 0 && idle >= 0 { (loaded - idle).max(0) } else { SENTINEL_NODATA };
+    vec![loaded, bloat]
+}
+
+/**
+ * Runs a single round of attempts against every current address of the given
+ * target at once (see `probe_addr`), returning the results once all of them
+ * are done.
+ */
+pub fn run_round(manager: &TargetManager) -> TargetResults {
+    let probe = manager.kind.probe();
+    // retrieve the target's current options
+    let (rs, num_columns) = {
+        let opt = &manager.options_read();
+        (RoundSettings::new(manager, opt), manager.kind.columns(opt).len())
+    };
+
+    // get the current time (to timestamp this round of data with)
+    let timestamp = Local::now().timestamp();
+
+    let mut handles = Vec::new();
+    let nonce = {
+        let t_opt = &manager.options_read();
+        for addr in t_opt.addrs.iter() {
+            // addresses pushed by agents are left to them
+            if t_opt.is_remote(addr) {
+                handles.push(None);
+                continue;
+            }
+            // fanned out addresses are probed as the address they are of, at their IP
+            let mut s = rs.clone();
+            let a = match t_opt.fanned_out(addr) {
+                Some((parent, ip)) => {
+                    s.probe.pin = Some(ip);
+                    parent.to_owned()
+                },
+                None => addr.clone(),
+            };
+
+            /*
+             * spawn a thread to actually collect the data for each separate
+             * address, sending it back through a channel.
+             *
+             * we don't care if send fails as that likely means we are no
+             * longer waiting for it
+             */
+            let (tx, rx) = channel();
+            handles.push(Some(rx));
+            thread::spawn(move || {
+                // on-demand rounds don't store more than their values
+                let attempt = |addr: &str, settings: &ProbeSettings| probe.attempt(addr, settings, None);
+                let _ = tx.send(probe_round(&a, &attempt, &s).0);
+            });
+        }
+        t_opt.nonce
+    };
+
+    let mut vals: Vec<i32> = Vec::with_capacity(handles.len() * num_columns);
+
+    // read back the data from the per-addr subthreads, blocking until each one
+    // completes (they always terminate due to the probe timeouts)
+    for h in handles {
+        match h.map(|h| h.recv()) {
+            Some(Ok(addr_vals)) => vals.extend(addr_vals),
+            // the subthread died without sending anything
+            Some(Err(_)) => vals.extend(iter::repeat_n(SENTINEL_ERROR, num_columns)),
+            None => vals.extend(iter::repeat_n(SENTINEL_NODATA, num_columns)),
+        }
+    }
+
+    TargetResults {
+        kind: manager.kind.kind_id(),
+        nonce,
+        timestamp,
+        vals,
+    }
+}
+
+/**
+ * A round of a single address, the `slot`-th of the target's options with the
+ * given nonce, scheduled for the given deadline, and once done, whether it
+ * failed.
+ */
+#[derive(Clone, Copy, Debug)]
+struct AddrRound {
+    nonce: i32,
+    slot: usize,
+    deadline: Instant,
+    failed: bool,
+}
+
+/**
+ * Returns the deadline of the round following the one with the given
+ * deadline, being the first one still ahead of `now`, along with the number of
+ * deadlines skipped because they already passed.
+ */
+fn next_deadline(deadline: Instant, interval: Duration, now: Instant) -> (Instant, u64) {
+    let interval = interval.max(Duration::from_millis(1));
+    let next = deadline + interval;
+    if next > now {
+        return (next, 0);
+    }
+    let skipped = ((now - next).as_nanos() / interval.as_nanos()) as u64 + 1;
+    (next + interval * skipped as u32, skipped)
+}
+
+/**
+ * Returns when to actually start the round with the given deadline, being a
+ * random delay of up to `jitter` past it (so that rounds of many targets with
+ * the same interval don't all start at once).
+ */
+fn jittered(deadline: Instant, jitter: Duration) -> Instant {
+    let millis = jitter.as_millis() as u64;
+    if millis == 0 {
+        return deadline;
+    }
+    // hashers of new `RandomState`s are randomly seeded, which is random enough here
+    let random = RandomState::new().build_hasher().finish();
+    deadline + Duration::from_millis(random % (millis + 1))
+}
+
+/**
+ * Runs the given round (see `probe_addr`) of a single address on the pool
+ * (or if the target is paused or in maintenance, records `SENTINEL_PAUSED` or
+ * `SENTINEL_MAINTENANCE` for it instead),
+ * sending it off with the values of all other addresses missing
+ * (`SENTINEL_NODATA`), and handing it back to the worker through `done` once
+ * it is.
+ *
+ * Rounds are timestamped as they are sent off, under the lock on `results_out`
+ * shared by all addresses of the target, so that its results stay in order of
+ * time.
+ */
+fn run_addr_round(pool: &ThreadPool, manager: Arc<TargetManager>,
+                  results_out: Arc<Mutex<Sender<TargetResults>>>,
+                  done: Sender<AddrRound>, round: AddrRound) {
+    let AddrRound { nonce, slot, .. } = round;
+    pool.execute(move || {
+        /*
+         * retrieve the target's current options, skipping the round if they
+         * changed (or if shutting down)
+         */
+        let (mut rs, addr, num_addrs, num_columns, skipped_as, fanned_out) = {
+            let opt = &manager.options_read();
+            if opt.nonce != nonce || shutdown::requested() {
+                let _ = done.send(round);
+                return;
+            }
+            (
+                RoundSettings::new(&manager, opt),
+                opt.addrs[slot].clone(),
+                opt.addrs.len(),
+                manager.kind.columns(opt).len(),
+                if opt.paused {
+                    Some(SENTINEL_PAUSED)
+                } else if opt.in_maintenance(&Local::now()) {
+                    Some(SENTINEL_MAINTENANCE)
+                } else {
+                    None
+                },
+                opt.fanned_out(&opt.addrs[slot]).map(|(parent, ip)| (parent.to_owned(), ip)),
+            )
+        };
+
+        let _span = info_span!("round", kind = manager.kind.compact_name(), addr = %addr).entered();
+
+        // while paused or in maintenance, only record that the round was skipped
+        let addr_vals = match skipped_as {
+            Some(sentinel) => vec![sentinel; num_columns],
+            None => {
+                let attempt = |addr: &str, settings: &ProbeSettings| {
+                    manager.kind.probe().attempt(addr, settings, Some(&manager))
+                };
+                // fanned out addresses are probed as the address they are of, at their IP
+                let probed = match fanned_out {
+                    Some((parent, ip)) => {
+                        rs.probe.pin = Some(ip);
+                        parent
+                    },
+                    None => addr.clone(),
+                };
+                let (vals, samples) = probe_round(&probed, &attempt, &rs);
+                let now = Local::now().timestamp();
+                // (only times make up the histograms of latency)
+                if manager.kind.unit_of("") == Unit::Duration {
+                    manager.record_attempts(&addr, &samples, now);
+                }
+                if let Err(e) = manager.record_ips(&addr, now) {
+                    warn!("Failed to record the IPs probed for {}: {}.", addr, e);
+                }
+                vals
+            },
+        };
+        let failed = addr_vals[0] < 0 && was_probed(addr_vals[0]);
+        let mut vals = vec![SENTINEL_NODATA; num_addrs * num_columns];
+        for (v, a) in vals[slot * num_columns..].iter_mut().zip(addr_vals) {
+            *v = a;
+        }
+
+        // send off our results to the main thread
+        {
+            let out = results_out.lock().unwrap();
+            let results = TargetResults {
+                kind: manager.kind.kind_id(),
+                nonce,
+                timestamp: Local::now().timestamp(),
+                vals,
+            };
+            telemetry::result_sending();
+            if out.send(results).is_err() {
+                telemetry::result_received();
+                telemetry::record_dropped(manager.kind.compact_name());
+                warn!("Failed to send results back to the main thread.");
+            }
+        }
+        let _ = done.send(AddrRound { failed, ..round });
+    });
+}
+
+/**
+ * Runs a data-collection worker for the given target, scheduling each of its
+ * addresses independently so that a slow or dead address doesn't hold up the
+ * others: a round of each address (see `run_addr_round`) is queued on the
+ * pool at every deadline `start + phase + n * interval` (`start` being when the
+ * addresses were scheduled), so that rounds don't drift, each delayed by up to
+ * `start_jitter` (see `jittered`). After a failed round, the address's next
+ * deadline is instead `down_interval` after it, until a round succeeds again.
+ * A round overrunning its address's next deadline(s) skips them (see
+ * `next_deadline`), which is recorded with the target. All addresses are
+ * rescheduled whenever the target's options change.
+ *
+ * Once shutdown is requested, no more rounds are queued, and the worker stops
+ * once those running are done.
+ */
+pub fn run_worker(manager: Arc<TargetManager>,
+                  results_out: Sender<TargetResults>,
+                  pool: Arc<ThreadPool>) -> thread::JoinHandle<()> {
+    let results_out = Arc::new(Mutex::new(results_out));
+
+    // start a new thread for the worker
+    let name = manager.kind.compact_name();
+    let spawned = thread::Builder::new().name(format!("worker-{}", name)).spawn(move || {
+        let _span = info_span!("worker", kind = %name).entered();
+        let (done_tx, done_rx) = channel();
+        let mut scheduled = None;
+        /*
+         * (start, slot, deadline) of the next round of every address not
+         * currently running, `start` being its deadline with jitter applied
+         */
+        let mut due: BinaryHeap<Reverse<(Instant, usize, Instant)>> = BinaryHeap::new();
+        let mut running = 0;
+
+        // continue to collect data until shutdown
+        loop {
+            let stopping = shutdown::requested();
+            if stopping && running == 0 {
+                return;
+            }
+
+            let (nonce, local_slots, dur_interval, dur_down_interval, dur_phase, dur_jitter) = {
+                let opt = manager.options_read();
+                // options files from before they were checked may have shorter intervals
+                let interval = opt.interval.max(MIN_INTERVAL);
+                // addresses pushed by agents are left to them
+                let local_slots: Vec<usize> = (0..opt.addrs.len()).filter(|&s| !opt.is_remote(&opt.addrs[s])).collect();
+                (opt.nonce, local_slots, Duration::from_millis(interval as u64),
+                 Duration::from_millis(opt.down_interval.map_or(interval, |d| d.max(MIN_INTERVAL)) as u64),
+                 Duration::from_millis((opt.phase % interval) as u64),
+                 Duration::from_millis(opt.start_jitter as u64))
+            };
+
+            // reschedule every address under new options
+            if scheduled != Some(nonce) && !stopping {
+                scheduled = Some(nonce);
+                due.clear();
+                let start = Instant::now() + dur_phase;
+                due.extend(local_slots.into_iter().map(|slot| Reverse((jittered(start, dur_jitter), slot, start))));
+            }
+
+            // queue the rounds that are due
+            let now = Instant::now();
+            while let Some(&Reverse((start, slot, deadline))) = due.peek() {
+                if start > now || stopping {
+                    break;
+                }
+                due.pop();
+                running += 1;
+                run_addr_round(&pool, manager.clone(), results_out.clone(), done_tx.clone(),
+                               AddrRound { nonce, slot, deadline, failed: false });
+            }
+
+            /*
+             * wait for rounds to be done (scheduling the next ones of their
+             * addresses) until the next becomes due, checking on the options
+             * every so often
+             */
+            let wait = due.peek()
+                .map_or(OPTIONS_POLL_INTERVAL, |&Reverse((when, _, _))| when.saturating_duration_since(now))
+                .min(OPTIONS_POLL_INTERVAL);
+            if let Ok(AddrRound { nonce: n, slot, deadline, failed }) = done_rx.recv_timeout(wait) {
+                running -= 1;
+                if n == nonce && !stopping {
+                    // probe failing addresses more often (if so configured) until they recover
+                    let interval = if failed { dur_down_interval } else { dur_interval };
+                    let (next, skipped) = next_deadline(deadline, interval, Instant::now());
+                    if skipped > 0 {
+                        // (unless the options changed since, moving the address out of its slot)
+                        let addr = {
+                            let opt = manager.options_read();
+                            opt.addrs.get(slot).filter(|_| opt.nonce == n).cloned()
+                        };
+                        if let Some(addr) = addr {
+                            debug!(addr = addr.as_str(), skipped, "Round overran, skipping rounds.");
+                            manager.record_skipped(&addr, skipped);
+                        }
+                        telemetry::record_overrun(manager.kind.compact_name());
+                    }
+                    due.push(Reverse((jittered(next, dur_jitter), slot, next)));
+                }
+            }
+        }
+    });
+    spawned.expect("Unable to start a worker.")
+}
+
+#[test]
+fn probe_addr_averages_attempts_and_counts_loss() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let attempts = AtomicU32::new(0);
+    let probe = |_: &str, _: &ProbeSettings| -> Result<Vec<u64>, i32> {
+        match attempts.fetch_add(1, Ordering::SeqCst) {
+            1 => Err(-2_100_000_005),
+            n => Ok(vec![(n as u64 % 3 + 1) * 1_000_000, 1000]),
+        }
+    };
+    let rs = RoundSettings {
+        avg_across: 4,
+        pause: Duration::from_millis(0),
+        aggregate: Aggregate::Mean,
+        aggregates: vec![Aggregate::Median, Aggregate::Max],
+        raw_samples: true,
+        num_probe_columns: 2,
+        load: None,
+        probe: ProbeSettings {
+            timeout: Duration::from_secs(1),
+            resolve_ttl: Duration::from_secs(1),
+            family: AddressFamily::Any,
+            pin: None,
+            source: None,
+            proxy: None,
+            dscp: None,
+            tcp: TcpOptions::default(),
+        },
+    };
+
+    // attempts of 1ms, a timeout, 3ms and 1ms
+    assert_eq!(probe_addr("a:80", &probe, &rs).0,
+               vec![1666, 1, 25, 2000, 1000, 3000, 1000, -2_100_000_005, 3000, 1000]);
+}
+
+#[test]
+fn probe_addr_probes_both_families_apart() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // 1ms over IPv4, while IPv6 times out every other attempt and takes 5ms otherwise
+    let attempts = AtomicU32::new(0);
+    let probe = |_: &str, settings: &ProbeSettings| -> Result<Vec<u64>, i32> {
+        match settings.family {
+            AddressFamily::Ipv4 => Ok(vec![1_000_000]),
+            AddressFamily::Ipv6 if attempts.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) => Err(-2_100_000_005),
+            AddressFamily::Ipv6 => Ok(vec![5_000_000]),
+            family => panic!("probed over {:?}", family),
+        }
+    };
+    let mut rs = RoundSettings {
+        avg_across: 2,
+        pause: Duration::from_millis(0),
+        aggregate: Aggregate::Max,
+        aggregates: Vec::new(),
+        raw_samples: true,
+        num_probe_columns: 1,
+        load: None,
+        probe: ProbeSettings {
+            timeout: Duration::from_secs(1),
+            resolve_ttl: Duration::from_secs(1),
+            family: AddressFamily::Both,
+            pin: None,
+            source: None,
+            proxy: None,
+            dscp: None,
+            tcp: TcpOptions::default(),
+        },
+    };
+
+    assert_eq!(probe_addr("a:80", &probe, &rs).0,
+               vec![5000, 25, 2000, 1000, 5000, 1000, -2_100_000_005, 1000, 5000]);
+
+    let mut options = crate::tcpping::KIND.default_options();
+    options.raw_samples = true;
+    options.avg_across = 2;
+    options.family = AddressFamily::Both;
+    assert_eq!(crate::tcpping::KIND.columns(&options).len(), 10);
+
+    // over IPv6 alone, all attempts of the round failing over it
+    rs.probe.family = AddressFamily::Ipv6;
+    rs.avg_across = 1;
+    attempts.store(0, Ordering::SeqCst);
+    assert_eq!(probe_addr("a:80", &probe, &rs).0, vec![-2_100_000_005, 100, SENTINEL_NODATA, -2_100_000_005]);
+}
+
+#[test]
+fn next_deadline_skips_those_passed() {
+    let start = Instant::now();
+    let second = Duration::from_secs(1);
+
+    assert_eq!(next_deadline(start, second, start + second / 2), (start + second, 0));
+    assert_eq!(next_deadline(start, second, start + second), (start + second * 2, 1));
+    assert_eq!(next_deadline(start, second, start + second * 3 + second / 2), (start + second * 4, 3));
+}
+
+#[test]
+fn probe_addr_races_ipv6_against_ipv4() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // 1ms over IPv4, while IPv6 fails at once in the first attempt and takes 5ms in the second
+    let attempts = AtomicU32::new(0);
+    let probe = |_: &str, settings: &ProbeSettings| -> Result<Vec<u64>, i32> {
+        match settings.family {
+            AddressFamily::Ipv4 => Ok(vec![1_000_000]),
+            AddressFamily::Ipv6 if attempts.fetch_add(1, Ordering::SeqCst) == 0 => Err(-2_100_000_005),
+            AddressFamily::Ipv6 => Ok(vec![5_000_000]),
+            family => panic!("probed over {:?}", family),
+        }
+    };
+    let rs = RoundSettings {
+        avg_across: 2,
+        pause: Duration::from_millis(0),
+        aggregate: Aggregate::Max,
+        aggregates: vec![Aggregate::Min],
+        raw_samples: false,
+        num_probe_columns: 1,
+        load: None,
+        probe: ProbeSettings {
+            timeout: Duration::from_secs(1),
+            resolve_ttl: Duration::from_secs(1),
+            family: AddressFamily::HappyEyeballs,
+            pin: None,
+            source: None,
+            proxy: None,
+            dscp: None,
+            tcp: TcpOptions::default(),
+        },
+    };
+
+    // IPv4 wins (at once) the first race, IPv6 (before IPv4 got going) the second
+    let vals = probe_addr("a:80", &probe, &rs).0;
+    assert_eq!((vals[0], vals[1]), (5000, 0));
+    assert!(vals[3] >= 1000 && vals[3] < 5000);
+    assert_eq!(vals[4..], [1000, 5000, 50]);
+
+    let mut options = crate::tcpping::KIND.default_options();
+    options.family = AddressFamily::HappyEyeballs;
+    assert_eq!(crate::tcpping::KIND.columns(&options)[4..], ["ipv4", "ipv6", "ipv6_wins"]);
+}
+
+#[test]
+fn probe_round_probes_again_under_load() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // a download dribbled out over a second, the probe taking 5ms meanwhile and 1ms otherwise
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/big.bin", listener.local_addr().unwrap());
+    let loading = Arc::new(AtomicBool::new(false));
+    let serving = loading.clone();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = stream.read(&mut [0u8; 1024]);
+        serving.store(true, Ordering::SeqCst);
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n").unwrap();
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(400));
+            stream.write_all(b"x").unwrap();
+        }
+        serving.store(false, Ordering::SeqCst);
+    });
+    let probe = |_: &str, _: &ProbeSettings| -> Result<Vec<u64>, i32> {
+        Ok(vec![if loading.load(Ordering::SeqCst) { 5_000_000 } else { 1_000_000 }])
+    };
+    let mut rs = RoundSettings {
+        avg_across: 2,
+        pause: Duration::from_millis(0),
+        aggregate: Aggregate::Mean,
+        aggregates: Vec::new(),
+        raw_samples: false,
+        num_probe_columns: 1,
+        load: Some(url),
+        probe: ProbeSettings {
+            timeout: Duration::from_secs(2),
+            resolve_ttl: Duration::from_secs(1),
+            family: AddressFamily::Any,
+            pin: None,
+            source: None,
+            proxy: None,
+            dscp: None,
+            tcp: TcpOptions::default(),
+        },
+    };
+    assert_eq!(probe_round("a:80", &probe, &rs).0, vec![1000, 0, 0, 5000, 4000]);
+
+    // a load failing at once leaves nothing to compare with
+    rs.load = Some("http://127.0.0.1:9/".to_owned());
+    assert_eq!(probe_round("a:80", &probe, &rs).0[3..], [SENTINEL_NODATA, SENTINEL_NODATA]);
+
+    let mut options = crate::tcpping::KIND.default_options();
+    options.load = Some("upload 1MB https://speed.example.com/up".to_owned());
+    assert_eq!(crate::tcpping::KIND.columns(&options)[4..], ["loaded", "bloat"]);
+}
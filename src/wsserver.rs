@@ -6,80 +6,456 @@
  * details.
  */
 
+/*!
+ * The websockets server, broadcasting results live to connected clients: the
+ * raw bytes of every result (see `TargetResults::to_raw_bytes`) for the web
+ * client, or at /ws/live, each round as JSON for the targets subscribed to.
+ * The same are also streamed to the subscribers of server-sent events (see
+ * `EventStream`) of the web server, the raw results as JSON arrays (for the
+ * web client when served over HTTPS, as there are no secure websockets).
+ */
 use std::thread;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError, RecvTimeoutError};
+use std::time::Duration;
 
-use ws;
 use ws::{Settings, Builder};
+use serde::Deserialize;
 
-use crate::options::MainConfiguration;
+use crate::options::{MainConfiguration, TargetResults};
+use crate::persist::TargetManager;
+use crate::sink::{ResultsSink, SinkError};
+use crate::webserver::round_json;
+use crate::auth::Auth;
+use crate::http;
+use crate::shutdown;
 
 /**
  * Error container for websocket broadcasts.
  */
 pub enum BroadcastError {
     SocketNotAvail,
-    WebSocketError(ws::Error)
+    WebSocketError(Box<ws::Error>)
+}
+
+/**
+ * The targets (by compact name) a live client is subscribed to, or all if
+ * `None`.
+ */
+pub type Targets = Option<HashSet<String>>;
+
+/**
+ * Parses the targets subscribed to from the parameters of a query
+ * (`targets=a,b`, or nothing for all), or `None` if they are invalid.
+ */
+pub fn parse_targets(params: &HashMap<String, String>) -> Option<Targets> {
+    if params.keys().any(|k| k != "targets") {
+        return None;
+    }
+    Some(params.get("targets")
+        .map(|t| t.split(',').filter(|t| !t.is_empty()).map(|t| t.to_owned()).collect()))
+}
+
+fn is_subscribed(targets: &Targets, target: &str) -> bool {
+    targets.as_ref().is_none_or(|t| t.contains(target))
+}
+
+/**
+ * What a connected websocket client (or event stream) is sent: every raw
+ * result, or (at /ws/live) each round as JSON, of the targets subscribed to.
+ */
+#[derive(Debug, PartialEq)]
+pub enum Subscription {
+    Raw,
+    Live(Targets),
 }
 
+impl Subscription {
+    /**
+     * Gets the subscription of a client connecting to the given path (with
+     * the given query), or `None` if its query is invalid.
+     */
+    fn for_resource(path: &str, params: &HashMap<String, String>) -> Option<Self> {
+        // the web client (at any other path) gets raw results like before
+        if path != "/ws/live" {
+            return Some(Subscription::Raw);
+        }
+        parse_targets(params).map(Subscription::Live)
+    }
+
+    fn is_live_for(&self, target: &str) -> bool {
+        match *self {
+            Subscription::Raw => false,
+            Subscription::Live(ref targets) => is_subscribed(targets, target),
+        }
+    }
+}
+
+/**
+ * A message from a live client changing the targets it is subscribed to (all
+ * if `null`).
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubscribeMessage {
+    subscribe: Option<Vec<String>>,
+}
+
+/**
+ * The most rounds an event stream may fall behind by before it is closed.
+ */
+const EVENT_BACKLOG: usize = 256;
+
+/**
+ * How long an event stream may be idle before a comment is sent to keep it
+ * (and any proxies along the way) from timing out.
+ */
+pub const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
 /**
- * Wrapper around a websocket broadcast sender for sharing across threads.
+ * Wrapper around a websocket broadcast sender (and the senders of each
+ * connected client, by connection, and of each event stream) for sharing
+ * across threads.
  */
 pub struct Broadcaster {
     sender: Mutex<Option<ws::Sender>>,
+    clients: Mutex<HashMap<u32, (ws::Sender, Subscription)>>,
+    streams: Mutex<(u64, HashMap<u64, EventSender>)>,
+}
+
+/**
+ * The sender of an event stream, and what it is subscribed to.
+ */
+type EventSender = (SyncSender<Arc<str>>, Subscription);
+
+/**
+ * A subscription to the rounds of some targets as server-sent events, lasting
+ * until it is dropped (or the broadcaster is closed).
+ */
+pub struct EventStream {
+    id: u64,
+    receiver: Receiver<Arc<str>>,
+    broadcaster: Arc<Broadcaster>,
+    raw: bool,
+}
+
+impl EventStream {
+    /**
+     * Waits (up to the given timeout) for the next round, as JSON.
+     */
+    pub fn next(&self, timeout: Duration) -> Result<Arc<str>, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    /**
+     * The name of the events of this stream.
+     */
+    pub fn event(&self) -> &'static str {
+        if self.raw { "raw" } else { "round" }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.broadcaster.streams.lock().unwrap().1.remove(&self.id);
+    }
 }
 
 impl Broadcaster {
     pub fn new() -> Broadcaster {
         Broadcaster {
             sender: Mutex::new(None),
+            clients: Mutex::new(HashMap::new()),
+            streams: Mutex::new((0, HashMap::new())),
         }
     }
 
+    /**
+     * Subscribes to results as server-sent events, unless `max` streams are
+     * already open.
+     */
+    pub fn subscribe_events(self: &Arc<Self>, subscription: Subscription, max: usize) -> Option<EventStream> {
+        let mut guard = self.streams.lock().unwrap();
+        let (ref mut next_id, ref mut streams) = *guard;
+        if streams.len() >= max {
+            return None;
+        }
+        let (sender, receiver) = sync_channel(EVENT_BACKLOG);
+        let id = *next_id;
+        *next_id += 1;
+        let raw = subscription == Subscription::Raw;
+        streams.insert(id, (sender, subscription));
+        Some(EventStream {
+            id,
+            receiver,
+            broadcaster: self.clone(),
+            raw,
+        })
+    }
+
     /**
      * Sets/updates the broadcast sender wrapped by this wrapper.
      */
     fn update(&self, new_sender: ws::Sender) {
         let mut guard = self.sender.lock().unwrap();
         *guard = Some(new_sender);
+        // the clients of the previous socket are gone with it
+        self.clients.lock().unwrap().clear();
+    }
+
+    /**
+     * Closes the connections of all websocket clients (and ends all event
+     * streams), and stops listening.
+     */
+    pub fn close(&self) {
+        if let Some(ref b) = *self.sender.lock().unwrap() {
+            let _ = b.shutdown();
+        }
+        self.streams.lock().unwrap().1.clear();
     }
 
     /**
-     * Broadcasts (sends through the broadcast sender) a message to all
-     * connected websocket clients.
+     * Sends a message to all connected websocket clients receiving raw
+     * results.
      */
     pub fn send<M>(&self, msg: M) -> Result<(), BroadcastError> where M: Into<ws::Message> {
-        let guard = self.sender.lock().unwrap();
-        if let Some(ref b) = *guard {
-            b.send(msg).map_err(|e| BroadcastError::WebSocketError(e))
-        } else {
-            Err(BroadcastError::SocketNotAvail)
+        if self.sender.lock().unwrap().is_none() {
+            return Err(BroadcastError::SocketNotAvail);
+        }
+        let msg = msg.into();
+        for (out, _) in self.clients.lock().unwrap().values().filter(|c| c.1 == Subscription::Raw) {
+            out.send(msg.clone()).map_err(|e| BroadcastError::WebSocketError(Box::new(e)))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Sends a message (made only if anyone is subscribed) to all event streams
+     * of raw results.
+     */
+    pub fn send_raw_events<F>(&self, msg: F) where F: FnOnce() -> String {
+        let mut guard = self.streams.lock().unwrap();
+        let streams = &mut guard.1;
+        if streams.values().any(|s| s.1 == Subscription::Raw) {
+            send_events(streams, &msg().into(), |s| *s == Subscription::Raw);
+        }
+    }
+
+    /**
+     * Sends a message (made only if anyone is subscribed) to all live clients
+     * and event streams subscribed to the given target.
+     */
+    pub fn send_live<F>(&self, target: &str, msg: F) -> Result<(), BroadcastError> where F: FnOnce() -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut guard = self.streams.lock().unwrap();
+        let streams = &mut guard.1;
+        let subscribed = clients.values().any(|c| c.1.is_live_for(target)) ||
+            streams.values().any(|s| s.1.is_live_for(target));
+        if !subscribed {
+            return Ok(());
+        }
+        let msg: Arc<str> = msg().into();
+
+        send_events(streams, &msg, |s| s.is_live_for(target));
+        for (out, _) in clients.values().filter(|c| c.1.is_live_for(target)) {
+            out.send(ws::Message::text(msg.to_string())).map_err(|e| BroadcastError::WebSocketError(Box::new(e)))?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Sends a message to the event streams of the given subscriptions, closing
+ * those that fell too far behind.
+ */
+fn send_events<F>(streams: &mut HashMap<u64, EventSender>, msg: &Arc<str>, to: F) where F: Fn(&Subscription) -> bool {
+    streams.retain(|_, &mut (ref sender, ref subscription)| {
+        !to(subscription) || match sender.try_send(msg.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Event stream fell behind, closing it.");
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    });
+}
+
+/**
+ * Handler of a connection of a websocket client, keeping its subscription in
+ * the broadcaster from when it connects until it goes away.
+ */
+struct ClientHandler {
+    out: ws::Sender,
+    broadcaster: Arc<Broadcaster>,
+    auth: Arc<Auth>,
+}
+
+impl ws::Handler for ClientHandler {
+    fn on_request(&mut self, req: &ws::Request) -> ws::Result<ws::Response> {
+        // the token (see `Auth::ws_token`), if given, isn't part of the subscription
+        let (path, query) = req.resource().split_once('?').unwrap_or((req.resource(), ""));
+        let mut params = http::query_params(query);
+        let token = params.remove("token");
+
+        let authorization = req.header("Authorization").and_then(|value| std::str::from_utf8(value).ok());
+        if !self.auth.allows_ws(authorization, token.as_deref()) {
+            debug!("Refusing unauthorized websocket connection.");
+            return Ok(ws::Response::new(401, "Unauthorized", Vec::new()));
+        }
+
+        let subscription = match Subscription::for_resource(path, &params) {
+            Some(s) => s,
+            None => return Ok(ws::Response::new(404, "Not Found", Vec::new())),
+        };
+        self.broadcaster.clients.lock().unwrap()
+            .insert(self.out.connection_id(), (self.out.clone(), subscription));
+        ws::Response::from_request(req)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let request = msg.as_text().ok().and_then(|t| serde_json::from_str::<SubscribeMessage>(t).ok());
+        let mut clients = self.broadcaster.clients.lock().unwrap();
+        let reply = match (request, clients.get_mut(&self.out.connection_id())) {
+            (Some(request), Some(&mut (_, ref mut subscription))) if *subscription != Subscription::Raw => {
+                let reply = serde_json::json!({ "subscribed": request.subscribe });
+                *subscription = Subscription::Live(request.subscribe.map(|t| t.into_iter().collect()));
+                reply
+            },
+            _ => serde_json::json!({ "error": "expected {\"subscribe\": [targets...] or null}" }),
+        };
+        self.out.send(reply.to_string())
+    }
+}
+
+impl Drop for ClientHandler {
+    fn drop(&mut self) {
+        self.broadcaster.clients.lock().unwrap().remove(&self.out.connection_id());
+    }
+}
+
+/**
+ * Sink broadcasting all results live to connected websocket clients.
+ */
+pub struct BroadcastSink {
+    broadcaster: Arc<Broadcaster>,
+    managers: Vec<Arc<TargetManager>>,
+}
+
+impl BroadcastSink {
+    pub fn new(broadcaster: Arc<Broadcaster>, managers: &[Arc<TargetManager>]) -> Self {
+        BroadcastSink {
+            broadcaster,
+            managers: managers.to_vec(),
+        }
+    }
+}
+
+impl ResultsSink for BroadcastSink {
+    fn name(&self) -> &'static str {
+        "WebSocket broadcast"
+    }
+
+    fn deliver(&mut self, results: &TargetResults) -> Result<(), SinkError> {
+        let manager = &self.managers[results.kind as usize];
+        let target = manager.kind.compact_name();
+        let raw = self.broadcaster.send(results.to_raw_bytes());
+        self.broadcaster.send_raw_events(|| {
+            let mut values = vec![results.kind as i64, results.nonce as i64, results.timestamp];
+            values.extend(results.vals.iter().map(|&v| v as i64));
+            serde_json::to_string(&values).unwrap()
+        });
+        let live = self.broadcaster.send_live(target, || {
+            // label the values by the layout as of the round's nonce
+            let (addrs, columns) = match manager.layout(results.nonce) {
+                Some(layout) => (layout.addrs, layout.columns),
+                None => (Vec::new(), Vec::new()),
+            };
+            round_json(target, results, &addrs, &columns).to_string()
+        });
+        match raw.and(live) {
+            Err(BroadcastError::WebSocketError(e)) => Err(SinkError::Dropped(format!("{}", e))),
+            // nobody to broadcast to until the websockets server is up
+            Err(BroadcastError::SocketNotAvail) | Ok(()) => Ok(()),
         }
     }
 }
 
+#[allow(clippy::result_large_err)]
 pub fn ws_server(configuration: Arc<RwLock<MainConfiguration>>,
-                 broadcaster: Arc<Broadcaster>) -> thread::JoinHandle<()> {
-    let ws_port = configuration.read().unwrap().ws_port;
+                 broadcaster: Arc<Broadcaster>,
+                 auth: Arc<Auth>) -> thread::JoinHandle<()> {
+    let (address, ws_port) = {
+        let c = configuration.read().unwrap();
+        (c.listen_address.clone(), c.ws_port)
+    };
     thread::spawn(move || {
         loop {
             let socket = {
                 let mut builder = Builder::new();
                 builder.with_settings(Settings::default());
-                /*
-                 * build a socket with a dumb factory and handler, as we only
-                 * need the socket for the broadcaster
-                 */
-                builder.build(|_| {
-                    move |_| {
-                        Ok(())
+                // track each client (and what it is subscribed to) by its connection
+                let broadcaster = broadcaster.clone();
+                let auth = auth.clone();
+                builder.build(move |out| {
+                    ClientHandler {
+                        out,
+                        broadcaster: broadcaster.clone(),
+                        auth: auth.clone(),
                     }
                 }).unwrap()
             };
             broadcaster.update(socket.broadcaster());
-            println!("WebSocket server (re)listening on port {}.", ws_port);
-            socket.listen(("0.0.0.0", ws_port))
+            info!("WebSocket server (re)listening on {} port {}.", address, ws_port);
+            socket.listen((address.as_str(), ws_port))
                   .expect("Unable to listen on websocket.");
+
+            // the broadcaster was closed on shutdown
+            if shutdown::requested() {
+                return;
+            }
         }
     })
 }
+
+#[test]
+fn subscriptions_by_resource() {
+    let live = |targets: &[&str]| Some(Subscription::Live(Some(targets.iter().map(|t| t.to_string()).collect())));
+
+    assert_eq!(Subscription::for_resource("/", &http::query_params("")), Some(Subscription::Raw));
+    assert_eq!(Subscription::for_resource("/ws/live", &http::query_params("")), Some(Subscription::Live(None)));
+    assert_eq!(Subscription::for_resource("/ws/live", &http::query_params("targets=tcpping,dns")), live(&["tcpping", "dns"]));
+    assert_eq!(Subscription::for_resource("/ws/live", &http::query_params("targets=")), live(&[]));
+    assert_eq!(Subscription::for_resource("/ws/live", &http::query_params("target=dns")), None);
+
+    let s = live(&["dns"]).unwrap();
+    assert!(s.is_live_for("dns"));
+    assert!(!s.is_live_for("tcpping"));
+    assert!(!Subscription::Raw.is_live_for("dns"));
+    assert_eq!(parse_targets(&http::query_params("")), Some(None));
+}
+
+#[test]
+fn event_streams_get_rounds_of_their_targets() {
+    let broadcaster = Arc::new(Broadcaster::new());
+    let dns = broadcaster.subscribe_events(Subscription::Live(parse_targets(&http::query_params("targets=dns")).unwrap()), 3).unwrap();
+    let all = broadcaster.subscribe_events(Subscription::Live(None), 3).unwrap();
+    let raw = broadcaster.subscribe_events(Subscription::Raw, 3).unwrap();
+    assert!(broadcaster.subscribe_events(Subscription::Live(None), 3).is_none());
+
+    let _ = broadcaster.send_live("tcpping", || "a".to_owned());
+    let _ = broadcaster.send_live("dns", || "b".to_owned());
+    let timeout = Duration::from_millis(10);
+    assert_eq!(&*dns.next(timeout).unwrap(), "b");
+    assert!(dns.next(timeout).is_err());
+    assert_eq!(&*all.next(timeout).unwrap(), "a");
+    assert_eq!(&*all.next(timeout).unwrap(), "b");
+    broadcaster.send_raw_events(|| "[0]".to_owned());
+    assert_eq!((&*raw.next(timeout).unwrap(), raw.event()), ("[0]", "raw"));
+    assert!(all.next(timeout).is_err());
+
+    // a stream closed frees up its place
+    drop(dns);
+    assert!(broadcaster.subscribe_events(Subscription::Live(None), 3).is_some());
+}